@@ -0,0 +1,117 @@
+//! The hot paths of receiving blocks: decoding and encoding messages, hashing blocks,
+//! checking signatures and work, and all of the checks a received block goes through
+//! before it is stored.
+#[macro_use]
+extern crate criterion;
+extern crate bytes;
+extern crate nano_lib_rs;
+extern crate nanopow_rs;
+
+use bytes::Bytes;
+use criterion::{Benchmark, Criterion, Throughput};
+
+use nano_lib_rs::block::{Block, BlockHash, BlockKind, BlockPayload, Link};
+use nano_lib_rs::keys::{deterministic_key, public_key_from_secret, sign_message, verify_signature, PublicKey,
+    Signature};
+use nano_lib_rs::message::{Message, MessageBuilder, MessageKind, MessagePayload};
+use nanopow_rs::Work;
+
+/// Signatures checked by the batch benchmark
+const BATCH_SIZE: usize = 64;
+
+/// A signed state block of the account at `index` of a fixed seed
+fn state_block(index: u32) -> Block {
+    let secret = deterministic_key(&[7u8; 32], index);
+    let account = public_key_from_secret(&secret);
+    let mut block = Block::new(BlockKind::State, Some(BlockPayload::State {
+        account: account.clone(),
+        previous: BlockHash::from_bytes(&[1u8; 32]).unwrap(),
+        representative: account,
+        balance: 1_000_000,
+        link: Link::Unknown([2u8; 32]),
+    }), None, Some(Work::from_hex("2bf29ef00786a6bc").unwrap()));
+    block.sign(&secret).unwrap();
+    block
+}
+
+fn publish() -> Bytes {
+    MessageBuilder::new(MessageKind::Publish)
+        .with_block_kind(BlockKind::State)
+        .with_payload(MessagePayload::Publish(state_block(0)))
+        .build()
+        .serialize_bytes()
+        .unwrap()
+}
+
+fn message_codec(c: &mut Criterion) {
+    c.bench_function("decode publish", |b| {
+        let bytes = publish();
+        b.iter(|| Message::deserialize_bytes(bytes.clone()).unwrap())
+    });
+    c.bench_function("encode publish", |b| {
+        let msg = Message::deserialize_bytes(publish()).unwrap();
+        b.iter(|| msg.serialize_bytes().unwrap())
+    });
+}
+
+fn block_hashing(c: &mut Criterion) {
+    c.bench_function("hash state block", |b| {
+        let mut block = state_block(0);
+        b.iter(|| block.hash(true).unwrap())
+    });
+}
+
+fn signatures(c: &mut Criterion) {
+    let signed: Vec<(PublicKey, [u8; 32], Signature)> = (0..BATCH_SIZE as u32)
+        .map(|index| {
+            let secret = deterministic_key(&[7u8; 32], index);
+            let message = [index as u8; 32];
+            (public_key_from_secret(&secret), message, sign_message(&secret, &message))
+        })
+        .collect();
+    let (key, message, signature) = signed[0].clone();
+    c.bench_function("verify signature", move |b| {
+        b.iter(|| assert!(verify_signature(&key, &message, &signature)))
+    });
+    // ed25519-dalek 0.6 can't verify a batch at once, so this is the baseline a batch
+    // check would have to beat
+    c.bench("verify signatures", Benchmark::new(format!("batch of {}", BATCH_SIZE), move |b| {
+        b.iter(|| {
+            for &(ref key, ref message, ref signature) in &signed {
+                assert!(verify_signature(key, message, signature));
+            }
+        })
+    }).throughput(Throughput::Elements(BATCH_SIZE as u32)));
+}
+
+fn work_validation(c: &mut Criterion) {
+    c.bench_function("check block work", |b| {
+        let block = state_block(0);
+        b.iter(|| block.work_value().unwrap())
+    });
+}
+
+fn block_processing(c: &mut Criterion) {
+    // What the node does with a published block before queueing it to be stored:
+    // decode it, hash it, and check its work and signature
+    c.bench_function("process published block", |b| {
+        let bytes = publish();
+        b.iter(|| {
+            let msg = Message::deserialize_bytes(bytes.clone()).unwrap();
+            let mut block = match msg.payload {
+                MessagePayload::Publish(block) => block,
+                _ => unreachable!(),
+            };
+            block.hash(false).unwrap();
+            block.work_value().unwrap();
+            let account = match block.payload {
+                Some(BlockPayload::State { ref account, .. }) => account.clone(),
+                _ => unreachable!(),
+            };
+            assert!(block.verify_signature(&account).unwrap());
+        })
+    });
+}
+
+criterion_group!(benches, message_codec, block_hashing, signatures, work_validation, block_processing);
+criterion_main!(benches);
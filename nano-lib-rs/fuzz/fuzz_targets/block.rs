@@ -0,0 +1,22 @@
+//! Decoding blocks, with the first byte of the input as the block kind and the rest
+//! as the block. Whatever a block decodes to must hash, and encode and decode again
+//! to the same block.
+#![no_main]
+use bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+use nano_lib_rs::block::{Block, BlockKind};
+
+fuzz_target!(|data: &[u8]| {
+    let kind = match data.first().and_then(|&kind| BlockKind::from_value(kind)) {
+        Some(kind) => kind,
+        None => return,
+    };
+    if let Ok(block) = Block::deserialize_bytes(Bytes::copy_from_slice(&data[1..]), kind) {
+        let mut decoded = Block::deserialize_bytes(block.serialize_bytes(), kind)
+            .expect("encoded block should decode");
+        assert_eq!(decoded, block);
+        if decoded.payload.is_some() {
+            decoded.hash(true).expect("decoded block should hash");
+        }
+    }
+});
@@ -0,0 +1,15 @@
+//! Decoding datagrams as the node's codec does. Whatever a datagram decodes to must
+//! encode to bytes which decode and encode to the same bytes again; the first decode
+//! needn't match, since short keepalives are padded when encoded.
+#![no_main]
+use bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+use nano_lib_rs::message::Message;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = Message::deserialize_bytes(Bytes::copy_from_slice(data)) {
+        let encoded = message.serialize_bytes().expect("decoded message should encode");
+        let decoded = Message::deserialize_bytes(encoded.clone()).expect("encoded message should decode");
+        assert_eq!(decoded.serialize_bytes().expect("decoded message should encode"), encoded);
+    }
+});
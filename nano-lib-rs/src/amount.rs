@@ -0,0 +1,189 @@
+//! Exact amounts of nano, and conversions between raw and the human denominations.
+//!
+//! Amounts are always held in raw as a `u128`, and are parsed and formatted as
+//! decimal strings, so that converting between denominations never rounds.
+use std::fmt;
+use std::str::FromStr;
+
+use error::*;
+
+/// A denomination of nano
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unit {
+    Raw,
+    /// 10^24 raw, the legacy rai
+    Rai,
+    /// 10^27 raw, also called krai
+    Knano,
+    /// 10^30 raw, the unit balances are usually shown in, also called Mnano and Mrai. The
+    /// official node has used `nano` for this unit since V22.
+    Nano,
+}
+
+impl Unit {
+    pub const ALL: [Unit; 4] = [Unit::Raw, Unit::Rai, Unit::Knano, Unit::Nano];
+
+    /// The power of ten this unit is in raw
+    pub fn exponent(&self) -> u32 {
+        match *self {
+            Unit::Raw => 0,
+            Unit::Rai => 24,
+            Unit::Knano => 27,
+            Unit::Nano => 30,
+        }
+    }
+
+    /// The number of raw in one of this unit
+    pub fn raw(&self) -> u128 {
+        10u128.pow(self.exponent())
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Unit::Raw => "raw",
+            Unit::Rai => "rai",
+            Unit::Knano => "knano",
+            Unit::Nano => "nano",
+        }
+    }
+
+    /// The unit called `name`, also accepting `Mnano` and the legacy `krai` and `Mrai`, in
+    /// any case
+    pub fn from_name(name: &str) -> Option<Unit> {
+        match name.to_lowercase().as_str() {
+            "raw" => Some(Unit::Raw),
+            "rai" => Some(Unit::Rai),
+            "knano" | "krai" => Some(Unit::Knano),
+            "nano" | "mnano" | "mrai" => Some(Unit::Nano),
+            _ => None,
+        }
+    }
+}
+
+/// An amount of nano in raw
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(pub u128);
+
+impl Amount {
+    pub fn from_raw(raw: u128) -> Self {
+        Amount(raw)
+    }
+
+    pub fn raw(&self) -> u128 {
+        self.0
+    }
+
+    /// `value` whole units of `unit`, unless that doesn't fit in 128 bits
+    pub fn from_unit(value: u128, unit: Unit) -> Option<Self> {
+        value.checked_mul(unit.raw()).map(Amount)
+    }
+
+    /// Parse a decimal amount of `unit` such as `1.5`, which must be a whole number of
+    /// raw
+    pub fn parse(value: &str, unit: Unit) -> Result<Self> {
+        let invalid = |reason: &str| Error::from(ErrorKind::InvalidAmountError(reason.into()));
+        let (whole, fraction) = match value.find('.') {
+            Some(i) => (&value[..i], &value[i + 1..]),
+            None => (value, ""),
+        };
+        if whole.is_empty() && fraction.is_empty() {
+            return Err(invalid("no digits"));
+        }
+        if !whole.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit()) {
+            return Err(invalid("not a decimal number"));
+        }
+        let exponent = unit.exponent() as usize;
+        if fraction.len() > exponent {
+            return Err(invalid("more precise than one raw"));
+        }
+        let digits = |s: &str| -> Result<u128> {
+            s.chars().try_fold(0u128, |acc, c| {
+                acc.checked_mul(10)
+                    .and_then(|acc| acc.checked_add(u128::from(c.to_digit(10).unwrap())))
+                    .ok_or_else(|| invalid("too large"))
+            })
+        };
+        let fraction_raw = digits(fraction)? * 10u128.pow((exponent - fraction.len()) as u32);
+        Amount::from_unit(digits(whole)?, unit)
+            .and_then(|amount| amount.checked_add(Amount(fraction_raw)))
+            .ok_or_else(|| invalid("too large"))
+    }
+
+    /// This amount in `unit` as an exact decimal, without trailing zeros
+    pub fn format(&self, unit: Unit) -> String {
+        let whole = self.0 / unit.raw();
+        let fraction = self.0 % unit.raw();
+        if fraction == 0 {
+            return whole.to_string();
+        }
+        let fraction = format!("{:0width$}", fraction, width = unit.exponent() as usize);
+        format!("{}.{}", whole, fraction.trim_end_matches('0'))
+    }
+
+    pub fn checked_add(&self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(&self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+}
+
+impl From<u128> for Amount {
+    fn from(raw: u128) -> Self {
+        Amount(raw)
+    }
+}
+
+/// Parses an amount in raw
+impl FromStr for Amount {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Amount::parse(s, Unit::Raw)
+    }
+}
+
+/// Formats an amount in raw
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_between_units() {
+        let amount = Amount::parse("1.5", Unit::Nano).unwrap();
+        assert_eq!(amount.raw(), 15 * 10u128.pow(29));
+        assert_eq!(amount.format(Unit::Nano), "1.5");
+        assert_eq!(amount.format(Unit::Knano), "1500");
+        assert_eq!(amount.format(Unit::Raw), amount.to_string());
+        assert_eq!(Amount(1).format(Unit::Nano), "0.000000000000000000000000000001");
+        assert_eq!(Amount::parse(".000000000000000000000001", Unit::Rai).unwrap(), Amount(1));
+        assert_eq!(Amount::from_unit(3, Unit::Rai), Some(Amount(3 * 10u128.pow(24))));
+        assert_eq!(Amount::from_unit(3, Unit::Nano), Some(Amount(3 * 10u128.pow(30))));
+        assert_eq!(Unit::from_name("nano"), Some(Unit::Nano));
+        assert_eq!(Unit::from_name("Mnano"), Some(Unit::Nano));
+        assert_eq!(Unit::from_name("Mrai"), Some(Unit::Nano));
+        assert_eq!(Unit::from_name("rai"), Some(Unit::Rai));
+
+        let max = Amount(u128::max_value());
+        assert_eq!(Amount::parse(&max.format(Unit::Nano), Unit::Nano).unwrap(), max);
+    }
+
+    #[test]
+    fn rejects_inexact_amounts() {
+        assert!(Amount::parse("1.5", Unit::Raw).is_err());
+        assert!(Amount::parse("0.0000000000000000000000001", Unit::Rai).is_err());
+        assert!(Amount::parse("0.0000000000000000000000000000001", Unit::Nano).is_err());
+        assert!(Amount::parse("1e30", Unit::Raw).is_err());
+        assert!(Amount::parse("-1", Unit::Nano).is_err());
+        assert!(Amount::parse(".", Unit::Nano).is_err());
+        assert!(Amount::parse("340282366920938463463374607431768211456", Unit::Raw).is_err());
+        assert!(Amount::parse("340282366920939", Unit::Knano).is_err());
+    }
+}
@@ -3,19 +3,19 @@ pub use nanopow_rs::{InputHash, Work};
 
 use byteorder::{BigEndian, ByteOrder};
 
-use bytes::{Bytes, BytesMut, BufMut, Buf, IntoBuf};
+use bytes::{Bytes, BytesMut, BufMut, Buf};
 use blake2::Blake2b;
 use blake2::digest::{Input, VariableOutput};
 
 use hash::{Hash, Hasher};
-use keys::{SecretKey, PublicKey, Signature, SIGNATURE_LENGTH};
+use keys::{self, SecretKey, PublicKey, Signature, SIGNATURE_LENGTH};
 use error::*;
 
 use data_encoding::HEXUPPER;
 
 use std::fmt;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct BlockHash([u8; 32]);
 
 impl BlockHash {
@@ -88,7 +88,7 @@ impl BlockKind {
             BlockKind::Send => 80,
             BlockKind::Receive => 64,
             BlockKind::Open => 96,
-            BlockKind::Change => 32,
+            BlockKind::Change => 64,
             BlockKind::State => 144,
         }
     }
@@ -121,8 +121,11 @@ impl Block {
     pub fn signature(&self) -> Option<Signature> {
         self.signature
     }
-    pub fn sign(&mut self, _key: &SecretKey) -> Result<()> {
-        unimplemented!();
+    /// Sign this block's hash with `key`
+    pub fn sign(&mut self, key: &SecretKey) -> Result<()> {
+        let hash = self.hash(false)?;
+        self.signature = Some(keys::sign_message(key, hash.as_bytes()));
+        Ok(())
     }
     pub fn work(&self) -> Option<Work> {
         self.work.clone()
@@ -156,6 +159,23 @@ impl Block {
         }
         bail!(ErrorKind::NoWorkError);
     }
+    /// The difficulty achieved by this block's work
+    pub fn work_value(&self) -> Result<u64> {
+        if let Some(ref p) = self.payload {
+            if let Some(ref w) = self.work {
+                return Ok(nanopow_rs::work_value(&p.work_source(), w))
+            }
+        }
+        bail!(ErrorKind::NoWorkError);
+    }
+    /// Whether this block's signature of its hash was made by `account`
+    pub fn verify_signature(&mut self, account: &PublicKey) -> Result<bool> {
+        let hash = self.hash(false)?;
+        match self.signature {
+            Some(ref signature) => Ok(keys::verify_signature(account, hash.as_bytes(), signature)),
+            None => bail!("Cannot verify the signature of an unsigned block"),
+        }
+    }
     pub fn cached_hash(&self) -> Option<BlockHash> {
         self.hash
     }
@@ -186,20 +206,29 @@ impl Block {
         self.calculate_hash()
     }
     pub fn serialize_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+        self.serialize_into(&mut buf);
+        buf.freeze()
+    }
+    /// Append this block's wire encoding to `buf`, which is left untouched for a block
+    /// without a payload
+    pub fn serialize_into(&self, buf: &mut BytesMut) {
         if let Some(ref p) = self.payload {
-            let mut buf = BytesMut::new();
-            p.serialize_bytes(&mut buf);
+            p.serialize_bytes(buf);
             if let Some(ref s) = self.signature {
                 buf.reserve(SIGNATURE_LENGTH);
                 buf.put_slice(&s.to_bytes());
             }
             if let Some(ref w) = self.work {
+                // Legacy blocks carry their work little-endian, mirroring deserialize_bytes
+                let mut work_buf = [0u8; 8];
+                work_buf.copy_from_slice(w.as_ref());
+                if self.kind != BlockKind::State {
+                    work_buf.reverse();
+                }
                 buf.reserve(8);
-                buf.put_slice(w.as_ref());
+                buf.put_slice(&work_buf);
             }
-            Bytes::from(buf)
-        } else {
-            Bytes::with_capacity(0)
         }
     }
     pub fn deserialize_bytes(bytes: Bytes, kind: BlockKind) -> Result<Self> {
@@ -214,7 +243,7 @@ impl Block {
                 } else if len < kind.size() + SIGNATURE_LENGTH + 8 {
                     bail!(ErrorKind::BlockParseError(BlockParseErrorKind::NoWork));
                 }
-                let mut buf = bytes.into_buf();
+                let mut buf = bytes;
                 let payload = BlockPayload::deserialize_bytes(&mut buf, kind)?;
                 let mut sig_buf = [0u8; 64];
                 buf.copy_to_slice(&mut sig_buf);
@@ -258,39 +287,6 @@ impl Hasher for BlockHasher {
     }
 }
 
-pub trait BufMutExt: BufMut {
-    fn put_i128<T: ByteOrder>(&mut self, n: i128) {
-        let mut buf = [0u8; 16];
-        T::write_i128(&mut buf, n);
-        self.put_slice(&buf)
-    }
-
-    fn put_u128<T: ByteOrder>(&mut self, n: u128) {
-        let mut buf = [0u8; 16];
-        T::write_u128(&mut buf, n);
-        self.put_slice(&buf)
-    }
-}
-
-impl<T: BufMut> BufMutExt for T {}
-
-pub trait BufExt: Buf {
-    fn get_u128<T: ByteOrder>(&mut self) -> u128 {
-        let mut buf = [0; 16];
-        self.copy_to_slice(&mut buf);
-        T::read_u128(&buf)
-    }
-
-    fn get_i128<T: ByteOrder>(&mut self) -> i128 {
-        let mut buf = [0; 16];
-        self.copy_to_slice(&mut buf);
-        T::read_i128(&buf)
-    }
-}
-
-impl<T: Buf> BufExt for T {}
-
-
 /// Link field contains source block_hash if receiving, destination account if sending
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Link {
@@ -367,7 +363,7 @@ impl BlockPayload {
                 buf.reserve(BlockKind::Send.size());
                 buf.put_slice(previous.as_bytes());
                 buf.put_slice(destination.as_bytes());
-                buf.put_u128::<BigEndian>(*balance);
+                buf.put_u128(*balance);
             }
             BlockPayload::Receive {
                 ref previous,
@@ -406,13 +402,13 @@ impl BlockPayload {
                 buf.put_slice(account.as_bytes());
                 buf.put_slice(previous.as_bytes());
                 buf.put_slice(representative.as_bytes());
-                buf.put_u128::<BigEndian>(*balance);
+                buf.put_u128(*balance);
                 buf.put_slice(link.as_bytes());
             }
         }
     }
 
-    pub fn deserialize_bytes<B: BufExt>(buf: &mut B, kind: BlockKind) -> Result<Self> {
+    pub fn deserialize_bytes<B: Buf>(buf: &mut B, kind: BlockKind) -> Result<Self> {
         Ok(match kind {
             BlockKind::Send => {
                 if buf.remaining() < BlockKind::Send.size() {
@@ -423,7 +419,7 @@ impl BlockPayload {
                 let previous = BlockHash::from_bytes(&temp_buf)?;
                 buf.copy_to_slice(&mut temp_buf);
                 let destination = PublicKey::from_bytes(&temp_buf)?;
-                let balance = buf.get_u128::<BigEndian>();
+                let balance = buf.get_u128();
                 BlockPayload::Send { previous, destination, balance }
             }
             BlockKind::Receive => {
@@ -472,7 +468,7 @@ impl BlockPayload {
                 let previous = BlockHash::from_bytes(&temp_buf)?;
                 buf.copy_to_slice(&mut temp_buf);
                 let representative = PublicKey::from_bytes(&temp_buf)?;
-                let balance = buf.get_u128::<BigEndian>();
+                let balance = buf.get_u128();
                 buf.copy_to_slice(&mut temp_buf);
                 // TODO: Process link properly
                 let link = Link::Unknown(temp_buf);
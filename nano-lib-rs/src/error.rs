@@ -50,6 +50,16 @@ error_chain!{
             description("An error occurred while decoding an ed25519 key")
             display("{}", err)
         }
+        /// Attempted to parse an invalid account address
+        InvalidAddressError(reason: String) {
+            description("invalid account address")
+            display("Invalid account address: {}", reason)
+        }
+        /// Attempted to parse an invalid or inexact amount
+        InvalidAmountError(reason: String) {
+            description("invalid amount")
+            display("Invalid amount: {}", reason)
+        }
         /// Attempted to decode message with invalid magic number
         InvalidMagicNumber {
             description("Invalid magic number")
@@ -1,6 +1,19 @@
 use super::error::Result;
 use std::mem;
 use byteorder::{ByteOrder, LittleEndian};
+use blake2::Blake2b;
+use blake2::digest::{Input, VariableOutput};
+
+/// The 32 byte Blake2b digest of `parts`, one after the other
+pub fn blake2b_256(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Blake2b::new(32).unwrap();
+    for part in parts {
+        hasher.process(part);
+    }
+    let mut digest = [0u8; 32];
+    hasher.variable_result(&mut digest).unwrap();
+    digest
+}
 
 pub trait Hasher {
     type Output;
@@ -1,7 +1,16 @@
+use std::fmt;
+use std::str::FromStr;
+
+use blake2::Blake2b;
+use blake2::digest::{Input, VariableOutput};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
 use nanopow_rs::InputHash;
-use super::hash::{Hash, Hasher};
+use super::hash::{blake2b_256, Hash, Hasher};
+use super::error::*;
 
-pub use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, PUBLIC_KEY_LENGTH, SECRET_KEY_LENGTH, SIGNATURE_LENGTH};
+pub use ed25519_dalek::{DecodingError, Keypair, PublicKey, SecretKey, Signature, PUBLIC_KEY_LENGTH, SECRET_KEY_LENGTH,
+    SIGNATURE_LENGTH};
 
 impl Hash for PublicKey {
     fn hash<H: Hasher>(&self, state: &mut H) {
@@ -9,11 +18,190 @@ impl Hash for PublicKey {
     }
 }
 
+/// The alphabet used to encode addresses, which omits characters that are easily confused
+pub const ADDRESS_ALPHABET: &[u8; 32] = b"13456789abcdefghijkmnopqrstuwxyz";
+/// Prefix of addresses produced by this library
+pub const ADDRESS_PREFIX: &str = "nano_";
+/// Prefix of addresses produced by older wallets, which is still accepted
+pub const LEGACY_ADDRESS_PREFIX: &str = "xrb_";
+
+const ADDRESS_KEY_CHARS: usize = 52;
+const ADDRESS_CHECKSUM_LENGTH: usize = 5;
+const ADDRESS_CHECKSUM_CHARS: usize = 8;
+
+/// Derive the public key for `secret`. Nano uses Blake2b rather than SHA-512 as the
+/// ed25519 hash function.
+pub fn public_key_from_secret(secret: &SecretKey) -> PublicKey {
+    PublicKey::from_secret::<Blake2b>(secret)
+}
+
+/// Sign `message` with `secret`, using Blake2b as the ed25519 hash function
+pub fn sign_message(secret: &SecretKey, message: &[u8]) -> Signature {
+    let keypair = Keypair {
+        public: public_key_from_secret(secret),
+        secret: SecretKey::from_bytes(secret.as_bytes()).expect("secret key has a valid length"),
+    };
+    keypair.sign::<Blake2b>(message)
+}
+
+/// Whether `signature` is a signature of `message` by `key`'s secret, using Blake2b as
+/// the ed25519 hash function
+pub fn verify_signature(key: &PublicKey, message: &[u8], signature: &Signature) -> bool {
+    key.verify::<Blake2b>(message, signature)
+}
+
+/// Length of a wallet seed, from which any number of keys can be derived
+pub const SEED_LENGTH: usize = 32;
+
+/// The secret key at `index` of `seed`: the Blake2b-256 hash of the seed followed by
+/// the index as 4 big-endian bytes, as derived by official wallets
+pub fn deterministic_key(seed: &[u8; SEED_LENGTH], index: u32) -> SecretKey {
+    let mut index_bytes = [0u8; 4];
+    BigEndian::write_u32(&mut index_bytes, index);
+    SecretKey::from_bytes(&blake2b_256(&[seed, &index_bytes])).expect("digest has a valid length")
+}
+
+/// Prepended to arbitrary messages before they are signed, so that a signed message can
+/// never pass for a signed block or vote
+pub const SIGNED_MESSAGE_PREFIX: &[u8] = b"Nano Signed Message:\n";
+
+/// The digest signed for an arbitrary `message`: the Blake2b-256 hash of
+/// `SIGNED_MESSAGE_PREFIX`, the message's length as 8 little-endian bytes, and the message
+pub fn signed_message_digest(message: &[u8]) -> [u8; 32] {
+    let mut length = [0u8; 8];
+    LittleEndian::write_u64(&mut length, message.len() as u64);
+    blake2b_256(&[SIGNED_MESSAGE_PREFIX, &length, message])
+}
+
+/// Sign an arbitrary `message` with `secret`, e.g. to prove ownership of an account
+pub fn sign_arbitrary_message(secret: &SecretKey, message: &[u8]) -> Signature {
+    sign_message(secret, &signed_message_digest(message))
+}
+
+/// Whether `signature` is a signature of the arbitrary `message` by `key`'s secret
+pub fn verify_arbitrary_message(key: &PublicKey, message: &[u8], signature: &Signature) -> bool {
+    verify_signature(key, &signed_message_digest(message), signature)
+}
+
+/// Encode `bytes` as a big-endian number, with leading zero bits added to make up a
+/// whole number of characters
+fn encode_base32(bytes: &[u8]) -> String {
+    let bits = bytes.len() * 8;
+    let chars = (bits + 4) / 5;
+    let padding = chars * 5 - bits;
+    let mut out = String::with_capacity(chars);
+    for i in 0..chars {
+        let mut value = 0usize;
+        for offset in 0..5 {
+            let bit = i * 5 + offset;
+            value <<= 1;
+            if bit >= padding {
+                let pos = bit - padding;
+                value |= ((bytes[pos / 8] >> (7 - pos % 8)) & 1) as usize;
+            }
+        }
+        out.push(ADDRESS_ALPHABET[value] as char);
+    }
+    out
+}
+
+/// The inverse of `encode_base32`, returning `None` on invalid characters or if any of
+/// the leading padding bits are set
+fn decode_base32(encoded: &str, len: usize) -> Option<Vec<u8>> {
+    let bits = len * 8;
+    let padding = encoded.len() * 5 - bits;
+    let mut out = vec![0u8; len];
+    for (i, c) in encoded.bytes().enumerate() {
+        let value = ADDRESS_ALPHABET.iter().position(|&a| a == c)?;
+        for offset in 0..5 {
+            let bit = i * 5 + offset;
+            let set = (value >> (4 - offset)) & 1 == 1;
+            if bit < padding {
+                if set {
+                    return None;
+                }
+            } else if set {
+                let pos = bit - padding;
+                out[pos / 8] |= 1 << (7 - pos % 8);
+            }
+        }
+    }
+    Some(out)
+}
+
+/// The address checksum: a 5 byte Blake2b digest of the key, in reverse byte order
+fn address_checksum(key: &[u8]) -> [u8; ADDRESS_CHECKSUM_LENGTH] {
+    let mut hasher = Blake2b::new(ADDRESS_CHECKSUM_LENGTH).unwrap();
+    hasher.process(key);
+    let mut checksum = [0u8; ADDRESS_CHECKSUM_LENGTH];
+    hasher.variable_result(&mut checksum).unwrap();
+    checksum.reverse();
+    checksum
+}
+
+/// An account address such as `nano_3t6k35gi95xu6tergt6p69ck76ogmitsa8mnijtpxm9fkcm736xtoncuohr3`
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Address(pub String);
 
+impl Address {
+    /// Decode the public key this address encodes, checking its prefix and checksum
+    pub fn to_public_key(&self) -> Result<PublicKey> {
+        let invalid = |reason: &str| Error::from(ErrorKind::InvalidAddressError(reason.into()));
+        let encoded = if self.0.starts_with(ADDRESS_PREFIX) {
+            &self.0[ADDRESS_PREFIX.len()..]
+        } else if self.0.starts_with(LEGACY_ADDRESS_PREFIX) {
+            &self.0[LEGACY_ADDRESS_PREFIX.len()..]
+        } else {
+            return Err(invalid("unknown prefix"));
+        };
+        if encoded.len() != ADDRESS_KEY_CHARS + ADDRESS_CHECKSUM_CHARS {
+            return Err(invalid("wrong length"));
+        }
+        // The length is counted in bytes, so a multi-byte character could otherwise
+        // leave the split off a character boundary
+        if !encoded.is_ascii() {
+            return Err(invalid("invalid encoding"));
+        }
+        let (key, checksum) = encoded.split_at(ADDRESS_KEY_CHARS);
+        let key = decode_base32(key, PUBLIC_KEY_LENGTH).ok_or_else(|| invalid("invalid encoding"))?;
+        let checksum = decode_base32(checksum, ADDRESS_CHECKSUM_LENGTH).ok_or_else(|| invalid("invalid encoding"))?;
+        if checksum[..] != address_checksum(&key)[..] {
+            return Err(invalid("checksum mismatch"));
+        }
+        Ok(PublicKey::from_bytes(&key)?)
+    }
+
+    /// Whether this is a well formed address with a correct checksum
+    pub fn is_valid(&self) -> bool {
+        self.to_public_key().is_ok()
+    }
+}
+
 impl From<PublicKey> for Address {
-    fn from(_key: PublicKey) -> Self {
-        unimplemented!();
+    fn from(key: PublicKey) -> Self {
+        let bytes = key.as_bytes();
+        Address(format!(
+            "{}{}{}",
+            ADDRESS_PREFIX,
+            encode_base32(&bytes[..]),
+            encode_base32(&address_checksum(&bytes[..]))
+        ))
+    }
+}
+
+impl FromStr for Address {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let address = Address(s.to_owned());
+        address.to_public_key()?;
+        Ok(address)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
     }
 }
 
@@ -30,3 +218,75 @@ impl From<PublicKey> for Account {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data_encoding::HEXUPPER;
+
+    const GENESIS_KEY: &str = "E89208DD038FBB269987689621D52292AE9C35941A7484756ECCED92A65093BA";
+    const GENESIS_ADDRESS: &str = "3t6k35gi95xu6tergt6p69ck76ogmitsa8mnijtpxm9fkcm736xtoncuohr3";
+
+    fn genesis_key() -> PublicKey {
+        PublicKey::from_bytes(&HEXUPPER.decode(GENESIS_KEY.as_bytes()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn encode_address() {
+        let address: Address = genesis_key().into();
+        assert_eq!(address.0, format!("nano_{}", GENESIS_ADDRESS));
+    }
+
+    #[test]
+    fn decode_address() {
+        for prefix in &["nano_", "xrb_"] {
+            let address: Address = format!("{}{}", prefix, GENESIS_ADDRESS).parse().unwrap();
+            assert_eq!(address.to_public_key().unwrap().as_bytes(), genesis_key().as_bytes());
+        }
+    }
+
+    #[test]
+    fn reject_invalid_addresses() {
+        let mut bad_checksum = format!("nano_{}", GENESIS_ADDRESS);
+        bad_checksum.pop();
+        bad_checksum.push('1');
+        assert!(!Address(bad_checksum).is_valid());
+        assert!(!Address(format!("xrc_{}", GENESIS_ADDRESS)).is_valid());
+        assert!(!Address(format!("nano_{}", &GENESIS_ADDRESS[1..])).is_valid());
+        assert!(!Address(format!("nano_{}", GENESIS_ADDRESS.replace("3t6", "3t0"))).is_valid());
+        // 60 bytes, with a two byte character across the split between key and checksum
+        let multi_byte = format!("nano_{}é{}", &GENESIS_ADDRESS[..51], &GENESIS_ADDRESS[53..]);
+        assert!(!Address(multi_byte).is_valid());
+    }
+
+    #[test]
+    fn verify_signatures() {
+        let secret = SecretKey::from_bytes(&[1u8; SECRET_KEY_LENGTH]).unwrap();
+        let public = public_key_from_secret(&secret);
+        let signature = sign_message(&secret, b"block hash");
+        assert!(verify_signature(&public, b"block hash", &signature));
+        assert!(!verify_signature(&public, b"other hash", &signature));
+        assert!(!verify_signature(&genesis_key(), b"block hash", &signature));
+    }
+
+    #[test]
+    fn sign_arbitrary_messages() {
+        let secret = SecretKey::from_bytes(&[1u8; SECRET_KEY_LENGTH]).unwrap();
+        let public = public_key_from_secret(&secret);
+        let signature = sign_arbitrary_message(&secret, b"I own this account");
+        assert!(verify_arbitrary_message(&public, b"I own this account", &signature));
+        assert!(!verify_arbitrary_message(&public, b"I own this account!", &signature));
+        // The signature covers the prefixed digest, not the message itself
+        assert!(!verify_signature(&public, b"I own this account", &signature));
+    }
+
+    #[test]
+    fn derive_deterministic_keys() {
+        let secret = deterministic_key(&[0u8; SEED_LENGTH], 0);
+        assert_eq!(HEXUPPER.encode(secret.as_bytes()),
+            "9F0E444C69F77A49BD0BE89DB92C38FE713E0963165CCA12FAF5712D7657120F");
+        assert_eq!(HEXUPPER.encode(public_key_from_secret(&secret).as_bytes()),
+            "C008B814A7D269A1FA3C6528B19201A24D797912DB9996FF02A1FF356E45552B");
+        assert_ne!(deterministic_key(&[0u8; SEED_LENGTH], 1).as_bytes(), secret.as_bytes());
+    }
+}
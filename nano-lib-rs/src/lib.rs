@@ -26,13 +26,16 @@ extern crate data_encoding;
 
 extern crate nanopow_rs;
 
-extern crate tokio_io;
 
 #[macro_use]
 mod macros; 
 
+pub mod amount;
 pub mod block;
 pub mod keys;
 pub mod hash;
 pub mod error;
 pub mod message;
+pub mod network;
+#[cfg(test)]
+mod vectors;
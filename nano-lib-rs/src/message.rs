@@ -1,4 +1,4 @@
-use bytes::{Bytes, BytesMut, BufMut, Buf, IntoBuf, LittleEndian};
+use bytes::{Bytes, BytesMut, BufMut, Buf};
 use bincode;
 use error::*;
 use block::{BlockKind, Block};
@@ -29,10 +29,15 @@ impl MessageKind {
 
 pub const MAGIC_NUMBER: u8 = 0x52;
 
+/// The number of peers in a keepalive. Datagrams listing more are cut short, so that
+/// one datagram can't have the node send to thousands of addresses.
+pub const KEEPALIVE_PEERS: usize = 8;
+
 enum_byte!(NetworkKind {
-    Test = 0x41, // 'A' in ASCII
+    Dev = 0x41, // 'A' in ASCII
     Beta = 0x42, // 'B' in ASCII
     Main = 0x43, // 'C' in ASCII
+    Test = 0x58, // 'X' in ASCII
 });
 
 enum_byte!(Version {
@@ -50,6 +55,9 @@ bitflags! {
   pub struct Extensions: u8 {
     const IPV4_ONLY = 1;
     const BOOTSTRAP_NODE = 2;
+    // The sender can't accept connections it didn't open, so shouldn't be passed on
+    // to other peers
+    const NON_RELAYABLE = 4;
     const NONE = 0;
   }
 }
@@ -82,29 +90,30 @@ pub enum MessagePayload {
 
 impl MessagePayload {
     pub fn serialize_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+        self.serialize_into(&mut buf);
+        buf.freeze()
+    }
+
+    /// Append the wire encoding of this payload to `buf`
+    pub fn serialize_into(&self, buf: &mut BytesMut) {
         match *self {
-            MessagePayload::Invalid => {
-                Bytes::with_capacity(0)
-            },
+            MessagePayload::Invalid => {},
             MessagePayload::KeepAlive(ref peers) => {
-                let mut buf = BytesMut::new();
                 buf.reserve(MessageKind::KeepAlive.size().unwrap());
                 // Official node will only accept exactly 8 peers
-                let mut peers = peers.clone();
-                for _ in 0..(8 - cmp::min(peers.len(), 8)) {
-                    peers.push("[::]:0".parse().unwrap());
-                }
-                for peer in &peers[..8] {
+                let unspecified: SocketAddrV6 = "[::]:0".parse().unwrap();
+                let padding = KEEPALIVE_PEERS - cmp::min(peers.len(), KEEPALIVE_PEERS);
+                for peer in peers.iter().take(KEEPALIVE_PEERS).chain(::std::iter::repeat(&unspecified).take(padding)) {
                     buf.put_slice(&peer.ip().octets()[..]);
-                    buf.put_u16::<LittleEndian>(peer.port());
+                    buf.put_u16_le(peer.port());
                 }
-                Bytes::from(buf)
             },
             MessagePayload::Publish(ref block) => {
-                block.serialize_bytes()
+                block.serialize_into(buf)
             },
             MessagePayload::ConfirmReq(ref block) => {
-                block.serialize_bytes()
+                block.serialize_into(buf)
             },
             MessagePayload::ConfirmAck {
                 ref public_key,
@@ -112,14 +121,11 @@ impl MessagePayload {
                 ref sequence,
                 ref block,
             } => {
-                let mut buf = BytesMut::new();
-                buf.reserve(32 + 32 + 8 + block.kind.size());
+                buf.reserve(32 + SIGNATURE_LENGTH + 8 + block.kind.size());
                 buf.put_slice(public_key.as_bytes());
                 buf.put_slice(&signature.to_bytes());
-                buf.put_u64::<LittleEndian>(*sequence);
-                let block_bytes = block.serialize_bytes();
-                buf.put(block_bytes);
-                Bytes::from(buf)
+                buf.put_u64_le(*sequence);
+                block.serialize_into(buf);
             },
         }
     }
@@ -127,18 +133,19 @@ impl MessagePayload {
     pub fn deserialize_bytes(header: MessageHeader, bytes: Bytes) -> Result<Self> {
         Ok(match header.kind {
             MessageKind::KeepAlive => {
-                let peers: Vec<SocketAddrV6> = bytes.chunks(18).filter_map(|chunk| {
+                let mut peers: Vec<SocketAddrV6> = Vec::with_capacity(KEEPALIVE_PEERS);
+                peers.extend(bytes.chunks(18).take(KEEPALIVE_PEERS).filter_map(|chunk| {
                     if chunk.len() == 18 {
-                        let mut buf = chunk.into_buf();
+                        let mut buf = chunk;
                         let mut octets = [0u8; 16];
                         for i in 0..16 {
                             octets[i] = buf.get_u8();
                         }
-                        Some(SocketAddrV6::new(Ipv6Addr::from(octets), buf.get_u16::<LittleEndian>(), 0, 0))
+                        Some(SocketAddrV6::new(Ipv6Addr::from(octets), buf.get_u16_le(), 0, 0))
                     } else {
                         None
                     }
-                }).collect();
+                }));
                 if peers.len() > 0 {
                     MessagePayload::KeepAlive(peers)
                 } else {
@@ -151,6 +158,22 @@ impl MessagePayload {
             MessageKind::ConfirmReq => {
                 MessagePayload::ConfirmReq(Block::deserialize_bytes(bytes, header.block_kind)?)
             },
+            MessageKind::ConfirmAck => {
+                if bytes.len() < 32 + SIGNATURE_LENGTH + 8 {
+                    bail!("Vote of {} bytes is too short", bytes.len());
+                }
+                let mut buf = bytes;
+                let public_key = PublicKey::from_bytes(&buf[..32])?;
+                let signature = Signature::from_bytes(&buf[32..32 + SIGNATURE_LENGTH])?;
+                buf.advance(32 + SIGNATURE_LENGTH);
+                let sequence = buf.get_u64_le();
+                MessagePayload::ConfirmAck {
+                    public_key,
+                    signature,
+                    sequence,
+                    block: Block::deserialize_bytes(buf, header.block_kind)?,
+                }
+            },
             _ => {
                 MessagePayload::Invalid
             }
@@ -173,14 +196,20 @@ impl Message {
     }
 
     pub fn serialize_bytes(&self) -> Result<Bytes> {
-        let header_ser = bincode::serialize(&self.header)?;
-        let data = self.payload.serialize_bytes();
-        let mut buf = BytesMut::with_capacity(header_ser.len() + data.len());
-        buf.put(header_ser);
-        buf.put(data);
-        Ok(Bytes::from(buf))
+        let mut buf = BytesMut::new();
+        self.serialize_into(&mut buf)?;
+        Ok(buf.freeze())
+    }
+
+    /// Append the wire encoding of this message to `buf`, without allocating if `buf`
+    /// already has room for it
+    pub fn serialize_into(&self, buf: &mut BytesMut) -> Result<()> {
+        bincode::serialize_into((&mut *buf).writer(), &self.header)?;
+        self.payload.serialize_into(buf);
+        Ok(())
     }
 
+    /// Decode a datagram, failing rather than panicking on any malformed input
     pub fn deserialize_bytes(mut bytes: Bytes) -> Result<Self> {
         let len = bytes.len();
         if bytes.len() < 8 {
@@ -288,7 +317,7 @@ mod tests {
     fn deserialize_message_header() {
         let test_cases = vec![
             (b"5243070701020000", MAGIC_NUMBER, NetworkKind::Main, Version::Seven, Version::Seven, Version::One, MessageKind::KeepAlive, BlockKind::Invalid, Extensions::NONE),
-            (b"5241060504000001", MAGIC_NUMBER, NetworkKind::Test, Version::Six, Version::Five, Version::Four, MessageKind::Invalid, BlockKind::NotABlock, Extensions::NONE),
+            (b"5241060504000001", MAGIC_NUMBER, NetworkKind::Dev, Version::Six, Version::Five, Version::Four, MessageKind::Invalid, BlockKind::NotABlock, Extensions::NONE),
             (b"5242030201010002", MAGIC_NUMBER, NetworkKind::Beta, Version::Three, Version::Two, Version::One, MessageKind::NotAMessage, BlockKind::Send, Extensions::NONE),
             (b"5243060601030003", MAGIC_NUMBER, NetworkKind::Main, Version::Six, Version::Six, Version::One, MessageKind::Publish, BlockKind::Receive, Extensions::NONE),
             (b"5243060601040004", MAGIC_NUMBER, NetworkKind::Main, Version::Six, Version::Six, Version::One, MessageKind::ConfirmReq, BlockKind::Open, Extensions::NONE),
@@ -329,4 +358,68 @@ mod tests {
         let message_ser = message.serialize_bytes().unwrap();
         assert_eq!(&message_ser[..], &message_raw[..]);
     }
+
+    #[test]
+    fn confirm_ack_round_trip() {
+        use block::{BlockPayload, BlockHash, Link, Work};
+        let account = PublicKey::from_bytes(&[7u8; 32]).unwrap();
+        let block = Block::new(BlockKind::State, Some(BlockPayload::State {
+            account: account.clone(),
+            previous: BlockHash::from_bytes(&[1u8; 32]).unwrap(),
+            representative: account.clone(),
+            balance: 5,
+            link: Link::Unknown([2u8; 32]),
+        }), Some(Signature::from_bytes(&[3u8; 64]).unwrap()), Some(Work::from_bytes(&[4u8; 8]).unwrap()));
+        let message = MessageBuilder::new(MessageKind::ConfirmAck)
+            .with_block_kind(BlockKind::State)
+            .with_payload(MessagePayload::ConfirmAck {
+                public_key: account,
+                signature: Signature::from_bytes(&[5u8; 64]).unwrap(),
+                sequence: 9,
+                block,
+            })
+            .build();
+        let message_ser = message.serialize_bytes().unwrap();
+        assert_eq!(Message::deserialize_bytes(message_ser).unwrap(), message);
+    }
+
+    #[test]
+    fn keepalive_peers_are_capped() {
+        let mut message_raw = HEXUPPER.decode(b"5243070701020000").unwrap();
+        for i in 0..100u16 {
+            message_raw.extend_from_slice(&[0u8; 16]);
+            message_raw.extend_from_slice(&i.to_le_bytes());
+        }
+        match Message::deserialize_bytes(Bytes::from(message_raw)).unwrap().payload {
+            MessagePayload::KeepAlive(peers) => assert_eq!(peers.len(), KEEPALIVE_PEERS),
+            payload => panic!("unexpected payload {:?}", payload),
+        }
+    }
+
+    #[test]
+    fn decodes_malformed_datagrams_without_panicking() {
+        let raw = HEXUPPER.decode(b"524307070102000100000000000000000000000000000000A31B00000000000000000000000000000000A31B").unwrap();
+        let mut datagrams: Vec<Vec<u8>> = (0..raw.len()).map(|len| raw[..len].to_vec()).collect();
+        let publish = MessageBuilder::new(MessageKind::Publish)
+            .with_block_kind(BlockKind::State)
+            .build();
+        // A header without a payload
+        let header = publish.serialize_bytes().unwrap().to_vec();
+        // Every message and block kind, each with bodies cut short at every length
+        for kind in 0..10u8 {
+            for block_kind in 0..8u8 {
+                let mut datagram = header.clone();
+                datagram[5] = kind;
+                datagram[7] = block_kind;
+                for len in (0..300).step_by(7) {
+                    let mut garbled = datagram.clone();
+                    garbled.extend((0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(kind)));
+                    datagrams.push(garbled);
+                }
+            }
+        }
+        for datagram in datagrams {
+            let _ = Message::deserialize_bytes(Bytes::from(datagram));
+        }
+    }
 }
@@ -0,0 +1,236 @@
+//! Parameters distinguishing the networks a node can join
+use data_encoding::HEXUPPER;
+
+use nanopow_rs::difficulty::EPOCH_1;
+
+use block::BlockHash;
+use keys::{Address, PublicKey, SecretKey};
+use message::NetworkKind;
+use error::*;
+
+/// Work threshold of the dev network, low enough to generate on a CPU instantly
+pub const DEV_WORK_THRESHOLD: u64 = 0xff00000000000000;
+
+/// Raw created by the genesis block of every network
+pub const GENESIS_AMOUNT: u128 = ::std::u128::MAX;
+
+#[derive(Clone, Debug)]
+pub struct NetworkParams {
+    /// Identifies the network in message headers
+    pub kind: NetworkKind,
+    /// Port nodes listen on unless configured otherwise
+    pub default_port: u16,
+    /// Peers contacted on startup unless configured otherwise, as host:port
+    pub default_peers: Vec<String>,
+    /// Minimum work difficulty accepted on the network
+    pub work_threshold: u64,
+    /// Account the genesis block opens
+    pub genesis_account: PublicKey,
+    /// Hash of the genesis block, if known
+    pub genesis_block: Option<BlockHash>,
+    /// Accounts whose funds are out of circulation, and left out of the available
+    /// supply along with the genesis account's balance
+    pub burn_accounts: Vec<PublicKey>,
+    /// The canary account whose confirmation height reaching the given height activates
+    /// final votes, or `None` if they are active from the start
+    pub final_votes_canary: Option<(PublicKey, u64)>,
+    /// The account signing the epoch blocks of each epoch, from version 1
+    pub epoch_signers: Vec<PublicKey>,
+}
+
+fn key(hex: &str) -> PublicKey {
+    PublicKey::from_bytes(&HEXUPPER.decode(hex.as_bytes()).unwrap()).unwrap()
+}
+
+fn hash(hex: &str) -> BlockHash {
+    BlockHash::from_hex(hex).unwrap()
+}
+
+/// The account of the all zero public key, which nobody holds the private key of, so
+/// sends to it can never be received
+pub fn burn_account() -> PublicKey {
+    PublicKey::from_bytes(&[0u8; 32]).unwrap()
+}
+
+/// The link of the epoch blocks upgrading accounts to epoch `version`, which is
+/// `epoch v{version} block` padded with zeros
+pub fn epoch_link(version: u8) -> [u8; 32] {
+    let mut link = [0u8; 32];
+    let text = format!("epoch v{} block", version);
+    link[..text.len()].copy_from_slice(text.as_bytes());
+    link
+}
+
+/// The private key of the dev network's genesis account, which also signs its epoch
+/// blocks. It is published with the official node, so never use it elsewhere.
+pub fn dev_genesis_key() -> SecretKey {
+    SecretKey::from_bytes(&HEXUPPER.decode(b"34F0A37AAD20F4A260F0A5B3CB3D7FB50673212263E58A380BC10474BB039CE4").unwrap())
+        .unwrap()
+}
+
+impl NetworkParams {
+    pub fn live() -> Self {
+        NetworkParams {
+            kind: NetworkKind::Main,
+            default_port: 7075,
+            default_peers: vec!["rai.raiblocks.net:7075".into()],
+            work_threshold: EPOCH_1,
+            genesis_account: key("E89208DD038FBB269987689621D52292AE9C35941A7484756ECCED92A65093BA"),
+            genesis_block: Some(hash("991CF190094C00F0B68E2E5F75F6BEE95A2E0BD93CEAA4A6734DB9F19B728948")),
+            // Besides the burn account, the landing and faucet accounts which held the
+            // funds still to be distributed
+            burn_accounts: vec![
+                burn_account(),
+                key("059F68AAB29DE0D3A27443625C7EA9CDDB6517A8B76FE37727EF6A4D76832AD5"),
+                key("8E319CE6F3025E5B2DF66DA7AB1467FE48F1679C13DD43BFDB29FA2E9FC40D3B"),
+            ],
+            final_votes_canary: Some((key("7CBAF192A3763DAEC9F9BAC1B2CDF665D8369F8400B4BC5AB4BA31C00BAA4404"), 1)),
+            epoch_signers: vec![
+                key("E89208DD038FBB269987689621D52292AE9C35941A7484756ECCED92A65093BA"),
+                key("DD24A9200D4BF8247981E4AC63DBDE38FD2319386970A26D02ECC98C79975DB1"),
+            ],
+        }
+    }
+
+    pub fn beta() -> Self {
+        NetworkParams {
+            kind: NetworkKind::Beta,
+            default_port: 54000,
+            default_peers: vec!["rai-beta.raiblocks.net:54000".into()],
+            work_threshold: EPOCH_1,
+            genesis_account: key("A59A47CC4F593E75AE9AD653FDA9358E2F7898D9ACC8C60E80D0495CE20FBA9F"),
+            genesis_block: None,
+            burn_accounts: vec![burn_account()],
+            final_votes_canary: None,
+            epoch_signers: vec![key("A59A47CC4F593E75AE9AD653FDA9358E2F7898D9ACC8C60E80D0495CE20FBA9F")],
+        }
+    }
+
+    /// The public test network, which takes work as hard as the live network's
+    pub fn test() -> Self {
+        NetworkParams {
+            kind: NetworkKind::Test,
+            default_port: 17075,
+            default_peers: vec!["peering-test.nano.org:17075".into()],
+            work_threshold: EPOCH_1,
+            genesis_account: key("45C6FF9D1706D61F0821327752671BDA9F9ED2DA40326B01935AB566FB9E08ED"),
+            genesis_block: None,
+            burn_accounts: vec![burn_account()],
+            final_votes_canary: None,
+            epoch_signers: vec![
+                key("45C6FF9D1706D61F0821327752671BDA9F9ED2DA40326B01935AB566FB9E08ED"),
+                key("45C6FF9D1706D61F0821327752671BDA9F9ED2DA40326B01935AB566FB9E08ED"),
+            ],
+        }
+    }
+
+    /// A local network for development and integration tests, with no default peers
+    pub fn dev() -> Self {
+        NetworkParams {
+            kind: NetworkKind::Dev,
+            default_port: 44000,
+            default_peers: Vec::new(),
+            work_threshold: DEV_WORK_THRESHOLD,
+            genesis_account: key("B0311EA55708D6A53C75CDBF88300259C6D018522FE3D4D0A242E431F9E8B6D0"),
+            genesis_block: None,
+            burn_accounts: vec![burn_account()],
+            final_votes_canary: None,
+            epoch_signers: vec![
+                key("B0311EA55708D6A53C75CDBF88300259C6D018522FE3D4D0A242E431F9E8B6D0"),
+                key("B0311EA55708D6A53C75CDBF88300259C6D018522FE3D4D0A242E431F9E8B6D0"),
+            ],
+        }
+    }
+
+    /// Look up a network by its name: `live`, `beta`, `test` or `dev`
+    pub fn named(name: &str) -> Option<Self> {
+        match name {
+            "live" => Some(NetworkParams::live()),
+            "beta" => Some(NetworkParams::beta()),
+            "test" => Some(NetworkParams::test()),
+            "dev" => Some(NetworkParams::dev()),
+            _ => None,
+        }
+    }
+
+    /// The genesis account as an address
+    pub fn genesis_address(&self) -> Address {
+        self.genesis_account.into()
+    }
+
+    /// The epoch version of blocks with `link`, if it is an epoch link with a signer on
+    /// this network
+    pub fn epoch_version(&self, link: &[u8; 32]) -> Option<u8> {
+        (1..=self.epoch_signers.len() as u8).find(|&version| epoch_link(version) == *link)
+    }
+
+    /// The signer of the epoch blocks with `link`, if it is an epoch link on this network
+    pub fn epoch_signer(&self, link: &[u8; 32]) -> Option<&PublicKey> {
+        self.epoch_version(link).map(|version| &self.epoch_signers[version as usize - 1])
+    }
+
+    /// Whether `difficulty` is enough for work to be accepted on this network
+    pub fn work_sufficient(&self, difficulty: u64) -> bool {
+        difficulty >= self.work_threshold
+    }
+
+    /// Parse the single character network identifier used in message headers
+    pub fn kind_from_id(id: &str) -> Result<NetworkKind> {
+        let mut chars = id.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii() => NetworkKind::from_value(c as u8)
+                .ok_or_else(|| format!("unknown network identifier `{}`", id).into()),
+            _ => bail!("network identifiers are a single character"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn genesis_addresses() {
+        assert_eq!(NetworkParams::live().genesis_address().0,
+            "nano_3t6k35gi95xu6tergt6p69ck76ogmitsa8mnijtpxm9fkcm736xtoncuohr3");
+        assert_eq!(NetworkParams::beta().genesis_address().0,
+            "nano_3betaz86ypbygpqbookmzpnmd5jhh4efmd8arr9a3n4bdmj1zgnzad7xpmfp");
+        assert_eq!(NetworkParams::test().genesis_address().0,
+            "nano_1jg8zygjg3pp5w644emqcbmjqpnzmubfni3kfe1s8pooeuxsw49fdq1mco9j");
+        assert_eq!(NetworkParams::dev().genesis_address().0,
+            "nano_3e3j5tkog48pnny9dmfzj1r16pg8t1e76dz5tmac6iq689wyjfpiij4txtdo");
+    }
+
+    #[test]
+    fn work_thresholds() {
+        use nanopow_rs::difficulty::{threshold, BlockDetails, Epoch};
+
+        for network in &[NetworkParams::live(), NetworkParams::beta(), NetworkParams::test()] {
+            assert_eq!(network.work_threshold, 0xffffffc000000000);
+        }
+        assert_eq!(threshold(Epoch::Two, BlockDetails::Send), 0xfffffff800000000);
+        assert_eq!(threshold(Epoch::Two, BlockDetails::Receive), 0xfffffe0000000000);
+        assert_eq!(NetworkParams::dev().work_threshold, DEV_WORK_THRESHOLD);
+    }
+
+    #[test]
+    fn epoch_signers() {
+        let dev = NetworkParams::dev();
+        assert_eq!(&epoch_link(1)[..15], b"epoch v1 block\0");
+        assert_eq!(dev.epoch_version(&epoch_link(2)), Some(2));
+        assert_eq!(NetworkParams::live().epoch_signer(&epoch_link(2)).map(|&signer| Address::from(signer).0),
+            Some("nano_3qb6o6i1tkzr6jwr5s7eehfxwg9x6eemitdinbpi7u8bjjwsgqfj4wzser3x".into()));
+        assert_eq!(NetworkParams::live().epoch_version(&epoch_link(3)), None);
+        assert_eq!(dev.epoch_version(&[0u8; 32]), None);
+        assert_eq!(dev.epoch_signer(&epoch_link(1)), Some(&::keys::public_key_from_secret(&dev_genesis_key())));
+    }
+
+    #[test]
+    fn network_ids() {
+        assert_eq!(NetworkParams::kind_from_id("C").unwrap(), NetworkKind::Main);
+        assert_eq!(NetworkParams::kind_from_id("X").unwrap(), NetworkKind::Test);
+        assert_eq!(NetworkParams::kind_from_id("A").unwrap(), NetworkKind::Dev);
+        assert!(NetworkParams::kind_from_id("CC").is_err());
+        assert!(NetworkParams::kind_from_id("Z").is_err());
+    }
+}
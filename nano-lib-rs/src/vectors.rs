@@ -0,0 +1,161 @@
+//! Known-answer tests against vectors of the official node, so that hashing, signing,
+//! work and addresses stay compatible with the rest of the network.
+//!
+//! The genesis blocks of the live and dev networks, the dev genesis key, and the key
+//! derived from the all-zero seed are those of the official node. The state block was
+//! built on the dev genesis block and hashed, signed and worked by a separate
+//! implementation of the official rules.
+use data_encoding::HEXUPPER;
+
+use nanopow_rs::{self, Work};
+use nanopow_rs::difficulty::EPOCH_1;
+
+use block::{Block, BlockHash, BlockKind, BlockPayload, Link};
+use keys::{deterministic_key, public_key_from_secret, sign_message, verify_signature, Address, PublicKey, SecretKey,
+    Signature, SEED_LENGTH};
+use network::DEV_WORK_THRESHOLD;
+
+struct OpenVector {
+    account: &'static str,
+    address: &'static str,
+    hash: &'static str,
+    signature: &'static str,
+    work: &'static str,
+    work_value: u64,
+}
+
+const LIVE_GENESIS: OpenVector = OpenVector {
+    account: "E89208DD038FBB269987689621D52292AE9C35941A7484756ECCED92A65093BA",
+    address: "nano_3t6k35gi95xu6tergt6p69ck76ogmitsa8mnijtpxm9fkcm736xtoncuohr3",
+    hash: "991CF190094C00F0B68E2E5F75F6BEE95A2E0BD93CEAA4A6734DB9F19B728948",
+    signature: "9F0C933C8ADE004D808EA1985FA746A7E95BA2A38F867640F53EC8F180BDFE9E\
+                2C1268DEAD7C2664F356E37ABA362BC58E46DBA03E523A7B5A19E4B6EB12BB02",
+    work: "62f05417dd3fb691",
+    work_value: 0xfffffff4000d3dac,
+};
+
+const DEV_GENESIS: OpenVector = OpenVector {
+    account: "B0311EA55708D6A53C75CDBF88300259C6D018522FE3D4D0A242E431F9E8B6D0",
+    address: "nano_3e3j5tkog48pnny9dmfzj1r16pg8t1e76dz5tmac6iq689wyjfpiij4txtdo",
+    hash: "04270D7F11C4B2B472F2854C5A59F2A7E84226CE9ED799DE75744BD7D85FC9D9",
+    signature: "ECDA914373A2F0CA1296475BAEE40500A7F0A7AD72A5A80C81D7FAB7F6C802B2\
+                CC7DB50F5DD0FB25B2EF11761FA7344A158DD5A700B21BD47DE5BD0F63153A02",
+    work: "7b42a00ee91d5810",
+    work_value: 0xff1475ba5c832b89,
+};
+
+const DEV_GENESIS_SECRET: &str = "34F0A37AAD20F4A260F0A5B3CB3D7FB50673212263E58A380BC10474BB039CE4";
+
+/// The key at index 0 of the all-zero seed
+const ZERO_SEED_SECRET: &str = "9F0E444C69F77A49BD0BE89DB92C38FE713E0963165CCA12FAF5712D7657120F";
+const ZERO_SEED_ACCOUNT: &str = "C008B814A7D269A1FA3C6528B19201A24D797912DB9996FF02A1FF356E45552B";
+const ZERO_SEED_ADDRESS: &str = "nano_3i1aq1cchnmbn9x5rsbap8b15akfh7wj7pwskuzi7ahz8oq6cobd99d4r3b7";
+
+/// A send of 1 nano (10^30 raw) from the dev genesis account to `ZERO_SEED_ACCOUNT`
+const STATE_SEND_HASH: &str = "D44399D1CF1F28929DBCB7258D1042723629D5AEFC078FEE7F2F1759CD9F66F0";
+const STATE_SEND_SIGNATURE: &str = "DB76B208215CE3189A3545EB52CF6FBF57153DE359BBB90E48A47BAFFC8F5BD5\
+                                    21C640DB5A06ABD7E527158DDD76B091198410020A2808FA7CB299407A934205";
+const STATE_SEND_WORK: &str = "000000000000019b";
+const STATE_SEND_WORK_VALUE: u64 = 0xff856c87b6de1263;
+
+fn bytes(hex: &str) -> Vec<u8> {
+    HEXUPPER.decode(hex.as_bytes()).unwrap()
+}
+
+fn key(hex: &str) -> PublicKey {
+    PublicKey::from_bytes(&bytes(hex)).unwrap()
+}
+
+fn secret(hex: &str) -> SecretKey {
+    SecretKey::from_bytes(&bytes(hex)).unwrap()
+}
+
+fn signature(hex: &str) -> Signature {
+    Signature::from_bytes(&bytes(hex)).unwrap()
+}
+
+/// A genesis block opens its account with itself as the source and representative
+fn genesis(vector: &OpenVector) -> Block {
+    let account = key(vector.account);
+    Block::new(BlockKind::Open, Some(BlockPayload::Open {
+        source: BlockHash::from_bytes(account.as_bytes()).unwrap(),
+        representative: account.clone(),
+        account,
+    }), Some(signature(vector.signature)), Some(Work::from_hex(vector.work).unwrap()))
+}
+
+fn state_send() -> Block {
+    let account = key(DEV_GENESIS.account);
+    Block::new(BlockKind::State, Some(BlockPayload::State {
+        account: account.clone(),
+        previous: BlockHash::from_hex(DEV_GENESIS.hash).unwrap(),
+        representative: account,
+        balance: u128::max_value() - 1_000_000_000_000_000_000_000_000_000_000,
+        link: Link::Unknown(*key(ZERO_SEED_ACCOUNT).as_bytes()),
+    }), Some(signature(STATE_SEND_SIGNATURE)), Some(Work::from_hex(STATE_SEND_WORK).unwrap()))
+}
+
+fn work_value(block: &Block) -> u64 {
+    nanopow_rs::work_value(&block.payload.as_ref().unwrap().work_source(), &block.work().unwrap())
+}
+
+#[test]
+fn block_hashes() {
+    for vector in &[LIVE_GENESIS, DEV_GENESIS] {
+        assert_eq!(String::from(genesis(vector).hash(true).unwrap()), vector.hash);
+    }
+    assert_eq!(String::from(state_send().hash(true).unwrap()), STATE_SEND_HASH);
+}
+
+#[test]
+fn block_signatures() {
+    for vector in &[LIVE_GENESIS, DEV_GENESIS] {
+        assert!(genesis(vector).verify_signature(&key(vector.account)).unwrap());
+    }
+    assert!(state_send().verify_signature(&key(DEV_GENESIS.account)).unwrap());
+    // A signature of one block doesn't pass for another's
+    let mut forged = state_send();
+    forged.signature = Some(signature(DEV_GENESIS.signature));
+    assert!(!forged.verify_signature(&key(DEV_GENESIS.account)).unwrap());
+}
+
+#[test]
+fn signing_is_deterministic() {
+    let secret = secret(DEV_GENESIS_SECRET);
+    assert_eq!(public_key_from_secret(&secret).as_bytes(), key(DEV_GENESIS.account).as_bytes());
+    let hash = BlockHash::from_hex(DEV_GENESIS.hash).unwrap();
+    assert_eq!(sign_message(&secret, hash.as_bytes()).to_bytes()[..], bytes(DEV_GENESIS.signature)[..]);
+    let hash = BlockHash::from_hex(STATE_SEND_HASH).unwrap();
+    let signature = sign_message(&secret, hash.as_bytes());
+    assert_eq!(signature.to_bytes()[..], bytes(STATE_SEND_SIGNATURE)[..]);
+    assert!(verify_signature(&key(DEV_GENESIS.account), hash.as_bytes(), &signature));
+}
+
+#[test]
+fn work_values() {
+    let live = genesis(&LIVE_GENESIS);
+    assert_eq!(work_value(&live), LIVE_GENESIS.work_value);
+    assert!(work_value(&live) >= EPOCH_1);
+    assert_eq!(work_value(&genesis(&DEV_GENESIS)), DEV_GENESIS.work_value);
+    let send = state_send();
+    assert_eq!(work_value(&send), STATE_SEND_WORK_VALUE);
+    assert!(work_value(&send) >= DEV_WORK_THRESHOLD && work_value(&send) < EPOCH_1);
+}
+
+#[test]
+fn deterministic_keys() {
+    let secret = deterministic_key(&[0u8; SEED_LENGTH], 0);
+    assert_eq!(secret.as_bytes()[..], bytes(ZERO_SEED_SECRET)[..]);
+    assert_eq!(public_key_from_secret(&secret).as_bytes(), key(ZERO_SEED_ACCOUNT).as_bytes());
+}
+
+#[test]
+fn addresses() {
+    for &(account, address) in &[(LIVE_GENESIS.account, LIVE_GENESIS.address),
+        (DEV_GENESIS.account, DEV_GENESIS.address), (ZERO_SEED_ACCOUNT, ZERO_SEED_ADDRESS)]
+    {
+        assert_eq!(Address::from(key(account)).0, address);
+        let legacy = address.replace("nano_", "xrb_");
+        assert_eq!(legacy.parse::<Address>().unwrap().to_public_key().unwrap().as_bytes(), key(account).as_bytes());
+    }
+}
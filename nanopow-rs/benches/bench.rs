@@ -4,7 +4,7 @@ extern crate nanopow_rs;
 
 use criterion::Criterion;
 
-use nanopow_rs::{generate_work, InputHash};
+use nanopow_rs::{check_work, generate_work, InputHash, Work};
 
 fn generate_unlimited(c: &mut Criterion) {
     c.bench_function("generate unlimited", |b| {
@@ -13,9 +13,17 @@ fn generate_unlimited(c: &mut Criterion) {
     });
 }
 
+fn check(c: &mut Criterion) {
+    c.bench_function("check work", |b| {
+      let hash = InputHash::from_hex("47F694A96653EB497709490776E492EFBB88EBC5C4E95CC0B2C9DCAB1930C36B").unwrap();
+      let work = Work::from_hex("2bf29ef00786a6bc").unwrap();
+      b.iter(|| check_work(&hash, &work))
+    });
+}
+
 criterion_group!{
   name = benches;
   config = Criterion::default().sample_size(10);
-  targets = generate_unlimited
+  targets = generate_unlimited, check
 }
 criterion_main!(benches);
\ No newline at end of file
@@ -0,0 +1,116 @@
+//! Conversions between work thresholds, multipliers and protocol epochs.
+//!
+//! A difficulty is compared directly against a work value; a multiplier expresses
+//! how many times more hashes are expected to be needed to reach one difficulty
+//! than another (the "base").
+
+/// The epoch an account chain has been upgraded to, which determines its work thresholds
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Epoch {
+    /// Legacy and epoch 1 blocks, which use a single threshold
+    One,
+    /// Epoch 2 blocks, which use a lower threshold for receives
+    Two,
+}
+
+/// The kind of state transition a block performs, which determines its work threshold
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockDetails {
+    /// Sends and representative changes
+    Send,
+    /// Receives and opens
+    Receive,
+    /// Epoch upgrade blocks
+    Epoch,
+}
+
+/// The threshold for all blocks before epoch 2
+pub const EPOCH_1: u64 = 0xffffffc000000000;
+/// The epoch 2 threshold for sends, changes and epoch blocks
+pub const EPOCH_2_SEND: u64 = 0xfffffff800000000;
+/// The epoch 2 threshold for receives and opens
+pub const EPOCH_2_RECEIVE: u64 = 0xfffffe0000000000;
+
+/// The minimum difficulty a block must reach, given its epoch and kind
+pub fn threshold(epoch: Epoch, details: BlockDetails) -> u64 {
+    match (epoch, details) {
+        (Epoch::One, _) => EPOCH_1,
+        (Epoch::Two, BlockDetails::Receive) => EPOCH_2_RECEIVE,
+        (Epoch::Two, _) => EPOCH_2_SEND,
+    }
+}
+
+/// The lowest threshold any block can be valid at
+pub fn entry_threshold() -> u64 {
+    EPOCH_2_RECEIVE
+}
+
+/// How many times harder `difficulty` is to reach than `base`
+pub fn to_multiplier(difficulty: u64, base: u64) -> f64 {
+    base.wrapping_neg() as f64 / difficulty.wrapping_neg() as f64
+}
+
+/// The difficulty which is `multiplier` times harder to reach than `base`, saturating at
+/// the hardest difficulty for multipliers too large to represent
+pub fn from_multiplier(multiplier: f64, base: u64) -> u64 {
+    let reverse = base.wrapping_neg() as f64 / multiplier;
+    // A reverse below 1 would wrap around to the easiest difficulty of 0
+    (reverse as u64).max(1).wrapping_neg()
+}
+
+/// Express a multiplier relative to `from` as a multiplier relative to `to`
+pub fn normalize_multiplier(multiplier: f64, from: u64, to: u64) -> f64 {
+    to_multiplier(from_multiplier(multiplier, from), to)
+}
+
+/// Format a difficulty the way it is written in RPCs and configs (16 lowercase hex digits)
+pub fn to_hex(difficulty: u64) -> String {
+    format!("{:016x}", difficulty)
+}
+
+/// Parse a difficulty written as 16 hex digits, with or without a `0x` prefix
+pub fn from_hex<T: AsRef<str>>(s: T) -> Option<u64> {
+    let s = s.as_ref();
+    let s = if s.starts_with("0x") { &s[2..] } else { s };
+    if s.len() != 16 {
+        return None;
+    }
+    u64::from_str_radix(s, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn epoch_thresholds() {
+        assert_eq!(threshold(Epoch::One, BlockDetails::Receive), EPOCH_1);
+        assert_eq!(threshold(Epoch::Two, BlockDetails::Send), EPOCH_2_SEND);
+        assert_eq!(threshold(Epoch::Two, BlockDetails::Epoch), EPOCH_2_SEND);
+        assert_eq!(threshold(Epoch::Two, BlockDetails::Receive), EPOCH_2_RECEIVE);
+        assert_eq!(to_multiplier(EPOCH_2_SEND, EPOCH_1), 8.0);
+        assert_eq!(to_multiplier(EPOCH_2_RECEIVE, EPOCH_1), 1.0 / 8.0);
+    }
+
+    #[test]
+    fn normalizes_multipliers() {
+        assert_eq!(normalize_multiplier(1.0, EPOCH_2_SEND, EPOCH_1), 8.0);
+        assert_eq!(normalize_multiplier(8.0, EPOCH_1, EPOCH_2_SEND), 1.0);
+        assert_eq!(normalize_multiplier(2.0, EPOCH_2_RECEIVE, EPOCH_2_RECEIVE), 2.0);
+    }
+
+    #[test]
+    fn saturates_huge_multipliers() {
+        assert_eq!(from_multiplier(1e30, EPOCH_1), u64::max_value());
+        assert_eq!(from_multiplier(::std::f64::INFINITY, EPOCH_1), u64::max_value());
+    }
+
+    #[test]
+    fn hex_round_trips() {
+        assert_eq!(to_hex(EPOCH_2_RECEIVE), "fffffe0000000000");
+        assert_eq!(from_hex("fffffe0000000000"), Some(EPOCH_2_RECEIVE));
+        assert_eq!(from_hex("0xffffffc000000000"), Some(EPOCH_1));
+        assert_eq!(from_hex("ffffffc00000000"), None);
+        assert_eq!(from_hex("ffffffc00000000g"), None);
+    }
+}
@@ -17,9 +17,16 @@ error_chain! {
       description("attempted to create Work with invalid length")
       display("Attempted to create Work with invalid length")
     }
+
+    /// The configured OpenCL platform or device does not exist
+    GpuDeviceNotFoundError(platform: usize, device: usize) {
+      description("OpenCL device not found")
+      display("OpenCL device {} on platform {} not found", device, platform)
+    }
   }
 
   foreign_links {
     FormatError(::std::fmt::Error) #[doc = "A formatting error occured"];
+    OclError(::ocl::Error) #[cfg(feature = "opencl")] #[doc = "An error occurred in OpenCL"];
   }
 }
\ No newline at end of file
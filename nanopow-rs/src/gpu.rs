@@ -0,0 +1,193 @@
+//! OpenCL work generation, enabled with the `opencl` feature.
+//!
+//! The kernel evaluates one nonce per work item; the host repeatedly enqueues
+//! batches of `threads` nonces until one meets the network threshold or the
+//! generation is cancelled.
+use ocl::{Buffer, Device, Kernel, Platform, ProQue};
+use rand;
+
+use super::{check_work_threshold, CancelHandle, InputHash, Work, THRESHOLD_VALUE};
+use error::*;
+
+const KERNEL_SRC: &str = r#"
+__constant static const ulong blake2b_IV[8] = {
+    0x6a09e667f3bcc908UL, 0xbb67ae8584caa73bUL, 0x3c6ef372fe94f82bUL, 0xa54ff53a5f1d36f1UL,
+    0x510e527fade682d1UL, 0x9b05688c2b3e6c1fUL, 0x1f83d9abfb41bd6bUL, 0x5be0cd19137e2179UL
+};
+
+__constant static const uchar blake2b_sigma[12][16] = {
+    {  0,  1,  2,  3,  4,  5,  6,  7,  8,  9, 10, 11, 12, 13, 14, 15 },
+    { 14, 10,  4,  8,  9, 15, 13,  6,  1, 12,  0,  2, 11,  7,  5,  3 },
+    { 11,  8, 12,  0,  5,  2, 15, 13, 10, 14,  3,  6,  7,  1,  9,  4 },
+    {  7,  9,  3,  1, 13, 12, 11, 14,  2,  6,  5, 10,  4,  0, 15,  8 },
+    {  9,  0,  5,  7,  2,  4, 10, 15, 14,  1, 11, 12,  6,  8,  3, 13 },
+    {  2, 12,  6, 10,  0, 11,  8,  3,  4, 13,  7,  5, 15, 14,  1,  9 },
+    { 12,  5,  1, 15, 14, 13,  4, 10,  0,  7,  6,  3,  9,  2,  8, 11 },
+    { 13, 11,  7, 14, 12,  1,  3,  9,  5,  0, 15,  4,  8,  6,  2, 10 },
+    {  6, 15, 14,  9, 11,  3,  0,  8, 12,  2, 13,  7,  1,  4, 10,  5 },
+    { 10,  2,  8,  4,  7,  6,  1,  5, 15, 11,  9, 14,  3, 12, 13,  0 },
+    {  0,  1,  2,  3,  4,  5,  6,  7,  8,  9, 10, 11, 12, 13, 14, 15 },
+    { 14, 10,  4,  8,  9, 15, 13,  6,  1, 12,  0,  2, 11,  7,  5,  3 }
+};
+
+#define ROTR64(x, n) rotate((x), (ulong)(64 - (n)))
+
+#define G(r, i, a, b, c, d)                          \
+    a = a + b + m[blake2b_sigma[r][2 * i]];          \
+    d = ROTR64(d ^ a, 32);                           \
+    c = c + d;                                       \
+    b = ROTR64(b ^ c, 24);                           \
+    a = a + b + m[blake2b_sigma[r][2 * i + 1]];      \
+    d = ROTR64(d ^ a, 16);                           \
+    c = c + d;                                       \
+    b = ROTR64(b ^ c, 63);
+
+__kernel void nano_work(__global const ulong *attempt, __global ulong *result,
+                        __global const uchar *root, const ulong threshold)
+{
+    ulong nonce = *attempt + get_global_id(0);
+    ulong m[16] = { 0 };
+    m[0] = nonce;
+    for (int w = 0; w < 4; ++w) {
+        ulong word = 0;
+        for (int b = 7; b >= 0; --b) {
+            word = (word << 8) | root[w * 8 + b];
+        }
+        m[w + 1] = word;
+    }
+
+    // Parameter block: 8 byte digest, no key, fanout and depth of 1
+    ulong h0 = blake2b_IV[0] ^ 0x01010008UL;
+    ulong v[16] = {
+        h0, blake2b_IV[1], blake2b_IV[2], blake2b_IV[3],
+        blake2b_IV[4], blake2b_IV[5], blake2b_IV[6], blake2b_IV[7],
+        blake2b_IV[0], blake2b_IV[1], blake2b_IV[2], blake2b_IV[3],
+        blake2b_IV[4] ^ 40UL, blake2b_IV[5], ~blake2b_IV[6], blake2b_IV[7]
+    };
+
+    for (int r = 0; r < 12; ++r) {
+        G(r, 0, v[0], v[4], v[8], v[12]);
+        G(r, 1, v[1], v[5], v[9], v[13]);
+        G(r, 2, v[2], v[6], v[10], v[14]);
+        G(r, 3, v[3], v[7], v[11], v[15]);
+        G(r, 4, v[0], v[5], v[10], v[15]);
+        G(r, 5, v[1], v[6], v[11], v[12]);
+        G(r, 6, v[2], v[7], v[8], v[13]);
+        G(r, 7, v[3], v[4], v[9], v[14]);
+    }
+
+    if ((h0 ^ v[0] ^ v[8]) >= threshold) {
+        *result = nonce;
+    }
+}
+"#;
+
+/// Selects the OpenCL device used for work generation
+#[derive(Clone, Copy, Debug)]
+pub struct GpuConfig {
+    /// Index of the OpenCL platform, as returned by `Platform::list`
+    pub platform: usize,
+    /// Index of the device within the platform
+    pub device: usize,
+    /// Number of nonces evaluated per kernel invocation
+    pub threads: usize,
+}
+
+impl Default for GpuConfig {
+    fn default() -> Self {
+        GpuConfig {
+            platform: 0,
+            device: 0,
+            threads: 1024 * 1024,
+        }
+    }
+}
+
+/// A work generator backed by a single OpenCL device
+pub struct GpuWorkGenerator {
+    config: GpuConfig,
+    kernel: Kernel,
+    attempt: Buffer<u64>,
+    result: Buffer<u64>,
+    root: Buffer<u8>,
+}
+
+impl GpuWorkGenerator {
+    /// Compile the work kernel for the configured device
+    pub fn new(config: GpuConfig) -> Result<Self> {
+        let platform = Platform::list()
+            .get(config.platform)
+            .cloned()
+            .ok_or(ErrorKind::GpuDeviceNotFoundError(config.platform, config.device))?;
+        let device = Device::list_all(&platform)?
+            .get(config.device)
+            .cloned()
+            .ok_or(ErrorKind::GpuDeviceNotFoundError(config.platform, config.device))?;
+        let pro_que = ProQue::builder()
+            .src(KERNEL_SRC)
+            .platform(platform)
+            .device(device)
+            .dims(config.threads)
+            .build()?;
+        let attempt = Buffer::<u64>::builder()
+            .queue(pro_que.queue().clone())
+            .len(1)
+            .fill_val(0u64)
+            .build()?;
+        let result = Buffer::<u64>::builder()
+            .queue(pro_que.queue().clone())
+            .len(1)
+            .fill_val(0u64)
+            .build()?;
+        let root = Buffer::<u8>::builder()
+            .queue(pro_que.queue().clone())
+            .len(32)
+            .fill_val(0u8)
+            .build()?;
+        let kernel = pro_que.kernel_builder("nano_work")
+            .arg(&attempt)
+            .arg(&result)
+            .arg(&root)
+            .arg(THRESHOLD_VALUE)
+            .build()?;
+        Ok(GpuWorkGenerator {
+            config,
+            kernel,
+            attempt,
+            result,
+            root,
+        })
+    }
+
+    /// The device configuration this generator was created with
+    pub fn config(&self) -> GpuConfig {
+        self.config
+    }
+
+    /// Attempts to generate work meeting `threshold` for a given `InputHash`, checking for
+    /// cancellation between kernel invocations. Returns `Ok(None)` if cancelled.
+    pub fn generate(&self, hash: &InputHash, threshold: u64, cancel: &CancelHandle) -> Result<Option<Work>> {
+        self.kernel.set_arg(3, threshold)?;
+        self.root.write(&hash.as_bytes()[..]).enq()?;
+        self.result.write(&[0u64][..]).enq()?;
+        let mut attempt = rand::random::<u64>();
+        let mut found = [0u64];
+        while !cancel.is_cancelled() {
+            self.attempt.write(&[attempt][..]).enq()?;
+            unsafe {
+                self.kernel.enq()?;
+            }
+            self.result.read(&mut found[..]).enq()?;
+            if found[0] != 0 {
+                let work = Work(found[0]);
+                if check_work_threshold(hash, &work, threshold) {
+                    return Ok(Some(work));
+                }
+                // Should never happen unless the device is faulty; keep searching
+                self.result.write(&[0u64][..]).enq()?;
+            }
+            attempt = attempt.wrapping_add(self.config.threads as u64);
+        }
+        Ok(None)
+    }
+}
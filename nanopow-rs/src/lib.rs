@@ -1,5 +1,6 @@
 //! Small Rust library to generate proof of work for the Nano cryptocurrency.
-//! Fully parallelized on the CPU. The goal is for this to be easily includable
+//! Fully parallelized on the CPU, with a configurable thread count and cooperative
+//! cancellation. The goal is for this to be easily includable
 //! in web applications as WASM using Parcel and stdweb. Currently, it works best
 //! as a native POW generation tool used in Rust and Node apps through
 //! [nanopow-rs-node](https://github.com/termhn/nanopow-rs-node)
@@ -17,6 +18,8 @@ extern crate num_cpus;
 extern crate byteorder;
 #[macro_use]
 extern crate lazy_static;
+#[cfg(feature = "opencl")]
+extern crate ocl;
 
 use blake2::{Blake2b};
 use blake2::digest::{Input, VariableOutput};
@@ -28,13 +31,27 @@ use rand::{XorShiftRng, Rng, SeedableRng};
 use byteorder::{ByteOrder, LittleEndian, BigEndian};
 
 use std::fmt;
+use std::cmp;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 /// Error types, using error-chain
 pub mod error;
 use error::*;
 
+/// Conversions between thresholds, multipliers and epochs
+pub mod difficulty;
+pub use difficulty::{to_multiplier, from_multiplier};
+
+/// OpenCL work generation
+#[cfg(feature = "opencl")]
+pub mod gpu;
+
 const THRESHOLD_STR: &[u8] = b"ffffffc000000000";
 
+/// The network threshold as a number. Work is valid if its work value is at least this.
+pub const THRESHOLD_VALUE: u64 = difficulty::EPOCH_1;
+
 lazy_static! {
     /// The network threshold
     pub static ref THRESHOLD: [u8; 8] = {
@@ -83,7 +100,7 @@ impl fmt::Display for Work {
 
 
 /// A 32 byte array used to represent a valid input hash
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct InputHash([u8; 32]);
 
 impl InputHash
@@ -144,10 +161,8 @@ impl fmt::Display for InputHash {
     }
 }
 
-fn check_result_threshold(hash: &[u8; 8]) -> bool {
-    (&hash).iter().rev().enumerate().fold(true, |acc, (i, &byte)| {
-        acc && byte >= THRESHOLD[i]
-    })
+fn check_result_threshold(hash: &[u8; 8], threshold: u64) -> bool {
+    LittleEndian::read_u64(&hash[..]) >= threshold
 }
 
 fn hash_work_internal(work: &[u8], hash: &[u8]) -> [u8; 8] {
@@ -159,71 +174,175 @@ fn hash_work_internal(work: &[u8], hash: &[u8]) -> [u8; 8] {
     output
 }
 
+/// A handle used to cooperatively cancel an in-progress work generation.
+///
+/// Cloned handles share the same underlying flag, so a handle can be given to a
+/// `WorkGenerator` and cancelled from any other thread.
+#[derive(Clone, Debug, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    /// Create a new, un-cancelled handle
+    pub fn new() -> Self {
+        CancelHandle(Arc::new(AtomicBool::new(false)))
+    }
+
+    /// Signal all generators using this handle to stop
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether `cancel` has been called on this handle (or any of its clones)
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Builder-style configuration for a multithreaded work search
+#[derive(Clone, Debug)]
+pub struct WorkGenerator {
+    threads: usize,
+    max_iters: Option<u64>,
+    threshold: u64,
+    cancel: CancelHandle,
+    seed: Option<u64>,
+}
+
+impl WorkGenerator {
+    /// Create a generator which uses one thread per logical CPU and never gives up
+    pub fn new() -> Self {
+        WorkGenerator {
+            threads: num_cpus::get(),
+            max_iters: None,
+            threshold: THRESHOLD_VALUE,
+            cancel: CancelHandle::new(),
+            seed: None,
+        }
+    }
+
+    /// Set the number of threads used to search for a nonce (at least one is always used)
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = cmp::max(threads, 1);
+        self
+    }
+
+    /// Give up after roughly `max_iters` attempts split across all threads
+    pub fn with_max_iters(mut self, max_iters: u64) -> Self {
+        self.max_iters = Some(max_iters);
+        self
+    }
+
+    /// Search for work meeting `threshold` rather than the network minimum
+    pub fn with_threshold(mut self, threshold: u64) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Use the given handle to allow cancelling the generation from another thread
+    pub fn with_cancel_handle(mut self, cancel: CancelHandle) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Draw nonces from generators seeded by `seed` rather than from the OS, for tests
+    /// which must be repeatable. With one thread the same work is found every time;
+    /// with more, which thread finds work first still varies.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// The number of threads this generator will use
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Attempts to generate valid work for a given `InputHash`. Returns `None` if the
+    /// maximum number of iterations was reached or the generation was cancelled.
+    pub fn generate(&self, hash: &InputHash) -> Option<Work> {
+        generate_work_internal(&hash.0[..], self.threads, self.max_iters, self.threshold, &self.cancel, self.seed)
+            .map(|w| Work(LittleEndian::read_u64(&w)))
+    }
+}
+
+impl Default for WorkGenerator {
+    fn default() -> Self {
+        WorkGenerator::new()
+    }
+}
+
 /// Attempts to generate valid work for a given `InputHash` (usually a block hash or public key)
 /// with optional maximum iterations
 pub fn generate_work(hash: &InputHash, max_iters: Option<u64>) -> Option<Work> {
-    let hash = hash.0;
-    if let Some(w) = generate_work_internal(&hash[..], max_iters) {
-        let work = LittleEndian::read_u64(&w);
-        Some(Work(work))
-    } else {
-        None
+    let generator = WorkGenerator::new();
+    match max_iters {
+        Some(iters) => generator.with_max_iters(iters).generate(hash),
+        None => generator.generate(hash),
     }
 }
 
-fn generate_work_internal(hash: &[u8], max_iters: Option<u64>) -> Option<[u8; 8]> {
-    let numcpus = num_cpus::get();
-    let (tx,rx) = crossbeam_channel::bounded::<Option<[u8; 8]>>(numcpus);
-    let (donetx, donerx) = crossbeam_channel::bounded::<bool>(numcpus);
+fn generate_work_internal(hash: &[u8], threads: usize, max_iters: Option<u64>, threshold: u64, cancel: &CancelHandle,
+    seed: Option<u64>) -> Option<[u8; 8]>
+{
+    let (tx, rx) = crossbeam_channel::bounded::<Option<[u8; 8]>>(threads);
+    let done = AtomicBool::new(false);
     let has_max_iters = max_iters.is_some();
-    let max_iters = max_iters.unwrap_or(numcpus as u64 * 2);
+    let iters_per_thread = max_iters.unwrap_or(0) / threads as u64;
     crossbeam_utils::scoped::scope(|scope| {
-        for _ in 0..numcpus {
-            scope.spawn(|| {
-                let mut rng: XorShiftRng = SeedableRng::from_seed(rand::random::<[u32; 4]>());
+        for index in 0..threads {
+            let tx = tx.clone();
+            let done = &done;
+            scope.spawn(move || {
+                let mut rng: XorShiftRng = match seed {
+                    // Each thread searches from its own seed, and XorShift can't be
+                    // seeded with zeros, which the last word rules out
+                    Some(seed) => SeedableRng::from_seed([seed as u32, (seed >> 32) as u32, index as u32, 0x9e37_79b9]),
+                    None => SeedableRng::from_seed(rand::random::<[u32; 4]>()),
+                };
                 let mut work = [0u8; 8];
                 let mut iters = 0u64;
                 let mut result_valid = false;
-                let mut done = donerx.try_recv().unwrap_or(false);
-                while !result_valid && !done && iters < max_iters/numcpus as u64 {
+                while !result_valid && !(has_max_iters && iters >= iters_per_thread) {
+                    if done.load(Ordering::Relaxed) || cancel.is_cancelled() {
+                        break;
+                    }
                     work = rng.gen::<[u8; 8]>();
                     let output = hash_work_internal(&work[..], hash);
-                    result_valid = check_result_threshold(&output);
-                    if has_max_iters {
-                        iters += 1;
-                    }
-                    done = donerx.try_recv().unwrap_or(false);
-                }
-                if done {
-                    return;
-                }
-                if result_valid {
-                    let _ = tx.send(Some(work)).is_ok();
-                } else {
-                    let _ = tx.send(None).is_ok();
+                    result_valid = check_result_threshold(&output, threshold);
+                    iters += 1;
                 }
+                // The channel has room for one message per thread, so this never blocks
+                let _ = tx.send(if result_valid { Some(work) } else { None });
             });
         }
-        let mut res = rx.recv().unwrap();
-        let mut msgs_resvd = 0;
-        while res.is_none() && msgs_resvd < numcpus-1 {
-            res = rx.recv().unwrap();
-            msgs_resvd += 1;
-        }
-        for _ in 0..numcpus {
-            donetx.send(true).unwrap();
+        let mut res = None;
+        for _ in 0..threads {
+            if let Some(work) = rx.recv().unwrap() {
+                res = Some(work);
+                break;
+            }
         }
+        done.store(true, Ordering::SeqCst);
         res
     })
 }
 
 /// Checks if a given `Work` value is valid for a given `InputHash` (usually a block hash or public key)
 pub fn check_work(hash: &InputHash, work: &Work) -> bool {
-    let hash = hash.0;
+    check_work_threshold(hash, work, THRESHOLD_VALUE)
+}
+
+/// Checks if a given `Work` value meets `threshold` for a given `InputHash`
+pub fn check_work_threshold(hash: &InputHash, work: &Work, threshold: u64) -> bool {
+    work_value(hash, work) >= threshold
+}
+
+/// The difficulty achieved by `work` for `hash`, comparable against thresholds
+pub fn work_value(hash: &InputHash, work: &Work) -> u64 {
     let mut work_bytes = [0u8; 8];
     LittleEndian::write_u64(&mut work_bytes, work.0);
-    let value = hash_work_internal(&work_bytes, &hash);
-    check_result_threshold(&value)
+    let value = hash_work_internal(&work_bytes, &hash.0);
+    LittleEndian::read_u64(&value)
 }
 
 #[cfg(test)]
@@ -286,4 +405,62 @@ mod tests {
         let valid = check_work(&hash, &work);
         assert!(valid);
     }
+
+    #[test]
+    fn seeded_generation_is_repeatable() {
+        let hash = InputHash::from_hex("47F694A96653EB497709490776E492EFBB88EBC5C4E95CC0B2C9DCAB1930C36B").unwrap();
+        let generator = WorkGenerator::new().with_threads(1).with_threshold(0xff00000000000000).with_seed(7);
+        let work = generator.generate(&hash).unwrap();
+        assert_eq!(generator.generate(&hash), Some(work));
+        assert!(check_work_threshold(&hash, &work, 0xff00000000000000));
+    }
+
+    #[test]
+    fn computes_work_value() {
+        let hash = InputHash::from_hex("8D3E5F07BFF7B7484CDCB392F47009F62997253D28BD98B94BCED95F03C4DA09").unwrap();
+        let work = Work::from_hex("4effb6b0cd5625e2").unwrap();
+        let value = work_value(&hash, &work);
+        assert!(value >= THRESHOLD_VALUE);
+        assert!(check_work_threshold(&hash, &work, value));
+        assert!(!check_work_threshold(&hash, &work, value + 1));
+    }
+
+    #[test]
+    fn converts_multipliers() {
+        assert_eq!(to_multiplier(THRESHOLD_VALUE, THRESHOLD_VALUE), 1.0);
+        assert_eq!(to_multiplier(0xfffffff800000000, THRESHOLD_VALUE), 8.0);
+        assert_eq!(from_multiplier(8.0, THRESHOLD_VALUE), 0xfffffff800000000);
+        assert_eq!(from_multiplier(1.0 / 64.0, THRESHOLD_VALUE), 0xfffff00000000000);
+    }
+
+    #[test]
+    fn generates_work_for_higher_threshold() {
+        let hash = InputHash::from_hex("47F694A96653EB497709490776E492EFBB88EBC5C4E95CC0B2C9DCAB1930C36B").unwrap();
+        let threshold = from_multiplier(2.0, THRESHOLD_VALUE);
+        let work = WorkGenerator::new().with_threshold(threshold).generate(&hash).unwrap();
+        assert!(check_work_threshold(&hash, &work, threshold));
+    }
+
+    #[test]
+    fn generates_valid_work_single_thread() {
+        let hash = InputHash::from_hex("47F694A96653EB497709490776E492EFBB88EBC5C4E95CC0B2C9DCAB1930C36B").unwrap();
+        let work = WorkGenerator::new().with_threads(1).generate(&hash).unwrap();
+        assert!(check_work(&hash, &work));
+    }
+
+    #[test]
+    fn cancelled_generation_returns_none() {
+        let hash = InputHash::from_hex("47F694A96653EB497709490776E492EFBB88EBC5C4E95CC0B2C9DCAB1930C36B").unwrap();
+        let cancel = CancelHandle::new();
+        cancel.cancel();
+        let work = WorkGenerator::new().with_cancel_handle(cancel).generate(&hash);
+        assert!(work.is_none());
+    }
+
+    #[test]
+    fn gives_up_after_max_iters() {
+        let hash = InputHash::from_hex("47F694A96653EB497709490776E492EFBB88EBC5C4E95CC0B2C9DCAB1930C36B").unwrap();
+        let work = WorkGenerator::new().with_threads(2).with_max_iters(0).generate(&hash);
+        assert!(work.is_none());
+    }
 }
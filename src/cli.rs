@@ -0,0 +1,641 @@
+//! Offline key and account utilities, mirroring the official node's command line
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::Ipv6Addr;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{TimeZone, Utc};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use data_encoding::HEXUPPER;
+use rand::{OsRng, Rng};
+#[cfg(feature = "rpc")]
+use serde_json::{self, Value};
+
+use nano_lib_rs::amount::{Amount, Unit};
+use nano_lib_rs::block::BlockHash;
+use nano_lib_rs::keys::{public_key_from_secret, Address, SecretKey, ADDRESS_ALPHABET,
+    ADDRESS_PREFIX, LEGACY_ADDRESS_PREFIX, SECRET_KEY_LENGTH};
+use nano_lib_rs::network::NetworkParams;
+
+use crate::config::Config;
+use crate::error::*;
+use crate::logging;
+use crate::migrate;
+use crate::net::replay;
+use crate::node::exclusions::{self, Exclusion, Exclusions};
+use crate::node::rollback;
+use crate::store::{self, Backend, Store, StoreConfig};
+use crate::store::checksum;
+use crate::store::diff::{self, Differences};
+use crate::store::export::{self, DumpFormat};
+use crate::store::fast_sync;
+use crate::store::vacuum;
+use crate::payment::{PaymentRequest, QrFormat};
+use crate::utils::{account_to_address, address_to_account, parse_public_key};
+
+pub fn subcommands<'a, 'b>() -> Vec<App<'a, 'b>> {
+    #[allow(unused_mut)]
+    let mut commands = vec![
+        SubCommand::with_name("key_create")
+            .about("Generate a random keypair and print it with its account"),
+        SubCommand::with_name("key_expand")
+            .about("Print the public key and account for a private key")
+            .arg(Arg::with_name("key").required(true).help("Private key, as hex")),
+        SubCommand::with_name("account_get")
+            .about("Print the account for a public key")
+            .arg(Arg::with_name("key").required(true).help("Public key, as hex")),
+        SubCommand::with_name("account_key")
+            .about("Print the public key for an account")
+            .arg(Arg::with_name("account").required(true)),
+        SubCommand::with_name("validate_account")
+            .about("Check that an account is well formed and its checksum is correct")
+            .arg(Arg::with_name("account").required(true)),
+        SubCommand::with_name("payment_uri")
+            .about("Print a nano: URI requesting a payment to an account, optionally saving it as a QR code")
+            .arg(Arg::with_name("account").required(true))
+            .arg(Arg::with_name("amount")
+                .long("amount")
+                .takes_value(true)
+                .help("Amount to request, in the unit given by --unit"))
+            .arg(Arg::with_name("unit")
+                .long("unit")
+                .takes_value(true)
+                .default_value("nano")
+                .help("Unit of --amount: raw, rai, knano or nano (10^30 raw)"))
+            .arg(Arg::with_name("label").long("label").takes_value(true).help("Name of the recipient"))
+            .arg(Arg::with_name("message").long("message").takes_value(true))
+            .arg(Arg::with_name("qr")
+                .long("qr")
+                .takes_value(true)
+                .help("Save a QR code of the URI to this .png or .svg file")),
+        SubCommand::with_name("ledger_export")
+            .about("Write the ledger in the store set up by the configuration file to a JSON or CSV dump")
+            .arg(Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["json", "csv"])
+                .default_value("json"))
+            .arg(Arg::with_name("output")
+                .long("output")
+                .takes_value(true)
+                .help("File to write the dump to (defaults to standard output)"))
+            .arg(Arg::with_name("account")
+                .long("account")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Only export this account and its chain; may be repeated"))
+            .arg(read_only_arg()),
+        SubCommand::with_name("ledger_import")
+            .about("Read a JSON or CSV dump into the empty store set up by the configuration file")
+            .arg(Arg::with_name("file").required(true))
+            .arg(Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["json", "csv"])
+                .default_value("json")),
+        SubCommand::with_name("ledger_checksum")
+            .about("Print an order-independent checksum of the ledger in the store set up by the configuration file")
+            .arg(Arg::with_name("cemented")
+                .long("cemented")
+                .help("Also print a checksum of each account's confirmation height"))
+            .arg(read_only_arg()),
+        SubCommand::with_name("ledger_diff")
+            .about("Compare the ledger in the store set up by the configuration file with another, listing the \
+                blocks and accounts which differ")
+            .arg(Arg::with_name("other")
+                .required(true)
+                .help("Directory of another LMDB store, such as one migrated from an official node, or with --dump, \
+                    a dump written by ledger_export"))
+            .arg(Arg::with_name("dump").long("dump").help("Read the other ledger from a dump"))
+            .arg(Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["json", "csv"])
+                .default_value("json")
+                .help("Format of the dump"))
+            .arg(Arg::with_name("limit")
+                .long("limit")
+                .takes_value(true)
+                .default_value("100")
+                .help("Differences of each kind listed at most; all are counted"))
+            .arg(read_only_arg()),
+        SubCommand::with_name("ledger_rollback")
+            .about("Roll back a block and the blocks depending on it in the store set up by the configuration file; \
+                stop the node first")
+            .arg(Arg::with_name("hash").required(true).help("Hash of the first block to roll back"))
+            .arg(Arg::with_name("dry_run")
+                .long("dry-run")
+                .help("Only list the blocks which would be rolled back")),
+        SubCommand::with_name("database_vacuum")
+            .about("Compact the LMDB store set up by the configuration file, reclaiming the space freed by pruning \
+                and rollbacks; stop the node first")
+            .arg(Arg::with_name("output")
+                .long("output")
+                .takes_value(true)
+                .help("New directory to write the compacted store to"))
+            .arg(Arg::with_name("in_place")
+                .long("in-place")
+                .conflicts_with("output")
+                .help("Replace the store with the compacted copy, keeping the original as data.mdb.bak")),
+        SubCommand::with_name("peer_exclude")
+            .about("Exclude a host from peering, on every port; stop the node first, or use the peer_exclude RPC \
+                action while it runs")
+            .arg(Arg::with_name("address")
+                .required(true)
+                .help("IP address of the host, with or without a port"))
+            .arg(Arg::with_name("duration")
+                .long("duration")
+                .takes_value(true)
+                .help("Seconds until the exclusion expires (defaults to never)"))
+            .arg(Arg::with_name("reason")
+                .long("reason")
+                .takes_value(true)
+                .help("Why the host is excluded, for the record")),
+        SubCommand::with_name("peer_unexclude")
+            .about("Remove a host's exclusion; stop the node first, or use the peer_unexclude RPC action while it runs")
+            .arg(Arg::with_name("address")
+                .required(true)
+                .help("IP address of the host, with or without a port")),
+        SubCommand::with_name("peer_exclusions")
+            .about("List the hosts excluded from peering"),
+        SubCommand::with_name("fast_sync")
+            .about("Download and verify the ledger snapshot set up by the configuration file into its empty store"),
+        SubCommand::with_name("migrate")
+            .about("Convert an official node's data directory into a nano-rs data directory")
+            .arg(Arg::with_name("source")
+                .required(true)
+                .help("Data directory of the official node, holding data.ldb; stop the node first"))
+            .arg(Arg::with_name("destination")
+                .required(true)
+                .help("Directory to write the nano-rs store and nano-rs.toml to")),
+        SubCommand::with_name("replay")
+            .about("Replay the datagrams of a pcap capture through a node, against a copy of the ledger set up by the configuration file")
+            .arg(Arg::with_name("capture")
+                .required(true)
+                .help("pcap file, as written by the capture_start RPC action or by tcpdump"))
+            .arg(Arg::with_name("node")
+                .long("node")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Address and port the capture was taken at, whose incoming datagrams are replayed; may be \
+                    repeated (defaults to the node's listen addresses, as in captures from capture_start)"))
+            .arg(Arg::with_name("ledger")
+                .long("ledger")
+                .takes_value(true)
+                .help("New directory to copy the ledger into, kept afterwards (defaults to a temporary directory)")),
+        SubCommand::with_name("vanity")
+            .about("Search for a keypair whose account starts with a pattern")
+            .arg(Arg::with_name("pattern")
+                .required(true)
+                .help("Start of the account after the prefix, e.g. `3abc`; `*` matches any character"))
+            .arg(Arg::with_name("threads")
+                .long("threads")
+                .takes_value(true)
+                .help("Number of threads to search with (defaults to one per logical CPU)")),
+    ];
+    #[cfg(feature = "rpc")]
+    commands.push(SubCommand::with_name("diagnostics")
+        .about("Print a diagnostics snapshot from a running node, to attach to bug reports")
+        .arg(Arg::with_name("rpc")
+            .long("rpc")
+            .takes_value(true)
+            .default_value("[::1]:7076")
+            .help("RPC address of the node")));
+    commands
+}
+
+fn read_only_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("read_only")
+        .long("read-only")
+        .help("Open the store without writing to it, so that it can be read while the node runs")
+}
+
+/// Run the subcommand in `matches`, if any. Returns false if no subcommand was given.
+pub fn run(matches: &ArgMatches) -> Result<bool> {
+    match matches.subcommand() {
+        ("key_create", Some(_)) => key_create()?,
+        ("key_expand", Some(args)) => key_expand(args.value_of("key").unwrap())?,
+        ("account_get", Some(args)) => account_get(args.value_of("key").unwrap())?,
+        ("account_key", Some(args)) => account_key(args.value_of("account").unwrap())?,
+        ("validate_account", Some(args)) => validate_account(args.value_of("account").unwrap())?,
+        ("payment_uri", Some(args)) => payment_uri(args)?,
+        ("ledger_export", Some(args)) => ledger_export(matches, args)?,
+        ("ledger_import", Some(args)) => ledger_import(matches, args)?,
+        ("ledger_checksum", Some(args)) => ledger_checksum(matches, args)?,
+        ("ledger_diff", Some(args)) => ledger_diff(matches, args)?,
+        ("ledger_rollback", Some(args)) => ledger_rollback(matches, args)?,
+        ("database_vacuum", Some(args)) => database_vacuum(matches, args)?,
+        ("peer_exclude", Some(args)) => peer_exclude(matches, args)?,
+        ("peer_unexclude", Some(args)) => peer_unexclude(matches, args)?,
+        ("peer_exclusions", Some(_)) => peer_exclusions(matches)?,
+        ("fast_sync", Some(_)) => fast_sync(matches)?,
+        ("migrate", Some(args)) => migrate(args)?,
+        ("replay", Some(args)) => replay(matches, args)?,
+        #[cfg(feature = "rpc")]
+        ("diagnostics", Some(args)) => diagnostics(args.value_of("rpc").unwrap())?,
+        ("vanity", Some(args)) => {
+            let threads = match args.value_of("threads") {
+                Some(threads) => threads.parse().map_err(|_| Error::from("--threads must be a number"))?,
+                None => ::num_cpus::get(),
+            };
+            vanity(args.value_of("pattern").unwrap(), threads)?
+        },
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn decode_key(key: &str) -> Result<Vec<u8>> {
+    HEXUPPER.decode(key.to_uppercase().as_bytes())
+        .map_err(|_| Error::from(format!("`{}` is not a valid hex key", key)))
+}
+
+fn secret_key(bytes: &[u8]) -> Result<SecretKey> {
+    Ok(SecretKey::from_bytes(bytes).map_err(nano_lib_rs::error::Error::from)?)
+}
+
+fn print_keys(secret: &SecretKey) {
+    let public = public_key_from_secret(secret);
+    println!("Private: {}", HEXUPPER.encode(secret.as_bytes()));
+    println!("Public: {}", HEXUPPER.encode(public.as_bytes()));
+    println!("Account: {}", Address::from(public));
+}
+
+fn key_create() -> Result<()> {
+    let mut bytes = [0u8; SECRET_KEY_LENGTH];
+    OsRng::new()?.fill_bytes(&mut bytes);
+    print_keys(&secret_key(&bytes)?);
+    Ok(())
+}
+
+fn key_expand(key: &str) -> Result<()> {
+    print_keys(&secret_key(&decode_key(key)?)?);
+    Ok(())
+}
+
+fn account_get(key: &str) -> Result<()> {
+    println!("Account: {}", account_to_address(&parse_public_key(key)?));
+    Ok(())
+}
+
+fn account_key(account: &str) -> Result<()> {
+    let public = address_to_account(account)?;
+    println!("Hex: {}", HEXUPPER.encode(public.as_bytes()));
+    Ok(())
+}
+
+fn validate_account(account: &str) -> Result<()> {
+    address_to_account(account)?;
+    println!("Account is valid");
+    Ok(())
+}
+
+fn payment_uri(args: &ArgMatches) -> Result<()> {
+    let mut request = PaymentRequest::new(address_to_account(args.value_of("account").unwrap())?);
+    if let Some(amount) = args.value_of("amount") {
+        let unit = args.value_of("unit").unwrap();
+        let unit = Unit::from_name(unit).ok_or_else(|| Error::from(format!("Unknown unit `{}`", unit)))?;
+        request.amount = Some(Amount::parse(amount, unit)?);
+    }
+    request.label = args.value_of("label").map(str::to_owned);
+    request.message = args.value_of("message").map(str::to_owned);
+    println!("{}", request.uri());
+    if let Some(path) = args.value_of("qr") {
+        let format = Path::new(path).extension()
+            .and_then(|extension| QrFormat::from_name(&extension.to_string_lossy()))
+            .ok_or_else(|| Error::from("--qr must name a .png or .svg file"))?;
+        fs::write(path, request.qr_code(format)?)?;
+        eprintln!("Saved QR code to {}", path);
+    }
+    Ok(())
+}
+
+/// Open the store set up by the configuration file given to the node, read-only if
+/// `args` has `--read-only`
+fn open_store(matches: &ArgMatches, args: &ArgMatches) -> Result<(Arc<dyn Store>, StoreConfig)> {
+    let mut config = Config::load(matches.value_of("config").unwrap_or(crate::DEFAULT_CONFIG_PATH))?.store_config()?;
+    config.lmdb.read_only = args.is_present("read_only");
+    Ok((store::open(&config)?, config))
+}
+
+/// The network of the configuration file
+fn load_network(matches: &ArgMatches) -> Result<NetworkParams> {
+    Config::load(matches.value_of("config").unwrap_or(crate::DEFAULT_CONFIG_PATH))?.network()
+}
+
+fn ledger_export(matches: &ArgMatches, args: &ArgMatches) -> Result<()> {
+    let (store, _) = open_store(matches, args)?;
+    let format = DumpFormat::from_name(args.value_of("format").unwrap()).unwrap();
+    let mut out: Box<dyn Write> = match args.value_of("output") {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+    let summary = match args.values_of("account") {
+        Some(accounts) => {
+            let accounts = accounts.map(address_to_account).collect::<Result<Vec<_>>>()?;
+            export::export_accounts(&*store, &accounts, format, &mut out)?
+        }
+        None => export::export(&*store, format, &mut out)?,
+    };
+    out.flush()?;
+    eprintln!("Exported {} blocks and {} accounts", summary.blocks, summary.accounts);
+    Ok(())
+}
+
+fn ledger_import(matches: &ArgMatches, args: &ArgMatches) -> Result<()> {
+    let (store, config) = open_store(matches, args)?;
+    let format = DumpFormat::from_name(args.value_of("format").unwrap()).unwrap();
+    let mut input = BufReader::new(File::open(args.value_of("file").unwrap())?);
+    let network = load_network(matches)?;
+    let summary = export::import(&*store, format, &mut input, &network.burn_accounts, config.batch.max_blocks)?;
+    eprintln!("Imported {} blocks and {} accounts", summary.blocks, summary.accounts);
+    Ok(())
+}
+
+fn ledger_checksum(matches: &ArgMatches, args: &ArgMatches) -> Result<()> {
+    let (store, _) = open_store(matches, args)?;
+    let checksum = checksum::checksum(&*store, args.is_present("cemented"))?;
+    println!("Blocks: {}", checksum.block_count);
+    println!("Checksum: {}", HEXUPPER.encode(&checksum.blocks));
+    if let Some(cemented) = checksum.cemented {
+        println!("Cemented checksum: {}", HEXUPPER.encode(&cemented));
+    }
+    Ok(())
+}
+
+/// Open the ledger `ledger_diff` compares against: a dump imported into memory, or a
+/// store opened read-only
+fn open_other_ledger(config: &StoreConfig, network: &NetworkParams, args: &ArgMatches) -> Result<Arc<dyn Store>> {
+    let other = args.value_of("other").unwrap();
+    let mut config = StoreConfig { block_cache_size: 0, account_cache_size: 0, ..config.clone() };
+    if !args.is_present("dump") {
+        config.backend = Backend::Lmdb;
+        config.path = Path::new(other).to_owned();
+        config.lmdb.read_only = true;
+        return store::open(&config);
+    }
+    config.backend = Backend::Memory;
+    let store = store::open(&config)?;
+    let format = DumpFormat::from_name(args.value_of("format").unwrap()).unwrap();
+    let mut input = BufReader::new(File::open(other).chain_err(|| format!("Could not open {}", other))?);
+    export::import(&*store, format, &mut input, &network.burn_accounts, config.batch.max_blocks)?;
+    Ok(store)
+}
+
+fn print_differences<T, F: Fn(&T) -> String>(kind: &str, differences: &Differences<T>, name: F) {
+    let listed = [&differences.only_in_first, &differences.only_in_second, &differences.differing];
+    for (i, &what) in ["only in this ledger", "only in the other", "which differ"].iter().enumerate() {
+        println!("{} {}: {}", kind, what, differences.counts[i]);
+        for record in listed[i] {
+            println!("  {}", name(record));
+        }
+    }
+}
+
+fn ledger_diff(matches: &ArgMatches, args: &ArgMatches) -> Result<()> {
+    let limit = args.value_of("limit").unwrap().parse().map_err(|_| Error::from("--limit must be a number"))?;
+    let (store, config) = open_store(matches, args)?;
+    let other = open_other_ledger(&config, &load_network(matches)?, args)?;
+    let differences = diff::diff(&*store, &*other, limit)?;
+    print_differences("Blocks", &differences.blocks, |hash| String::from(*hash));
+    print_differences("Accounts", &differences.accounts, account_to_address);
+    for account in &differences.accounts.differing {
+        if let (Some(ours), Some(theirs)) = (store.account(account)?, other.account(account)?) {
+            println!("{}: head {} / {}, balance {} / {}, representative {} / {}, confirmation height {} / {}",
+                account_to_address(account), String::from(ours.head), String::from(theirs.head), ours.balance,
+                theirs.balance, account_to_address(&ours.representative), account_to_address(&theirs.representative),
+                ours.confirmation_height, theirs.confirmation_height);
+        }
+    }
+    if differences.is_empty() {
+        eprintln!("The ledgers are the same");
+    }
+    Ok(())
+}
+
+fn ledger_rollback(matches: &ArgMatches, args: &ArgMatches) -> Result<()> {
+    let hash = BlockHash::from_hex(args.value_of("hash").unwrap().to_uppercase())
+        .map_err(|_| Error::from("The hash must be 64 hex digits"))?;
+    let (store, _) = open_store(matches, args)?;
+    let dry_run = args.is_present("dry_run");
+    let summary = rollback::rollback(&*store, &load_network(matches)?.burn_accounts, &hash, dry_run)?;
+    for hash in &summary.blocks {
+        println!("{}", String::from(*hash));
+    }
+    if dry_run {
+        eprintln!("Would roll back {} blocks", summary.blocks.len());
+    } else {
+        eprintln!("Rolled back {} blocks", summary.blocks.len());
+    }
+    Ok(())
+}
+
+fn database_vacuum(matches: &ArgMatches, args: &ArgMatches) -> Result<()> {
+    let config = Config::load(matches.value_of("config").unwrap_or(crate::DEFAULT_CONFIG_PATH))?.store_config()?;
+    let destination = match (args.value_of("output"), args.is_present("in_place")) {
+        (Some(output), _) => Some(Path::new(output)),
+        (None, true) => None,
+        (None, false) => bail!("Give --output to write a compacted copy, or --in-place to replace the store"),
+    };
+    let summary = vacuum::vacuum(&config, destination)?;
+    eprintln!("Copied {} records, from {} to {} MiB", summary.records, summary.size_before >> 20,
+        summary.size_after >> 20);
+    match summary.backup {
+        Some(backup) => eprintln!("The original was kept as {}", backup.display()),
+        None => eprintln!("Point store.path at {} to use the compacted copy", args.value_of("output").unwrap()),
+    }
+    Ok(())
+}
+
+/// The exclusions saved next to the store set up by the configuration file
+fn load_exclusions(matches: &ArgMatches) -> Result<Exclusions> {
+    let config = Config::load(matches.value_of("config").unwrap_or(crate::DEFAULT_CONFIG_PATH))?.store_config()?;
+    Exclusions::load(exclusions::path(&config.path))
+}
+
+fn print_exclusion(host: &Ipv6Addr, exclusion: &Exclusion) {
+    let until = match exclusion.until.and_then(|until| Utc.timestamp_opt(until as i64, 0).single()) {
+        Some(until) => format!("until {}", until.to_rfc3339()),
+        None => "indefinitely".to_owned(),
+    };
+    match exclusion.reason.as_str() {
+        "" => println!("{} {}", host, until),
+        reason => println!("{} {}: {}", host, until, reason),
+    }
+}
+
+fn peer_exclude(matches: &ArgMatches, args: &ArgMatches) -> Result<()> {
+    let host = exclusions::parse_host(args.value_of("address").unwrap())?;
+    let duration = match args.value_of("duration") {
+        Some(secs) => Some(Duration::from_secs(secs.parse().map_err(|_| Error::from("--duration must be a number"))?)),
+        None => None,
+    };
+    let exclusion = load_exclusions(matches)?.exclude(host, duration, args.value_of("reason").unwrap_or(""))?;
+    print_exclusion(&host, &exclusion);
+    Ok(())
+}
+
+fn peer_unexclude(matches: &ArgMatches, args: &ArgMatches) -> Result<()> {
+    let host = exclusions::parse_host(args.value_of("address").unwrap())?;
+    if !load_exclusions(matches)?.remove(&host)? {
+        bail!("{} is not excluded", host);
+    }
+    eprintln!("Removed the exclusion of {}", host);
+    Ok(())
+}
+
+fn peer_exclusions(matches: &ArgMatches) -> Result<()> {
+    for (host, exclusion) in load_exclusions(matches)?.list()? {
+        print_exclusion(&host, &exclusion);
+    }
+    Ok(())
+}
+
+fn fast_sync(matches: &ArgMatches) -> Result<()> {
+    let config = Config::load(matches.value_of("config").unwrap_or(crate::DEFAULT_CONFIG_PATH))?;
+    let snapshot = config.snapshot_config()?
+        .ok_or_else(|| Error::from("The configuration file has no [snapshot] section"))?;
+    fast_sync::fast_sync(&snapshot, &config.store_config()?, &config.network()?)?;
+    eprintln!("Installed the ledger snapshot");
+    Ok(())
+}
+
+fn migrate(args: &ArgMatches) -> Result<()> {
+    let destination = Path::new(args.value_of("destination").unwrap());
+    let summary = migrate::migrate(Path::new(args.value_of("source").unwrap()), destination,
+        Config::default().store.batch_size)?;
+    eprintln!("Migrated {} blocks and {} accounts of the {} network from store version {}", summary.blocks,
+        summary.accounts, summary.network, summary.store_version);
+    if summary.wallets_found {
+        eprintln!("wallets.ldb was not migrated, as nano-rs doesn't keep wallets; keep a backup of it");
+    }
+    eprintln!("Start the node with --config {}", destination.join("nano-rs.toml").display());
+    Ok(())
+}
+
+fn replay(matches: &ArgMatches, args: &ArgMatches) -> Result<()> {
+    let config_path = matches.value_of("config").unwrap_or(crate::DEFAULT_CONFIG_PATH);
+    let config = Config::load(config_path)?;
+    let node_addrs = match args.values_of("node") {
+        Some(addrs) => addrs
+            .map(|addr| addr.parse().map_err(|_| Error::from(format!("`{}` is not an address and port", addr))))
+            .collect::<Result<Vec<_>>>()?,
+        None => Vec::new(),
+    };
+    let log_filter = logging::init(&config)?;
+    let summary = replay::replay(config_path, config, log_filter, Path::new(args.value_of("capture").unwrap()),
+        &node_addrs, args.value_of("ledger").map(Path::new))?;
+    eprintln!("Replayed {} datagrams, {} of which could not be decoded, and skipped {} sent elsewhere",
+        summary.replayed, summary.undecodable, summary.skipped);
+    eprintln!("The ledger went from {} to {} blocks", summary.blocks_before, summary.blocks_after);
+    if let Some(ledger) = args.value_of("ledger") {
+        eprintln!("The replayed ledger was kept in {}", ledger);
+    }
+    Ok(())
+}
+
+#[cfg(feature = "rpc")]
+fn diagnostics(rpc: &str) -> Result<()> {
+    let response: Value = reqwest::blocking::Client::new()
+        .post(&format!("http://{}", rpc))
+        .json(&json!({ "action": "diagnostics" }))
+        .send()?
+        .json()?;
+    if let Some(error) = response.get("error").and_then(Value::as_str) {
+        bail!("Node returned an error: {}", error);
+    }
+    println!("{}", serde_json::to_string_pretty(&response)?);
+    Ok(())
+}
+
+/// Validate a vanity pattern and strip any account prefix from it
+fn vanity_pattern(pattern: &str) -> Result<String> {
+    let pattern = if pattern.starts_with(ADDRESS_PREFIX) {
+        &pattern[ADDRESS_PREFIX.len()..]
+    } else if pattern.starts_with(LEGACY_ADDRESS_PREFIX) {
+        &pattern[LEGACY_ADDRESS_PREFIX.len()..]
+    } else {
+        pattern
+    };
+    if let Some(c) = pattern.chars().find(|&c| c != '*' && !ADDRESS_ALPHABET.contains(&(c as u8))) {
+        bail!("`{}` can never appear in an account", c);
+    }
+    // The first character only encodes the top bit of the key
+    if let Some(c) = pattern.chars().next() {
+        if c != '*' && c != '1' && c != '3' {
+            bail!("Accounts always start with 1 or 3");
+        }
+    }
+    Ok(pattern.to_owned())
+}
+
+fn matches_pattern(account: &str, pattern: &str) -> bool {
+    account.bytes().zip(pattern.bytes()).all(|(a, p)| p == b'*' || a == p)
+}
+
+/// The expected number of keys to try before finding a match for `pattern`
+fn expected_attempts(pattern: &str) -> f64 {
+    pattern.chars().enumerate()
+        .filter(|&(_, c)| c != '*')
+        .map(|(i, _)| if i == 0 { 2.0 } else { 32.0 })
+        .product()
+}
+
+fn vanity(pattern: &str, threads: usize) -> Result<()> {
+    let pattern = vanity_pattern(pattern)?;
+    if threads == 0 {
+        bail!("--threads must be greater than 0");
+    }
+    let expected = expected_attempts(&pattern);
+    eprintln!("Searching for {}{} with {} threads, expecting about {} attempts",
+        ADDRESS_PREFIX, pattern, threads, expected);
+
+    let done = Arc::new(AtomicBool::new(false));
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let (tx, rx) = mpsc::channel();
+    for _ in 0..threads {
+        let (done, attempts, tx, pattern) = (done.clone(), attempts.clone(), tx.clone(), pattern.clone());
+        thread::spawn(move || -> Result<()> {
+            let mut rng = OsRng::new()?;
+            let mut bytes = [0u8; SECRET_KEY_LENGTH];
+            while !done.load(Ordering::Relaxed) {
+                rng.fill_bytes(&mut bytes);
+                let secret = secret_key(&bytes)?;
+                let account = Address::from(public_key_from_secret(&secret));
+                attempts.fetch_add(1, Ordering::Relaxed);
+                if matches_pattern(&account.0[ADDRESS_PREFIX.len()..], &pattern) {
+                    done.store(true, Ordering::Relaxed);
+                    let _ = tx.send(secret);
+                }
+            }
+            Ok(())
+        });
+    }
+    drop(tx);
+
+    let start = Instant::now();
+    loop {
+        match rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(secret) => {
+                print_keys(&secret);
+                return Ok(());
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                let elapsed = start.elapsed();
+                let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+                let tried = attempts.load(Ordering::Relaxed) as f64;
+                let rate = tried / secs;
+                let remaining = if tried < expected { (expected - tried) / rate } else { 0.0 };
+                eprintln!("{} attempts, {:.0} keys/s, about {:.0}s remaining", tried, rate, remaining);
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => bail!("All search threads exited"),
+        }
+    }
+}
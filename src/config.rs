@@ -0,0 +1,1553 @@
+//! The node's TOML configuration file.
+//!
+//! Every key is optional and falls back to the value in `DEFAULT_CONFIG`. Unknown
+//! keys are rejected so that typos don't silently fall back to defaults, and values
+//! are validated when converted into the configuration of each subsystem, with
+//! errors naming the offending key.
+//!
+//! The `[logging]` levels and `[work]` section can be reloaded while the node is
+//! running; other changes only take effect after a restart.
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use data_encoding::HEXUPPER;
+use nano_lib_rs::keys::{Address, PublicKey};
+use nano_lib_rs::block::BlockHash;
+use nano_lib_rs::network::{self, NetworkParams};
+use nanopow_rs::difficulty;
+
+use crate::logging::{LogFilter, LogFormat};
+use crate::node::{NodeConfig, NodeMode, PeeringConfig, Whitelist, KEEPALIVE_INTERVAL};
+use crate::node::pruner::PruningConfig;
+use crate::node::voting::VotingConfig;
+use crate::node::backup::BackupConfig;
+use crate::node::webhook::WebhookConfig;
+use crate::node::queue::{DropPolicy, TrafficClass};
+use crate::signer::{Endpoint, SignerConfig};
+use crate::store::{Backend, BatchConfig, LmdbOptions, StoreConfig, SyncPolicy};
+use crate::store::export::DumpFormat;
+use crate::store::fast_sync::SnapshotConfig;
+use crate::work::{WorkConfig, WorkPool, DEFAULT_PREGENERATION_LIMIT};
+use crate::work::dpow::DpowConfig;
+use crate::work::opencl::OpenClConfig;
+use crate::utils::to_ipv6;
+use crate::error::*;
+
+/// The documented default configuration, as emitted by `--generate-config`
+pub const DEFAULT_CONFIG: &str = r#"# nano-rs configuration
+#
+# Every key is optional; removing a key uses the default shown here.
+
+[node]
+# Which network to join: "live", "beta", "test", "dev", or "custom" to use the
+# parameters in [custom_network]
+network = "live"
+# "full"; "observer" for query nodes, such as an exchange's, which process blocks
+# and serve RPC but never vote or handle keys, with the store tuned for reads; or
+# "pruned" for machines with little disk, which prune blocks as set in [pruning]
+mode = "full"
+# Vote as a representative, with the key of the [signer]. Votes are only cast while
+# the store is within [voting] sync_threshold blocks of the network's cemented count
+enable_voting = false
+# Never expect inbound connections, as behind CGNAT: keep the peers in [outbound] with
+# frequent keepalives, and ask peers not to pass this node on to others
+outbound_only = false
+# Exchange traffic only with the peers in [whitelist], dropping datagrams from any
+# other address as they are received, for private networks and back-office nodes
+whitelist_only = false
+# Advertise the node over mDNS, and peer with the nodes of the same network which
+# advertise themselves on the LAN, so that dev networks need no preconfigured peers
+enable_mdns = false
+# Address to listen for UDP traffic on; defaults to all interfaces on the network's port
+# listen_addr = "[::]:7075"
+# Further addresses to listen on, each with its own socket, such as a public IPv4, a
+# public IPv6 and a LAN address. Peers are answered from the socket they were heard
+# on. With more than one address, IPv6 sockets only carry IPv6 traffic
+# listen_addrs = ["0.0.0.0:7075", "[2001:db8::1]:7075"]
+listen_addrs = []
+# The DSCP code point (0-63) to mark UDP datagrams with, so that QoS setups can
+# prioritize the node's vote and block traffic, e.g. 46 for expedited forwarding.
+# 0 leaves them unmarked
+dscp = 0
+# Peers to contact on startup, as host:port; defaults to the network's public peers
+# preconfigured_peers = ["rai.raiblocks.net:7075"]
+# Threads processing received blocks; blocks of different accounts are processed in
+# parallel. 0 uses one per logical CPU
+block_processor_threads = 0
+# Threads checking the signatures of received blocks, so that the socket is never
+# kept waiting; 0 uses one per logical CPU
+signature_checker_threads = 0
+# Which traffic is dropped first as the processing queues fill up: "republish"
+# (blocks which were seen before), "publish", "confirm_req" or "confirm_ack". Each
+# class may fill a larger share of a queue than the classes before it; classes
+# which aren't listed are dropped last
+drop_order = ["republish", "publish", "confirm_req", "confirm_ack"]
+# Seed for the node's random choices, such as which peers keepalives list, so that
+# simulations and tests can be repeated exactly; on the dev network it also seeds
+# CPU work generation. Leave unset outside of tests, to seed from the OS
+# seed = 1
+
+# Parameters of a private network, used when network = "custom"
+# [custom_network]
+# Single character identifying the network in message headers
+# id = "A"
+# port = 44000
+# Minimum work difficulty, as 16 hex digits
+# work_threshold = "ff00000000000000"
+# genesis_account = "nano_3e3j5tkog48pnny9dmfzj1r16pg8t1e76dz5tmac6iq689wyjfpiij4txtdo"
+# Optional hash of the genesis block
+# genesis_block = ""
+# Accounts left out of the available supply, besides the genesis account; by default
+# the burn account, nano_1111111111111111111111111111111111111111111111111111hifc8npp
+# burn_accounts = []
+# The accounts signing the epoch blocks of each epoch, from version 1; by default the
+# genesis account signs every epoch's blocks. Epoch blocks are created with the
+# `epoch_upgrade` RPC action, given the signer's private key.
+# epoch_signers = []
+
+[store]
+# "lmdb" (requires building with the `lmdb` feature), or "memory" to keep the
+# ledger in memory and lose it when the node stops
+backend = "lmdb"
+# Directory the database is kept in
+path = "data"
+# Processed blocks are written in one transaction once this many are waiting, or
+# once the first has waited batch_delay_ms milliseconds
+batch_size = 256
+batch_delay_ms = 50
+# Recently read and written blocks kept in memory in front of the store; 0 disables
+# the cache
+block_cache_size = 16384
+# Recently read account records kept in memory; records are reloaded from the store
+# after their account is written. 0 disables the cache
+account_cache_size = 16384
+# Initial size of the LMDB memory map in MiB, which only reserves address space; it
+# is doubled whenever a write finds it full
+map_size_mb = 131072
+# When LMDB flushes commits to disk: "always", on every commit; "batched", every
+# sync_interval_ms milliseconds, which speeds up bootstrapping but lets an OS crash
+# lose the latest writes or corrupt the database; or "none", leaving it to the OS,
+# for throwaway nodes
+sync = "always"
+sync_interval_ms = 1000
+
+# Used when node.enable_voting is set. The network's cemented count is the highest
+# reported by the block_count RPC action of the reference_rpc nodes, which must be
+# trusted, and is checked every check_interval_secs seconds
+[voting]
+sync_threshold = 1000
+# reference_rpc = ["http://[::1]:7076"]
+reference_rpc = []
+check_interval_secs = 60
+timeout_ms = 5000
+
+# Used when node.outbound_only is set. The persistent_peers, as host:port, are
+# never pruned and are sent keepalives every keepalive_interval_secs seconds, which
+# must be shorter than the NAT's UDP mapping timeout; by default the node's
+# preconfigured peers are kept
+[outbound]
+persistent_peers = []
+keepalive_interval_secs = 20
+
+# Used when node.whitelist_only is set. The peers, as host:port, or as a bare host
+# to allow it on any port, are the only addresses the node sends to or accepts
+# datagrams from. Those with a port are contacted on startup, along with the
+# preconfigured peers which are whitelisted
+[whitelist]
+peers = []
+
+# Used when node.mode = "pruned". Every interval_secs seconds, blocks more than
+# keep_depth blocks behind their account's frontier are deleted, except sends
+# which haven't been received yet
+[pruning]
+keep_depth = 64
+interval_secs = 3600
+
+# Bootstrap an empty store from a trusted ledger snapshot, a dump written by
+# `ledger_export`, downloaded over HTTPS when the node starts. The snapshot must
+# have the block checksum printed by `ledger_checksum`, and the chains of
+# spot_checks random accounts have their signatures checked before it is used.
+# [snapshot]
+# url = "https://example.com/ledger.json"
+# format = "json"
+# checksum = ""
+# spot_checks = 64
+
+[rpc]
+# Serve the JSON RPC interface
+enable = true
+address = "[::1]:7076"
+# Serve only the work_generate/work_cancel/work_validate actions, using the same
+# protocol as nano-work-server, on a separate address
+work_server_enable = false
+work_server_address = "[::1]:7077"
+# Serve the deterministic_key, key_expand and seed_validate actions, which take
+# seeds and private keys in requests and return private keys. Only enable this if
+# every client that can reach the RPC address is trusted with secrets
+enable_key_derivation = false
+# Connections each of the RPC and work servers holds, and how many of them may come
+# from one IP address. Once a server is full, its longest idle connection is closed
+# to make room; connections idle for idle_timeout_secs seconds are closed anyway
+max_connections = 64
+max_connections_per_ip = 16
+idle_timeout_secs = 60
+
+# Deposit accounts handed out by payment_begin, one per payment in progress. nano-rs
+# keeps no wallets, so these are watched only: their keys stay with the wallet which
+# receives the payments, and payment_wait reports a payment once the account's
+# balance has grown by the amount
+[payment]
+deposit_accounts = []
+
+[work]
+# Threads used to generate work on the CPU; 0 uses one per logical CPU
+threads = 0
+# Maximum number of roots work is generated for ahead of time; 0 disables it
+pregeneration_limit = 2
+
+# Generate work on an OpenCL device (requires building with the `opencl` feature)
+# [work.opencl]
+# platform = 0
+# device = 0
+# threads = 1048576
+
+# Request work from a distributed PoW service such as DPoW or BoomPoW
+# [work.dpow]
+# url = "https://dpow.nanocenter.org/service/"
+# user = ""
+# api_key = ""
+# timeout = 15
+# precache = false
+
+# POST {"account", "hash", "source", "amount"} as JSON to url as soon as a send to
+# one of accounts is written, before it is received; an empty list notifies sends to
+# every account. Failed notifications are retried every retry_interval_secs seconds
+# until they have been attempted max_attempts times, and are kept next to the store
+# across restarts.
+# [webhook]
+# url = "http://[::1]:8080/receivable"
+# accounts = []
+# timeout_ms = 5000
+# retry_interval_secs = 30
+# max_attempts = 10
+
+# Back up the ledger to a dump in directory, "daily" or "weekly", keeping the newest
+# keep backups. A backup is due an interval after the newest one, and is taken from
+# one snapshot of the ledger, in the format of ledger_export. If command is set, it
+# is run with the path of each new backup, for example to upload it.
+# [backup]
+# directory = "/var/backups/nano-rs"
+# schedule = "daily"
+# keep = 7
+# format = "json"
+# command = "/usr/local/bin/upload-nano-backup"
+
+# Sign for account with a key kept by an external signing service, such as an HSM
+# bridge or a separate hardened process, rather than in the node. Set either url,
+# for a service taking HTTP POSTs, or ipc_path, for one listening on a Unix socket.
+# Otherwise set key_file to a file holding the account's private key in hex, which
+# is loaded into the node's memory.
+# [signer]
+# account = ""
+# url = "http://[::1]:7078/"
+# ipc_path = "/run/nano-signer.sock"
+# key_file = "/etc/nano-rs/signer.key"
+# timeout_ms = 5000
+
+[logging]
+# One of "off", "error", "warn", "info", "debug" or "trace", used for modules
+# not listed under [logging.modules]
+level = "info"
+# "text", or "json" to write one JSON object per line
+format = "text"
+# Log when each span closes, with the time spent in it. Received messages are
+# traced through the `udp_recv`, `decode`, `message`, `signature_check`, `block`
+# and `work_check` spans, which are logged at the debug and trace levels.
+span_timings = false
+# Directory log files are written to; an empty string disables log files
+directory = "log"
+
+# Levels for individual modules and their submodules, overriding `level`
+[logging.modules]
+# "nano_rs::node" = "debug"
+# "hyper" = "warn"
+"#;
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub node: NodeSection,
+    pub custom_network: Option<CustomNetworkSection>,
+    pub store: StoreSection,
+    pub voting: VotingSection,
+    pub outbound: OutboundSection,
+    pub whitelist: WhitelistSection,
+    pub pruning: PruningSection,
+    pub snapshot: Option<SnapshotSection>,
+    pub rpc: RpcSection,
+    pub payment: PaymentSection,
+    pub work: WorkSection,
+    pub webhook: Option<WebhookSection>,
+    pub backup: Option<BackupSection>,
+    pub signer: Option<SignerSection>,
+    pub logging: LoggingSection,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeSection {
+    pub network: String,
+    pub mode: String,
+    pub enable_voting: bool,
+    pub outbound_only: bool,
+    pub whitelist_only: bool,
+    pub enable_mdns: bool,
+    pub listen_addr: Option<String>,
+    pub listen_addrs: Vec<String>,
+    pub dscp: u8,
+    pub preconfigured_peers: Option<Vec<String>>,
+    pub block_processor_threads: usize,
+    pub signature_checker_threads: usize,
+    pub drop_order: Vec<String>,
+    pub seed: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CustomNetworkSection {
+    pub id: String,
+    pub port: u16,
+    pub work_threshold: String,
+    pub genesis_account: String,
+    #[serde(default)]
+    pub genesis_block: Option<String>,
+    #[serde(default)]
+    pub burn_accounts: Option<Vec<String>>,
+    #[serde(default)]
+    pub epoch_signers: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StoreSection {
+    pub backend: String,
+    pub path: String,
+    pub batch_size: usize,
+    pub batch_delay_ms: u64,
+    pub block_cache_size: usize,
+    pub account_cache_size: usize,
+    pub map_size_mb: usize,
+    pub sync: String,
+    pub sync_interval_ms: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VotingSection {
+    pub sync_threshold: u64,
+    pub reference_rpc: Vec<String>,
+    pub check_interval_secs: u64,
+    pub timeout_ms: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutboundSection {
+    pub persistent_peers: Vec<String>,
+    pub keepalive_interval_secs: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WhitelistSection {
+    pub peers: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PruningSection {
+    pub keep_depth: u64,
+    pub interval_secs: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotSection {
+    pub url: String,
+    #[serde(default = "default_snapshot_format")]
+    pub format: String,
+    pub checksum: String,
+    #[serde(default = "default_spot_checks")]
+    pub spot_checks: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RpcSection {
+    pub enable: bool,
+    pub address: String,
+    pub work_server_enable: bool,
+    pub work_server_address: String,
+    pub enable_key_derivation: bool,
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+    pub idle_timeout_secs: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PaymentSection {
+    pub deposit_accounts: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkSection {
+    pub threads: usize,
+    pub pregeneration_limit: usize,
+    pub opencl: Option<OpenClSection>,
+    pub dpow: Option<DpowSection>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OpenClSection {
+    #[serde(default)]
+    pub platform: usize,
+    #[serde(default)]
+    pub device: usize,
+    #[serde(default = "default_opencl_threads")]
+    pub threads: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DpowSection {
+    pub url: String,
+    pub user: String,
+    pub api_key: String,
+    #[serde(default = "default_dpow_timeout")]
+    pub timeout: u64,
+    #[serde(default)]
+    pub precache: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SignerSection {
+    pub account: String,
+    pub url: Option<String>,
+    pub ipc_path: Option<String>,
+    pub key_file: Option<String>,
+    #[serde(default = "default_signer_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookSection {
+    pub url: String,
+    #[serde(default)]
+    pub accounts: Vec<String>,
+    #[serde(default = "default_webhook_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default = "default_retry_interval_secs")]
+    pub retry_interval_secs: u64,
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackupSection {
+    pub directory: String,
+    #[serde(default = "default_backup_schedule")]
+    pub schedule: String,
+    #[serde(default = "default_backup_keep")]
+    pub keep: usize,
+    #[serde(default = "default_snapshot_format")]
+    pub format: String,
+    pub command: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingSection {
+    pub level: String,
+    pub format: String,
+    pub span_timings: bool,
+    pub directory: String,
+    pub modules: BTreeMap<String, String>,
+}
+
+fn default_opencl_threads() -> usize {
+    OpenClConfig::default().threads
+}
+
+fn default_dpow_timeout() -> u64 {
+    15
+}
+
+fn default_signer_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_webhook_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_retry_interval_secs() -> u64 {
+    30
+}
+
+fn default_max_attempts() -> u32 {
+    10
+}
+
+fn default_snapshot_format() -> String {
+    "json".into()
+}
+
+fn default_backup_schedule() -> String {
+    "daily".into()
+}
+
+fn default_backup_keep() -> usize {
+    7
+}
+
+fn default_spot_checks() -> usize {
+    64
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            node: NodeSection::default(),
+            custom_network: None,
+            store: StoreSection::default(),
+            voting: VotingSection::default(),
+            outbound: OutboundSection::default(),
+            whitelist: WhitelistSection::default(),
+            pruning: PruningSection::default(),
+            snapshot: None,
+            rpc: RpcSection::default(),
+            payment: PaymentSection::default(),
+            work: WorkSection::default(),
+            webhook: None,
+            backup: None,
+            signer: None,
+            logging: LoggingSection::default(),
+        }
+    }
+}
+
+impl Default for NodeSection {
+    fn default() -> Self {
+        NodeSection {
+            network: "live".into(),
+            mode: "full".into(),
+            enable_voting: false,
+            outbound_only: false,
+            whitelist_only: false,
+            enable_mdns: false,
+            listen_addr: None,
+            listen_addrs: Vec::new(),
+            dscp: 0,
+            preconfigured_peers: None,
+            block_processor_threads: 0,
+            signature_checker_threads: 0,
+            drop_order: TrafficClass::ALL.iter().map(|class| class.name().to_owned()).collect(),
+            seed: None,
+        }
+    }
+}
+
+impl Default for StoreSection {
+    fn default() -> Self {
+        StoreSection {
+            backend: "lmdb".into(),
+            path: "data".into(),
+            batch_size: 256,
+            batch_delay_ms: 50,
+            block_cache_size: 16384,
+            account_cache_size: 16384,
+            map_size_mb: 131072,
+            sync: "always".into(),
+            sync_interval_ms: 1000,
+        }
+    }
+}
+
+impl Default for VotingSection {
+    fn default() -> Self {
+        VotingSection {
+            sync_threshold: 1000,
+            reference_rpc: Vec::new(),
+            check_interval_secs: 60,
+            timeout_ms: 5000,
+        }
+    }
+}
+
+impl Default for OutboundSection {
+    fn default() -> Self {
+        OutboundSection {
+            persistent_peers: Vec::new(),
+            keepalive_interval_secs: 20,
+        }
+    }
+}
+
+impl Default for PruningSection {
+    fn default() -> Self {
+        PruningSection {
+            keep_depth: 64,
+            interval_secs: 3600,
+        }
+    }
+}
+
+impl Default for RpcSection {
+    fn default() -> Self {
+        RpcSection {
+            enable: true,
+            address: "[::1]:7076".into(),
+            work_server_enable: false,
+            work_server_address: "[::1]:7077".into(),
+            enable_key_derivation: false,
+            max_connections: 64,
+            max_connections_per_ip: 16,
+            idle_timeout_secs: 60,
+        }
+    }
+}
+
+impl Default for WorkSection {
+    fn default() -> Self {
+        WorkSection {
+            threads: 0,
+            pregeneration_limit: DEFAULT_PREGENERATION_LIMIT,
+            opencl: None,
+            dpow: None,
+        }
+    }
+}
+
+impl Default for LoggingSection {
+    fn default() -> Self {
+        LoggingSection {
+            level: "info".into(),
+            format: "text".into(),
+            span_timings: false,
+            directory: "log".into(),
+            modules: BTreeMap::new(),
+        }
+    }
+}
+
+const LOG_LEVELS: &[&str] = &["off", "error", "warn", "info", "debug", "trace"];
+
+fn parse_level<'a>(key: &str, level: &'a str) -> Result<&'a str> {
+    if LOG_LEVELS.contains(&level) {
+        Ok(level)
+    } else {
+        Err(invalid(key, format!("unknown level `{}`", level)))
+    }
+}
+
+fn invalid<S: Into<String>>(key: &str, message: S) -> Error {
+    ErrorKind::ConfigError(key.into(), message.into()).into()
+}
+
+fn parse_addr(key: &str, value: &str) -> Result<SocketAddr> {
+    value.parse().map_err(|_| invalid(key, format!("`{}` is not a valid socket address", value)))
+}
+
+impl Config {
+    /// Parse a configuration from the contents of a TOML file
+    pub fn from_toml(contents: &str) -> Result<Self> {
+        let config: Config = toml::from_str(contents)
+            .map_err(|e| Error::from(ErrorKind::ConfigParseError(format!("{}", e))))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Load the configuration at `path`, or the defaults if it doesn't exist
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+        Config::from_toml(&contents)
+    }
+
+    /// Check values which can be validated without side effects such as DNS lookups
+    pub fn validate(&self) -> Result<()> {
+        self.network()?;
+        self.voting_config()?;
+        if self.outbound.keepalive_interval_secs == 0 || self.outbound.keepalive_interval_secs > KEEPALIVE_INTERVAL {
+            return Err(invalid("outbound.keepalive_interval_secs",
+                format!("must be between 1 and {}", KEEPALIVE_INTERVAL)));
+        }
+        if self.node_mode()? == NodeMode::Observer {
+            if self.signer.is_some() {
+                return Err(invalid("signer", "observer nodes never vote, so they can't have a signer"));
+            }
+            if self.rpc.enable_key_derivation {
+                return Err(invalid("rpc.enable_key_derivation", "observer nodes don't handle keys"));
+            }
+        }
+        self.listen_addrs(self.network()?.default_port)?;
+        if self.node.dscp > 63 {
+            return Err(invalid("node.dscp", "must be a DSCP code point, from 0 to 63"));
+        }
+        self.drop_policy()?;
+        self.store_backend()?;
+        if self.store.batch_size == 0 {
+            return Err(invalid("store.batch_size", "must be greater than 0"));
+        }
+        if self.store.map_size_mb == 0 {
+            return Err(invalid("store.map_size_mb", "must be greater than 0"));
+        }
+        if self.sync_policy()? == SyncPolicy::Batched(Duration::from_millis(0)) {
+            return Err(invalid("store.sync_interval_ms", "must be greater than 0"));
+        }
+        if self.pruning.keep_depth == 0 {
+            return Err(invalid("pruning.keep_depth", "must be greater than 0, as frontiers are always kept"));
+        }
+        if self.pruning.interval_secs == 0 {
+            return Err(invalid("pruning.interval_secs", "must be greater than 0"));
+        }
+        if self.rpc.enable {
+            parse_addr("rpc.address", &self.rpc.address)?;
+        }
+        if self.rpc.work_server_enable {
+            parse_addr("rpc.work_server_address", &self.rpc.work_server_address)?;
+        }
+        if self.rpc.max_connections == 0 {
+            return Err(invalid("rpc.max_connections", "must be greater than 0"));
+        }
+        if self.rpc.max_connections_per_ip == 0 {
+            return Err(invalid("rpc.max_connections_per_ip", "must be greater than 0"));
+        }
+        if self.rpc.idle_timeout_secs == 0 {
+            return Err(invalid("rpc.idle_timeout_secs", "must be greater than 0"));
+        }
+        if let Some(ref opencl) = self.work.opencl {
+            if opencl.threads == 0 {
+                return Err(invalid("work.opencl.threads", "must be greater than 0"));
+            }
+        }
+        if let Some(ref dpow) = self.work.dpow {
+            if !dpow.url.starts_with("http://") && !dpow.url.starts_with("https://") {
+                return Err(invalid("work.dpow.url", "must be an http:// or https:// URL"));
+            }
+        }
+        self.deposit_accounts()?;
+        self.webhook_config()?;
+        self.backup_config()?;
+        self.snapshot_config()?;
+        self.signer_config()?;
+        self.log_directives()?;
+        self.log_format()?;
+        Ok(())
+    }
+
+    pub fn network(&self) -> Result<NetworkParams> {
+        if self.node.network == "custom" {
+            return match self.custom_network {
+                Some(ref custom) => custom.params(),
+                None => Err(invalid("node.network", "a [custom_network] section is required for custom networks")),
+            };
+        }
+        NetworkParams::named(&self.node.network).ok_or_else(|| invalid("node.network",
+            format!("unknown network `{}`, expected one of live, beta, test, dev, custom", self.node.network)))
+    }
+
+    /// The `[logging]` levels as `tracing` filter directives, e.g. `info,nano_rs::node=debug`
+    pub fn log_directives(&self) -> Result<String> {
+        let mut directives = vec![parse_level("logging.level", &self.logging.level)?.to_owned()];
+        for (module, level) in &self.logging.modules {
+            let key = format!("logging.modules.{}", module);
+            if module.is_empty() || module.contains(|c: char| c == ',' || c == '=' || c.is_whitespace()) {
+                return Err(invalid(&key, "not a valid module path"));
+            }
+            directives.push(format!("{}={}", module, parse_level(&key, level)?));
+        }
+        Ok(directives.join(","))
+    }
+
+    pub fn log_format(&self) -> Result<LogFormat> {
+        LogFormat::from_name(&self.logging.format).ok_or_else(|| invalid("logging.format",
+            format!("unknown format `{}`, expected text or json", self.logging.format)))
+    }
+
+    /// The addresses to listen for UDP traffic on: `listen_addr` and `listen_addrs`,
+    /// or all interfaces on `default_port` if neither is set
+    pub fn listen_addrs(&self, default_port: u16) -> Result<Vec<SocketAddr>> {
+        let mut addrs = Vec::new();
+        if let Some(ref addr) = self.node.listen_addr {
+            addrs.push(parse_addr("node.listen_addr", addr)?);
+        }
+        for addr in &self.node.listen_addrs {
+            let addr = parse_addr("node.listen_addrs", addr)?;
+            if addrs.contains(&addr) {
+                return Err(invalid("node.listen_addrs", format!("{} is listed twice", addr)));
+            }
+            addrs.push(addr);
+        }
+        if addrs.is_empty() {
+            addrs.push(SocketAddr::new("::".parse().unwrap(), default_port));
+        }
+        Ok(addrs)
+    }
+
+    pub fn voting_config(&self) -> Result<Option<VotingConfig>> {
+        if !self.node.enable_voting {
+            return Ok(None);
+        }
+        if self.node_mode()? == NodeMode::Observer {
+            return Err(invalid("node.enable_voting", "observer nodes never vote"));
+        }
+        if self.signer.is_none() {
+            return Err(invalid("node.enable_voting",
+                "voting needs the representative's key from a [signer], as nano-rs has no wallet"));
+        }
+        if self.voting.reference_rpc.is_empty() {
+            return Err(invalid("voting.reference_rpc", "voting needs a trusted node to check sync against"));
+        }
+        for url in &self.voting.reference_rpc {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(invalid("voting.reference_rpc", format!("`{}` is not an http:// or https:// URL", url)));
+            }
+        }
+        if self.voting.check_interval_secs == 0 {
+            return Err(invalid("voting.check_interval_secs", "must be greater than 0"));
+        }
+        Ok(Some(VotingConfig {
+            sync_threshold: self.voting.sync_threshold,
+            reference_rpc: self.voting.reference_rpc.clone(),
+            check_interval: Duration::from_secs(self.voting.check_interval_secs),
+            timeout: Duration::from_millis(self.voting.timeout_ms),
+        }))
+    }
+
+    pub fn node_mode(&self) -> Result<NodeMode> {
+        NodeMode::from_name(&self.node.mode).ok_or_else(|| invalid("node.mode",
+            format!("unknown mode `{}`, expected full, observer or pruned", self.node.mode)))
+    }
+
+    pub fn drop_policy(&self) -> Result<DropPolicy> {
+        let mut order = Vec::new();
+        for name in &self.node.drop_order {
+            let class = TrafficClass::from_name(name).ok_or_else(|| invalid("node.drop_order",
+                format!("unknown traffic `{}`, expected republish, publish, confirm_req or confirm_ack", name)))?;
+            if order.contains(&class) {
+                return Err(invalid("node.drop_order", format!("`{}` is listed more than once", name)));
+            }
+            order.push(class);
+        }
+        Ok(DropPolicy::new(order))
+    }
+
+    pub fn store_backend(&self) -> Result<Backend> {
+        Backend::from_name(&self.store.backend).ok_or_else(|| invalid("store.backend",
+            format!("unknown backend `{}`, expected lmdb or memory", self.store.backend)))
+    }
+
+    pub fn sync_policy(&self) -> Result<SyncPolicy> {
+        SyncPolicy::from_name(&self.store.sync, Duration::from_millis(self.store.sync_interval_ms))
+            .ok_or_else(|| invalid("store.sync", format!("unknown policy `{}`, expected always, batched or none",
+                self.store.sync)))
+    }
+
+    pub fn store_config(&self) -> Result<StoreConfig> {
+        Ok(StoreConfig {
+            backend: self.store_backend()?,
+            path: PathBuf::from(&self.store.path),
+            batch: BatchConfig {
+                max_blocks: self.store.batch_size,
+                max_delay: Duration::from_millis(self.store.batch_delay_ms),
+            },
+            block_cache_size: self.store.block_cache_size,
+            account_cache_size: self.store.account_cache_size,
+            lmdb: LmdbOptions {
+                read_mostly: self.node_mode()? == NodeMode::Observer,
+                map_size: self.store.map_size_mb.saturating_mul(1024 * 1024),
+                sync: self.sync_policy()?,
+                read_only: false,
+            },
+        })
+    }
+
+    pub fn work_config(&self) -> WorkConfig {
+        WorkConfig {
+            threads: if self.work.threads == 0 { None } else { Some(self.work.threads) },
+            pregeneration_limit: self.work.pregeneration_limit,
+            opencl: self.work.opencl.as_ref().map(|opencl| OpenClConfig {
+                platform: opencl.platform,
+                device: opencl.device,
+                threads: opencl.threads,
+            }),
+            dpow: self.work.dpow.as_ref().map(|dpow| DpowConfig {
+                url: dpow.url.clone(),
+                user: dpow.user.clone(),
+                api_key: dpow.api_key.clone(),
+                timeout: dpow.timeout,
+                precache: dpow.precache,
+            }),
+            // Seeded work is only wanted in tests, which run on the dev network
+            seed: self.node.seed.filter(|_| self.node.network == "dev"),
+        }
+    }
+
+    pub fn snapshot_config(&self) -> Result<Option<SnapshotConfig>> {
+        let snapshot = match self.snapshot {
+            Some(ref snapshot) => snapshot,
+            None => return Ok(None),
+        };
+        if !snapshot.url.starts_with("https://") {
+            return Err(invalid("snapshot.url", "must be an https:// URL"));
+        }
+        let format = DumpFormat::from_name(&snapshot.format)
+            .ok_or_else(|| invalid("snapshot.format", "expected json or csv"))?;
+        let mut checksum = [0u8; 32];
+        match HEXUPPER.decode(snapshot.checksum.to_uppercase().as_bytes()) {
+            Ok(ref bytes) if bytes.len() == 32 => checksum.copy_from_slice(bytes),
+            _ => return Err(invalid("snapshot.checksum", "must be 64 hex digits")),
+        }
+        Ok(Some(SnapshotConfig {
+            url: snapshot.url.clone(),
+            format,
+            checksum,
+            spot_checks: snapshot.spot_checks,
+        }))
+    }
+
+    /// The accounts of `payment.deposit_accounts`, each at most once
+    pub fn deposit_accounts(&self) -> Result<Vec<PublicKey>> {
+        let mut accounts = Vec::with_capacity(self.payment.deposit_accounts.len());
+        for address in &self.payment.deposit_accounts {
+            let account = Address(address.clone()).to_public_key()
+                .map_err(|e| invalid("payment.deposit_accounts", format!("{}: {}", address, e)))?;
+            if accounts.contains(&account) {
+                return Err(invalid("payment.deposit_accounts", format!("{} is listed twice", address)));
+            }
+            accounts.push(account);
+        }
+        Ok(accounts)
+    }
+
+    pub fn backup_config(&self) -> Result<Option<BackupConfig>> {
+        let backup = match self.backup {
+            Some(ref backup) => backup,
+            None => return Ok(None),
+        };
+        let interval = match backup.schedule.as_str() {
+            "daily" => Duration::from_secs(24 * 60 * 60),
+            "weekly" => Duration::from_secs(7 * 24 * 60 * 60),
+            schedule => return Err(invalid("backup.schedule",
+                format!("unknown schedule `{}`, expected daily or weekly", schedule))),
+        };
+        if backup.keep == 0 {
+            return Err(invalid("backup.keep", "must be greater than 0"));
+        }
+        Ok(Some(BackupConfig {
+            directory: PathBuf::from(&backup.directory),
+            interval,
+            keep: backup.keep,
+            format: DumpFormat::from_name(&backup.format)
+                .ok_or_else(|| invalid("backup.format", "expected json or csv"))?,
+            command: backup.command.clone(),
+        }))
+    }
+
+    pub fn webhook_config(&self) -> Result<Option<WebhookConfig>> {
+        let webhook = match self.webhook {
+            Some(ref webhook) => webhook,
+            None => return Ok(None),
+        };
+        if !webhook.url.starts_with("http://") && !webhook.url.starts_with("https://") {
+            return Err(invalid("webhook.url", "must be an http:// or https:// URL"));
+        }
+        let mut accounts = Vec::with_capacity(webhook.accounts.len());
+        for address in &webhook.accounts {
+            accounts.push(Address(address.clone()).to_public_key()
+                .map_err(|e| invalid("webhook.accounts", format!("{}: {}", address, e)))?);
+        }
+        if webhook.retry_interval_secs == 0 {
+            return Err(invalid("webhook.retry_interval_secs", "must be greater than 0"));
+        }
+        if webhook.max_attempts == 0 {
+            return Err(invalid("webhook.max_attempts", "must be greater than 0"));
+        }
+        Ok(Some(WebhookConfig {
+            url: webhook.url.clone(),
+            accounts,
+            timeout: Duration::from_millis(webhook.timeout_ms),
+            retry_interval: Duration::from_secs(webhook.retry_interval_secs),
+            max_attempts: webhook.max_attempts,
+        }))
+    }
+
+    pub fn signer_config(&self) -> Result<Option<SignerConfig>> {
+        let signer = match self.signer {
+            Some(ref signer) => signer,
+            None => return Ok(None),
+        };
+        let account = Address(signer.account.clone()).to_public_key()
+            .map_err(|e| invalid("signer.account", format!("{}", e)))?;
+        let endpoint = match (&signer.url, &signer.ipc_path, &signer.key_file) {
+            (&Some(ref url), &None, &None) => {
+                if !url.starts_with("http://") && !url.starts_with("https://") {
+                    return Err(invalid("signer.url", "must be an http:// or https:// URL"));
+                }
+                Endpoint::Http(url.clone())
+            }
+            (&None, &Some(ref path), &None) => Endpoint::Ipc(PathBuf::from(path)),
+            (&None, &None, &Some(ref path)) => Endpoint::KeyFile(PathBuf::from(path)),
+            _ => return Err(invalid("signer", "exactly one of url, ipc_path and key_file must be set")),
+        };
+        Ok(Some(SignerConfig {
+            account,
+            endpoint,
+            timeout: Duration::from_millis(signer.timeout_ms),
+        }))
+    }
+
+    /// The whitelisted peers, resolved, if the node is whitelist-only
+    fn whitelist(&self) -> Result<Option<Whitelist>> {
+        if !self.node.whitelist_only {
+            return Ok(None);
+        }
+        let mut whitelist = Whitelist::default();
+        for peer in &self.whitelist.peers {
+            if let Ok(ip) = peer.parse::<IpAddr>() {
+                whitelist.hosts.push(*to_ipv6(SocketAddr::new(ip, 0)).ip());
+                continue;
+            }
+            // A bare host name resolves with port 0, allowing it on any port
+            let resolved = match peer.to_socket_addrs() {
+                Ok(addrs) => Ok(addrs.collect::<Vec<_>>()),
+                Err(_) => (peer.as_str(), 0).to_socket_addrs().map(|addrs| addrs.collect()),
+            };
+            match resolved {
+                Ok(addrs) => for addr in addrs {
+                    match addr.port() {
+                        0 => whitelist.hosts.push(*to_ipv6(addr).ip()),
+                        _ => whitelist.addrs.push(to_ipv6(addr)),
+                    }
+                },
+                Err(e) => warn!("Could not resolve whitelisted peer {}: {}", peer, e),
+            }
+        }
+        if whitelist.addrs.is_empty() && whitelist.hosts.is_empty() {
+            return Err(invalid("whitelist.peers", "a whitelist-only node needs a whitelisted peer it can reach"));
+        }
+        Ok(Some(whitelist))
+    }
+
+    /// Build the node configuration, resolving preconfigured peers
+    pub fn node_config(&self) -> Result<NodeConfig> {
+        let network = self.network()?;
+        let whitelist = self.whitelist()?;
+        let configured = self.node.preconfigured_peers.as_ref().unwrap_or(&network.default_peers);
+        let mut peers: Vec<SocketAddr> = Vec::new();
+        for peer in configured.iter() {
+            match peer.to_socket_addrs() {
+                Ok(addrs) => peers.extend(addrs),
+                Err(e) => warn!("Could not resolve preconfigured peer {}: {}", peer, e),
+            }
+        }
+        if peers.is_empty() && !configured.is_empty() {
+            return Err(invalid("node.preconfigured_peers", "none of the preconfigured peers could be resolved"));
+        }
+        if let Some(ref whitelist) = whitelist {
+            peers.retain(|&peer| whitelist.allows(to_ipv6(peer)));
+            for &addr in &whitelist.addrs {
+                if !peers.contains(&SocketAddr::V6(addr)) {
+                    peers.push(SocketAddr::V6(addr));
+                }
+            }
+        }
+        let mut peering = if self.node.outbound_only {
+            let mut persistent = Vec::new();
+            for peer in &self.outbound.persistent_peers {
+                match peer.to_socket_addrs() {
+                    Ok(addrs) => persistent.extend(addrs.map(to_ipv6)),
+                    Err(e) => warn!("Could not resolve persistent peer {}: {}", peer, e),
+                }
+            }
+            if self.outbound.persistent_peers.is_empty() {
+                persistent.extend(peers.iter().cloned().map(to_ipv6));
+            }
+            if persistent.is_empty() {
+                return Err(invalid("outbound.persistent_peers",
+                    "an outbound-only node needs a persistent peer it can reach"));
+            }
+            PeeringConfig {
+                outbound_only: true,
+                persistent,
+                keepalive_interval: Duration::from_secs(self.outbound.keepalive_interval_secs),
+                whitelist: None,
+            }
+        } else {
+            PeeringConfig::default()
+        };
+        peering.whitelist = whitelist;
+        let listen_addrs = self.listen_addrs(network.default_port)?;
+        Ok(NodeConfig {
+            mode: self.node_mode()?,
+            peers,
+            network,
+            listen_addrs,
+            dscp: self.node.dscp,
+            seed: self.node.seed,
+            mdns: if self.node.enable_mdns { Some(self.node.network.clone()) } else { None },
+            peering,
+            rpc_addr: if self.rpc.enable {
+                Some(parse_addr("rpc.address", &self.rpc.address)?)
+            } else {
+                None
+            },
+            work_server_addr: if self.rpc.work_server_enable {
+                Some(parse_addr("rpc.work_server_address", &self.rpc.work_server_address)?)
+            } else {
+                None
+            },
+            work: self.work_config(),
+            block_processor_threads: match self.node.block_processor_threads {
+                0 => ::num_cpus::get(),
+                threads => threads,
+            },
+            signature_checker_threads: match self.node.signature_checker_threads {
+                0 => ::num_cpus::get(),
+                threads => threads,
+            },
+            drop_policy: self.drop_policy()?,
+            store: self.store_config()?,
+            voting: self.voting_config()?,
+            pruning: if self.node_mode()? == NodeMode::Pruned {
+                Some(PruningConfig {
+                    keep_depth: self.pruning.keep_depth,
+                    interval: Duration::from_secs(self.pruning.interval_secs),
+                })
+            } else {
+                None
+            },
+            snapshot: self.snapshot_config()?,
+            signer: self.signer_config()?,
+            deposit_accounts: self.deposit_accounts()?,
+            webhook: self.webhook_config()?,
+            backup: self.backup_config()?,
+        })
+    }
+}
+
+impl CustomNetworkSection {
+    fn params(&self) -> Result<NetworkParams> {
+        let kind = NetworkParams::kind_from_id(&self.id)
+            .map_err(|e| invalid("custom_network.id", format!("{}", e)))?;
+        let work_threshold = difficulty::from_hex(&self.work_threshold)
+            .ok_or_else(|| invalid("custom_network.work_threshold", "must be 16 hex digits"))?;
+        let genesis_account = Address(self.genesis_account.clone()).to_public_key()
+            .map_err(|e| invalid("custom_network.genesis_account", format!("{}", e)))?;
+        let genesis_block = match self.genesis_block {
+            Some(ref hash) if !hash.is_empty() => Some(BlockHash::from_hex(hash.to_uppercase())
+                .map_err(|_| invalid("custom_network.genesis_block", "must be a 64 digit hex block hash"))?),
+            _ => None,
+        };
+        let burn_accounts = match self.burn_accounts {
+            Some(ref addresses) => {
+                let mut accounts = Vec::with_capacity(addresses.len());
+                for address in addresses {
+                    accounts.push(Address(address.clone()).to_public_key()
+                        .map_err(|e| invalid("custom_network.burn_accounts", format!("{}: {}", address, e)))?);
+                }
+                accounts
+            }
+            None => vec![network::burn_account()],
+        };
+        let epoch_signers = match self.epoch_signers {
+            Some(ref addresses) => {
+                let mut signers = Vec::with_capacity(addresses.len());
+                for address in addresses {
+                    signers.push(Address(address.clone()).to_public_key()
+                        .map_err(|e| invalid("custom_network.epoch_signers", format!("{}: {}", address, e)))?);
+                }
+                signers
+            }
+            None => vec![genesis_account.clone(); 2],
+        };
+        Ok(NetworkParams {
+            kind,
+            default_port: self.port,
+            default_peers: Vec::new(),
+            work_threshold,
+            genesis_account,
+            genesis_block,
+            burn_accounts,
+            final_votes_canary: None,
+            epoch_signers,
+        })
+    }
+}
+
+/// The outcome of reloading the configuration file
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReloadSummary {
+    /// Settings which changed and were applied immediately
+    pub applied: Vec<&'static str>,
+    /// Sections which changed but only take effect after a restart
+    pub restart_required: Vec<&'static str>,
+}
+
+/// Re-reads the configuration file the node was started with and applies the
+/// settings which can safely change at runtime
+#[derive(Debug)]
+pub struct ConfigReloader {
+    path: PathBuf,
+    current: Mutex<Config>,
+    log_filter: LogFilter,
+}
+
+impl ConfigReloader {
+    /// Create a reloader for `config`, which was loaded from `path`
+    pub fn new<P: Into<PathBuf>>(path: P, config: Config, log_filter: LogFilter) -> Self {
+        ConfigReloader {
+            path: path.into(),
+            current: Mutex::new(config),
+            log_filter,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The `[rpc]` section the node was started with, as changes need a restart
+    pub fn rpc(&self) -> RpcSection {
+        self.current.lock().unwrap().rpc.clone()
+    }
+
+    /// The filter of the running logger, which may also be changed over RPC
+    pub fn log_filter(&self) -> &LogFilter {
+        &self.log_filter
+    }
+
+    /// Load the file again and apply any changed log levels and work settings to `work`.
+    /// Applying new levels replaces any filter that was set over RPC.
+    /// If the file is invalid nothing is applied and the previous configuration is kept.
+    pub fn reload(&self, work: &WorkPool) -> Result<ReloadSummary> {
+        let new = Config::load(&self.path)?;
+        let mut current = self.current.lock().unwrap();
+        let mut summary = ReloadSummary::default();
+
+        if new.logging.level != current.logging.level || new.logging.modules != current.logging.modules {
+            self.log_filter.set(&new.log_directives()?)?;
+            if new.logging.level != current.logging.level {
+                summary.applied.push("logging.level");
+            }
+            if new.logging.modules != current.logging.modules {
+                summary.applied.push("logging.modules");
+            }
+            current.logging.level = new.logging.level.clone();
+            current.logging.modules = new.logging.modules.clone();
+        }
+        if new.work != current.work {
+            work.reconfigure(new.work_config());
+            current.work = new.work.clone();
+            summary.applied.push("work");
+        }
+
+        if new.node != current.node {
+            summary.restart_required.push("node");
+        }
+        if new.custom_network != current.custom_network {
+            summary.restart_required.push("custom_network");
+        }
+        if new.store != current.store {
+            summary.restart_required.push("store");
+        }
+        if new.outbound != current.outbound {
+            summary.restart_required.push("outbound");
+        }
+        if new.whitelist != current.whitelist {
+            summary.restart_required.push("whitelist");
+        }
+        if new.voting != current.voting {
+            summary.restart_required.push("voting");
+        }
+        if new.pruning != current.pruning {
+            summary.restart_required.push("pruning");
+        }
+        if new.snapshot != current.snapshot {
+            summary.restart_required.push("snapshot");
+        }
+        if new.rpc != current.rpc {
+            summary.restart_required.push("rpc");
+        }
+        if new.payment != current.payment {
+            summary.restart_required.push("payment");
+        }
+        if new.webhook != current.webhook {
+            summary.restart_required.push("webhook");
+        }
+        if new.backup != current.backup {
+            summary.restart_required.push("backup");
+        }
+        if new.signer != current.signer {
+            summary.restart_required.push("signer");
+        }
+        if new.logging.format != current.logging.format {
+            summary.restart_required.push("logging.format");
+        }
+        if new.logging.span_timings != current.logging.span_timings {
+            summary.restart_required.push("logging.span_timings");
+        }
+        if new.logging.directory != current.logging.directory {
+            summary.restart_required.push("logging.directory");
+        }
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddrV6;
+    use nano_lib_rs::message::NetworkKind;
+
+    #[test]
+    fn default_config_matches_defaults() {
+        let parsed = Config::from_toml(DEFAULT_CONFIG).expect("default config should parse");
+        let defaults = Config::default();
+        assert_eq!(parsed.node.network, defaults.node.network);
+        assert_eq!(parsed.node.mode, defaults.node.mode);
+        assert_eq!(parsed.node.enable_voting, defaults.node.enable_voting);
+        assert_eq!(parsed.node.outbound_only, defaults.node.outbound_only);
+        assert_eq!(parsed.outbound, defaults.outbound);
+        assert_eq!(parsed.voting, defaults.voting);
+        assert_eq!(parsed.node.listen_addr, defaults.node.listen_addr);
+        assert_eq!(parsed.node.listen_addrs, defaults.node.listen_addrs);
+        assert_eq!(parsed.node.dscp, defaults.node.dscp);
+        assert_eq!(parsed.node.preconfigured_peers, defaults.node.preconfigured_peers);
+        assert_eq!(parsed.node.block_processor_threads, defaults.node.block_processor_threads);
+        assert_eq!(parsed.node.signature_checker_threads, defaults.node.signature_checker_threads);
+        assert_eq!(parsed.node.drop_order, defaults.node.drop_order);
+        assert_eq!(parsed.node.seed, defaults.node.seed);
+        assert_eq!(parsed.store, defaults.store);
+        assert_eq!(parsed.rpc.enable, defaults.rpc.enable);
+        assert_eq!(parsed.rpc.address, defaults.rpc.address);
+        assert_eq!(parsed.rpc.work_server_enable, defaults.rpc.work_server_enable);
+        assert_eq!(parsed.rpc.work_server_address, defaults.rpc.work_server_address);
+        assert_eq!(parsed.rpc.enable_key_derivation, defaults.rpc.enable_key_derivation);
+        assert_eq!(parsed.rpc.max_connections, defaults.rpc.max_connections);
+        assert_eq!(parsed.rpc.max_connections_per_ip, defaults.rpc.max_connections_per_ip);
+        assert_eq!(parsed.rpc.idle_timeout_secs, defaults.rpc.idle_timeout_secs);
+        assert_eq!(parsed.payment, defaults.payment);
+        assert_eq!(parsed.work.threads, defaults.work.threads);
+        assert_eq!(parsed.work.pregeneration_limit, defaults.work.pregeneration_limit);
+        assert!(parsed.work.opencl.is_none());
+        assert!(parsed.work.dpow.is_none());
+        assert!(parsed.webhook.is_none());
+        assert!(parsed.signer.is_none());
+        assert!(parsed.snapshot.is_none());
+        assert_eq!(parsed.pruning, defaults.pruning);
+        assert_eq!(parsed.logging.level, defaults.logging.level);
+        assert_eq!(parsed.logging.format, defaults.logging.format);
+        assert_eq!(parsed.logging.span_timings, defaults.logging.span_timings);
+        assert_eq!(parsed.logging.directory, defaults.logging.directory);
+        assert_eq!(parsed.logging.modules, defaults.logging.modules);
+    }
+
+    #[test]
+    fn empty_config_uses_defaults() {
+        let config = Config::from_toml("").unwrap();
+        assert_eq!(config.network().unwrap().kind, NetworkKind::Main);
+    }
+
+    #[test]
+    fn parses_custom_network() {
+        let config = Config::from_toml(r#"
+            [node]
+            network = "custom"
+            [custom_network]
+            id = "A"
+            port = 44000
+            work_threshold = "ff00000000000000"
+            genesis_account = "nano_3e3j5tkog48pnny9dmfzj1r16pg8t1e76dz5tmac6iq689wyjfpiij4txtdo"
+        "#).unwrap();
+        let network = config.network().unwrap();
+        assert_eq!(network.kind, NetworkKind::Dev);
+        assert_eq!(network.work_threshold, 0xff00000000000000);
+        assert_eq!(network.burn_accounts, vec![nano_lib_rs::network::burn_account()]);
+        assert_eq!(network.epoch_signers, vec![network.genesis_account.clone(); 2]);
+        assert!(Config::from_toml("[node]\nnetwork = \"custom\"\n").is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let err = Config::from_toml("[node]\nlisten_address = \"[::]:7075\"\n").unwrap_err();
+        assert!(format!("{}", err).contains("listen_address"));
+    }
+
+    #[test]
+    fn reports_offending_key() {
+        let err = Config::from_toml("[rpc]\naddress = \"localhost\"\n").unwrap_err();
+        match *err.kind() {
+            ErrorKind::ConfigError(ref key, _) => assert_eq!(key, "rpc.address"),
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+        let err = Config::from_toml("[node]\nnetwork = \"moon\"\n").unwrap_err();
+        match *err.kind() {
+            ErrorKind::ConfigError(ref key, _) => assert_eq!(key, "node.network"),
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn parses_work_sources() {
+        let config = Config::from_toml(r#"
+            [work]
+            threads = 4
+            [work.opencl]
+            device = 1
+            [work.dpow]
+            url = "https://dpow.nanocenter.org/service/"
+            user = "user"
+            api_key = "key"
+        "#).unwrap();
+        let work = config.work_config();
+        assert_eq!(work.threads, Some(4));
+        assert_eq!(work.opencl.unwrap().device, 1);
+        assert_eq!(work.dpow.unwrap().timeout, 15);
+    }
+
+    #[test]
+    fn parses_signer() {
+        let config = Config::from_toml(r#"
+            [signer]
+            account = "nano_3e3j5tkog48pnny9dmfzj1r16pg8t1e76dz5tmac6iq689wyjfpiij4txtdo"
+            ipc_path = "/run/nano-signer.sock"
+        "#).unwrap();
+        let signer = config.signer_config().unwrap().unwrap();
+        assert_eq!(signer.endpoint, Endpoint::Ipc(PathBuf::from("/run/nano-signer.sock")));
+        assert_eq!(signer.timeout, Duration::from_millis(5000));
+        let config = Config::from_toml(r#"
+            [signer]
+            account = "nano_3e3j5tkog48pnny9dmfzj1r16pg8t1e76dz5tmac6iq689wyjfpiij4txtdo"
+            key_file = "/etc/nano-rs/signer.key"
+        "#).unwrap();
+        let signer = config.signer_config().unwrap().unwrap();
+        assert_eq!(signer.endpoint, Endpoint::KeyFile(PathBuf::from("/etc/nano-rs/signer.key")));
+        let err = Config::from_toml(r#"
+            [signer]
+            account = "nano_3e3j5tkog48pnny9dmfzj1r16pg8t1e76dz5tmac6iq689wyjfpiij4txtdo"
+        "#).unwrap_err();
+        match *err.kind() {
+            ErrorKind::ConfigError(ref key, _) => assert_eq!(key, "signer"),
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn parses_outbound_only() {
+        let config = Config::from_toml(r#"
+            [node]
+            outbound_only = true
+            preconfigured_peers = ["[::1]:7075"]
+            [outbound]
+            keepalive_interval_secs = 15
+        "#).unwrap();
+        let peering = config.node_config().unwrap().peering;
+        assert!(peering.outbound_only);
+        assert_eq!(peering.persistent, vec!["[::1]:7075".parse::<SocketAddrV6>().unwrap()]);
+        assert_eq!(peering.cutoff(), Duration::from_secs(75));
+        let err = Config::from_toml("[outbound]\nkeepalive_interval_secs = 0\n").unwrap_err();
+        match *err.kind() {
+            ErrorKind::ConfigError(ref key, _) => assert_eq!(key, "outbound.keepalive_interval_secs"),
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn parses_whitelist() {
+        let config = Config::from_toml(r#"
+            [node]
+            whitelist_only = true
+            preconfigured_peers = ["[2001:db8::1]:7075", "[2001:db8::2]:7075"]
+            [whitelist]
+            peers = ["[2001:db8::1]:7075", "2001:db8::3"]
+        "#).unwrap();
+        let node = config.node_config().unwrap();
+        let allowed = |addr: &str| node.peering.allows(addr.parse().unwrap());
+        assert!(allowed("[2001:db8::1]:7075"));
+        assert!(!allowed("[2001:db8::1]:7076"));
+        assert!(allowed("[2001:db8::3]:54000"));
+        assert!(!allowed("[2001:db8::2]:7075"));
+        assert_eq!(node.peers, vec!["[2001:db8::1]:7075".parse::<SocketAddr>().unwrap()]);
+        assert!(PeeringConfig::default().allows("[2001:db8::2]:7075".parse().unwrap()));
+        let config = Config::from_toml("[node]\nwhitelist_only = true\n").unwrap();
+        match *config.node_config().err().expect("whitelist_only without peers").kind() {
+            ErrorKind::ConfigError(ref key, _) => assert_eq!(key, "whitelist.peers"),
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn parses_backup() {
+        let config = Config::from_toml(r#"
+            [backup]
+            directory = "/var/backups/nano-rs"
+            schedule = "weekly"
+        "#).unwrap();
+        let backup = config.backup_config().unwrap().unwrap();
+        assert_eq!(backup.interval, Duration::from_secs(7 * 24 * 60 * 60));
+        assert_eq!((backup.keep, backup.format), (7, DumpFormat::Json));
+        let err = Config::from_toml("[backup]\ndirectory = \"/tmp\"\nschedule = \"hourly\"\n").unwrap_err();
+        match *err.kind() {
+            ErrorKind::ConfigError(ref key, _) => assert_eq!(key, "backup.schedule"),
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn parses_sync_policy() {
+        let config = Config::from_toml("[store]\nsync = \"batched\"\nsync_interval_ms = 250\n").unwrap();
+        assert_eq!(config.store_config().unwrap().lmdb.sync, SyncPolicy::Batched(Duration::from_millis(250)));
+        for toml in &["[store]\nsync = \"sometimes\"\n", "[store]\nsync = \"batched\"\nsync_interval_ms = 0\n"] {
+            match *Config::from_toml(toml).unwrap_err().kind() {
+                ErrorKind::ConfigError(ref key, _) => assert!(key.starts_with("store.sync")),
+                ref kind => panic!("unexpected error {:?}", kind),
+            }
+        }
+    }
+
+    #[test]
+    fn builds_log_directives() {
+        let config = Config::from_toml(r#"
+            [logging]
+            level = "warn"
+            format = "json"
+            [logging.modules]
+            "nano_rs::node" = "debug"
+            hyper = "off"
+        "#).unwrap();
+        assert_eq!(config.log_directives().unwrap(), "warn,hyper=off,nano_rs::node=debug");
+        assert_eq!(config.log_format().unwrap(), LogFormat::Json);
+        let err = Config::from_toml("[logging.modules]\n\"nano_rs\" = \"loud\"\n").unwrap_err();
+        match *err.kind() {
+            ErrorKind::ConfigError(ref key, _) => assert_eq!(key, "logging.modules.nano_rs"),
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+}
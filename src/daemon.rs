@@ -0,0 +1,133 @@
+//! Running as a long-lived daemon: pid files and graceful shutdown on SIGINT/SIGTERM
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use futures::channel::oneshot;
+use futures::future::{self, FutureExt, Shared};
+use futures::Future;
+
+use crate::error::*;
+
+/// Seconds the node is given to finish in-flight work once shutdown starts
+pub const SHUTDOWN_DEADLINE: u64 = 10;
+
+/// Writes the process id to a file, removing it again when dropped
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_owned();
+        let mut file = File::create(&path)?;
+        writeln!(file, "{}", process::id())?;
+        Ok(PidFile { path })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Could not remove pid file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// A shutdown trigger shared by every long-running task of the node. Tasks wrap
+/// themselves with `until`, so that once triggered they stop producing messages and
+/// drop their senders, letting the socket flush what is already queued and close.
+pub struct Shutdown {
+    triggered: AtomicBool,
+    tx: Mutex<Option<oneshot::Sender<()>>>,
+    rx: Shared<oneshot::Receiver<()>>,
+}
+
+impl fmt::Debug for Shutdown {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Shutdown")
+            .field("triggered", &self.is_triggered())
+            .finish()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (tx, rx) = oneshot::channel();
+        Shutdown {
+            triggered: AtomicBool::new(false),
+            tx: Mutex::new(Some(tx)),
+            rx: rx.shared(),
+        }
+    }
+
+    /// Start shutting down. Calling this more than once has no further effect.
+    pub fn trigger(&self) {
+        if let Some(tx) = self.tx.lock().unwrap().take() {
+            info!("Shutting down");
+            self.triggered.store(true, Ordering::SeqCst);
+            let _ = tx.send(());
+        }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::SeqCst)
+    }
+
+    /// A future which resolves once shutdown is triggered
+    pub fn signal(&self) -> impl Future<Output=()> + Send + 'static {
+        self.rx.clone().map(|_| ())
+    }
+
+    /// Run `task` until it completes or shutdown is triggered, whichever is first
+    pub fn until<F>(&self, task: F) -> impl Future<Output=()>
+        where F: Future<Output=()>
+    {
+        let signal = self.signal();
+        async move {
+            tokio::select! {
+                _ = task => {}
+                _ = signal => {}
+            }
+        }
+    }
+}
+
+/// Trigger `shutdown` on the first SIGINT or, on unix, SIGTERM
+pub async fn shutdown_on_signals(shutdown: Arc<Shutdown>) {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Error listening for SIGINT: {}", e);
+            future::pending::<()>().await;
+        }
+    };
+    tokio::select! {
+        _ = ctrl_c => shutdown.trigger(),
+        _ = terminate() => shutdown.trigger(),
+        _ = shutdown.signal() => {}
+    }
+}
+
+#[cfg(unix)]
+async fn terminate() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut sigterm) => {
+            sigterm.recv().await;
+        }
+        Err(e) => {
+            error!("Error listening for SIGTERM: {}", e);
+            future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn terminate() {
+    future::pending::<()>().await
+}
@@ -7,26 +7,49 @@ error_chain!{
             description("A non recoverable error occurred while processing a stream")
             display("A non recoverable error occurred while processing a stream")
         }
-        /// An error occurred with a Tokio-timer timeout
-        TokioTimeoutError(inner: String) {
-            description("Error in Tokio Timeout")
-            display("Error in tokio timeout: {}", inner)
+        /// A configuration value is invalid
+        ConfigError(key: String, message: String) {
+            description("Invalid configuration value")
+            display("Invalid value for `{}`: {}", key, message)
+        }
+        /// The configuration file could not be parsed
+        ConfigParseError(message: String) {
+            description("Could not parse configuration file")
+            display("{}", message)
+        }
+        /// An RPC request could not be completed
+        RpcError(message: String) {
+            description("RPC request failed")
+            display("{}", message)
+        }
+        /// A log filter could not be parsed or applied
+        LogFilterError(message: String) {
+            description("Invalid log filter")
+            display("Invalid log filter: {}", message)
         }
     }
     links{
         NanoLibError(::nano_lib_rs::error::Error, ::nano_lib_rs::error::ErrorKind) #[doc = "An error occurred in nano-lib"];
     }
     foreign_links{
-        FernInitError(::fern::InitError) #[doc = "An error occured while setting up fern"];
-        SetLoggerError(::log::SetLoggerError) #[doc = "An error occured while setting the logger"];
+        SetLoggerError(::log::SetLoggerError) #[doc = "An error occured while forwarding `log` records to tracing"];
+        SetSubscriberError(::tracing::subscriber::SetGlobalDefaultError) #[doc = "An error occured while installing the tracing subscriber"];
         IoError(::std::io::Error) #[doc = "An IO error occurred"];
         AddrParseError(::std::net::AddrParseError) #[doc = "An error occurred while parsing an address"];
-        TokioTimerError(::tokio_timer::TimerError) #[doc = "An error occurred in a tokio timer"];
+        HyperError(::hyper::Error) #[cfg(feature = "rpc")] #[doc = "An error occurred in the HTTP server"];
+        ReqwestError(::reqwest::Error) #[cfg(any(feature = "rpc", feature = "dpow"))] #[doc = "An error occurred while making an HTTP request"];
+        LmdbError(::lmdb::Error) #[cfg(feature = "lmdb")] #[doc = "An error occurred in the LMDB store"];
+        QrError(::qrcode::types::QrError) #[cfg(feature = "qr")] #[doc = "A QR code could not be generated"];
+        ImageError(::image::ImageError) #[cfg(feature = "qr")] #[doc = "A QR code could not be rendered"];
+        JsonError(::serde_json::Error) #[doc = "An error occurred while serializing or deserializing JSON"];
+        WindowsServiceError(::windows_service::Error) #[cfg(windows)] #[doc = "An error occurred while talking to the Windows service manager"];
+        EventLogError(::winlog::Error) #[cfg(windows)] #[doc = "An error occurred while registering with the Windows event log"];
     }
 }
 
-impl<T> From<::tokio_timer::TimeoutError<T>> for Error {
-    fn from(err: ::tokio_timer::TimeoutError<T>) -> Self {
-        Self::from_kind(ErrorKind::TokioTimeoutError(format!("{}", err)))
+/// Keys read from the store, or from the wire, which aren't valid curve points
+impl From<::nano_lib_rs::keys::DecodingError> for Error {
+    fn from(err: ::nano_lib_rs::keys::DecodingError) -> Self {
+        ::nano_lib_rs::error::Error::from(err).into()
     }
 }
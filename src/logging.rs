@@ -0,0 +1,164 @@
+//! Structured logging through `tracing`.
+//!
+//! Events are written to stderr and, if `logging.directory` is set, to a log file, either
+//! as text or as one JSON object per line. Records from dependencies which still use the
+//! `log` crate (nano-lib-rs, reqwest) are forwarded as events. Which events are kept
+//! is decided by an `EnvFilter` that can be replaced while the node is running.
+use std::fmt as std_fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use tracing_log::LogTracer;
+use tracing_subscriber::{fmt, reload, EnvFilter, Layer, Registry};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::time::ChronoLocal;
+use tracing_subscriber::layer::{Identity, SubscriberExt};
+
+use crate::config::Config;
+use crate::error::*;
+
+const TIME_FORMAT: &str = "[%Y-%m-%d][%H:%M:%S]";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    pub fn from_name(name: &str) -> Option<LogFormat> {
+        match name {
+            "text" => Some(LogFormat::Text),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// The level filter of the installed subscriber, as `tracing` filter directives such as
+/// `info,nano_rs::node=debug`
+pub struct LogFilter {
+    directives: Mutex<String>,
+    reload: Box<dyn Fn(EnvFilter) -> Result<()> + Send + Sync>,
+}
+
+impl LogFilter {
+    /// A filter which isn't attached to any subscriber, for nodes run by tests, which
+    /// must not install a global subscriber of their own
+    #[cfg(any(test, feature = "testing"))]
+    pub fn detached(directives: &str) -> Result<Self> {
+        parse_filter(directives)?;
+        Ok(LogFilter {
+            directives: Mutex::new(directives.to_owned()),
+            reload: Box::new(|_| Ok(())),
+        })
+    }
+
+    pub fn directives(&self) -> String {
+        self.directives.lock().unwrap().clone()
+    }
+
+    /// Replace the filter. Invalid directives are rejected and leave the filter unchanged.
+    pub fn set(&self, directives: &str) -> Result<()> {
+        let mut current = self.directives.lock().unwrap();
+        (self.reload)(parse_filter(directives)?)?;
+        *current = directives.to_owned();
+        Ok(())
+    }
+}
+
+impl std_fmt::Debug for LogFilter {
+    fn fmt(&self, f: &mut std_fmt::Formatter) -> std_fmt::Result {
+        f.debug_struct("LogFilter").field("directives", &self.directives()).finish()
+    }
+}
+
+fn parse_filter(directives: &str) -> Result<EnvFilter> {
+    EnvFilter::try_new(directives)
+        .map_err(|e| ErrorKind::LogFilterError(format!("{}", e)).into())
+}
+
+/// Appends to a log file shared by every writer the formatter creates
+struct LogFile(Arc<File>);
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self.0).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self.0).flush()
+    }
+}
+
+fn open_log_file(directory: &str) -> Result<Option<Arc<File>>> {
+    if directory.is_empty() {
+        return Ok(None);
+    }
+    let base_path = match fs::create_dir_all(directory) {
+        Ok(_) => format!("{}/", directory),
+        Err(_) => String::new(),
+    };
+    let path = format!("{}nano-rs__{}.log", base_path, chrono::Local::now().format("%Y-%m-%d__%H-%M-%S"));
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(Some(Arc::new(file)))
+}
+
+/// Install the global subscriber described by the `[logging]` section
+pub fn init(config: &Config) -> Result<LogFilter> {
+    init_with(config, Identity::new())
+}
+
+/// Install the global subscriber described by the `[logging]` section, also passing
+/// every event which passes the filter to `extra`
+pub fn init_with<L>(config: &Config, extra: L) -> Result<LogFilter>
+    where L: Layer<Registry> + Send + Sync + 'static
+{
+    let directives = config.log_directives()?;
+    let json = config.log_format()? == LogFormat::Json;
+    let file = open_log_file(&config.logging.directory)?;
+    let span_events = if config.logging.span_timings { FmtSpan::CLOSE } else { FmtSpan::NONE };
+
+    let (filter, handle) = reload::Layer::new(parse_filter(&directives)?);
+
+    let stderr_text = if json {
+        None
+    } else {
+        Some(fmt::layer()
+            .with_span_events(span_events.clone())
+            .with_timer(ChronoLocal::with_format(TIME_FORMAT.into()))
+            .with_writer(io::stderr))
+    };
+    let stderr_json = if json {
+        Some(fmt::layer().json().with_span_events(span_events.clone()).with_writer(io::stderr))
+    } else {
+        None
+    };
+    let file_text = file.clone().filter(|_| !json).map(|file| fmt::layer()
+        .with_ansi(false)
+        .with_span_events(span_events.clone())
+        .with_timer(ChronoLocal::with_format(TIME_FORMAT.into()))
+        .with_writer(move || LogFile(file.clone())));
+    let file_json = file.filter(|_| json).map(|file| fmt::layer()
+        .json()
+        .with_span_events(span_events)
+        .with_writer(move || LogFile(file.clone())));
+
+    let subscriber = Registry::default()
+        .with(extra)
+        .with(filter)
+        .with(stderr_text)
+        .with(stderr_json)
+        .with(file_text)
+        .with(file_json);
+
+    LogTracer::init()?;
+    tracing::subscriber::set_global_default(subscriber)?;
+
+    Ok(LogFilter {
+        directives: Mutex::new(directives),
+        reload: Box::new(move |filter| handle.reload(filter)
+            .map_err(|e| ErrorKind::LogFilterError(format!("{}", e)).into())),
+    })
+}
@@ -1,18 +1,19 @@
-#![feature(conservative_impl_trait)]
 extern crate tokio;
-extern crate tokio_io;
-extern crate tokio_timer;
+extern crate tokio_util;
 extern crate net2;
-#[macro_use]
+extern crate socket2;
 extern crate futures;
 
 extern crate data_encoding;
 
 extern crate nano_lib_rs;
+extern crate nanopow_rs;
 
-#[macro_use]
 extern crate log;
-extern crate fern;
+#[macro_use]
+extern crate tracing;
+extern crate tracing_log;
+extern crate tracing_subscriber;
 extern crate chrono;
 
 #[macro_use]
@@ -23,91 +24,150 @@ extern crate bytes;
 extern crate rand;
 extern crate indexmap;
 
+#[cfg(feature = "rpc")]
+extern crate hyper;
+#[cfg(any(feature = "rpc", feature = "dpow"))]
+extern crate reqwest;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate toml;
+extern crate clap;
+extern crate num_cpus;
+#[cfg(feature = "jemalloc")]
+extern crate jemallocator;
+#[cfg(feature = "jemalloc")]
+extern crate jemalloc_ctl;
+#[cfg(feature = "lmdb")]
+extern crate lmdb_rs as lmdb;
+#[cfg(feature = "lmdb")]
+extern crate lmdb_sys;
+#[cfg(feature = "qr")]
+extern crate qrcode;
+#[cfg(feature = "qr")]
+extern crate image;
+#[cfg(windows)]
+#[macro_use]
+extern crate windows_service;
+#[cfg(windows)]
+extern crate winlog;
+
+mod cli;
+mod config;
+mod daemon;
 mod error;
+mod logging;
+mod migrate;
 mod net;
+#[cfg(feature = "testing")]
+mod testing;
 mod utils;
 mod node;
+mod payment;
+#[cfg(feature = "rpc")]
+mod rpc;
+mod service;
+mod signer;
+mod stats;
+mod store;
+mod systemd;
+mod work;
 
-use error::*;
-use node::{NodeConfig};
+use crate::error::*;
+use crate::config::{Config, ConfigReloader};
+use crate::daemon::{PidFile, Shutdown};
+use crate::logging::LogFilter;
 
-use nano_lib_rs::message::NetworkKind;
+use clap::{App, Arg};
 
-use std::net::{ToSocketAddrs, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
 
-use futures::{Future};
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static ALLOCATOR: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
-fn run() -> Result<()> {
-    info!("Starting nano-rs!");
+const DEFAULT_CONFIG_PATH: &str = "nano-rs.toml";
 
-    let listen_addr = "[::]:7075".parse()?;
-    let peers: Vec<SocketAddr> = "rai.raiblocks.net:7075".to_socket_addrs()?.collect();
-    if let None = peers.get(0) {
-        return Err("Could not connect to initial peer".into());
-    }
-    let network = NetworkKind::Main;
+fn run(config_path: &str, config: Config, log_filter: LogFilter, pid_file: Option<&str>, shutdown: Arc<Shutdown>) -> Result<()> {
+    info!("Starting nano-rs!");
 
-    let config = NodeConfig {
-        peers,
-        network,
-        listen_addr,
+    let _pid_file = match pid_file {
+        Some(path) => Some(PidFile::create(path)?),
+        None => None,
     };
 
-    let mut runtime = tokio::runtime::Runtime::new()?;
-    let handle = runtime.handle().clone();
-    let node = node::run(config, &handle)?;
+    let node_config = config.node_config()?;
+    let reloader = ConfigReloader::new(config_path, config, log_filter);
 
-    runtime.spawn(node);
-    runtime.shutdown_on_idle().wait().unwrap();
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.spawn(daemon::shutdown_on_signals(shutdown.clone()));
+    let result = runtime.block_on(node::run(node_config, reloader, shutdown));
+    // Anything still running, such as in-flight RPC requests, is abandoned
+    runtime.shutdown_timeout(Duration::from_secs(1));
+    result?;
 
     info!("Stopping nano-rs!");
     Ok(())
 }
 
-fn setup_logger() -> Result<()> {
-    use std::fs::create_dir;
-    let base_path: &str = match create_dir("log") {
-        Ok(_) => {
-            "log/"
-        },
+fn main() {
+    let matches = App::new("nano-rs")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("A Nano node implementation")
+        .arg(Arg::with_name("config")
+            .long("config")
+            .value_name("FILE")
+            .help("Path to the configuration file (defaults to nano-rs.toml)")
+            .takes_value(true))
+        .arg(Arg::with_name("generate-config")
+            .long("generate-config")
+            .help("Print a documented default configuration file and exit"))
+        .arg(Arg::with_name("pid-file")
+            .long("pid-file")
+            .value_name("FILE")
+            .help("Write the process id to FILE while the node is running")
+            .takes_value(true))
+        .subcommands(cli::subcommands())
+        .subcommands(service::subcommands())
+        .get_matches();
+
+    if matches.is_present("generate-config") {
+        print!("{}", config::DEFAULT_CONFIG);
+        return;
+    }
+
+    match cli::run(&matches).and_then(|done| if done { Ok(true) } else { service::run(&matches) }) {
+        Ok(true) => return,
+        Ok(false) => (),
         Err(e) => {
-            if e.kind() == std::io::ErrorKind::AlreadyExists {
-                "log/"
-            } else {
-                ""
-            }
+            eprintln!("{}", e);
+            ::std::process::exit(1);
         }
-    };
-    fern::Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "{}[{}][{}] {}",
-                chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
-                record.target(),
-                record.level(),
-                message
-            ))
-        })
-        .level(log::LevelFilter::Info)
-        .level_for("tokio_reactor", log::LevelFilter::Error)
-        .chain(std::io::stderr())
-        .chain(fern::log_file(format!("{}nano-rs__{}.log", base_path, chrono::Local::now().format("%Y-%m-%d__%H-%M-%S")))?)
-        .apply()?;
-    Ok(())
-}
+    }
 
-fn main() {
-    // Setup logger
-    if let Err(e) = setup_logger() {
-        use std::io::Write;
-        let stderr = &mut ::std::io::stderr();
-        let errmsg = "Error writing to stderr";
+    let config_path = matches.value_of("config").unwrap_or(DEFAULT_CONFIG_PATH);
+    let config = match Config::load(config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration in {}: {}", config_path, e);
+            ::std::process::exit(1);
+        }
+    };
 
-        writeln!(stderr, "Error while initializing logger: {}", e).expect(errmsg);
-    }
+    let log_filter = match logging::init(&config) {
+        Ok(filter) => filter,
+        Err(e) => {
+            eprintln!("Error while initializing logger: {}", e);
+            ::std::process::exit(1);
+        }
+    };
 
     // Run program and log errors from error-chain using logger
-    if let Err(ref e) = run() {
+    let shutdown = Arc::new(Shutdown::new());
+    if let Err(ref e) = run(config_path, config, log_filter, matches.value_of("pid-file"), shutdown) {
 
         error!("Failed with error: {}", e);
 
@@ -0,0 +1,517 @@
+//! Migrating the data directory of an official (C++) node to nano-rs.
+//!
+//! The ledger is read from `data.ldb`, the official node's LMDB database, which must
+//! be at store version 15 or later (V19.0 onwards); older databases can be upgraded
+//! by starting a recent official node on them first. Blocks are read from the
+//! legacy per-kind tables or the unified `blocks` table, whichever the database
+//! has, without their sideband, and every block's hash is checked against its key.
+//! Account records keep the frontier, representative, balance and confirmation
+//! height; representative weights are recomputed from the balances, and the height of
+//! each block in its chain is indexed.
+//!
+//! The network is told by which genesis account the ledger contains. The peering
+//! port, preconfigured peers and RPC settings are carried over from
+//! `config-node.toml` and `config-rpc.toml`, or from `config.json` for older nodes,
+//! into a `nano-rs.toml` based on the documented defaults. nano-rs has no wallets,
+//! so `wallets.ldb` is left alone.
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+use serde_json::{self, Value};
+
+use nano_lib_rs::network::NetworkParams;
+
+use crate::config::{Config, DEFAULT_CONFIG};
+use crate::error::*;
+
+/// The oldest official store version which can be migrated
+pub const MIN_STORE_VERSION: u64 = 15;
+
+/// The networks a ledger can be recognised as belonging to, by genesis account
+fn networks() -> Vec<(&'static str, NetworkParams)> {
+    vec![
+        ("live", NetworkParams::live()),
+        ("beta", NetworkParams::beta()),
+        ("test", NetworkParams::test()),
+        ("dev", NetworkParams::dev()),
+    ]
+}
+
+/// What a migration produced
+#[derive(Clone, Debug, PartialEq)]
+pub struct MigrationSummary {
+    pub store_version: u64,
+    pub network: &'static str,
+    pub blocks: u64,
+    pub accounts: u64,
+    /// Whether the source had a `wallets.ldb`, which wasn't migrated
+    pub wallets_found: bool,
+}
+
+/// The settings carried over from an official node's configuration
+#[derive(Clone, Debug, Default, PartialEq)]
+struct OfficialConfig {
+    peering_port: Option<u16>,
+    preconfigured_peers: Option<Vec<String>>,
+    rpc_enable: Option<bool>,
+    rpc_address: Option<String>,
+    rpc_port: Option<u16>,
+}
+
+fn read_file(path: &Path) -> Result<Option<String>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    Ok(Some(contents))
+}
+
+fn port(value: i64, key: &str) -> Result<u16> {
+    if value <= 0 || value > i64::from(u16::max_value()) {
+        bail!("`{}` is not a valid port in {}", value, key);
+    }
+    Ok(value as u16)
+}
+
+fn strings(values: &[&str]) -> Vec<String> {
+    values.iter().map(|&value| value.to_owned()).collect()
+}
+
+/// Read the configuration of the official node in `dir`, if it has one
+fn read_config(dir: &Path) -> Result<OfficialConfig> {
+    let mut config = OfficialConfig::default();
+    if let Some(contents) = read_file(&dir.join("config-node.toml"))? {
+        let node: ::toml::Value = contents.parse()
+            .map_err(|e| Error::from(format!("Invalid config-node.toml: {}", e)))?;
+        if let Some(value) = node.get("node").and_then(|node| node.get("peering_port")) {
+            let value = value.as_integer().ok_or_else(|| Error::from("node.peering_port is not a number"))?;
+            config.peering_port = Some(port(value, "node.peering_port")?);
+        }
+        if let Some(peers) = node.get("node").and_then(|node| node.get("preconfigured_peers")) {
+            let peers = peers.as_array()
+                .and_then(|peers| peers.iter().map(|peer| peer.as_str()).collect::<Option<Vec<_>>>())
+                .ok_or_else(|| Error::from("node.preconfigured_peers is not a list of strings"))?;
+            config.preconfigured_peers = Some(strings(&peers));
+        }
+        config.rpc_enable = node.get("rpc").and_then(|rpc| rpc.get("enable")).and_then(|value| value.as_bool());
+        if let Some(contents) = read_file(&dir.join("config-rpc.toml"))? {
+            let rpc: ::toml::Value = contents.parse()
+                .map_err(|e| Error::from(format!("Invalid config-rpc.toml: {}", e)))?;
+            config.rpc_address = rpc.get("address").and_then(|value| value.as_str()).map(str::to_owned);
+            if let Some(value) = rpc.get("port").and_then(|value| value.as_integer()) {
+                config.rpc_port = Some(port(value, "port")?);
+            }
+        }
+    } else if let Some(contents) = read_file(&dir.join("config.json"))? {
+        // Every value of the legacy configuration is a string
+        let json: Value = serde_json::from_str(&contents)?;
+        let number = |value: &Value, key: &str| -> Result<Option<u16>> {
+            match value.as_str() {
+                Some(value) => {
+                    let value = value.parse().map_err(|_| Error::from(format!("{} is not a number", key)))?;
+                    Ok(Some(port(value, key)?))
+                }
+                None => Ok(None),
+            }
+        };
+        config.peering_port = number(&json["node"]["peering_port"], "node.peering_port")?;
+        if let Some(peers) = json["node"]["preconfigured_peers"].as_array() {
+            let peers = peers.iter().map(Value::as_str).collect::<Option<Vec<_>>>()
+                .ok_or_else(|| Error::from("node.preconfigured_peers is not a list of strings"))?;
+            config.preconfigured_peers = Some(strings(&peers));
+        }
+        config.rpc_enable = json["rpc_enable"].as_str().map(|value| value == "true");
+        config.rpc_address = json["rpc"]["address"].as_str().map(str::to_owned);
+        config.rpc_port = number(&json["rpc"]["port"], "rpc.port")?;
+    }
+    Ok(config)
+}
+
+/// Replace the line `from` of `config` with `to`
+fn set_line(config: &mut String, from: &str, to: &str) -> Result<()> {
+    let start = config.find(&format!("\n{}\n", from))
+        .ok_or_else(|| Error::from(format!("The default configuration has no line `{}`", from)))?;
+    config.replace_range(start + 1..start + 1 + from.len(), to);
+    Ok(())
+}
+
+/// The nano-rs configuration for a node on `network`, migrated from `official`
+fn migrated_config(official: &OfficialConfig, network: &str, params: &NetworkParams, store_path: &Path)
+    -> Result<String>
+{
+    let mut config = DEFAULT_CONFIG.to_owned();
+    set_line(&mut config, "network = \"live\"", &format!("network = \"{}\"", network))?;
+    if let Some(port) = official.peering_port {
+        set_line(&mut config, "# listen_addr = \"[::]:7075\"", &format!("listen_addr = \"[::]:{}\"", port))?;
+    }
+    if let Some(ref peers) = official.preconfigured_peers {
+        // The official node contacts its preconfigured peers on the network's port
+        let peers = peers.iter()
+            .map(|peer| if peer.contains(':') { peer.clone() } else { format!("{}:{}", peer, params.default_port) })
+            .collect::<Vec<_>>();
+        set_line(&mut config, "# preconfigured_peers = [\"rai.raiblocks.net:7075\"]",
+            &format!("preconfigured_peers = {}", serde_json::to_string(&peers)?))?;
+    }
+    let path = store_path.to_str().ok_or_else(|| Error::from("The store path is not valid UTF-8"))?;
+    set_line(&mut config, "path = \"data\"", &format!("path = {}", serde_json::to_string(path)?))?;
+    if let Some(enable) = official.rpc_enable {
+        set_line(&mut config, "enable = true", &format!("enable = {}", enable))?;
+    }
+    if official.rpc_address.is_some() || official.rpc_port.is_some() {
+        let host = official.rpc_address.as_ref().map_or("::1", String::as_str);
+        let host = if host.contains(':') { format!("[{}]", host) } else { host.to_owned() };
+        set_line(&mut config, "address = \"[::1]:7076\"",
+            &format!("address = \"{}:{}\"", host, official.rpc_port.unwrap_or(7076)))?;
+    }
+    Config::from_toml(&config).chain_err(|| "The migrated configuration is invalid")?;
+    Ok(config)
+}
+
+#[cfg(feature = "lmdb")]
+mod ledger {
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    use bytes::Bytes;
+    use lmdb::{Cursor, Database, Environment, EnvironmentFlags, RoTransaction, Transaction};
+
+    use nano_lib_rs::block::{Block, BlockHash, BlockKind};
+    use nano_lib_rs::keys::PublicKey;
+
+    use crate::error::*;
+    use crate::store::{AccountInfo, Store, WriteBatch};
+
+    /// The tables of one kind of block, in the store versions which have them
+    const BLOCK_TABLES: [(&str, BlockKind); 8] = [
+        ("send", BlockKind::Send),
+        ("receive", BlockKind::Receive),
+        ("open", BlockKind::Open),
+        ("change", BlockKind::Change),
+        ("state", BlockKind::State),
+        ("state_v0", BlockKind::State),
+        ("state_v1", BlockKind::State),
+        ("state_blocks", BlockKind::State),
+    ];
+
+    const ACCOUNT_TABLES: [&str; 3] = ["accounts", "accounts_v0", "accounts_v1"];
+
+    /// The length of the fields of an official account record which are read: the
+    /// head, representative, open block, balance, modification time and block count
+    const ACCOUNT_RECORD_LEN: usize = 32 + 32 + 32 + 16 + 8 + 8;
+
+    /// An official LMDB database, opened read only
+    pub struct OfficialLedger {
+        env: Environment,
+    }
+
+    impl OfficialLedger {
+        pub fn open(path: &Path) -> Result<Self> {
+            if !path.exists() {
+                bail!("{} doesn't exist", path.display());
+            }
+            let env = Environment::new()
+                .set_flags(EnvironmentFlags::NO_SUB_DIR | EnvironmentFlags::READ_ONLY)
+                .set_max_dbs(128)
+                .open(path)?;
+            Ok(OfficialLedger { env })
+        }
+
+        fn table(&self, name: &str) -> Result<Option<Database>> {
+            match self.env.open_db(Some(name)) {
+                Ok(db) => Ok(Some(db)),
+                Err(::lmdb::Error::NotFound) => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        }
+
+        /// The store version, kept big-endian in the last bytes of the value of key 1 of
+        /// the `meta` table
+        pub fn version(&self) -> Result<u64> {
+            let meta = self.table("meta")?.ok_or_else(|| Error::from("The database has no meta table"))?;
+            let mut key = [0u8; 32];
+            key[31] = 1;
+            let txn = self.env.begin_ro_txn()?;
+            let value = txn.get(meta, &key)?;
+            if value.len() != 32 {
+                bail!("Store version of {} bytes, expected 32", value.len());
+            }
+            let mut version = [0u8; 8];
+            version.copy_from_slice(&value[24..]);
+            Ok(u64::from_be_bytes(version))
+        }
+
+        /// Whether the ledger has an account record for `account`
+        pub fn has_account(&self, account: &PublicKey) -> Result<bool> {
+            let txn = self.env.begin_ro_txn()?;
+            for name in ACCOUNT_TABLES.iter() {
+                if let Some(table) = self.table(name)? {
+                    match txn.get(table, account.as_bytes()) {
+                        Ok(_) => return Ok(true),
+                        Err(::lmdb::Error::NotFound) => (),
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+            }
+            Ok(false)
+        }
+
+        /// Copy every block to `store`, returning how many there were
+        pub fn copy_blocks(&self, store: &dyn Store, batch_size: usize) -> Result<u64> {
+            let txn = self.env.begin_ro_txn()?;
+            let mut batch = WriteBatch::new();
+            let mut blocks = 0;
+            let mut copy = |key: &[u8], kind: BlockKind, value: &[u8], batch: &mut WriteBatch| -> Result<()> {
+                let hash = BlockHash::from_bytes(key)?;
+                let mut block = decode_block(kind, value)
+                    .chain_err(|| format!("Invalid block {}", String::from(hash)))?;
+                if block.hash(false)? != hash {
+                    bail!("Block {} is stored under the wrong hash", String::from(hash));
+                }
+                batch.put_block(hash, block);
+                blocks += 1;
+                if batch.len() >= batch_size.max(1) {
+                    store.write(batch)?;
+                    batch.clear();
+                }
+                Ok(())
+            };
+            if let Some(table) = self.table("blocks")? {
+                // Since store version 19 every block is in one table, prefixed with its kind
+                for (key, value) in txn.open_ro_cursor(table)?.iter() {
+                    let (&kind, value) = value.split_first().ok_or_else(|| Error::from("Empty block record"))?;
+                    let kind = BlockKind::from_value(kind)
+                        .ok_or_else(|| Error::from(format!("Unknown block kind {}", kind)))?;
+                    copy(key, kind, value, &mut batch)?;
+                }
+            }
+            for &(name, kind) in BLOCK_TABLES.iter() {
+                if let Some(table) = self.table(name)? {
+                    for (key, value) in txn.open_ro_cursor(table)?.iter() {
+                        copy(key, kind, value, &mut batch)?;
+                    }
+                }
+            }
+            store.write(&batch)?;
+            Ok(blocks)
+        }
+
+        /// Copy every account record to `store` along with the weights of their
+        /// representatives, which the `burn_accounts` don't count towards, returning
+        /// how many records there were
+        pub fn copy_accounts(&self, store: &dyn Store, burn_accounts: &[PublicKey], batch_size: usize)
+            -> Result<u64>
+        {
+            let txn = self.env.begin_ro_txn()?;
+            let confirmation_heights = self.table("confirmation_height")?;
+            let mut batch = WriteBatch::new();
+            let mut weights: HashMap<[u8; 32], (PublicKey, u128)> = HashMap::new();
+            let mut accounts = 0;
+            for name in ACCOUNT_TABLES.iter() {
+                let table = match self.table(name)? {
+                    Some(table) => table,
+                    None => continue,
+                };
+                for (key, value) in txn.open_ro_cursor(table)?.iter() {
+                    let account = PublicKey::from_bytes(key)?;
+                    let (mut info, block_count) = decode_account(value)
+                        .chain_err(|| format!("Invalid account record in {}", name))?;
+                    info.confirmation_height = confirmation_height(&txn, confirmation_heights, key, block_count)?;
+                    if !burn_accounts.contains(&account) {
+                        let weight = weights.entry(*info.representative.as_bytes())
+                            .or_insert_with(|| (info.representative.clone(), 0));
+                        weight.1 = weight.1.saturating_add(info.balance);
+                    }
+                    batch.put_account(account, info);
+                    accounts += 1;
+                    if batch.accounts().len() >= batch_size.max(1) {
+                        store.write(&batch)?;
+                        batch.clear();
+                    }
+                }
+            }
+            for (_, (representative, weight)) in weights {
+                batch.put_weight(representative, weight);
+            }
+            store.write(&batch)?;
+            Ok(accounts)
+        }
+    }
+
+    /// Decode a block stored by the official node, ignoring the sideband after it
+    fn decode_block(kind: BlockKind, value: &[u8]) -> Result<Block> {
+        let len = kind.size() + 64 + 8;
+        if kind.size() == 0 || value.len() < len {
+            bail!("{:?} block record of {} bytes", kind, value.len());
+        }
+        Ok(Block::deserialize_bytes(Bytes::copy_from_slice(&value[..len]), kind)?)
+    }
+
+    /// Decode an official account record, also returning the number of blocks of the
+    /// account. The balance is big-endian, and the block count little-endian.
+    pub fn decode_account(value: &[u8]) -> Result<(AccountInfo, u64)> {
+        if value.len() < ACCOUNT_RECORD_LEN {
+            bail!("Account record of {} bytes, expected at least {}", value.len(), ACCOUNT_RECORD_LEN);
+        }
+        let mut balance = [0u8; 16];
+        balance.copy_from_slice(&value[96..112]);
+        let mut block_count = [0u8; 8];
+        block_count.copy_from_slice(&value[120..128]);
+        Ok((AccountInfo {
+            head: BlockHash::from_bytes(&value[..32])?,
+            representative: PublicKey::from_bytes(&value[32..64])?,
+            balance: u128::from_be_bytes(balance),
+            confirmation_height: 0,
+        }, u64::from_le_bytes(block_count)))
+    }
+
+    /// The confirmation height of `account`. Store versions before 17 keep it as a
+    /// big-endian number, and later ones little-endian followed by the confirmed
+    /// frontier. Heights beyond the account's block count are distrusted and reset.
+    fn confirmation_height(txn: &RoTransaction, table: Option<Database>, account: &[u8], block_count: u64)
+        -> Result<u64>
+    {
+        let table = match table {
+            Some(table) => table,
+            None => return Ok(0),
+        };
+        let value = match txn.get(table, &account) {
+            Ok(value) => value,
+            Err(::lmdb::Error::NotFound) => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let mut height = [0u8; 8];
+        let height = match value.len() {
+            8 => {
+                height.copy_from_slice(value);
+                u64::from_be_bytes(height)
+            }
+            40 => {
+                height.copy_from_slice(&value[..8]);
+                u64::from_le_bytes(height)
+            }
+            len => bail!("Confirmation height record of {} bytes", len),
+        };
+        if height > block_count {
+            warn!("Confirmation height {} is beyond the {} blocks of its account, resetting it", height,
+                block_count);
+            return Ok(0);
+        }
+        Ok(height)
+    }
+}
+
+/// Migrate the official node data directory `source` into the new nano-rs data
+/// directory `destination`: an LMDB store in `destination/data`, and a
+/// `destination/nano-rs.toml` using it
+#[cfg(feature = "lmdb")]
+pub fn migrate(source: &Path, destination: &Path, batch_size: usize) -> Result<MigrationSummary> {
+    use crate::store::{heights, Store};
+    use crate::store::lmdb::LmdbStore;
+    use self::ledger::OfficialLedger;
+
+    let config_path = destination.join("nano-rs.toml");
+    if config_path.exists() {
+        bail!("{} already exists", config_path.display());
+    }
+    let ledger = OfficialLedger::open(&source.join("data.ldb"))?;
+    let store_version = ledger.version()?;
+    if store_version < MIN_STORE_VERSION {
+        bail!("The database is at store version {}, but only versions from {} can be migrated; \
+            start a recent official node on it to upgrade it first", store_version, MIN_STORE_VERSION);
+    }
+    let mut network = None;
+    for (name, params) in networks() {
+        if ledger.has_account(&params.genesis_account)? {
+            network = Some((name, params));
+            break;
+        }
+    }
+    let (network, params) = network
+        .ok_or_else(|| Error::from("The ledger doesn't have the genesis account of a known network"))?;
+    let official = read_config(source)?;
+
+    fs::create_dir_all(destination)?;
+    let store_path = destination.canonicalize()?.join("data");
+    let config = migrated_config(&official, network, &params, &store_path)?;
+    let store = LmdbStore::open(&store_path)?;
+    if store.block_count()? > 0 {
+        bail!("{} already holds a ledger", store_path.display());
+    }
+    info!("Migrating the {} ledger at store version {}", network, store_version);
+    let blocks = ledger.copy_blocks(&store, batch_size)?;
+    let accounts = ledger.copy_accounts(&store, &params.burn_accounts, batch_size)?;
+    heights::index(&store, batch_size)?;
+
+    let mut migrated_accounts = 0;
+    store.for_each_account(&mut |_, _| {
+        migrated_accounts += 1;
+        Ok(())
+    })?;
+    if store.block_count()? != blocks || migrated_accounts != accounts {
+        bail!("Read {} blocks and {} accounts, but the new store has {} blocks and {} accounts", blocks,
+            accounts, store.block_count()?, migrated_accounts);
+    }
+    fs::write(&config_path, config)?;
+    Ok(MigrationSummary {
+        store_version,
+        network,
+        blocks,
+        accounts,
+        wallets_found: source.join("wallets.ldb").exists(),
+    })
+}
+
+#[cfg(not(feature = "lmdb"))]
+pub fn migrate(_source: &Path, _destination: &Path, _batch_size: usize) -> Result<MigrationSummary> {
+    bail!("Migration needs nano-rs to be built with the `lmdb` feature")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrates_config() {
+        let dir = ::std::env::temp_dir().join(format!("nano-rs-migrate-config-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("config.json"), r#"{
+            "version": "16",
+            "rpc_enable": "true",
+            "rpc": { "address": "::ffff:127.0.0.1", "port": "7076" },
+            "node": { "peering_port": "54000", "preconfigured_peers": ["peering-beta.nano.org"] }
+        }"#).unwrap();
+        let official = read_config(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(official.peering_port, Some(54000));
+
+        let config = migrated_config(&official, "beta", &NetworkParams::beta(), Path::new("/srv/nano/data")).unwrap();
+        let config = Config::from_toml(&config).unwrap();
+        assert_eq!(config.node.network, "beta");
+        assert_eq!(config.node.listen_addr, Some("[::]:54000".into()));
+        assert_eq!(config.node.preconfigured_peers,
+            Some(vec![format!("peering-beta.nano.org:{}", NetworkParams::beta().default_port)]));
+        assert_eq!(config.store.path, "/srv/nano/data");
+        assert!(config.rpc.enable);
+        assert_eq!(config.rpc.address, "[::ffff:127.0.0.1]:7076");
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn decodes_accounts() {
+        let mut value = vec![1u8; 32];
+        value.extend_from_slice(&[2u8; 32]);
+        value.extend_from_slice(&[3u8; 32]);
+        value.extend_from_slice(&42u128.to_be_bytes());
+        value.extend_from_slice(&[0u8; 8]);
+        value.extend_from_slice(&7u64.to_le_bytes());
+        value.push(1);
+        let (info, block_count) = ledger::decode_account(&value).unwrap();
+        assert_eq!(info.head.as_bytes(), &[1u8; 32]);
+        assert_eq!(info.representative.as_bytes(), &[2u8; 32]);
+        assert_eq!(info.balance, 42);
+        assert_eq!(block_count, 7);
+        assert!(ledger::decode_account(&value[..100]).is_err());
+    }
+}
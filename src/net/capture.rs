@@ -0,0 +1,362 @@
+//! Capturing raw datagrams to a pcap file for offline inspection, e.g. in Wireshark.
+//!
+//! Each datagram is written with synthesized IPv6 and UDP headers carrying the peer
+//! and local addresses, so that captures can be filtered and dissected like traffic
+//! captured from the network interface. IPv4 peers appear as IPv4-mapped addresses.
+//! UDP checksums are left as zero.
+//!
+//! `PcapReader` reads the UDP datagrams back out of such captures, or out of captures
+//! taken with tcpdump on an Ethernet interface, for `replay`.
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV6};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::BufMut;
+
+use crate::error::*;
+use crate::stats::Direction;
+
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+/// The magic number of captures whose timestamps are in nanoseconds
+const PCAP_MAGIC_NANOS: u32 = 0xa1b23c4d;
+const LINKTYPE_ETHERNET: u32 = 1;
+/// Packets start with an IPv4 or IPv6 header
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+const SNAPLEN: u32 = 65535;
+const IPV6_HEADER_LEN: usize = 40;
+const UDP_HEADER_LEN: usize = 8;
+const IPPROTO_UDP: u8 = 17;
+
+/// Writes datagrams to `W` in the pcap format
+#[derive(Debug)]
+pub struct PcapWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Write the pcap file header to `inner`
+    pub fn new(mut inner: W) -> Result<Self> {
+        let mut header = Vec::with_capacity(24);
+        header.put_u32_le(PCAP_MAGIC);
+        header.put_u16_le(2);
+        header.put_u16_le(4);
+        header.put_i32_le(0);
+        header.put_u32_le(0);
+        header.put_u32_le(SNAPLEN);
+        header.put_u32_le(LINKTYPE_RAW);
+        inner.write_all(&header)?;
+        Ok(PcapWriter { inner })
+    }
+
+    /// Write a datagram sent from `src` to `dst` at `time`
+    pub fn write_datagram(&mut self, time: SystemTime, src: SocketAddrV6, dst: SocketAddrV6, data: &[u8]) -> Result<()> {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let udp_len = UDP_HEADER_LEN + data.len();
+        let packet_len = IPV6_HEADER_LEN + udp_len;
+        let mut record = Vec::with_capacity(16 + packet_len);
+
+        record.put_u32_le(since_epoch.as_secs() as u32);
+        record.put_u32_le(since_epoch.subsec_nanos() / 1000);
+        record.put_u32_le(packet_len as u32);
+        record.put_u32_le(packet_len as u32);
+
+        record.put_u32(6 << 28);
+        record.put_u16(udp_len as u16);
+        record.put_u8(IPPROTO_UDP);
+        record.put_u8(64);
+        record.put_slice(&src.ip().octets());
+        record.put_slice(&dst.ip().octets());
+
+        record.put_u16(src.port());
+        record.put_u16(dst.port());
+        record.put_u16(udp_len as u16);
+        record.put_u16(0);
+        record.put_slice(data);
+
+        self.inner.write_all(&record)?;
+        Ok(())
+    }
+}
+
+/// A UDP datagram read from a capture
+#[derive(Clone, Debug, PartialEq)]
+pub struct Datagram {
+    pub time: SystemTime,
+    pub src: SocketAddrV6,
+    pub dst: SocketAddrV6,
+    pub data: Vec<u8>,
+}
+
+/// Reads the UDP datagrams of a pcap capture from `R`, skipping other packets, and
+/// fragments, which the node's datagrams are too small to be split into
+#[derive(Debug)]
+pub struct PcapReader<R: Read> {
+    inner: R,
+    big_endian: bool,
+    nanos: bool,
+    linktype: u32,
+}
+
+fn read_u32(bytes: &[u8], big_endian: bool) -> u32 {
+    let mut word = [0u8; 4];
+    word.copy_from_slice(&bytes[..4]);
+    if big_endian { u32::from_be_bytes(word) } else { u32::from_le_bytes(word) }
+}
+
+fn read_u16(bytes: &[u8]) -> Option<u16> {
+    Some(u16::from_be_bytes([*bytes.get(0)?, *bytes.get(1)?]))
+}
+
+impl<R: Read> PcapReader<R> {
+    /// Read the pcap file header from `inner`
+    pub fn new(mut inner: R) -> Result<Self> {
+        let mut header = [0u8; 24];
+        inner.read_exact(&mut header).chain_err(|| "Capture is too short to be a pcap file")?;
+        let magic = read_u32(&header, false);
+        let (big_endian, nanos) = match magic {
+            PCAP_MAGIC => (false, false),
+            PCAP_MAGIC_NANOS => (false, true),
+            _ if magic.swap_bytes() == PCAP_MAGIC => (true, false),
+            _ if magic.swap_bytes() == PCAP_MAGIC_NANOS => (true, true),
+            _ => bail!("Not a pcap capture; pcapng captures can be converted with `editcap -F pcap`"),
+        };
+        let linktype = read_u32(&header[20..], big_endian);
+        match linktype {
+            LINKTYPE_ETHERNET | LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => {}
+            _ => bail!("Unsupported capture link type {}; capture on an Ethernet interface", linktype),
+        }
+        Ok(PcapReader { inner, big_endian, nanos, linktype })
+    }
+
+    /// The next UDP datagram of the capture, or `None` at its end
+    pub fn next_datagram(&mut self) -> Result<Option<Datagram>> {
+        let mut record = [0u8; 16];
+        loop {
+            if !self.read_record_header(&mut record)? {
+                return Ok(None);
+            }
+            let secs = read_u32(&record, self.big_endian);
+            let fraction = read_u32(&record[4..], self.big_endian);
+            let captured = read_u32(&record[8..], self.big_endian) as usize;
+            if captured > 4 * SNAPLEN as usize {
+                bail!("Capture record of {} bytes is corrupt", captured);
+            }
+            let mut packet = vec![0u8; captured];
+            self.inner.read_exact(&mut packet).chain_err(|| "Capture ends in the middle of a packet")?;
+            let nanos = if self.nanos { fraction } else { fraction.saturating_mul(1000) };
+            if let Some((src, dst, data)) = udp(self.linktype, &packet) {
+                return Ok(Some(Datagram {
+                    time: UNIX_EPOCH + Duration::new(u64::from(secs), nanos),
+                    src,
+                    dst,
+                    data: data.to_vec(),
+                }));
+            }
+        }
+    }
+
+    /// Fill `record`, returning false if the capture ended before it
+    fn read_record_header(&mut self, record: &mut [u8]) -> Result<bool> {
+        let mut filled = 0;
+        while filled < record.len() {
+            match self.inner.read(&mut record[filled..]) {
+                Ok(0) if filled == 0 => return Ok(false),
+                Ok(0) => bail!("Capture ends in the middle of a record header"),
+                Ok(read) => filled += read,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// The addresses and payload of the UDP datagram in `packet`, if it holds one
+fn udp(linktype: u32, packet: &[u8]) -> Option<(SocketAddrV6, SocketAddrV6, &[u8])> {
+    let ip = match linktype {
+        LINKTYPE_ETHERNET => {
+            let mut ethertype = read_u16(packet.get(12..)?)?;
+            let mut offset = 14;
+            // 802.1Q VLAN tag
+            if ethertype == 0x8100 {
+                ethertype = read_u16(packet.get(16..)?)?;
+                offset = 18;
+            }
+            if ethertype != 0x0800 && ethertype != 0x86dd {
+                return None;
+            }
+            packet.get(offset..)?
+        }
+        _ => packet,
+    };
+    match ip.first()? >> 4 {
+        4 => {
+            let header_len = usize::from(ip[0] & 0x0f) * 4;
+            let total_len = usize::from(read_u16(ip.get(2..)?)?);
+            let fragment = read_u16(ip.get(6..)?)?;
+            if header_len < 20 || *ip.get(9)? != IPPROTO_UDP || fragment & 0x3fff != 0 {
+                return None;
+            }
+            let mut src = [0u8; 4];
+            src.copy_from_slice(ip.get(12..16)?);
+            let mut dst = [0u8; 4];
+            dst.copy_from_slice(ip.get(16..20)?);
+            // Ethernet pads short frames, past the end given by the IP header
+            let end = ::std::cmp::min(total_len, ip.len());
+            udp_payload(ip.get(header_len..end)?, Ipv4Addr::from(src).to_ipv6_mapped(), Ipv4Addr::from(dst).to_ipv6_mapped())
+        }
+        6 => {
+            // Datagrams behind extension headers are skipped, as nodes never send them
+            if *ip.get(6)? != IPPROTO_UDP {
+                return None;
+            }
+            let payload_len = usize::from(read_u16(ip.get(4..)?)?);
+            let mut src = [0u8; 16];
+            src.copy_from_slice(ip.get(8..24)?);
+            let mut dst = [0u8; 16];
+            dst.copy_from_slice(ip.get(24..40)?);
+            let end = ::std::cmp::min(IPV6_HEADER_LEN + payload_len, ip.len());
+            udp_payload(ip.get(IPV6_HEADER_LEN..end)?, Ipv6Addr::from(src), Ipv6Addr::from(dst))
+        }
+        _ => None,
+    }
+}
+
+fn udp_payload(udp: &[u8], src: Ipv6Addr, dst: Ipv6Addr) -> Option<(SocketAddrV6, SocketAddrV6, &[u8])> {
+    let len = usize::from(read_u16(udp.get(4..)?)?);
+    if len < UDP_HEADER_LEN {
+        return None;
+    }
+    let data = udp.get(UDP_HEADER_LEN..::std::cmp::min(len, udp.len()))?;
+    Some((SocketAddrV6::new(src, read_u16(udp)?, 0, 0), SocketAddrV6::new(dst, read_u16(udp.get(2..)?)?, 0, 0), data))
+}
+
+#[derive(Debug)]
+struct ActiveCapture {
+    path: PathBuf,
+    writer: PcapWriter<File>,
+}
+
+/// A capture of the node's traffic which can be started and stopped while it runs
+#[derive(Debug, Default)]
+pub struct Capture {
+    active: Mutex<Option<ActiveCapture>>,
+}
+
+impl Capture {
+    pub fn new() -> Self {
+        Capture::default()
+    }
+
+    /// Start writing every datagram to a new pcap file at `path`, replacing any capture
+    /// already in progress
+    pub fn start<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref().to_owned();
+        let writer = PcapWriter::new(File::create(&path)?)?;
+        info!("Capturing datagrams to {}", path.display());
+        *self.active.lock().unwrap() = Some(ActiveCapture { path, writer });
+        Ok(())
+    }
+
+    /// Stop capturing, returning the path of the finished capture if one was running
+    pub fn stop(&self) -> Option<PathBuf> {
+        let capture = self.active.lock().unwrap().take()?;
+        info!("Stopped capturing datagrams to {}", capture.path.display());
+        Some(capture.path)
+    }
+
+    /// The file being captured to, if any
+    pub fn path(&self) -> Option<PathBuf> {
+        self.active.lock().unwrap().as_ref().map(|capture| capture.path.clone())
+    }
+
+    /// Record a datagram exchanged between `local` and `peer`, if capturing. Capturing
+    /// stops if the file can't be written.
+    pub fn record(&self, direction: Direction, local: SocketAddrV6, peer: SocketAddrV6, data: &[u8]) {
+        let mut active = self.active.lock().unwrap();
+        let failed = match *active {
+            Some(ref mut capture) => {
+                let (src, dst) = match direction {
+                    Direction::In => (peer, local),
+                    Direction::Out => (local, peer),
+                };
+                capture.writer.write_datagram(SystemTime::now(), src, dst, data).err()
+            }
+            None => return,
+        };
+        if let Some(e) = failed {
+            error!("Error writing packet capture, stopping: {}", e);
+            *active = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_pcap_records() {
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        let src: SocketAddrV6 = "[::ffff:192.0.2.1]:7075".parse().unwrap();
+        let dst: SocketAddrV6 = "[2001:db8::1]:54000".parse().unwrap();
+        let time = UNIX_EPOCH + Duration::new(1_500_000_000, 250_000_000);
+        writer.write_datagram(time, src, dst, b"RC").unwrap();
+        let out = writer.inner;
+
+        assert_eq!(out.len(), 24 + 16 + 40 + 8 + 2);
+        assert_eq!(&out[0..4], &[0xd4, 0xc3, 0xb2, 0xa1]);
+        assert_eq!(&out[20..24], &[101, 0, 0, 0]);
+        // Record header: seconds, microseconds, captured and original lengths
+        assert_eq!(&out[24..28], &[0x00, 0x2f, 0x68, 0x59]);
+        assert_eq!(&out[28..32], &[0x90, 0xd0, 0x03, 0x00]);
+        assert_eq!(&out[32..36], &[50, 0, 0, 0]);
+        // IPv6 header
+        let ip = &out[40..80];
+        assert_eq!(ip[0], 0x60);
+        assert_eq!(&ip[4..6], &[0, 10]);
+        assert_eq!(ip[6], 17);
+        assert_eq!(&ip[8..24], &src.ip().octets());
+        assert_eq!(&ip[24..40], &dst.ip().octets());
+        // UDP header and payload
+        assert_eq!(&out[80..88], &[0x1b, 0xa3, 0xd2, 0xf0, 0, 10, 0, 0]);
+        assert_eq!(&out[88..], b"RC");
+    }
+
+    #[test]
+    fn reads_datagrams_back() {
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        let src: SocketAddrV6 = "[::ffff:192.0.2.1]:7075".parse().unwrap();
+        let dst: SocketAddrV6 = "[2001:db8::1]:54000".parse().unwrap();
+        let time = UNIX_EPOCH + Duration::new(1_500_000_000, 250_000_000);
+        writer.write_datagram(time, src, dst, b"RC").unwrap();
+        writer.write_datagram(time, dst, src, b"").unwrap();
+        let mut reader = PcapReader::new(&writer.inner[..]).unwrap();
+        assert_eq!(reader.next_datagram().unwrap(), Some(Datagram { time, src, dst, data: b"RC".to_vec() }));
+        assert_eq!(reader.next_datagram().unwrap().unwrap().data, Vec::<u8>::new());
+        assert_eq!(reader.next_datagram().unwrap(), None);
+
+        // The same datagram in an Ethernet frame, as captured by tcpdump over IPv4
+        let mut capture = writer.inner[..24].to_vec();
+        capture[20] = LINKTYPE_ETHERNET as u8;
+        let mut frame = vec![0u8; 12];
+        frame.extend_from_slice(&[0x08, 0x00, 0x45, 0, 0, 30, 0, 0, 0x40, 0, 64, IPPROTO_UDP, 0, 0, 192, 0, 2, 1, 192, 0, 2, 2]);
+        frame.extend_from_slice(&[0x1b, 0xa3, 0x1b, 0xa3, 0, 10, 0, 0]);
+        frame.extend_from_slice(b"RC");
+        // Padded to Ethernet's minimum frame size
+        frame.resize(60, 0);
+        capture.extend_from_slice(&writer.inner[24..32]);
+        capture.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        capture.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        capture.extend_from_slice(&frame);
+        let datagram = PcapReader::new(&capture[..]).unwrap().next_datagram().unwrap().unwrap();
+        assert_eq!(datagram.src, "[::ffff:192.0.2.1]:7075".parse().unwrap());
+        assert_eq!(datagram.dst, "[::ffff:192.0.2.2]:7075".parse().unwrap());
+        assert_eq!(datagram.data, b"RC".to_vec());
+    }
+}
@@ -0,0 +1,299 @@
+//! Injecting faults into the frames a node sends, for testing under packet loss.
+//!
+//! `Faults` wraps the `Outgoing` sink of a channel and drops, duplicates, delays and
+//! reorders the frames sent into it, as set by a `FaultConfig`. Decisions are drawn
+//! from a generator seeded by the config, so a failing test can be run again with the
+//! same faults. Frames which aren't dropped are each held for a random delay between
+//! `min_delay` and `max_delay`; reordered frames are held `reorder_window` longer,
+//! so that the frames sent after them overtake them. `FaultyTransport` wraps every
+//! channel of a transport alike.
+#![cfg_attr(not(feature = "testing"), allow(dead_code))]
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{self, AtomicU64};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::{ready, Sink};
+use rand::{Rng, XorShiftRng};
+use tokio::time::{self, Instant, Sleep};
+
+use nano_lib_rs::message::Message;
+
+use crate::error::*;
+use crate::node::state::State;
+use crate::utils::seeded_rng;
+use super::{Incoming, Outgoing, Transport};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FaultConfig {
+    pub seed: u64,
+    /// The probability that a frame is dropped
+    pub drop: f64,
+    /// The probability that a frame is sent twice
+    pub duplicate: f64,
+    /// The probability that a frame is held back for `reorder_window`
+    pub reorder: f64,
+    pub reorder_window: Duration,
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for FaultConfig {
+    /// No faults at all
+    fn default() -> Self {
+        FaultConfig {
+            seed: 0,
+            drop: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            reorder_window: Duration::from_millis(50),
+            min_delay: Duration::from_millis(0),
+            max_delay: Duration::from_millis(0),
+        }
+    }
+}
+
+/// How many frames had each kind of fault injected
+#[derive(Debug, Default)]
+pub struct FaultCounts {
+    pub dropped: AtomicU64,
+    pub duplicated: AtomicU64,
+    pub reordered: AtomicU64,
+}
+
+impl FaultCounts {
+    /// The counts of dropped, duplicated and reordered frames
+    pub fn get(&self) -> (u64, u64, u64) {
+        (self.dropped.load(atomic::Ordering::Relaxed),
+            self.duplicated.load(atomic::Ordering::Relaxed),
+            self.reordered.load(atomic::Ordering::Relaxed))
+    }
+}
+
+/// A frame held until `at`, sent after frames held until the same time which were
+/// queued before it
+struct Delayed {
+    at: Instant,
+    seq: u64,
+    item: (Message, SocketAddr),
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Delayed) -> bool {
+        (self.at, self.seq) == (other.at, other.seq)
+    }
+}
+
+impl Eq for Delayed {}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Delayed) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Delayed {
+    fn cmp(&self, other: &Delayed) -> Ordering {
+        (self.at, self.seq).cmp(&(other.at, other.seq))
+    }
+}
+
+/// A sink passing frames on to `inner` with faults injected
+pub struct Faults {
+    inner: Outgoing,
+    config: FaultConfig,
+    rng: XorShiftRng,
+    queue: BinaryHeap<Reverse<Delayed>>,
+    seq: u64,
+    timer: Pin<Box<Sleep>>,
+    counts: Arc<FaultCounts>,
+}
+
+impl Faults {
+    /// Inject faults into the frames of the channel at `index` as set by `config`,
+    /// counting them in `counts`. Must be called within a Tokio runtime.
+    pub fn new(inner: Outgoing, index: usize, config: FaultConfig, counts: Arc<FaultCounts>) -> Self {
+        Faults {
+            inner,
+            rng: seeded_rng(Some(config.seed), index as u32),
+            config,
+            queue: BinaryHeap::new(),
+            seq: 0,
+            timer: Box::pin(time::sleep(Duration::from_secs(0))),
+            counts,
+        }
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.rng.gen::<f64>() < probability
+    }
+
+    fn delay(&mut self) -> Duration {
+        let (min, max) = (self.config.min_delay, self.config.max_delay);
+        if max <= min {
+            return min;
+        }
+        let range = (max - min).as_micros() as u64;
+        min + Duration::from_micros(self.rng.gen_range(0, range + 1))
+    }
+
+    fn hold(&mut self, item: (Message, SocketAddr), delay: Duration) {
+        self.seq += 1;
+        self.queue.push(Reverse(Delayed { at: Instant::now() + delay, seq: self.seq, item }));
+    }
+
+    /// Send the frames whose delay has passed, then wake the task once the next one's
+    /// has
+    fn poll_due(&mut self, cx: &mut Context) -> Poll<Result<()>> {
+        loop {
+            let at = match self.queue.peek() {
+                Some(&Reverse(ref delayed)) => delayed.at,
+                None => return Poll::Ready(Ok(())),
+            };
+            if at > Instant::now() {
+                // Frames already passed on mustn't wait for the held ones
+                if let Poll::Ready(Err(e)) = self.inner.as_mut().poll_flush(cx) {
+                    return Poll::Ready(Err(e));
+                }
+                self.timer.as_mut().reset(at);
+                if self.timer.as_mut().poll(cx).is_pending() {
+                    return Poll::Ready(Ok(()));
+                }
+                continue;
+            }
+            ready!(self.inner.as_mut().poll_ready(cx))?;
+            if let Some(Reverse(delayed)) = self.queue.pop() {
+                self.inner.as_mut().start_send(delayed.item)?;
+            }
+        }
+    }
+}
+
+impl Sink<(Message, SocketAddr)> for Faults {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        self.get_mut().poll_due(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: (Message, SocketAddr)) -> Result<()> {
+        let this = self.get_mut();
+        if this.chance(this.config.drop) {
+            trace!("Dropping a frame to {}", item.1);
+            this.counts.dropped.fetch_add(1, atomic::Ordering::Relaxed);
+            return Ok(());
+        }
+        let mut delay = this.delay();
+        if this.chance(this.config.reorder) {
+            trace!("Holding back a frame to {}", item.1);
+            this.counts.reordered.fetch_add(1, atomic::Ordering::Relaxed);
+            delay += this.config.reorder_window;
+        }
+        if this.chance(this.config.duplicate) {
+            trace!("Duplicating a frame to {}", item.1);
+            this.counts.duplicated.fetch_add(1, atomic::Ordering::Relaxed);
+            let copy_delay = this.delay();
+            this.hold(item.clone(), copy_delay);
+        }
+        this.hold(item, delay);
+        Ok(())
+    }
+
+    /// Completes once every held frame was sent, as when the node shuts down
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_due(cx))?;
+        if !this.queue.is_empty() {
+            return Poll::Pending;
+        }
+        this.inner.as_mut().poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        self.get_mut().inner.as_mut().poll_close(cx)
+    }
+}
+
+/// Injects faults into every channel of `inner`, counting them in `counts`
+pub struct FaultyTransport<T> {
+    inner: T,
+    config: FaultConfig,
+    counts: Arc<FaultCounts>,
+}
+
+impl<T: Transport> FaultyTransport<T> {
+    pub fn new(inner: T, config: FaultConfig) -> Self {
+        FaultyTransport { inner, config, counts: Arc::new(FaultCounts::default()) }
+    }
+
+    pub fn counts(&self) -> Arc<FaultCounts> {
+        self.counts.clone()
+    }
+}
+
+impl<T: Transport> Transport for FaultyTransport<T> {
+    fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.inner.local_addrs()
+    }
+
+    fn open(self, state: &Arc<State>) -> Vec<(Outgoing, Incoming)> {
+        let (config, counts) = (self.config, self.counts);
+        self.inner.open(state).into_iter().enumerate()
+            .map(|(index, (sink, stream))| {
+                let sink: Outgoing = Box::pin(Faults::new(sink, index, config.clone(), counts.clone()));
+                (sink, stream)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{stream, SinkExt, StreamExt};
+    use futures::channel::mpsc;
+    use nano_lib_rs::message::{MessageBuilder, MessageKind, MessagePayload};
+
+    /// The ports of the frames which make it through the faults of `config`, and the
+    /// fault counts
+    async fn delivered(config: FaultConfig) -> (Vec<u16>, (u64, u64, u64)) {
+        let (sender, receiver) = mpsc::unbounded();
+        let inner: Outgoing = Box::pin(sender.sink_map_err(|_| Error::from("receiver dropped")));
+        let counts = Arc::new(FaultCounts::default());
+        let mut faults = Faults::new(inner, 0, config, counts.clone());
+        let peer = "[::]:7075".parse().unwrap();
+        let msg = MessageBuilder::new(MessageKind::KeepAlive)
+            .with_payload(MessagePayload::KeepAlive(vec![peer; 8]))
+            .build();
+        // Sent without flushing in between, as the node does, so frames can overtake
+        let mut frames = stream::iter(1..=100)
+            .map(|port| Ok((msg.clone(), SocketAddr::from(([10, 0, 0, 1], port)))));
+        faults.send_all(&mut frames).await.unwrap();
+        faults.close().await.unwrap();
+        drop(faults);
+        let ports = receiver.map(|(_, addr): (Message, SocketAddr)| addr.port()).collect().await;
+        (ports, counts.get())
+    }
+
+    #[tokio::test]
+    async fn injects_seeded_faults() {
+        let lossy = FaultConfig { seed: 7, drop: 0.3, duplicate: 0.1, ..FaultConfig::default() };
+        let (ports, (dropped, duplicated, _)) = delivered(lossy.clone()).await;
+        assert!(dropped > 0 && duplicated > 0);
+        assert_eq!(ports.len() as u64, 100 - dropped + duplicated);
+        // The same seed injects the same faults
+        assert_eq!(delivered(lossy).await.0, ports);
+
+        let reordering = FaultConfig { seed: 7, reorder: 0.2, reorder_window: Duration::from_millis(5), ..FaultConfig::default() };
+        let (ports, (_, _, reordered)) = delivered(reordering).await;
+        assert_eq!(ports.len(), 100);
+        assert!(reordered > 0);
+        assert!(ports.windows(2).any(|pair| pair[0] > pair[1]));
+    }
+}
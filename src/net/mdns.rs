@@ -0,0 +1,337 @@
+//! Discovering peers on the local network over multicast DNS, so that the nodes of a
+//! dev network on one LAN find each other without preconfigured peers.
+//!
+//! Each node advertises an instance of the `_nano-rs._udp.local` service, named after
+//! its network and a random identifier, with an SRV record giving its peering port.
+//! It browses for the others by asking for the service every `QUERY_INTERVAL`, and
+//! answers the others' questions. Every instance of the same network it hears of is
+//! sent a keepalive at the address the answer came from, and from then on is peered
+//! with like any other peer. Only IPv4 is used, and only the records the nodes' own
+//! browsing needs are sent, rather than those of a complete DNS-SD responder.
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{stream, Stream};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::time;
+
+use nano_lib_rs::message::Message;
+
+use crate::error::*;
+use crate::node::state::State;
+use crate::utils::to_ipv6;
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+/// The service every node advertises an instance of
+const SERVICE: &str = "_nano-rs._udp.local";
+
+/// How often the node asks for the service, and so announces itself
+pub const QUERY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long, in seconds, others may keep the node's records
+const RECORD_TTL: u32 = 120;
+
+const TYPE_PTR: u16 = 12;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+
+/// The flags of an authoritative response
+const RESPONSE_FLAGS: u16 = 0x8400;
+
+/// The largest packet read; mDNS packets are limited to 9000 bytes
+const MAX_PACKET: usize = 9000;
+
+/// A DNS packet, as far as discovery needs it
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Packet {
+    is_response: bool,
+    /// The name and type of each question
+    questions: Vec<(String, u16)>,
+    /// The instance names of the PTR records for the service
+    instances: Vec<String>,
+    /// The name and port of each SRV record
+    ports: Vec<(String, u16)>,
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.split('.') {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+}
+
+fn put_header(out: &mut Vec<u8>, flags: u16, questions: u16, answers: u16, additional: u16) {
+    for &field in &[0, flags, questions, answers, 0, additional] {
+        put_u16(out, field);
+    }
+}
+
+fn put_record(out: &mut Vec<u8>, name: &str, kind: u16, data: &[u8]) {
+    put_name(out, name);
+    put_u16(out, kind);
+    put_u16(out, CLASS_IN);
+    out.extend_from_slice(&RECORD_TTL.to_be_bytes());
+    put_u16(out, data.len() as u16);
+    out.extend_from_slice(data);
+}
+
+/// A question asking for every instance of the service
+fn query() -> Vec<u8> {
+    let mut out = Vec::new();
+    put_header(&mut out, 0, 1, 0, 0);
+    put_name(&mut out, SERVICE);
+    put_u16(&mut out, TYPE_PTR);
+    put_u16(&mut out, CLASS_IN);
+    out
+}
+
+/// A response advertising the instance `instance`, reached on `port`
+fn announcement(instance: &str, port: u16) -> Vec<u8> {
+    let name = format!("{}.{}", instance, SERVICE);
+    let mut out = Vec::new();
+    put_header(&mut out, RESPONSE_FLAGS, 0, 1, 1);
+    let mut ptr = Vec::new();
+    put_name(&mut ptr, &name);
+    put_record(&mut out, SERVICE, TYPE_PTR, &ptr);
+    let mut srv = Vec::new();
+    // Priority and weight, then the port and the host
+    put_u16(&mut srv, 0);
+    put_u16(&mut srv, 0);
+    put_u16(&mut srv, port);
+    put_name(&mut srv, &format!("{}.local", instance));
+    put_record(&mut out, &name, TYPE_SRV, &srv);
+    out
+}
+
+struct Reader<'a> {
+    packet: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.packet.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// Read a name, following compression pointers, lowercased
+    fn name(&mut self) -> Option<String> {
+        let mut labels: Vec<String> = Vec::new();
+        let mut pos = self.pos;
+        let mut end = None;
+        // Each pointer must point backwards, so a packet can't make names loop
+        let mut limit = pos;
+        loop {
+            let len = *self.packet.get(pos)? as usize;
+            if len == 0 {
+                end = end.or(Some(pos + 1));
+                break;
+            }
+            if len & 0xc0 == 0xc0 {
+                let target = (len & 0x3f) << 8 | *self.packet.get(pos + 1)? as usize;
+                if target >= limit {
+                    return None;
+                }
+                end = end.or(Some(pos + 2));
+                pos = target;
+                limit = target;
+                continue;
+            }
+            let label = self.packet.get(pos + 1..pos + 1 + len)?;
+            labels.push(String::from_utf8_lossy(label).to_lowercase());
+            pos += 1 + len;
+        }
+        self.pos = end?;
+        Some(labels.join("."))
+    }
+}
+
+/// Parse `packet`, or return `None` if it is malformed
+fn parse(packet: &[u8]) -> Option<Packet> {
+    let mut reader = Reader { packet, pos: 0 };
+    let mut header = [0u16; 6];
+    for field in header.iter_mut() {
+        *field = reader.u16()?;
+    }
+    let mut parsed = Packet { is_response: header[1] & 0x8000 != 0, ..Packet::default() };
+    for _ in 0..header[2] {
+        let name = reader.name()?;
+        let kind = reader.u16()?;
+        reader.u16()?;
+        parsed.questions.push((name, kind));
+    }
+    let records = u32::from(header[3]) + u32::from(header[4]) + u32::from(header[5]);
+    for _ in 0..records {
+        let name = reader.name()?;
+        let kind = reader.u16()?;
+        reader.bytes(6)?;
+        let len = reader.u16()? as usize;
+        let data_end = reader.pos + len;
+        if data_end > packet.len() {
+            return None;
+        }
+        match kind {
+            TYPE_PTR if name == SERVICE => parsed.instances.push(reader.name()?),
+            TYPE_SRV => {
+                reader.bytes(4)?;
+                parsed.ports.push((name, reader.u16()?));
+            }
+            _ => (),
+        }
+        reader.pos = data_end;
+    }
+    Some(parsed)
+}
+
+impl Packet {
+    fn asks_for_service(&self) -> bool {
+        !self.is_response && self.questions.iter().any(|&(ref name, kind)| {
+            name == SERVICE && (kind == TYPE_PTR || kind == TYPE_ANY)
+        })
+    }
+
+    /// The ports of the instances of `network` which the packet advertises, other than
+    /// `own`
+    fn peer_ports(&self, network: &str, own: &str) -> Vec<u16> {
+        let prefix = format!("{}-", network);
+        self.instances.iter()
+            .filter(|instance| instance.starts_with(&prefix) && !instance.starts_with(&format!("{}.", own)))
+            .filter_map(|instance| self.ports.iter().find(|&&(ref name, _)| name == instance))
+            .map(|&(_, port)| port)
+            .collect()
+    }
+}
+
+/// Bind a socket to the mDNS port, sharing it with any other responder on the host,
+/// and join the mDNS group
+fn bind() -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+    socket.join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+struct Browser {
+    state: Arc<State>,
+    socket: UdpSocket,
+    /// The network's name, which starts the names of its instances
+    network: String,
+    instance: String,
+    port: u16,
+    ticks: time::Interval,
+    buf: Vec<u8>,
+}
+
+impl Browser {
+    async fn send(&self, packet: &[u8]) {
+        if let Err(e) = self.socket.send_to(packet, (MDNS_GROUP, MDNS_PORT)).await {
+            warn!("Error sending an mDNS packet: {}", e);
+        }
+    }
+
+    /// Wait for the next instance of the network which isn't a peer yet
+    async fn next_peer(&mut self) -> SocketAddr {
+        loop {
+            let (len, src) = tokio::select! {
+                _ = self.ticks.tick() => {
+                    self.send(&query()).await;
+                    self.send(&announcement(&self.instance, self.port)).await;
+                    continue;
+                }
+                received = self.socket.recv_from(&mut self.buf) => match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        warn!("Error receiving an mDNS packet: {}", e);
+                        continue;
+                    }
+                },
+            };
+            let packet = match parse(&self.buf[..len]) {
+                Some(packet) => packet,
+                None => continue,
+            };
+            if packet.asks_for_service() {
+                self.send(&announcement(&self.instance, self.port)).await;
+            }
+            for port in packet.peer_ports(&self.network, &self.instance) {
+                let peer = SocketAddr::new(src.ip(), port);
+                if !self.state.peers.read().unwrap().contains_key(&to_ipv6(peer)) {
+                    info!("Discovered the peer {} over mDNS", peer);
+                    return peer;
+                }
+            }
+        }
+    }
+}
+
+/// Advertise the node as a peer of `network` on its first listen address, and send a
+/// keepalive to each new peer discovered, until the node shuts down
+pub fn discover(state: Arc<State>, network: &str) -> Result<impl Stream<Item=Result<(Message, SocketAddr)>>> {
+    let port = match state.sockets.first() {
+        Some(addr) => addr.port(),
+        None => bail!("The node has no socket to advertise over mDNS"),
+    };
+    let socket = bind().chain_err(|| format!("Could not listen for mDNS on {}", SocketAddrV4::new(MDNS_GROUP, MDNS_PORT)))?;
+    let network = network.to_lowercase();
+    let instance = format!("{}-{:016x}", network, ::rand::random::<u64>());
+    info!("Advertising the node over mDNS as {}", instance);
+    let browser = Browser {
+        state,
+        socket,
+        network,
+        instance,
+        port,
+        ticks: time::interval(QUERY_INTERVAL),
+        buf: vec![0; MAX_PACKET],
+    };
+    Ok(stream::unfold(browser, |mut browser| async move {
+        let peer = browser.next_peer().await;
+        Some((Ok((browser.state.keepalive(), peer)), browser))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_instances_of_the_network() {
+        let packet = parse(&query()).unwrap();
+        assert!(packet.asks_for_service());
+        assert!(packet.peer_ports("dev", "dev-1").is_empty());
+
+        // A response advertising two instances, of different networks
+        let mut combined = Vec::new();
+        put_header(&mut combined, RESPONSE_FLAGS, 0, 2, 2);
+        for &(instance, port) in &[("dev-2", 7075), ("beta-3", 7076)] {
+            combined.extend_from_slice(&announcement(instance, port)[12..]);
+        }
+        let packet = parse(&combined).unwrap();
+        assert!(!packet.asks_for_service());
+        assert_eq!(packet.instances.len(), 2);
+        assert_eq!(packet.peer_ports("dev", "dev-1"), vec![7075]);
+        assert!(packet.peer_ports("dev", "dev-2").is_empty());
+        assert_eq!(packet.peer_ports("beta", "beta-1"), vec![7076]);
+        assert!(parse(&combined[..combined.len() - 3]).is_none());
+    }
+}
@@ -0,0 +1,307 @@
+//! An in-process transport, so that tests can run several nodes in one process
+//! without real sockets.
+//!
+//! A `MemoryNetwork` connects any number of `MemoryChannel`s, each standing in for a
+//! UDP socket at an address. Messages are encoded and decoded with `MessageCodec` as
+//! they would be on a socket, but each frame is handed straight to the queue of the
+//! channel it's addressed to. Frames to an address without a channel are dropped, as
+//! UDP datagrams would be. How frames are delivered is set per link, from one address
+//! to another: immediately, held until released, or dropped, so that tests can
+//! control the order in which nodes hear from each other. Traffic on these channels
+//! isn't counted in stats or captured.
+#![cfg_attr(not(feature = "testing"), allow(dead_code))]
+use std::collections::{HashMap, VecDeque};
+use std::net::{SocketAddr, SocketAddrV6};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use bytes::{Bytes, BytesMut};
+use futures::{ready, Sink, Stream, StreamExt};
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio_util::codec::{Decoder, Encoder};
+
+use nano_lib_rs::message::Message;
+
+use crate::error::*;
+use crate::node::state::State;
+use crate::utils::to_ipv6;
+use super::codec::MessageCodec;
+use super::{Incoming, Outgoing, Transport};
+
+/// What happens to frames sent over a link
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Delivery {
+    /// Frames are queued for the receiver as soon as they are sent
+    Immediate,
+    /// Frames are kept, in order, until the link is released
+    Hold,
+    /// Frames are discarded
+    Drop,
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// The queue of each channel, and the address its frames are sent from
+    endpoints: HashMap<SocketAddrV6, (SocketAddr, UnboundedSender<(SocketAddr, Bytes)>)>,
+    /// Delivery over links without their own
+    default: Delivery,
+    links: HashMap<(SocketAddrV6, SocketAddrV6), Delivery>,
+    held: HashMap<(SocketAddrV6, SocketAddrV6), VecDeque<Bytes>>,
+    delivered: u64,
+    dropped: u64,
+}
+
+impl Inner {
+    fn deliver(&mut self, from: SocketAddrV6, to: SocketAddrV6, frame: Bytes) {
+        let sent = match (self.endpoints.get(&to), self.endpoints.get(&from)) {
+            (Some(&(_, ref queue)), Some(&(from_addr, _))) => queue.unbounded_send((from_addr, frame)).is_ok(),
+            _ => false,
+        };
+        if sent {
+            self.delivered += 1;
+        } else {
+            self.dropped += 1;
+        }
+    }
+}
+
+/// Connects `MemoryChannel`s, each at its own address
+#[derive(Clone, Debug)]
+pub struct MemoryNetwork {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl MemoryNetwork {
+    /// A network delivering every frame immediately
+    pub fn new() -> Self {
+        MemoryNetwork {
+            inner: Arc::new(Mutex::new(Inner {
+                endpoints: HashMap::new(),
+                default: Delivery::Immediate,
+                links: HashMap::new(),
+                held: HashMap::new(),
+                delivered: 0,
+                dropped: 0,
+            })),
+        }
+    }
+
+    /// A channel at `addr`, replacing any channel already there
+    pub fn channel(&self, addr: SocketAddr) -> MemoryChannel {
+        let (sender, receiver) = mpsc::unbounded();
+        self.inner.lock().unwrap().endpoints.insert(to_ipv6(addr), (addr, sender));
+        MemoryChannel {
+            addr,
+            network: self.clone(),
+            incoming: receiver,
+            codec: MessageCodec::new(),
+        }
+    }
+
+    /// A transport with a channel at each of `addrs`, for `node::run_on`
+    pub fn transport(&self, addrs: &[SocketAddr]) -> MemoryTransport {
+        MemoryTransport {
+            channels: addrs.iter().map(|&addr| self.channel(addr)).collect(),
+        }
+    }
+
+    /// Remove the channel at `addr`, so that frames sent to it are dropped
+    pub fn disconnect(&self, addr: SocketAddr) {
+        self.inner.lock().unwrap().endpoints.remove(&to_ipv6(addr));
+    }
+
+    /// Set how frames are delivered over links without their own delivery
+    pub fn set_default(&self, delivery: Delivery) {
+        self.inner.lock().unwrap().default = delivery;
+    }
+
+    /// Set how frames sent from `from` to `to` are delivered. Frames already held
+    /// stay held until the link is released.
+    pub fn set_link(&self, from: SocketAddr, to: SocketAddr, delivery: Delivery) {
+        self.inner.lock().unwrap().links.insert((to_ipv6(from), to_ipv6(to)), delivery);
+    }
+
+    /// How many frames sent from `from` to `to` are held
+    pub fn held(&self, from: SocketAddr, to: SocketAddr) -> usize {
+        self.inner.lock().unwrap().held.get(&(to_ipv6(from), to_ipv6(to))).map_or(0, |held| held.len())
+    }
+
+    /// Deliver the frames held from `from` to `to`, in the order they were sent,
+    /// returning how many
+    pub fn release(&self, from: SocketAddr, to: SocketAddr) -> usize {
+        let (from, to) = (to_ipv6(from), to_ipv6(to));
+        let mut inner = self.inner.lock().unwrap();
+        let held = inner.held.remove(&(from, to)).unwrap_or_default();
+        let count = held.len();
+        for frame in held {
+            inner.deliver(from, to, frame);
+        }
+        count
+    }
+
+    /// Deliver every held frame, returning how many
+    pub fn release_all(&self) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let held: Vec<_> = inner.held.drain().collect();
+        let mut count = 0;
+        for ((from, to), frames) in held {
+            count += frames.len();
+            for frame in frames {
+                inner.deliver(from, to, frame);
+            }
+        }
+        count
+    }
+
+    /// How many frames have been delivered, and how many dropped
+    pub fn counts(&self) -> (u64, u64) {
+        let inner = self.inner.lock().unwrap();
+        (inner.delivered, inner.dropped)
+    }
+
+    fn send(&self, from: SocketAddr, to: SocketAddr, frame: Bytes) {
+        let (from, to) = (to_ipv6(from), to_ipv6(to));
+        let mut inner = self.inner.lock().unwrap();
+        let delivery = inner.links.get(&(from, to)).cloned().unwrap_or(inner.default);
+        match delivery {
+            Delivery::Immediate => inner.deliver(from, to, frame),
+            Delivery::Hold => inner.held.entry((from, to)).or_insert_with(VecDeque::new).push_back(frame),
+            Delivery::Drop => inner.dropped += 1,
+        }
+    }
+}
+
+/// Stands in for a UDP socket at `addr` on a `MemoryNetwork`, as both the `Stream`
+/// of messages received and the `Sink` of messages to send
+pub struct MemoryChannel {
+    addr: SocketAddr,
+    network: MemoryNetwork,
+    incoming: UnboundedReceiver<(SocketAddr, Bytes)>,
+    codec: MessageCodec,
+}
+
+impl MemoryChannel {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Stream for MemoryChannel {
+    type Item = Result<(Message, SocketAddr)>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            let (from, frame) = match ready!(this.incoming.poll_next_unpin(cx)) {
+                Some(received) => received,
+                None => return Poll::Ready(None),
+            };
+            match this.codec.decode(&mut BytesMut::from(&frame[..])) {
+                Ok(Some(msg)) => return Poll::Ready(Some(Ok((msg, from)))),
+                Ok(None) => continue,
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            }
+        }
+    }
+}
+
+impl Sink<(Message, SocketAddr)> for MemoryChannel {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, (msg, addr): (Message, SocketAddr)) -> Result<()> {
+        let this = self.get_mut();
+        let mut frame = BytesMut::new();
+        this.codec.encode(msg, &mut frame)?;
+        this.network.send(this.addr, addr, frame.freeze());
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The channels of one node on a `MemoryNetwork`
+pub struct MemoryTransport {
+    channels: Vec<MemoryChannel>,
+}
+
+impl Transport for MemoryTransport {
+    fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.channels.iter().map(MemoryChannel::local_addr).collect()
+    }
+
+    fn open(self, _state: &Arc<State>) -> Vec<(Outgoing, Incoming)> {
+        self.channels.into_iter()
+            .map(|channel| {
+                let (sink, stream) = channel.split();
+                let sink: Outgoing = Box::pin(sink);
+                (sink, stream.boxed())
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::SinkExt;
+    use nano_lib_rs::message::{MessageBuilder, MessageKind, MessagePayload};
+
+    fn keepalive() -> Message {
+        let peer: SocketAddrV6 = "[::]:7075".parse().unwrap();
+        MessageBuilder::new(MessageKind::KeepAlive)
+            .with_payload(MessagePayload::KeepAlive(vec![peer; 8]))
+            .build()
+    }
+
+    #[test]
+    fn holds_and_releases_frames() {
+        let network = MemoryNetwork::new();
+        let (a, b): (SocketAddr, SocketAddr) = ("10.0.0.1:7075".parse().unwrap(), "10.0.0.2:7075".parse().unwrap());
+        let (mut first, mut second) = (network.channel(a), network.channel(b));
+        block_on(async {
+            first.send((keepalive(), b)).await.unwrap();
+            let (msg, from) = second.next().await.unwrap().unwrap();
+            assert_eq!((msg.kind(), from), (MessageKind::KeepAlive, a));
+
+            network.set_link(a, b, Delivery::Hold);
+            first.send((keepalive(), b)).await.unwrap();
+            first.send((keepalive(), "10.0.0.3:7075".parse().unwrap())).await.unwrap();
+            assert_eq!(network.held(a, b), 1);
+            assert_eq!(network.release(a, b), 1);
+            assert_eq!(second.next().await.unwrap().unwrap().1, a);
+        });
+        assert_eq!(network.counts(), (2, 1));
+    }
+
+    #[test]
+    fn applies_the_default_delivery() {
+        let network = MemoryNetwork::new();
+        let (a, b): (SocketAddr, SocketAddr) = ("10.0.0.1:7075".parse().unwrap(), "10.0.0.2:7075".parse().unwrap());
+        let (mut first, mut second) = (network.channel(a), network.channel(b));
+        block_on(async {
+            network.set_default(Delivery::Hold);
+            first.send((keepalive(), b)).await.unwrap();
+            second.send((keepalive(), a)).await.unwrap();
+            assert_eq!(network.release_all(), 2);
+            assert_eq!(second.next().await.unwrap().unwrap().1, a);
+            assert_eq!(first.next().await.unwrap().unwrap().1, b);
+
+            network.set_default(Delivery::Immediate);
+            network.disconnect(b);
+            first.send((keepalive(), b)).await.unwrap();
+        });
+        assert_eq!(network.counts(), (2, 1));
+    }
+}
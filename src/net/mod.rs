@@ -1,4 +1,40 @@
+pub mod capture;
 pub mod codec;
+#[cfg(any(test, feature = "testing"))]
+pub mod faults;
+pub mod mdns;
+#[cfg(any(test, feature = "testing"))]
+pub mod memory;
+pub mod pool;
+pub mod rebind;
+pub mod replay;
 pub mod udp_framed;
 
-pub use self::udp_framed::UdpFramed;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures::Sink;
+use futures::stream::BoxStream;
+
+use nano_lib_rs::message::Message;
+
+use crate::error::*;
+use crate::node::state::State;
+
+/// Messages received on a channel, each with the address it was sent from
+pub type Incoming = BoxStream<'static, Result<(Message, SocketAddr)>>;
+
+/// Sends messages on a channel, each to the address given with it
+pub type Outgoing = Pin<Box<dyn Sink<(Message, SocketAddr), Error = Error> + Send>>;
+
+/// How the node exchanges messages with its peers: a UDP socket for each listen
+/// address, or in tests, in-process queues (see `memory`).
+pub trait Transport {
+    /// The address of each channel, in order
+    fn local_addrs(&self) -> Vec<SocketAddr>;
+
+    /// Start using the channels once the node's state exists, the one at each index
+    /// having the address at that index of `local_addrs`
+    fn open(self, state: &Arc<State>) -> Vec<(Outgoing, Incoming)>;
+}
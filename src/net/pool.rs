@@ -0,0 +1,90 @@
+//! Reusable receive buffers.
+//!
+//! Datagrams are decoded from a `Bytes` slice of the buffer they were read into, so a
+//! buffer can only be reused once the decoded message has dropped that slice. Buffers
+//! are given back to the pool after processing, and taking one reclaims its memory
+//! when nothing else refers to it, so at a steady packet rate no allocations are made.
+use std::sync::Mutex;
+
+use bytes::BytesMut;
+
+/// A pool of buffers with room for at least `buffer_size` bytes each
+#[derive(Debug)]
+pub struct BufferPool {
+    buffers: Mutex<Vec<BytesMut>>,
+    buffer_size: usize,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    /// Create an empty pool which keeps at most `max_buffers` idle buffers
+    pub fn new(buffer_size: usize, max_buffers: usize) -> Self {
+        BufferPool {
+            buffers: Mutex::new(Vec::with_capacity(max_buffers)),
+            buffer_size,
+            max_buffers,
+        }
+    }
+
+    /// Take an empty buffer from the pool, allocating one if none is idle or the
+    /// memory of the idle one is still in use
+    pub fn take(&self) -> BytesMut {
+        match self.buffers.lock().unwrap().pop() {
+            Some(mut buf) => {
+                buf.reserve(self.buffer_size);
+                buf
+            }
+            None => BytesMut::with_capacity(self.buffer_size),
+        }
+    }
+
+    /// Return a buffer to the pool, dropping it if the pool is full
+    pub fn give_back(&self, mut buf: BytesMut) {
+        buf.clear();
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max_buffers {
+            buffers.push(buf);
+        }
+    }
+
+    /// The number of idle buffers
+    pub fn len(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_memory_once_slices_are_dropped() {
+        let pool = BufferPool::new(1024, 1);
+        let mut buf = pool.take();
+        let memory = buf.as_ptr();
+        buf.extend_from_slice(b"datagram");
+        drop(buf.split().freeze());
+        pool.give_back(buf);
+        assert_eq!(pool.len(), 1);
+
+        let buf = pool.take();
+        assert_eq!(buf.as_ptr(), memory);
+        assert!(buf.capacity() >= 1024);
+        assert_eq!(pool.len(), 0);
+    }
+
+    #[test]
+    fn allocates_while_slices_are_alive() {
+        let pool = BufferPool::new(1024, 1);
+        let mut buf = pool.take();
+        let memory = buf.as_ptr();
+        buf.extend_from_slice(b"datagram");
+        let datagram = buf.split().freeze();
+        pool.give_back(buf);
+
+        let buf = pool.take();
+        assert_ne!(buf.as_ptr(), memory);
+        assert!(buf.capacity() >= 1024);
+        assert_eq!(&datagram[..], b"datagram");
+    }
+}
@@ -0,0 +1,110 @@
+//! Rebinding the node's sockets when the host's network changes.
+//!
+//! A socket bound to an address which is removed, or left broken by a laptop being
+//! suspended and resumed, fails every send and receive until it's bound again. Each
+//! `UdpFramed` counts consecutive errors and rebinds its socket to the same address
+//! once `REBIND_AFTER_ERRORS` is reached, retrying every `REBIND_RETRY` seconds while
+//! the address is missing. `watch_clock` notices the host resuming from suspend,
+//! when the wall clock jumps ahead of the monotonic clock, and asks every socket to
+//! rebind. Once a socket is bound again, keepalives are sent to every peer so that
+//! they learn the node is back, and NAT mappings are opened again.
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::Waker;
+use std::time::{Duration, SystemTime};
+
+use tokio::sync::Notify;
+use tokio::time::{self, Instant};
+
+use crate::node::state::State;
+
+/// Consecutive errors on a socket after which it is bound again
+pub const REBIND_AFTER_ERRORS: usize = 16;
+
+/// Seconds between attempts to bind a socket whose address is missing
+pub const REBIND_RETRY: u64 = 5;
+
+/// Seconds between checks of the clocks for a resume from suspend
+const CLOCK_CHECK_INTERVAL: u64 = 10;
+
+/// How far the wall clock may move ahead of the monotonic clock between checks
+/// before the host is assumed to have been suspended
+const SUSPEND_THRESHOLD: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Default)]
+pub struct Rebind {
+    /// Incremented whenever every socket should rebind
+    epoch: AtomicUsize,
+    /// The tasks receiving on each socket, by index in `State::sockets`
+    receivers: Mutex<HashMap<usize, Waker>>,
+    rebound: Notify,
+}
+
+impl Rebind {
+    pub fn new() -> Self {
+        Rebind::default()
+    }
+
+    pub fn epoch(&self) -> usize {
+        self.epoch.load(Ordering::SeqCst)
+    }
+
+    /// Ask every socket to rebind
+    pub fn request(&self, reason: &str) {
+        info!("Rebinding sockets: {}", reason);
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        for (_, waker) in self.receivers.lock().unwrap().drain() {
+            waker.wake();
+        }
+    }
+
+    /// Remember the task receiving on the socket at `index`, to wake it when the
+    /// socket should rebind
+    pub fn register(&self, index: usize, waker: &Waker) {
+        let mut receivers = self.receivers.lock().unwrap();
+        match receivers.get(&index) {
+            Some(registered) if registered.will_wake(waker) => {}
+            _ => {
+                receivers.insert(index, waker.clone());
+            }
+        }
+    }
+
+    /// Wake the task receiving on the socket at `index`, as its socket was replaced
+    pub fn wake(&self, index: usize) {
+        if let Some(waker) = self.receivers.lock().unwrap().remove(&index) {
+            waker.wake();
+        }
+    }
+
+    /// Note that a socket was bound again, so that peers are sent keepalives
+    pub fn rebound(&self) {
+        self.rebound.notify_one();
+    }
+
+    /// Wait until a socket was bound again
+    pub async fn wait_rebound(&self) {
+        self.rebound.notified().await
+    }
+}
+
+/// Ask every socket to rebind whenever the host seems to have resumed from suspend,
+/// as the monotonic clock doesn't advance while suspended but the wall clock does
+pub async fn watch_clock(state: Arc<State>) {
+    let period = Duration::from_secs(CLOCK_CHECK_INTERVAL);
+    let mut interval = time::interval_at(Instant::now() + period, period);
+    let mut last = (Instant::now(), SystemTime::now());
+    loop {
+        interval.tick().await;
+        let now = (Instant::now(), SystemTime::now());
+        let monotonic = now.0 - last.0;
+        let wall = now.1.duration_since(last.1).unwrap_or_default();
+        if wall > monotonic + SUSPEND_THRESHOLD {
+            state.rebind.request(&format!("the host seems to have been suspended for {}s",
+                (wall - monotonic).as_secs()));
+        }
+        last = now;
+    }
+}
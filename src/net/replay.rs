@@ -0,0 +1,262 @@
+//! Replaying captured datagrams through a node, to reproduce protocol bugs from
+//! captures taken in production.
+//!
+//! `replay` runs a node on a `ReplayTransport`, against a copy of the ledger set up by
+//! the configuration file. The node's one channel receives, in order, the datagrams of
+//! a pcap capture which were sent to the node, and everything the node sends is
+//! discarded. Datagrams are decoded by the node's codec and handled as if they had
+//! just arrived, but no faster than the signature checker and block processor keep
+//! up, so that nothing is dropped from their queues; the timing of the capture isn't
+//! kept. Once every datagram has been handled the node shuts down, finishing the
+//! blocks already queued. RPC, voting, the signer, the webhook, snapshots and backups
+//! are disabled, and no preconfigured peers are contacted.
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::net::{SocketAddr, SocketAddrV6};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::BytesMut;
+use futures::{future, sink, stream, SinkExt, StreamExt};
+use futures::channel::oneshot;
+use tokio::time;
+use tokio_util::codec::Decoder;
+
+use nano_lib_rs::message::{MessageBuilder, MessageKind};
+
+use crate::config::{Config, ConfigReloader};
+use crate::daemon::Shutdown;
+use crate::error::*;
+use crate::logging::LogFilter;
+use crate::node::{self, shutdown, state::State};
+use crate::store::Backend;
+use crate::utils::to_ipv6;
+use super::capture::PcapReader;
+use super::codec::MessageCodec;
+use super::{Incoming, Outgoing, Transport};
+
+/// Datagrams are held back while this many blocks wait to be checked or processed
+const MAX_QUEUED_BLOCKS: usize = 256;
+
+/// How often the queues are checked while datagrams are held back
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Feeds captured datagrams to a node, discarding what it sends
+pub struct ReplayTransport {
+    local_addr: SocketAddr,
+    datagrams: Vec<(SocketAddr, Vec<u8>)>,
+    /// Sent the number of datagrams which couldn't be decoded, once every datagram has
+    /// been handled
+    done: oneshot::Sender<u64>,
+}
+
+impl ReplayTransport {
+    /// A channel at `local_addr` receiving `datagrams`, each from the address given
+    /// with it, and a future resolving once they have all been handled
+    pub fn new(local_addr: SocketAddr, datagrams: Vec<(SocketAddr, Vec<u8>)>) -> (Self, oneshot::Receiver<u64>) {
+        let (done, handled) = oneshot::channel();
+        (ReplayTransport { local_addr, datagrams, done }, handled)
+    }
+}
+
+/// Wait until the node's queues have room for another datagram's block
+async fn wait_for_queues(state: &State) {
+    while state.verifier.queued() + state.processor.queued() >= MAX_QUEUED_BLOCKS {
+        time::sleep(QUEUE_POLL_INTERVAL).await;
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn local_addrs(&self) -> Vec<SocketAddr> {
+        vec![self.local_addr]
+    }
+
+    fn open(self, state: &Arc<State>) -> Vec<(Outgoing, Incoming)> {
+        let state = state.clone();
+        let replay = (self.datagrams.into_iter(), MessageCodec::new(), Some(self.done), 0u64);
+        // The node handles each datagram before the next one is asked for, so once the
+        // last has been, every block is queued
+        let incoming = stream::unfold(replay, move |(mut datagrams, mut codec, mut done, undecodable)| {
+            let state = state.clone();
+            async move {
+                let (src, data) = match datagrams.next() {
+                    Some(datagram) => datagram,
+                    None => {
+                        if let Some(done) = done.take() {
+                            let _ = done.send(undecodable);
+                        }
+                        return future::pending().await;
+                    }
+                };
+                wait_for_queues(&state).await;
+                let msg = codec.decode(&mut BytesMut::from(&data[..]))
+                    .ok()
+                    .and_then(|msg| msg)
+                    .unwrap_or_else(|| MessageBuilder::new(MessageKind::Invalid).build());
+                let undecodable = undecodable + if msg.kind() == MessageKind::Invalid { 1 } else { 0 };
+                Some((Ok::<_, Error>((msg, src)), (datagrams, codec, done, undecodable)))
+            }
+        });
+        let outgoing: Outgoing = Box::pin(sink::drain().sink_map_err(|never| match never {}));
+        vec![(outgoing, incoming.boxed())]
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplaySummary {
+    /// Datagrams sent to the node, which were replayed
+    pub replayed: usize,
+    /// Datagrams sent by the node or to other addresses, which were skipped
+    pub skipped: usize,
+    pub undecodable: u64,
+    pub blocks_before: u64,
+    pub blocks_after: u64,
+}
+
+/// The datagrams of the capture at `path` which were sent to one of `node_addrs`
+fn read_capture(path: &Path, node_addrs: &[SocketAddrV6]) -> Result<(Vec<(SocketAddr, Vec<u8>)>, usize)> {
+    let file = File::open(path).chain_err(|| format!("Could not open {}", path.display()))?;
+    let mut reader = PcapReader::new(BufReader::new(file))?;
+    let mut datagrams = Vec::new();
+    let mut skipped = 0;
+    while let Some(datagram) = reader.next_datagram()? {
+        if node_addrs.contains(&datagram.dst) {
+            datagrams.push((SocketAddr::V6(datagram.src), datagram.data));
+        } else {
+            skipped += 1;
+        }
+    }
+    Ok((datagrams, skipped))
+}
+
+/// Copy the files of the on-disk store at `from` into the new directory `to`
+fn copy_ledger(from: &Path, to: &Path) -> Result<()> {
+    if !from.is_dir() {
+        bail!("There is no ledger at {} to replay against", from.display());
+    }
+    fs::create_dir(to).chain_err(|| format!("Could not create {}; it must not exist yet", to.display()))?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        // The lock file describes readers of the original, not of the copy
+        if entry.file_type()?.is_file() && entry.file_name() != "lock.mdb" {
+            fs::copy(entry.path(), to.join(entry.file_name()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Replay the datagrams of the capture at `capture` which were sent to `node_addrs`,
+/// or to the node's listen addresses if none are given, through a node set up by
+/// `config`, loaded from `config_path`. The node runs against a copy of the ledger
+/// made at `ledger`, or in a temporary directory which is removed afterwards.
+pub fn replay(config_path: &str, mut config: Config, log_filter: LogFilter, capture: &Path, node_addrs: &[SocketAddr],
+    ledger: Option<&Path>) -> Result<ReplaySummary>
+{
+    let store = config.store_config()?;
+    let (copy, temporary) = match ledger {
+        Some(ledger) => (ledger.to_owned(), false),
+        None => (::std::env::temp_dir().join(format!("nano-rs-replay-{}", ::std::process::id())), true),
+    };
+    if store.backend == Backend::Lmdb {
+        copy_ledger(&store.path, &copy)?;
+        info!("Copied the ledger at {} to {}", store.path.display(), copy.display());
+    }
+    config.store.path = copy.to_string_lossy().into_owned();
+    config.node.preconfigured_peers = Some(Vec::new());
+    config.node.outbound_only = false;
+    config.node.enable_mdns = false;
+    config.node.enable_voting = false;
+    config.rpc.enable = false;
+    config.rpc.work_server_enable = false;
+    config.signer = None;
+    config.webhook = None;
+    config.backup = None;
+    config.snapshot = None;
+    let node_config = config.node_config()?;
+
+    let node_addrs: Vec<SocketAddrV6> = if node_addrs.is_empty() {
+        node_config.listen_addrs.iter().cloned().map(to_ipv6).collect()
+    } else {
+        node_addrs.iter().cloned().map(to_ipv6).collect()
+    };
+    let (datagrams, skipped) = read_capture(capture, &node_addrs)?;
+    let replayed = datagrams.len();
+    info!("Replaying {} datagrams sent to {:?}, skipping {}", replayed, node_addrs, skipped);
+
+    let local_addr = node_config.listen_addrs[0];
+    let (transport, handled) = ReplayTransport::new(local_addr, datagrams);
+    let cache_paths = shutdown::cache_paths(&node_config.store.path);
+    let reloader = ConfigReloader::new(config_path, config, log_filter);
+    let shutdown = Arc::new(Shutdown::new());
+    let (started, state) = oneshot::channel();
+    let started = move |state: Arc<State>| {
+        let _ = started.send(state);
+    };
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    let result = runtime.block_on(async {
+        let node = tokio::spawn(node::run_on(transport, node_config, reloader, shutdown.clone(), started));
+        let state = match state.await {
+            Ok(state) => state,
+            Err(_) => return match node.await {
+                Ok(Err(e)) => Err(e),
+                _ => Err(Error::from("The node exited before replaying")),
+            },
+        };
+        let blocks_before = state.store.block_count()?;
+        let undecodable = handled.await.unwrap_or(0);
+        shutdown.trigger();
+        node.await.map_err(|_| Error::from("The node exited unexpectedly"))??;
+        let blocks_after = state.store.block_count()?;
+        Ok(ReplaySummary { replayed, skipped, undecodable, blocks_before, blocks_after })
+    });
+    drop(runtime);
+    if temporary {
+        let _ = fs::remove_dir_all(&copy);
+        let _ = fs::remove_file(&cache_paths.0);
+        let _ = fs::remove_file(&cache_paths.1);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+    use nano_lib_rs::message::MessagePayload;
+    use crate::net::capture::PcapWriter;
+
+    #[test]
+    fn replays_datagrams_sent_to_the_node() {
+        let dir = ::std::env::temp_dir().join(format!("nano-rs-replay-test-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (node, peer): (SocketAddrV6, SocketAddrV6) =
+            ("[::]:44000".parse().unwrap(), "[::ffff:93.184.216.34]:44000".parse().unwrap());
+        let keepalive = MessageBuilder::new(MessageKind::KeepAlive)
+            .with_network(nano_lib_rs::network::NetworkParams::dev().kind)
+            .with_payload(MessagePayload::KeepAlive(vec![peer; 8]))
+            .build()
+            .serialize_bytes()
+            .unwrap();
+        let mut writer = PcapWriter::new(File::create(dir.join("capture.pcap")).unwrap()).unwrap();
+        writer.write_datagram(SystemTime::now(), peer, node, &keepalive).unwrap();
+        writer.write_datagram(SystemTime::now(), peer, node, b"garbage").unwrap();
+        writer.write_datagram(SystemTime::now(), node, peer, &keepalive).unwrap();
+        drop(writer);
+
+        let config = Config::from_toml(&format!(r#"
+            [node]
+            network = "dev"
+            listen_addrs = ["[::]:44000"]
+            [store]
+            backend = "memory"
+            path = {:?}
+        "#, dir.join("ledger").to_string_lossy())).unwrap();
+        let filter = LogFilter::detached("info").unwrap();
+        let summary = replay("nano-rs.toml", config, filter, &dir.join("capture.pcap"), &[], None).unwrap();
+        assert_eq!((summary.replayed, summary.skipped, summary.undecodable), (2, 1, 1));
+        assert_eq!(summary.blocks_after, summary.blocks_before);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
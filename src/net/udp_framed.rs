@@ -1,17 +1,132 @@
-//! A custom version of tokio::net::UdpFramed that does not exit on send error and
+//! A custom version of tokio_util::udp::UdpFramed that does not exit on send error and
 //! which contains a reference to a `State` object
-use std::net::{SocketAddr, Ipv4Addr, SocketAddrV4};
+use std::future::Future;
+use std::io;
+use std::mem::MaybeUninit;
+use std::net::{SocketAddr, Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
 
-use futures::{Async, Poll, Stream, Sink, StartSend, AsyncSink};
+use futures::{ready, Stream, Sink, StreamExt};
 
+use net2::UdpBuilder;
+use socket2::SockRef;
+use tokio::io::ReadBuf;
 use tokio::net::UdpSocket;
+use tokio::time::{self, Sleep};
 
-use tokio_io::codec::{Decoder, Encoder};
+use tokio_util::codec::{Decoder, Encoder};
 use bytes::{BytesMut, BufMut};
 
 use std::sync::Arc;
-use node::state::State;
-use utils::to_ipv6;
+use nano_lib_rs::message::Message;
+use crate::error::*;
+use crate::node::state::State;
+use crate::stats::{Direction, StatType, TrafficDetail};
+use crate::utils::to_ipv6;
+use super::codec::MessageCodec;
+use super::rebind::{REBIND_AFTER_ERRORS, REBIND_RETRY};
+use super::{Incoming, Outgoing, Transport};
+
+/// Bind a non-blocking UDP socket to `addr`, carrying only IPv6 traffic if `only_v6`
+/// is set and `addr` is an IPv6 address. Unless `dscp` is 0, the datagrams sent are
+/// marked with it.
+pub fn bind(addr: &SocketAddr, only_v6: bool, dscp: u8) -> io::Result<::std::net::UdpSocket> {
+    let socket = match *addr {
+        SocketAddr::V4(_) => UdpBuilder::new_v4()?.bind(addr)?,
+        SocketAddr::V6(_) => UdpBuilder::new_v6()?.only_v6(only_v6)?.bind(addr)?,
+    };
+    socket.set_nonblocking(true)?;
+    if dscp != 0 {
+        // Marking is best effort: the datagrams are still worth sending unmarked
+        if let Err(e) = set_dscp(&socket, addr, only_v6, dscp) {
+            warn!("Could not mark datagrams sent from {} with DSCP {}: {}", addr, dscp, e);
+        }
+    }
+    Ok(socket)
+}
+
+/// Set the DSCP code point of the datagrams sent on `socket`, the upper six bits of
+/// the IPv4 TOS byte or the IPv6 traffic class
+fn set_dscp(socket: &::std::net::UdpSocket, addr: &SocketAddr, only_v6: bool, dscp: u8) -> io::Result<()> {
+    let socket = SockRef::from(socket);
+    let tos = u32::from(dscp) << 2;
+    match *addr {
+        SocketAddr::V4(_) => socket.set_tos(tos),
+        SocketAddr::V6(_) => {
+            if !only_v6 {
+                // IPv4 datagrams of a dual-stack socket take the TOS byte, where supported
+                let _ = socket.set_tos(tos);
+            }
+            set_traffic_class(&socket, tos)
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd",
+    target_os = "netbsd", target_os = "openbsd", target_os = "dragonfly"))]
+fn set_traffic_class(socket: &SockRef, class: u32) -> io::Result<()> {
+    socket.set_tclass_v6(class)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd",
+    target_os = "netbsd", target_os = "openbsd", target_os = "dragonfly")))]
+fn set_traffic_class(_socket: &SockRef, _class: u32) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "setting the IPv6 traffic class is not supported on this platform"))
+}
+
+/// A UDP socket bound to each of the node's listen addresses
+#[derive(Debug)]
+pub struct UdpTransport {
+    sockets: Vec<UdpSocket>,
+    local_addrs: Vec<SocketAddr>,
+    dscp: u8,
+}
+
+impl UdpTransport {
+    /// Bind a socket to each of `addrs`, marking datagrams with `dscp`. A lone IPv6
+    /// socket is bound dual-stack; with several, IPv6 sockets only carry IPv6 traffic,
+    /// so that they can share a port with IPv4 sockets.
+    pub fn bind(addrs: &[SocketAddr], dscp: u8) -> Result<Self> {
+        let mut sockets = Vec::with_capacity(addrs.len());
+        let mut local_addrs = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            let socket_std = bind(addr, addrs.len() > 1, dscp)
+                .chain_err(|| format!("Could not listen on {}", addr))?;
+            local_addrs.push(socket_std.local_addr()?);
+            sockets.push(UdpSocket::from_std(socket_std)?);
+        }
+        Ok(UdpTransport { sockets, local_addrs, dscp })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.local_addrs.clone()
+    }
+
+    fn open(self, state: &Arc<State>) -> Vec<(Outgoing, Incoming)> {
+        let dscp = self.dscp;
+        self.sockets.into_iter().enumerate()
+            .map(|(index, socket)| {
+                let (sink, stream) = UdpFramed::new(socket, index, dscp, MessageCodec::new(), state.clone())
+                    .split::<(Message, SocketAddr)>();
+                let sink: Outgoing = Box::pin(sink);
+                (sink, stream.boxed())
+            })
+            .collect()
+    }
+}
+
+/// Receive errors caused by a peer, such as an ICMP port unreachable reported by
+/// Windows, rather than by the socket
+fn is_peer_error(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionRefused => true,
+        _ => false,
+    }
+}
 
 /// A unified `Stream` and `Sink` interface to an underlying `UdpSocket`, using
 /// the `Encoder` and `Decoder` traits to encode and decode frames.
@@ -29,122 +144,287 @@ use utils::to_ipv6;
 /// If you want to work more directly with the streams and sink, consider
 /// calling `split` on the `UdpFramed` returned by this method, which will break
 /// them into separate objects, allowing them to interact more easily.
+///
+/// The socket is bound again to the same address after `REBIND_AFTER_ERRORS`
+/// consecutive errors, or when every socket is asked to rebind (see `rebind`). Until
+/// that succeeds, datagrams to send are dropped.
+///
+/// The datagrams received from peers which aren't whitelisted, when the node is
+/// whitelist-only, or which are excluded, are dropped before they are decoded, and
+/// those addressed to such peers before they are encoded.
 #[must_use = "sinks do nothing unless polled"]
 #[derive(Debug)]
 pub struct UdpFramed<C> {
-    socket: UdpSocket,
+    /// `None` while the socket couldn't be bound again
+    socket: Option<UdpSocket>,
+    /// The index of the socket in `State::sockets`
+    index: usize,
+    /// The DSCP code point datagrams are marked with, reapplied when rebinding
+    dscp: u8,
+    local_addr: SocketAddrV6,
     codec: C,
-    rd: BytesMut,
+    rd: Option<BytesMut>,
     wr: BytesMut,
     out_addr: SocketAddr,
+    out_detail: &'static str,
     flushed: bool,
     node_state: Arc<State>,
+    /// The `Rebind` epoch the socket was bound in
+    epoch: usize,
+    /// Consecutive send and receive errors
+    errors: usize,
+    /// Peers which couldn't be sent to during the current run of errors. They are
+    /// removed once a send succeeds, showing that the socket itself is fine.
+    failed: Vec<SocketAddrV6>,
+    /// When to next try binding the socket, after failing to
+    retry: Option<Pin<Box<Sleep>>>,
 }
 
-impl<C: Decoder> Stream for UdpFramed<C> {
-    type Item = (C::Item, SocketAddr);
-    type Error = C::Error;
+impl<C> UdpFramed<C> {
+    /// Whether the socket should be bound again
+    fn needs_rebind(&self) -> bool {
+        self.socket.is_none() || self.errors >= REBIND_AFTER_ERRORS || self.epoch != self.node_state.rebind.epoch()
+    }
 
-    fn poll(&mut self) -> Poll<Option<(Self::Item)>, Self::Error> {
-        self.rd.reserve(INITIAL_RD_CAPACITY);
-
-        let (n, addr) = unsafe {
-            // Read into the buffer without having to initialize the memory.
-            let (n, addr) = try_ready!(self.socket.poll_recv_from(self.rd.bytes_mut()));
-            self.rd.advance_mut(n);
-            (n, addr)
-        };
-        trace!("received {} bytes, decoding", n);
-        let frame_res = self.codec.decode(&mut self.rd);
-        self.rd.clear();
-        let frame = frame_res?;
-        let result = frame.map(|frame| (frame, addr)); // frame -> (frame, addr)
-        trace!("frame decoded from buffer");
-        Ok(Async::Ready(result))
+    /// Bind the socket again to its address in `State::sockets`, unless waiting to
+    /// retry. Returns whether a socket is bound.
+    fn rebind(&mut self, cx: &mut Context) -> bool {
+        if let Some(ref mut retry) = self.retry {
+            if retry.as_mut().poll(cx).is_pending() {
+                return self.socket.is_some();
+            }
+        }
+        self.retry = None;
+        self.epoch = self.node_state.rebind.epoch();
+        // The old socket is closed first, so that the address is free again
+        self.socket = None;
+        let sockets = &self.node_state.sockets;
+        let addr = sockets[self.index];
+        let bound = bind(&addr, sockets.len() > 1, self.dscp).and_then(UdpSocket::from_std);
+        // The receiving task was waiting on the old socket
+        self.node_state.rebind.wake(self.index);
+        match bound {
+            Ok(socket) => {
+                info!("Bound {} again after {} errors", addr, self.errors);
+                self.node_state.stats.inc(StatType::Peering, "rebound", Direction::Out);
+                self.socket = Some(socket);
+                self.errors = 0;
+                self.failed.clear();
+                self.node_state.rebind.rebound();
+                true
+            }
+            Err(e) => {
+                warn!("Error binding {} again, retrying in {}s: {}", addr, REBIND_RETRY, e);
+                let mut retry = Box::pin(time::sleep(Duration::from_secs(REBIND_RETRY)));
+                // Registers the waker to be woken for the retry
+                let _ = retry.as_mut().poll(cx);
+                self.retry = Some(retry);
+                false
+            }
+        }
     }
 }
 
-impl<C: Encoder> Sink for UdpFramed<C> {
-    type SinkItem = (C::Item, SocketAddr);
-    type SinkError = C::Error;
+impl<C: Decoder + Unpin> Stream for UdpFramed<C>
+    where C::Item: TrafficDetail
+{
+    type Item = ::std::result::Result<(C::Item, SocketAddr), C::Error>;
 
-    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
-        trace!("sending frame");
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let mut rebound = false;
+        loop {
+            if this.needs_rebind() {
+                if rebound {
+                    // Yield rather than spin while a new socket fails too
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                rebound = true;
+                if !this.rebind(cx) {
+                    return Poll::Pending;
+                }
+            }
+            // Kept across polls until a datagram arrives, then given back once it's decoded
+            let pool = &this.node_state.buffers;
+            let rd = this.rd.get_or_insert_with(|| pool.take());
+            let socket = this.socket.as_ref().unwrap();
 
-        if !self.flushed {
-            match try!(self.poll_complete()) {
-                Async::Ready(()) => {},
-                Async::NotReady => return Ok(AsyncSink::NotReady(item)),
+            let (result, n) = unsafe {
+                // Read into the buffer without having to initialize the memory.
+                let chunk = &mut *(rd.chunk_mut() as *mut _ as *mut [MaybeUninit<u8>]);
+                let mut read = ReadBuf::uninit(chunk);
+                let result = match socket.poll_recv_from(cx, &mut read) {
+                    Poll::Ready(result) => result,
+                    Poll::Pending => {
+                        this.node_state.rebind.register(this.index, cx.waker());
+                        // A rebind requested before registering would otherwise be missed
+                        if this.epoch != this.node_state.rebind.epoch() {
+                            cx.waker().wake_by_ref();
+                        }
+                        return Poll::Pending;
+                    }
+                };
+                let n = read.filled().len();
+                rd.advance_mut(n);
+                (result, n)
+            };
+            let addr = match result {
+                Ok(addr) => addr,
+                Err(ref e) if is_peer_error(e) => continue,
+                Err(e) => {
+                    debug!("Error receiving datagram: {}", e);
+                    this.errors += 1;
+                    continue;
+                }
+            };
+            this.errors = 0;
+            let mut rd = this.rd.take().unwrap();
+            if let Some(detail) = this.node_state.blocked(to_ipv6(addr)) {
+                this.node_state.buffers.give_back(rd);
+                this.node_state.stats.inc(StatType::Drop, detail, Direction::In);
+                continue;
+            }
+            // Processing of the decoded message continues in the `message` span, which
+            // shares the `peer` field with this one
+            let span = trace_span!("udp_recv", peer = %addr, bytes = n);
+            let _enter = span.enter();
+            this.node_state.capture.record(Direction::In, this.local_addr, to_ipv6(addr), &rd);
+            trace!("received {} bytes, decoding", n);
+            this.node_state.stats.add(StatType::Traffic, "bytes", Direction::In, n as u64);
+            let codec = &mut this.codec;
+            let frame_res = trace_span!("decode").in_scope(|| codec.decode(&mut rd));
+            this.node_state.buffers.give_back(rd);
+            let frame = match frame_res {
+                Ok(frame) => frame,
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            };
+            if let Some(ref frame) = frame {
+                this.node_state.stats.add(StatType::Traffic, frame.traffic_detail(), Direction::In, n as u64);
             }
+            this.node_state.record_peer_traffic(to_ipv6(addr), Direction::In, n as u64);
+            let result = frame.map(|frame| Ok((frame, addr))); // frame -> (frame, addr)
+            trace!("frame decoded from buffer");
+            return Poll::Ready(result);
         }
+    }
+}
+
+impl<I, C: Encoder<I> + Unpin> Sink<(I, SocketAddr)> for UdpFramed<C>
+    where I: TrafficDetail
+{
+    type Error = C::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<::std::result::Result<(), C::Error>> {
+        if self.flushed {
+            return Poll::Ready(Ok(()));
+        }
+        Sink::<(I, SocketAddr)>::poll_flush(self, cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: (I, SocketAddr)) -> ::std::result::Result<(), C::Error> {
+        trace!("sending frame");
+        let this = self.get_mut();
 
         let (frame, out_addr) = item;
-        self.codec.encode(frame, &mut self.wr)?;
-        self.out_addr = out_addr;
-        self.flushed = false;
-        trace!("frame encoded; length={}", self.wr.len());
+        if let Some(detail) = this.node_state.blocked(to_ipv6(out_addr)) {
+            this.node_state.stats.inc(StatType::Drop, detail, Direction::Out);
+            return Ok(());
+        }
+        this.out_detail = frame.traffic_detail();
+        this.codec.encode(frame, &mut this.wr)?;
+        this.out_addr = out_addr;
+        this.flushed = false;
+        trace!("frame encoded; length={}", this.wr.len());
 
-        Ok(AsyncSink::Ready)
+        Ok(())
     }
 
-    fn poll_complete(&mut self) -> Poll<(), C::Error> {
-        if self.flushed {
-            return Ok(Async::Ready(()))
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<::std::result::Result<(), C::Error>> {
+        let this = self.get_mut();
+        if this.flushed {
+            return Poll::Ready(Ok(()))
         }
 
-        trace!("flushing frame; length={}", self.wr.len());
-        match self.socket.poll_send_to(&self.wr, &self.out_addr) {
-            Ok(Async::NotReady) => {
-                return Ok(Async::NotReady);
-            },
-            Ok(Async::Ready(n)) => {
+        trace!("flushing frame; length={}", this.wr.len());
+        // Retries are driven by the receiving task, so that sending isn't held up
+        if this.needs_rebind() && (this.retry.is_some() || !this.rebind(cx)) {
+            trace!("no socket, dropping frame");
+            this.wr.clear();
+            this.flushed = true;
+            return Poll::Ready(Ok(()));
+        }
+        let socket = this.socket.as_ref().unwrap();
+        match ready!(socket.poll_send_to(cx, &this.wr, this.out_addr)) {
+            Ok(n) => {
                 trace!("written {}", n);
+                this.node_state.capture.record(Direction::Out, this.local_addr, to_ipv6(this.out_addr), &this.wr[..n]);
+                this.node_state.stats.add(StatType::Traffic, "bytes", Direction::Out, n as u64);
+                this.node_state.stats.add(StatType::Traffic, this.out_detail, Direction::Out, n as u64);
+                this.node_state.record_peer_traffic(to_ipv6(this.out_addr), Direction::Out, n as u64);
 
-                let wrote_all = n == self.wr.len();
-                self.wr.clear();
-                self.flushed = true;
-
-                if !wrote_all {
-                    debug!("Failed to write entire datagram to socket; Wrote: {} expected: {}", n, self.wr.len());
+                if n != this.wr.len() {
+                    debug!("Failed to write entire datagram to socket; Wrote: {} expected: {}", n, this.wr.len());
+                }
+                this.errors = 0;
+                for peer in this.failed.drain(..) {
+                    debug!("Removing peer which couldn't be sent to: {}", peer);
+                    this.node_state.remove_peer(peer);
                 }
             },
             Err(e) => {
-                if e.kind() == ::std::io::ErrorKind::WouldBlock {
-                    return Ok(Async::NotReady);
+                debug!("Error sending frame to {}: {:?}", this.out_addr, e);
+                this.errors += 1;
+                if this.failed.len() < REBIND_AFTER_ERRORS {
+                    this.failed.push(to_ipv6(this.out_addr));
                 }
-                debug!("Error sending frame: {:?}, removing peer: {}", e, self.out_addr);
-                self.node_state.remove_peer(to_ipv6(self.out_addr));
             }
         }
-        Ok(Async::Ready(()))
+        // The datagram is dropped either way, rather than being retried
+        this.wr.clear();
+        this.flushed = true;
+        Poll::Ready(Ok(()))
     }
 
-    fn close(&mut self) -> Poll<(), C::Error> {
-        try_ready!(self.poll_complete());
-        Ok(().into())
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<::std::result::Result<(), C::Error>> {
+        Sink::<(I, SocketAddr)>::poll_flush(self, cx)
     }
 }
 
-const INITIAL_RD_CAPACITY: usize = 64 * 1024;
-const INITIAL_WR_CAPACITY: usize = 8 * 1024;
+pub const INITIAL_RD_CAPACITY: usize = 64 * 1024;
+/// Number of idle receive buffers kept for reuse
+pub const POOLED_RD_BUFFERS: usize = 16;
+pub const INITIAL_WR_CAPACITY: usize = 8 * 1024;
 
 impl<C> UdpFramed<C> {
-    /// Create a new `UdpFramed` backed by the given socket and codec.
+    /// Create a new `UdpFramed` backed by the given socket, bound to the address at
+    /// `index` of `State::sockets`, and codec.
     ///
     /// See struct level documention for more details.
-    pub fn new(socket: UdpSocket, codec: C, state: Arc<State>) -> UdpFramed<C> {
+    pub fn new(socket: UdpSocket, index: usize, dscp: u8, codec: C, state: Arc<State>) -> UdpFramed<C> {
+        let local_addr = socket.local_addr().map(to_ipv6)
+            .unwrap_or_else(|_| SocketAddrV6::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), 0, 0, 0));
         UdpFramed {
-            socket: socket,
+            socket: Some(socket),
+            index,
+            dscp,
+            local_addr,
             codec: codec,
             out_addr: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0)),
-            rd: BytesMut::with_capacity(INITIAL_RD_CAPACITY),
+            out_detail: "bytes",
+            rd: None,
             wr: BytesMut::with_capacity(INITIAL_WR_CAPACITY),
             flushed: true,
+            epoch: state.rebind.epoch(),
             node_state: state,
+            errors: 0,
+            failed: Vec::new(),
+            retry: None,
         }
     }
 
-    /// Returns a reference to the underlying I/O stream wrapped by `Framed`.
+    /// Returns a reference to the underlying I/O stream wrapped by `Framed`, unless
+    /// it couldn't be bound again.
     ///
     /// # Note
     ///
@@ -152,12 +432,12 @@ impl<C> UdpFramed<C> {
     /// coming in as it may corrupt the stream of frames otherwise being worked
     /// with.
     #[allow(dead_code)]
-    pub fn get_ref(&self) -> &UdpSocket {
-        &self.socket
+    pub fn get_ref(&self) -> Option<&UdpSocket> {
+        self.socket.as_ref()
     }
 
     /// Returns a mutable reference to the underlying I/O stream wrapped by
-    /// `Framed`.
+    /// `Framed`, unless it couldn't be bound again.
     ///
     /// # Note
     ///
@@ -165,13 +445,13 @@ impl<C> UdpFramed<C> {
     /// coming in as it may corrupt the stream of frames otherwise being worked
     /// with.
     #[allow(dead_code)]
-    pub fn get_mut(&mut self) -> &mut UdpSocket {
-        &mut self.socket
+    pub fn get_mut(&mut self) -> Option<&mut UdpSocket> {
+        self.socket.as_mut()
     }
 
     /// Consumes the `Framed`, returning its underlying I/O stream.
     #[allow(dead_code)]
-    pub fn into_inner(self) -> UdpSocket {
+    pub fn into_inner(self) -> Option<UdpSocket> {
         self.socket
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,174 @@
+//! Taking scheduled backups of the ledger, so that representatives have restore points.
+//!
+//! Every `BackupConfig::interval` the ledger is exported, from one snapshot of the
+//! store, to a dump in `directory` named after when it was taken, which `ledger_import`
+//! or a `[snapshot]` can restore. The next backup is due an interval after the newest
+//! one in the directory, so restarting the node doesn't put it off. Once a backup is
+//! written, all but the newest `keep` are deleted, and `command`, if set, is run with
+//! the new backup's path, to upload it elsewhere.
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use chrono::Utc;
+use tokio::time;
+
+use crate::error::*;
+use crate::store::{self, Store};
+use crate::store::export::{self, DumpFormat};
+use super::state::State;
+
+/// The start of the names of backup files
+const BACKUP_PREFIX: &str = "ledger-";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackupConfig {
+    pub directory: PathBuf,
+    pub interval: Duration,
+    /// Backups kept, deleting older ones
+    pub keep: usize,
+    pub format: DumpFormat,
+    /// Run with the path of each new backup
+    pub command: Option<String>,
+}
+
+impl BackupConfig {
+    fn extension(&self) -> &'static str {
+        match self.format {
+            DumpFormat::Json => "json",
+            DumpFormat::Csv => "csv",
+        }
+    }
+
+    /// The backups in `directory`, oldest first
+    fn backups(&self) -> Result<Vec<PathBuf>> {
+        if !self.directory.is_dir() {
+            return Ok(Vec::new());
+        }
+        let suffix = format!(".{}", self.extension());
+        let mut backups = Vec::new();
+        for entry in fs::read_dir(&self.directory)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if name.starts_with(BACKUP_PREFIX) && name.ends_with(&suffix) {
+                backups.push(self.directory.join(name));
+            }
+        }
+        // Names are timestamps, which sort in the order they were taken
+        backups.sort();
+        Ok(backups)
+    }
+
+    /// How long until the next backup is due
+    fn until_due(&self) -> Result<Duration> {
+        let newest = match self.backups()?.pop() {
+            Some(newest) => newest,
+            None => return Ok(Duration::from_secs(0)),
+        };
+        let age = SystemTime::now().duration_since(fs::metadata(&newest)?.modified()?).unwrap_or_default();
+        Ok(self.interval.checked_sub(age).unwrap_or_default())
+    }
+}
+
+/// Export `store` to a new backup, delete those beyond the newest `keep`, and run the
+/// hook on it. Returns the new backup's path.
+pub fn take_backup(store: &dyn Store, config: &BackupConfig) -> Result<PathBuf> {
+    fs::create_dir_all(&config.directory)
+        .chain_err(|| format!("Could not create {}", config.directory.display()))?;
+    let name = format!("{}{}.{}", BACKUP_PREFIX, Utc::now().format("%Y%m%dT%H%M%S%.3fZ"), config.extension());
+    let path = config.directory.join(name);
+    // Written under another name first, so that an interrupted backup isn't kept
+    let partial = path.with_extension("partial");
+    {
+        let mut out = BufWriter::new(File::create(&partial)?);
+        store::read_snapshot(store, |store| export::export(store, config.format, &mut out))?;
+        out.flush()?;
+    }
+    fs::rename(&partial, &path)?;
+    rotate(config)?;
+    if let Some(ref command) = config.command {
+        run_hook(command, &path)?;
+    }
+    Ok(path)
+}
+
+/// Delete all but the newest `keep` backups
+fn rotate(config: &BackupConfig) -> Result<()> {
+    let backups = config.backups()?;
+    let excess = backups.len().saturating_sub(config.keep);
+    for old in &backups[..excess] {
+        fs::remove_file(old)?;
+        debug!("Deleted the old backup {}", old.display());
+    }
+    Ok(())
+}
+
+fn run_hook(command: &str, path: &Path) -> Result<()> {
+    let status = Command::new(command).arg(path).status()
+        .chain_err(|| format!("Could not run the backup command {}", command))?;
+    if !status.success() {
+        bail!("The backup command {} failed on {}: {}", command, path.display(), status);
+    }
+    Ok(())
+}
+
+/// Take a backup whenever one is due, until the node shuts down
+pub async fn schedule(state: Arc<State>, config: BackupConfig) {
+    loop {
+        let wait = config.until_due().unwrap_or_else(|e| {
+            warn!("Error finding the latest ledger backup: {}", e);
+            config.interval
+        });
+        time::sleep(wait).await;
+        let (store, backup) = (state.store.clone(), config.clone());
+        let started = SystemTime::now();
+        let failed = match tokio::task::spawn_blocking(move || take_backup(&*store, &backup)).await {
+            Ok(Ok(path)) => {
+                info!("Backed up the ledger to {} in {}s", path.display(),
+                    started.elapsed().unwrap_or_default().as_secs());
+                false
+            }
+            Ok(Err(e)) => {
+                error!("Error backing up the ledger: {}", e);
+                true
+            }
+            Err(_) => {
+                error!("Backup thread exited unexpectedly");
+                true
+            }
+        };
+        // A failed backup is retried after an interval, rather than right away
+        if failed {
+            time::sleep(config.interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::MemoryStore;
+
+    #[test]
+    fn rotates_backups() {
+        let dir = ::std::env::temp_dir().join(format!("nano-rs-backup-test-{}", ::std::process::id()));
+        let config = BackupConfig {
+            directory: dir.clone(),
+            interval: Duration::from_secs(86_400),
+            keep: 2,
+            format: DumpFormat::Json,
+            command: None,
+        };
+        fs::create_dir_all(&dir).unwrap();
+        for old in &["ledger-20200101T000000.000Z.json", "ledger-20200102T000000.000Z.json", "notes.json"] {
+            fs::write(dir.join(old), b"").unwrap();
+        }
+        let path = take_backup(&MemoryStore::new(), &config).unwrap();
+        assert_eq!(config.backups().unwrap(), vec![dir.join("ledger-20200102T000000.000Z.json"), path]);
+        assert!(dir.join("notes.json").exists());
+        assert!(config.until_due().unwrap() > Duration::from_secs(86_000));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
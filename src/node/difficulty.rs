@@ -0,0 +1,57 @@
+//! Tracks the difficulty of work on recently observed blocks to estimate how
+//! much work is needed for a block to be prioritized by the network.
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+
+/// Number of recently observed blocks used to compute the active multiplier
+const DIFFICULTY_SAMPLES: usize = 256;
+
+#[derive(Debug)]
+pub struct DifficultyTracker {
+    base: u64,
+    samples: Mutex<VecDeque<f64>>,
+}
+
+impl DifficultyTracker {
+    /// Track difficulty relative to the network minimum `base`
+    pub fn new(base: u64) -> Self {
+        DifficultyTracker {
+            base,
+            samples: Mutex::new(VecDeque::with_capacity(DIFFICULTY_SAMPLES)),
+        }
+    }
+
+    /// The minimum difficulty accepted by the network
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    /// Record the work difficulty of a block seen on the network
+    pub fn observe(&self, difficulty: u64) {
+        if difficulty < self.base {
+            return;
+        }
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() == DIFFICULTY_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(nanopow_rs::to_multiplier(difficulty, self.base));
+    }
+
+    /// The median multiplier of recently observed blocks; 1.0 when the network is idle
+    pub fn multiplier(&self) -> f64 {
+        let mut sorted: Vec<f64> = self.samples.lock().unwrap().iter().cloned().collect();
+        if sorted.is_empty() {
+            return 1.0;
+        }
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let median = sorted[sorted.len() / 2];
+        if median > 1.0 { median } else { 1.0 }
+    }
+
+    /// The difficulty new blocks should be generated at to compete during congestion
+    pub fn active_difficulty(&self) -> u64 {
+        nanopow_rs::from_multiplier(self.multiplier(), self.base)
+    }
+}
@@ -0,0 +1,98 @@
+//! Upgrading accounts to new epochs, on the private networks nano-rs is tested on.
+//!
+//! Epoch blocks are state blocks which only change their account's epoch. They link to
+//! `epoch_link(version)`, and are signed by the network's signer of that epoch rather
+//! than by the account. The store doesn't record accounts' epochs, so an account's
+//! epoch is found by following its chain back to its latest epoch block. That suits the
+//! small ledgers of test networks, but is slow on large ones.
+use nano_lib_rs::block::{Block, BlockHash, BlockPayload};
+use nano_lib_rs::keys::PublicKey;
+use nano_lib_rs::network::NetworkParams;
+
+use crate::error::*;
+use crate::store::{AccountInfo, Store};
+use crate::store::export::previous;
+
+/// The epoch of the chain ending in `head`, or 0 if it has no epoch blocks. Stops at
+/// the first block which isn't stored, as in pruned ledgers.
+pub fn chain_epoch(store: &dyn Store, network: &NetworkParams, head: BlockHash) -> Result<u8> {
+    let mut next = Some(head);
+    while let Some(hash) = next {
+        let block: Block = match store.block(&hash)? {
+            Some(block) => block,
+            None => break,
+        };
+        if let Some(BlockPayload::State { ref link, .. }) = block.payload {
+            if let Some(version) = network.epoch_version(link.as_bytes()) {
+                return Ok(version);
+            }
+        }
+        next = previous(&block);
+    }
+    Ok(0)
+}
+
+/// Up to `count` accounts which are in the epoch before `version`, and so can be
+/// upgraded to it
+pub fn upgradable(store: &dyn Store, network: &NetworkParams, version: u8, count: usize)
+    -> Result<Vec<(PublicKey, AccountInfo)>>
+{
+    // Chains are followed once the accounts have been read, rather than while reading
+    // them, as LMDB allows only one read transaction per thread
+    let mut accounts = Vec::new();
+    store.for_each_account(&mut |account, info| {
+        accounts.push((account, info));
+        Ok(())
+    })?;
+    let mut upgradable = Vec::new();
+    for (account, info) in accounts {
+        if upgradable.len() == count {
+            break;
+        }
+        if chain_epoch(store, network, info.head)? + 1 == version {
+            upgradable.push((account, info));
+        }
+    }
+    Ok(upgradable)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nano_lib_rs::block::{BlockKind, Link};
+    use nano_lib_rs::network::epoch_link;
+    use crate::store::WriteBatch;
+    use crate::store::memory::MemoryStore;
+
+    #[test]
+    fn finds_epochs() {
+        let store = MemoryStore::new();
+        let network = NetworkParams::dev();
+        let account = PublicKey::from_bytes(&[1u8; 32]).unwrap();
+        let mut batch = WriteBatch::new();
+        let mut previous = BlockHash::from_bytes(&[0u8; 32]).unwrap();
+        for link in &[[2u8; 32], epoch_link(1), [3u8; 32]] {
+            let mut block = Block::new(BlockKind::State, Some(BlockPayload::State {
+                account: account.clone(),
+                previous,
+                representative: account.clone(),
+                balance: 1,
+                link: Link::Unknown(*link),
+            }), None, None);
+            previous = block.hash(false).unwrap();
+            batch.put_block(previous, block);
+        }
+        batch.put_account(account.clone(), AccountInfo {
+            head: previous,
+            representative: account.clone(),
+            balance: 1,
+            confirmation_height: 0,
+        });
+        store.write(&batch).unwrap();
+
+        assert_eq!(chain_epoch(&store, &network, previous).unwrap(), 1);
+        assert_eq!(chain_epoch(&store, &network, batch.blocks()[0].0).unwrap(), 0);
+        assert!(upgradable(&store, &network, 1, 10).unwrap().is_empty());
+        assert_eq!(upgradable(&store, &network, 2, 10).unwrap().len(), 1);
+    }
+}
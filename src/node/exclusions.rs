@@ -0,0 +1,172 @@
+//! Peers excluded from the node, by their IP address, until an exclusion expires or is
+//! removed.
+//!
+//! Datagrams from and to excluded hosts are dropped as they are received and sent, on
+//! any port. Exclusions are added and removed over RPC, or with the `peer_exclude`
+//! commands while the node is stopped, and saved to a file next to the store as they
+//! change, so that they outlast restarts. Expired exclusions are forgotten when the
+//! list is next read.
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::*;
+use crate::utils::to_ipv6;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Exclusion {
+    /// When the exclusion expires, in seconds since the Unix epoch, or `None` if it
+    /// never does
+    pub until: Option<u64>,
+    pub reason: String,
+}
+
+impl Exclusion {
+    fn is_active(&self, now: u64) -> bool {
+        self.until.map_or(true, |until| until > now)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    address: Ipv6Addr,
+    #[serde(flatten)]
+    exclusion: Exclusion,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs())
+}
+
+/// The file the exclusions are saved in, next to the store at `path`
+pub fn path(store_path: &Path) -> PathBuf {
+    let mut path = store_path.to_owned().into_os_string();
+    path.push(".excluded_peers");
+    PathBuf::from(path)
+}
+
+/// Parse a host to exclude, given as an IP address with or without a port
+pub fn parse_host(host: &str) -> Result<Ipv6Addr> {
+    let ip = match host.parse::<SocketAddr>() {
+        Ok(addr) => addr.ip(),
+        Err(_) => host.parse::<IpAddr>().map_err(|_| Error::from(format!("`{}` is not an IP address", host)))?,
+    };
+    Ok(*to_ipv6(SocketAddr::new(ip, 0)).ip())
+}
+
+#[derive(Debug, Default)]
+pub struct Exclusions {
+    /// Where the exclusions are saved, unless they are only kept in memory
+    path: Option<PathBuf>,
+    hosts: RwLock<BTreeMap<Ipv6Addr, Exclusion>>,
+}
+
+impl Exclusions {
+    /// Exclusions kept only in memory
+    #[cfg(test)]
+    pub fn new() -> Self {
+        Exclusions::default()
+    }
+
+    /// The exclusions saved at `path`, which are saved there again as they change
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let mut hosts = BTreeMap::new();
+        match File::open(&path) {
+            Ok(file) => {
+                let entries: Vec<Entry> = serde_json::from_reader(BufReader::new(file))
+                    .chain_err(|| format!("Could not read the excluded peers in {}", path.display()))?;
+                let now = now();
+                for entry in entries.into_iter().filter(|entry| entry.exclusion.is_active(now)) {
+                    hosts.insert(entry.address, entry.exclusion);
+                }
+            }
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+        Ok(Exclusions { path: Some(path), hosts: RwLock::new(hosts) })
+    }
+
+    pub fn is_excluded(&self, host: &Ipv6Addr) -> bool {
+        self.hosts.read().unwrap().get(host).map_or(false, |exclusion| exclusion.is_active(now()))
+    }
+
+    /// Exclude `host` for `duration`, or until the exclusion is removed if `None`,
+    /// replacing any exclusion it had. Returns the exclusion.
+    pub fn exclude(&self, host: Ipv6Addr, duration: Option<Duration>, reason: &str) -> Result<Exclusion> {
+        let exclusion = Exclusion {
+            until: duration.map(|duration| now().saturating_add(duration.as_secs())),
+            reason: reason.to_owned(),
+        };
+        let mut hosts = self.hosts.write().unwrap();
+        hosts.insert(host, exclusion.clone());
+        self.save(&hosts)?;
+        Ok(exclusion)
+    }
+
+    /// Remove the exclusion of `host`, returning whether it had one
+    pub fn remove(&self, host: &Ipv6Addr) -> Result<bool> {
+        let mut hosts = self.hosts.write().unwrap();
+        let removed = hosts.remove(host).map_or(false, |exclusion| exclusion.is_active(now()));
+        self.save(&hosts)?;
+        Ok(removed)
+    }
+
+    /// The hosts excluded now, forgetting the exclusions which expired
+    pub fn list(&self) -> Result<Vec<(Ipv6Addr, Exclusion)>> {
+        let mut hosts = self.hosts.write().unwrap();
+        let now = now();
+        let count = hosts.len();
+        hosts.retain(|_, exclusion| exclusion.is_active(now));
+        if hosts.len() != count {
+            self.save(&hosts)?;
+        }
+        Ok(hosts.iter().map(|(host, exclusion)| (*host, exclusion.clone())).collect())
+    }
+
+    fn save(&self, hosts: &BTreeMap<Ipv6Addr, Exclusion>) -> Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let entries: Vec<Entry> = hosts.iter()
+            .map(|(&address, exclusion)| Entry { address, exclusion: exclusion.clone() })
+            .collect();
+        let mut staging = path.clone().into_os_string();
+        staging.push(".tmp");
+        serde_json::to_writer(BufWriter::new(File::create(&staging)?), &entries)?;
+        fs::rename(&staging, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn persists_exclusions() {
+        let path = ::std::env::temp_dir().join(format!("nano-rs-exclusions-test-{}", ::std::process::id()));
+        let (host, other) = (parse_host("203.0.113.1:7075").unwrap(), parse_host("2001:db8::1").unwrap());
+        {
+            let exclusions = Exclusions::load(path.clone()).unwrap();
+            exclusions.exclude(host, None, "spam").unwrap();
+            exclusions.exclude(other, Some(Duration::from_secs(3600)), "").unwrap();
+            assert!(exclusions.is_excluded(&parse_host("203.0.113.1").unwrap()));
+        }
+        let exclusions = Exclusions::load(path.clone()).unwrap();
+        assert!(exclusions.is_excluded(&host) && exclusions.is_excluded(&other));
+        assert_eq!(exclusions.list().unwrap()[0].1, Exclusion { until: None, reason: "spam".into() });
+        assert!(exclusions.remove(&host).unwrap());
+        assert!(!exclusions.remove(&host).unwrap());
+        // An exclusion which has expired is forgotten
+        exclusions.hosts.write().unwrap().get_mut(&other).unwrap().until = Some(now() - 1);
+        assert!(!exclusions.is_excluded(&other));
+        assert!(exclusions.list().unwrap().is_empty());
+        assert!(Exclusions::load(path.clone()).unwrap().list().unwrap().is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,71 +1,105 @@
-use nano_lib_rs::message::{MessageBuilder, Message, MessageKind, MessagePayload};
+use nano_lib_rs::message::{Message, MessagePayload};
+use nano_lib_rs::block::{Block, BlockHash};
 
-use node::State;
-use error::*;
-use utils::check_addr;
+use crate::node::{Messages, State};
+use crate::node::queue::TrafficClass;
+use crate::error::*;
+use crate::stats::{Direction, StatType};
+use crate::utils::check_addr;
 
-use std::net::{SocketAddrV6, SocketAddr};
+use std::net::{Ipv6Addr, SocketAddrV6, SocketAddr};
 use std::sync::Arc;
+use std::time::Instant;
 
-use futures::{stream, Stream};
+use futures::{stream, StreamExt};
 
-pub fn keepalive(msg: Message, _src: SocketAddrV6, state: Arc<State>)
-    -> Box<Stream<Item=(Message, SocketAddr), Error=Error> + Send>
-{
+/// Queue `block`, received in traffic of `class`, to have its signature checked and then
+/// be processed, unless it can't be hashed. Publishes of blocks which were seen before
+/// are queued as republishes.
+fn process_block(mut block: Block, class: TrafficClass, src: SocketAddrV6, state: &State) {
+    let hash = match block.hash(false) {
+        Ok(hash) => hash,
+        Err(e) => {
+            debug!("Error calculating hash for block: {}", e);
+            return;
+        }
+    };
+    let class = match (state.block_seen(hash), class) {
+        (false, TrafficClass::Publish) => TrafficClass::Republish,
+        (_, class) => class,
+    };
+    let key = state.processor.route(hash, &block);
+    if let Err(reason) = state.verifier.submit(class, key, block, hash, src) {
+        state.stats.inc(StatType::Drop, reason.detail(class), Direction::In);
+        debug!("Signature verification queue too full, dropping block {}: {}", String::from(hash), reason.detail(class));
+    }
+}
+
+/// Process `block`, created by this node, as if it had been published to it, and
+/// publish it to every peer. Its work is kept competitive until it is confirmed.
+pub fn publish_local(mut block: Block, state: &State) -> Result<BlockHash> {
+    let hash = block.hash(false)?;
+    state.work_watcher.watch(block.clone());
+    state.republisher.republish(vec![block.clone()]);
+    process_block(block, TrafficClass::Publish, SocketAddrV6::new(Ipv6Addr::LOCALHOST, 0, 0, 0), state);
+    Ok(hash)
+}
+
+pub fn keepalive(msg: Message, src: SocketAddrV6, state: Arc<State>) -> Messages {
+    state.keepalive_received(src, msg.header.extensions);
     if let MessagePayload::KeepAlive(peer_addrs) = msg.payload {
-        let send_peers = state.random_peers(8);
-        let msg = MessageBuilder::new(MessageKind::KeepAlive)
-            .with_payload(MessagePayload::KeepAlive(send_peers))
-            .build();
-        let to_send = peer_addrs.into_iter()
-            .filter_map(move |peer_addr| {
-                if check_addr(peer_addr) {
-                    Some((msg.clone(), SocketAddr::V6(peer_addr)))
-                } else {
-                    None
-                }
-            });
+        let msg = state.keepalive();
+        // Known peers are sent keepalives periodically. Also answering the peers they
+        // list would have every keepalive between known peers set off several more.
+        let to_send: Vec<_> = peer_addrs.into_iter()
+            .filter(|&peer_addr| check_addr(peer_addr) && !state.is_peer(&peer_addr))
+            .map(|peer_addr| Ok((msg.clone(), SocketAddr::V6(peer_addr))))
+            .collect();
         let count = state.peer_count();
         debug!("Added peers, new peer count: {}", count);
-        Box::new(stream::iter_ok(to_send))
+        stream::iter(to_send).boxed()
     } else {
         debug!("Malformed Keepalive, no peers added!");
-        Box::new(stream::empty())
+        stream::empty().boxed()
     }
 }
 
-pub fn publish(mut msg: Message, _src: SocketAddrV6, _state: Arc<State>)
-    -> Box<Stream<Item=(Message, SocketAddr), Error=Error> + Send>
-{
-    if let MessagePayload::Publish(ref mut block) =  msg.payload {
-        let hash = match block.hash(false) {
-            Ok(hash) => hash.into(),
-            Err(e) => format!("Error calculating hash for block: {}", e),
-        };
-        let valid = if block.verify_work().unwrap_or(false) { "valid" } else { "INVALID" };
-        info!("Got {:?} block with hash {}. Work {}.", block.kind, hash, valid);
-        Box::new(stream::empty())
+pub fn publish(msg: Message, src: SocketAddrV6, state: Arc<State>) -> Messages {
+    if let MessagePayload::Publish(block) = msg.payload {
+        process_block(block, TrafficClass::Publish, src, &state);
     } else {
         debug!("Malformed Publish, ignoring.");
-        Box::new(stream::empty())
     }
+    stream::empty().boxed()
 }
 
-pub fn confirm_req(mut msg: Message, _src: SocketAddrV6, _state: Arc<State>)
-    -> Box<Stream<Item=(Message, SocketAddr), Error=Error> + Send>
-{
+pub fn confirm_req(msg: Message, src: SocketAddrV6, state: Arc<State>) -> Messages {
+    if let MessagePayload::ConfirmReq(block) = msg.payload {
+        process_block(block, TrafficClass::ConfirmReq, src, &state);
+    } else {
+        debug!("Malformed ConfirmReq, ignoring.");
+    }
+    stream::empty().boxed()
+}
 
-    if let MessagePayload::ConfirmReq(ref mut block) =  msg.payload {
+/// Process the block a vote is for, unless its representative's votes are throttled.
+/// Votes themselves aren't counted yet.
+pub fn confirm_ack(msg: Message, src: SocketAddrV6, state: Arc<State>) -> Messages {
+    if let MessagePayload::ConfirmAck { public_key, mut block, .. } = msg.payload {
         let hash = match block.hash(false) {
-            Ok(hash) => hash.into(),
-            Err(e) => format!("Error calculating hash for block: {}", e),
+            Ok(hash) => hash,
+            Err(e) => {
+                debug!("Error calculating hash for voted block: {}", e);
+                return stream::empty().boxed();
+            }
         };
-        let valid = if block.verify_work().unwrap_or(false) { "valid" } else { "invalid" };
-        info!("Got {:?} block with hash {}. Work is {}", block.kind, hash, valid);
-        Box::new(stream::empty())
+        if let Err(reason) = state.recent_votes.check(&public_key, hash, Instant::now()) {
+            state.stats.inc(StatType::Drop, reason.detail(), Direction::In);
+            return stream::empty().boxed();
+        }
+        process_block(block, TrafficClass::ConfirmAck, src, &state);
     } else {
-        debug!("Malformed ConfirmReq, ignoring.");
-        Box::new(stream::empty())
+        debug!("Malformed ConfirmAck, ignoring.");
     }
+    stream::empty().boxed()
 }
- 
\ No newline at end of file
@@ -0,0 +1,91 @@
+//! Estimates of the memory held by the node's containers, and allocator statistics
+//! when built with the `jemalloc` feature, queried with the `memory_stats` RPC.
+//!
+//! Container sizes are the number of entries times the size of an entry, so memory
+//! owned by entries (such as a block's signature and payload) and the container's own
+//! overhead are not included. They are meant for telling which subsystem is growing,
+//! not for accounting for every byte.
+use std::mem::size_of;
+use std::net::{SocketAddr, SocketAddrV6};
+use std::time::Instant;
+
+use chrono::{DateTime, Local};
+
+use nano_lib_rs::block::{Block, BlockHash, InputHash, Work};
+use nano_lib_rs::message::Message;
+
+use crate::error::*;
+use crate::net::udp_framed::{INITIAL_RD_CAPACITY, INITIAL_WR_CAPACITY};
+use crate::stats::{Direction, StatType};
+use super::SEND_QUEUE_SIZE;
+use super::state::{PeerInfo, State};
+
+#[derive(Clone, Debug)]
+pub struct ContainerUsage {
+    pub name: &'static str,
+    pub count: usize,
+    /// Estimated bytes held by the container
+    pub bytes: usize,
+}
+
+fn usage<T>(name: &'static str, count: usize) -> ContainerUsage {
+    ContainerUsage {
+        name,
+        count,
+        bytes: count * size_of::<T>(),
+    }
+}
+
+/// The estimated size of each of the node's containers
+pub fn containers(state: &State) -> Vec<ContainerUsage> {
+    let sample_values = state.stats.samples().iter().map(|sample| sample.values.len()).sum();
+    vec![
+        usage::<(SocketAddrV6, PeerInfo)>("peers", state.peer_count()),
+        usage::<(SocketAddrV6, PeerInfo)>("inactive_peers", state.inactive_peers.read().unwrap().len()),
+        usage::<(BlockHash, Instant)>("seen_blocks", state.seen_block_count()),
+        usage::<(InputHash, Work)>("work_cache", state.work.cached_count()),
+        usage::<(BlockHash, Block, Instant, bool)>("work_watcher", state.work_watcher.len()),
+        usage::<((BlockHash, u64, SocketAddrV6), Instant)>("recent_publishes", state.recent_publishes.len()),
+        usage::<(([u8; 32], BlockHash), Instant)>("recent_votes", state.recent_votes.len()),
+        usage::<([u8; 32], u128)>("rep_weights", state.weights.len()),
+        usage::<(Message, SocketAddr)>("send_queue", SEND_QUEUE_SIZE),
+        usage::<[u8; INITIAL_RD_CAPACITY]>("receive_buffers", state.buffers.len()),
+        usage::<[u8; INITIAL_WR_CAPACITY]>("send_buffer", 1),
+        usage::<((StatType, &str, Direction), (u64, DateTime<Local>))>("stats_counters", state.stats.counters().len()),
+        usage::<(DateTime<Local>, u64)>("stats_samples", sample_values),
+        usage::<(DateTime<Local>, String)>("recent_errors", state.errors.recent().len()),
+    ]
+}
+
+/// Bytes as reported by the allocator
+#[derive(Clone, Copy, Debug)]
+pub struct AllocatorStats {
+    /// Allocated by the application
+    pub allocated: usize,
+    /// In pages used by allocations
+    pub active: usize,
+    /// In physically resident pages mapped by the allocator
+    pub resident: usize,
+    /// In chunks mapped by the allocator
+    pub mapped: usize,
+}
+
+/// Allocator statistics, if nano-rs was built with the `jemalloc` feature
+#[cfg(feature = "jemalloc")]
+pub fn allocator() -> Result<Option<AllocatorStats>> {
+    use jemalloc_ctl::{epoch, stats};
+    let ctl_error = |e: ::jemalloc_ctl::Error| Error::from(format!("Error reading jemalloc stats: {}", e));
+    // Statistics are cached by jemalloc until the epoch is advanced
+    epoch::advance().map_err(ctl_error)?;
+    Ok(Some(AllocatorStats {
+        allocated: stats::allocated::read().map_err(ctl_error)?,
+        active: stats::active::read().map_err(ctl_error)?,
+        resident: stats::resident::read().map_err(ctl_error)?,
+        mapped: stats::mapped::read().map_err(ctl_error)?,
+    }))
+}
+
+#[cfg(not(feature = "jemalloc"))]
+pub fn allocator() -> Result<Option<AllocatorStats>> {
+    Ok(None)
+}
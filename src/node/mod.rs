@@ -1,152 +1,606 @@
+pub mod backup;
+pub mod difficulty;
+pub mod epoch;
+pub mod exclusions;
 pub mod handler;
+pub mod memory;
+pub mod processor;
+pub mod pruner;
+pub mod queue;
+pub mod republish;
+pub mod rollback;
+pub mod shutdown;
 pub mod state;
+pub mod verifier;
+pub mod voting;
+pub mod votes;
+pub mod webhook;
+pub mod weights;
+pub mod work_watcher;
+pub mod writer;
 use self::state::{State, PeerInfo};
+use self::queue::DropPolicy;
+use self::weights::RepWeights;
 
-use net::codec::MessageCodec;
-use net::{UdpFramed};
+use crate::net::{mdns, rebind, Transport};
+use crate::net::udp_framed::UdpTransport;
 
 use nano_lib_rs::message::{MessageBuilder, Message, MessageKind, MessagePayload, NetworkKind};
-use nano_lib_rs;
+use nano_lib_rs::network::NetworkParams;
+use nano_lib_rs::block::Block;
+use nano_lib_rs::keys::PublicKey;
 
-use tokio;
-use tokio::prelude::*;
-use tokio::net::{UdpSocket};
-use futures::{self, Future};
-use futures::sync::mpsc;
+use tokio::time::{self, Instant};
+use futures::{future, stream, SinkExt, Stream, StreamExt, TryStreamExt};
+use futures::channel::mpsc;
+use futures::future::BoxFuture;
+use futures::stream::{BoxStream, FuturesUnordered};
 
-use std::net::{SocketAddr, SocketAddrV6};
-use net2::UdpBuilder;
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
 use std::sync::{Arc};
 
-use tokio_timer::{Timer, TimerError};
 use std::time::{Duration};
 
 use indexmap::IndexMap;
 
-use error::*;
+use crate::error::*;
 
-use utils::{log_errors, to_ipv6};
+use crate::config::ConfigReloader;
+use crate::daemon::Shutdown;
+use crate::signer::{self, SignerConfig};
+use crate::utils::{account_to_address, forward, to_ipv6};
+use crate::work::{WorkPool, WorkConfig};
+#[cfg(feature = "rpc")]
+use crate::rpc;
+use crate::stats::{self, Direction, StatType};
+use crate::store::{self, StoreConfig};
+use crate::store::fast_sync::{self, SnapshotConfig};
+use self::pruner::PruningConfig;
+use self::voting::{Voting, VotingConfig, VotingStatus};
+use self::backup::BackupConfig;
+use self::exclusions::Exclusions;
+use self::webhook::WebhookConfig;
+use crate::systemd;
 
-const KEEPALIVE_INTERVAL: u64 = 60;
-const KEEPALIVE_CUTOFF: u64 = KEEPALIVE_INTERVAL * 5;
+pub const KEEPALIVE_INTERVAL: u64 = 60;
 
-const PEER_PRUNE_INTERVAL: u64 = KEEPALIVE_INTERVAL * 2;
+const WORK_WATCHER_INTERVAL: u64 = 5;
 
-fn process_messages<S>(network: NetworkKind, state: Arc<State>, stream: S) -> impl Stream<Item=(Message, SocketAddr), Error=Error>
-    where S: Stream<Item=(Message, SocketAddr), Error=Error>
+/// Number of outgoing messages which can be queued for the socket
+pub const SEND_QUEUE_SIZE: usize = 2048;
+
+/// Messages to send, each with the address to send it to
+pub type Messages = BoxStream<'static, Result<(Message, SocketAddr)>>;
+
+/// Yield once after `delay`, then once every `period`
+fn ticks(delay: Duration, period: Duration) -> impl Stream<Item=()> {
+    stream::unfold(time::interval_at(Instant::now() + delay, period), |mut interval| async move {
+        interval.tick().await;
+        Some(((), interval))
+    })
+}
+
+/// Handle the messages received on the socket at index `socket` of `State::sockets`
+fn process_messages<S>(network: NetworkKind, state: Arc<State>, stream: S, socket: usize)
+    -> impl Stream<Item=Result<(Message, SocketAddr)>>
+    where S: Stream<Item=Result<(Message, SocketAddr)>>
 {
-    stream.map(move |(msg, src_addr)| -> Box<Stream<Item=(Message, SocketAddr), Error=Error> + Send> {
+    stream.map_ok(move |(msg, src_addr)| -> Messages {
         if network == msg.header.network {
             let state = state.clone();
             let kind = msg.kind();
             let src_addr_v6 = to_ipv6(src_addr);
-            let _ = state.add_or_update_peer(src_addr_v6, true);
+            let span = debug_span!("message", kind = ?kind, peer = %src_addr);
+            let _enter = span.enter();
+            let _ = state.add_or_update_peer(src_addr_v6, socket, true);
+            state.stats.inc(StatType::Message, stats::message_detail(kind), Direction::In);
             debug!("Received message of kind: {:?} from {}", kind, src_addr);
             match kind {
                 MessageKind::KeepAlive => handler::keepalive(msg, src_addr_v6, state.clone()),
                 MessageKind::Publish => handler::publish(msg, src_addr_v6, state.clone()),
                 MessageKind::ConfirmReq => handler::confirm_req(msg, src_addr_v6, state.clone()),
-                _ => Box::new(stream::empty())
+                MessageKind::ConfirmAck => handler::confirm_ack(msg, src_addr_v6, state.clone()),
+                _ => stream::empty().boxed()
             }
         } else {
             debug!("Received message from {:?} network, ignoring...", msg.header.network);
-            Box::new(stream::empty())
+            stream::empty().boxed()
         }
     })
-    .flatten()
+    .try_flatten()
 }
 
-fn send_keepalives(state: Arc<State>, timer: &Timer) -> impl Stream<Item=(Message, SocketAddr), Error=Error> {
-    stream::once(Ok(()))
-        .chain(timer.interval(Duration::from_secs(KEEPALIVE_INTERVAL)))
-        .map(move |_| {
-            let state = state.clone();
+/// How the node keeps in touch with its peers
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeeringConfig {
+    /// Never expect inbound traffic which doesn't answer our own, as behind CGNAT, and
+    /// ask peers not to pass the node on to others
+    pub outbound_only: bool,
+    /// Peers which are sent keepalives and never pruned, even when they go quiet,
+    /// keeping a NAT mapping open to each of them
+    pub persistent: Vec<SocketAddrV6>,
+    pub keepalive_interval: Duration,
+    /// The only peers traffic is exchanged with, if the node is whitelist-only
+    pub whitelist: Option<Whitelist>,
+}
+
+/// The peers a whitelist-only node exchanges traffic with
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Whitelist {
+    /// Peers allowed on one port
+    pub addrs: Vec<SocketAddrV6>,
+    /// Hosts allowed on any port
+    pub hosts: Vec<Ipv6Addr>,
+}
+
+impl Whitelist {
+    pub fn allows(&self, peer: SocketAddrV6) -> bool {
+        self.hosts.contains(peer.ip())
+            || self.addrs.iter().any(|addr| addr.ip() == peer.ip() && addr.port() == peer.port())
+    }
+}
+
+impl PeeringConfig {
+    /// Whether traffic may be exchanged with `peer`
+    pub fn allows(&self, peer: SocketAddrV6) -> bool {
+        self.whitelist.as_ref().map_or(true, |whitelist| whitelist.allows(peer))
+    }
+
+    /// How long a peer may go unheard from before it is pruned
+    pub fn cutoff(&self) -> Duration {
+        self.keepalive_interval * 5
+    }
+
+    pub fn prune_interval(&self) -> Duration {
+        self.keepalive_interval * 2
+    }
+}
+
+impl Default for PeeringConfig {
+    fn default() -> Self {
+        PeeringConfig {
+            outbound_only: false,
+            persistent: Vec::new(),
+            keepalive_interval: Duration::from_secs(KEEPALIVE_INTERVAL),
+            whitelist: None,
+        }
+    }
+}
+
+/// Send keepalives to every peer every keepalive interval, and whenever a socket was
+/// bound again so that peers hear from the node's new socket
+fn send_keepalives(state: Arc<State>) -> impl Stream<Item=Result<(Message, SocketAddr)>> {
+    let rebound = stream::unfold(state.clone(), |state| async move {
+        state.rebind.wait_rebound().await;
+        Some(((), state))
+    });
+    stream::select(ticks(Duration::from_secs(0), state.peering.keepalive_interval), rebound)
+        .flat_map(move |_| {
             let count = state.peer_count();
             debug!("Sending keepalives to peers. Current peer count: {}", count);
-            let peers = state.peers.read().unwrap().clone();
+            let mut peers: Vec<SocketAddrV6> = state.peers.read().unwrap().keys().cloned().collect();
+            // Persistent peers which have gone quiet are kept trying
+            let quiet: Vec<SocketAddrV6> = state.peering.persistent.iter()
+                .filter(|addr| !peers.contains(addr))
+                .cloned()
+                .collect();
+            peers.extend(quiet);
             let inner_state = state.clone();
-            stream::iter_ok::<_, Error>(peers.into_iter()).map(move |(addr, _)| {
-                let send_peers = inner_state.random_peers(8);
-                let msg = MessageBuilder::new(MessageKind::KeepAlive)
-                    .with_payload(MessagePayload::KeepAlive(send_peers))
-                    .build();
-                (msg, SocketAddr::V6(addr))
-            })
+            stream::iter(peers.into_iter().map(move |addr| {
+                inner_state.keepalive_sent(addr);
+                Ok::<_, Error>((inner_state.keepalive(), SocketAddr::V6(addr)))
+            }))
         })
-        .flatten()
 }
 
-fn prune_peers(state: Arc<State>, timer: &Timer) -> impl Future<Item=(), Error=TimerError> {
-    timer.interval(Duration::from_secs(PEER_PRUNE_INTERVAL))
-        .for_each(move |_| {
-            let state = state.clone();
-            let count = state.prune_peers();
-            debug!("Pruned {} inactive peers. Current peer count: {}", count, state.peer_count());
-            futures::future::ok(())
+/// Publish `block` to every peer it wasn't published to recently
+fn publish_to_peers(mut block: Block, state: &State) -> impl Stream<Item=Result<(Message, SocketAddr)>> {
+    let mut peers: Vec<SocketAddrV6> = state.peers.read().unwrap().keys().cloned().collect();
+    if let Ok(hash) = block.hash(false) {
+        let (work, now) = (block.work_value().unwrap_or(0), ::std::time::Instant::now());
+        let count = peers.len();
+        peers.retain(|&peer| state.recent_publishes.should_publish(hash, work, peer, now));
+        let suppressed = (count - peers.len()) as u64;
+        if suppressed > 0 {
+            state.stats.add(StatType::Drop, "duplicate_publish", Direction::Out, suppressed);
+        }
+    }
+    let msg = MessageBuilder::new(MessageKind::Publish)
+        .with_network(state.network.kind)
+        .with_block_kind(block.kind)
+        .with_payload(MessagePayload::Publish(block))
+        .build();
+    stream::iter(peers.into_iter().map(move |addr| Ok::<_, Error>((msg.clone(), SocketAddr::V6(addr)))))
+}
+
+/// Publish the blocks queued on `State::republisher` to every peer
+fn republish_blocks(state: Arc<State>) -> impl Stream<Item=Result<(Message, SocketAddr)>> {
+    stream::unfold(state, |state| async move {
+        let blocks = state.republisher.next_blocks().await;
+        debug!("Republishing {} blocks", blocks.len());
+        let published: Vec<_> = blocks.into_iter().map(|block| publish_to_peers(block, &state)).collect();
+        Some((stream::iter(published).flatten(), state))
+    })
+    .flatten()
+}
+
+fn watch_work(state: Arc<State>) -> impl Stream<Item=Result<(Message, SocketAddr)>> {
+    let period = Duration::from_secs(WORK_WATCHER_INTERVAL);
+    ticks(period, period)
+        .map(move |_| {
+            let threshold = state.difficulty.active_difficulty();
+            let stale = state.work_watcher.stale_blocks(threshold);
+            if !stale.is_empty() {
+                debug!("Regenerating work for {} watched blocks at difficulty {:016x}", stale.len(), threshold);
+            }
+            let inner_state = state.clone();
+            let jobs: FuturesUnordered<_> = stale.into_iter().map(|mut block| {
+                let state = state.clone();
+                async move {
+                    let root = block.payload.as_ref().unwrap().work_source();
+                    let work = State::generate_work_async(state.clone(), root, threshold).await?;
+                    let hash = match block.hash(false) {
+                        Ok(hash) => hash,
+                        Err(_) => return Ok(None),
+                    };
+                    Ok::<_, Error>(state.work_watcher.update_work(&hash, work))
+                }
+            }).collect();
+            jobs.try_filter_map(future::ok)
+                .map_ok(move |block| publish_to_peers(block, &inner_state))
+                .try_flatten()
         })
+        .flatten()
+}
+
+async fn prune_peers(state: Arc<State>) {
+    let period = state.peering.prune_interval();
+    let mut interval = time::interval_at(Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        let count = state.prune_peers();
+        debug!("Pruned {} inactive peers. Current peer count: {}", count, state.peer_count());
+    }
+}
+
+/// Prune the ledger every `config.interval`, on a blocking thread
+async fn prune_ledger(state: Arc<State>, config: PruningConfig, batch_size: usize) {
+    let mut interval = time::interval_at(Instant::now() + config.interval, config.interval);
+    loop {
+        interval.tick().await;
+        let store = state.store.clone();
+        let pruned = tokio::task::spawn_blocking(move || pruner::prune(&*store, config.keep_depth, batch_size))
+            .await
+            .map_err(|_| Error::from("Pruning thread exited unexpectedly"));
+        match pruned {
+            Ok(Ok(summary)) => {
+                state.stats.add(StatType::Store, "blocks_pruned", Direction::Out, summary.pruned);
+                info!("Pruned {} blocks of {} accounts, keeping {} pending sends", summary.pruned,
+                    summary.accounts, summary.pending_kept);
+            }
+            Ok(Err(e)) | Err(e) => error!("Error pruning the ledger: {}", e),
+        }
+    }
+}
+
+/// Check every `config.check_interval` whether the store is synced closely enough
+/// with the network to vote
+async fn check_voting_sync(state: Arc<State>, config: VotingConfig) {
+    let mut interval = time::interval(config.check_interval);
+    loop {
+        interval.tick().await;
+        let (store, voting_config) = (state.store.clone(), config.clone());
+        let checked = tokio::task::spawn_blocking(move || -> Result<VotingStatus> {
+            let network_cemented = match voting::network_cemented(&voting_config) {
+                Ok(count) => count,
+                Err(e) => return Ok(VotingStatus::NoReference(e.to_string())),
+            };
+            let blocks = store.block_count()?;
+            Ok(if voting::is_synced(blocks, network_cemented, voting_config.sync_threshold) {
+                VotingStatus::Active
+            } else {
+                VotingStatus::Syncing { blocks, network_cemented }
+            })
+        }).await.map_err(|_| Error::from("Sync check thread exited unexpectedly"));
+        match checked {
+            Ok(Ok(status)) => state.voting.update(status),
+            Ok(Err(e)) | Err(e) => error!("Error checking sync for voting: {}", e),
+        }
+    }
+}
+
+/// What a node does beyond following the ledger
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeMode {
+    Full,
+    /// Processes blocks and serves RPC for queries, but never votes or handles keys
+    Observer,
+    /// Keeps only frontiers, recent blocks and pending sends, for machines with
+    /// little disk
+    Pruned,
+}
+
+impl NodeMode {
+    pub fn from_name(name: &str) -> Option<NodeMode> {
+        match name {
+            "full" => Some(NodeMode::Full),
+            "observer" => Some(NodeMode::Observer),
+            "pruned" => Some(NodeMode::Pruned),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            NodeMode::Full => "full",
+            NodeMode::Observer => "observer",
+            NodeMode::Pruned => "pruned",
+        }
+    }
 }
 
 pub struct NodeConfig {
+    pub mode: NodeMode,
     pub peers: Vec<SocketAddr>,
-    pub listen_addr: SocketAddr,
-    pub network: NetworkKind,
+    /// Addresses to listen for UDP traffic on, each with its own socket
+    pub listen_addrs: Vec<SocketAddr>,
+    /// The DSCP code point UDP datagrams are marked with, or 0 to leave them unmarked
+    pub dscp: u8,
+    /// Seeds the node's random choices, if set, so that runs can be repeated
+    pub seed: Option<u64>,
+    /// The name of the network the node advertises itself on over mDNS, if enabled
+    pub mdns: Option<String>,
+    pub peering: PeeringConfig,
+    pub network: NetworkParams,
+    /// Address to serve RPC requests on, if enabled
+    pub rpc_addr: Option<SocketAddr>,
+    /// Address to serve nano-work-server compatible work requests on, if enabled
+    pub work_server_addr: Option<SocketAddr>,
+    pub work: WorkConfig,
+    /// Threads processing received blocks
+    pub block_processor_threads: usize,
+    /// Threads checking the signatures of received blocks
+    pub signature_checker_threads: usize,
+    /// Which traffic is dropped first when processing queues fill up
+    pub drop_policy: DropPolicy,
+    pub store: StoreConfig,
+    /// When the node votes, if `node.enable_voting` is set
+    pub voting: Option<VotingConfig>,
+    /// How old blocks are pruned, in pruned mode
+    pub pruning: Option<PruningConfig>,
+    /// The snapshot an empty store is bootstrapped from, if configured
+    pub snapshot: Option<SnapshotConfig>,
+    /// The external service signing for the node, if configured
+    pub signer: Option<SignerConfig>,
+    /// Watched accounts handed out for payments over RPC
+    pub deposit_accounts: Vec<PublicKey>,
+    /// The webhook notified of sends to watched accounts, if configured
+    pub webhook: Option<WebhookConfig>,
+    /// When the ledger is backed up, if configured
+    pub backup: Option<BackupConfig>,
+}
+
+
+/// Reload the configuration whenever the process receives SIGHUP
+#[cfg(unix)]
+async fn reload_on_sighup(state: Arc<State>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!("Error listening for SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        info!("Received SIGHUP, reloading configuration");
+        if let Err(e) = state.reload_config() {
+            error!("Failed to reload configuration, keeping previous settings: {}", e);
+        }
+    }
 }
 
+type Server = BoxFuture<'static, ()>;
 
-pub fn run(config: NodeConfig, handle: &tokio::reactor::Handle) -> Result<impl Future<Item = (), Error = ()>> {
-    let socket_std = UdpBuilder::new_v6()?
-        .only_v6(false)?
-        .bind(&config.listen_addr)?;
-    let socket = UdpSocket::from_std(socket_std, handle)?;
+/// Bind the RPC and work servers, if enabled
+#[cfg(feature = "rpc")]
+fn bind_servers(rpc_addr: Option<SocketAddr>, work_server_addr: Option<SocketAddr>, state: Arc<State>)
+    -> Result<Vec<Server>>
+{
+    let mut servers: Vec<Server> = Vec::new();
+    if let Some(ref addr) = rpc_addr {
+        servers.push(Box::pin(rpc::serve(addr, state.clone())?));
+        if state.config.rpc().enable_key_derivation {
+            warn!("Key derivation is enabled over RPC: requests to {} may carry seeds and private keys", addr);
+            if !addr.ip().is_loopback() {
+                warn!("The RPC address {} is not a loopback address; anyone who can reach it can derive keys", addr);
+            }
+        }
+    }
+    if let Some(ref addr) = work_server_addr {
+        servers.push(Box::pin(rpc::serve_work(addr, state.clone())?));
+    }
+    Ok(servers)
+}
 
-    info!("Listening on: {}", socket.local_addr()?);
+#[cfg(not(feature = "rpc"))]
+fn bind_servers(rpc_addr: Option<SocketAddr>, work_server_addr: Option<SocketAddr>, _state: Arc<State>)
+    -> Result<Vec<Server>>
+{
+    if rpc_addr.is_some() || work_server_addr.is_some() {
+        warn!("RPC was enabled in the configuration, but nano-rs was built without the `rpc` feature");
+    }
+    Ok(Vec::new())
+}
+
+/// Ping the systemd watchdog from the event loop, so that a wedged node is restarted
+async fn feed_watchdog(interval: Duration) {
+    let mut ticks = time::interval(interval);
+    loop {
+        ticks.tick().await;
+        systemd::notify_or_warn("WATCHDOG=1");
+    }
+}
 
+/// Run the node on UDP sockets bound to its listen addresses until `shutdown` is
+/// triggered
+pub async fn run(config: NodeConfig, reloader: ConfigReloader, shutdown: Arc<Shutdown>) -> Result<()> {
+    let transport = UdpTransport::bind(&config.listen_addrs, config.dscp)?;
+    if config.dscp != 0 {
+        info!("Marking UDP datagrams with DSCP {}", config.dscp);
+    }
+    run_on(transport, config, reloader, shutdown, |_| ()).await
+}
+
+/// Run the node on `transport` until `shutdown` is triggered, passing its state to
+/// `started` once every task is running. Every task then stops, and the work already
+/// accepted is finished in the stages of `shutdown::run`.
+pub async fn run_on<T, F>(transport: T, config: NodeConfig, reloader: ConfigReloader, shutdown: Arc<Shutdown>, started: F)
+    -> Result<()>
+    where T: Transport, F: FnOnce(Arc<State>)
+{
+    let local_addrs = transport.local_addrs();
+    for local_addr in &local_addrs {
+        info!("Listening on: {}", local_addr);
+    }
+
+    let cache_paths = shutdown::cache_paths(&config.store.path);
+    let (cached_peers, cached_work) = shutdown::load_caches(&cache_paths);
+    if !cached_peers.is_empty() || !cached_work.is_empty() {
+        info!("Loaded {} peers and {} cached work values from the last run", cached_peers.len(), cached_work.len());
+    }
     let initial_peers: IndexMap<SocketAddrV6, PeerInfo> = config.peers.into_iter()
+        .map(to_ipv6)
+        .chain(config.peering.persistent.iter().cloned())
+        .chain(cached_peers)
         .map(|addr| {
-            (to_ipv6(addr), PeerInfo::default())
+            (addr, PeerInfo::default())
         }).collect();
 
-    let state = Arc::new(State::new(initial_peers));
-
-    let (sink, stream) = UdpFramed::new(socket, MessageCodec::new(), state.clone()).split();
-
-    let message_processor = process_messages(config.network, state.clone(), stream);
-
-    let timer = Timer::default();
-    let keepalive_handler = send_keepalives(state.clone(), &timer);
-    let peer_prune_handler = prune_peers(state.clone(), &timer);
-
-    let (sock_send, sock_recv) = mpsc::channel::<(nano_lib_rs::message::Message, SocketAddr)>(2048);
-    let process_send = sock_send.clone();
-    let keepalive_send = sock_send.clone();
-    
-    Ok(futures::future::lazy(||{
-        tokio::spawn(
-            process_send
-                .sink_map_err(|e| error!("Fatal error sending messages: {:?}", e))
-                .send_all(log_errors(message_processor)
-                    .map_err(|e| error!("Fatal error processing keepalives: {:?}", e)))
-                .map(|_| ())
-        );
-
-        tokio::spawn(
-            keepalive_send
-                .sink_map_err(|e| error!("Fatal sending keepalive: {:?}", e))
-                .send_all(log_errors(keepalive_handler)
-                    .map_err(|e| error!("Fatal error processing keepalives: {:?}", e)))
-                .map(|_| ())
-        );
-
-        tokio::spawn(
-            peer_prune_handler
-                .map_err(|e| error!("Error pruning peers: {}", e))
-        );
-
-        tokio::spawn(sink
-            .sink_map_err(|e| error!("Fatal error sending message: {:?}", e))
-            .send_all(sock_recv)
-            .map(|_| ()));
-
-        Ok(())
-    }))
-}
\ No newline at end of file
+    let exclusions = Exclusions::load(exclusions::path(&config.store.path))?;
+    let excluded = exclusions.list()?.len();
+    if excluded > 0 {
+        info!("Excluding {} peers", excluded);
+    }
+
+    let network = config.network.kind;
+    info!("Joining the {:?} network as a {} node", network, config.mode.name());
+    if config.peering.outbound_only {
+        info!("Outbound only, keeping {} persistent peers with keepalives every {}s", config.peering.persistent.len(),
+            config.peering.keepalive_interval.as_secs());
+    }
+    if let Some(ref snapshot) = config.snapshot {
+        if fast_sync::is_empty(&config.store)? {
+            let (snapshot, store, network) = (snapshot.clone(), config.store.clone(), config.network.clone());
+            tokio::task::spawn_blocking(move || fast_sync::fast_sync(&snapshot, &store, &network))
+                .await
+                .map_err(|_| Error::from("Fast sync thread exited unexpectedly"))??;
+        }
+    }
+    let store = store::open(&config.store)?;
+    let weights = RepWeights::load(&*store, &config.network.burn_accounts)?;
+    info!("Loaded the weights of {} representatives", weights.len());
+    let signer = match config.signer {
+        Some(ref signer) => {
+            let signer = signer::open(signer)?;
+            info!("Signing for {} with the {}", account_to_address(signer.account()), signer.name());
+            Some(signer)
+        }
+        None => None,
+    };
+    let work = WorkPool::new(config.work);
+    work.restore_cache(cached_work);
+    let voting = Voting::new(config.voting.is_some(), signer.as_ref().map(|signer| signer.account().clone()));
+    let state = Arc::new(State::new(config.network, config.mode, initial_peers, work, reloader,
+        shutdown.clone(), store, weights, signer, voting, config.peering, local_addrs, config.seed,
+        config.deposit_accounts, config.webhook, exclusions));
+    state.verifier.start(&state, config.signature_checker_threads, &config.drop_policy);
+    state.processor.start(&state, config.block_processor_threads, &config.drop_policy);
+    state.writer.start(&state, config.store.batch);
+
+    let servers = bind_servers(config.rpc_addr, config.work_server_addr, state.clone())?;
+
+    let keepalive_handler = send_keepalives(state.clone());
+    let work_watcher = watch_work(state.clone());
+    let republished = republish_blocks(state.clone());
+
+    let (sock_send, sock_recv) = mpsc::channel::<(Message, SocketAddr)>(SEND_QUEUE_SIZE);
+    let errors = state.errors.clone();
+
+    let channels = transport.open(&state);
+    let mut sinks = Vec::with_capacity(channels.len());
+    for (index, (sink, stream)) in channels.into_iter().enumerate() {
+        let message_processor = process_messages(network, state.clone(), stream, index);
+        tokio::spawn(shutdown.until(forward(message_processor, sock_send.clone(), errors.clone(), "message processing")));
+        sinks.push(sink);
+    }
+    tokio::spawn(shutdown.until(forward(keepalive_handler, sock_send.clone(), errors.clone(), "keepalives")));
+    tokio::spawn(shutdown.until(forward(work_watcher, sock_send.clone(), errors.clone(), "work watcher")));
+    if let Some(ref network) = config.mdns {
+        let discovered = mdns::discover(state.clone(), network)?;
+        tokio::spawn(shutdown.until(forward(discovered, sock_send.clone(), errors.clone(), "mDNS discovery")));
+    }
+    tokio::spawn(shutdown.until(forward(republished, sock_send, errors, "republishing")));
+    tokio::spawn(shutdown.until(prune_peers(state.clone())));
+    tokio::spawn(shutdown.until(rebind::watch_clock(state.clone())));
+    if let Some(pruning) = config.pruning {
+        tokio::spawn(shutdown.until(prune_ledger(state.clone(), pruning, config.store.batch.max_blocks)));
+    }
+    if let Some(backup) = config.backup {
+        tokio::spawn(shutdown.until(backup::schedule(state.clone(), backup)));
+    }
+    if let Some(voting) = config.voting {
+        tokio::spawn(shutdown.until(check_voting_sync(state.clone(), voting)));
+    }
+    if state.webhook.is_enabled() {
+        tokio::spawn(shutdown.until(webhook::deliver(state.clone(), webhook::queue_path(&config.store.path))));
+    }
+
+    for server in servers {
+        tokio::spawn(shutdown.until(server));
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(shutdown.until(reload_on_sighup(state.clone())));
+
+    if let Some(interval) = systemd::watchdog_interval() {
+        tokio::spawn(shutdown.until(feed_watchdog(interval)));
+    }
+
+    // Each socket sends the messages routed to it, ending once routing ends
+    let mut socket_sends = Vec::with_capacity(sinks.len());
+    let mut flushes = Vec::with_capacity(sinks.len());
+    for mut sink in sinks {
+        let (socket_send, socket_recv) = mpsc::channel::<(Message, SocketAddr)>(SEND_QUEUE_SIZE);
+        socket_sends.push(socket_send);
+        flushes.push(tokio::spawn(async move {
+            let mut sent = socket_recv.map(Ok::<_, Error>);
+            if let Err(e) = sink.send_all(&mut sent).await {
+                error!("Fatal error sending message: {:?}", e);
+            }
+        }));
+    }
+    // Ends once every sender above has been dropped, flushing queued messages first
+    let send_state = state.clone();
+    let routed = tokio::spawn(async move {
+        let mut sock_recv = sock_recv;
+        while let Some((msg, addr)) = sock_recv.next().await {
+            send_state.stats.inc(StatType::Message, stats::message_detail(msg.kind()), Direction::Out);
+            let (index, addr) = send_state.socket_for(to_ipv6(addr));
+            if socket_sends[index].send((msg, addr)).await.is_err() {
+                return;
+            }
+        }
+    });
+    let flushed = async move {
+        let _ = routed.await;
+        future::join_all(flushes).await;
+    };
+
+    started(state.clone());
+    systemd::notify_or_warn("READY=1");
+    shutdown.signal().await;
+    systemd::notify_or_warn("STOPPING=1");
+
+    shutdown::run(state, flushed, cache_paths).await
+}
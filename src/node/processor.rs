@@ -0,0 +1,237 @@
+//! Processing received blocks on a pool of worker threads.
+//!
+//! Blocks are routed to a worker by the chain they belong to, so blocks of one chain
+//! are processed in the order they arrived while different chains are processed in
+//! parallel. Blocks are routed when they are received, before their signatures are
+//! checked, so that the `SignatureVerifier` can keep chains in order too. Without a ledger the account of a legacy block isn't known, so chains are
+//! identified by the account of open and state blocks, and otherwise by following
+//! `previous` back to a block which was routed earlier. A legacy block whose
+//! predecessor hasn't been seen starts a chain of its own.
+//!
+//! Blocks with sufficient work which aren't already stored, or pruned, are passed to
+//! the `BlockWriter` to be written in the next batch.
+use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddrV6;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+
+use nano_lib_rs::block::{Block, BlockHash, BlockPayload};
+
+use crate::stats::{Direction, StatType};
+use super::queue::{class_queue, ClassQueue, ClassReceiver, DropPolicy, DropReason, TrafficClass};
+use super::state::State;
+
+/// Blocks which can be queued for each worker before further blocks are dropped
+pub const BLOCK_QUEUE_SIZE: usize = 1024;
+
+/// Number of recently routed blocks whose chain is remembered
+const MAX_ROUTED_BLOCKS: usize = 64 * 1024;
+
+/// Identifies the chain a block was routed to
+pub type ChainKey = [u8; 32];
+
+struct Job {
+    block: Block,
+    hash: BlockHash,
+    peer: SocketAddrV6,
+}
+
+/// Remembers the chain recently routed blocks belong to
+#[derive(Debug, Default)]
+struct ChainRouter {
+    chains: HashMap<BlockHash, ChainKey>,
+    order: VecDeque<BlockHash>,
+}
+
+impl ChainRouter {
+    /// The chain of `block`, whose hash is `hash`
+    fn route(&mut self, hash: BlockHash, block: &Block) -> ChainKey {
+        let key = match block.payload {
+            Some(BlockPayload::Open { ref account, .. }) => *account.as_bytes(),
+            Some(BlockPayload::State { ref account, ref previous, .. }) => {
+                self.chains.get(previous).cloned().unwrap_or(*account.as_bytes())
+            }
+            Some(BlockPayload::Send { ref previous, .. })
+            | Some(BlockPayload::Receive { ref previous, .. })
+            | Some(BlockPayload::Change { ref previous, .. }) => {
+                self.chains.get(previous).cloned().unwrap_or(*previous.as_bytes())
+            }
+            None => *hash.as_bytes(),
+        };
+        if self.chains.insert(hash, key).is_none() {
+            self.order.push_back(hash);
+            if self.order.len() > MAX_ROUTED_BLOCKS {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.chains.remove(&oldest);
+                }
+            }
+        }
+        key
+    }
+}
+
+/// The worker of `workers` which handles the chain `key`
+pub fn worker_index(key: &ChainKey, workers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % workers as u64) as usize
+}
+
+/// Record the difficulty of `block`'s work and whether it meets the network threshold
+fn check_work(block: &Block, state: &State) -> bool {
+    let span = trace_span!("work_check");
+    let _enter = span.enter();
+    let difficulty = block.work_value().ok();
+    if let Some(difficulty) = difficulty {
+        state.difficulty.observe(difficulty);
+    }
+    difficulty.map_or(false, |d| state.network.work_sufficient(d))
+}
+
+fn process(job: Job, state: &State) {
+    let hash = String::from(job.hash);
+    let span = debug_span!("block", hash = %hash, peer = %job.peer);
+    let _enter = span.enter();
+    let sufficient = check_work(&job.block, state);
+    state.stats.inc(StatType::Block, if sufficient { "valid_work" } else { "insufficient_work" }, Direction::In);
+    let valid = if sufficient { "valid" } else { "INVALID" };
+    info!("Got {:?} block with hash {}. Work {}.", job.block.kind, hash, valid);
+    if !sufficient {
+        return;
+    }
+    let known = state.store.block_exists(&job.hash)
+        .and_then(|exists| Ok(exists || state.store.is_pruned(&job.hash)?));
+    match known {
+        Ok(true) => state.stats.inc(StatType::Block, "old", Direction::In),
+        Ok(false) => {
+            if !state.writer.write(job.hash, job.block) {
+                warn!("Block writer isn't running, not storing block {}", hash);
+            }
+        }
+        Err(e) => error!("Error looking up block {} in the store: {}", hash, e),
+    }
+}
+
+fn run_worker(state: Weak<State>, jobs: ClassReceiver<Job>) {
+    for job in jobs {
+        match state.upgrade() {
+            Some(state) => process(job, &state),
+            None => return,
+        }
+    }
+}
+
+/// Processes blocks on worker threads, keeping the blocks of each chain in order
+pub struct BlockProcessor {
+    router: Mutex<ChainRouter>,
+    workers: Mutex<Vec<ClassQueue<Job>>>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl fmt::Debug for BlockProcessor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BlockProcessor")
+            .field("workers", &self.workers.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl BlockProcessor {
+    /// Create a processor without workers; blocks are dropped until it is started
+    pub fn new() -> Self {
+        BlockProcessor {
+            router: Mutex::new(ChainRouter::default()),
+            workers: Mutex::new(Vec::new()),
+            threads: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Start `threads` workers processing blocks for `state`, whose queues are shed
+    /// according to `policy`
+    pub fn start(&self, state: &Arc<State>, threads: usize, policy: &DropPolicy) {
+        let mut workers = self.workers.lock().unwrap();
+        let mut handles = self.threads.lock().unwrap();
+        for i in 0..threads.max(1) {
+            let (tx, rx) = class_queue(BLOCK_QUEUE_SIZE, policy);
+            let state = Arc::downgrade(state);
+            let handle = thread::Builder::new()
+                .name(format!("block processor {}", i))
+                .spawn(move || run_worker(state, rx));
+            match handle {
+                Ok(handle) => {
+                    workers.push(tx);
+                    handles.push(handle);
+                }
+                Err(e) => error!("Could not start block processor thread: {}", e),
+            }
+        }
+        debug!("Started {} block processor threads", workers.len());
+    }
+
+    /// The chain of `block`, whose hash is `hash`. Blocks must be routed in the order
+    /// they were received.
+    pub fn route(&self, hash: BlockHash, block: &Block) -> ChainKey {
+        self.router.lock().unwrap().route(hash, block)
+    }
+
+    /// Queue `block` of `class`, received from `peer`, on the worker for the chain `key`,
+    /// unless that worker's queue is too full for the class
+    pub fn submit(&self, class: TrafficClass, key: ChainKey, block: Block, hash: BlockHash, peer: SocketAddrV6)
+        -> ::std::result::Result<(), DropReason>
+    {
+        let workers = self.workers.lock().unwrap();
+        if workers.is_empty() {
+            return Err(DropReason::QueueFull);
+        }
+        workers[worker_index(&key, workers.len())].push(class, Job { block, hash, peer })
+    }
+
+    /// The number of blocks waiting to be processed
+    pub fn queued(&self) -> usize {
+        self.workers.lock().unwrap().iter().map(ClassQueue::len).sum()
+    }
+
+    /// Stop the workers once they have processed the blocks already queued
+    pub fn stop(&self) {
+        self.workers.lock().unwrap().clear();
+        for handle in self.threads.lock().unwrap().drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nano_lib_rs::block::BlockKind;
+    use nano_lib_rs::keys::PublicKey;
+
+    #[test]
+    fn routes_chains_together() {
+        let mut router = ChainRouter::default();
+        let account = PublicKey::from_bytes(&[1u8; 32]).unwrap();
+        let open_hash = BlockHash::from_bytes(&[2u8; 32]).unwrap();
+        let open = Block::new(BlockKind::Open, Some(BlockPayload::Open {
+            source: BlockHash::from_bytes(&[3u8; 32]).unwrap(),
+            representative: account.clone(),
+            account: account.clone(),
+        }), None, None);
+        let change_hash = BlockHash::from_bytes(&[4u8; 32]).unwrap();
+        let change = Block::new(BlockKind::Change, Some(BlockPayload::Change {
+            previous: open_hash,
+            representative: account.clone(),
+        }), None, None);
+        let unrelated = Block::new(BlockKind::Change, Some(BlockPayload::Change {
+            previous: BlockHash::from_bytes(&[5u8; 32]).unwrap(),
+            representative: account.clone(),
+        }), None, None);
+
+        let key = router.route(open_hash, &open);
+        assert_eq!(key, *account.as_bytes());
+        assert_eq!(router.route(change_hash, &change), key);
+        assert_ne!(router.route(BlockHash::from_bytes(&[6u8; 32]).unwrap(), &unrelated), key);
+    }
+}
@@ -0,0 +1,204 @@
+//! Pruning old blocks from the ledger of a pruned node, for machines with little disk.
+//!
+//! Every `PruningConfig::interval`, the chain of each account is walked back from its
+//! frontier until a pruned block, and the blocks more than `keep_depth` blocks behind
+//! the frontier are pruned: deleted from the store, which remembers their hashes so
+//! that RPC actions can report them as pruned rather than unknown. Frontiers, account
+//! records and weights are always kept.
+//!
+//! Send blocks are kept until a block receiving them is seen in a pass, so that the
+//! sends still pending for their destination stay available. Whether a state block is
+//! a send depends on the balance of the block before it, so a state block whose
+//! predecessor is missing, or a legacy block without a balance, is kept as if it were
+//! a pending send.
+//!
+//! Pruned blocks are never fetched again; a node which needs more history needs a
+//! full ledger.
+use std::collections::HashSet;
+use std::time::Duration;
+
+use nano_lib_rs::block::{Block, BlockHash, BlockPayload};
+
+use crate::error::*;
+use crate::store::{Store, WriteBatch};
+use crate::store::export::previous;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PruningConfig {
+    /// Blocks behind each frontier which are kept
+    pub keep_depth: u64,
+    /// How often the ledger is pruned
+    pub interval: Duration,
+}
+
+/// The outcome of a pruning pass
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PruneSummary {
+    pub accounts: u64,
+    pub pruned: u64,
+    /// Blocks old enough to be pruned which were kept as pending sends
+    pub pending_kept: u64,
+}
+
+/// The balance of the account after `block`, if the block carries it
+fn balance(block: &Block) -> Option<u128> {
+    match block.payload {
+        Some(BlockPayload::Send { balance, .. }) | Some(BlockPayload::State { balance, .. }) => Some(balance),
+        _ => None,
+    }
+}
+
+/// Whether `block` may be a send, given the block before it in its chain, if any is
+/// stored. A block is only ruled out when it is known not to be a send.
+fn may_be_send(block: &Block, previous: Option<&Block>) -> bool {
+    match block.payload {
+        Some(BlockPayload::Send { .. }) => true,
+        Some(BlockPayload::State { balance: after, previous: ref hash, .. }) => {
+            if hash.as_bytes() == &[0u8; 32] {
+                return false;
+            }
+            match previous.and_then(balance) {
+                Some(before) => after < before,
+                None => true,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// Add the hash of the block `block` receives, if it may be a receive, to `received`
+fn note_received(block: &Block, received: &mut HashSet<[u8; 32]>) {
+    match block.payload {
+        Some(BlockPayload::Receive { source, .. }) | Some(BlockPayload::Open { source, .. }) => {
+            received.insert(*source.as_bytes());
+        }
+        // The links of sends are accounts, which never collide with block hashes
+        Some(BlockPayload::State { ref link, .. }) => {
+            received.insert(*link.as_bytes());
+        }
+        _ => (),
+    }
+}
+
+/// Prune the blocks of `store` more than `keep_depth` blocks behind their frontier,
+/// writing batches of at most `batch_size` blocks
+pub fn prune(store: &dyn Store, keep_depth: u64, batch_size: usize) -> Result<PruneSummary> {
+    let mut summary = PruneSummary::default();
+    let mut heads = Vec::new();
+    store.for_each_account(&mut |_, info| {
+        heads.push(info.head);
+        Ok(())
+    })?;
+    summary.accounts = heads.len() as u64;
+
+    let mut batch = WriteBatch::new();
+    let mut received = HashSet::new();
+    let mut sends: Vec<BlockHash> = Vec::new();
+    for head in heads {
+        let mut current = match store.block(&head)? {
+            Some(block) => (head, block),
+            None => continue,
+        };
+        let mut depth = 0;
+        loop {
+            let (hash, block) = current;
+            let before = match previous(&block) {
+                Some(previous) => store.block(&previous)?.map(|block| (previous, block)),
+                None => None,
+            };
+            note_received(&block, &mut received);
+            if depth >= keep_depth {
+                if may_be_send(&block, before.as_ref().map(|&(_, ref block)| block)) {
+                    sends.push(hash);
+                } else {
+                    batch.prune_block(hash);
+                    summary.pruned += 1;
+                    if batch.pruned().len() >= batch_size.max(1) {
+                        store.write(&batch)?;
+                        batch.clear();
+                    }
+                }
+            }
+            depth += 1;
+            match before {
+                Some(before) => current = before,
+                None => break,
+            }
+        }
+    }
+    for hash in sends {
+        if received.contains(hash.as_bytes()) {
+            batch.prune_block(hash);
+            summary.pruned += 1;
+        } else {
+            summary.pending_kept += 1;
+        }
+    }
+    store.write(&batch)?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nano_lib_rs::block::{BlockKind, Link};
+    use nano_lib_rs::keys::PublicKey;
+    use crate::store::AccountInfo;
+    use crate::store::memory::MemoryStore;
+
+    fn state_block(account: u8, previous: BlockHash, balance: u128, link: [u8; 32]) -> (BlockHash, Block) {
+        let mut block = Block::new(BlockKind::State, Some(BlockPayload::State {
+            account: PublicKey::from_bytes(&[account; 32]).unwrap(),
+            previous,
+            representative: PublicKey::from_bytes(&[account; 32]).unwrap(),
+            balance,
+            link: Link::Unknown(link),
+        }), None, None);
+        (block.hash(false).unwrap(), block)
+    }
+
+    /// Write `blocks` as the chain of `account`, returning their hashes
+    fn chain(store: &MemoryStore, account: u8, blocks: &[(u128, [u8; 32])]) -> Vec<BlockHash> {
+        let mut batch = WriteBatch::new();
+        let mut previous = BlockHash::from_bytes(&[0u8; 32]).unwrap();
+        let mut hashes = Vec::new();
+        for &(balance, link) in blocks {
+            let (hash, block) = state_block(account, previous, balance, link);
+            batch.put_block(hash, block);
+            hashes.push(hash);
+            previous = hash;
+        }
+        batch.put_account(PublicKey::from_bytes(&[account; 32]).unwrap(), AccountInfo {
+            head: previous,
+            representative: PublicKey::from_bytes(&[account; 32]).unwrap(),
+            balance: blocks[blocks.len() - 1].0,
+            confirmation_height: 0,
+        });
+        store.write(&batch).unwrap();
+        hashes
+    }
+
+    #[test]
+    fn keeps_recent_blocks_and_pending_sends() {
+        let store = MemoryStore::new();
+        // Opened by receiving 10, then sends of 1 and 2, a receive, and a change
+        let first = chain(&store, 1, &[(10, [7u8; 32]), (9, [2u8; 32]), (7, [3u8; 32]), (8, [8u8; 32]),
+            (8, [0u8; 32])]);
+        // Receives only the first send
+        let second = chain(&store, 2, &[(1, *first[1].as_bytes())]);
+
+        let summary = prune(&store, 2, 2).unwrap();
+        assert_eq!(summary, PruneSummary { accounts: 2, pruned: 2, pending_kept: 1 });
+        assert!(store.is_pruned(&first[0]).unwrap());
+        assert!(store.is_pruned(&first[1]).unwrap());
+        assert!(store.block_exists(&first[2]).unwrap());
+        assert!(!store.is_pruned(&first[2]).unwrap());
+        for hash in first[3..].iter().chain(second.iter()) {
+            assert!(store.block_exists(hash).unwrap());
+        }
+
+        // The pending send has no stored predecessor any more, and is still kept
+        let summary = prune(&store, 2, 2).unwrap();
+        assert_eq!(summary, PruneSummary { accounts: 2, pruned: 0, pending_kept: 1 });
+    }
+}
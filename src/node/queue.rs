@@ -0,0 +1,194 @@
+//! Bounded queues which shed traffic by class as they fill up.
+//!
+//! Rather than dropping whatever arrives once a queue is full, each class of traffic
+//! may only fill a queue up to a limit set by its place in the `DropPolicy`: with n
+//! classes, the class shed first may fill 1/n of the queue, the next 2/n, and the
+//! class shed last the whole queue. By default republished blocks are shed first and
+//! blocks carried by votes last, so that confirmations keep flowing during a flood.
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+
+/// The kind of traffic a queued block arrived in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TrafficClass {
+    /// A publish of a block which was seen before
+    Republish,
+    /// A publish of a new block
+    Publish,
+    ConfirmReq,
+    ConfirmAck,
+}
+
+impl TrafficClass {
+    pub const ALL: [TrafficClass; 4] = [
+        TrafficClass::Republish,
+        TrafficClass::Publish,
+        TrafficClass::ConfirmReq,
+        TrafficClass::ConfirmAck,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            TrafficClass::Republish => "republish",
+            TrafficClass::Publish => "publish",
+            TrafficClass::ConfirmReq => "confirm_req",
+            TrafficClass::ConfirmAck => "confirm_ack",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<TrafficClass> {
+        TrafficClass::ALL.iter().cloned().find(|class| class.name() == name)
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// Why a block wasn't queued
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropReason {
+    /// The queue was filled past the limit of the block's class
+    Shed,
+    /// The queue was completely full, or its worker has stopped
+    QueueFull,
+}
+
+impl DropReason {
+    /// The stats detail counting blocks of `class` dropped for this reason
+    pub fn detail(&self, class: TrafficClass) -> &'static str {
+        match (*self, class) {
+            (DropReason::Shed, TrafficClass::Republish) => "republish_shed",
+            (DropReason::Shed, TrafficClass::Publish) => "publish_shed",
+            (DropReason::Shed, TrafficClass::ConfirmReq) => "confirm_req_shed",
+            (DropReason::Shed, TrafficClass::ConfirmAck) => "confirm_ack_shed",
+            (DropReason::QueueFull, TrafficClass::Republish) => "republish_queue_full",
+            (DropReason::QueueFull, TrafficClass::Publish) => "publish_queue_full",
+            (DropReason::QueueFull, TrafficClass::ConfirmReq) => "confirm_req_queue_full",
+            (DropReason::QueueFull, TrafficClass::ConfirmAck) => "confirm_ack_queue_full",
+        }
+    }
+}
+
+/// The order in which classes of traffic are shed, first to last
+#[derive(Clone, Debug, PartialEq)]
+pub struct DropPolicy {
+    order: Vec<TrafficClass>,
+}
+
+impl DropPolicy {
+    /// A policy shedding the classes in `order` first. Classes which aren't listed are
+    /// shed last.
+    pub fn new(order: Vec<TrafficClass>) -> Self {
+        let mut order = order;
+        for class in TrafficClass::ALL.iter() {
+            if !order.contains(class) {
+                order.push(*class);
+            }
+        }
+        DropPolicy { order }
+    }
+
+    /// How much of a queue which holds `capacity` blocks `class` may fill
+    pub fn limit(&self, class: TrafficClass, capacity: usize) -> usize {
+        let rank = self.order.iter().position(|&c| c == class).unwrap_or(self.order.len() - 1) + 1;
+        (capacity * rank / self.order.len()).max(1)
+    }
+}
+
+impl Default for DropPolicy {
+    fn default() -> Self {
+        DropPolicy::new(TrafficClass::ALL.to_vec())
+    }
+}
+
+/// The sending half of a queue feeding a worker thread
+#[derive(Debug)]
+pub struct ClassQueue<T> {
+    sender: SyncSender<T>,
+    depth: Arc<AtomicUsize>,
+    limits: [usize; 4],
+}
+
+/// The receiving half of a `ClassQueue`
+#[derive(Debug)]
+pub struct ClassReceiver<T> {
+    receiver: Receiver<T>,
+    depth: Arc<AtomicUsize>,
+}
+
+/// Create a queue holding up to `capacity` items, shed according to `policy`
+pub fn class_queue<T>(capacity: usize, policy: &DropPolicy) -> (ClassQueue<T>, ClassReceiver<T>) {
+    let (sender, receiver) = mpsc::sync_channel(capacity);
+    let depth = Arc::new(AtomicUsize::new(0));
+    let mut limits = [capacity; 4];
+    for class in TrafficClass::ALL.iter() {
+        limits[class.index()] = policy.limit(*class, capacity);
+    }
+    (ClassQueue { sender, depth: depth.clone(), limits }, ClassReceiver { receiver, depth })
+}
+
+impl<T> ClassQueue<T> {
+    /// Queue `item` of `class`, unless the queue is filled past the class' limit
+    pub fn push(&self, class: TrafficClass, item: T) -> ::std::result::Result<(), DropReason> {
+        if self.depth.load(Ordering::Relaxed) >= self.limits[class.index()] {
+            return Err(DropReason::Shed);
+        }
+        self.depth.fetch_add(1, Ordering::Relaxed);
+        self.sender.try_send(item).map_err(|_| {
+            self.depth.fetch_sub(1, Ordering::Relaxed);
+            DropReason::QueueFull
+        })
+    }
+
+    /// The number of queued items
+    pub fn len(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Iterator for ClassReceiver<T> {
+    type Item = T;
+
+    /// Wait for the next item, ending once every sender has been dropped
+    fn next(&mut self) -> Option<T> {
+        let item = self.receiver.recv().ok()?;
+        self.depth.fetch_sub(1, Ordering::Relaxed);
+        Some(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sheds_classes_in_order() {
+        let (queue, mut receiver) = class_queue(8, &DropPolicy::default());
+        for i in 0..2 {
+            assert_eq!(queue.push(TrafficClass::Republish, i), Ok(()));
+        }
+        assert_eq!(queue.push(TrafficClass::Republish, 2), Err(DropReason::Shed));
+        assert_eq!(queue.push(TrafficClass::Publish, 2), Ok(()));
+        for i in 3..8 {
+            assert_eq!(queue.push(TrafficClass::ConfirmAck, i), Ok(()));
+        }
+        assert_eq!(queue.push(TrafficClass::ConfirmReq, 8), Err(DropReason::Shed));
+        assert_eq!(queue.push(TrafficClass::ConfirmAck, 8), Err(DropReason::Shed));
+        assert_eq!(queue.len(), 8);
+
+        assert_eq!(receiver.next(), Some(0));
+        assert_eq!(queue.push(TrafficClass::ConfirmAck, 8), Ok(()));
+        drop(queue);
+        assert_eq!(receiver.collect::<Vec<_>>(), (1..9).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn unlisted_classes_are_shed_last() {
+        let policy = DropPolicy::new(vec![TrafficClass::ConfirmAck]);
+        assert_eq!(policy.limit(TrafficClass::ConfirmAck, 100), 25);
+        assert_eq!(policy.limit(TrafficClass::Republish, 100), 50);
+        assert_eq!(policy.limit(TrafficClass::ConfirmReq, 100), 100);
+    }
+}
@@ -0,0 +1,198 @@
+//! Rebroadcasting stored blocks to peers, for local blocks which failed to propagate.
+//!
+//! The store doesn't index the successors of blocks, so the part of a chain to
+//! republish is found by following it back from its account's latest block to the
+//! requested one. Blocks are republished oldest first, so that peers can process each
+//! after its predecessor.
+//!
+//! However a block comes to be published, it isn't sent again to a peer it was sent
+//! to in the last `PUBLISH_WINDOW`, unless its work changed, so that a saturated node
+//! doesn't spend its bandwidth repeating itself.
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddrV6;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tokio::sync::Notify;
+
+use nano_lib_rs::block::{Block, BlockHash};
+
+use crate::error::*;
+use crate::store::Store;
+use crate::store::export::{self, previous};
+
+/// Blocks waiting to be published to every peer
+#[derive(Debug)]
+pub struct Republisher {
+    queue: Mutex<Vec<Block>>,
+    queued: Notify,
+}
+
+impl Republisher {
+    pub fn new() -> Self {
+        Republisher {
+            queue: Mutex::new(Vec::new()),
+            queued: Notify::new(),
+        }
+    }
+
+    /// Queue `blocks` to be published, in order
+    pub fn republish(&self, blocks: Vec<Block>) {
+        if blocks.is_empty() {
+            return;
+        }
+        self.queue.lock().unwrap().extend(blocks);
+        self.queued.notify_one();
+    }
+
+    /// The blocks queued since the last call, once there are any
+    pub async fn next_blocks(&self) -> Vec<Block> {
+        loop {
+            let blocks: Vec<Block> = self.queue.lock().unwrap().drain(..).collect();
+            if !blocks.is_empty() {
+                return blocks;
+            }
+            self.queued.notified().await;
+        }
+    }
+}
+
+/// How long a block isn't published again to a peer it was published to
+pub const PUBLISH_WINDOW: Duration = Duration::from_secs(10);
+
+/// Publishes remembered at most, forgetting the oldest first
+const MAX_RECENT_PUBLISHES: usize = 65_536;
+
+/// A block, by its hash and work value, published to a peer
+type Publish = (BlockHash, u64, SocketAddrV6);
+
+/// When blocks were last published to each peer, within `PUBLISH_WINDOW`
+#[derive(Debug, Default)]
+pub struct RecentPublishes {
+    inner: Mutex<(HashMap<Publish, Instant>, VecDeque<(Publish, Instant)>)>,
+}
+
+impl RecentPublishes {
+    pub fn new() -> Self {
+        RecentPublishes::default()
+    }
+
+    /// Whether the block `hash` with a work value of `work` should be published to
+    /// `peer` at `now`, remembering that it was if so
+    pub fn should_publish(&self, hash: BlockHash, work: u64, peer: SocketAddrV6, now: Instant) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let (ref mut times, ref mut order) = *inner;
+        while let Some(&(publish, at)) = order.front() {
+            if now.duration_since(at) < PUBLISH_WINDOW && order.len() < MAX_RECENT_PUBLISHES {
+                break;
+            }
+            order.pop_front();
+            if times.get(&publish) == Some(&at) {
+                times.remove(&publish);
+            }
+        }
+        let publish = (hash, work, peer);
+        if times.contains_key(&publish) {
+            return false;
+        }
+        times.insert(publish, now);
+        order.push_back((publish, now));
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().0.len()
+    }
+}
+
+/// The stored block `hash` and at most `count - 1` of its successors, oldest first
+pub fn chain_from(store: &dyn Store, hash: &BlockHash, count: usize) -> Result<Vec<(BlockHash, Block)>> {
+    let block = match store.block(hash)? {
+        Some(block) => block,
+        None => return Err(ErrorKind::RpcError("Block not found".into()).into()),
+    };
+    let head = match export::chain_account(store, &block)? {
+        Some(account) => store.account(&account)?.map(|info| info.head),
+        None => None,
+    };
+    let mut head = match head {
+        Some(head) => head,
+        None => return Ok(vec![(*hash, block)]),
+    };
+    let mut successors = Vec::new();
+    while head != *hash {
+        let current = match store.block(&head)? {
+            Some(current) => current,
+            None => break,
+        };
+        let predecessor = previous(&current);
+        successors.push((head, current));
+        head = match predecessor {
+            Some(predecessor) => predecessor,
+            None => break,
+        };
+    }
+    if head != *hash {
+        // The block isn't on its account's stored chain, as with a fork which lost
+        successors.clear();
+    }
+    successors.push((*hash, block));
+    Ok(successors.into_iter().rev().take(count).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nano_lib_rs::block::{BlockKind, BlockPayload, Link};
+    use nano_lib_rs::keys::PublicKey;
+    use crate::store::{AccountInfo, WriteBatch};
+    use crate::store::memory::MemoryStore;
+
+    #[test]
+    fn suppresses_recent_publishes() {
+        let recent = RecentPublishes::new();
+        let hash = BlockHash::from_bytes(&[1u8; 32]).unwrap();
+        let (peer, other): (SocketAddrV6, SocketAddrV6) = ("[::1]:7075".parse().unwrap(), "[::2]:7075".parse().unwrap());
+        let now = Instant::now();
+        assert!(recent.should_publish(hash, 5, peer, now));
+        assert!(!recent.should_publish(hash, 5, peer, now + Duration::from_secs(1)));
+        assert!(recent.should_publish(hash, 5, other, now));
+        // Regenerated work is published again
+        assert!(recent.should_publish(hash, 6, peer, now));
+        assert!(recent.should_publish(hash, 5, peer, now + PUBLISH_WINDOW));
+        assert_eq!(recent.len(), 1);
+    }
+
+    #[test]
+    fn follows_chains_forward() {
+        let store = MemoryStore::new();
+        let account = PublicKey::from_bytes(&[1u8; 32]).unwrap();
+        let mut batch = WriteBatch::new();
+        let mut previous = BlockHash::from_bytes(&[0u8; 32]).unwrap();
+        let mut hashes = Vec::new();
+        for balance in (1..5u128).rev() {
+            let mut block = Block::new(BlockKind::State, Some(BlockPayload::State {
+                account: account.clone(),
+                previous,
+                representative: account.clone(),
+                balance,
+                link: Link::Unknown([balance as u8; 32]),
+            }), None, None);
+            previous = block.hash(false).unwrap();
+            batch.put_block(previous, block);
+            hashes.push(previous);
+        }
+        batch.put_account(account.clone(), AccountInfo {
+            head: previous,
+            representative: account.clone(),
+            balance: 1,
+            confirmation_height: 0,
+        });
+        store.write(&batch).unwrap();
+
+        let chain: Vec<BlockHash> = chain_from(&store, &hashes[1], 2).unwrap().into_iter().map(|(hash, _)| hash).collect();
+        assert_eq!(chain, &hashes[1..3]);
+        assert_eq!(chain_from(&store, &hashes[1], 10).unwrap().len(), 3);
+        assert!(chain_from(&store, &BlockHash::from_bytes(&[9u8; 32]).unwrap(), 10).is_err());
+    }
+}
@@ -0,0 +1,358 @@
+//! Rolling back blocks, to recover test network nodes from bad forks they accepted.
+//!
+//! Rolling back a block removes it and every later block of its account's chain,
+//! newest first. A send which has been received takes the receiving block, and the
+//! blocks after it, with it. Rolling back a receive gives the destination its pending
+//! entry back, and rolling back a send deletes its entry.
+//! Account records and representative weights are set back to those of the account's
+//! new latest block, and an account whose first block is rolled back loses its record.
+//!
+//! Cemented blocks are never rolled back. A legacy receive or open doesn't carry its
+//! account's balance, so an account can't be rolled back to one, nor can the receive of
+//! a legacy send which follows one, as the amount sent isn't known. Every change is
+//! made in one batch, or with a dry run, only listed.
+use std::collections::HashSet;
+
+use nano_lib_rs::block::{Block, BlockHash, BlockPayload};
+use nano_lib_rs::keys::PublicKey;
+
+use crate::error::*;
+use crate::store::{AccountInfo, PendingInfo, Store, WriteBatch};
+use crate::store::export::{chain_account, previous};
+use super::weights::RepWeights;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RollbackSummary {
+    /// The blocks rolled back, in the order they were
+    pub blocks: Vec<BlockHash>,
+}
+
+/// The changes of a rollback, read through in place of the store
+struct Rollback<'a> {
+    store: &'a dyn Store,
+    weights: RepWeights,
+    batch: WriteBatch,
+    removed: HashSet<BlockHash>,
+    deleted_accounts: HashSet<[u8; 32]>,
+    summary: RollbackSummary,
+}
+
+impl<'a> Rollback<'a> {
+    fn block(&self, hash: &BlockHash) -> Result<Block> {
+        let block = if self.removed.contains(hash) { None } else { self.store.block(hash)? };
+        block.ok_or_else(|| Error::from(format!("Block {} is not stored", String::from(*hash))))
+    }
+
+    fn account(&self, account: &PublicKey) -> Result<Option<AccountInfo>> {
+        if self.deleted_accounts.contains(account.as_bytes()) {
+            return Ok(None);
+        }
+        match self.batch.account(account) {
+            Some(info) => Ok(Some(info.clone())),
+            None => self.store.account(account),
+        }
+    }
+
+    fn is_pending(&self, account: &PublicKey, hash: &BlockHash) -> Result<bool> {
+        let matches = |&(ref key, ref entry): &(PublicKey, BlockHash)| key == account && entry == hash;
+        if self.batch.received().iter().any(matches) {
+            return Ok(false);
+        }
+        if self.batch.pending().iter().any(|&(ref key, ref entry, _)| key == account && entry == hash) {
+            return Ok(true);
+        }
+        Ok(self.store.pending(account)?.iter().any(|&(entry, _)| entry == *hash))
+    }
+
+    /// The balance of the chain as of `hash`, or 0 before its first block
+    fn balance_at(&self, hash: Option<BlockHash>) -> Result<u128> {
+        let mut current = hash;
+        while let Some(hash) = current {
+            let block = self.block(&hash)?;
+            match block.payload {
+                Some(BlockPayload::State { balance, .. }) | Some(BlockPayload::Send { balance, .. }) => {
+                    return Ok(balance);
+                }
+                Some(BlockPayload::Change { .. }) => current = previous(&block),
+                _ => bail!("The balance as of the legacy block {} isn't known", String::from(hash)),
+            }
+        }
+        Ok(0)
+    }
+
+    fn representative_at(&self, hash: BlockHash) -> Result<PublicKey> {
+        let mut current = hash;
+        loop {
+            let block = self.block(&current)?;
+            match block.payload {
+                Some(BlockPayload::State { ref representative, .. })
+                | Some(BlockPayload::Open { ref representative, .. })
+                | Some(BlockPayload::Change { ref representative, .. }) => return Ok(representative.clone()),
+                _ => (),
+            }
+            current = previous(&block)
+                .ok_or_else(|| Error::from(format!("No representative is set by {}", String::from(hash))))?;
+        }
+    }
+
+    /// The account which made the send `hash` and the amount sent, if it's a send
+    fn send(&self, hash: &BlockHash) -> Result<Option<(PublicKey, u128)>> {
+        let block = self.block(hash)?;
+        let balance = match block.payload {
+            Some(BlockPayload::State { balance, .. }) | Some(BlockPayload::Send { balance, .. }) => balance,
+            _ => return Ok(None),
+        };
+        let before = self.balance_at(previous(&block))?;
+        if balance >= before {
+            return Ok(None);
+        }
+        let account = chain_account(self.store, &block)?
+            .ok_or_else(|| Error::from(format!("The account of {} isn't known", String::from(*hash))))?;
+        Ok(Some((account, before - balance)))
+    }
+
+    /// Give `account` back the pending entry of the send `source` it received, if it is
+    /// a stored send
+    fn restore_pending(&mut self, account: &PublicKey, source: BlockHash) -> Result<()> {
+        if self.removed.contains(&source) || !self.store.block_exists(&source)? {
+            return Ok(());
+        }
+        if let Some((sender, amount)) = self.send(&source)? {
+            self.batch.put_pending(account.clone(), source, PendingInfo { source: sender, amount });
+        }
+        Ok(())
+    }
+
+    /// The block of `destination`'s chain receiving the send `send`, if any
+    fn receiver(&self, destination: &PublicKey, send: &BlockHash) -> Result<Option<BlockHash>> {
+        let mut current = self.account(destination)?.map(|info| info.head);
+        while let Some(hash) = current {
+            let block = match self.store.block(&hash)? {
+                Some(ref block) if !self.removed.contains(&hash) => block.clone(),
+                _ => return Ok(None),
+            };
+            let receives = match block.payload {
+                Some(BlockPayload::State { ref link, .. }) => link.as_bytes() == send.as_bytes(),
+                Some(BlockPayload::Receive { source, .. }) | Some(BlockPayload::Open { source, .. }) => source == *send,
+                _ => false,
+            };
+            if receives {
+                return Ok(Some(hash));
+            }
+            current = previous(&block);
+        }
+        Ok(None)
+    }
+
+    /// Fail unless `hash` is on the chain recorded in `info`, after its cemented blocks
+    fn check_rollback(&self, hash: &BlockHash, info: &AccountInfo) -> Result<()> {
+        let mut current = Some(info.head);
+        while current != Some(*hash) {
+            current = match current {
+                Some(current) => previous(&self.block(&current)?),
+                None => bail!("Block {} isn't on its account's chain", String::from(*hash)),
+            };
+        }
+        if info.confirmation_height == 0 {
+            return Ok(());
+        }
+        let mut height = 1;
+        let mut current = previous(&self.block(hash)?);
+        while let Some(hash) = current {
+            current = match self.block(&hash) {
+                Ok(block) => previous(&block),
+                Err(_) => bail!("Blocks before {} were pruned, and they may be cemented", String::from(hash)),
+            };
+            height += 1;
+        }
+        if height <= info.confirmation_height {
+            bail!("Block {} is cemented, and can't be rolled back", String::from(*hash));
+        }
+        Ok(())
+    }
+
+    /// Roll back `hash` and every later block of its chain
+    fn roll_back(&mut self, hash: &BlockHash) -> Result<()> {
+        if self.removed.contains(hash) {
+            return Ok(());
+        }
+        let account = chain_account(self.store, &self.block(hash)?)?
+            .ok_or_else(|| Error::from(format!("The account of {} isn't known", String::from(*hash))))?;
+        let info = self.account(&account)?
+            .ok_or_else(|| Error::from(format!("{} has no account record", String::from(*hash))))?;
+        self.check_rollback(hash, &info)?;
+        while !self.removed.contains(hash) {
+            let info = self.account(&account)?
+                .ok_or_else(|| Error::from(format!("{} has no account record", String::from(*hash))))?;
+            self.roll_back_head(&account, info)?;
+        }
+        Ok(())
+    }
+
+    /// Roll back the latest block of `account`, recorded in `info`
+    fn roll_back_head(&mut self, account: &PublicKey, info: AccountInfo) -> Result<()> {
+        let hash = info.head;
+        let block = self.block(&hash)?;
+        let before = previous(&block);
+        let previous_balance = match block.payload {
+            Some(BlockPayload::State { .. }) => self.balance_at(before)?,
+            _ => 0,
+        };
+        match block.payload {
+            Some(BlockPayload::State { balance, ref link, .. }) if balance < previous_balance => {
+                let destination = PublicKey::from_bytes(link.as_bytes())?;
+                if !self.is_pending(&destination, &hash)? {
+                    if let Some(receive) = self.receiver(&destination, &hash)? {
+                        self.roll_back(&receive)?;
+                    }
+                }
+                self.batch.delete_pending(destination, hash);
+            }
+            Some(BlockPayload::Send { ref destination, .. }) => {
+                if !self.is_pending(destination, &hash)? {
+                    if let Some(receive) = self.receiver(destination, &hash)? {
+                        self.roll_back(&receive)?;
+                    }
+                }
+                self.batch.delete_pending(destination.clone(), hash);
+            }
+            Some(BlockPayload::State { balance, ref link, .. }) if balance > previous_balance => {
+                self.restore_pending(account, BlockHash::from_bytes(link.as_bytes())?)?;
+            }
+            Some(BlockPayload::Receive { source, .. }) | Some(BlockPayload::Open { source, .. }) => {
+                self.restore_pending(account, source)?;
+            }
+            _ => (),
+        }
+
+        match before {
+            Some(before) => {
+                let balance = self.balance_at(Some(before))?;
+                let representative = self.representative_at(before)?;
+                self.weights.transfer(&mut self.batch, account, Some((&info.representative, info.balance)),
+                    &representative, balance);
+                self.batch.put_account(account.clone(), AccountInfo {
+                    head: before,
+                    representative,
+                    balance,
+                    confirmation_height: info.confirmation_height,
+                });
+            }
+            None => {
+                self.weights.transfer(&mut self.batch, account, Some((&info.representative, info.balance)),
+                    &info.representative, 0);
+                self.batch.delete_account(account.clone());
+                self.deleted_accounts.insert(*account.as_bytes());
+            }
+        }
+        self.batch.delete_block(hash);
+        self.removed.insert(hash);
+        self.summary.blocks.push(hash);
+        Ok(())
+    }
+}
+
+/// Roll back `hash` and the blocks depending on it in `store`, writing the changes
+/// unless `dry_run` is set. The balances of `burn_accounts` aren't in the weights. The
+/// node must not be running on `store`.
+pub fn rollback(store: &dyn Store, burn_accounts: &[PublicKey], hash: &BlockHash, dry_run: bool)
+    -> Result<RollbackSummary>
+{
+    let mut rollback = Rollback {
+        store,
+        weights: RepWeights::load(store, burn_accounts)?,
+        batch: WriteBatch::new(),
+        removed: HashSet::new(),
+        deleted_accounts: HashSet::new(),
+        summary: RollbackSummary::default(),
+    };
+    rollback.roll_back(hash)?;
+    if !dry_run {
+        store.write(&rollback.batch)?;
+    }
+    Ok(rollback.summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nano_lib_rs::block::{BlockKind, Link};
+    use crate::store::memory::MemoryStore;
+
+    fn state_block(account: u8, previous: BlockHash, balance: u128, link: [u8; 32]) -> (BlockHash, Block) {
+        let mut block = Block::new(BlockKind::State, Some(BlockPayload::State {
+            account: PublicKey::from_bytes(&[account; 32]).unwrap(),
+            previous,
+            representative: PublicKey::from_bytes(&[account; 32]).unwrap(),
+            balance,
+            link: Link::Unknown(link),
+        }), None, None);
+        (block.hash(false).unwrap(), block)
+    }
+
+    fn put_chain(batch: &mut WriteBatch, account: u8, blocks: &[(BlockHash, Block)], balance: u128) {
+        for &(hash, ref block) in blocks {
+            batch.put_block(hash, block.clone());
+        }
+        let key = PublicKey::from_bytes(&[account; 32]).unwrap();
+        batch.put_account(key.clone(), AccountInfo {
+            head: blocks[blocks.len() - 1].0,
+            representative: key.clone(),
+            balance,
+            confirmation_height: 1,
+        });
+        batch.put_weight(key, balance);
+    }
+
+    #[test]
+    fn rolls_back_receives_of_rolled_back_sends() {
+        let store = MemoryStore::new();
+        let (first, second) = (PublicKey::from_bytes(&[1u8; 32]).unwrap(), PublicKey::from_bytes(&[2u8; 32]).unwrap());
+        let zero = BlockHash::from_bytes(&[0u8; 32]).unwrap();
+        // The first account sends 3 of its 10 to the second, which receives it and then
+        // changes representative
+        let open = state_block(1, zero, 10, [7u8; 32]);
+        let send = state_block(1, open.0, 7, [2u8; 32]);
+        let receive = state_block(2, zero, 3, *send.0.as_bytes());
+        let change = state_block(2, receive.0, 3, [0u8; 32]);
+        let mut batch = WriteBatch::new();
+        put_chain(&mut batch, 1, &[open.clone(), send.clone()], 7);
+        put_chain(&mut batch, 2, &[receive.clone(), change.clone()], 3);
+        store.write(&batch).unwrap();
+
+        // The receive is cemented
+        assert!(rollback(&store, &[], &receive.0, false).is_err());
+        assert!(rollback(&store, &[], &send.0, false).is_err());
+        let mut batch = WriteBatch::new();
+        let info = store.account(&second).unwrap().unwrap();
+        batch.put_account(second.clone(), AccountInfo { confirmation_height: 0, ..info });
+        store.write(&batch).unwrap();
+
+        let summary = rollback(&store, &[], &send.0, true).unwrap();
+        assert_eq!(summary.blocks, vec![change.0, receive.0, send.0]);
+        assert_eq!(store.block_count().unwrap(), 4);
+
+        assert_eq!(rollback(&store, &[], &send.0, false).unwrap(), summary);
+        assert_eq!(store.block_count().unwrap(), 1);
+        let info = store.account(&first).unwrap().unwrap();
+        assert_eq!((info.head, info.balance), (open.0, 10));
+        assert_eq!(store.account(&second).unwrap(), None);
+        assert!(store.pending(&second).unwrap().is_empty());
+        let weights = RepWeights::load(&store, &[]).unwrap();
+        assert_eq!((weights.weight(&first), weights.weight(&second)), (10, 0));
+
+        // Rolling back only the receive gives the send its pending entry back
+        let mut batch = WriteBatch::new();
+        put_chain(&mut batch, 1, &[open.clone(), send.clone()], 7);
+        put_chain(&mut batch, 2, &[receive.clone()], 3);
+        batch.put_account(second.clone(), AccountInfo {
+            head: receive.0,
+            representative: second.clone(),
+            balance: 3,
+            confirmation_height: 0,
+        });
+        store.write(&batch).unwrap();
+        assert_eq!(rollback(&store, &[], &receive.0, false).unwrap().blocks, vec![receive.0]);
+        assert_eq!(store.pending(&second).unwrap(), vec![(send.0, PendingInfo { source: first, amount: 3 })]);
+    }
+}
@@ -0,0 +1,167 @@
+//! Stopping the node's subsystems in order once shutdown is triggered.
+//!
+//! Triggering `Shutdown` stops every task producing messages, and the RPC and work
+//! servers, so no new work is accepted. What was already accepted is then finished
+//! in stages, each logged with how long it took: queued datagrams are sent, the
+//! signature checker and block processor drain their queues, the block writer
+//! commits its last batch, and the peers and work cache are persisted next to the
+//! store, to be loaded on the next start. The stages share one deadline of
+//! `SHUTDOWN_DEADLINE` seconds; a stage still running when it passes is abandoned,
+//! and the stages after it are skipped.
+use std::fs::{self, File};
+use std::future::Future;
+use std::io::{BufReader, BufWriter};
+use std::net::SocketAddrV6;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use data_encoding::HEXUPPER;
+use serde_json::Value;
+use tokio::time::{self, Instant};
+
+use nano_lib_rs::block::{InputHash, Work};
+
+use crate::error::*;
+use crate::daemon::SHUTDOWN_DEADLINE;
+use crate::utils::{check_addr, millis};
+use super::state::State;
+
+/// Runs shutdown stages against one shared deadline
+pub struct Stages {
+    deadline: Instant,
+}
+
+impl Stages {
+    pub fn new() -> Self {
+        Stages { deadline: Instant::now() + Duration::from_secs(SHUTDOWN_DEADLINE) }
+    }
+
+    /// Run the stage `name`, failing if it doesn't finish before the deadline
+    pub async fn run<F>(&self, name: &str, stage: F) -> Result<()>
+        where F: Future<Output=Result<()>>
+    {
+        info!("Shutdown: {}", name);
+        let started = Instant::now();
+        match time::timeout_at(self.deadline, stage).await {
+            Ok(result) => {
+                result.chain_err(|| format!("Error {} during shutdown", name))?;
+                debug!("Shutdown: {} took {}ms", name, millis(started.elapsed()));
+                Ok(())
+            }
+            Err(_) => bail!("Node did not stop within {} seconds, while {}", SHUTDOWN_DEADLINE, name),
+        }
+    }
+
+    /// Run `stage` on a blocking thread as the stage `name`
+    pub async fn run_blocking<F>(&self, name: &str, stage: F) -> Result<()>
+        where F: FnOnce() -> Result<()> + Send + 'static
+    {
+        self.run(name, async move {
+            tokio::task::spawn_blocking(stage).await
+                .map_err(|_| Error::from("Shutdown thread exited unexpectedly"))?
+        }).await
+    }
+}
+
+/// The files the peers and work cache are persisted in, next to the store at `path`
+pub fn cache_paths(path: &Path) -> (PathBuf, PathBuf) {
+    let mut peers = path.to_owned().into_os_string();
+    peers.push(".peers");
+    let mut work = path.to_owned().into_os_string();
+    work.push(".work");
+    (PathBuf::from(peers), PathBuf::from(work))
+}
+
+/// Write the peers and cached work of `state` to the files at `paths`
+pub fn persist_caches(state: &State, paths: &(PathBuf, PathBuf)) -> Result<()> {
+    let peers: Vec<String> = state.peers.read().unwrap().keys().map(|addr| addr.to_string()).collect();
+    let work: Vec<Value> = state.work.cached_work().into_iter()
+        .map(|(root, work)| json!([HEXUPPER.encode(root.as_bytes()), work.to_string()]))
+        .collect();
+    serde_json::to_writer(BufWriter::new(File::create(&paths.0)?), &peers)?;
+    serde_json::to_writer(BufWriter::new(File::create(&paths.1)?), &work)?;
+    info!("Persisted {} peers and {} cached work values", peers.len(), work.len());
+    Ok(())
+}
+
+/// The peers and cached work persisted at `paths` by a previous run, if any,
+/// skipping entries which don't parse. The files are removed once read, so that a
+/// later crash doesn't leave stale entries behind.
+pub fn load_caches(paths: &(PathBuf, PathBuf)) -> (Vec<SocketAddrV6>, Vec<(InputHash, Work)>) {
+    fn read(path: &Path) -> Option<Vec<Value>> {
+        let file = File::open(path).ok()?;
+        let values = serde_json::from_reader(BufReader::new(file));
+        let _ = fs::remove_file(path);
+        match values {
+            Ok(values) => Some(values),
+            Err(e) => {
+                warn!("Ignoring {}, which could not be read: {}", path.display(), e);
+                None
+            }
+        }
+    }
+    let peers = read(&paths.0).unwrap_or_default().iter()
+        .filter_map(|peer| peer.as_str()?.parse().ok())
+        .filter(|peer| check_addr(*peer))
+        .collect();
+    let work = read(&paths.1).unwrap_or_default().iter()
+        .filter_map(|entry| {
+            let root = InputHash::from_hex(entry.get(0)?.as_str()?).ok()?;
+            let work = Work::from_hex(entry.get(1)?.as_str()?).ok()?;
+            Some((root, work))
+        })
+        .collect();
+    (peers, work)
+}
+
+/// Finish the work `state` already accepted, once `flushed` has sent the queued
+/// datagrams
+pub async fn run<F>(state: Arc<State>, flushed: F, cache_paths: (PathBuf, PathBuf)) -> Result<()>
+    where F: Future<Output=()>
+{
+    let stages = Stages::new();
+    stages.run("flushing sockets", async move {
+        flushed.await;
+        Ok(())
+    }).await?;
+    let verifier_state = state.clone();
+    stages.run_blocking("draining the signature checker", move || {
+        verifier_state.verifier.stop();
+        Ok(())
+    }).await?;
+    let processor_state = state.clone();
+    stages.run_blocking("draining the block processor", move || {
+        processor_state.processor.stop();
+        Ok(())
+    }).await?;
+    let writer_state = state.clone();
+    stages.run_blocking("flushing the block writer", move || {
+        writer_state.writer.stop();
+        Ok(())
+    }).await?;
+    stages.run_blocking("persisting peers and work", move || persist_caches(&state, &cache_paths)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn loads_persisted_caches() {
+        let dir = env::temp_dir().join(format!("nano-rs-shutdown-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let paths = cache_paths(&dir.join("data"));
+        fs::write(&paths.0, r#"["[::ffff:93.184.216.34]:7075", "[::1]:7075", "nonsense"]"#).unwrap();
+        fs::write(&paths.1, r#"[["8D3E5F07BFF7B7484CDCB392F47009F62997253D28BD98B94BCED95F03C4DA09", "4effb6b0cd5625e2"], ["00"]]"#)
+            .unwrap();
+        let (peers, work) = load_caches(&paths);
+        assert_eq!(peers, vec!["[::ffff:93.184.216.34]:7075".parse::<SocketAddrV6>().unwrap()]);
+        assert_eq!(work.len(), 1);
+        assert!(!paths.0.exists() && !paths.1.exists());
+        assert_eq!(load_caches(&paths), (Vec::new(), Vec::new()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
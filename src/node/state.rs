@@ -1,39 +1,305 @@
-use std::sync::{RwLock};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
 use std::time::{Instant, Duration};
-use std::net::{SocketAddrV6};
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
 use indexmap::IndexMap;
 use indexmap::map::{Entry};
-use rand::{self, Rng};
+use rand::{Rng, XorShiftRng};
 
-use utils::{check_addr};
-use super::KEEPALIVE_CUTOFF;
+use nano_lib_rs::block::{BlockHash, InputHash, Work};
+use nano_lib_rs::keys::PublicKey;
+use nano_lib_rs::message::{Extensions, Message, MessageBuilder, MessageKind, MessagePayload};
+use nano_lib_rs::network::NetworkParams;
+
+use crate::error::*;
+
+use crate::config::{ConfigReloader, ReloadSummary};
+use crate::daemon::Shutdown;
+use crate::net::capture::Capture;
+use crate::net::pool::BufferPool;
+use crate::net::rebind::Rebind;
+use crate::net::udp_framed::{INITIAL_RD_CAPACITY, POOLED_RD_BUFFERS};
+use crate::payment::DepositPool;
+use super::exclusions::{Exclusion, Exclusions};
+use super::processor::BlockProcessor;
+use super::republish::{RecentPublishes, Republisher};
+use super::verifier::SignatureVerifier;
+use super::voting::Voting;
+use super::votes::VoteFilter;
+use super::webhook::{Webhook, WebhookConfig};
+use super::weights::RepWeights;
+use super::writer::BlockWriter;
+use crate::signer::Signer;
+use crate::stats::{Direction, Stats, StatType};
+use crate::store::Store;
+use crate::utils::{check_addr, from_ipv6, millis, seeded_rng, ErrorLog};
+use crate::work::WorkPool;
+use super::{NodeMode, PeeringConfig};
+use super::difficulty::DifficultyTracker;
+use super::work_watcher::WorkWatcher;
+
+/// Number of blocks whose first sighting is remembered for confirmation latency
+const MAX_SEEN_BLOCKS: usize = 65_536;
+
+/// Keepalives answered later than this are assumed to be the peer's own periodic
+/// keepalive rather than a reply, and are not used to estimate its round-trip time
+const MAX_RTT: u64 = 5;
 
 #[derive(Clone, Copy, Debug)]
 pub struct PeerInfo {
-    last_seen: Instant
+    last_seen: Instant,
+    keepalive_sent: Option<Instant>,
+    rtt: Option<Duration>,
+    bytes_in: u64,
+    bytes_out: u64,
+    relayable: bool,
+    socket: Option<usize>,
+}
+
+impl PeerInfo {
+    pub fn last_seen(&self) -> Instant {
+        self.last_seen
+    }
+
+    /// A moving average of the time between sending the peer a keepalive and hearing
+    /// one back, once one has been measured
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Bytes received from and sent to the peer
+    pub fn traffic(&self) -> (u64, u64) {
+        (self.bytes_in, self.bytes_out)
+    }
+
+    /// Whether the peer may be passed on to others in keepalives, which it isn't if
+    /// it said it can't accept connections it didn't open
+    pub fn relayable(&self) -> bool {
+        self.relayable
+    }
+
+    /// The index in `State::sockets` of the local socket the peer was last heard on
+    pub fn socket(&self) -> Option<usize> {
+        self.socket
+    }
 }
 
 impl Default for PeerInfo {
     fn default() -> Self {
         PeerInfo {
-            last_seen: Instant::now()
+            last_seen: Instant::now(),
+            keepalive_sent: None,
+            rtt: None,
+            bytes_in: 0,
+            bytes_out: 0,
+            relayable: true,
+            socket: None,
         }
     }
 }
 
 type Peers = IndexMap<SocketAddrV6, PeerInfo>;
 
+/// Whether a socket bound to `local` can send to `peer`. A lone IPv6 socket is bound
+/// dual-stack, so it reaches IPv4 peers too.
+fn can_reach(local: &SocketAddr, peer: SocketAddrV6, dual_stack: bool) -> bool {
+    match (local, from_ipv6(peer)) {
+        (SocketAddr::V4(_), SocketAddr::V4(_)) => true,
+        (SocketAddr::V6(_), SocketAddr::V6(_)) => true,
+        (SocketAddr::V6(_), SocketAddr::V4(_)) => dual_stack,
+        (SocketAddr::V4(_), SocketAddr::V6(_)) => false,
+    }
+}
+
+/// When recently seen blocks were first received, forgetting the oldest beyond
+/// `MAX_SEEN_BLOCKS`
+#[derive(Debug, Default)]
+struct SeenBlocks {
+    times: HashMap<BlockHash, Instant>,
+    order: VecDeque<BlockHash>,
+}
+
 #[derive(Debug)]
 pub struct State {
+    pub network: NetworkParams,
+    pub mode: NodeMode,
     pub peers: RwLock<Peers>,
     pub inactive_peers: RwLock<Peers>,
+    pub work: WorkPool,
+    pub difficulty: DifficultyTracker,
+    pub work_watcher: WorkWatcher,
+    pub config: ConfigReloader,
+    pub errors: Arc<ErrorLog>,
+    pub started: Instant,
+    pub shutdown: Arc<Shutdown>,
+    pub stats: Stats,
+    pub capture: Capture,
+    /// Buffers datagrams are received into
+    pub buffers: BufferPool,
+    pub verifier: SignatureVerifier,
+    pub processor: BlockProcessor,
+    pub store: Arc<dyn Store>,
+    pub writer: BlockWriter,
+    /// The committed weight of each representative
+    pub weights: RepWeights,
+    /// Signs for the node's account, if it has one
+    pub signer: Option<Arc<dyn Signer>>,
+    /// How the node keeps in touch with its peers
+    pub peering: PeeringConfig,
+    /// Hosts no traffic is exchanged with
+    pub exclusions: Exclusions,
+    /// The local addresses of the UDP sockets the node listens on
+    pub sockets: Vec<SocketAddr>,
+    pub rebind: Rebind,
+    /// Whether the node votes with the signer's account
+    pub voting: Voting,
+    /// The deposit accounts handed out by `payment_begin`
+    pub deposits: DepositPool,
+    /// Notified of sends to watched accounts as they are written
+    pub webhook: Webhook,
+    /// Blocks queued to be published to every peer, by the `republish` RPC action and
+    /// as local blocks are created
+    pub republisher: Republisher,
+    /// The blocks published to each peer recently, which aren't published to it again
+    pub recent_publishes: RecentPublishes,
+    /// The votes handled from each representative recently, to throttle repeats
+    pub recent_votes: VoteFilter,
+    seen_blocks: Mutex<SeenBlocks>,
+    /// Draws the node's random choices, from `node.seed` if set
+    rng: Mutex<XorShiftRng>,
 }
 
 impl State {
-    pub fn new(initial_peers: Peers) -> Self {
+    pub fn new(network: NetworkParams, mode: NodeMode, initial_peers: Peers, work: WorkPool, config: ConfigReloader,
+        shutdown: Arc<Shutdown>, store: Arc<dyn Store>, weights: RepWeights, signer: Option<Arc<dyn Signer>>,
+        voting: Voting, peering: PeeringConfig, sockets: Vec<SocketAddr>, seed: Option<u64>,
+        deposit_accounts: Vec<PublicKey>, webhook: Option<WebhookConfig>, exclusions: Exclusions) -> Self
+    {
         State {
+            difficulty: DifficultyTracker::new(network.work_threshold),
+            network,
+            mode,
             peers: RwLock::new(initial_peers),
             inactive_peers: RwLock::new(IndexMap::new()),
+            work,
+            work_watcher: WorkWatcher::new(),
+            config,
+            errors: Arc::new(ErrorLog::new()),
+            started: Instant::now(),
+            shutdown,
+            stats: Stats::new(),
+            capture: Capture::new(),
+            buffers: BufferPool::new(INITIAL_RD_CAPACITY, POOLED_RD_BUFFERS),
+            verifier: SignatureVerifier::new(),
+            processor: BlockProcessor::new(),
+            store,
+            writer: BlockWriter::new(),
+            weights,
+            signer,
+            voting,
+            peering,
+            exclusions,
+            sockets,
+            rebind: Rebind::new(),
+            deposits: DepositPool::new(deposit_accounts),
+            webhook: Webhook::new(webhook),
+            republisher: Republisher::new(),
+            recent_publishes: RecentPublishes::new(),
+            recent_votes: VoteFilter::new(),
+            seen_blocks: Mutex::new(SeenBlocks::default()),
+            // Channels' faults are seeded on the streams from 0, so the node's choices
+            // draw from the other end
+            rng: Mutex::new(seeded_rng(seed, ::std::u32::MAX)),
+        }
+    }
+
+    /// Reload the configuration file, applying the settings which can change at runtime
+    pub fn reload_config(&self) -> Result<ReloadSummary> {
+        let summary = self.config.reload(&self.work)?;
+        if summary.applied.is_empty() {
+            info!("Reloaded {}, nothing to apply", self.config.path().display());
+        } else {
+            info!("Reloaded {}, applied changes to: {}", self.config.path().display(), summary.applied.join(", "));
+        }
+        if !summary.restart_required.is_empty() {
+            warn!("Changes to {} will only take effect after a restart", summary.restart_required.join(", "));
+        }
+        Ok(summary)
+    }
+
+    /// Generate work for `root` on a blocking thread, resolving once it is done
+    pub async fn generate_work_async(state: Arc<State>, root: InputHash, threshold: u64) -> Result<Option<Work>> {
+        tokio::task::spawn_blocking(move || {
+            let start = Instant::now();
+            let work = state.work.generate(root, threshold);
+            state.stats.sample(StatType::Work, "generate_ms", millis(start.elapsed()));
+            let detail = if work.is_some() { "generated" } else { "cancelled" };
+            state.stats.inc(StatType::Work, detail, Direction::In);
+            work
+        }).await.map_err(|_| Error::from("Work generation thread exited unexpectedly"))
+    }
+
+    /// Generate work for `root` in the background so it is cached by the time a block
+    /// needs it. Returns false if the pre-generation limit has been reached or work is
+    /// already available.
+    pub fn pregenerate_work(state: Arc<State>, root: InputHash) -> bool {
+        let threshold = state.difficulty.active_difficulty();
+        if !state.work.begin_pregeneration(&root, threshold) {
+            return false;
+        }
+        thread::spawn(move || {
+            debug!("Pre-generating work for root {}", root);
+            let _ = state.work.generate(root, threshold);
+            state.work.end_pregeneration();
+        });
+        true
+    }
+
+    /// Called when an account's frontier moves from `previous` to `frontier`: cached work
+    /// for the old frontier is dropped and work for the next block is pre-generated.
+    pub fn frontier_changed(state: Arc<State>, previous: Option<InputHash>, frontier: InputHash) {
+        if let Some(previous) = previous {
+            state.work.invalidate(&previous);
+        }
+        State::pregenerate_work(state, frontier);
+    }
+
+    /// Remember when `hash` was first received, for measuring its confirmation latency.
+    /// Returns false if it was received before.
+    pub fn block_seen(&self, hash: BlockHash) -> bool {
+        let mut seen = self.seen_blocks.lock().unwrap();
+        if seen.times.contains_key(&hash) {
+            return false;
+        }
+        if seen.order.len() == MAX_SEEN_BLOCKS {
+            if let Some(oldest) = seen.order.pop_front() {
+                seen.times.remove(&oldest);
+            }
+        }
+        seen.times.insert(hash, Instant::now());
+        seen.order.push_back(hash);
+        true
+    }
+
+    pub fn seen_block_count(&self) -> usize {
+        self.seen_blocks.lock().unwrap().times.len()
+    }
+
+    /// Called once `hash` is confirmed, recording the time since it was first seen
+    #[allow(dead_code)]
+    pub fn block_confirmed(&self, hash: &BlockHash) {
+        if let Some(seen) = self.seen_blocks.lock().unwrap().times.get(hash) {
+            self.stats.observe(StatType::Confirmation, "confirmed", millis(seen.elapsed()));
+        }
+    }
+
+    /// Called once `hash` is cemented, recording the time since it was first seen. The
+    /// block is forgotten afterwards.
+    #[allow(dead_code)]
+    pub fn block_cemented(&self, hash: &BlockHash) {
+        if let Some(seen) = self.seen_blocks.lock().unwrap().times.remove(hash) {
+            self.stats.observe(StatType::Confirmation, "cemented", millis(seen.elapsed()));
         }
     }
 
@@ -41,7 +307,13 @@ impl State {
         self.peers.read().unwrap().len()
     }
 
-    pub fn add_or_update_peer(&self, peer: SocketAddrV6, force: bool) -> bool {
+    pub fn is_peer(&self, addr: &SocketAddrV6) -> bool {
+        self.peers.read().unwrap().contains_key(addr)
+    }
+
+    /// Note that `peer` was heard from on the local socket at index `socket` of
+    /// `sockets`, adding it if it is new
+    pub fn add_or_update_peer(&self, peer: SocketAddrV6, socket: usize, force: bool) -> bool {
         if !force {
             let inactive_map = self.inactive_peers.read().unwrap();
             if let Some(_) = inactive_map.get(&peer) {
@@ -57,11 +329,13 @@ impl State {
         match map.entry(peer) {
             Entry::Occupied(mut entry) => {
                 entry.get_mut().last_seen = Instant::now();
+                entry.get_mut().socket = Some(socket);
                 false
             },
             Entry::Vacant(entry) => {
                 if check_addr(peer) {
-                    entry.insert(PeerInfo::default());
+                    entry.insert(PeerInfo { socket: Some(socket), ..PeerInfo::default() });
+                    self.stats.inc(StatType::Peering, "new", Direction::In);
                     true
                 } else {
                     false
@@ -75,7 +349,10 @@ impl State {
         let mut map = self.peers.write().unwrap();
         let mut to_prune = Vec::new();
         for (addr, info) in map.iter() {
-            if Instant::now() - info.last_seen > Duration::from_secs(KEEPALIVE_CUTOFF) {
+            if self.peering.persistent.contains(addr) {
+                continue;
+            }
+            if Instant::now() - info.last_seen > self.peering.cutoff() {
                 to_prune.push(*addr);
                 inactive_map.insert(*addr, *info);
             }
@@ -83,6 +360,7 @@ impl State {
         for addr in to_prune.iter() {
             map.remove(addr);
         }
+        self.stats.add(StatType::Peering, "pruned", Direction::In, to_prune.len() as u64);
         to_prune.len()
     }
     
@@ -90,15 +368,147 @@ impl State {
         let mut map = self.peers.write().unwrap();
         if let Entry::Occupied(entry) = map.entry(peer) {
             entry.remove();
+            self.stats.inc(StatType::Peering, "removed", Direction::In);
+        }
+    }
+
+    /// The detail traffic with `peer` is dropped under, if it isn't whitelisted or it
+    /// is excluded
+    pub fn blocked(&self, peer: SocketAddrV6) -> Option<&'static str> {
+        if !self.peering.allows(peer) {
+            Some("not_whitelisted")
+        } else if self.exclusions.is_excluded(peer.ip()) {
+            Some("excluded_peer")
+        } else {
+            None
+        }
+    }
+
+    /// Exclude the host `host`, dropping it as a peer on every port
+    pub fn exclude_host(&self, host: Ipv6Addr, duration: Option<Duration>, reason: &str) -> Result<Exclusion> {
+        let exclusion = self.exclusions.exclude(host, duration, reason)?;
+        let removed = {
+            let mut peers = self.peers.write().unwrap();
+            let count = peers.len();
+            peers.retain(|peer, _| peer.ip() != &host);
+            count - peers.len()
+        };
+        self.inactive_peers.write().unwrap().retain(|peer, _| peer.ip() != &host);
+        self.stats.add(StatType::Peering, "removed", Direction::In, removed as u64);
+        Ok(exclusion)
+    }
+
+    /// Count traffic exchanged with `peer`, if it is a known peer
+    pub fn record_peer_traffic(&self, peer: SocketAddrV6, direction: Direction, bytes: u64) {
+        if let Some(info) = self.peers.write().unwrap().get_mut(&peer) {
+            match direction {
+                Direction::In => info.bytes_in += bytes,
+                Direction::Out => info.bytes_out += bytes,
+            }
         }
     }
 
+    /// Note that a keepalive is being sent to `peer`, to time its reply
+    pub fn keepalive_sent(&self, peer: SocketAddrV6) {
+        if let Some(info) = self.peers.write().unwrap().get_mut(&peer) {
+            info.keepalive_sent = Some(Instant::now());
+        }
+    }
+
+    /// Update the round-trip time of `peer` if a keepalive from it answers one we
+    /// sent, and whether it may be relayed
+    pub fn keepalive_received(&self, peer: SocketAddrV6, extensions: Extensions) {
+        let mut peers = self.peers.write().unwrap();
+        let info = match peers.get_mut(&peer) {
+            Some(info) => info,
+            None => return,
+        };
+        info.relayable = !extensions.contains(Extensions::NON_RELAYABLE);
+        let sample = match info.keepalive_sent.take() {
+            Some(sent) => sent.elapsed(),
+            None => return,
+        };
+        if sample > Duration::from_secs(MAX_RTT) {
+            return;
+        }
+        info.rtt = Some(match info.rtt {
+            Some(rtt) => (rtt * 3 + sample) / 4,
+            None => sample,
+        });
+        self.stats.sample(StatType::Peering, "rtt_ms", millis(sample));
+    }
+
+    /// Up to `n` peers with the lowest round-trip times, preferred as targets for
+    /// requests which wait on a reply, such as bootstrapping and confirm_req. Peers
+    /// without a measurement are used last.
+    #[allow(dead_code)]
+    pub fn fastest_peers(&self, n: usize) -> Vec<SocketAddrV6> {
+        let mut peers: Vec<(SocketAddrV6, Option<Duration>)> = self.peers.read().unwrap().iter()
+            .map(|(addr, info)| (*addr, info.rtt))
+            .collect();
+        peers.sort_by_key(|&(_, rtt)| (rtt.is_none(), rtt));
+        peers.into_iter().take(n).map(|(addr, _)| addr).collect()
+    }
+
+    /// `n` peers chosen at random from those which may be relayed, or none if there
+    /// aren't any. Peers are kept in the order they were added, so a seeded node picks
+    /// the same peers on every run.
     pub fn random_peers(&self, n: usize) -> Vec<SocketAddrV6> {
-        let mut rng = rand::thread_rng();
-        let peers = self.peers.read().unwrap();
+        let peers: Vec<SocketAddrV6> = self.peers.read().unwrap().iter()
+            .filter(|&(_, info)| info.relayable)
+            .map(|(addr, _)| *addr)
+            .collect();
+        if peers.is_empty() {
+            return Vec::new();
+        }
+        let mut rng = self.rng.lock().unwrap();
         (0..n).into_iter().map(|_| {
             let idx = rng.gen_range::<usize>(0, peers.len());
-            peers.get_index(idx).unwrap().0.clone()
+            peers[idx]
         }).collect()
     }
-}
\ No newline at end of file
+
+    /// The index in `sockets` of the socket to send to `peer` from, and the address to
+    /// send to in that socket's address family. Peers are answered from the socket
+    /// they were last heard on; others are sent to from the first socket which can
+    /// reach their address family.
+    pub fn socket_for(&self, peer: SocketAddrV6) -> (usize, SocketAddr) {
+        let known = self.peers.read().unwrap().get(&peer).and_then(|info| info.socket);
+        let index = known
+            .filter(|&index| index < self.sockets.len())
+            .or_else(|| self.sockets.iter().position(|local| can_reach(local, peer, self.sockets.len() == 1)))
+            .unwrap_or(0);
+        let addr = match self.sockets.get(index) {
+            Some(SocketAddr::V4(_)) => from_ipv6(peer),
+            _ => SocketAddr::V6(peer),
+        };
+        (index, addr)
+    }
+
+    /// A keepalive listing random peers, marked as non-relayable in outbound-only mode
+    pub fn keepalive(&self) -> Message {
+        let extensions = if self.peering.outbound_only { Extensions::NON_RELAYABLE } else { Extensions::NONE };
+        MessageBuilder::new(MessageKind::KeepAlive)
+            .with_network(self.network.kind)
+            .with_extensions(extensions)
+            .with_payload(MessagePayload::KeepAlive(self.random_peers(8)))
+            .build()
+    }
+}
+#[cfg(test)]
+impl State {
+    /// The state of a dev network node around `store`, with nothing started, for testing
+    /// the parts of the node which only need its state
+    pub fn for_tests(store: Arc<dyn Store>) -> Self {
+        use crate::config::Config;
+        use crate::logging::LogFilter;
+        use crate::work::WorkConfig;
+
+        let reloader = ConfigReloader::new("nano-rs.toml", Config::default(), LogFilter::detached("info").unwrap());
+        let network = NetworkParams::dev();
+        let weights = RepWeights::new(&network.burn_accounts);
+        State::new(network, NodeMode::Full, IndexMap::new(), WorkPool::new(WorkConfig::default()),
+            reloader, Arc::new(Shutdown::new()), store, weights, None, Voting::new(false, None),
+            PeeringConfig::default(), Vec::new(), Some(0), Vec::new(), None, Exclusions::new())
+    }
+}
@@ -0,0 +1,237 @@
+//! Checking the signatures of received blocks on a pool of threads.
+//!
+//! The socket task only hashes and routes a received block before queueing it here,
+//! so that reception never waits for signature checks. Like the `BlockProcessor`,
+//! each chain is checked by one thread, so that blocks reach the processor in the
+//! order they arrived. Queues are bounded, and shed blocks by `TrafficClass` as they
+//! fill up.
+//!
+//! Without a ledger the signer of a legacy send, receive or change block isn't known,
+//! so only open and state blocks are checked; other blocks are passed on unchecked.
+//! Nor is it known whether a state block with an epoch link changes its balance, so
+//! such blocks may be signed either by their account or by the epoch's signer.
+use std::fmt;
+use std::net::SocketAddrV6;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+
+use nano_lib_rs::block::{Block, BlockHash, BlockPayload};
+use nano_lib_rs::keys::PublicKey;
+use nano_lib_rs::network::NetworkParams;
+
+use crate::stats::{Direction, StatType};
+use super::processor::{worker_index, ChainKey};
+use super::queue::{class_queue, ClassQueue, ClassReceiver, DropPolicy, DropReason, TrafficClass};
+use super::state::State;
+
+/// Blocks which can be queued for each verification thread before further blocks are
+/// dropped
+pub const VERIFICATION_QUEUE_SIZE: usize = 4096;
+
+struct Job {
+    class: TrafficClass,
+    key: ChainKey,
+    block: Block,
+    hash: BlockHash,
+    peer: SocketAddrV6,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Outcome {
+    Valid,
+    Invalid,
+    Unsigned,
+    /// The signer can't be determined without a ledger
+    Unchecked,
+}
+
+/// The account which must have signed `block`, if it can be told from the block alone
+fn signer(block: &Block) -> Option<PublicKey> {
+    match block.payload {
+        Some(BlockPayload::Open { ref account, .. })
+        | Some(BlockPayload::State { ref account, .. }) => Some(account.clone()),
+        _ => None,
+    }
+}
+
+/// The signer of the epoch `block` upgrades its account to on `network`, if it is one
+fn epoch_signer(block: &Block, network: &NetworkParams) -> Option<PublicKey> {
+    match block.payload {
+        Some(BlockPayload::State { ref link, .. }) => network.epoch_signer(link.as_bytes()).cloned(),
+        _ => None,
+    }
+}
+
+fn check(block: &mut Block, network: &NetworkParams) -> Outcome {
+    let span = trace_span!("signature_check");
+    let _enter = span.enter();
+    if !block.is_signed() {
+        return Outcome::Unsigned;
+    }
+    let account = match signer(block) {
+        Some(account) => account,
+        None => return Outcome::Unchecked,
+    };
+    if let Ok(true) = block.verify_signature(&account) {
+        return Outcome::Valid;
+    }
+    match epoch_signer(block, network) {
+        Some(signer) => match block.verify_signature(&signer) {
+            Ok(true) => Outcome::Valid,
+            _ => Outcome::Invalid,
+        },
+        None => Outcome::Invalid,
+    }
+}
+
+fn verify(mut job: Job, state: &State) {
+    let outcome = check(&mut job.block, &state.network);
+    let detail = match outcome {
+        Outcome::Valid => "valid_signature",
+        Outcome::Invalid => "bad_signature",
+        Outcome::Unsigned => "unsigned",
+        Outcome::Unchecked => "signature_unchecked",
+    };
+    state.stats.inc(StatType::Block, detail, Direction::In);
+    match outcome {
+        Outcome::Valid | Outcome::Unchecked => {
+            if let Err(reason) = state.processor.submit(job.class, job.key, job.block, job.hash, job.peer) {
+                state.stats.inc(StatType::Drop, reason.detail(job.class), Direction::In);
+                debug!("Block processor queue too full, dropping block {}: {}", String::from(job.hash),
+                    reason.detail(job.class));
+            }
+        }
+        Outcome::Invalid | Outcome::Unsigned => {
+            debug!("Dropping block {} from {}: {}", String::from(job.hash), job.peer, detail);
+        }
+    }
+}
+
+fn run_verifier(state: Weak<State>, jobs: ClassReceiver<Job>) {
+    for job in jobs {
+        match state.upgrade() {
+            Some(state) => verify(job, &state),
+            None => return,
+        }
+    }
+}
+
+/// Checks block signatures on dedicated threads, keeping the blocks of each chain in
+/// order
+pub struct SignatureVerifier {
+    workers: Mutex<Vec<ClassQueue<Job>>>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl fmt::Debug for SignatureVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SignatureVerifier")
+            .field("workers", &self.workers.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl SignatureVerifier {
+    /// Create a verifier without threads; blocks are dropped until it is started
+    pub fn new() -> Self {
+        SignatureVerifier {
+            workers: Mutex::new(Vec::new()),
+            threads: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Start `threads` threads checking signatures for `state`, whose queues are shed
+    /// according to `policy`
+    pub fn start(&self, state: &Arc<State>, threads: usize, policy: &DropPolicy) {
+        let mut workers = self.workers.lock().unwrap();
+        let mut handles = self.threads.lock().unwrap();
+        for i in 0..threads.max(1) {
+            let (tx, rx) = class_queue(VERIFICATION_QUEUE_SIZE, policy);
+            let state = Arc::downgrade(state);
+            let handle = thread::Builder::new()
+                .name(format!("signature verifier {}", i))
+                .spawn(move || run_verifier(state, rx));
+            match handle {
+                Ok(handle) => {
+                    workers.push(tx);
+                    handles.push(handle);
+                }
+                Err(e) => error!("Could not start signature verification thread: {}", e),
+            }
+        }
+        debug!("Started {} signature verification threads", workers.len());
+    }
+
+    /// Queue `block` of `class`, received from `peer` and routed to the chain `key`, to
+    /// have its signature checked, unless the queue is too full for the class
+    pub fn submit(&self, class: TrafficClass, key: ChainKey, block: Block, hash: BlockHash, peer: SocketAddrV6)
+        -> ::std::result::Result<(), DropReason>
+    {
+        let workers = self.workers.lock().unwrap();
+        if workers.is_empty() {
+            return Err(DropReason::QueueFull);
+        }
+        workers[worker_index(&key, workers.len())].push(class, Job { class, key, block, hash, peer })
+    }
+
+    /// The number of blocks waiting to have their signatures checked
+    pub fn queued(&self) -> usize {
+        self.workers.lock().unwrap().iter().map(ClassQueue::len).sum()
+    }
+
+    /// Stop the threads once they have checked the blocks already queued
+    pub fn stop(&self) {
+        self.workers.lock().unwrap().clear();
+        for handle in self.threads.lock().unwrap().drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nano_lib_rs::block::{BlockKind, Link};
+    use nano_lib_rs::keys::{public_key_from_secret, SecretKey, Signature};
+    use nano_lib_rs::network::{dev_genesis_key, epoch_link};
+
+    #[test]
+    fn checks_signatures() {
+        let network = NetworkParams::dev();
+        let secret = SecretKey::from_bytes(&[9u8; 32]).unwrap();
+        let account = public_key_from_secret(&secret);
+        let mut block = Block::new(BlockKind::State, Some(BlockPayload::State {
+            account: account.clone(),
+            previous: BlockHash::from_bytes(&[1u8; 32]).unwrap(),
+            representative: account.clone(),
+            balance: 1,
+            link: Link::Unknown([2u8; 32]),
+        }), None, None);
+        assert_eq!(check(&mut block, &network), Outcome::Unsigned);
+
+        block.sign(&secret).unwrap();
+        assert_eq!(check(&mut block, &network), Outcome::Valid);
+
+        block.signature = Some(Signature::from_bytes(&[3u8; 64]).unwrap());
+        assert_eq!(check(&mut block, &network), Outcome::Invalid);
+
+        // Epoch blocks may be signed by the epoch's signer instead
+        let mut epoch = Block::new(BlockKind::State, Some(BlockPayload::State {
+            account: account.clone(),
+            previous: BlockHash::from_bytes(&[1u8; 32]).unwrap(),
+            representative: account.clone(),
+            balance: 1,
+            link: Link::Unknown(epoch_link(1)),
+        }), None, None);
+        epoch.sign(&dev_genesis_key()).unwrap();
+        assert_eq!(check(&mut epoch, &network), Outcome::Valid);
+        epoch.sign(&SecretKey::from_bytes(&[4u8; 32]).unwrap()).unwrap();
+        assert_eq!(check(&mut epoch, &network), Outcome::Invalid);
+
+        let mut change = Block::new(BlockKind::Change, Some(BlockPayload::Change {
+            previous: block.hash(false).unwrap(),
+            representative: account,
+        }), Some(Signature::from_bytes(&[3u8; 64]).unwrap()), None);
+        assert_eq!(check(&mut change, &network), Outcome::Unchecked);
+    }
+}
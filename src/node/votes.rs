@@ -0,0 +1,134 @@
+//! Throttling the votes handled from each representative.
+//!
+//! nano-rs doesn't count or relay votes yet, but queues the block of each one to be
+//! processed, so a representative repeating its votes would have every repeat cost a
+//! signature check. A vote is only handled once per `VOTE_WINDOW` for each
+//! representative and block, and at most `MAX_VOTES_PER_WINDOW` of a representative's
+//! votes are handled in a window, whatever they are for.
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use nano_lib_rs::block::BlockHash;
+use nano_lib_rs::keys::PublicKey;
+
+/// How long a representative's vote for a block isn't handled again
+pub const VOTE_WINDOW: Duration = Duration::from_secs(5);
+
+/// Votes handled from a representative in a `VOTE_WINDOW` at most
+pub const MAX_VOTES_PER_WINDOW: usize = 256;
+
+/// Votes remembered at most, forgetting the oldest first
+const MAX_RECENT_VOTES: usize = 65_536;
+
+/// A representative's vote for a block
+type Vote = ([u8; 32], BlockHash);
+
+/// Why a vote wasn't handled
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VoteDrop {
+    /// The representative voted for the block within `VOTE_WINDOW`
+    Duplicate,
+    /// The representative has had `MAX_VOTES_PER_WINDOW` votes handled within the window
+    Throttled,
+}
+
+impl VoteDrop {
+    /// The detail votes dropped for this reason are counted under
+    pub fn detail(self) -> &'static str {
+        match self {
+            VoteDrop::Duplicate => "duplicate_vote",
+            VoteDrop::Throttled => "vote_throttled",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    times: HashMap<Vote, Instant>,
+    order: VecDeque<(Vote, Instant)>,
+    /// Votes in `order` from each representative
+    counts: HashMap<[u8; 32], usize>,
+}
+
+/// The votes handled recently, within `VOTE_WINDOW`
+#[derive(Debug, Default)]
+pub struct VoteFilter {
+    inner: Mutex<Inner>,
+}
+
+impl VoteFilter {
+    pub fn new() -> Self {
+        VoteFilter::default()
+    }
+
+    /// Whether the vote of `representative` for the block `hash` should be handled at
+    /// `now`, remembering that it was if so
+    pub fn check(&self, representative: &PublicKey, hash: BlockHash, now: Instant) -> Result<(), VoteDrop> {
+        let mut inner = self.inner.lock().unwrap();
+        let Inner { ref mut times, ref mut order, ref mut counts } = *inner;
+        while let Some(&(vote, at)) = order.front() {
+            if now.duration_since(at) < VOTE_WINDOW && order.len() < MAX_RECENT_VOTES {
+                break;
+            }
+            order.pop_front();
+            if times.get(&vote) == Some(&at) {
+                times.remove(&vote);
+            }
+            let emptied = match counts.get_mut(&vote.0) {
+                Some(count) => {
+                    *count -= 1;
+                    *count == 0
+                }
+                None => false,
+            };
+            if emptied {
+                counts.remove(&vote.0);
+            }
+        }
+        let vote = (*representative.as_bytes(), hash);
+        if times.contains_key(&vote) {
+            return Err(VoteDrop::Duplicate);
+        }
+        let count = counts.entry(vote.0).or_insert(0);
+        if *count >= MAX_VOTES_PER_WINDOW {
+            return Err(VoteDrop::Throttled);
+        }
+        *count += 1;
+        times.insert(vote, now);
+        order.push_back((vote, now));
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().times.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttles_representatives() {
+        let filter = VoteFilter::new();
+        let (rep, other) = (PublicKey::from_bytes(&[1u8; 32]).unwrap(), PublicKey::from_bytes(&[2u8; 32]).unwrap());
+        let hash = |i: usize| {
+            let mut bytes = [0u8; 32];
+            bytes[..8].copy_from_slice(&(i as u64).to_le_bytes());
+            BlockHash::from_bytes(&bytes).unwrap()
+        };
+        let now = Instant::now();
+        assert_eq!(filter.check(&rep, hash(0), now), Ok(()));
+        assert_eq!(filter.check(&rep, hash(0), now + Duration::from_secs(1)), Err(VoteDrop::Duplicate));
+        assert_eq!(filter.check(&other, hash(0), now), Ok(()));
+        for i in 1..MAX_VOTES_PER_WINDOW {
+            assert_eq!(filter.check(&rep, hash(i), now), Ok(()));
+        }
+        assert_eq!(filter.check(&rep, hash(MAX_VOTES_PER_WINDOW), now), Err(VoteDrop::Throttled));
+        assert_eq!(filter.check(&other, hash(1), now), Ok(()));
+
+        assert_eq!(filter.check(&rep, hash(0), now + VOTE_WINDOW), Ok(()));
+        assert_eq!(filter.len(), 1);
+    }
+}
@@ -0,0 +1,216 @@
+//! Deciding whether a representative node votes.
+//!
+//! Voting is only ever enabled explicitly, with `node.enable_voting`, and the
+//! representative's key comes from the configured `Signer`; nano-rs has no wallet to
+//! keep it in. A node far behind the network would vote on forks the network settled
+//! long ago, so voting only becomes active once the store holds at least the
+//! network's cemented block count, less `VotingConfig::sync_threshold`. The network's
+//! count is the highest `cemented` count returned by the `block_count` RPC action of
+//! the trusted reference nodes. The check repeats every `check_interval`, so voting
+//! stops again if the node falls behind.
+//!
+//! Final votes, the second round of voting, are activated by a network once its canary
+//! account is cemented up to the canary height. nano-rs casts no votes yet, in either
+//! round, so whether voting is active and whether final votes are activated only
+//! decide what `diagnostics` reports.
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use nano_lib_rs::keys::PublicKey;
+use nano_lib_rs::network::NetworkParams;
+
+use crate::error::*;
+use crate::store::Store;
+use crate::utils::account_to_address;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VotingConfig {
+    /// How many blocks the store may be behind the network's cemented count
+    pub sync_threshold: u64,
+    /// RPC URLs of trusted nodes whose cemented counts are compared against
+    pub reference_rpc: Vec<String>,
+    /// How often the node's sync is checked
+    pub check_interval: Duration,
+    /// How long to wait for a reference node
+    pub timeout: Duration,
+}
+
+/// Whether the node votes, and if not, why not
+#[derive(Clone, Debug, PartialEq)]
+pub enum VotingStatus {
+    /// `node.enable_voting` isn't set
+    Disabled,
+    /// Sync hasn't been checked yet
+    Starting,
+    /// The store is too far behind the network
+    Syncing { blocks: u64, network_cemented: u64 },
+    /// None of the reference nodes could be asked for their cemented counts
+    NoReference(String),
+    Active,
+}
+
+impl fmt::Display for VotingStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VotingStatus::Disabled => write!(f, "disabled"),
+            VotingStatus::Starting => write!(f, "waiting for the first sync check"),
+            VotingStatus::Syncing { blocks, network_cemented } => {
+                write!(f, "syncing: {} blocks stored, the network has cemented {}", blocks, network_cemented)
+            }
+            VotingStatus::NoReference(ref reason) => write!(f, "network cemented count unknown: {}", reason),
+            VotingStatus::Active => write!(f, "active"),
+        }
+    }
+}
+
+/// Whether a store of `blocks` blocks is within `threshold` of `network_cemented`
+pub fn is_synced(blocks: u64, network_cemented: u64, threshold: u64) -> bool {
+    blocks.saturating_add(threshold) >= network_cemented
+}
+
+/// Whether `network` has activated final votes, which it has once `store` has cemented
+/// the network's canary account up to the canary height
+pub fn final_votes_active(network: &NetworkParams, store: &dyn Store) -> Result<bool> {
+    match network.final_votes_canary {
+        Some((ref account, height)) => Ok(store.account(account)?.map_or(false, |info| info.confirmation_height >= height)),
+        None => Ok(true),
+    }
+}
+
+/// The `cemented` count in the response to a `block_count` RPC action
+fn parse_cemented(response: &Value) -> Result<u64> {
+    if let Some(error) = response.get("error").and_then(|error| error.as_str()) {
+        bail!("Reference node error: {}", error);
+    }
+    response.get("cemented")
+        .and_then(|count| count.as_str())
+        .and_then(|count| count.parse().ok())
+        .ok_or_else(|| Error::from("Reference node response did not contain a cemented count"))
+}
+
+/// The highest cemented count of the reference nodes which respond. Only available
+/// with the `rpc` or `dpow` features.
+#[cfg(any(feature = "rpc", feature = "dpow"))]
+pub fn network_cemented(config: &VotingConfig) -> Result<u64> {
+    let client = reqwest::blocking::Client::builder().timeout(config.timeout).build()?;
+    let mut counts = Vec::new();
+    let mut last_error = None;
+    for url in &config.reference_rpc {
+        let response = client.post(url)
+            .json(&json!({ "action": "block_count" }))
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.json::<Value>())
+            .map_err(Error::from)
+            .and_then(|response| parse_cemented(&response));
+        match response {
+            Ok(count) => counts.push(count),
+            Err(e) => {
+                debug!("Error asking {} for its cemented count: {}", url, e);
+                last_error = Some(format!("{}: {}", url, e));
+            }
+        }
+    }
+    match counts.into_iter().max() {
+        Some(count) => Ok(count),
+        None => bail!(last_error.unwrap_or_else(|| "no reference nodes are configured".into())),
+    }
+}
+
+#[cfg(not(any(feature = "rpc", feature = "dpow")))]
+pub fn network_cemented(_config: &VotingConfig) -> Result<u64> {
+    bail!("Checking sync needs nano-rs to be built with the `rpc` or `dpow` feature")
+}
+
+/// Whether the node votes, and for which account
+#[derive(Debug)]
+pub struct Voting {
+    account: Option<PublicKey>,
+    status: Mutex<VotingStatus>,
+}
+
+impl Voting {
+    /// Voting for the representative `account`, if `enabled`
+    pub fn new(enabled: bool, account: Option<PublicKey>) -> Self {
+        let status = if enabled { VotingStatus::Starting } else { VotingStatus::Disabled };
+        Voting {
+            account,
+            status: Mutex::new(status),
+        }
+    }
+
+    /// The account votes are cast for, if voting is enabled
+    pub fn account(&self) -> Option<&PublicKey> {
+        self.account.as_ref()
+    }
+
+    pub fn status(&self) -> VotingStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Whether votes may be cast now
+    #[cfg(test)]
+    pub fn is_active(&self) -> bool {
+        *self.status.lock().unwrap() == VotingStatus::Active
+    }
+
+    /// Record the outcome of a sync check, logging when voting starts or stops
+    pub fn update(&self, status: VotingStatus) {
+        let mut current = self.status.lock().unwrap();
+        let account = self.account.as_ref().map(account_to_address).unwrap_or_default();
+        if status == VotingStatus::Active && *current != VotingStatus::Active {
+            info!("Voting as {}", account);
+        } else if status != VotingStatus::Active && *current == VotingStatus::Active {
+            warn!("Stopped voting as {}: {}", account, status);
+        } else if status != *current {
+            info!("Not voting yet: {}", status);
+        }
+        *current = status;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nano_lib_rs::block::BlockHash;
+    use crate::store::{AccountInfo, WriteBatch};
+    use crate::store::memory::MemoryStore;
+
+    #[test]
+    fn checks_sync() {
+        assert!(is_synced(1000, 1000, 0));
+        assert!(is_synced(900, 1000, 100));
+        assert!(!is_synced(899, 1000, 100));
+        assert!(is_synced(u64::max_value(), 1000, 100));
+        assert_eq!(parse_cemented(&json!({ "count": "12", "cemented": "10" })).unwrap(), 10);
+        assert!(parse_cemented(&json!({ "error": "Unknown action" })).is_err());
+
+        let voting = Voting::new(false, None);
+        assert_eq!(voting.status(), VotingStatus::Disabled);
+        assert!(!voting.is_active());
+    }
+
+    #[test]
+    fn activates_final_votes_at_the_canary() {
+        let store = MemoryStore::new();
+        let canary = PublicKey::from_bytes(&[1u8; 32]).unwrap();
+        let network = NetworkParams { final_votes_canary: Some((canary.clone(), 2)), ..NetworkParams::dev() };
+        assert!(final_votes_active(&NetworkParams::dev(), &store).unwrap());
+        assert!(!final_votes_active(&network, &store).unwrap());
+
+        for &(confirmation_height, active) in &[(1, false), (2, true)] {
+            let mut batch = WriteBatch::new();
+            batch.put_account(canary.clone(), AccountInfo {
+                head: BlockHash::from_bytes(&[2u8; 32]).unwrap(),
+                representative: canary.clone(),
+                balance: 0,
+                confirmation_height,
+            });
+            store.write(&batch).unwrap();
+            assert_eq!(final_votes_active(&network, &store).unwrap(), active);
+        }
+    }
+}
@@ -0,0 +1,289 @@
+//! Notifying a webhook of sends to watched accounts, before they are received.
+//!
+//! When the block writer commits a state block sending to an account the webhook
+//! watches, a `Receivable` is queued, and POSTed to the webhook as
+//! `{"account": ..., "hash": ..., "source": ..., "amount": ...}` with the amount in
+//! raw. Whether a state block is a send depends on its account's previous balance,
+//! so only sends from accounts whose record was written by a state block are noticed.
+//!
+//! Notifications which fail, because the request errors or the webhook doesn't
+//! respond with a success status, are retried every `retry_interval`, until they
+//! have been attempted `max_attempts` times. Undelivered notifications are persisted
+//! next to the store whenever the queue changes, and loaded again when the node
+//! starts, so that restarts don't lose them.
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::time::{self, Instant};
+
+use nano_lib_rs::block::BlockHash;
+use nano_lib_rs::keys::PublicKey;
+
+use crate::error::*;
+use crate::utils::account_to_address;
+use super::state::State;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// The accounts sends are notified for, or every account if empty
+    pub accounts: Vec<PublicKey>,
+    pub timeout: Duration,
+    pub retry_interval: Duration,
+    pub max_attempts: u32,
+}
+
+/// A send which hasn't been received yet
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Receivable {
+    /// The destination of the send
+    pub account: String,
+    pub hash: String,
+    /// The account which sent it
+    pub source: String,
+    /// The amount sent in raw
+    pub amount: String,
+    /// How many times the notification has failed
+    #[serde(default)]
+    pub attempts: u32,
+}
+
+impl Receivable {
+    pub fn new(account: &PublicKey, hash: BlockHash, source: &PublicKey, amount: u128) -> Self {
+        Receivable {
+            account: account_to_address(account),
+            hash: String::from(hash),
+            source: account_to_address(source),
+            amount: amount.to_string(),
+            attempts: 0,
+        }
+    }
+
+    fn body(&self) -> serde_json::Value {
+        json!({
+            "account": self.account,
+            "hash": self.hash,
+            "source": self.source,
+            "amount": self.amount,
+        })
+    }
+}
+
+/// The file undelivered notifications are persisted to, next to the store at `path`
+pub fn queue_path(path: &Path) -> PathBuf {
+    let mut queue = path.to_owned().into_os_string();
+    queue.push(".webhook");
+    PathBuf::from(queue)
+}
+
+/// The notifications persisted at `path` by a previous run, if any
+fn load(path: &Path) -> Vec<Receivable> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return Vec::new(),
+    };
+    match serde_json::from_reader(BufReader::new(file)) {
+        Ok(queue) => queue,
+        Err(e) => {
+            warn!("Ignoring {}, which could not be read: {}", path.display(), e);
+            Vec::new()
+        }
+    }
+}
+
+/// Persist `queue` to `path`, removing the file once it is empty
+fn save(path: &Path, queue: &[Receivable]) -> Result<()> {
+    if queue.is_empty() {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        return Ok(());
+    }
+    let mut staging = path.to_owned().into_os_string();
+    staging.push(".tmp");
+    serde_json::to_writer(BufWriter::new(File::create(&staging)?), queue)?;
+    fs::rename(&staging, path)?;
+    Ok(())
+}
+
+/// The sends to notify the webhook of, if one is configured
+#[derive(Debug)]
+pub struct Webhook {
+    config: Option<WebhookConfig>,
+    queue: Mutex<VecDeque<Receivable>>,
+    queued: Notify,
+}
+
+impl Webhook {
+    pub fn new(config: Option<WebhookConfig>) -> Self {
+        Webhook {
+            config,
+            queue: Mutex::new(VecDeque::new()),
+            queued: Notify::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.is_some()
+    }
+
+    /// Whether sends to `account` are notified
+    pub fn watches(&self, account: &PublicKey) -> bool {
+        match self.config {
+            Some(ref config) => config.accounts.is_empty() || config.accounts.contains(account),
+            None => false,
+        }
+    }
+
+    /// Queue a notification of `receivable`, if the webhook is enabled
+    pub fn notify(&self, receivable: Receivable) {
+        if self.is_enabled() {
+            self.queue.lock().unwrap().push_back(receivable);
+            self.queued.notify_one();
+        }
+    }
+
+    fn take(&self) -> Vec<Receivable> {
+        self.queue.lock().unwrap().drain(..).collect()
+    }
+}
+
+/// POST each of `batch` to the webhook, returning the notifications which failed.
+/// Only available with the `rpc` or `dpow` features.
+#[cfg(any(feature = "rpc", feature = "dpow"))]
+fn post_all(config: &WebhookConfig, batch: Vec<Receivable>) -> Vec<Receivable> {
+    let client = match reqwest::blocking::Client::builder().timeout(config.timeout).build() {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("Could not create a client for the webhook: {}", e);
+            return batch;
+        }
+    };
+    batch.into_iter()
+        .filter(|receivable| {
+            let result = client.post(&config.url)
+                .json(&receivable.body())
+                .send()
+                .and_then(|response| response.error_for_status());
+            match result {
+                Ok(_) => false,
+                Err(e) => {
+                    debug!("Error notifying the webhook of {}: {}", receivable.hash, e);
+                    true
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(not(any(feature = "rpc", feature = "dpow")))]
+fn post_all(_config: &WebhookConfig, batch: Vec<Receivable>) -> Vec<Receivable> {
+    warn!("Webhooks need nano-rs to be built with the `rpc` or `dpow` feature");
+    batch
+}
+
+/// Persist `queue` to `path`, warning rather than failing if it can't be
+fn persist(path: &Path, queue: &[Receivable]) {
+    if let Err(e) = save(path, queue) {
+        warn!("Could not persist {} webhook notifications to {}: {}", queue.len(), path.display(), e);
+    }
+}
+
+/// Deliver the notifications queued on `state`'s webhook, and those persisted at
+/// `path` by a previous run, until the node stops
+pub async fn deliver(state: Arc<State>, path: PathBuf) {
+    let config = match state.webhook.config {
+        Some(ref config) => config.clone(),
+        None => return,
+    };
+    let mut pending = load(&path);
+    if !pending.is_empty() {
+        info!("Loaded {} undelivered webhook notifications", pending.len());
+    }
+    let mut retry_at = Instant::now();
+    loop {
+        let mut batch = state.webhook.take();
+        let retrying = Instant::now() >= retry_at;
+        if retrying {
+            batch.append(&mut pending);
+            retry_at = Instant::now() + config.retry_interval;
+        }
+        if !batch.is_empty() {
+            let in_flight: Vec<Receivable> = pending.iter().chain(batch.iter()).cloned().collect();
+            persist(&path, &in_flight);
+            let post_config = config.clone();
+            let failed = match tokio::task::spawn_blocking(move || post_all(&post_config, batch)).await {
+                Ok(failed) => failed,
+                Err(_) => {
+                    warn!("Webhook thread exited unexpectedly");
+                    in_flight[pending.len()..].to_vec()
+                }
+            };
+            for mut receivable in failed {
+                receivable.attempts += 1;
+                if receivable.attempts >= config.max_attempts {
+                    warn!("Giving up notifying the webhook of {} after {} attempts", receivable.hash,
+                        receivable.attempts);
+                } else {
+                    pending.push(receivable);
+                }
+            }
+            persist(&path, &pending);
+        }
+        if pending.is_empty() {
+            state.webhook.queued.notified().await;
+        } else {
+            tokio::select! {
+                _ = time::sleep_until(retry_at) => {}
+                _ = state.webhook.queued.notified() => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn persists_the_queue() {
+        let dir = ::std::env::temp_dir().join(format!("nano-rs-webhook-test-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = queue_path(&dir.join("data"));
+        let (account, source) = (PublicKey::from_bytes(&[1u8; 32]).unwrap(), PublicKey::from_bytes(&[2u8; 32]).unwrap());
+        let mut receivable = Receivable::new(&account, BlockHash::from_bytes(&[3u8; 32]).unwrap(), &source, 1000);
+        receivable.attempts = 2;
+
+        save(&path, &[receivable.clone()]).unwrap();
+        assert_eq!(load(&path), vec![receivable]);
+        save(&path, &[]).unwrap();
+        assert!(!path.exists());
+        assert!(load(&path).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn filters_accounts() {
+        let (watched, other) = (PublicKey::from_bytes(&[1u8; 32]).unwrap(), PublicKey::from_bytes(&[2u8; 32]).unwrap());
+        let config = WebhookConfig {
+            url: "http://[::1]:8080/".into(),
+            accounts: vec![watched],
+            timeout: Duration::from_secs(5),
+            retry_interval: Duration::from_secs(30),
+            max_attempts: 10,
+        };
+        let webhook = Webhook::new(Some(config.clone()));
+        assert!(webhook.watches(&watched) && !webhook.watches(&other));
+        assert!(Webhook::new(Some(WebhookConfig { accounts: Vec::new(), ..config })).watches(&other));
+        assert!(!Webhook::new(None).watches(&watched));
+
+        Webhook::new(None).notify(Receivable::new(&watched, BlockHash::from_bytes(&[3u8; 32]).unwrap(), &other, 1));
+        webhook.notify(Receivable::new(&watched, BlockHash::from_bytes(&[3u8; 32]).unwrap(), &other, 1));
+        assert_eq!(webhook.take().len(), 1);
+    }
+}
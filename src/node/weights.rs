@@ -0,0 +1,128 @@
+//! The voting weight delegated to each representative.
+//!
+//! Weights are kept in memory and updated as blocks are written, and the changed
+//! weights are stored in the same transaction as the blocks which changed them, so
+//! that they are loaded at startup rather than recomputed from every account.
+//!
+//! Writing a block moves its account's balance from the representative of the
+//! account's previous record to the representative of its new one. Legacy blocks don't
+//! carry a balance, so the block writer works it out from the account's record and the
+//! amounts sent and received. Ledgers written before legacy blocks were accounted for
+//! are given the weights of their legacy accounts by `store::legacy::record`.
+//!
+//! The funds of a network's burn accounts can never be spent, so they don't count
+//! towards their representatives' weight. Their balances are never added to the
+//! weights, whether as their blocks are written or when weights are computed from the
+//! account records.
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use nano_lib_rs::keys::PublicKey;
+
+use crate::error::*;
+use crate::store::{Store, WriteBatch};
+
+#[derive(Debug, Default)]
+pub struct RepWeights {
+    weights: RwLock<HashMap<[u8; 32], u128>>,
+    /// The accounts whose balances don't count towards their representatives
+    burn_accounts: Vec<PublicKey>,
+}
+
+impl RepWeights {
+    #[cfg(test)]
+    pub fn new(burn_accounts: &[PublicKey]) -> Self {
+        RepWeights { burn_accounts: burn_accounts.to_vec(), ..RepWeights::default() }
+    }
+
+    /// Load the weights stored in `store`
+    pub fn load(store: &dyn Store, burn_accounts: &[PublicKey]) -> Result<Self> {
+        let weights = store.weights()?.into_iter()
+            .filter(|&(_, weight)| weight > 0)
+            .map(|(representative, weight)| (*representative.as_bytes(), weight))
+            .collect();
+        Ok(RepWeights { weights: RwLock::new(weights), burn_accounts: burn_accounts.to_vec() })
+    }
+
+    /// The committed weight of `representative`
+    pub fn weight(&self, representative: &PublicKey) -> u128 {
+        self.weights.read().unwrap().get(representative.as_bytes()).cloned().unwrap_or(0)
+    }
+
+    /// The number of representatives with a weight
+    pub fn len(&self) -> usize {
+        self.weights.read().unwrap().len()
+    }
+
+    /// Add to `batch` the weights after the balance of `account` changes from
+    /// `previous`, a representative and balance if the account has a record, to
+    /// `balance` delegated to `representative`. Burn accounts change no weights.
+    pub fn transfer(&self, batch: &mut WriteBatch, account: &PublicKey, previous: Option<(&PublicKey, u128)>,
+        representative: &PublicKey, balance: u128)
+    {
+        if self.burn_accounts.contains(account) {
+            return;
+        }
+        if let Some((previous, old_balance)) = previous {
+            let weight = batch.weight(previous).unwrap_or_else(|| self.weight(previous));
+            batch.put_weight(previous.clone(), weight.saturating_sub(old_balance));
+        }
+        let weight = batch.weight(representative).unwrap_or_else(|| self.weight(representative));
+        batch.put_weight(representative.clone(), weight.saturating_add(balance));
+    }
+
+    /// Apply the weights of `batch` once it has been committed
+    pub fn apply(&self, batch: &WriteBatch) {
+        let mut weights = self.weights.write().unwrap();
+        for &(ref representative, weight) in batch.weights() {
+            if weight == 0 {
+                weights.remove(representative.as_bytes());
+            } else {
+                weights.insert(*representative.as_bytes(), weight);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moves_balances_between_representatives() {
+        let weights = RepWeights::new(&[]);
+        let first = PublicKey::from_bytes(&[1u8; 32]).unwrap();
+        let second = PublicKey::from_bytes(&[2u8; 32]).unwrap();
+        let mut batch = WriteBatch::new();
+        weights.transfer(&mut batch, &first, None, &first, 100);
+        weights.transfer(&mut batch, &second, None, &first, 50);
+        // Within a batch, later changes build on earlier ones
+        weights.transfer(&mut batch, &first, Some((&first, 100)), &second, 80);
+        assert_eq!(weights.weight(&first), 0);
+        weights.apply(&batch);
+        assert_eq!(weights.weight(&first), 50);
+        assert_eq!(weights.weight(&second), 80);
+
+        batch.clear();
+        weights.transfer(&mut batch, &second, Some((&first, 50)), &second, 50);
+        weights.apply(&batch);
+        assert_eq!(weights.weight(&first), 0);
+        assert_eq!(weights.weight(&second), 130);
+        assert_eq!(weights.len(), 1);
+    }
+
+    #[test]
+    fn leaves_out_burn_accounts() {
+        let (burn, other, representative) = (PublicKey::from_bytes(&[0u8; 32]).unwrap(),
+            PublicKey::from_bytes(&[1u8; 32]).unwrap(), PublicKey::from_bytes(&[2u8; 32]).unwrap());
+        let weights = RepWeights::new(&[burn.clone()]);
+        let mut batch = WriteBatch::new();
+        for &(account, balance) in &[(&burn, 30), (&other, 70)] {
+            weights.transfer(&mut batch, account, None, &representative, balance);
+        }
+        weights.transfer(&mut batch, &burn, Some((&representative, 30)), &other, 20);
+        weights.apply(&batch);
+        assert_eq!(weights.weight(&representative), 70);
+        assert_eq!(weights.weight(&other), 0);
+    }
+}
@@ -0,0 +1,82 @@
+//! Keeps blocks published by this node at a competitive difficulty until they are confirmed.
+//!
+//! nano-rs doesn't confirm blocks itself, so a block is watched until `WATCH_TIMEOUT`
+//! has passed rather than until it is confirmed.
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use indexmap::IndexMap;
+
+use nano_lib_rs::block::{Block, BlockHash, Work};
+
+/// How long a block is watched before giving up on it
+const WATCH_TIMEOUT: u64 = 60 * 30;
+
+#[derive(Debug)]
+struct WatchedBlock {
+    block: Block,
+    added: Instant,
+    regenerating: bool,
+}
+
+/// Local blocks which have been published but not yet confirmed
+#[derive(Debug)]
+pub struct WorkWatcher {
+    blocks: Mutex<IndexMap<BlockHash, WatchedBlock>>,
+}
+
+impl WorkWatcher {
+    pub fn new() -> Self {
+        WorkWatcher {
+            blocks: Mutex::new(IndexMap::new()),
+        }
+    }
+
+    /// Start watching a locally published block. Blocks without a payload or work are ignored.
+    pub fn watch(&self, mut block: Block) -> bool {
+        if block.payload.is_none() || !block.has_work() {
+            return false;
+        }
+        let hash = match block.hash(false) {
+            Ok(hash) => hash,
+            Err(_) => return false,
+        };
+        let watched = WatchedBlock {
+            block,
+            added: Instant::now(),
+            regenerating: false,
+        };
+        self.blocks.lock().unwrap().insert(hash, watched).is_none()
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.lock().unwrap().len()
+    }
+
+    /// Returns the watched blocks whose work no longer meets `threshold`, marking them
+    /// as being regenerated. Blocks which have been watched for too long are dropped.
+    pub fn stale_blocks(&self, threshold: u64) -> Vec<Block> {
+        let mut blocks = self.blocks.lock().unwrap();
+        let timeout = Duration::from_secs(WATCH_TIMEOUT);
+        blocks.retain(|_, watched| watched.added.elapsed() < timeout);
+        blocks.values_mut()
+            .filter(|watched| !watched.regenerating)
+            .filter(|watched| watched.block.work_value().map(|v| v < threshold).unwrap_or(false))
+            .map(|watched| {
+                watched.regenerating = true;
+                watched.block.clone()
+            })
+            .collect()
+    }
+
+    /// Record regenerated work for a watched block. Returns the updated block if it is
+    /// still being watched.
+    pub fn update_work(&self, hash: &BlockHash, work: Option<Work>) -> Option<Block> {
+        let mut blocks = self.blocks.lock().unwrap();
+        let watched = blocks.get_mut(hash)?;
+        watched.regenerating = false;
+        let work = work?;
+        watched.block.work = Some(work);
+        Some(watched.block.clone())
+    }
+}
@@ -0,0 +1,757 @@
+//! Writing processed blocks to the store in batches.
+//!
+//! A transaction per block means a commit, and with LMDB an fsync, per block, so
+//! processed blocks are instead queued for a writer thread which commits them
+//! together once `BatchConfig::max_blocks` have accumulated or the oldest has waited
+//! `BatchConfig::max_delay`.
+//!
+//! The account record and the `RepWeights` are updated with the writing of each block,
+//! keeping the account's confirmation height. State blocks carry their account's
+//! balance; the balance after a legacy block is the record's, less the amount of a
+//! send or plus the pending amount a receive or open block receives. The account of a
+//! legacy block other than an open block is the one indexed for its predecessor.
+//! A block is only written if it follows the latest block of its account's record, or
+//! opens an account without one. Of two blocks with the same predecessor, the first
+//! written is kept and the other is counted as a `fork` and left out. Legacy blocks
+//! which spend more than their account holds, or receive a send which isn't pending
+//! for it, are counted as a `negative_spend` or `unreceivable` and left out.
+//!
+//! A block whose predecessor isn't stored yet, or a receive whose source isn't, is
+//! counted as a `gap_previous` or `gap_source` and kept in the store as unchecked,
+//! keyed by the missing block, rather than written to the ledger. When the missing
+//! block is written, the blocks waiting for it are taken out of the unchecked table in
+//! the same batch and written in turn, or held back again for another missing block.
+//! Unchecked blocks are committed with the batch, so blocks received ahead of their
+//! dependencies survive a restart. Genesis blocks receive from no block, so they wait
+//! for no source.
+//!
+//! A legacy send, or a state block with a lower balance than its account's record, is
+//! a send, which gets a pending entry for its destination, and which the `Webhook` is
+//! notified of once it is committed if it watches the destination. Any other state
+//! block, and legacy open and receive blocks, delete the pending entry of the block
+//! they may receive. Each block written has its height in its chain indexed, one above
+//! its predecessor's.
+//!
+//! nano-rs has no wallets, so the account of the `[signer]` stands in for them: once a
+//! batch moving its frontier is committed, work for its next block is pre-generated.
+use std::fmt;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use nano_lib_rs::block::{Block, BlockHash, BlockKind, BlockPayload, InputHash};
+use nano_lib_rs::network::GENESIS_AMOUNT;
+use nano_lib_rs::keys::PublicKey;
+
+use crate::error::*;
+use crate::store::{AccountInfo, BatchConfig, BlockHeight, PendingInfo, WriteBatch};
+use crate::store::export::previous;
+use crate::stats::{Direction, StatType};
+use crate::utils::{account_to_address, millis};
+use super::state::State;
+use super::webhook::Receivable;
+
+/// Commits blocks to a store in batched transactions on a dedicated thread
+pub struct BlockWriter {
+    queue: Mutex<Option<SyncSender<(BlockHash, Block)>>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl fmt::Debug for BlockWriter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BlockWriter")
+            .field("running", &self.queue.lock().unwrap().is_some())
+            .finish()
+    }
+}
+
+/// Commit `batch`, then notify the webhook of the sends in `receivables`. Returns
+/// whether anything was committed.
+fn commit(batch: &mut WriteBatch, state: &State, receivables: &mut Vec<Receivable>) -> bool {
+    if batch.is_empty() {
+        return false;
+    }
+    let start = Instant::now();
+    let result = state.store.write(batch);
+    let elapsed = millis(start.elapsed());
+    let committed = result.is_ok();
+    match result {
+        Ok(()) => {
+            state.weights.apply(batch);
+            state.stats.inc(StatType::Store, "commits", Direction::Out);
+            state.stats.add(StatType::Store, "blocks_written", Direction::Out, batch.len() as u64);
+            state.stats.observe(StatType::Store, "commit_ms", elapsed);
+            trace!("Committed {} blocks in {}ms", batch.len(), elapsed);
+            for receivable in receivables.drain(..) {
+                state.webhook.notify(receivable);
+            }
+        }
+        Err(e) => {
+            state.stats.inc(StatType::Store, "commit_errors", Direction::Out);
+            error!("Error committing {} blocks to the store: {}", batch.len(), e);
+        }
+    }
+    batch.clear();
+    receivables.clear();
+    committed
+}
+
+/// The work roots of the signer's account before and after `batch`, if `batch` moves
+/// its frontier
+fn signer_roots(batch: &WriteBatch, state: &State) -> Option<(InputHash, InputHash)> {
+    let account = state.signer.as_ref()?.account();
+    let head = batch.account(account)?.head;
+    let previous = match state.store.account(account) {
+        Ok(Some(info)) => info.head.into(),
+        // An open block's work is for the account itself
+        Ok(None) => InputHash::new(*account.as_bytes()),
+        Err(e) => {
+            debug!("Error reading the signer's account, not pre-generating work: {}", e);
+            return None;
+        }
+    };
+    Some((previous, head.into()))
+}
+
+/// Commit `batch` as `commit` does, then pre-generate work for the signer's account if
+/// its frontier moved
+fn flush(batch: &mut WriteBatch, state: &Arc<State>, receivables: &mut Vec<Receivable>) {
+    let roots = signer_roots(batch, state);
+    if commit(batch, state, receivables) {
+        if let Some((previous, frontier)) = roots {
+            State::frontier_changed(state.clone(), Some(previous), frontier);
+        }
+    }
+}
+
+/// Where a block falls on its account's chain as written so far
+enum Position {
+    /// It follows the latest block of the account, whose record is given, or opens an
+    /// account without a record
+    Head(PublicKey, Option<AccountInfo>),
+    /// Its predecessor is stored but already has a successor, or it opens an account
+    /// which is already open
+    Fork(PublicKey),
+    /// Its predecessor isn't stored yet
+    Gap(BlockHash),
+    /// It's a legacy block of a chain which isn't indexed or has no record
+    Unrecorded,
+}
+
+/// The record of `account`, as last written to `batch` or to the store
+fn account_info(batch: &WriteBatch, state: &State, account: &PublicKey) -> Result<Option<AccountInfo>> {
+    match batch.account(account) {
+        Some(info) => Ok(Some(info.clone())),
+        None => state.store.account(account),
+    }
+}
+
+/// Whether the block `hash` is in `batch` or was stored, even if it has been pruned since
+fn is_stored(batch: &WriteBatch, state: &State, hash: &BlockHash) -> Result<bool> {
+    Ok(batch.block(hash).is_some() || state.store.block_exists(hash)? || state.store.is_pruned(hash)?)
+}
+
+/// Where a block of `account` following `previous` falls on the account's chain
+fn position(batch: &WriteBatch, state: &State, account: &PublicKey, previous: &BlockHash) -> Result<Position> {
+    let opens = previous.as_bytes() == &[0u8; 32];
+    Ok(match account_info(batch, state, account)? {
+        Some(info) => {
+            if !opens && info.head == *previous {
+                Position::Head(account.clone(), Some(info))
+            } else if opens || is_stored(batch, state, previous)? {
+                Position::Fork(account.clone())
+            } else {
+                Position::Gap(*previous)
+            }
+        }
+        None if opens => Position::Head(account.clone(), None),
+        // In ledgers written before legacy accounts were kept, a chain begun with legacy
+        // blocks has no record until its first state block
+        None if is_stored(batch, state, previous)? => Position::Head(account.clone(), None),
+        None => Position::Gap(*previous),
+    })
+}
+
+/// Where the legacy `block` falls on its account's chain. The account of a block other
+/// than an open block is that indexed for its predecessor.
+fn legacy_position(batch: &WriteBatch, state: &State, block: &Block) -> Result<Position> {
+    if let Some(BlockPayload::Open { ref account, .. }) = block.payload {
+        return position(batch, state, account, &BlockHash::from_bytes(&[0u8; 32])?);
+    }
+    let previous = match previous(block) {
+        Some(previous) => previous,
+        None => return Ok(Position::Unrecorded),
+    };
+    if !is_stored(batch, state, &previous)? {
+        return Ok(Position::Gap(previous));
+    }
+    let account = match batch.height(&previous) {
+        Some(indexed) => indexed.account.clone(),
+        None => match state.store.height(&previous)? {
+            Some(indexed) => indexed.account,
+            None => return Ok(Position::Unrecorded),
+        },
+    };
+    Ok(match account_info(batch, state, &account)? {
+        Some(ref info) if info.head != previous => Position::Fork(account),
+        Some(info) => Position::Head(account, Some(info)),
+        None => Position::Unrecorded,
+    })
+}
+
+/// The amount of the pending entry of the send `source` to `account`, unless it has
+/// been received
+fn pending_amount(batch: &WriteBatch, state: &State, account: &PublicKey, source: &BlockHash) -> Result<Option<u128>> {
+    if batch.received().iter().any(|&(ref key, ref hash)| key == account && hash == source) {
+        return Ok(None);
+    }
+    let pending = batch.pending().iter().rev().find(|&&(ref key, ref hash, _)| key == account && hash == source);
+    if let Some(&(_, _, ref info)) = pending {
+        return Ok(Some(info.amount));
+    }
+    Ok(state.store.pending(account)?.into_iter().find(|&(ref hash, _)| hash == source).map(|(_, info)| info.amount))
+}
+
+/// Add to `batch` the record of the account of the state block `hash`, which follows
+/// the record `info`, with the representative weights the block moves, and the pending
+/// entry it adds if it is a send or deletes if it is a receive. A send is also added
+/// to `receivables` if the webhook watches its destination.
+fn put_state(batch: &mut WriteBatch, state: &State, hash: BlockHash, block: &Block, info: Option<AccountInfo>,
+    receivables: &mut Vec<Receivable>)
+{
+    let (account, representative, balance, link) = match block.payload {
+        Some(BlockPayload::State { ref account, ref representative, balance, ref link, .. }) => {
+            (account, representative, balance, link)
+        }
+        _ => return,
+    };
+    state.weights.transfer(batch, account, info.as_ref().map(|info| (&info.representative, info.balance)),
+        representative, balance);
+    batch.put_account(account.clone(), AccountInfo {
+        head: hash,
+        representative: representative.clone(),
+        balance,
+        confirmation_height: info.as_ref().map_or(0, |info| info.confirmation_height),
+    });
+    match info {
+        Some(ref info) if balance < info.balance => {
+            if let Ok(destination) = PublicKey::from_bytes(link.as_bytes()) {
+                let amount = info.balance - balance;
+                batch.put_pending(destination.clone(), hash, PendingInfo { source: account.clone(), amount });
+                if state.webhook.watches(&destination) {
+                    receivables.push(Receivable::new(&destination, hash, account, amount));
+                }
+            }
+        }
+        _ => {
+            // Deleting an entry which doesn't exist does nothing, so blocks which turn
+            // out not to be receives are harmless
+            if let Ok(source) = BlockHash::from_bytes(link.as_bytes()) {
+                batch.delete_pending(account.clone(), source);
+            }
+        }
+    }
+}
+
+/// Add to `batch` the record of `account` after the legacy block `hash`, which follows
+/// the record `info`, with the representative weights the block moves, and the pending
+/// entry it adds if it is a send or deletes if it is a receive. A send is also added to
+/// `receivables` if the webhook watches its destination. Returns false, counting the
+/// block, if it receives a send which isn't pending for the account, or sends more
+/// than the account holds.
+fn put_legacy(batch: &mut WriteBatch, state: &State, hash: BlockHash, block: &Block, account: PublicKey,
+    info: Option<AccountInfo>, receivables: &mut Vec<Receivable>) -> Result<bool>
+{
+    let before = info.as_ref().map_or(0, |info| info.balance);
+    let current = info.as_ref().map(|info| info.representative.clone())
+        .ok_or_else(|| Error::from(format!("Legacy block {} follows no account record", String::from(hash))));
+    let (representative, balance) = match block.payload {
+        Some(BlockPayload::Send { ref destination, balance, .. }) => {
+            let amount = match before.checked_sub(balance) {
+                Some(amount) => amount,
+                None => {
+                    state.stats.inc(StatType::Block, "negative_spend", Direction::In);
+                    return Ok(false);
+                }
+            };
+            batch.put_pending(destination.clone(), hash, PendingInfo { source: account.clone(), amount });
+            if state.webhook.watches(destination) {
+                receivables.push(Receivable::new(destination, hash, &account, amount));
+            }
+            (current?, balance)
+        }
+        Some(BlockPayload::Receive { .. }) | Some(BlockPayload::Open { .. }) => {
+            let amount = match source(state, block, None) {
+                Some(source) => match pending_amount(batch, state, &account, &source)? {
+                    Some(amount) => {
+                        batch.delete_pending(account.clone(), source);
+                        amount
+                    }
+                    None => {
+                        state.stats.inc(StatType::Block, "unreceivable", Direction::In);
+                        return Ok(false);
+                    }
+                },
+                // The genesis block creates the network's funds
+                None => GENESIS_AMOUNT,
+            };
+            let representative = match block.payload {
+                Some(BlockPayload::Open { ref representative, .. }) => representative.clone(),
+                _ => current?,
+            };
+            (representative, before.saturating_add(amount))
+        }
+        Some(BlockPayload::Change { ref representative, .. }) => (representative.clone(), before),
+        _ => return Ok(false),
+    };
+    state.weights.transfer(batch, &account, info.as_ref().map(|info| (&info.representative, info.balance)),
+        &representative, balance);
+    batch.put_account(account, AccountInfo {
+        head: hash,
+        representative,
+        balance,
+        confirmation_height: info.as_ref().map_or(0, |info| info.confirmation_height),
+    });
+    Ok(true)
+}
+
+/// The account the send `hash` was made to, if the block is stored
+fn destination(batch: &WriteBatch, state: &State, hash: &BlockHash) -> Result<Option<PublicKey>> {
+    let block = match batch.block(hash) {
+        Some(block) => Some(block.clone()),
+        None => state.store.block(hash)?,
+    };
+    Ok(match block.and_then(|block| block.payload) {
+        Some(BlockPayload::Send { destination, .. }) => Some(destination),
+        Some(BlockPayload::State { link, .. }) => PublicKey::from_bytes(link.as_bytes()).ok(),
+        _ => None,
+    })
+}
+
+/// The block which `block` receives from, if it is a receive whose source is known: a
+/// state block with a higher balance than its account's record `info`, or opening its
+/// account with a balance, or a legacy open or receive block other than the genesis
+/// block
+fn source(state: &State, block: &Block, info: Option<&AccountInfo>) -> Option<BlockHash> {
+    match block.payload {
+        Some(BlockPayload::State { ref previous, balance, ref link, .. }) => {
+            let receives = match info {
+                Some(info) => balance > info.balance,
+                None => previous.as_bytes() == &[0u8; 32] && balance > 0,
+            };
+            if receives { BlockHash::from_bytes(link.as_bytes()).ok() } else { None }
+        }
+        Some(BlockPayload::Open { ref account, source, .. }) => {
+            if account.as_bytes() == state.network.genesis_account.as_bytes() { None } else { Some(source) }
+        }
+        Some(BlockPayload::Receive { source, .. }) => Some(source),
+        _ => None,
+    }
+}
+
+/// The first of a block's `previous` and `source` which isn't stored yet, with the stat
+/// a block missing it is counted under
+fn missing_dependency(batch: &WriteBatch, state: &State, previous: Option<BlockHash>, source: Option<BlockHash>)
+    -> Result<Option<(BlockHash, &'static str)>>
+{
+    for &(dependency, stat) in &[(previous, "gap_previous"), (source, "gap_source")] {
+        if let Some(dependency) = dependency {
+            if !is_stored(batch, state, &dependency)? {
+                return Ok(Some((dependency, stat)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// The account and height of `block`: at height 1 if it opens its account, or one
+/// above its predecessor if that is indexed
+fn height(batch: &WriteBatch, state: &State, block: &Block) -> Result<Option<BlockHeight>> {
+    let previous = match (previous(block), &block.payload) {
+        (Some(previous), _) => previous,
+        (None, &Some(BlockPayload::Open { ref account, .. }))
+        | (None, &Some(BlockPayload::State { ref account, .. })) => {
+            return Ok(Some(BlockHeight { account: account.clone(), height: 1 }));
+        }
+        (None, _) => return Ok(None),
+    };
+    let below = match batch.height(&previous) {
+        Some(below) => Some(below.clone()),
+        None => state.store.height(&previous)?,
+    };
+    Ok(below.map(|below| BlockHeight { account: below.account, height: below.height + 1 }))
+}
+
+/// The blocks waiting for `dependency` in the store or in `batch`, unless they were
+/// taken out of the unchecked table in `batch` already
+fn dependents(batch: &WriteBatch, state: &State, dependency: &BlockHash) -> Result<Vec<(BlockHash, Block)>> {
+    let mut dependents = state.store.unchecked(dependency)?;
+    for &(ref key, hash, ref block) in batch.unchecked() {
+        if key == dependency && dependents.iter().all(|&(ref other, _)| *other != hash) {
+            dependents.push((hash, block.clone()));
+        }
+    }
+    dependents.retain(|&(ref hash, _)| !batch.checked().iter().any(|checked| *checked == (*dependency, *hash)));
+    Ok(dependents)
+}
+
+/// Add `block` to `batch`, along with the changes to its account and to the pending
+/// entries, and to `receivables` if it's a send the webhook watches. Returns whether it
+/// was added. Blocks already written, blocks which fork their account's chain and
+/// legacy blocks which can't be applied are counted and left out; blocks missing a
+/// dependency are counted and added to the batch as unchecked.
+fn put_one(batch: &mut WriteBatch, state: &State, hash: BlockHash, block: Block,
+    receivables: &mut Vec<Receivable>) -> bool
+{
+    match is_stored(batch, state, &hash) {
+        Ok(false) => (),
+        Ok(true) => {
+            state.stats.inc(StatType::Block, "old", Direction::In);
+            return false;
+        }
+        Err(e) => {
+            error!("Error looking up block {}, not writing it: {}", String::from(hash), e);
+            return false;
+        }
+    }
+    let position = match block.payload {
+        Some(BlockPayload::State { ref account, ref previous, .. }) => position(batch, state, account, previous),
+        _ => legacy_position(batch, state, &block),
+    };
+    let (chain, missing) = match position {
+        Ok(Position::Head(account, info)) => {
+            let source = source(state, &block, info.as_ref());
+            (Some((account, info)), missing_dependency(batch, state, None, source))
+        }
+        Ok(Position::Fork(account)) => {
+            debug!("Block {} forks the chain of {}, not writing it", String::from(hash), account_to_address(&account));
+            state.stats.inc(StatType::Block, "fork", Direction::In);
+            return false;
+        }
+        Ok(Position::Gap(previous)) => (None, Ok(Some((previous, "gap_previous")))),
+        Ok(Position::Unrecorded) => (None, missing_dependency(batch, state, None, source(state, &block, None))),
+        Err(e) => (None, Err(e)),
+    };
+    match missing {
+        Ok(None) => (),
+        Ok(Some((dependency, stat))) => {
+            state.stats.inc(StatType::Block, stat, Direction::In);
+            batch.put_unchecked(dependency, hash, block);
+            return false;
+        }
+        Err(e) => {
+            error!("Error reading the dependencies of block {}, not writing it: {}", String::from(hash), e);
+            return false;
+        }
+    }
+    match chain {
+        Some((_, info)) if block.kind == BlockKind::State => put_state(batch, state, hash, &block, info, receivables),
+        Some((account, info)) => match put_legacy(batch, state, hash, &block, account, info, receivables) {
+            Ok(true) => (),
+            Ok(false) => return false,
+            Err(e) => {
+                error!("Error writing the account record of block {}, not writing it: {}", String::from(hash), e);
+                return false;
+            }
+        },
+        // A legacy block of a chain without a record leaves the records untouched
+        None => {
+            if let Some(BlockPayload::Receive { source, .. }) = block.payload {
+                match destination(batch, state, &source) {
+                    Ok(Some(account)) => batch.delete_pending(account, source),
+                    Ok(None) => (),
+                    Err(e) => error!("Error reading the source of a receive, keeping its pending entry: {}", e),
+                }
+            }
+        }
+    }
+    match height(batch, state, &block) {
+        Ok(Some(height)) => batch.put_height(hash, height),
+        Ok(None) => (),
+        Err(e) => error!("Error reading the height of the predecessor of block {}: {}", String::from(hash), e),
+    }
+    batch.put_block(hash, block);
+    true
+}
+
+/// Add `block` to `batch` as `put_one` does, then the unchecked blocks which were
+/// waiting for it, and for those, in turn
+fn put_block(batch: &mut WriteBatch, state: &State, hash: BlockHash, block: Block, receivables: &mut Vec<Receivable>) {
+    let mut written = Vec::new();
+    if put_one(batch, state, hash, block, receivables) {
+        written.push(hash);
+    }
+    while let Some(dependency) = written.pop() {
+        let dependents = match dependents(batch, state, &dependency) {
+            Ok(dependents) => dependents,
+            Err(e) => {
+                error!("Error reading the blocks waiting for {}: {}", String::from(dependency), e);
+                continue;
+            }
+        };
+        for (hash, block) in dependents {
+            batch.delete_unchecked(dependency, hash);
+            if put_one(batch, state, hash, block, receivables) {
+                written.push(hash);
+            }
+        }
+    }
+}
+
+fn run_writer(state: Weak<State>, config: BatchConfig, queue: Receiver<(BlockHash, Block)>) {
+    let mut batch = WriteBatch::new();
+    let mut receivables = Vec::new();
+    let mut deadline = None;
+    loop {
+        let received = match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    Err(RecvTimeoutError::Timeout)
+                } else {
+                    queue.recv_timeout(deadline - now)
+                }
+            }
+            None => queue.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let state = match state.upgrade() {
+            Some(state) => state,
+            None => return,
+        };
+        match received {
+            Ok((hash, block)) => {
+                if batch.is_empty() {
+                    deadline = Some(Instant::now() + config.max_delay);
+                }
+                put_block(&mut batch, &state, hash, block, &mut receivables);
+                if batch.len() < config.max_blocks {
+                    continue;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                flush(&mut batch, &state, &mut receivables);
+                return;
+            }
+        }
+        flush(&mut batch, &state, &mut receivables);
+        deadline = None;
+    }
+}
+
+impl BlockWriter {
+    /// Create a writer which drops blocks until it is started
+    pub fn new() -> Self {
+        BlockWriter {
+            queue: Mutex::new(None),
+            thread: Mutex::new(None),
+        }
+    }
+
+    /// Start committing blocks to `state`'s store
+    pub fn start(&self, state: &Arc<State>, config: BatchConfig) {
+        // Room for a few batches, after which processing waits for the writer
+        let (tx, rx) = mpsc::sync_channel(config.max_blocks * 4);
+        let state = Arc::downgrade(state);
+        let handle = thread::Builder::new()
+            .name("block writer".into())
+            .spawn(move || run_writer(state, config, rx));
+        match handle {
+            Ok(handle) => {
+                *self.queue.lock().unwrap() = Some(tx);
+                *self.thread.lock().unwrap() = Some(handle);
+            }
+            Err(e) => error!("Could not start block writer thread: {}", e),
+        }
+    }
+
+    /// Queue `block` to be written, waiting if the queue is full. Returns false if the
+    /// writer isn't running.
+    pub fn write(&self, hash: BlockHash, block: Block) -> bool {
+        let queue = self.queue.lock().unwrap().clone();
+        match queue {
+            Some(queue) => queue.send((hash, block)).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Commit the blocks already queued and stop the writer thread
+    pub fn stop(&self) {
+        self.queue.lock().unwrap().take();
+        if let Some(handle) = self.thread.lock().unwrap().take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nano_lib_rs::block::{BlockKind, Link};
+    use crate::store::memory::MemoryStore;
+
+    fn state_block(account: u8, previous: BlockHash, balance: u128, link: [u8; 32]) -> (BlockHash, Block) {
+        let mut block = Block::new(BlockKind::State, Some(BlockPayload::State {
+            account: PublicKey::from_bytes(&[account; 32]).unwrap(),
+            previous,
+            representative: PublicKey::from_bytes(&[account; 32]).unwrap(),
+            balance,
+            link: Link::Unknown(link),
+        }), None, None);
+        (block.hash(false).unwrap(), block)
+    }
+
+    /// Write `blocks` in order and commit them in one batch, as the writer thread does
+    fn write(state: &State, blocks: &[(BlockHash, Block)]) {
+        let (mut batch, mut receivables) = (WriteBatch::new(), Vec::new());
+        for &(hash, ref block) in blocks {
+            put_block(&mut batch, state, hash, block.clone(), &mut receivables);
+        }
+        commit(&mut batch, state, &mut receivables);
+    }
+
+    fn key(byte: u8) -> PublicKey {
+        PublicKey::from_bytes(&[byte; 32]).unwrap()
+    }
+
+    /// The network's genesis block, which receives from no block, for the blocks of a
+    /// test to receive from
+    fn genesis(state: &State) -> (BlockHash, Block) {
+        let account = state.network.genesis_account.clone();
+        let mut block = Block::new(BlockKind::Open, Some(BlockPayload::Open {
+            source: BlockHash::from_bytes(account.as_bytes()).unwrap(),
+            representative: account.clone(),
+            account,
+        }), None, None);
+        (block.hash(false).unwrap(), block)
+    }
+
+    #[test]
+    fn keeps_the_first_of_forked_blocks() {
+        let state = State::for_tests(Arc::new(MemoryStore::new()));
+        let zero = BlockHash::from_bytes(&[0u8; 32]).unwrap();
+        let genesis = genesis(&state);
+        let open = state_block(1, zero, 100, *genesis.0.as_bytes());
+        let send = state_block(1, open.0, 60, [2u8; 32]);
+        // Forks of the send in the same batch and in a later one, and of the open block
+        let forks = [state_block(1, open.0, 10, [3u8; 32]), state_block(1, open.0, 20, [4u8; 32]),
+            state_block(1, zero, 5, [5u8; 32])];
+        write(&state, &[genesis, open.clone(), send.clone(), forks[0].clone()]);
+        write(&state, &forks[1..]);
+
+        for &(ref hash, _) in &forks {
+            assert!(state.store.block(hash).unwrap().is_none());
+        }
+        assert_eq!(state.stats.count(StatType::Block, "fork", Direction::In), 3);
+        let info = state.store.account(&key(1)).unwrap().unwrap();
+        assert_eq!((info.head, info.balance), (send.0, 60));
+        assert_eq!(state.weights.weight(&key(1)), 60);
+        assert_eq!(state.store.pending(&key(2)).unwrap()[0].1.amount, 40);
+        for destination in 3..6 {
+            assert!(state.store.pending(&key(destination)).unwrap().is_empty());
+        }
+    }
+
+    fn legacy_block(kind: BlockKind, payload: BlockPayload) -> (BlockHash, Block) {
+        let mut block = Block::new(kind, Some(payload), None, None);
+        (block.hash(false).unwrap(), block)
+    }
+
+    #[test]
+    fn accounts_for_legacy_blocks() {
+        let state = State::for_tests(Arc::new(MemoryStore::new()));
+        let genesis = genesis(&state);
+        let account = state.network.genesis_account.clone();
+        let send = legacy_block(BlockKind::Send, BlockPayload::Send {
+            previous: genesis.0,
+            destination: key(2),
+            balance: GENESIS_AMOUNT - 50,
+        });
+        let open = legacy_block(BlockKind::Open, BlockPayload::Open { source: send.0, representative: key(3), account: key(2) });
+        // The send has been received by the open block already, and the account only
+        // holds 50
+        let receive = legacy_block(BlockKind::Receive, BlockPayload::Receive { previous: open.0, source: send.0 });
+        let overspend = legacy_block(BlockKind::Send, BlockPayload::Send {
+            previous: open.0,
+            destination: key(4),
+            balance: 60,
+        });
+        let change = legacy_block(BlockKind::Change, BlockPayload::Change { previous: open.0, representative: key(5) });
+        write(&state, &[genesis.clone(), send.clone(), open.clone(), receive.clone(), overspend.clone(),
+            change.clone()]);
+
+        assert_eq!(state.stats.count(StatType::Block, "unreceivable", Direction::In), 1);
+        assert_eq!(state.stats.count(StatType::Block, "negative_spend", Direction::In), 1);
+        assert!(state.store.block(&receive.0).unwrap().is_none());
+        assert!(state.store.block(&overspend.0).unwrap().is_none());
+        let info = state.store.account(&account).unwrap().unwrap();
+        assert_eq!((info.head, info.balance), (send.0, GENESIS_AMOUNT - 50));
+        let info = state.store.account(&key(2)).unwrap().unwrap();
+        assert_eq!((info.head, info.representative, info.balance), (change.0, key(5), 50));
+        assert!(state.store.pending(&key(2)).unwrap().is_empty());
+        assert_eq!(state.weights.weight(&account), GENESIS_AMOUNT - 50);
+        assert_eq!(state.weights.weight(&key(3)), 0);
+        assert_eq!(state.weights.weight(&key(5)), 50);
+        assert_eq!(state.store.height(&change.0).unwrap(), Some(BlockHeight { account: key(2), height: 2 }));
+    }
+
+    #[test]
+    fn holds_back_blocks_until_their_dependencies_arrive() {
+        let state = State::for_tests(Arc::new(MemoryStore::new()));
+        let genesis = genesis(&state);
+        let open = state_block(1, BlockHash::from_bytes(&[0u8; 32]).unwrap(), 100, *genesis.0.as_bytes());
+        let send = state_block(1, open.0, 60, [2u8; 32]);
+        // Against the open block, this would look like a send of 20 rather than the
+        // receive of 20 it is
+        let receive = state_block(1, send.0, 80, *genesis.0.as_bytes());
+        write(&state, &[open.clone(), receive.clone()]);
+
+        assert!(state.store.block(&open.0).unwrap().is_none());
+        assert!(state.store.block(&receive.0).unwrap().is_none());
+        assert_eq!(state.stats.count(StatType::Block, "gap_source", Direction::In), 1);
+        assert_eq!(state.stats.count(StatType::Block, "gap_previous", Direction::In), 1);
+        assert_eq!(state.store.unchecked(&genesis.0).unwrap()[0].0, open.0);
+        assert_eq!(state.store.unchecked(&send.0).unwrap()[0].0, receive.0);
+        assert!(state.store.account(&key(1)).unwrap().is_none());
+
+        // The unchecked blocks are written once the genesis block arrives, up to the
+        // receive, which waits for the send
+        write(&state, &[genesis.clone()]);
+        assert_eq!(state.store.unchecked_count().unwrap(), 1);
+        assert_eq!(state.store.account(&key(1)).unwrap().unwrap().head, open.0);
+
+        write(&state, &[send.clone(), send.clone()]);
+        assert_eq!(state.stats.count(StatType::Block, "old", Direction::In), 1);
+        assert_eq!(state.store.unchecked_count().unwrap(), 0);
+        let info = state.store.account(&key(1)).unwrap().unwrap();
+        assert_eq!((info.head, info.balance), (receive.0, 80));
+        assert_eq!(state.store.height(&receive.0).unwrap(), Some(BlockHeight { account: key(1), height: 3 }));
+        assert_eq!(state.store.pending(&key(2)).unwrap()[0].1.amount, 40);
+        assert_eq!(state.weights.weight(&key(1)), 80);
+    }
+
+    #[test]
+    fn finds_the_roots_of_the_signer_frontier() {
+        use nano_lib_rs::keys::SecretKey;
+        use crate::signer::{KeySigner, Signer};
+
+        let mut state = State::for_tests(Arc::new(MemoryStore::new()));
+        let mut batch = WriteBatch::new();
+        let info = |head| AccountInfo { head, representative: key(1), balance: 0, confirmation_height: 0 };
+        let open = BlockHash::from_bytes(&[1u8; 32]).unwrap();
+        let signer = KeySigner::new(SecretKey::from_bytes(&[7u8; 32]).unwrap());
+        let account = signer.account().clone();
+        batch.put_account(account.clone(), info(open));
+        assert!(signer_roots(&batch, &state).is_none());
+
+        state.signer = Some(Arc::new(signer));
+        assert_eq!(signer_roots(&batch, &state), Some((InputHash::new(*account.as_bytes()), open.into())));
+        state.store.write(&batch).unwrap();
+        let mut batch = WriteBatch::new();
+        assert!(signer_roots(&batch, &state).is_none());
+        let next = BlockHash::from_bytes(&[2u8; 32]).unwrap();
+        batch.put_account(account, info(next));
+        assert_eq!(signer_roots(&batch, &state), Some((open.into(), next.into())));
+    }
+}
@@ -0,0 +1,210 @@
+//! `nano:` payment request URIs, QR codes encoding them, and the deposit accounts
+//! payments are made to.
+//!
+//! A payment request names the account to pay and optionally an amount in raw, a
+//! label for the recipient and a message, following the URI scheme wallets scan:
+//! `nano:nano_1...?amount=1000000&label=Shop`. Rendering QR codes needs the `qr`
+//! feature.
+//!
+//! A `DepositPool` hands out the configured deposit accounts, one to each payment in
+//! progress, and takes them back when the payment ends. nano-rs keeps no wallets, so
+//! the accounts are only watched: a payment has been received once the account's
+//! stored balance has grown by its amount since the payment began, which happens as
+//! the wallet holding the account's key receives the sends to it.
+use std::sync::Mutex;
+
+use nano_lib_rs::amount::Amount;
+use nano_lib_rs::keys::PublicKey;
+
+use crate::error::*;
+use crate::store::Store;
+use crate::utils::account_to_address;
+
+/// The image format a QR code is rendered in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QrFormat {
+    Png,
+    Svg,
+}
+
+impl QrFormat {
+    pub fn from_name(name: &str) -> Option<QrFormat> {
+        match name.to_lowercase().as_str() {
+            "png" => Some(QrFormat::Png),
+            "svg" => Some(QrFormat::Svg),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PaymentRequest {
+    pub account: PublicKey,
+    pub amount: Option<Amount>,
+    pub label: Option<String>,
+    pub message: Option<String>,
+}
+
+/// Percent-encode everything but the characters unreserved in URIs
+fn encode_component(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+impl PaymentRequest {
+    pub fn new(account: PublicKey) -> Self {
+        PaymentRequest {
+            account,
+            amount: None,
+            label: None,
+            message: None,
+        }
+    }
+
+    /// The `nano:` URI of this request
+    pub fn uri(&self) -> String {
+        let mut params = Vec::new();
+        if let Some(amount) = self.amount {
+            params.push(format!("amount={}", amount));
+        }
+        if let Some(ref label) = self.label {
+            params.push(format!("label={}", encode_component(label)));
+        }
+        if let Some(ref message) = self.message {
+            params.push(format!("message={}", encode_component(message)));
+        }
+        let mut uri = format!("nano:{}", account_to_address(&self.account));
+        if !params.is_empty() {
+            uri.push('?');
+            uri.push_str(&params.join("&"));
+        }
+        uri
+    }
+
+    /// A QR code of this request's URI, rendered in `format`
+    #[cfg(feature = "qr")]
+    pub fn qr_code(&self, format: QrFormat) -> Result<Vec<u8>> {
+        use image::Luma;
+        use image::png::PngEncoder;
+        use qrcode::QrCode;
+        use qrcode::render::svg;
+
+        let code = QrCode::new(self.uri().as_bytes())?;
+        match format {
+            QrFormat::Svg => Ok(code.render::<svg::Color>().min_dimensions(256, 256).build().into_bytes()),
+            QrFormat::Png => {
+                let image = code.render::<Luma<u8>>().min_dimensions(256, 256).build();
+                let mut png = Vec::new();
+                PngEncoder::new(&mut png).encode(&image, image.width(), image.height(), image::ColorType::L8)?;
+                Ok(png)
+            }
+        }
+    }
+
+    #[cfg(not(feature = "qr"))]
+    pub fn qr_code(&self, _format: QrFormat) -> Result<Vec<u8>> {
+        bail!("QR codes need nano-rs to be built with the `qr` feature")
+    }
+}
+
+#[derive(Debug)]
+struct Deposit {
+    account: PublicKey,
+    /// The balance of the account when the payment in progress began, if there is one
+    started_at: Option<u128>,
+}
+
+/// The deposit accounts payments are made to, each used by one payment at a time
+#[derive(Debug)]
+pub struct DepositPool {
+    deposits: Mutex<Vec<Deposit>>,
+}
+
+fn balance(store: &dyn Store, account: &PublicKey) -> Result<u128> {
+    Ok(store.account(account)?.map_or(0, |info| info.balance))
+}
+
+impl DepositPool {
+    pub fn new(accounts: Vec<PublicKey>) -> Self {
+        DepositPool {
+            deposits: Mutex::new(accounts.into_iter().map(|account| Deposit { account, started_at: None }).collect()),
+        }
+    }
+
+    /// Begin a payment to the first account which isn't in use, noting its balance
+    /// in `store`. Fails if every account is in use.
+    pub fn begin(&self, store: &dyn Store) -> Result<PublicKey> {
+        let mut deposits = self.deposits.lock().unwrap();
+        if deposits.is_empty() {
+            return Err(ErrorKind::RpcError("No deposit accounts are configured in payment.deposit_accounts".into()).into());
+        }
+        let deposit = deposits.iter_mut()
+            .find(|deposit| deposit.started_at.is_none())
+            .ok_or_else(|| Error::from(ErrorKind::RpcError("Every deposit account is in use".into())))?;
+        deposit.started_at = Some(balance(store, &deposit.account)?);
+        Ok(deposit.account)
+    }
+
+    /// The raw received by `account` in `store` since its payment began
+    pub fn received(&self, store: &dyn Store, account: &PublicKey) -> Result<u128> {
+        let started_at = self.deposits.lock().unwrap().iter()
+            .find(|deposit| deposit.account == *account)
+            .and_then(|deposit| deposit.started_at)
+            .ok_or_else(|| Error::from(ErrorKind::RpcError("No payment to this account is in progress".into())))?;
+        Ok(balance(store, account)?.saturating_sub(started_at))
+    }
+
+    /// End the payment to `account`, so that it can be handed out again
+    pub fn end(&self, account: &PublicKey) -> Result<()> {
+        let mut deposits = self.deposits.lock().unwrap();
+        match deposits.iter_mut().find(|deposit| deposit.account == *account && deposit.started_at.is_some()) {
+            Some(deposit) => {
+                deposit.started_at = None;
+                Ok(())
+            }
+            None => Err(ErrorKind::RpcError("No payment to this account is in progress".into()).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::MemoryStore;
+
+    #[test]
+    fn encodes_uris() {
+        let account = PublicKey::from_bytes(&[0u8; 32]).unwrap();
+        let mut request = PaymentRequest::new(account);
+        let address = "nano_1111111111111111111111111111111111111111111111111111hifc8npp";
+        assert_eq!(request.uri(), format!("nano:{}", address));
+
+        request.amount = Some(Amount(1000));
+        request.label = Some("Coffee & cake".to_owned());
+        request.message = Some("Order #12".to_owned());
+        assert_eq!(request.uri(),
+            format!("nano:{}?amount=1000&label=Coffee%20%26%20cake&message=Order%20%2312", address));
+    }
+
+    #[test]
+    fn hands_out_each_deposit_account_once() {
+        let (first, second) = (PublicKey::from_bytes(&[1u8; 32]).unwrap(), PublicKey::from_bytes(&[2u8; 32]).unwrap());
+        let store = MemoryStore::new();
+        let pool = DepositPool::new(vec![first, second]);
+        assert_eq!(pool.begin(&store).unwrap(), first);
+        assert_eq!(pool.begin(&store).unwrap(), second);
+        assert!(pool.begin(&store).is_err());
+        assert_eq!(pool.received(&store, &first).unwrap(), 0);
+
+        pool.end(&first).unwrap();
+        assert!(pool.end(&first).is_err());
+        assert!(pool.received(&store, &first).is_err());
+        assert_eq!(pool.begin(&store).unwrap(), first);
+    }
+}
@@ -0,0 +1,1139 @@
+use serde_json::Value;
+
+use std::cmp;
+use std::net::Ipv6Addr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use data_encoding::{BASE64, HEXUPPER};
+use nano_lib_rs::amount::{Amount, Unit};
+use nano_lib_rs::block::{Block, BlockHash, BlockKind, BlockPayload, InputHash, Link, Work};
+use nano_lib_rs::keys::{self, PublicKey, SecretKey, Signature};
+use nano_lib_rs::message::NetworkKind;
+use nano_lib_rs::network::{self, GENESIS_AMOUNT};
+use nanopow_rs::difficulty::{self, BlockDetails, Epoch};
+
+use crate::node::{self, epoch, exclusions, memory, republish, voting};
+use crate::node::exclusions::Exclusion;
+use crate::node::state::State;
+use crate::payment::{PaymentRequest, QrFormat};
+use crate::store::{self, checksum, Store};
+use crate::stats::HISTOGRAM_BUCKETS;
+use crate::utils::{account_to_address, address_to_account, is_valid_address, millis, parse_public_key};
+use crate::error::*;
+use super::{fail, RpcResult};
+
+/// How often `payment_wait` checks the balance of the deposit account
+const PAYMENT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The most blocks `republish` publishes at once
+const MAX_REPUBLISHED: usize = 1024;
+
+/// The most accounts `epoch_upgrade` upgrades at once
+const MAX_EPOCH_UPGRADES: usize = 1024;
+
+fn invalid(field: &str) -> Error {
+    ErrorKind::RpcError(format!("Bad or missing field `{}`", field)).into()
+}
+
+fn get_str<'a>(request: &'a Value, field: &str) -> Result<&'a str> {
+    request.get(field).and_then(Value::as_str).ok_or_else(|| invalid(field))
+}
+
+fn parse_root(request: &Value, field: &str) -> Result<InputHash> {
+    InputHash::from_hex(get_str(request, field)?.to_uppercase()).map_err(|_| invalid(field))
+}
+
+fn parse_hash(request: &Value, field: &str) -> Result<BlockHash> {
+    BlockHash::from_hex(get_str(request, field)?.to_uppercase()).map_err(|_| invalid(field))
+}
+
+fn parse_account(request: &Value, field: &str) -> Result<PublicKey> {
+    address_to_account(get_str(request, field)?).map_err(|_| invalid(field))
+}
+
+fn parse_hex(request: &Value, field: &str) -> Result<Vec<u8>> {
+    HEXUPPER.decode(get_str(request, field)?.to_uppercase().as_bytes()).map_err(|_| invalid(field))
+}
+
+fn parse_seed(request: &Value, field: &str) -> Result<[u8; keys::SEED_LENGTH]> {
+    let bytes = parse_hex(request, field)?;
+    if bytes.len() != keys::SEED_LENGTH {
+        return Err(invalid(field));
+    }
+    let mut seed = [0u8; keys::SEED_LENGTH];
+    seed.copy_from_slice(&bytes);
+    Ok(seed)
+}
+
+/// Actions handling seeds and private keys are refused unless `rpc.enable_key_derivation`
+/// is set
+fn check_key_derivation(state: &State) -> Result<()> {
+    if !state.config.rpc().enable_key_derivation {
+        return Err(ErrorKind::RpcError("Key derivation is disabled; set rpc.enable_key_derivation to allow \
+            actions handling seeds and private keys".into()).into());
+    }
+    Ok(())
+}
+
+fn key_response(secret: &SecretKey) -> Value {
+    let public = keys::public_key_from_secret(secret);
+    json!({
+        "private": HEXUPPER.encode(secret.as_bytes()),
+        "public": HEXUPPER.encode(public.as_bytes()),
+        "account": account_to_address(&public),
+    })
+}
+
+fn parse_amount(request: &Value, field: &str, unit: Unit) -> Result<Amount> {
+    Amount::parse(get_str(request, field)?, unit).map_err(|_| invalid(field))
+}
+
+fn parse_u64(request: &Value, field: &str) -> Result<u64> {
+    get_str(request, field)?.parse().map_err(|_| invalid(field))
+}
+
+/// The flag `field`, given as `true` or `"true"`, or `default` if it's missing
+fn get_flag(request: &Value, field: &str, default: bool) -> Result<bool> {
+    match request.get(field) {
+        None => Ok(default),
+        Some(&Value::Bool(flag)) => Ok(flag),
+        Some(&Value::String(ref flag)) if flag == "true" => Ok(true),
+        Some(&Value::String(ref flag)) if flag == "false" => Ok(false),
+        Some(_) => Err(invalid(field)),
+    }
+}
+
+fn parse_work(request: &Value, field: &str) -> Result<Work> {
+    Work::from_hex(get_str(request, field)?.to_lowercase()).map_err(|_| invalid(field))
+}
+
+fn parse_difficulty_hex(value: &str, field: &str) -> Result<u64> {
+    difficulty::from_hex(value).ok_or_else(|| invalid(field))
+}
+
+fn parse_multiplier(request: &Value, field: &str) -> Result<f64> {
+    let multiplier: f64 = get_str(request, field)?
+        .parse()
+        .map_err(|_| invalid(field))?;
+    if !(multiplier > 0.0 && multiplier.is_finite()) {
+        return Err(invalid(field));
+    }
+    Ok(multiplier)
+}
+
+/// The base threshold a multiplier is relative to: given directly as `base`, derived from
+/// `epoch` and `subtype`, or the network minimum by default
+fn parse_base(request: &Value, state: &State) -> Result<u64> {
+    if request.get("base").is_some() {
+        return parse_difficulty_hex(get_str(request, "base")?, "base");
+    }
+    if request.get("epoch").is_none() {
+        return Ok(state.difficulty.base());
+    }
+    let epoch = match get_str(request, "epoch")? {
+        "1" => Epoch::One,
+        "2" => Epoch::Two,
+        _ => return Err(invalid("epoch")),
+    };
+    let details = match request.get("subtype").and_then(Value::as_str).unwrap_or("send") {
+        "send" | "change" => BlockDetails::Send,
+        "receive" | "open" => BlockDetails::Receive,
+        "epoch" => BlockDetails::Epoch,
+        _ => return Err(invalid("subtype")),
+    };
+    Ok(difficulty::threshold(epoch, details))
+}
+
+/// The requested difficulty, either given directly or as a multiplier of the network
+/// minimum. Defaults to the active difficulty if neither is provided.
+fn parse_difficulty(request: &Value, state: &State) -> Result<u64> {
+    if request.get("difficulty").is_some() {
+        return parse_difficulty_hex(get_str(request, "difficulty")?, "difficulty");
+    }
+    if request.get("multiplier").is_some() {
+        let multiplier = parse_multiplier(request, "multiplier")?;
+        return Ok(difficulty::from_multiplier(multiplier, state.difficulty.base()));
+    }
+    Ok(state.difficulty.active_difficulty())
+}
+
+fn format_difficulty(value: u64) -> String {
+    difficulty::to_hex(value)
+}
+
+pub fn active_difficulty(_request: Value, state: Arc<State>) -> Result<Value> {
+    let difficulty = &state.difficulty;
+    Ok(json!({
+        "network_minimum": format_difficulty(difficulty.base()),
+        "network_current": format_difficulty(difficulty.active_difficulty()),
+        "multiplier": format!("{}", difficulty.multiplier()),
+    }))
+}
+
+/// Counters, samples or container sizes, chosen with `type`
+pub fn stats(request: Value, state: Arc<State>) -> Result<Value> {
+    let stats = &state.stats;
+    let created = stats.created().format("%Y.%m.%d %H:%M:%S").to_string();
+    match get_str(&request, "type")? {
+        "counters" => {
+            let entries: Vec<Value> = stats.counters().into_iter()
+                .map(|counter| json!({
+                    "time": counter.updated.format("%H:%M:%S").to_string(),
+                    "type": counter.stat_type.name(),
+                    "detail": counter.detail,
+                    "dir": counter.direction.name(),
+                    "value": counter.value.to_string(),
+                }))
+                .collect();
+            Ok(json!({ "type": "counters", "created": created, "entries": entries }))
+        }
+        "samples" => {
+            let entries: Vec<Value> = stats.samples().into_iter()
+                .map(|sample| {
+                    let values: Vec<Value> = sample.values.into_iter()
+                        .map(|(time, value)| json!({
+                            "time": time.format("%H:%M:%S").to_string(),
+                            "value": value.to_string(),
+                        }))
+                        .collect();
+                    json!({
+                        "type": sample.stat_type.name(),
+                        "detail": sample.detail,
+                        "values": values,
+                    })
+                })
+                .collect();
+            Ok(json!({ "type": "samples", "created": created, "entries": entries }))
+        }
+        "histograms" => {
+            let entries: Vec<Value> = stats.histograms().into_iter()
+                .map(|(stat_type, detail, histogram)| {
+                    let bounds = HISTOGRAM_BUCKETS.iter().map(|bound| bound.to_string())
+                        .chain(Some("inf".to_owned()));
+                    let buckets: Vec<Value> = bounds.zip(histogram.buckets.iter())
+                        .map(|(le, count)| json!({ "le": le, "count": count.to_string() }))
+                        .collect();
+                    let percentile = |p| histogram.percentile(p).map(|ms| ms.to_string());
+                    json!({
+                        "type": stat_type.name(),
+                        "detail": detail,
+                        "count": histogram.count.to_string(),
+                        "sum": histogram.sum.to_string(),
+                        "p50": percentile(50.0),
+                        "p90": percentile(90.0),
+                        "p99": percentile(99.0),
+                        "buckets": buckets,
+                    })
+                })
+                .collect();
+            Ok(json!({ "type": "histograms", "created": created, "entries": entries }))
+        }
+        "peers" => {
+            let entries: Vec<Value> = state.peers.read().unwrap().iter()
+                .map(|(addr, info)| {
+                    let (bytes_in, bytes_out) = info.traffic();
+                    json!({
+                        "peer": format!("{}", addr),
+                        "in": bytes_in.to_string(),
+                        "out": bytes_out.to_string(),
+                    })
+                })
+                .collect();
+            Ok(json!({ "type": "peers", "created": created, "entries": entries }))
+        }
+        "objects" => Ok(json!({
+            "peers": state.peer_count().to_string(),
+            "inactive_peers": state.inactive_peers.read().unwrap().len().to_string(),
+            "work_cache": state.work.cached_count().to_string(),
+            "work_active": state.work.active_count().to_string(),
+            "work_watcher": state.work_watcher.len().to_string(),
+        })),
+        _ => Err(invalid("type")),
+    }
+}
+
+/// The stored record of `account`: its latest block, representative, balance in raw
+/// and confirmation height
+pub fn account_info(request: Value, state: Arc<State>) -> Result<Value> {
+    let account = parse_account(&request, "account")?;
+    match state.store.account(&account)? {
+        Some(info) => Ok(json!({
+            "frontier": String::from(info.head),
+            "representative": account_to_address(&info.representative),
+            "balance": info.balance.to_string(),
+            "confirmation_height": info.confirmation_height.to_string(),
+        })),
+        None => Err(ErrorKind::RpcError("Account not found".into()).into()),
+    }
+}
+
+/// The stored block `hash`, with its kind and wire encoding. Pruned blocks are reported
+/// as such rather than as unknown.
+pub fn block_info(request: Value, state: Arc<State>) -> Result<Value> {
+    let hash = parse_hash(&request, "hash")?;
+    match state.store.block(&hash)? {
+        Some(block) => Ok(json!({
+            "kind": format!("{:?}", block.kind).to_lowercase(),
+            "contents": HEXUPPER.encode(&block.serialize_bytes()),
+        })),
+        None if state.store.is_pruned(&hash)? => {
+            Err(ErrorKind::RpcError("Block was pruned, and is only available from a full node".into()).into())
+        }
+        None => Err(ErrorKind::RpcError("Block not found".into()).into()),
+    }
+}
+
+/// Publish the stored block `hash` to every peer again, followed by at most
+/// `count - 1` of its successors, returning the hashes of the blocks published. The
+/// chain is found on a blocking thread, following it back from its latest block.
+pub fn republish(request: Value, state: Arc<State>) -> RpcResult {
+    let parsed = parse_hash(&request, "hash").and_then(|hash| {
+        let count = match request.get("count") {
+            Some(_) => parse_u64(&request, "count")? as usize,
+            None => MAX_REPUBLISHED,
+        };
+        Ok((hash, cmp::min(count, MAX_REPUBLISHED)))
+    });
+    let (hash, count) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return fail(format!("{}", e)),
+    };
+    Box::pin(async move {
+        let store = state.store.clone();
+        let chain = tokio::task::spawn_blocking(move || republish::chain_from(&*store, &hash, count))
+            .await
+            .map_err(|_| Error::from("Republish thread exited unexpectedly"))??;
+        let hashes: Vec<String> = chain.iter().map(|&(hash, _)| String::from(hash)).collect();
+        state.republisher.republish(chain.into_iter().map(|(_, block)| block).collect());
+        Ok(json!({ "success": "", "blocks": hashes }))
+    })
+}
+
+/// Change the representative of the signer's `account` to `representative`, publishing
+/// a state block signed by the signer, with `work` if given or work generated at the
+/// active difficulty. Returns the hash of the block.
+pub fn account_representative_set(request: Value, state: Arc<State>) -> RpcResult {
+    let parsed = parse_account(&request, "account").and_then(|account| {
+        let representative = parse_account(&request, "representative")?;
+        let work = match request.get("work") {
+            Some(_) => Some(parse_work(&request, "work")?),
+            None => None,
+        };
+        let signer = match state.signer {
+            Some(ref signer) if *signer.account() == account => signer.clone(),
+            _ => return Err(ErrorKind::RpcError("nano-rs keeps no wallets, and only signs for the [signer] account"
+                .into()).into()),
+        };
+        Ok((account, representative, work, signer))
+    });
+    let (account, representative, work, signer) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return fail(format!("{}", e)),
+    };
+    Box::pin(async move {
+        let (store, key) = (state.store.clone(), account.clone());
+        let info = tokio::task::spawn_blocking(move || store.account(&key))
+            .await
+            .map_err(|_| Error::from("Account lookup thread exited unexpectedly"))??
+            .ok_or_else(|| Error::from(ErrorKind::RpcError("Account not found".into())))?;
+        let payload = BlockPayload::State {
+            account,
+            previous: info.head,
+            representative,
+            balance: info.balance,
+            link: Link::Unknown([0u8; 32]),
+        };
+        let root = payload.work_source();
+        let work = match work {
+            Some(work) => work,
+            None => State::generate_work_async(state.clone(), root, state.difficulty.active_difficulty()).await?
+                .ok_or_else(|| Error::from(ErrorKind::RpcError("Cancelled".into())))?,
+        };
+        let mut block = Block::new(BlockKind::State, Some(payload), None, Some(work));
+        let hash = block.hash(false)?;
+        block.signature = Some(tokio::task::spawn_blocking(move || signer.sign(hash.as_bytes()))
+            .await
+            .map_err(|_| Error::from("Signing thread exited unexpectedly"))??);
+        let hash = node::handler::publish_local(block, &state)?;
+        Ok(json!({ "block": String::from(hash) }))
+    })
+}
+
+/// Upgrade up to `count` accounts, at most `MAX_EPOCH_UPGRADES` at once, to `epoch`,
+/// publishing an epoch block for each signed with `key`, the private key of the epoch's
+/// signer. On the dev network the published key of its genesis account is used unless
+/// `key` is given. Returns the hashes of the blocks.
+pub fn epoch_upgrade(request: Value, state: Arc<State>) -> RpcResult {
+    let parsed = get_str(&request, "epoch").and_then(|version| {
+        let version: u8 = version.parse().map_err(|_| invalid("epoch"))?;
+        let signer = match version {
+            0 => return Err(invalid("epoch")),
+            version => state.network.epoch_signers.get(version as usize - 1).ok_or_else(|| invalid("epoch"))?,
+        };
+        let key = match (request.get("key"), state.network.kind) {
+            (None, NetworkKind::Dev) => network::dev_genesis_key(),
+            _ => SecretKey::from_bytes(&parse_hex(&request, "key")?).map_err(|_| invalid("key"))?,
+        };
+        if keys::public_key_from_secret(&key) != *signer {
+            return Err(ErrorKind::RpcError(format!("The key doesn't sign epoch {} blocks on this network", version))
+                .into());
+        }
+        let count = match request.get("count") {
+            Some(_) => parse_u64(&request, "count")? as usize,
+            None => MAX_EPOCH_UPGRADES,
+        };
+        Ok((version, key, cmp::min(count, MAX_EPOCH_UPGRADES)))
+    });
+    let (version, key, count) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return fail(format!("{}", e)),
+    };
+    Box::pin(async move {
+        let (store, params) = (state.store.clone(), state.network.clone());
+        let accounts = tokio::task::spawn_blocking(move || epoch::upgradable(&*store, &params, version, count))
+            .await
+            .map_err(|_| Error::from("Epoch upgrade thread exited unexpectedly"))??;
+        let mut hashes = Vec::with_capacity(accounts.len());
+        for (account, info) in accounts {
+            let payload = BlockPayload::State {
+                account,
+                previous: info.head,
+                representative: info.representative,
+                balance: info.balance,
+                link: Link::Unknown(network::epoch_link(version)),
+            };
+            let root = payload.work_source();
+            let work = State::generate_work_async(state.clone(), root, state.difficulty.active_difficulty()).await?
+                .ok_or_else(|| Error::from(ErrorKind::RpcError("Cancelled".into())))?;
+            let mut block = Block::new(BlockKind::State, Some(payload), None, Some(work));
+            block.sign(&key)?;
+            hashes.push(String::from(node::handler::publish_local(block, &state)?));
+        }
+        Ok(json!({ "blocks": hashes }))
+    })
+}
+
+/// The public key of `account` in hex
+pub fn account_key(request: Value, _state: Arc<State>) -> Result<Value> {
+    let account = parse_account(&request, "account")?;
+    Ok(json!({ "key": HEXUPPER.encode(account.as_bytes()) }))
+}
+
+/// The `nano_` address of the hex public key `key`
+pub fn account_get(request: Value, _state: Arc<State>) -> Result<Value> {
+    let key = parse_public_key(get_str(&request, "key")?).map_err(|_| invalid("key"))?;
+    Ok(json!({ "account": account_to_address(&key) }))
+}
+
+/// Whether `account` is a valid address, without failing the request if it isn't
+pub fn validate_account_number(request: Value, _state: Arc<State>) -> Result<Value> {
+    let valid = is_valid_address(get_str(&request, "account")?);
+    Ok(json!({ "valid": if valid { "1" } else { "0" } }))
+}
+
+/// `amount` of `unit` in raw, failing unless it is a whole number of raw
+pub fn to_raw(request: Value, unit: Unit) -> Result<Value> {
+    let amount = parse_amount(&request, "amount", unit)?;
+    Ok(json!({ "amount": amount.to_string() }))
+}
+
+/// `amount` raw in `unit`, as an exact decimal
+pub fn from_raw(request: Value, unit: Unit) -> Result<Value> {
+    let amount = parse_amount(&request, "amount", Unit::Raw)?;
+    Ok(json!({ "amount": amount.format(unit) }))
+}
+
+/// A `nano:` URI requesting `amount` raw to `account`, with an optional `label` and
+/// `message`, and a QR code of it if `qr` is `png` (base64 encoded) or `svg`
+pub fn payment_uri(request: Value, _state: Arc<State>) -> Result<Value> {
+    let mut payment = PaymentRequest::new(parse_account(&request, "account")?);
+    if request.get("amount").is_some() {
+        payment.amount = Some(parse_amount(&request, "amount", Unit::Raw)?);
+    }
+    if request.get("label").is_some() {
+        payment.label = Some(get_str(&request, "label")?.to_owned());
+    }
+    if request.get("message").is_some() {
+        payment.message = Some(get_str(&request, "message")?.to_owned());
+    }
+    let mut response = json!({ "uri": payment.uri() });
+    if request.get("qr").is_some() {
+        let format = QrFormat::from_name(get_str(&request, "qr")?).ok_or_else(|| invalid("qr"))?;
+        let image = payment.qr_code(format)?;
+        response["qr"] = match format {
+            QrFormat::Png => json!(BASE64.encode(&image)),
+            QrFormat::Svg => json!(String::from_utf8_lossy(&image)),
+        };
+    }
+    Ok(response)
+}
+
+/// The private key at `index` of `seed`, with its public key and account. Requires
+/// `rpc.enable_key_derivation`.
+pub fn deterministic_key(request: Value, state: Arc<State>) -> Result<Value> {
+    check_key_derivation(&state)?;
+    let seed = parse_seed(&request, "seed")?;
+    let index = get_str(&request, "index")?.parse().map_err(|_| invalid("index"))?;
+    Ok(key_response(&keys::deterministic_key(&seed, index)))
+}
+
+/// The public key and account of the private `key`. Requires `rpc.enable_key_derivation`.
+pub fn key_expand(request: Value, state: Arc<State>) -> Result<Value> {
+    check_key_derivation(&state)?;
+    let secret = SecretKey::from_bytes(&parse_hex(&request, "key")?).map_err(|_| invalid("key"))?;
+    Ok(key_response(&secret))
+}
+
+/// Whether `seed` is a well formed seed, and if so the account at its index 0, so that
+/// tooling can check a seed was entered correctly. Requires `rpc.enable_key_derivation`.
+pub fn seed_validate(request: Value, state: Arc<State>) -> Result<Value> {
+    check_key_derivation(&state)?;
+    get_str(&request, "seed")?;
+    Ok(match parse_seed(&request, "seed") {
+        Ok(seed) => {
+            let public = keys::public_key_from_secret(&keys::deterministic_key(&seed, 0));
+            json!({ "valid": "1", "account": account_to_address(&public) })
+        }
+        Err(_) => json!({ "valid": "0" }),
+    })
+}
+
+/// Sign the arbitrary `message` with the private `key`, to prove ownership of its account
+pub fn sign_message(request: Value, _state: Arc<State>) -> Result<Value> {
+    let secret = SecretKey::from_bytes(&parse_hex(&request, "key")?).map_err(|_| invalid("key"))?;
+    let message = get_str(&request, "message")?;
+    let signature = keys::sign_arbitrary_message(&secret, message.as_bytes());
+    Ok(json!({
+        "account": account_to_address(&keys::public_key_from_secret(&secret)),
+        "signature": HEXUPPER.encode(&signature.to_bytes()),
+    }))
+}
+
+/// Whether `signature` is `account`'s signature of the arbitrary `message`
+pub fn verify_message(request: Value, _state: Arc<State>) -> Result<Value> {
+    let account = parse_account(&request, "account")?;
+    let message = get_str(&request, "message")?;
+    let signature = Signature::from_bytes(&parse_hex(&request, "signature")?).map_err(|_| invalid("signature"))?;
+    let valid = keys::verify_arbitrary_message(&account, message.as_bytes(), &signature);
+    Ok(json!({ "valid": if valid { "1" } else { "0" } }))
+}
+
+/// Begin a payment, handing out a deposit account which isn't in use. `wallet` is
+/// accepted for compatibility with the official node, but the accounts are those of
+/// `payment.deposit_accounts`.
+pub fn payment_begin(_request: Value, state: Arc<State>) -> Result<Value> {
+    let account = state.deposits.begin(&*state.store)?;
+    Ok(json!({ "account": account_to_address(&account) }))
+}
+
+/// Wait up to `timeout` milliseconds for the deposit account `account` to receive at
+/// least `amount` raw since its payment began. The wait is cut short at
+/// `rpc.idle_timeout_secs`, after which the connection would be closed.
+pub fn payment_wait(request: Value, state: Arc<State>) -> RpcResult {
+    let parsed = parse_account(&request, "account").and_then(|account| {
+        let amount = parse_amount(&request, "amount", Unit::Raw)?;
+        let timeout = parse_u64(&request, "timeout")?;
+        Ok((account, amount, timeout))
+    });
+    let (account, amount, timeout) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return fail(format!("{}", e)),
+    };
+    let timeout = cmp::min(Duration::from_millis(timeout), Duration::from_secs(state.config.rpc().idle_timeout_secs));
+    Box::pin(async move {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let (polled, key) = (state.clone(), account.clone());
+            let received = tokio::task::spawn_blocking(move || polled.deposits.received(&*polled.store, &key))
+                .await
+                .map_err(|_| Error::from("Payment thread exited unexpectedly"))??;
+            if received >= amount.raw() {
+                return Ok(json!({ "deposit": "1" }));
+            }
+            if Instant::now() >= deadline {
+                return Ok(json!({ "deposit": "0" }));
+            }
+            tokio::time::sleep(cmp::min(PAYMENT_POLL_INTERVAL, deadline.saturating_duration_since(Instant::now()))).await;
+        }
+    })
+}
+
+/// End the payment to the deposit account `account`, so that it can be handed out again
+pub fn payment_end(request: Value, state: Arc<State>) -> Result<Value> {
+    let account = parse_account(&request, "account")?;
+    state.deposits.end(&account)?;
+    Ok(json!({}))
+}
+
+/// The options of the `receivable` and `accounts_receivable` actions
+struct ReceivableQuery {
+    count: usize,
+    threshold: u128,
+    source: bool,
+    sorting: bool,
+    only_confirmed: bool,
+}
+
+impl ReceivableQuery {
+    fn parse(request: &Value) -> Result<Self> {
+        Ok(ReceivableQuery {
+            count: match request.get("count") {
+                Some(_) => parse_u64(request, "count")? as usize,
+                None => usize::max_value(),
+            },
+            threshold: match request.get("threshold") {
+                Some(_) => parse_amount(request, "threshold", Unit::Raw)?.raw(),
+                None => 0,
+            },
+            source: get_flag(request, "source", false)?,
+            sorting: get_flag(request, "sorting", false)?,
+            only_confirmed: get_flag(request, "include_only_confirmed", true)?,
+        })
+    }
+
+    /// The receivable blocks of `account` as in a response: their hashes, or if a
+    /// threshold or `source` is given, their amounts and sources by hash
+    fn blocks(&self, store: &dyn Store, account: &PublicKey) -> Result<Value> {
+        let mut entries = Vec::new();
+        for (hash, info) in store.pending(account)? {
+            if info.amount < self.threshold || (self.only_confirmed && !is_cemented(store, &hash, &info.source)?) {
+                continue;
+            }
+            entries.push((hash, info));
+        }
+        if self.sorting {
+            entries.sort_by(|a, b| b.1.amount.cmp(&a.1.amount));
+        }
+        entries.truncate(self.count);
+        if self.threshold == 0 && !self.source {
+            return Ok(json!(entries.into_iter().map(|(hash, _)| String::from(hash)).collect::<Vec<_>>()));
+        }
+        let mut blocks = ::serde_json::Map::new();
+        for (hash, info) in entries {
+            let entry = if self.source {
+                json!({ "amount": info.amount.to_string(), "source": account_to_address(&info.source) })
+            } else {
+                json!(info.amount.to_string())
+            };
+            blocks.insert(String::from(hash), entry);
+        }
+        Ok(Value::Object(blocks))
+    }
+}
+
+/// Whether the send `hash` from `source` is cemented, that is whether its height in the
+/// chain of `source` is within the account's confirmation height. Only cemented blocks
+/// are pruned, so pruned sends are cemented; sends with no height indexed are taken as
+/// not cemented.
+fn is_cemented(store: &dyn Store, hash: &BlockHash, source: &PublicKey) -> Result<bool> {
+    let confirmation_height = match store.account(source)? {
+        Some(info) => info.confirmation_height,
+        None => return Ok(false),
+    };
+    match store.height(hash)? {
+        Some(indexed) => Ok(indexed.height <= confirmation_height),
+        None => store.is_pruned(hash),
+    }
+}
+
+/// The sends to `account` which it hasn't received yet, read on a blocking thread. With
+/// `threshold`, only those of at least that many raw; with `source`, along with the
+/// accounts which sent them; with `sorting`, largest first; and at most `count` of
+/// them. Unless `include_only_confirmed` is `false`, only cemented sends are included.
+pub fn receivable(request: Value, state: Arc<State>) -> RpcResult {
+    let parsed = parse_account(&request, "account").and_then(|account| Ok((account, ReceivableQuery::parse(&request)?)));
+    let (account, query) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return fail(format!("{}", e)),
+    };
+    Box::pin(async move {
+        let blocks = tokio::task::spawn_blocking(move || {
+            store::read_snapshot(&*state.store, |store| query.blocks(store, &account))
+        })
+            .await
+            .map_err(|_| Error::from("Receivable thread exited unexpectedly"))??;
+        Ok(json!({ "blocks": blocks }))
+    })
+}
+
+/// The receivable blocks of each of `accounts` with any, as for `receivable`, all read
+/// from one snapshot of the ledger on a blocking thread
+pub fn accounts_receivable(request: Value, state: Arc<State>) -> RpcResult {
+    let parsed = ReceivableQuery::parse(&request).and_then(|query| {
+        let addresses = request.get("accounts").and_then(Value::as_array).ok_or_else(|| invalid("accounts"))?;
+        let mut accounts = Vec::with_capacity(addresses.len());
+        for address in addresses {
+            let address = address.as_str().ok_or_else(|| invalid("accounts"))?;
+            accounts.push((address.to_owned(), address_to_account(address).map_err(|_| invalid("accounts"))?));
+        }
+        Ok((query, accounts))
+    });
+    let (query, accounts) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return fail(format!("{}", e)),
+    };
+    Box::pin(async move {
+        tokio::task::spawn_blocking(move || store::read_snapshot(&*state.store, |store| {
+            let mut blocks = ::serde_json::Map::new();
+            for &(ref address, ref account) in &accounts {
+                let receivable = query.blocks(store, account)?;
+                let empty = match receivable {
+                    Value::Array(ref hashes) => hashes.is_empty(),
+                    Value::Object(ref entries) => entries.is_empty(),
+                    _ => false,
+                };
+                if !empty {
+                    blocks.insert(address.clone(), receivable);
+                }
+            }
+            Ok(json!({ "blocks": blocks }))
+        }))
+            .await
+            .map_err(|_| Error::from("Receivable thread exited unexpectedly"))?
+    })
+}
+
+/// An order-independent checksum of every stored block, and of each account's
+/// confirmation height if `cemented` is `true`, computed on a blocking thread
+pub fn ledger_checksum(request: Value, state: Arc<State>) -> RpcResult {
+    let cemented = request.get("cemented").and_then(Value::as_str) == Some("true");
+    Box::pin(async move {
+        let store = state.store.clone();
+        let (checksum, pruned) = tokio::task::spawn_blocking(move || -> Result<_> {
+            Ok((checksum::checksum(&*store, cemented)?, store.pruned_count()?))
+        })
+            .await
+            .map_err(|_| Error::from("Checksum thread exited unexpectedly"))??;
+        let mut response = json!({
+            "checksum": HEXUPPER.encode(&checksum.blocks),
+            "blocks": checksum.block_count.to_string(),
+        });
+        // The checksum of a pruned ledger only covers the blocks which are left
+        if pruned > 0 {
+            response["pruned"] = json!(pruned.to_string());
+        }
+        if let Some(cemented) = checksum.cemented {
+            response["cemented_checksum"] = json!(HEXUPPER.encode(&cemented));
+        }
+        Ok(response)
+    })
+}
+
+/// The raw in circulation: the genesis amount, less the genesis account's balance and
+/// the balances and receivable sends of the network's burn accounts
+pub fn available_supply(_request: Value, state: Arc<State>) -> Result<Value> {
+    let excluded = store::read_snapshot(&*state.store, |store| {
+        let mut excluded = store.account(&state.network.genesis_account)?.map_or(0, |info| info.balance);
+        for account in &state.network.burn_accounts {
+            excluded = excluded.saturating_add(store.account(account)?.map_or(0, |info| info.balance));
+            for (_, info) in store.pending(account)? {
+                excluded = excluded.saturating_add(info.amount);
+            }
+        }
+        Ok(excluded)
+    })?;
+    Ok(json!({ "available": GENESIS_AMOUNT.saturating_sub(excluded).to_string() }))
+}
+
+/// The balances of the genesis account and of the network's burn accounts, with the
+/// sends each burn account hasn't received, which it never will
+pub fn special_accounts(_request: Value, state: Arc<State>) -> Result<Value> {
+    let genesis = &state.network.genesis_account;
+    store::read_snapshot(&*state.store, |store| {
+        let mut burn = Vec::with_capacity(state.network.burn_accounts.len());
+        for account in &state.network.burn_accounts {
+            let receivable = store.pending(account)?.into_iter()
+                .fold(0u128, |total, (_, info)| total.saturating_add(info.amount));
+            burn.push(json!({
+                "account": account_to_address(account),
+                "balance": store.account(account)?.map_or(0, |info| info.balance).to_string(),
+                "receivable": receivable.to_string(),
+            }));
+        }
+        Ok(json!({
+            "genesis": {
+                "account": account_to_address(genesis),
+                "balance": store.account(genesis)?.map_or(0, |info| info.balance).to_string(),
+            },
+            "burn": burn,
+        }))
+    })
+}
+
+/// The number of stored blocks, of blocks waiting for a dependency, and of cemented
+/// blocks
+pub fn block_count(_request: Value, state: Arc<State>) -> Result<Value> {
+    let counts = state.store.block_counts()?;
+    Ok(json!({
+        "count": counts.total().to_string(),
+        "unchecked": state.store.unchecked_count()?.to_string(),
+        "cemented": counts.cemented.to_string(),
+    }))
+}
+
+/// The number of stored blocks of each kind
+pub fn block_count_type(_request: Value, state: Arc<State>) -> Result<Value> {
+    let counts = state.store.block_counts()?;
+    Ok(json!({
+        "send": counts.send.to_string(),
+        "receive": counts.receive.to_string(),
+        "open": counts.open.to_string(),
+        "change": counts.change.to_string(),
+        "state": counts.state.to_string(),
+    }))
+}
+
+/// The number of accounts with a stored frontier
+pub fn frontier_count(_request: Value, state: Arc<State>) -> Result<Value> {
+    Ok(json!({ "count": state.store.account_count()?.to_string() }))
+}
+
+/// The voting weight delegated to `account` in raw
+pub fn account_weight(request: Value, state: Arc<State>) -> Result<Value> {
+    let account = parse_account(&request, "account")?;
+    Ok(json!({ "weight": state.weights.weight(&account).to_string() }))
+}
+
+/// The accounts delegating to `account` with at least `threshold` raw, with their
+/// balances, found by scanning every account record on a blocking thread. At most
+/// `count` are listed, in order of their public keys, starting after `start` if
+/// given.
+pub fn delegators(request: Value, state: Arc<State>) -> RpcResult {
+    let parsed = parse_account(&request, "account").and_then(|representative| {
+        let threshold = match request.get("threshold") {
+            Some(_) => parse_amount(&request, "threshold", Unit::Raw)?.raw(),
+            None => 0,
+        };
+        let count = match request.get("count") {
+            Some(_) => parse_u64(&request, "count")? as usize,
+            None => usize::max_value(),
+        };
+        let start = match request.get("start") {
+            Some(_) => Some(parse_account(&request, "start")?),
+            None => None,
+        };
+        Ok((representative, threshold, count, start))
+    });
+    let (representative, threshold, count, start) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return fail(format!("{}", e)),
+    };
+    Box::pin(async move {
+        let store = state.store.clone();
+        let mut delegators = tokio::task::spawn_blocking(move || {
+            let mut delegators = Vec::new();
+            store.for_each_account(&mut |account, info| {
+                if info.representative == representative && info.balance >= threshold {
+                    delegators.push((account, info.balance));
+                }
+                Ok(())
+            })?;
+            Ok::<_, Error>(delegators)
+        }).await.map_err(|_| Error::from("Delegator scan thread exited unexpectedly"))??;
+        delegators.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+        let mut listed = ::serde_json::Map::new();
+        let after_start = delegators.into_iter()
+            .filter(|&(ref account, _)| start.as_ref().map_or(true, |start| account.as_bytes() > start.as_bytes()));
+        for (account, balance) in after_start.take(count) {
+            listed.insert(account_to_address(&account), json!(balance.to_string()));
+        }
+        Ok(json!({ "delegators": listed }))
+    })
+}
+
+/// The number of accounts delegating to `account`, found by scanning every account
+/// record on a blocking thread
+pub fn delegators_count(request: Value, state: Arc<State>) -> RpcResult {
+    let representative = match parse_account(&request, "account") {
+        Ok(representative) => representative,
+        Err(e) => return fail(format!("{}", e)),
+    };
+    Box::pin(async move {
+        let store = state.store.clone();
+        let count = tokio::task::spawn_blocking(move || {
+            let mut count = 0u64;
+            store.for_each_account(&mut |_, info| {
+                if info.representative == representative {
+                    count += 1;
+                }
+                Ok(())
+            })?;
+            Ok::<_, Error>(count)
+        }).await.map_err(|_| Error::from("Delegator scan thread exited unexpectedly"))??;
+        Ok(json!({ "count": count.to_string() }))
+    })
+}
+
+/// Connected peers with the seconds since they were last heard from, their round-trip
+/// time in milliseconds if it has been measured, the bytes exchanged with them, and
+/// whether they may be passed on to other peers
+pub fn peers(_request: Value, state: Arc<State>) -> Result<Value> {
+    let mut peers = ::serde_json::Map::new();
+    for (addr, info) in state.peers.read().unwrap().iter() {
+        let (bytes_in, bytes_out) = info.traffic();
+        peers.insert(format!("{}", addr), json!({
+            "last_seen": info.last_seen().elapsed().as_secs().to_string(),
+            "rtt": info.rtt().map(|rtt| millis(rtt).to_string()),
+            "bytes_in": bytes_in.to_string(),
+            "bytes_out": bytes_out.to_string(),
+            "relayable": info.relayable(),
+        }));
+    }
+    Ok(json!({ "peers": peers }))
+}
+
+fn parse_host(request: &Value, field: &str) -> Result<Ipv6Addr> {
+    exclusions::parse_host(get_str(request, field)?).map_err(|_| invalid(field))
+}
+
+fn exclusion_json(host: &Ipv6Addr, exclusion: &Exclusion) -> Value {
+    json!({
+        "address": host.to_string(),
+        "until": exclusion.until.map(|until| until.to_string()),
+        "reason": exclusion.reason,
+    })
+}
+
+/// Exclude a host from peering, for `duration` seconds or until the exclusion is
+/// removed, dropping it as a peer
+pub fn peer_exclude(request: Value, state: Arc<State>) -> Result<Value> {
+    let host = parse_host(&request, "address")?;
+    let duration = match request.get("duration") {
+        Some(_) => Some(Duration::from_secs(parse_u64(&request, "duration")?)),
+        None => None,
+    };
+    let reason = request.get("reason").and_then(Value::as_str).unwrap_or("");
+    let exclusion = state.exclude_host(host, duration, reason)?;
+    Ok(exclusion_json(&host, &exclusion))
+}
+
+/// Remove a host's exclusion, reporting whether it had one
+pub fn peer_unexclude(request: Value, state: Arc<State>) -> Result<Value> {
+    let host = parse_host(&request, "address")?;
+    Ok(json!({ "removed": state.exclusions.remove(&host)? }))
+}
+
+/// The hosts excluded from peering, with when each exclusion expires
+pub fn peer_exclusions(_request: Value, state: Arc<State>) -> Result<Value> {
+    let exclusions: Vec<Value> = state.exclusions.list()?.iter()
+        .map(|&(ref host, ref exclusion)| exclusion_json(host, exclusion))
+        .collect();
+    Ok(json!({ "exclusions": exclusions }))
+}
+
+/// The estimated size of the node's containers, and allocator statistics when built
+/// with the `jemalloc` feature
+pub fn memory_stats(_request: Value, state: Arc<State>) -> Result<Value> {
+    let containers = memory::containers(&state);
+    let total: usize = containers.iter().map(|container| container.bytes).sum();
+    let containers: Vec<Value> = containers.into_iter()
+        .map(|container| json!({
+            "name": container.name,
+            "count": container.count.to_string(),
+            "bytes": container.bytes.to_string(),
+        }))
+        .collect();
+    let allocator = memory::allocator()?.map(|stats| json!({
+        "allocated": stats.allocated.to_string(),
+        "active": stats.active.to_string(),
+        "resident": stats.resident.to_string(),
+        "mapped": stats.mapped.to_string(),
+    }));
+    Ok(json!({
+        "containers": containers,
+        "total": total.to_string(),
+        "allocator": allocator,
+    }))
+}
+
+/// The store's size on disk and the entries of each of its tables, with the figures on
+/// pages, readers, caching and compaction which the backend has, or `null`
+pub fn store_stats(_request: Value, state: Arc<State>) -> Result<Value> {
+    let stats = state.store.stats()?;
+    let figure = |figure: Option<u64>| figure.map(|figure| figure.to_string());
+    let mut tables = ::serde_json::Map::new();
+    for table in &stats.tables {
+        tables.insert(table.name.to_owned(), json!({
+            "entries": table.entries.to_string(),
+            "pages": figure(table.pages),
+            "depth": figure(table.depth),
+        }));
+    }
+    Ok(json!({
+        "backend": state.store.name(),
+        "size_on_disk": figure(stats.size_on_disk),
+        "map_size": figure(stats.map_size),
+        "page_size": figure(stats.page_size),
+        "pages_used": figure(stats.pages_used),
+        "readers": figure(stats.readers),
+        "cache": stats.cached.map(|(blocks, accounts)| json!({
+            "blocks": blocks.to_string(),
+            "accounts": accounts.to_string(),
+        })),
+        "last_vacuum": figure(stats.last_vacuum),
+        "tables": tables,
+    }))
+}
+
+/// Start capturing every datagram the node sends and receives to a pcap file at `path`
+pub fn capture_start(request: Value, state: Arc<State>) -> Result<Value> {
+    let path = get_str(&request, "path")?;
+    state.capture.start(path)?;
+    Ok(json!({ "started": path }))
+}
+
+/// Stop a packet capture, reporting the file it was written to
+pub fn capture_stop(_request: Value, state: Arc<State>) -> Result<Value> {
+    let path = state.capture.stop().map(|path| path.display().to_string());
+    Ok(json!({ "stopped": path }))
+}
+
+/// Reset all counters, samples and histograms
+pub fn stats_clear(_request: Value, state: Arc<State>) -> Result<Value> {
+    state.stats.clear();
+    Ok(json!({ "success": "" }))
+}
+
+/// A snapshot of the node's internal state, to be attached to bug reports
+pub fn diagnostics(_request: Value, state: Arc<State>) -> Result<Value> {
+    let peers: Vec<Value> = state.peers.read().unwrap().iter()
+        .map(|(addr, info)| json!({
+            "address": format!("{}", addr),
+            "last_seen": info.last_seen().elapsed().as_secs(),
+            "rtt": info.rtt().map(millis),
+            "socket": info.socket().and_then(|index| state.sockets.get(index)).map(|addr| addr.to_string()),
+        }))
+        .collect();
+    let recent_errors: Vec<Value> = state.errors.recent().into_iter()
+        .map(|(time, message)| json!({
+            "time": time.to_rfc3339(),
+            "message": message,
+        }))
+        .collect();
+    Ok(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "network": format!("{:?}", state.network.kind),
+        "uptime": state.started.elapsed().as_secs(),
+        "sockets": state.sockets.iter().map(|addr| addr.to_string()).collect::<Vec<_>>(),
+        "peers": peers,
+        "inactive_peers": state.inactive_peers.read().unwrap().len(),
+        "work": {
+            "sources": state.work.source_names(),
+            "active": state.work.active_count(),
+            "pregenerating": state.work.pregenerating_count(),
+            "cached": state.work.cached_count(),
+        },
+        "work_watcher": state.work_watcher.len(),
+        "voting": {
+            "status": state.voting.status().to_string(),
+            "account": state.voting.account().map(account_to_address),
+            "final_votes": voting::final_votes_active(&state.network, &*state.store)?,
+        },
+        "capture": state.capture.path().map(|path| path.display().to_string()),
+        "active_difficulty": format_difficulty(state.difficulty.active_difficulty()),
+        "error_counts": state.errors.counts(),
+        "recent_errors": recent_errors,
+    }))
+}
+
+/// Shut the node down gracefully, as on SIGTERM
+pub fn stop(_request: Value, state: Arc<State>) -> Result<Value> {
+    state.shutdown.trigger();
+    Ok(json!({ "success": "" }))
+}
+
+/// Reload the configuration file, as on SIGHUP, reporting which settings changed
+pub fn config_reload(_request: Value, state: Arc<State>) -> Result<Value> {
+    let summary = state.reload_config()?;
+    Ok(json!({
+        "applied": summary.applied,
+        "restart_required": summary.restart_required,
+    }))
+}
+
+/// The log filter currently in effect
+pub fn log_filter(_request: Value, state: Arc<State>) -> Result<Value> {
+    Ok(json!({ "filter": state.config.log_filter().directives() }))
+}
+
+/// Replace the log filter until the next restart or configuration reload, e.g. with
+/// `info,nano_rs::node=debug`
+pub fn log_filter_set(request: Value, state: Arc<State>) -> Result<Value> {
+    let filter = get_str(&request, "filter")?;
+    state.config.log_filter().set(filter)?;
+    info!("Log filter changed over RPC to: {}", filter);
+    Ok(json!({ "success": "" }))
+}
+
+/// Convert between a difficulty and a multiplier of some base threshold, and report the
+/// multiplier relative to each epoch threshold
+pub fn difficulty_convert(request: Value, state: Arc<State>) -> Result<Value> {
+    let base = parse_base(&request, &state)?;
+    let value = if request.get("difficulty").is_some() {
+        parse_difficulty_hex(get_str(&request, "difficulty")?, "difficulty")?
+    } else {
+        difficulty::from_multiplier(parse_multiplier(&request, "multiplier")?, base)
+    };
+    let thresholds = [
+        ("epoch_1", difficulty::EPOCH_1),
+        ("epoch_2_send", difficulty::EPOCH_2_SEND),
+        ("epoch_2_receive", difficulty::EPOCH_2_RECEIVE),
+    ];
+    let mut multipliers = ::serde_json::Map::new();
+    for &(name, threshold) in thresholds.iter() {
+        multipliers.insert(name.to_owned(), json!(format!("{}", difficulty::to_multiplier(value, threshold))));
+    }
+    Ok(json!({
+        "difficulty": format_difficulty(value),
+        "base": format_difficulty(base),
+        "multiplier": format!("{}", difficulty::to_multiplier(value, base)),
+        "multipliers": multipliers,
+        "valid_epoch_1": if value >= difficulty::EPOCH_1 { "1" } else { "0" },
+        "valid_epoch_2_send": if value >= difficulty::EPOCH_2_SEND { "1" } else { "0" },
+        "valid_epoch_2_receive": if value >= difficulty::EPOCH_2_RECEIVE { "1" } else { "0" },
+    }))
+}
+
+pub fn work_generate(request: Value, state: Arc<State>) -> RpcResult {
+    let root = match parse_root(&request, "hash") {
+        Ok(root) => root,
+        Err(e) => return fail(format!("{}", e)),
+    };
+    let difficulty = match parse_difficulty(&request, &state) {
+        Ok(difficulty) => difficulty,
+        Err(e) => return fail(format!("{}", e)),
+    };
+    let base = state.difficulty.base();
+    Box::pin(async move {
+        let work = State::generate_work_async(state, root, difficulty).await?
+            .ok_or_else(|| Error::from(ErrorKind::RpcError("Cancelled".into())))?;
+        let value = nanopow_rs::work_value(&root, &work);
+        Ok(json!({
+            "work": String::from(work),
+            "difficulty": format_difficulty(value),
+            "multiplier": format!("{}", difficulty::to_multiplier(value, base)),
+        }))
+    })
+}
+
+pub fn work_cancel(request: Value, state: Arc<State>) -> Result<Value> {
+    let root = parse_root(&request, "hash")?;
+    state.work.cancel(&root);
+    Ok(json!({}))
+}
+
+pub fn work_validate(request: Value, state: Arc<State>) -> Result<Value> {
+    let root = parse_root(&request, "hash")?;
+    let work = parse_work(&request, "work")?;
+    let threshold = match request.get("difficulty") {
+        Some(_) => parse_difficulty_hex(get_str(&request, "difficulty")?, "difficulty")?,
+        None => state.difficulty.base(),
+    };
+    let value = nanopow_rs::work_value(&root, &work);
+    Ok(json!({
+        "valid": if value >= threshold { "1" } else { "0" },
+        "difficulty": format_difficulty(value),
+        "multiplier": format!("{}", difficulty::to_multiplier(value, state.difficulty.base())),
+    }))
+}
@@ -0,0 +1,53 @@
+//! Node stats in the Prometheus text exposition format, served at `GET /metrics`
+use std::fmt::Write;
+
+use crate::node::state::State;
+use crate::stats::HISTOGRAM_BUCKETS;
+
+/// Render every counter and histogram, plus a few gauges of the node's state
+pub fn render(state: &State) -> String {
+    let mut out = String::new();
+
+    let mut counters = state.stats.counters();
+    counters.sort_by_key(|counter| counter.stat_type.name());
+    let mut last_type = None;
+    for counter in counters {
+        let name = format!("nano_rs_{}_total", counter.stat_type.name());
+        if last_type != Some(counter.stat_type) {
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            last_type = Some(counter.stat_type);
+        }
+        let _ = writeln!(out, "{}{{detail=\"{}\",dir=\"{}\"}} {}",
+            name, counter.detail, counter.direction.name(), counter.value);
+    }
+
+    for (stat_type, detail, histogram) in state.stats.histograms() {
+        let name = format!("nano_rs_{}_{}_seconds", stat_type.name(), detail);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (i, count) in histogram.buckets.iter().enumerate() {
+            cumulative += count;
+            let le = match HISTOGRAM_BUCKETS.get(i) {
+                Some(&bound) => format!("{}", bound as f64 / 1000.0),
+                None => "+Inf".to_owned(),
+            };
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, cumulative);
+        }
+        let _ = writeln!(out, "{}_sum {}", name, histogram.sum as f64 / 1000.0);
+        let _ = writeln!(out, "{}_count {}", name, histogram.count);
+    }
+
+    let gauges = [
+        ("nano_rs_peers", state.peer_count()),
+        ("nano_rs_inactive_peers", state.inactive_peers.read().unwrap().len()),
+        ("nano_rs_work_active", state.work.active_count()),
+        ("nano_rs_work_cached", state.work.cached_count()),
+    ];
+    for &(name, value) in gauges.iter() {
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+    let _ = writeln!(out, "# TYPE nano_rs_active_difficulty gauge");
+    let _ = writeln!(out, "nano_rs_active_difficulty {}", state.difficulty.active_difficulty());
+    out
+}
@@ -0,0 +1,276 @@
+//! A JSON RPC server compatible with the official node's `{"action": ...}` protocol
+pub mod handler;
+pub mod metrics;
+pub mod slots;
+
+use hyper::{self, Body, Method, Request, Response, Server, StatusCode};
+use hyper::body::HttpBody;
+use hyper::header::{self, HeaderValue};
+use hyper::service::{make_service_fn, service_fn};
+
+use futures::future::{self, BoxFuture, Future, FutureExt};
+
+use serde_json::{self, Value};
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::net::TcpListener;
+
+use nano_lib_rs::amount::Unit;
+
+use crate::node::NodeMode;
+use crate::node::state::State;
+use crate::error::*;
+use self::slots::{SlotConfig, SlotIncoming, Slots};
+
+/// The eventual result of an RPC action; errors are reported to the client as `{"error": ...}`
+pub type RpcResult = BoxFuture<'static, Result<Value>>;
+
+/// Routes a parsed request to the handler for its action
+pub type Dispatcher = fn(&str, Value, Arc<State>) -> Option<RpcResult>;
+
+/// Fail an RPC action with a message shown to the client
+pub fn fail<S: Into<String>>(message: S) -> RpcResult {
+    sync(Err(ErrorKind::RpcError(message.into()).into()))
+}
+
+/// Complete an RPC action with the result of a synchronous handler
+pub fn sync(result: Result<Value>) -> RpcResult {
+    future::ready(result).boxed()
+}
+
+/// Complete an RPC action with a synchronous handler which reads the store, run on a
+/// blocking thread so that a slow read doesn't hold up the server's other connections
+pub fn blocking(handler: fn(Value, Arc<State>) -> Result<Value>, request: Value, state: Arc<State>) -> RpcResult {
+    tokio::task::spawn_blocking(move || handler(request, state))
+        .map(|result| result.unwrap_or_else(|_| Err(Error::from("RPC handler thread exited unexpectedly"))))
+        .boxed()
+}
+
+/// The longest request body accepted, far longer than any action needs
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+/// Bind the RPC server to `addr`, returning a future which serves requests until it errors.
+/// The server also answers `GET /metrics` with stats for Prometheus.
+pub fn serve(addr: &SocketAddr, state: Arc<State>) -> Result<impl Future<Output=()> + Send> {
+    serve_with(addr, state, "RPC", dispatch, true)
+}
+
+/// Bind a server speaking the nano-work-server protocol to `addr`, which only accepts
+/// the `work_generate`, `work_cancel` and `work_validate` actions
+pub fn serve_work(addr: &SocketAddr, state: Arc<State>) -> Result<impl Future<Output=()> + Send> {
+    serve_with(addr, state, "Work server", dispatch_work, false)
+}
+
+/// Bind a server to `addr`, holding as many connections as the `[rpc]` section allows
+fn serve_with(addr: &SocketAddr, state: Arc<State>, name: &'static str, dispatcher: Dispatcher, serve_metrics: bool)
+    -> Result<impl Future<Output=()> + Send>
+{
+    let listener = ::std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    info!("{} listening on: {}", name, listener.local_addr()?);
+    let slots = Arc::new(Slots::new(SlotConfig::from(&state.config.rpc())));
+    let server = Server::builder(SlotIncoming::new(listener, slots.clone()))
+        .serve(make_service_fn(move |_| {
+            let state = state.clone();
+            future::ok::<_, Infallible>(service_fn(move |req| {
+                handle_request(req, state.clone(), dispatcher, serve_metrics)
+            }))
+        }))
+        .map(move |res| if let Err(e) = res {
+            error!("{} error: {}", name, e);
+        });
+    Ok(async move {
+        tokio::select! {
+            _ = server => {}
+            _ = slots::close_idle(slots) => {}
+        }
+    })
+}
+
+async fn handle_request(req: Request<Body>, state: Arc<State>, dispatcher: Dispatcher, serve_metrics: bool)
+    -> ::std::result::Result<Response<Body>, hyper::Error>
+{
+    if serve_metrics && req.method() == Method::GET && req.uri().path() == "/metrics" {
+        let mut response = Response::new(Body::from(metrics::render(&state)));
+        response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain; version=0.0.4"));
+        return Ok(response);
+    }
+    if req.method() != Method::POST {
+        let body = json!({ "error": "Only POST requests are accepted" });
+        return Ok(json_response(StatusCode::METHOD_NOT_ALLOWED, &body));
+    }
+    let declared = req.headers().get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok());
+    let body = match declared {
+        Some(length) if length > MAX_BODY_BYTES as u64 => None,
+        _ => read_body(req.into_body()).await?,
+    };
+    let body = match body {
+        Some(body) => body,
+        None => {
+            let body = json!({ "error": format!("Requests are limited to {} bytes", MAX_BODY_BYTES) });
+            return Ok(json_response(StatusCode::PAYLOAD_TOO_LARGE, &body));
+        }
+    };
+    let result = match serde_json::from_slice::<Value>(&body) {
+        Ok(request) => route(request, state, dispatcher).await,
+        Err(e) => Err(e.into()),
+    };
+    let body = match result {
+        Ok(value) => value,
+        Err(e) => {
+            debug!("RPC request failed: {}", e);
+            json!({ "error": format!("{}", e) })
+        }
+    };
+    Ok(json_response(StatusCode::OK, &body))
+}
+
+/// Read `body`, or `None` once it is longer than `MAX_BODY_BYTES`, so that a client
+/// can't make the server buffer an unbounded body
+async fn read_body(mut body: Body) -> ::std::result::Result<Option<Vec<u8>>, hyper::Error> {
+    let mut read = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if read.len() + chunk.len() > MAX_BODY_BYTES {
+            return Ok(None);
+        }
+        read.extend_from_slice(&chunk);
+    }
+    Ok(Some(read))
+}
+
+fn route(request: Value, state: Arc<State>, dispatcher: Dispatcher) -> RpcResult {
+    let action = match request.get("action").and_then(Value::as_str) {
+        Some(action) => action.to_owned(),
+        None => return fail("Missing action"),
+    };
+    debug!("RPC action: {}", action);
+    match dispatcher(&action, request, state) {
+        Some(result) => result,
+        None => fail(format!("Unknown action: {}", action)),
+    }
+}
+
+/// Wallet actions of the official node, and actions taking seeds or private keys
+const KEY_ACTIONS: [&str; 15] = [
+    "account_create", "accounts_create", "account_move", "account_remove", "account_representative_set",
+    "deterministic_key", "epoch_upgrade", "key_expand", "receive", "receive_minimum_set", "search_pending",
+    "seed_validate", "send", "sign_message", "work_set",
+];
+
+/// Whether `action` is refused by observer nodes, which never handle keys
+fn handles_keys(action: &str) -> bool {
+    action.starts_with("wallet_") || action.starts_with("password_") || KEY_ACTIONS.contains(&action)
+}
+
+fn dispatch(action: &str, request: Value, state: Arc<State>) -> Option<RpcResult> {
+    if state.mode == NodeMode::Observer && handles_keys(action) {
+        return Some(fail(format!("Observer nodes don't accept wallet or key actions such as {}", action)));
+    }
+    Some(match action {
+        "account_get" => sync(handler::account_get(request, state)),
+        "account_info" => blocking(handler::account_info, request, state),
+        "account_key" => sync(handler::account_key(request, state)),
+        "account_representative_set" => handler::account_representative_set(request, state),
+        "account_weight" => sync(handler::account_weight(request, state)),
+        "accounts_pending" | "accounts_receivable" => handler::accounts_receivable(request, state),
+        "active_difficulty" => sync(handler::active_difficulty(request, state)),
+        "available_supply" => blocking(handler::available_supply, request, state),
+        "block_count" => blocking(handler::block_count, request, state),
+        "block_count_type" => blocking(handler::block_count_type, request, state),
+        "block_info" => blocking(handler::block_info, request, state),
+        "difficulty_convert" => sync(handler::difficulty_convert(request, state)),
+        "capture_start" => sync(handler::capture_start(request, state)),
+        "capture_stop" => sync(handler::capture_stop(request, state)),
+        "config_reload" => sync(handler::config_reload(request, state)),
+        "delegators" => handler::delegators(request, state),
+        "delegators_count" => handler::delegators_count(request, state),
+        "deterministic_key" => sync(handler::deterministic_key(request, state)),
+        "diagnostics" => blocking(handler::diagnostics, request, state),
+        "epoch_upgrade" => handler::epoch_upgrade(request, state),
+        "frontier_count" => blocking(handler::frontier_count, request, state),
+        "key_expand" => sync(handler::key_expand(request, state)),
+        "ledger_checksum" => handler::ledger_checksum(request, state),
+        "log_filter" => sync(handler::log_filter(request, state)),
+        "log_filter_set" => sync(handler::log_filter_set(request, state)),
+        "memory_stats" => sync(handler::memory_stats(request, state)),
+        "payment_begin" => blocking(handler::payment_begin, request, state),
+        "payment_end" => sync(handler::payment_end(request, state)),
+        "payment_uri" => sync(handler::payment_uri(request, state)),
+        "payment_wait" => handler::payment_wait(request, state),
+        "peer_exclude" => sync(handler::peer_exclude(request, state)),
+        "peer_exclusions" => sync(handler::peer_exclusions(request, state)),
+        "peer_unexclude" => sync(handler::peer_unexclude(request, state)),
+        "peers" => sync(handler::peers(request, state)),
+        "pending" | "receivable" => handler::receivable(request, state),
+        "republish" => handler::republish(request, state),
+        "seed_validate" => sync(handler::seed_validate(request, state)),
+        "sign_message" => sync(handler::sign_message(request, state)),
+        "special_accounts" => blocking(handler::special_accounts, request, state),
+        "stats" => sync(handler::stats(request, state)),
+        "stats_clear" => sync(handler::stats_clear(request, state)),
+        "stop" => sync(handler::stop(request, state)),
+        "store_stats" => blocking(handler::store_stats, request, state),
+        "validate_account_number" => sync(handler::validate_account_number(request, state)),
+        "verify_message" => sync(handler::verify_message(request, state)),
+        _ => return dispatch_work(action, request, state),
+    })
+}
+
+fn dispatch_work(action: &str, request: Value, state: Arc<State>) -> Option<RpcResult> {
+    Some(match action {
+        "work_generate" => handler::work_generate(request, state),
+        "work_cancel" => sync(handler::work_cancel(request, state)),
+        "work_validate" => sync(handler::work_validate(request, state)),
+        _ => return dispatch_units(action, request),
+    })
+}
+
+/// `<unit>_to_raw` and `<unit>_from_raw`, also called `raw_to_<unit>`, for `nano` (also
+/// called `Mnano`), `knano` and `rai`, and the legacy names `krai` and `mrai`
+fn dispatch_units(action: &str, request: Value) -> Option<RpcResult> {
+    let (unit, to_raw) = match action.strip_suffix("_to_raw") {
+        Some(unit) => (unit, true),
+        None => match action.strip_prefix("raw_to_") {
+            Some(unit) => (unit, false),
+            None => (action.strip_suffix("_from_raw")?, false),
+        },
+    };
+    let unit = Unit::from_name(unit).filter(|&unit| unit != Unit::Raw)?;
+    Some(sync(if to_raw { handler::to_raw(request, unit) } else { handler::from_raw(request, unit) }))
+}
+
+fn json_response(status: StatusCode, body: &Value) -> Response<Body> {
+    let mut response = Response::new(Body::from(body.to_string()));
+    *response.status_mut() = status;
+    response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn convert(action: &str, amount: &str) -> Option<String> {
+        let response = block_on(dispatch_units(action, json!({ "amount": amount }))?).unwrap();
+        Some(response["amount"].as_str().unwrap().to_owned())
+    }
+
+    #[test]
+    fn dispatches_unit_conversions() {
+        let raw = "1500000000000000000000000000000";
+        assert_eq!(convert("nano_to_raw", "1.5").unwrap(), raw);
+        assert_eq!(convert("nano_from_raw", raw).unwrap(), "1.5");
+        assert_eq!(convert("raw_to_nano", raw).unwrap(), "1.5");
+        assert_eq!(convert("raw_to_krai", raw).unwrap(), "1500");
+        assert!(convert("raw_to_raw", raw).is_none());
+        assert!(convert("raw_to_", raw).is_none());
+    }
+}
@@ -0,0 +1,288 @@
+//! Bounding the TCP connections of the RPC and work servers.
+//!
+//! Peers only ever talk to nano-rs over UDP, so its TCP listeners are the RPC and work
+//! servers, which public nodes may expose. Each server holds at most
+//! `max_connections` connections, and at most `max_per_ip` from one address; further
+//! connections from an address at its cap are closed as soon as they are accepted.
+//! When the server is full, the connection which has been idle longest is evicted to
+//! make room, and connections idle for longer than `idle_timeout` are closed, so that
+//! descriptor usage stays bounded.
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use futures::ready;
+use hyper::server::accept::Accept;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{self, Instant, Sleep};
+
+use crate::config::RpcSection;
+
+/// How long to wait after an error accepting a connection, such as running out of
+/// file descriptors, before accepting again
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SlotConfig {
+    pub max_connections: usize,
+    pub max_per_ip: usize,
+    pub idle_timeout: Duration,
+}
+
+impl<'a> From<&'a RpcSection> for SlotConfig {
+    fn from(rpc: &'a RpcSection) -> Self {
+        SlotConfig {
+            max_connections: rpc.max_connections,
+            max_per_ip: rpc.max_connections_per_ip,
+            idle_timeout: Duration::from_secs(rpc.idle_timeout_secs),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Slot {
+    ip: IpAddr,
+    last_active: Instant,
+    /// Set when the connection was evicted or timed out, so that its next read or
+    /// write fails
+    closed: bool,
+    /// The task waiting to read from the connection
+    waker: Option<Waker>,
+}
+
+impl Slot {
+    fn close(&mut self) {
+        self.closed = true;
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// The connections held by one server
+#[derive(Debug)]
+pub struct Slots {
+    config: SlotConfig,
+    next_id: AtomicU64,
+    slots: Mutex<HashMap<u64, Slot>>,
+}
+
+impl Slots {
+    pub fn new(config: SlotConfig) -> Self {
+        Slots {
+            config,
+            next_id: AtomicU64::new(0),
+            slots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a slot for a connection from `ip`, evicting the longest idle connection if
+    /// the server is full. Returns `None` if `ip` already holds its share of slots.
+    pub fn acquire(&self, ip: IpAddr) -> Option<u64> {
+        let mut slots = self.slots.lock().unwrap();
+        let from_ip = slots.values().filter(|slot| !slot.closed && slot.ip == ip).count();
+        if from_ip >= self.config.max_per_ip {
+            return None;
+        }
+        let open = slots.values().filter(|slot| !slot.closed).count();
+        if open >= self.config.max_connections {
+            let idlest = slots.iter_mut()
+                .filter(|&(_, ref slot)| !slot.closed)
+                .min_by_key(|&(_, ref slot)| slot.last_active);
+            if let Some((_, slot)) = idlest {
+                debug!("Evicting the connection from {}, idle for {}s", slot.ip, slot.last_active.elapsed().as_secs());
+                slot.close();
+            }
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        slots.insert(id, Slot { ip, last_active: Instant::now(), closed: false, waker: None });
+        Some(id)
+    }
+
+    /// Whether the connection in slot `id` may still be used, noting that it was if so
+    fn touch(&self, id: u64) -> bool {
+        match self.slots.lock().unwrap().get_mut(&id) {
+            Some(slot) if !slot.closed => {
+                slot.last_active = Instant::now();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn is_closed(&self, id: u64) -> bool {
+        self.slots.lock().unwrap().get(&id).map_or(true, |slot| slot.closed)
+    }
+
+    /// Wake the task waiting to read from slot `id` if it is closed
+    fn park(&self, id: u64, waker: &Waker) {
+        if let Some(slot) = self.slots.lock().unwrap().get_mut(&id) {
+            if slot.closed {
+                waker.wake_by_ref();
+            } else {
+                slot.waker = Some(waker.clone());
+            }
+        }
+    }
+
+    fn release(&self, id: u64) {
+        self.slots.lock().unwrap().remove(&id);
+    }
+
+    /// Close the connections idle for longer than `idle_timeout`, returning how many
+    pub fn close_idle(&self) -> usize {
+        let timeout = self.config.idle_timeout;
+        let mut closed = 0;
+        for slot in self.slots.lock().unwrap().values_mut() {
+            if !slot.closed && slot.last_active.elapsed() > timeout {
+                slot.close();
+                closed += 1;
+            }
+        }
+        closed
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.lock().unwrap().values().filter(|slot| !slot.closed).count()
+    }
+}
+
+/// Close idle connections of `slots` until the server stops
+pub async fn close_idle(slots: Arc<Slots>) {
+    let period = ::std::cmp::max(slots.config.idle_timeout / 2, Duration::from_secs(1));
+    let mut interval = time::interval_at(Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        let closed = slots.close_idle();
+        if closed > 0 {
+            debug!("Closed {} idle connections, {} remain", closed, slots.len());
+        }
+    }
+}
+
+fn evicted() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "connection evicted")
+}
+
+/// A connection holding a slot, which it gives back when dropped
+#[derive(Debug)]
+pub struct SlotStream {
+    stream: TcpStream,
+    id: u64,
+    slots: Arc<Slots>,
+}
+
+impl Drop for SlotStream {
+    fn drop(&mut self) {
+        self.slots.release(self.id);
+    }
+}
+
+impl AsyncRead for SlotStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.slots.is_closed(this.id) {
+            return Poll::Ready(Err(evicted()));
+        }
+        let filled = buf.filled().len();
+        match Pin::new(&mut this.stream).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                if buf.filled().len() > filled && !this.slots.touch(this.id) {
+                    return Poll::Ready(Err(evicted()));
+                }
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => {
+                this.slots.park(this.id, cx.waker());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl AsyncWrite for SlotStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if !this.slots.touch(this.id) {
+            return Poll::Ready(Err(evicted()));
+        }
+        Pin::new(&mut this.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// Accepts connections for hyper, each holding a slot of `slots`
+pub struct SlotIncoming {
+    listener: TcpListener,
+    slots: Arc<Slots>,
+    backoff: Option<Pin<Box<Sleep>>>,
+}
+
+impl SlotIncoming {
+    pub fn new(listener: TcpListener, slots: Arc<Slots>) -> Self {
+        SlotIncoming { listener, slots, backoff: None }
+    }
+}
+
+impl Accept for SlotIncoming {
+    type Conn = SlotStream;
+    type Error = io::Error;
+
+    fn poll_accept(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<io::Result<SlotStream>>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(ref mut backoff) = this.backoff {
+                ready!(backoff.as_mut().poll(cx));
+            }
+            this.backoff = None;
+            match ready!(this.listener.poll_accept(cx)) {
+                Ok((stream, addr)) => match this.slots.acquire(addr.ip()) {
+                    Some(id) => return Poll::Ready(Some(Ok(SlotStream { stream, id, slots: this.slots.clone() }))),
+                    None => debug!("Refusing a connection from {}, which holds its share of connections", addr),
+                },
+                Err(e) => {
+                    debug!("Error accepting a connection: {}", e);
+                    this.backoff = Some(Box::pin(time::sleep(ACCEPT_BACKOFF)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_idlest_connections() {
+        let slots = Slots::new(SlotConfig { max_connections: 2, max_per_ip: 2, idle_timeout: Duration::from_secs(60) });
+        let (first, second): (IpAddr, IpAddr) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        let a = slots.acquire(first).unwrap();
+        let b = slots.acquire(first).unwrap();
+        assert_eq!(slots.acquire(first), None);
+        ::std::thread::sleep(Duration::from_millis(2));
+        assert!(slots.touch(a));
+        // The server is full, so the idlest connection makes room
+        let c = slots.acquire(second).unwrap();
+        assert!(slots.is_closed(b));
+        assert!(!slots.is_closed(a) && !slots.is_closed(c));
+        assert_eq!(slots.len(), 2);
+        slots.release(b);
+        assert_eq!(slots.close_idle(), 0);
+    }
+}
@@ -0,0 +1,196 @@
+//! Installing and running the node as a Windows service.
+//!
+//! The service runs from a data directory under `%ProgramData%\nano-rs`, which holds
+//! its configuration file and logs, and also logs to the Windows event log.
+//! On other platforms no service commands are available.
+use clap::{App, ArgMatches};
+
+use crate::error::*;
+
+#[cfg(windows)]
+pub const SERVICE_NAME: &str = "nano-rs";
+
+#[cfg(not(windows))]
+pub fn subcommands<'a, 'b>() -> Vec<App<'a, 'b>> {
+    Vec::new()
+}
+
+#[cfg(not(windows))]
+pub fn run(_matches: &ArgMatches) -> Result<bool> {
+    Ok(false)
+}
+
+#[cfg(windows)]
+pub use self::windows::{run, subcommands};
+
+#[cfg(windows)]
+mod windows {
+    use std::env;
+    use std::ffi::OsString;
+    use std::fmt;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use clap::{App, ArgMatches, SubCommand};
+    use log::{Level as LogLevel, Log, Record};
+    use tracing::{Event, Level, Subscriber};
+    use tracing::field::{Field, Visit};
+    use tracing_subscriber::layer::{Context, Layer};
+    use windows_service::service::{ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl,
+        ServiceExitCode, ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType};
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+    use windows_service::service_dispatcher;
+    use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+
+    use crate::config::Config;
+    use crate::daemon::Shutdown;
+    use crate::error::*;
+    use super::SERVICE_NAME;
+
+    pub fn subcommands<'a, 'b>() -> Vec<App<'a, 'b>> {
+        vec![
+            SubCommand::with_name("service")
+                .about("Manage the nano-rs Windows service")
+                .subcommand(SubCommand::with_name("install")
+                    .about("Install the service, starting automatically on boot"))
+                .subcommand(SubCommand::with_name("uninstall")
+                    .about("Remove the service"))
+                .subcommand(SubCommand::with_name("run")
+                    .about("Run as a service; only used by the service manager")),
+        ]
+    }
+
+    pub fn run(matches: &ArgMatches) -> Result<bool> {
+        match matches.subcommand() {
+            ("service", Some(args)) => match args.subcommand_name() {
+                Some("install") => install()?,
+                Some("uninstall") => uninstall()?,
+                Some("run") => service_dispatcher::start(SERVICE_NAME, ffi_service_main)?,
+                _ => bail!("Expected one of install, uninstall or run"),
+            },
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// `%ProgramData%\nano-rs`, holding the configuration file and logs
+    fn data_dir() -> PathBuf {
+        let program_data = env::var_os("ProgramData").unwrap_or_else(|| OsString::from(r"C:\ProgramData"));
+        PathBuf::from(program_data).join(SERVICE_NAME)
+    }
+
+    fn install() -> Result<()> {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CREATE_SERVICE)?;
+        let info = ServiceInfo {
+            name: OsString::from(SERVICE_NAME),
+            display_name: OsString::from("nano-rs node"),
+            service_type: ServiceType::OwnProcess,
+            start_type: ServiceStartType::AutoStart,
+            error_control: ServiceErrorControl::Normal,
+            executable_path: env::current_exe()?,
+            launch_arguments: vec![OsString::from("service"), OsString::from("run")],
+            account_name: None,
+            account_password: None,
+        };
+        manager.create_service(info, ServiceAccess::empty())?;
+        winlog::register(SERVICE_NAME);
+        fs::create_dir_all(data_dir())?;
+        println!("Installed the {} service; its configuration is read from {}",
+            SERVICE_NAME, data_dir().join(crate::DEFAULT_CONFIG_PATH).display());
+        Ok(())
+    }
+
+    fn uninstall() -> Result<()> {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+        let service = manager.open_service(SERVICE_NAME, ServiceAccess::DELETE)?;
+        service.delete()?;
+        winlog::deregister(SERVICE_NAME);
+        println!("Removed the {} service", SERVICE_NAME);
+        Ok(())
+    }
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    fn service_main(_arguments: Vec<OsString>) {
+        if let Err(e) = run_service() {
+            error!("Service failed: {}", e);
+        }
+    }
+
+    fn status(state: ServiceState, exit_code: u32) -> ServiceStatus {
+        ServiceStatus {
+            service_type: ServiceType::OwnProcess,
+            current_state: state,
+            controls_accepted: if state == ServiceState::Running {
+                ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN
+            } else {
+                ServiceControlAccept::empty()
+            },
+            exit_code: ServiceExitCode::Win32(exit_code),
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+        }
+    }
+
+    fn run_service() -> Result<()> {
+        let dir = data_dir();
+        fs::create_dir_all(&dir)?;
+        env::set_current_dir(&dir)?;
+        let config = Config::load(crate::DEFAULT_CONFIG_PATH)?;
+
+        let event_log = EventLog(winlog::WinLogger::try_new(SERVICE_NAME)?);
+        let log_filter = crate::logging::init_with(&config, event_log)?;
+
+        let shutdown = Arc::new(Shutdown::new());
+        let handler_shutdown = shutdown.clone();
+        let status_handle = service_control_handler::register(SERVICE_NAME, move |event| match event {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                handler_shutdown.trigger();
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        })?;
+
+        status_handle.set_service_status(status(ServiceState::Running, 0))?;
+        let result = crate::run(crate::DEFAULT_CONFIG_PATH, config, log_filter, None, shutdown);
+        status_handle.set_service_status(status(ServiceState::Stopped, if result.is_ok() { 0 } else { 1 }))?;
+        result
+    }
+
+    /// Writes events to the Windows event log
+    struct EventLog(winlog::WinLogger);
+
+    /// Collects the formatted message of an event
+    struct Message(String);
+
+    impl Visit for Message {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            if field.name() == "message" {
+                self.0 = format!("{:?}", value);
+            }
+        }
+    }
+
+    impl<S: Subscriber> Layer<S> for EventLog {
+        fn on_event(&self, event: &Event, _ctx: Context<S>) {
+            let metadata = event.metadata();
+            let level = match *metadata.level() {
+                Level::ERROR => LogLevel::Error,
+                Level::WARN => LogLevel::Warn,
+                Level::INFO => LogLevel::Info,
+                Level::DEBUG => LogLevel::Debug,
+                Level::TRACE => LogLevel::Trace,
+            };
+            let mut message = Message(String::new());
+            event.record(&mut message);
+            self.0.log(&Record::builder()
+                .args(format_args!("{}", message.0))
+                .level(level)
+                .target(metadata.target())
+                .build());
+        }
+    }
+}
@@ -0,0 +1,288 @@
+//! Signing block and vote hashes with an account's key.
+//!
+//! The wallet and vote generator sign through a `Signer`, so that a representative's
+//! voting key doesn't have to live in the node: besides keys loaded into memory from a
+//! key file, hashes can be signed by an external service such as an HSM bridge or a
+//! separate hardened process, reached over HTTP or a Unix socket. External services are sent
+//! `{"action": "sign", "account": ..., "hash": ...}` and respond with
+//! `{"signature": ...}` or `{"error": ...}`; over a socket, each is one line of JSON.
+//! Signatures from external services are checked before use, so that a misbehaving
+//! service can't make the node publish invalid blocks or votes.
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use data_encoding::HEXUPPER;
+use serde_json::Value;
+
+use nano_lib_rs::keys::{self, PublicKey, SecretKey, Signature};
+
+use crate::error::*;
+use crate::utils::account_to_address;
+
+/// Signs 32 byte block and vote hashes for one account
+pub trait Signer: fmt::Debug + Send + Sync {
+    /// A short human readable name used in logs
+    fn name(&self) -> &str;
+
+    /// The account whose key signs
+    fn account(&self) -> &PublicKey;
+
+    fn sign(&self, hash: &[u8; 32]) -> Result<Signature>;
+}
+
+/// Signs with a key held in memory
+pub struct KeySigner {
+    secret: SecretKey,
+    account: PublicKey,
+}
+
+impl KeySigner {
+    pub fn new(secret: SecretKey) -> Self {
+        KeySigner {
+            account: keys::public_key_from_secret(&secret),
+            secret,
+        }
+    }
+
+    /// Sign with the private key of `account`, read in hex from the file at `path`
+    pub fn load(path: &Path, account: &PublicKey) -> Result<Self> {
+        let hex = fs::read_to_string(path)
+            .chain_err(|| format!("Could not read the signer's key file {}", path.display()))?;
+        let secret = HEXUPPER.decode(hex.trim().to_uppercase().as_bytes()).ok()
+            .and_then(|secret| SecretKey::from_bytes(&secret).ok())
+            .ok_or_else(|| Error::from(format!("{} doesn't hold a private key in hex", path.display())))?;
+        let signer = KeySigner::new(secret);
+        if signer.account != *account {
+            bail!("The key in {} isn't the key of {}", path.display(), account_to_address(account));
+        }
+        Ok(signer)
+    }
+}
+
+impl fmt::Debug for KeySigner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KeySigner")
+            .field("account", &account_to_address(&self.account))
+            .finish()
+    }
+}
+
+impl Signer for KeySigner {
+    fn name(&self) -> &str {
+        "in-memory key"
+    }
+
+    fn account(&self) -> &PublicKey {
+        &self.account
+    }
+
+    fn sign(&self, hash: &[u8; 32]) -> Result<Signature> {
+        Ok(keys::sign_message(&self.secret, hash))
+    }
+}
+
+/// How an external signing service is reached, or where the key is kept if hashes
+/// are signed in memory
+#[derive(Clone, Debug, PartialEq)]
+pub enum Endpoint {
+    /// An HTTP URL requests are `POST`ed to
+    Http(String),
+    /// A Unix socket
+    Ipc(PathBuf),
+    /// A file holding the private key in hex
+    KeyFile(PathBuf),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SignerConfig {
+    /// The account the service signs for
+    pub account: PublicKey,
+    pub endpoint: Endpoint,
+    /// How long to wait for a signature
+    pub timeout: Duration,
+}
+
+fn sign_request(account: &PublicKey, hash: &[u8; 32]) -> Value {
+    json!({
+        "action": "sign",
+        "account": account_to_address(account),
+        "hash": HEXUPPER.encode(hash),
+    })
+}
+
+/// The signature in a service's `response`, if it is `account`'s signature of `hash`
+fn parse_signature(account: &PublicKey, hash: &[u8; 32], response: &Value) -> Result<Signature> {
+    if let Some(error) = response.get("error").and_then(Value::as_str) {
+        bail!("Signing service error: {}", error);
+    }
+    let signature = response.get("signature")
+        .and_then(Value::as_str)
+        .and_then(|signature| HEXUPPER.decode(signature.to_uppercase().as_bytes()).ok())
+        .and_then(|signature| Signature::from_bytes(&signature).ok())
+        .ok_or("Signing service response did not contain a signature")?;
+    if !keys::verify_signature(account, hash, &signature) {
+        bail!("Signing service returned an invalid signature for {}", account_to_address(account));
+    }
+    Ok(signature)
+}
+
+/// Signs through an external service over HTTP. Only available with the `rpc` or
+/// `dpow` features.
+#[cfg(any(feature = "rpc", feature = "dpow"))]
+#[derive(Debug)]
+pub struct HttpSigner {
+    url: String,
+    account: PublicKey,
+    client: reqwest::blocking::Client,
+}
+
+#[cfg(any(feature = "rpc", feature = "dpow"))]
+impl HttpSigner {
+    pub fn new(url: String, account: PublicKey, timeout: Duration) -> Result<Self> {
+        let client = reqwest::blocking::Client::builder().timeout(timeout).build()?;
+        Ok(HttpSigner { url, account, client })
+    }
+}
+
+#[cfg(any(feature = "rpc", feature = "dpow"))]
+impl Signer for HttpSigner {
+    fn name(&self) -> &str {
+        "HTTP signing service"
+    }
+
+    fn account(&self) -> &PublicKey {
+        &self.account
+    }
+
+    fn sign(&self, hash: &[u8; 32]) -> Result<Signature> {
+        let response: Value = self.client.post(&self.url)
+            .json(&sign_request(&self.account, hash))
+            .send()?
+            .error_for_status()?
+            .json()?;
+        parse_signature(&self.account, hash, &response)
+    }
+}
+
+/// Signs through an external service listening on a Unix socket, connecting for each
+/// signature
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct IpcSigner {
+    path: PathBuf,
+    account: PublicKey,
+    timeout: Duration,
+}
+
+#[cfg(unix)]
+impl IpcSigner {
+    pub fn new(path: PathBuf, account: PublicKey, timeout: Duration) -> Self {
+        IpcSigner { path, account, timeout }
+    }
+}
+
+#[cfg(unix)]
+impl Signer for IpcSigner {
+    fn name(&self) -> &str {
+        "IPC signing service"
+    }
+
+    fn account(&self) -> &PublicKey {
+        &self.account
+    }
+
+    fn sign(&self, hash: &[u8; 32]) -> Result<Signature> {
+        use std::io::{BufRead, BufReader, Write};
+        use std::os::unix::net::UnixStream;
+
+        let mut stream = UnixStream::connect(&self.path)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let mut request = sign_request(&self.account, hash).to_string();
+        request.push('\n');
+        stream.write_all(request.as_bytes())?;
+        let mut response = String::new();
+        BufReader::new(stream).read_line(&mut response)?;
+        parse_signature(&self.account, hash, &serde_json::from_str(&response)?)
+    }
+}
+
+/// Connect to the external signing service described by `config`, or load its key
+pub fn open(config: &SignerConfig) -> Result<Arc<dyn Signer>> {
+    let account = config.account.clone();
+    let signer: Arc<dyn Signer> = match config.endpoint {
+        #[cfg(any(feature = "rpc", feature = "dpow"))]
+        Endpoint::Http(ref url) => Arc::new(HttpSigner::new(url.clone(), account, config.timeout)?),
+        #[cfg(not(any(feature = "rpc", feature = "dpow")))]
+        Endpoint::Http(_) => bail!("HTTP signing services need nano-rs to be built with the `rpc` or `dpow` feature"),
+        #[cfg(unix)]
+        Endpoint::Ipc(ref path) => Arc::new(IpcSigner::new(path.clone(), account, config.timeout)),
+        #[cfg(not(unix))]
+        Endpoint::Ipc(_) => bail!("IPC signing services are only supported on Unix"),
+        Endpoint::KeyFile(ref path) => Arc::new(KeySigner::load(path, &account)?),
+    };
+    Ok(signer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_service_signatures() {
+        let signer = KeySigner::new(SecretKey::from_bytes(&[7u8; 32]).unwrap());
+        let hash = [1u8; 32];
+        let signature = signer.sign(&hash).unwrap();
+        let response = json!({ "signature": HEXUPPER.encode(&signature.to_bytes()) });
+        assert!(parse_signature(signer.account(), &hash, &response).is_ok());
+        assert!(parse_signature(signer.account(), &[2u8; 32], &response).is_err());
+        assert!(parse_signature(signer.account(), &hash, &json!({ "error": "locked" })).is_err());
+    }
+
+    #[test]
+    fn loads_key_files() {
+        let path = ::std::env::temp_dir().join(format!("nano-rs-signer-{}.key", ::std::process::id()));
+        let key = KeySigner::new(SecretKey::from_bytes(&[7u8; 32]).unwrap());
+        fs::write(&path, format!("{}\n", HEXUPPER.encode(&[7u8; 32]).to_lowercase())).unwrap();
+        let signer = KeySigner::load(&path, key.account()).unwrap();
+        assert_eq!(signer.sign(&[3u8; 32]).unwrap(), key.sign(&[3u8; 32]).unwrap());
+        assert!(KeySigner::load(&path, &PublicKey::from_bytes(&[1u8; 32]).unwrap()).is_err());
+        fs::write(&path, "not a key").unwrap();
+        assert!(KeySigner::load(&path, key.account()).is_err());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn signs_over_ipc() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::os::unix::net::UnixListener;
+        use std::thread;
+
+        let path = ::std::env::temp_dir().join(format!("nano-rs-signer-{}.sock", ::std::process::id()));
+        let _ = ::std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let key = KeySigner::new(SecretKey::from_bytes(&[7u8; 32]).unwrap());
+        let account = key.account().clone();
+        let service = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut line = String::new();
+            BufReader::new(&stream).read_line(&mut line).unwrap();
+            let request: Value = serde_json::from_str(&line).unwrap();
+            let mut hash = [0u8; 32];
+            hash.copy_from_slice(&HEXUPPER.decode(request["hash"].as_str().unwrap().as_bytes()).unwrap());
+            let signature = key.sign(&hash).unwrap();
+            let response = json!({ "signature": HEXUPPER.encode(&signature.to_bytes()) });
+            (&stream).write_all(format!("{}\n", response).as_bytes()).unwrap();
+        });
+
+        let signer = IpcSigner::new(path.clone(), account.clone(), Duration::from_secs(5));
+        let signature = signer.sign(&[3u8; 32]).unwrap();
+        assert!(keys::verify_signature(&account, &[3u8; 32], &signature));
+        service.join().unwrap();
+        let _ = ::std::fs::remove_file(&path);
+    }
+}
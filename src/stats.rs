@@ -0,0 +1,284 @@
+//! Counters, samples and histograms of node activity, queried with the `stats` RPC
+//! and served to Prometheus.
+//!
+//! Counters are keyed by a type, a detail naming what was counted within that type,
+//! and a direction. Samples keep the most recent values recorded for a type and
+//! detail, such as how long work generation took. Histograms count durations in
+//! milliseconds into fixed buckets, for latencies whose distribution matters more
+//! than their recent values. Counters which have no natural direction use
+//! `Direction::In`.
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Local};
+use indexmap::IndexMap;
+
+use nano_lib_rs::message::{Message, MessageKind};
+
+/// Number of values kept for each sample
+const SAMPLE_CAPACITY: usize = 64;
+
+/// Upper bounds of histogram buckets in milliseconds; larger values fall in a final,
+/// unbounded bucket
+pub const HISTOGRAM_BUCKETS: &[u64] = &[50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 30_000, 60_000, 300_000];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StatType {
+    /// Bytes sent and received, in total as `bytes` and by message kind
+    Traffic,
+    /// Messages sent and received, by kind
+    Message,
+    /// Blocks received, by outcome
+    Block,
+    /// Changes to the peer table
+    Peering,
+    /// Work generation
+    Work,
+    /// Time from first seeing a block to its confirmation and cementing
+    Confirmation,
+    /// Batched writes of blocks to the store
+    Store,
+    /// Blocks dropped from full processing queues, by traffic class and reason,
+    /// publishes to peers which were just sent the block, and repeated or throttled votes
+    Drop,
+}
+
+impl StatType {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            StatType::Traffic => "traffic",
+            StatType::Message => "message",
+            StatType::Block => "block",
+            StatType::Peering => "peering",
+            StatType::Work => "work",
+            StatType::Confirmation => "confirmation",
+            StatType::Store => "store",
+            StatType::Drop => "drop",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+impl Direction {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Direction::In => "in",
+            Direction::Out => "out",
+        }
+    }
+}
+
+/// The counter detail for a message of `kind`
+pub fn message_detail(kind: MessageKind) -> &'static str {
+    match kind {
+        MessageKind::Invalid => "invalid",
+        MessageKind::NotAMessage => "not_a_type",
+        MessageKind::KeepAlive => "keepalive",
+        MessageKind::Publish => "publish",
+        MessageKind::ConfirmReq => "confirm_req",
+        MessageKind::ConfirmAck => "confirm_ack",
+        MessageKind::BulkPull => "bulk_pull",
+        MessageKind::BulkPush => "bulk_push",
+        MessageKind::FrontierReq => "frontier_req",
+    }
+}
+
+/// Frames whose traffic is also counted under a detail of their own
+pub trait TrafficDetail {
+    fn traffic_detail(&self) -> &'static str;
+}
+
+impl TrafficDetail for Message {
+    fn traffic_detail(&self) -> &'static str {
+        message_detail(self.kind())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Counter {
+    pub stat_type: StatType,
+    pub detail: &'static str,
+    pub direction: Direction,
+    pub value: u64,
+    pub updated: DateTime<Local>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Sample {
+    pub stat_type: StatType,
+    pub detail: &'static str,
+    /// Recorded values with the time they were recorded, oldest first
+    pub values: Vec<(DateTime<Local>, u64)>,
+}
+
+/// Durations counted into `HISTOGRAM_BUCKETS`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Histogram {
+    /// The number of values in each bucket, with the unbounded bucket last
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    /// The sum of all values in milliseconds
+    pub sum: u64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram {
+            buckets: vec![0; HISTOGRAM_BUCKETS.len() + 1],
+            count: 0,
+            sum: 0,
+        }
+    }
+
+    fn observe(&mut self, value: u64) {
+        let bucket = HISTOGRAM_BUCKETS.iter().position(|&bound| value <= bound)
+            .unwrap_or(HISTOGRAM_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += value;
+    }
+
+    /// The upper bound of the bucket holding the `percentile`th value, or `None` if it
+    /// falls in the unbounded bucket or nothing was recorded
+    pub fn percentile(&self, percentile: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((percentile / 100.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return HISTOGRAM_BUCKETS.get(i).cloned();
+            }
+        }
+        None
+    }
+}
+
+type CounterKey = (StatType, &'static str, Direction);
+type SampleKey = (StatType, &'static str);
+
+#[derive(Debug)]
+pub struct Stats {
+    created: Mutex<DateTime<Local>>,
+    counters: Mutex<IndexMap<CounterKey, (u64, DateTime<Local>)>>,
+    samples: Mutex<IndexMap<SampleKey, VecDeque<(DateTime<Local>, u64)>>>,
+    histograms: Mutex<IndexMap<SampleKey, Histogram>>,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats {
+            created: Mutex::new(Local::now()),
+            counters: Mutex::new(IndexMap::new()),
+            samples: Mutex::new(IndexMap::new()),
+            histograms: Mutex::new(IndexMap::new()),
+        }
+    }
+
+    /// Increment a counter by one
+    pub fn inc(&self, stat_type: StatType, detail: &'static str, direction: Direction) {
+        self.add(stat_type, detail, direction, 1);
+    }
+
+    /// Increment a counter by `value`
+    pub fn add(&self, stat_type: StatType, detail: &'static str, direction: Direction, value: u64) {
+        let mut counters = self.counters.lock().unwrap();
+        let entry = counters.entry((stat_type, detail, direction)).or_insert((0, Local::now()));
+        entry.0 += value;
+        entry.1 = Local::now();
+    }
+
+    /// Record a sampled value, dropping the oldest once `SAMPLE_CAPACITY` are kept
+    pub fn sample(&self, stat_type: StatType, detail: &'static str, value: u64) {
+        let mut samples = self.samples.lock().unwrap();
+        let values = samples.entry((stat_type, detail)).or_insert_with(VecDeque::new);
+        if values.len() == SAMPLE_CAPACITY {
+            values.pop_front();
+        }
+        values.push_back((Local::now(), value));
+    }
+
+    /// Count a duration in milliseconds into a histogram
+    pub fn observe(&self, stat_type: StatType, detail: &'static str, value: u64) {
+        self.histograms.lock().unwrap()
+            .entry((stat_type, detail))
+            .or_insert_with(Histogram::new)
+            .observe(value);
+    }
+
+    /// The value of a single counter
+    #[cfg(test)]
+    pub fn count(&self, stat_type: StatType, detail: &'static str, direction: Direction) -> u64 {
+        self.counters.lock().unwrap().get(&(stat_type, detail, direction)).map_or(0, |c| c.0)
+    }
+
+    pub fn counters(&self) -> Vec<Counter> {
+        self.counters.lock().unwrap().iter()
+            .map(|(&(stat_type, detail, direction), &(value, updated))| Counter {
+                stat_type,
+                detail,
+                direction,
+                value,
+                updated,
+            })
+            .collect()
+    }
+
+    pub fn samples(&self) -> Vec<Sample> {
+        self.samples.lock().unwrap().iter()
+            .map(|(&(stat_type, detail), values)| Sample {
+                stat_type,
+                detail,
+                values: values.iter().cloned().collect(),
+            })
+            .collect()
+    }
+
+    pub fn histograms(&self) -> Vec<(StatType, &'static str, Histogram)> {
+        self.histograms.lock().unwrap().iter()
+            .map(|(&(stat_type, detail), histogram)| (stat_type, detail, histogram.clone()))
+            .collect()
+    }
+
+    /// When the stats were created or last cleared
+    pub fn created(&self) -> DateTime<Local> {
+        *self.created.lock().unwrap()
+    }
+
+    /// Reset all counters, samples and histograms
+    pub fn clear(&self) {
+        self.counters.lock().unwrap().clear();
+        self.samples.lock().unwrap().clear();
+        self.histograms.lock().unwrap().clear();
+        *self.created.lock().unwrap() = Local::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_percentiles() {
+        let stats = Stats::new();
+        for &ms in [10, 40, 80, 200, 200, 900, 4_000, 4_000, 20_000, 400_000].iter() {
+            stats.observe(StatType::Confirmation, "confirmed", ms);
+        }
+        let (_, _, histogram) = stats.histograms().pop().unwrap();
+        assert_eq!(histogram.count, 10);
+        assert_eq!(histogram.sum, 429_430);
+        assert_eq!(histogram.buckets[0], 2);
+        assert_eq!(histogram.percentile(50.0), Some(250));
+        assert_eq!(histogram.percentile(90.0), Some(30_000));
+        assert_eq!(histogram.percentile(99.0), None);
+        stats.clear();
+        assert!(stats.histograms().is_empty());
+    }
+}
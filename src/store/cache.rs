@@ -0,0 +1,276 @@
+//! A cache of recently used blocks and account records in front of another store.
+//!
+//! The same hot blocks and accounts are looked up over and over while processing and
+//! answering requests, so the most recently used ones are kept in memory, and the
+//! least recently used one is evicted once a cache is full. Writes go to the inner
+//! store first. Once committed, written blocks are added to the block cache, while
+//! the records of written accounts are invalidated and read again on next use.
+//! Snapshots are read from the inner store alone, as the caches may hold records
+//! written after a snapshot was taken.
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use nano_lib_rs::block::{Block, BlockHash};
+use nano_lib_rs::keys::PublicKey;
+
+use crate::error::*;
+use super::{AccountInfo, BlockCounts, BlockHeight, PendingInfo, Store, StoreStats, WriteBatch};
+
+/// Values by key, with when each was last used
+#[derive(Debug)]
+struct Lru<K, V> {
+    entries: HashMap<K, (V, u64)>,
+    by_use: BTreeMap<u64, K>,
+    clock: u64,
+    capacity: usize,
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> Lru<K, V> {
+    fn new(capacity: usize) -> Self {
+        Lru {
+            entries: HashMap::new(),
+            by_use: BTreeMap::new(),
+            clock: 0,
+            capacity,
+        }
+    }
+
+    fn get(&mut self, key: &K) -> Option<V> {
+        let clock = self.clock + 1;
+        let entry = self.entries.get_mut(key)?;
+        self.by_use.remove(&entry.1);
+        self.by_use.insert(clock, key.clone());
+        entry.1 = clock;
+        self.clock = clock;
+        Some(entry.0.clone())
+    }
+
+    fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.clock += 1;
+        if let Some((_, used)) = self.entries.insert(key.clone(), (value, self.clock)) {
+            self.by_use.remove(&used);
+        }
+        self.by_use.insert(self.clock, key);
+        while self.entries.len() > self.capacity {
+            let oldest = match self.by_use.keys().next() {
+                Some(&used) => used,
+                None => break,
+            };
+            if let Some(key) = self.by_use.remove(&oldest) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some((_, used)) = self.entries.remove(key) {
+            self.by_use.remove(&used);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+/// Keeps recently used blocks and account records of `inner` in memory
+#[derive(Debug)]
+pub struct CachedStore {
+    inner: Arc<dyn Store>,
+    blocks: Mutex<Lru<BlockHash, Block>>,
+    accounts: Mutex<Lru<[u8; 32], AccountInfo>>,
+}
+
+impl CachedStore {
+    /// Cache up to `blocks` blocks and `accounts` account records of `inner`
+    pub fn new(inner: Arc<dyn Store>, blocks: usize, accounts: usize) -> Self {
+        CachedStore {
+            inner,
+            blocks: Mutex::new(Lru::new(blocks)),
+            accounts: Mutex::new(Lru::new(accounts)),
+        }
+    }
+
+    /// The number of cached blocks and account records
+    pub fn len(&self) -> (usize, usize) {
+        (self.blocks.lock().unwrap().len(), self.accounts.lock().unwrap().len())
+    }
+}
+
+impl Store for CachedStore {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn block(&self, hash: &BlockHash) -> Result<Option<Block>> {
+        if let Some(block) = self.blocks.lock().unwrap().get(hash) {
+            return Ok(Some(block));
+        }
+        let block = self.inner.block(hash)?;
+        if let Some(ref block) = block {
+            self.blocks.lock().unwrap().insert(*hash, block.clone());
+        }
+        Ok(block)
+    }
+
+    fn block_exists(&self, hash: &BlockHash) -> Result<bool> {
+        if self.blocks.lock().unwrap().get(hash).is_some() {
+            return Ok(true);
+        }
+        self.inner.block_exists(hash)
+    }
+
+    fn block_count(&self) -> Result<u64> {
+        self.inner.block_count()
+    }
+
+    fn block_counts(&self) -> Result<BlockCounts> {
+        self.inner.block_counts()
+    }
+
+    fn is_pruned(&self, hash: &BlockHash) -> Result<bool> {
+        self.inner.is_pruned(hash)
+    }
+
+    fn pruned_count(&self) -> Result<u64> {
+        self.inner.pruned_count()
+    }
+
+    fn height(&self, hash: &BlockHash) -> Result<Option<BlockHeight>> {
+        self.inner.height(hash)
+    }
+
+    fn account(&self, account: &PublicKey) -> Result<Option<AccountInfo>> {
+        if let Some(info) = self.accounts.lock().unwrap().get(account.as_bytes()) {
+            return Ok(Some(info));
+        }
+        let info = self.inner.account(account)?;
+        if let Some(ref info) = info {
+            self.accounts.lock().unwrap().insert(*account.as_bytes(), info.clone());
+        }
+        Ok(info)
+    }
+
+    fn account_count(&self) -> Result<u64> {
+        self.inner.account_count()
+    }
+
+    fn pending(&self, account: &PublicKey) -> Result<Vec<(BlockHash, PendingInfo)>> {
+        self.inner.pending(account)
+    }
+
+    fn unchecked(&self, dependency: &BlockHash) -> Result<Vec<(BlockHash, Block)>> {
+        self.inner.unchecked(dependency)
+    }
+
+    fn unchecked_count(&self) -> Result<u64> {
+        self.inner.unchecked_count()
+    }
+
+    fn weights(&self) -> Result<Vec<(PublicKey, u128)>> {
+        self.inner.weights()
+    }
+
+    fn for_each_block(&self, f: &mut dyn FnMut(BlockHash, Block) -> Result<()>) -> Result<()> {
+        self.inner.for_each_block(f)
+    }
+
+    fn for_each_account(&self, f: &mut dyn FnMut(PublicKey, AccountInfo) -> Result<()>) -> Result<()> {
+        self.inner.for_each_account(f)
+    }
+
+    fn write(&self, batch: &WriteBatch) -> Result<()> {
+        self.inner.write(batch)?;
+        let mut blocks = self.blocks.lock().unwrap();
+        for &(hash, ref block) in batch.blocks() {
+            blocks.insert(hash, block.clone());
+        }
+        for hash in batch.pruned().iter().chain(batch.deleted_blocks()) {
+            blocks.remove(hash);
+        }
+        let mut accounts = self.accounts.lock().unwrap();
+        for account in batch.accounts().iter().map(|&(ref account, _)| account).chain(batch.deleted_accounts()) {
+            accounts.remove(account.as_bytes());
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> Result<StoreStats> {
+        Ok(StoreStats { cached: Some(self.len()), ..self.inner.stats()? })
+    }
+
+    fn snapshot(&self, f: &mut dyn FnMut(&dyn Store) -> Result<()>) -> Result<()> {
+        self.inner.snapshot(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nano_lib_rs::block::{BlockKind, BlockPayload};
+    use crate::store::memory::MemoryStore;
+
+    fn block(previous: u8) -> (BlockHash, Block) {
+        let hash = BlockHash::from_bytes(&[previous + 100; 32]).unwrap();
+        (hash, Block::new(BlockKind::Change, Some(BlockPayload::Change {
+            previous: BlockHash::from_bytes(&[previous; 32]).unwrap(),
+            representative: PublicKey::from_bytes(&[1u8; 32]).unwrap(),
+        }), None, None))
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let store = CachedStore::new(Arc::new(MemoryStore::new()), 2, 0);
+        let mut batch = WriteBatch::new();
+        let (a, b, c) = (block(1), block(2), block(3));
+        batch.put_block(a.0, a.1.clone());
+        batch.put_block(b.0, b.1.clone());
+        store.write(&batch).unwrap();
+        assert_eq!(store.len(), (2, 0));
+
+        // Using `a` makes `b` the least recently used
+        assert_eq!(store.block(&a.0).unwrap(), Some(a.1.clone()));
+        batch.clear();
+        batch.put_block(c.0, c.1.clone());
+        store.write(&batch).unwrap();
+        let mut blocks = store.blocks.lock().unwrap();
+        assert!(blocks.get(&a.0).is_some());
+        assert!(blocks.get(&b.0).is_none());
+        assert!(blocks.get(&c.0).is_some());
+        drop(blocks);
+
+        // Evicted blocks are still read from the inner store
+        assert_eq!(store.block(&b.0).unwrap(), Some(b.1));
+        assert_eq!(store.block_count().unwrap(), 3);
+    }
+
+    #[test]
+    fn invalidates_written_accounts() {
+        let store = CachedStore::new(Arc::new(MemoryStore::new()), 0, 16);
+        let account = PublicKey::from_bytes(&[2u8; 32]).unwrap();
+        let mut info = AccountInfo {
+            head: block(1).0,
+            representative: account.clone(),
+            balance: 10,
+            confirmation_height: 0,
+        };
+        let mut batch = WriteBatch::new();
+        batch.put_account(account.clone(), info.clone());
+        store.write(&batch).unwrap();
+        assert_eq!(store.len(), (0, 0));
+        assert_eq!(store.account(&account).unwrap(), Some(info.clone()));
+        assert_eq!(store.len(), (0, 1));
+
+        info.head = block(2).0;
+        info.balance = 5;
+        batch.clear();
+        batch.put_account(account.clone(), info.clone());
+        store.write(&batch).unwrap();
+        assert_eq!(store.len(), (0, 0));
+        assert_eq!(store.account(&account).unwrap(), Some(info));
+    }
+}
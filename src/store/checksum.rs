@@ -0,0 +1,99 @@
+//! An order-independent checksum of the ledger, to cheaply check that two nodes
+//! store the same ledger.
+//!
+//! The block checksum is the XOR of the hashes of every stored block, like the
+//! checksum the official node kept, so it doesn't depend on the order blocks were
+//! written in. The cemented checksum is the XOR, over every account with confirmed
+//! blocks, of the Blake2b digest of the account and its confirmation height as 8
+//! little-endian bytes.
+use nano_lib_rs::hash::blake2b_256;
+
+use crate::error::*;
+use super::Store;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LedgerChecksum {
+    pub blocks: [u8; 32],
+    pub block_count: u64,
+    /// The checksum of cemented state, if it was computed
+    pub cemented: Option<[u8; 32]>,
+}
+
+fn xor(into: &mut [u8; 32], bytes: &[u8; 32]) {
+    for (a, b) in into.iter_mut().zip(bytes.iter()) {
+        *a ^= b;
+    }
+}
+
+/// Compute the checksum of `store`, including cemented state if `cemented` is set
+pub fn checksum(store: &dyn Store, cemented: bool) -> Result<LedgerChecksum> {
+    let mut blocks = [0u8; 32];
+    let mut block_count = 0;
+    store.for_each_block(&mut |hash, _| {
+        xor(&mut blocks, hash.as_bytes());
+        block_count += 1;
+        Ok(())
+    })?;
+    let cemented = if cemented {
+        let mut checksum = [0u8; 32];
+        store.for_each_account(&mut |account, info| {
+            if info.confirmation_height > 0 {
+                let height = info.confirmation_height.to_le_bytes();
+                xor(&mut checksum, &blake2b_256(&[&account.as_bytes()[..], &height[..]]));
+            }
+            Ok(())
+        })?;
+        Some(checksum)
+    } else {
+        None
+    };
+    Ok(LedgerChecksum { blocks, block_count, cemented })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nano_lib_rs::block::{Block, BlockHash, BlockKind, BlockPayload};
+    use nano_lib_rs::keys::PublicKey;
+    use crate::store::{AccountInfo, WriteBatch};
+    use crate::store::memory::MemoryStore;
+
+    fn block(previous: u8) -> (BlockHash, Block) {
+        let hash = BlockHash::from_bytes(&[previous + 100; 32]).unwrap();
+        (hash, Block::new(BlockKind::Change, Some(BlockPayload::Change {
+            previous: BlockHash::from_bytes(&[previous; 32]).unwrap(),
+            representative: PublicKey::from_bytes(&[1u8; 32]).unwrap(),
+        }), None, None))
+    }
+
+    #[test]
+    fn ignores_write_order() {
+        let (first, second) = (MemoryStore::new(), MemoryStore::new());
+        let mut batch = WriteBatch::new();
+        for i in 0..3 {
+            let (hash, block) = block(i);
+            batch.put_block(hash, block);
+            first.write(&batch).unwrap();
+            batch.clear();
+        }
+        for i in (0..3).rev() {
+            let (hash, block) = block(i);
+            batch.put_block(hash, block);
+        }
+        second.write(&batch).unwrap();
+        assert_eq!(checksum(&first, true).unwrap(), checksum(&second, true).unwrap());
+        assert_eq!(checksum(&first, false).unwrap().block_count, 3);
+
+        batch.clear();
+        let account = PublicKey::from_bytes(&[2u8; 32]).unwrap();
+        batch.put_account(account.clone(), AccountInfo {
+            head: block(2).0,
+            representative: account,
+            balance: 0,
+            confirmation_height: 1,
+        });
+        second.write(&batch).unwrap();
+        assert_eq!(checksum(&first, false).unwrap(), checksum(&second, false).unwrap());
+        assert_ne!(checksum(&first, true).unwrap(), checksum(&second, true).unwrap());
+    }
+}
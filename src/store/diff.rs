@@ -0,0 +1,147 @@
+//! Comparing two ledgers, to debug a node whose ledger diverged from another's.
+//!
+//! Each ledger's blocks and account records are looked up in the other, so both are
+//! scanned once. Blocks differ if their encodings do, including their signatures and
+//! work, and account records if any field does. A block which one ledger pruned isn't
+//! counted as missing from it. To compare against an official node's ledger, migrate a
+//! copy of it first; to compare against a ledger the other node exported, import the
+//! dump into a memory store.
+use nano_lib_rs::block::BlockHash;
+use nano_lib_rs::keys::PublicKey;
+
+use crate::error::*;
+use super::{encode_block, Store};
+
+/// What differs between two ledgers, `first` and `second`, in one kind of record
+#[derive(Clone, Debug, PartialEq)]
+pub struct Differences<T> {
+    pub only_in_first: Vec<T>,
+    pub only_in_second: Vec<T>,
+    pub differing: Vec<T>,
+    /// How many records there are of each of the above, of which at most `limit` are
+    /// listed
+    pub counts: [u64; 3],
+    limit: usize,
+}
+
+impl<T> Differences<T> {
+    fn new(limit: usize) -> Self {
+        Differences {
+            only_in_first: Vec::new(),
+            only_in_second: Vec::new(),
+            differing: Vec::new(),
+            counts: [0; 3],
+            limit,
+        }
+    }
+
+    fn note(&mut self, index: usize, record: T) {
+        self.counts[index] += 1;
+        let listed = match index {
+            0 => &mut self.only_in_first,
+            1 => &mut self.only_in_second,
+            _ => &mut self.differing,
+        };
+        if listed.len() < self.limit {
+            listed.push(record);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts == [0; 3]
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LedgerDiff {
+    pub blocks: Differences<BlockHash>,
+    pub accounts: Differences<PublicKey>,
+}
+
+impl LedgerDiff {
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty() && self.accounts.is_empty()
+    }
+}
+
+/// The blocks and account records which differ between `first` and `second`, listing
+/// at most `limit` of each kind of difference
+pub fn diff(first: &dyn Store, second: &dyn Store, limit: usize) -> Result<LedgerDiff> {
+    let mut blocks = Differences::new(limit);
+    first.for_each_block(&mut |hash, block| {
+        match second.block(&hash)? {
+            Some(ref other) if encode_block(other) != encode_block(&block) => blocks.note(2, hash),
+            Some(_) => (),
+            None if !second.is_pruned(&hash)? => blocks.note(0, hash),
+            None => (),
+        }
+        Ok(())
+    })?;
+    second.for_each_block(&mut |hash, _| {
+        if !first.block_exists(&hash)? && !first.is_pruned(&hash)? {
+            blocks.note(1, hash);
+        }
+        Ok(())
+    })?;
+
+    let mut accounts = Differences::new(limit);
+    first.for_each_account(&mut |account, info| {
+        match second.account(&account)? {
+            Some(ref other) if *other != info => accounts.note(2, account),
+            Some(_) => (),
+            None => accounts.note(0, account),
+        }
+        Ok(())
+    })?;
+    second.for_each_account(&mut |account, _| {
+        if first.account(&account)?.is_none() {
+            accounts.note(1, account);
+        }
+        Ok(())
+    })?;
+    Ok(LedgerDiff { blocks, accounts })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nano_lib_rs::block::{Block, BlockKind, BlockPayload};
+    use crate::store::{AccountInfo, WriteBatch};
+    use crate::store::memory::MemoryStore;
+
+    fn change(previous: u8, representative: u8) -> (BlockHash, Block) {
+        let mut block = Block::new(BlockKind::Change, Some(BlockPayload::Change {
+            previous: BlockHash::from_bytes(&[previous; 32]).unwrap(),
+            representative: PublicKey::from_bytes(&[representative; 32]).unwrap(),
+        }), None, None);
+        (block.hash(false).unwrap(), block)
+    }
+
+    #[test]
+    fn finds_differences() {
+        let (first, second) = (MemoryStore::new(), MemoryStore::new());
+        let (shared, only_first, only_second, pruned) = (change(1, 1), change(2, 1), change(3, 1), change(4, 1));
+        let account = PublicKey::from_bytes(&[9u8; 32]).unwrap();
+        let info = AccountInfo { head: shared.0, representative: account.clone(), balance: 1, confirmation_height: 0 };
+        let mut batch = WriteBatch::new();
+        for &(hash, ref block) in &[shared.clone(), only_first.clone(), pruned.clone()] {
+            batch.put_block(hash, block.clone());
+        }
+        batch.put_account(account.clone(), info.clone());
+        first.write(&batch).unwrap();
+        let mut batch = WriteBatch::new();
+        for &(hash, ref block) in &[shared, only_second.clone()] {
+            batch.put_block(hash, block.clone());
+        }
+        batch.prune_block(pruned.0);
+        batch.put_account(account.clone(), AccountInfo { balance: 2, ..info });
+        second.write(&batch).unwrap();
+
+        let differences = diff(&first, &second, 10).unwrap();
+        assert_eq!(differences.blocks.only_in_first, vec![only_first.0]);
+        assert_eq!(differences.blocks.only_in_second, vec![only_second.0]);
+        assert_eq!(differences.blocks.counts, [1, 1, 0]);
+        assert_eq!(differences.accounts.differing, vec![account]);
+        assert!(diff(&first, &first, 10).unwrap().is_empty());
+    }
+}
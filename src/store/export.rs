@@ -0,0 +1,409 @@
+//! Exporting the ledger to JSON or CSV dumps, and importing them into a fresh store.
+//!
+//! A dump is a sequence of records, each either a block or an account record, in no
+//! particular order. Every record has the fields in `COLUMNS` which apply to it:
+//!
+//! - `record`: `block` or `account`
+//! - `id`: the block's hash, or the account's address
+//! - `kind`: the block's kind, one of `send`, `receive`, `open`, `change` or `state`
+//! - `account`, `previous`, `representative`, `balance`, `link`: the fields of the
+//!   block's kind; `link` is the destination address of a send, the source hash of
+//!   a receive or open, and 64 hex digits for a state block
+//! - `contents`: the block's wire encoding, including its signature and work, in hex
+//! - `representative`, `balance`, `frontier`, `confirmation_height`: the fields of an
+//!   account record
+//!
+//! Hashes and keys are upper case hex, accounts are `nano_` addresses and balances
+//! are in raw. JSON dumps have one object per line, leaving out the fields which
+//! don't apply. CSV dumps start with a header of `COLUMNS`, and leave fields which
+//! don't apply empty; no field ever contains a comma or a quote.
+//!
+//! Only `contents` is read back when importing a block, and its hash is checked
+//! against `id`; the decoded fields are there for analysis.
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+
+use bytes::Bytes;
+use data_encoding::HEXUPPER;
+use serde_json::{self, Map, Value};
+
+use nano_lib_rs::block::{Block, BlockHash, BlockKind, BlockPayload};
+use nano_lib_rs::keys::PublicKey;
+
+use crate::error::*;
+use crate::utils::{account_to_address, address_to_account};
+use super::{heights, AccountInfo, Store, WriteBatch};
+
+/// The fields of a record, in the order of CSV columns
+pub const COLUMNS: [&str; 11] = [
+    "record", "id", "kind", "account", "previous", "representative", "balance", "link", "contents",
+    "frontier", "confirmation_height",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpFormat {
+    Json,
+    Csv,
+}
+
+impl DumpFormat {
+    pub fn from_name(name: &str) -> Option<DumpFormat> {
+        match name {
+            "json" => Some(DumpFormat::Json),
+            "csv" => Some(DumpFormat::Csv),
+            _ => None,
+        }
+    }
+}
+
+/// The number of records exported or imported
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DumpSummary {
+    pub blocks: u64,
+    pub accounts: u64,
+}
+
+type Record = Vec<(&'static str, String)>;
+
+fn kind_name(kind: BlockKind) -> &'static str {
+    match kind {
+        BlockKind::Send => "send",
+        BlockKind::Receive => "receive",
+        BlockKind::Open => "open",
+        BlockKind::Change => "change",
+        BlockKind::State => "state",
+        BlockKind::Invalid | BlockKind::NotABlock => "invalid",
+    }
+}
+
+fn kind_from_name(name: &str) -> Option<BlockKind> {
+    match name {
+        "send" => Some(BlockKind::Send),
+        "receive" => Some(BlockKind::Receive),
+        "open" => Some(BlockKind::Open),
+        "change" => Some(BlockKind::Change),
+        "state" => Some(BlockKind::State),
+        _ => None,
+    }
+}
+
+fn block_record(hash: BlockHash, block: &Block) -> Record {
+    let mut record = vec![("record", "block".to_owned()), ("id", String::from(hash)),
+        ("kind", kind_name(block.kind).to_owned())];
+    match block.payload {
+        Some(BlockPayload::Send { previous, ref destination, balance }) => {
+            record.push(("previous", String::from(previous)));
+            record.push(("balance", balance.to_string()));
+            record.push(("link", account_to_address(destination)));
+        }
+        Some(BlockPayload::Receive { previous, source }) => {
+            record.push(("previous", String::from(previous)));
+            record.push(("link", String::from(source)));
+        }
+        Some(BlockPayload::Open { source, ref representative, ref account }) => {
+            record.push(("account", account_to_address(account)));
+            record.push(("representative", account_to_address(representative)));
+            record.push(("link", String::from(source)));
+        }
+        Some(BlockPayload::Change { previous, ref representative }) => {
+            record.push(("previous", String::from(previous)));
+            record.push(("representative", account_to_address(representative)));
+        }
+        Some(BlockPayload::State { ref account, previous, ref representative, balance, ref link }) => {
+            record.push(("account", account_to_address(account)));
+            record.push(("previous", String::from(previous)));
+            record.push(("representative", account_to_address(representative)));
+            record.push(("balance", balance.to_string()));
+            record.push(("link", HEXUPPER.encode(link.as_bytes())));
+        }
+        None => {}
+    }
+    record.push(("contents", HEXUPPER.encode(&block.serialize_bytes())));
+    record
+}
+
+fn account_record(account: &PublicKey, info: &AccountInfo) -> Record {
+    vec![
+        ("record", "account".to_owned()),
+        ("id", account_to_address(account)),
+        ("representative", account_to_address(&info.representative)),
+        ("balance", info.balance.to_string()),
+        ("frontier", String::from(info.head)),
+        ("confirmation_height", info.confirmation_height.to_string()),
+    ]
+}
+
+/// Writes records in a `DumpFormat`
+struct DumpWriter<'a> {
+    format: DumpFormat,
+    out: &'a mut dyn Write,
+    summary: DumpSummary,
+}
+
+impl<'a> DumpWriter<'a> {
+    fn new(format: DumpFormat, out: &'a mut dyn Write) -> Result<Self> {
+        if format == DumpFormat::Csv {
+            writeln!(out, "{}", COLUMNS.join(","))?;
+        }
+        Ok(DumpWriter { format, out, summary: DumpSummary::default() })
+    }
+
+    fn write(&mut self, record: Record) -> Result<()> {
+        match self.format {
+            DumpFormat::Json => {
+                let object: Map<String, Value> = record.into_iter()
+                    .map(|(field, value)| (field.to_owned(), Value::String(value)))
+                    .collect();
+                writeln!(self.out, "{}", Value::Object(object))?;
+            }
+            DumpFormat::Csv => {
+                let row: Vec<&str> = COLUMNS.iter()
+                    .map(|column| record.iter()
+                        .find(|&&(field, _)| field == *column)
+                        .map_or("", |&(_, ref value)| value.as_str()))
+                    .collect();
+                writeln!(self.out, "{}", row.join(","))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn block(&mut self, hash: BlockHash, block: &Block) -> Result<()> {
+        self.summary.blocks += 1;
+        self.write(block_record(hash, block))
+    }
+
+    fn account(&mut self, account: &PublicKey, info: &AccountInfo) -> Result<()> {
+        self.summary.accounts += 1;
+        self.write(account_record(account, info))
+    }
+}
+
+/// The block preceding `block` in its chain, if it isn't the first
+pub fn previous(block: &Block) -> Option<BlockHash> {
+    match block.payload {
+        Some(BlockPayload::Send { previous, .. })
+        | Some(BlockPayload::Receive { previous, .. })
+        | Some(BlockPayload::Change { previous, .. })
+        | Some(BlockPayload::State { previous, .. }) if previous.as_bytes() != &[0u8; 32] => Some(previous),
+        _ => None,
+    }
+}
+
+/// The account whose chain `block` is in, found by following legacy blocks back to
+/// their open block. `None` if a block on the way isn't stored.
+pub fn chain_account(store: &dyn Store, block: &Block) -> Result<Option<PublicKey>> {
+    let mut block = block.clone();
+    loop {
+        match block.payload {
+            Some(BlockPayload::State { ref account, .. }) | Some(BlockPayload::Open { ref account, .. }) => {
+                return Ok(Some(account.clone()));
+            }
+            _ => (),
+        }
+        block = match previous(&block) {
+            Some(hash) => match store.block(&hash)? {
+                Some(block) => block,
+                None => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+    }
+}
+
+/// Write every block and account record of `store` to `out`
+pub fn export(store: &dyn Store, format: DumpFormat, out: &mut dyn Write) -> Result<DumpSummary> {
+    let mut writer = DumpWriter::new(format, out)?;
+    store.for_each_block(&mut |hash, block| writer.block(hash, &block))?;
+    store.for_each_account(&mut |account, info| writer.account(&account, &info))?;
+    Ok(writer.summary)
+}
+
+/// Write the records of `accounts` and the blocks of their chains to `out`. Chains are
+/// followed back from each account's latest block for as long as the blocks are
+/// stored.
+pub fn export_accounts(store: &dyn Store, accounts: &[PublicKey], format: DumpFormat, out: &mut dyn Write)
+    -> Result<DumpSummary>
+{
+    let mut writer = DumpWriter::new(format, out)?;
+    for account in accounts {
+        let info = match store.account(account)? {
+            Some(info) => info,
+            None => bail!("Account {} not found", account_to_address(account)),
+        };
+        writer.account(account, &info)?;
+        let mut next = Some(info.head);
+        while let Some(hash) = next {
+            next = match store.block(&hash)? {
+                Some(block) => {
+                    writer.block(hash, &block)?;
+                    previous(&block)
+                }
+                None => None,
+            };
+        }
+    }
+    Ok(writer.summary)
+}
+
+fn parse_record(format: DumpFormat, line: &str) -> Result<HashMap<String, String>> {
+    match format {
+        DumpFormat::Json => {
+            let object: Map<String, Value> = serde_json::from_str(line)?;
+            Ok(object.into_iter()
+                .filter_map(|(field, value)| value.as_str().map(|value| (field, value.to_owned())))
+                .collect())
+        }
+        DumpFormat::Csv => {
+            let values: Vec<&str> = line.split(',').collect();
+            if values.len() != COLUMNS.len() {
+                bail!("Expected {} columns, found {}", COLUMNS.len(), values.len());
+            }
+            Ok(COLUMNS.iter().zip(values)
+                .filter(|&(_, value)| !value.is_empty())
+                .map(|(column, value)| ((*column).to_owned(), value.to_owned()))
+                .collect())
+        }
+    }
+}
+
+fn field<'a>(record: &'a HashMap<String, String>, name: &str) -> Result<&'a str> {
+    record.get(name).map(String::as_str).ok_or_else(|| Error::from(format!("Missing field `{}`", name)))
+}
+
+fn import_block(record: &HashMap<String, String>) -> Result<(BlockHash, Block)> {
+    let kind = field(record, "kind")?;
+    let kind = kind_from_name(kind).ok_or_else(|| Error::from(format!("Unknown block kind `{}`", kind)))?;
+    let contents = HEXUPPER.decode(field(record, "contents")?.as_bytes())
+        .map_err(|_| Error::from("`contents` is not hex"))?;
+    let mut block = Block::deserialize_bytes(Bytes::from(contents), kind)?;
+    let hash = block.hash(false)?;
+    if String::from(hash) != field(record, "id")? {
+        bail!("Block {} has the hash {}", field(record, "id")?, String::from(hash));
+    }
+    Ok((hash, block))
+}
+
+fn import_account(record: &HashMap<String, String>) -> Result<(PublicKey, AccountInfo)> {
+    let account = address_to_account(field(record, "id")?)?;
+    let info = AccountInfo {
+        head: BlockHash::from_hex(field(record, "frontier")?)?,
+        representative: address_to_account(field(record, "representative")?)?,
+        balance: field(record, "balance")?.parse().map_err(|_| Error::from("`balance` is not a number"))?,
+        confirmation_height: field(record, "confirmation_height")?.parse()
+            .map_err(|_| Error::from("`confirmation_height` is not a number"))?,
+    };
+    Ok((account, info))
+}
+
+/// Read a dump from `input` into `store`, which must be empty, writing `batch_size`
+/// records per transaction. Representative weights are computed from the imported
+/// account records other than those of `burn_accounts`, and the heights of the
+/// imported blocks are indexed.
+pub fn import(store: &dyn Store, format: DumpFormat, input: &mut dyn BufRead, burn_accounts: &[PublicKey],
+    batch_size: usize) -> Result<DumpSummary>
+{
+    if store.block_count()? > 0 {
+        bail!("Dumps can only be imported into an empty store");
+    }
+    let mut summary = DumpSummary::default();
+    let mut batch = WriteBatch::new();
+    let mut weights: HashMap<[u8; 32], (PublicKey, u128)> = HashMap::new();
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() || (format == DumpFormat::Csv && i == 0) {
+            continue;
+        }
+        let record = parse_record(format, &line).chain_err(|| format!("Invalid record on line {}", i + 1))?;
+        let imported = match field(&record, "record")? {
+            "block" => import_block(&record).map(|(hash, block)| {
+                batch.put_block(hash, block);
+                summary.blocks += 1;
+            }),
+            "account" => import_account(&record).map(|(account, info)| {
+                if !burn_accounts.contains(&account) {
+                    let weight = weights.entry(*info.representative.as_bytes())
+                        .or_insert_with(|| (info.representative.clone(), 0));
+                    weight.1 = weight.1.saturating_add(info.balance);
+                }
+                batch.put_account(account, info);
+                summary.accounts += 1;
+            }),
+            other => Err(Error::from(format!("Unknown record `{}`", other))),
+        };
+        imported.chain_err(|| format!("Invalid record on line {}", i + 1))?;
+        if batch.len() + batch.accounts().len() >= batch_size.max(1) {
+            store.write(&batch)?;
+            batch.clear();
+        }
+    }
+    for (_, (representative, weight)) in weights {
+        batch.put_weight(representative, weight);
+    }
+    store.write(&batch)?;
+    heights::index(store, batch_size)?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nano_lib_rs::block::{Link, Work};
+    use nano_lib_rs::keys::{public_key_from_secret, SecretKey};
+    use crate::store::memory::MemoryStore;
+
+    fn ledger() -> MemoryStore {
+        let secret = SecretKey::from_bytes(&[5u8; 32]).unwrap();
+        let account = public_key_from_secret(&secret);
+        let mut block = Block::new(BlockKind::State, Some(BlockPayload::State {
+            account: account.clone(),
+            previous: BlockHash::from_bytes(&[0u8; 32]).unwrap(),
+            representative: account.clone(),
+            balance: 42,
+            link: Link::Unknown([1u8; 32]),
+        }), None, Some(Work::from_hex("0000000000000000").unwrap()));
+        block.sign(&secret).unwrap();
+        let hash = block.hash(false).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put_block(hash, block);
+        batch.put_account(account.clone(), AccountInfo {
+            head: hash,
+            representative: account,
+            balance: 42,
+            confirmation_height: 1,
+        });
+        let store = MemoryStore::new();
+        store.write(&batch).unwrap();
+        store
+    }
+
+    #[test]
+    fn round_trips_dumps() {
+        let store = ledger();
+        for &format in [DumpFormat::Json, DumpFormat::Csv].iter() {
+            let mut dump = Vec::new();
+            assert_eq!(export(&store, format, &mut dump).unwrap(), DumpSummary { blocks: 1, accounts: 1 });
+
+            let imported = MemoryStore::new();
+            let summary = import(&imported, format, &mut &dump[..], &[], 1).unwrap();
+            assert_eq!(summary, DumpSummary { blocks: 1, accounts: 1 });
+            let mut again = Vec::new();
+            export(&imported, format, &mut again).unwrap();
+            assert_eq!(dump, again);
+            assert_eq!(imported.weights().unwrap()[0].1, 42);
+            assert!(import(&imported, format, &mut &dump[..], &[], 1).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_mismatched_hashes() {
+        let mut dump = Vec::new();
+        export(&ledger(), DumpFormat::Json, &mut dump).unwrap();
+        let dump = String::from_utf8(dump).unwrap();
+        let block = dump.lines().next().unwrap();
+        let mut record: Map<String, Value> = serde_json::from_str(block).unwrap();
+        record.insert("id".to_owned(), json!(String::from(BlockHash::from_bytes(&[9u8; 32]).unwrap())));
+        let dump = format!("{}\n", Value::Object(record));
+        assert!(import(&MemoryStore::new(), DumpFormat::Json, &mut dump.as_bytes(), &[], 1).is_err());
+    }
+}
@@ -0,0 +1,219 @@
+//! Bootstrapping an empty node from a trusted ledger snapshot.
+//!
+//! Rather than pulling every block from peers, a node with an empty store can
+//! download a ledger dump (see `export`) over HTTPS from a configured URL. The dump
+//! is imported into a staging database next to the store, checked against the
+//! configured `checksum`, and a random sample of its chains is walked from their
+//! frontiers back to their open blocks, checking every signature on the way, by the
+//! account or, for epoch blocks, by the network's epoch signer. Only
+//! then does the staging database replace the store. The snapshot is trusted for
+//! everything which isn't spot checked, so the URL and checksum should come from a
+//! source the operator trusts.
+use std::path::PathBuf;
+
+use nano_lib_rs::block::{BlockHash, BlockPayload};
+use nano_lib_rs::keys::PublicKey;
+use nano_lib_rs::network::NetworkParams;
+
+use crate::error::*;
+use super::export::DumpFormat;
+use super::{Store, StoreConfig};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnapshotConfig {
+    /// The `https://` URL of the ledger dump
+    pub url: String,
+    pub format: DumpFormat,
+    /// The expected block checksum of the ledger, as computed by `ledger_checksum`
+    pub checksum: [u8; 32],
+    /// The number of randomly chosen chains to validate
+    pub spot_checks: usize,
+}
+
+/// Where the snapshot is downloaded and imported before replacing the store at `path`
+fn staging_paths(path: &PathBuf) -> (PathBuf, PathBuf) {
+    let mut download = path.clone().into_os_string();
+    download.push(".download");
+    let mut staging = path.clone().into_os_string();
+    staging.push(".staging");
+    (PathBuf::from(download), PathBuf::from(staging))
+}
+
+/// Walk the chain of `account` back from its frontier `head`, checking that every
+/// block is signed by the account, or by the epoch's signer on `network` if it is an
+/// epoch block, and that the chain ends in an open block
+fn validate_chain(store: &dyn Store, network: &NetworkParams, account: &PublicKey, head: BlockHash) -> Result<u64> {
+    let mut next = head;
+    let mut blocks = 0;
+    loop {
+        let mut block = store.block(&next)?
+            .ok_or_else(|| Error::from(format!("Block {} is missing", String::from(next))))?;
+        if block.hash(false)? != next {
+            bail!("Block {} is stored under the wrong hash", String::from(next));
+        }
+        let epoch_signer = match block.payload {
+            Some(BlockPayload::State { ref link, .. }) => network.epoch_signer(link.as_bytes()).cloned(),
+            _ => None,
+        };
+        let signed = block.verify_signature(account)? || match epoch_signer {
+            Some(signer) => block.verify_signature(&signer)?,
+            None => false,
+        };
+        if !signed {
+            bail!("Block {} has an invalid signature", String::from(next));
+        }
+        blocks += 1;
+        let previous = match block.payload {
+            Some(BlockPayload::Open { .. }) => None,
+            Some(BlockPayload::State { previous, .. }) if previous.as_bytes() == &[0u8; 32] => None,
+            Some(BlockPayload::Send { previous, .. })
+            | Some(BlockPayload::Receive { previous, .. })
+            | Some(BlockPayload::Change { previous, .. })
+            | Some(BlockPayload::State { previous, .. }) => Some(previous),
+            None => bail!("Block {} has no contents", String::from(next)),
+        };
+        match previous {
+            Some(previous) => next = previous,
+            None => return Ok(blocks),
+        }
+    }
+}
+
+/// Validate the chains of `count` accounts of `store`, chosen at random
+pub fn spot_check(store: &dyn Store, network: &NetworkParams, count: usize) -> Result<u64> {
+    use rand::Rng;
+
+    // Reservoir sampling, so that the accounts don't have to be held in memory
+    let mut rng = ::rand::thread_rng();
+    let mut sample: Vec<(PublicKey, BlockHash)> = Vec::with_capacity(count);
+    let mut seen = 0usize;
+    store.for_each_account(&mut |account, info| {
+        seen += 1;
+        if sample.len() < count {
+            sample.push((account, info.head));
+        } else {
+            let i = rng.gen_range(0, seen);
+            if i < count {
+                sample[i] = (account, info.head);
+            }
+        }
+        Ok(())
+    })?;
+    let mut blocks = 0;
+    for (account, head) in sample {
+        blocks += validate_chain(store, network, &account, head)?;
+    }
+    Ok(blocks)
+}
+
+/// Whether the store described by `config` holds no blocks yet
+pub fn is_empty(config: &StoreConfig) -> Result<bool> {
+    Ok(!config.path.exists() || super::open(config)?.block_count()? == 0)
+}
+
+/// Download the snapshot described by `snapshot` and install it as the store
+/// described by `config`, which must be empty, for a node on `network`
+#[cfg(all(feature = "lmdb", any(feature = "rpc", feature = "dpow")))]
+pub fn fast_sync(snapshot: &SnapshotConfig, config: &StoreConfig, network: &NetworkParams) -> Result<()> {
+    use std::fs::{self, File};
+    use std::io::{BufReader, BufWriter};
+    use data_encoding::HEXUPPER;
+    use super::{checksum, export, lmdb::LmdbStore};
+
+    if !is_empty(config)? {
+        bail!("Snapshots can only be installed in place of an empty store");
+    }
+    let (download, staging) = staging_paths(&config.path);
+    info!("Downloading ledger snapshot from {}", snapshot.url);
+    {
+        let mut response = reqwest::blocking::Client::builder()
+            .timeout(None)
+            .build()?
+            .get(&snapshot.url)
+            .send()?
+            .error_for_status()?;
+        let mut file = BufWriter::new(File::create(&download)?);
+        let bytes = response.copy_to(&mut file)?;
+        info!("Downloaded {} bytes", bytes);
+    }
+
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    let verified = (|| -> Result<()> {
+        let store = LmdbStore::open(&staging)?;
+        let mut input = BufReader::new(File::open(&download)?);
+        let summary = export::import(&store, snapshot.format, &mut input, &network.burn_accounts,
+            config.batch.max_blocks)?;
+        info!("Imported {} blocks and {} accounts from the snapshot", summary.blocks, summary.accounts);
+        let computed = checksum::checksum(&store, false)?;
+        if computed.blocks != snapshot.checksum {
+            bail!("Snapshot checksum is {}, expected {}", HEXUPPER.encode(&computed.blocks),
+                HEXUPPER.encode(&snapshot.checksum));
+        }
+        let blocks = spot_check(&store, network, snapshot.spot_checks)?;
+        info!("Validated {} blocks of {} randomly chosen chains", blocks, snapshot.spot_checks);
+        Ok(())
+    })();
+    let _ = fs::remove_file(&download);
+    if let Err(e) = verified {
+        let _ = fs::remove_dir_all(&staging);
+        return Err(e).chain_err(|| "The ledger snapshot could not be verified");
+    }
+    if config.path.exists() {
+        fs::remove_dir_all(&config.path)?;
+    }
+    fs::rename(&staging, &config.path)?;
+    info!("Installed the ledger snapshot in {}", config.path.display());
+    Ok(())
+}
+
+#[cfg(not(all(feature = "lmdb", any(feature = "rpc", feature = "dpow"))))]
+pub fn fast_sync(_snapshot: &SnapshotConfig, _config: &StoreConfig, _network: &NetworkParams) -> Result<()> {
+    bail!("Fast sync needs nano-rs to be built with the `lmdb` feature, and the `rpc` or `dpow` feature")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nano_lib_rs::block::{Block, BlockKind, Link, Work};
+    use nano_lib_rs::keys::{public_key_from_secret, SecretKey, Signature};
+    use crate::store::{AccountInfo, WriteBatch};
+    use crate::store::memory::MemoryStore;
+
+    #[test]
+    fn validates_chains() {
+        let secret = SecretKey::from_bytes(&[3u8; 32]).unwrap();
+        let account = public_key_from_secret(&secret);
+        let mut batch = WriteBatch::new();
+        let mut previous = BlockHash::from_bytes(&[0u8; 32]).unwrap();
+        for balance in 1..4 {
+            let mut block = Block::new(BlockKind::State, Some(BlockPayload::State {
+                account: account.clone(),
+                previous,
+                representative: account.clone(),
+                balance,
+                link: Link::Unknown([0u8; 32]),
+            }), None, Some(Work::from_hex("0000000000000000").unwrap()));
+            block.sign(&secret).unwrap();
+            previous = block.hash(false).unwrap();
+            batch.put_block(previous, block);
+        }
+        batch.put_account(account.clone(), AccountInfo {
+            head: previous,
+            representative: account.clone(),
+            balance: 3,
+            confirmation_height: 0,
+        });
+        let store = MemoryStore::new();
+        store.write(&batch).unwrap();
+        assert_eq!(spot_check(&store, &NetworkParams::dev(), 8).unwrap(), 3);
+
+        let mut forged = batch.blocks()[1].1.clone();
+        forged.signature = Some(Signature::from_bytes(&[1u8; 64]).unwrap());
+        batch.clear();
+        batch.put_block(forged.hash(false).unwrap(), forged);
+        store.write(&batch).unwrap();
+        assert!(spot_check(&store, &NetworkParams::dev(), 8).is_err());
+    }
+}
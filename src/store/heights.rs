@@ -0,0 +1,103 @@
+//! Indexing the account and height of each block in its chain, so that whether a block
+//! is cemented is told by comparing its height with the account's confirmation height
+//! rather than by walking the chain.
+//!
+//! The block writer indexes each block it writes, one above its predecessor. Ledgers
+//! copied in whole, by `migrate` or `export::import`, are indexed once copied, by
+//! walking each account's chain back from its latest block to the first block already
+//! indexed or to its open block. A chain which reaches a block pruned before it was
+//! indexed is left unindexed, as the heights of the blocks above it aren't known.
+use nano_lib_rs::block::BlockHash;
+
+use crate::error::*;
+use super::export::previous;
+use super::{BlockHeight, Store, WriteBatch};
+
+/// Index the heights of the blocks in `store` which aren't indexed yet, writing up to
+/// `batch_size` heights per transaction. Returns the number of blocks indexed.
+pub fn index(store: &dyn Store, batch_size: usize) -> Result<u64> {
+    let mut heads = Vec::new();
+    store.for_each_account(&mut |account, info| {
+        heads.push((account, info.head));
+        Ok(())
+    })?;
+    let mut batch = WriteBatch::new();
+    let mut indexed = 0;
+    for (account, head) in heads {
+        let (chain, base) = unindexed(store, head)?;
+        for (height, &hash) in chain.iter().rev().enumerate() {
+            batch.put_height(hash, BlockHeight { account: account.clone(), height: base + height as u64 + 1 });
+        }
+        indexed += chain.len() as u64;
+        if batch.heights().len() >= batch_size.max(1) {
+            store.write(&batch)?;
+            batch.clear();
+        }
+    }
+    store.write(&batch)?;
+    Ok(indexed)
+}
+
+/// The blocks from `head` back which aren't indexed, latest first, and the height of
+/// the block below them. No blocks if the chain reaches a pruned block first.
+fn unindexed(store: &dyn Store, head: BlockHash) -> Result<(Vec<BlockHash>, u64)> {
+    let mut chain = Vec::new();
+    let mut current = Some(head);
+    while let Some(hash) = current {
+        if let Some(indexed) = store.height(&hash)? {
+            return Ok((chain, indexed.height));
+        }
+        match store.block(&hash)? {
+            Some(block) => current = previous(&block),
+            None => return Ok((Vec::new(), 0)),
+        }
+        chain.push(hash);
+    }
+    Ok((chain, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nano_lib_rs::block::{Block, BlockKind, BlockPayload, Link};
+    use nano_lib_rs::keys::PublicKey;
+    use crate::store::AccountInfo;
+    use crate::store::memory::MemoryStore;
+
+    #[test]
+    fn indexes_chains_from_their_heads() {
+        let store = MemoryStore::new();
+        let account = PublicKey::from_bytes(&[1u8; 32]).unwrap();
+        let mut batch = WriteBatch::new();
+        let mut previous = BlockHash::from_bytes(&[0u8; 32]).unwrap();
+        let mut hashes = Vec::new();
+        for balance in &[30, 20, 10] {
+            let mut block = Block::new(BlockKind::State, Some(BlockPayload::State {
+                account: account.clone(),
+                previous,
+                representative: account.clone(),
+                balance: *balance,
+                link: Link::Unknown([2u8; 32]),
+            }), None, None);
+            previous = block.hash(false).unwrap();
+            batch.put_block(previous, block);
+            hashes.push(previous);
+        }
+        batch.put_account(account.clone(), AccountInfo {
+            head: previous,
+            representative: account.clone(),
+            balance: 10,
+            confirmation_height: 2,
+        });
+        // The open block is already indexed, so only the blocks above it are
+        batch.put_height(hashes[0], BlockHeight { account: account.clone(), height: 1 });
+        store.write(&batch).unwrap();
+
+        assert_eq!(index(&store, 1).unwrap(), 2);
+        for (height, hash) in hashes.iter().enumerate() {
+            let indexed = BlockHeight { account: account.clone(), height: height as u64 + 1 };
+            assert_eq!(store.height(hash).unwrap(), Some(indexed));
+        }
+        assert_eq!(index(&store, 1).unwrap(), 0);
+    }
+}
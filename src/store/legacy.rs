@@ -0,0 +1,297 @@
+//! Recording the accounts of chains of legacy blocks, for ledgers written by a nano-rs
+//! which left legacy blocks out of the account records.
+//!
+//! Such a ledger has no record, and so no weight, for an account until its first state
+//! block, and no pending entries for legacy sends. `record` resolves them by a scan of
+//! every block: the balance as of a legacy block is that of the latest send before it,
+//! plus the amounts received since, each the balance before its send less the balance
+//! after, and the representative is that of the latest open or change block. An open
+//! block receiving from its own account's key, as genesis blocks do, holds every raw.
+//! A chain whose balance depends on a pruned block, or which doesn't reach its open
+//! block, is left unrecorded.
+use std::collections::{HashMap, HashSet};
+
+use nano_lib_rs::block::{Block, BlockHash, BlockKind, BlockPayload};
+use nano_lib_rs::keys::PublicKey;
+use nano_lib_rs::network::GENESIS_AMOUNT;
+
+use crate::error::*;
+use super::export::previous;
+use super::{AccountInfo, BlockHeight, PendingInfo, Store, WriteBatch};
+
+/// A balance, or a block whose balance has to be found first
+enum Lookup {
+    Known(Option<u128>),
+    Needs(BlockHash),
+}
+
+/// The legacy blocks of a store, with the balances found so far
+struct Ledger<'a> {
+    store: &'a dyn Store,
+    legacy: HashMap<BlockHash, Block>,
+    balances: HashMap<BlockHash, Option<u128>>,
+}
+
+impl<'a> Ledger<'a> {
+    fn block(&self, hash: &BlockHash) -> Result<Option<Block>> {
+        match self.legacy.get(hash) {
+            Some(block) => Ok(Some(block.clone())),
+            None => self.store.block(hash),
+        }
+    }
+
+    /// The balance as of `hash`, if it can be found
+    fn balance(&mut self, hash: BlockHash) -> Result<Option<u128>> {
+        let mut stack = vec![hash];
+        while let Some(&top) = stack.last() {
+            if self.balances.contains_key(&top) {
+                stack.pop();
+                continue;
+            }
+            match self.lookup(&top)? {
+                Lookup::Known(balance) => {
+                    self.balances.insert(top, balance);
+                    stack.pop();
+                }
+                Lookup::Needs(dependency) => stack.push(dependency),
+            }
+        }
+        Ok(self.balances[&hash])
+    }
+
+    /// The balance as of `hash`, from the balances found so far
+    fn lookup(&self, hash: &BlockHash) -> Result<Lookup> {
+        let block = match self.block(hash)? {
+            Some(block) => block,
+            None => return Ok(Lookup::Known(None)),
+        };
+        let source = match block.payload {
+            Some(BlockPayload::State { balance, .. }) | Some(BlockPayload::Send { balance, .. }) => {
+                return Ok(Lookup::Known(Some(balance)));
+            }
+            Some(BlockPayload::Open { ref account, source, .. }) if source.as_bytes() == account.as_bytes() => {
+                return Ok(Lookup::Known(Some(GENESIS_AMOUNT)));
+            }
+            Some(BlockPayload::Receive { source, .. }) | Some(BlockPayload::Open { source, .. }) => Some(source),
+            _ => None,
+        };
+        let before = match self.before(&block) {
+            Lookup::Known(before) => before,
+            needs => return Ok(needs),
+        };
+        let amount = match source {
+            Some(source) => match self.amount(&source)? {
+                Lookup::Known(amount) => amount,
+                needs => return Ok(needs),
+            },
+            None => Some(0),
+        };
+        Ok(Lookup::Known(match (before, amount) {
+            (Some(before), Some(amount)) => before.checked_add(amount),
+            _ => None,
+        }))
+    }
+
+    /// The balance before `block`, from the balances found so far
+    fn before(&self, block: &Block) -> Lookup {
+        match previous(block) {
+            Some(previous) => match self.balances.get(&previous) {
+                Some(&balance) => Lookup::Known(balance),
+                None => Lookup::Needs(previous),
+            },
+            None => Lookup::Known(Some(0)),
+        }
+    }
+
+    /// The amount sent by the send `hash`, from the balances found so far
+    fn amount(&self, hash: &BlockHash) -> Result<Lookup> {
+        let block = match self.block(hash)? {
+            Some(block) => block,
+            None => return Ok(Lookup::Known(None)),
+        };
+        let balance = match block.payload {
+            Some(BlockPayload::Send { balance, .. }) | Some(BlockPayload::State { balance, .. }) => balance,
+            _ => return Ok(Lookup::Known(None)),
+        };
+        Ok(match self.before(&block) {
+            Lookup::Known(before) => Lookup::Known(before.and_then(|before| before.checked_sub(balance))),
+            needs => needs,
+        })
+    }
+
+    /// The amount sent by the send `hash`, if it can be found
+    fn sent(&mut self, hash: &BlockHash) -> Result<Option<u128>> {
+        if let Lookup::Needs(previous) = self.amount(hash)? {
+            self.balance(previous)?;
+        }
+        Ok(match self.amount(hash)? {
+            Lookup::Known(amount) => amount,
+            Lookup::Needs(_) => None,
+        })
+    }
+
+    /// The legacy chain ending at `head`, latest first, and the account of its open
+    /// block, unless the chain reaches a block which isn't stored
+    fn chain(&self, head: BlockHash) -> Option<(Vec<BlockHash>, PublicKey)> {
+        let mut chain = Vec::new();
+        let mut current = head;
+        loop {
+            let block = self.legacy.get(&current)?;
+            chain.push(current);
+            match block.payload {
+                Some(BlockPayload::Open { ref account, .. }) => return Some((chain, account.clone())),
+                _ => current = previous(block)?,
+            }
+        }
+    }
+}
+
+/// The records of the accounts of `store` whose chains are only legacy blocks, with
+/// their weights unless they are `burn_accounts`, the heights of their blocks if they
+/// aren't indexed, and the pending entries of the legacy sends which haven't been
+/// received, as a batch to write. Also returns the number of chains which couldn't be
+/// recorded.
+pub fn record(store: &dyn Store, burn_accounts: &[PublicKey]) -> Result<(WriteBatch, u64)> {
+    let mut legacy = HashMap::new();
+    let mut followed = HashSet::new();
+    let mut received = HashSet::new();
+    store.for_each_block(&mut |hash, block| {
+        if let Some(previous) = previous(&block) {
+            followed.insert(previous);
+        }
+        match block.payload {
+            Some(BlockPayload::Receive { source, .. }) | Some(BlockPayload::Open { source, .. }) => {
+                received.insert(source);
+            }
+            // The links of state sends are accounts, which a block hash never matches
+            Some(BlockPayload::State { ref link, .. }) => {
+                received.insert(BlockHash::from_bytes(link.as_bytes())?);
+            }
+            _ => (),
+        }
+        if block.kind != BlockKind::State {
+            legacy.insert(hash, block);
+        }
+        Ok(())
+    })?;
+    let mut ledger = Ledger { store, legacy, balances: HashMap::new() };
+    let chains: Vec<_> = ledger.legacy.keys().filter(|hash| !followed.contains(*hash)).cloned().collect();
+    let mut weights: HashMap<_, _> = store.weights()?.into_iter()
+        .map(|(representative, weight)| (*representative.as_bytes(), weight))
+        .collect();
+    let mut batch = WriteBatch::new();
+    let mut accounts = HashMap::new();
+    let mut unresolved = 0;
+    for head in chains {
+        let (chain, account) = match ledger.chain(head) {
+            Some(chain) => chain,
+            None => {
+                unresolved += 1;
+                continue;
+            }
+        };
+        let representative = chain.iter().filter_map(|hash| match ledger.legacy[hash].payload {
+            Some(BlockPayload::Open { ref representative, .. })
+            | Some(BlockPayload::Change { ref representative, .. }) => Some(representative.clone()),
+            _ => None,
+        }).next();
+        let balance = ledger.balance(head)?;
+        let recorded = store.account(&account)?.is_some();
+        let (representative, balance) = match (representative, balance) {
+            (Some(representative), Some(balance)) if !recorded => (representative, balance),
+            _ => {
+                unresolved += 1;
+                continue;
+            }
+        };
+        if !burn_accounts.contains(&account) {
+            let weight = weights.entry(*representative.as_bytes()).or_insert(0);
+            *weight = weight.saturating_add(balance);
+            batch.put_weight(representative.clone(), *weight);
+        }
+        if store.height(&head)?.is_none() {
+            for (height, &hash) in chain.iter().rev().enumerate() {
+                batch.put_height(hash, BlockHeight { account: account.clone(), height: height as u64 + 1 });
+            }
+        }
+        for &hash in &chain {
+            accounts.insert(hash, account.clone());
+        }
+        batch.put_account(account, AccountInfo { head, representative, balance, confirmation_height: 0 });
+    }
+    let sends: Vec<_> = ledger.legacy.iter().filter_map(|(&hash, block)| match block.payload {
+        Some(BlockPayload::Send { ref destination, .. }) if !received.contains(&hash) => {
+            Some((hash, destination.clone()))
+        }
+        _ => None,
+    }).collect();
+    for (hash, destination) in sends {
+        // Sends of chains followed by state blocks have accounts with records, but no
+        // pending entries either
+        let source = match accounts.get(&hash) {
+            Some(account) => Some(account.clone()),
+            None => ledger.chain(hash).map(|(_, account)| account),
+        };
+        if let (Some(source), Some(amount)) = (source, ledger.sent(&hash)?) {
+            batch.put_pending(destination, hash, PendingInfo { source, amount });
+        }
+    }
+    Ok((batch, unresolved))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::MemoryStore;
+
+    fn key(byte: u8) -> PublicKey {
+        PublicKey::from_bytes(&[byte; 32]).unwrap()
+    }
+
+    fn put(batch: &mut WriteBatch, payload: BlockPayload) -> BlockHash {
+        let kind = match payload {
+            BlockPayload::Send { .. } => BlockKind::Send,
+            BlockPayload::Receive { .. } => BlockKind::Receive,
+            BlockPayload::Open { .. } => BlockKind::Open,
+            BlockPayload::Change { .. } => BlockKind::Change,
+            BlockPayload::State { .. } => BlockKind::State,
+        };
+        let mut block = Block::new(kind, Some(payload), None, None);
+        let hash = block.hash(false).unwrap();
+        batch.put_block(hash, block);
+        hash
+    }
+
+    #[test]
+    fn records_chains_of_legacy_blocks() {
+        let store = MemoryStore::new();
+        let mut batch = WriteBatch::new();
+        let genesis = put(&mut batch, BlockPayload::Open {
+            source: BlockHash::from_bytes(key(1).as_bytes()).unwrap(),
+            representative: key(1),
+            account: key(1),
+        });
+        let send = put(&mut batch, BlockPayload::Send { previous: genesis, destination: key(2), balance: 100 });
+        let change = put(&mut batch, BlockPayload::Change { previous: send, representative: key(3) });
+        let open = put(&mut batch, BlockPayload::Open { source: send, representative: key(2), account: key(2) });
+        let unreceived = put(&mut batch, BlockPayload::Send { previous: open, destination: key(4), balance: 10 });
+        // A chain whose open block was pruned
+        put(&mut batch, BlockPayload::Change { previous: BlockHash::from_bytes(&[5u8; 32]).unwrap(),
+            representative: key(5) });
+        store.write(&batch).unwrap();
+
+        // The second account is a burn account, so its balance lends no weight
+        let (batch, unresolved) = record(&store, &[key(2)]).unwrap();
+        assert_eq!(unresolved, 1);
+        store.write(&batch).unwrap();
+        let info = store.account(&key(1)).unwrap().unwrap();
+        assert_eq!((info.head, info.representative, info.balance), (change, key(3), 100));
+        let info = store.account(&key(2)).unwrap().unwrap();
+        assert_eq!((info.head, info.balance), (unreceived, 10));
+        assert_eq!(store.weights().unwrap(), vec![(key(3), 100)]);
+        let pending = store.pending(&key(4)).unwrap();
+        assert_eq!((pending[0].0, pending[0].1.amount), (unreceived, GENESIS_AMOUNT - 100 - 10));
+        assert!(store.pending(&key(2)).unwrap().is_empty());
+        assert_eq!(store.height(&change).unwrap(), Some(BlockHeight { account: key(1), height: 3 }));
+    }
+}
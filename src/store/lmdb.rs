@@ -0,0 +1,1181 @@
+//! A store in an LMDB database, with one LMDB database per table. The block counts
+//! are kept in the `meta` database, updated in the transaction writing each batch.
+//!
+//! The `meta` database also records the version of the layout the database was written
+//! in. Opening a database written by an older nano-rs upgrades it in place, by running
+//! the migrations in `MIGRATIONS` from its version on, each in a transaction which also
+//! records the version it upgraded to, so an interrupted upgrade resumes where it
+//! stopped. A database which isn't empty is first copied to `pre-migration-v<version>`
+//! in its directory, to go back to if an upgrade goes wrong. Databases written by a
+//! newer nano-rs aren't opened, nor are databases opened read-only upgraded.
+//!
+//! A write which finds the memory map full is aborted, and retried once the map has
+//! been doubled. LMDB only allows the map to be resized while the process has no
+//! transactions open, so `open` counts the transactions open on each thread, and a
+//! resize waits for there to be none, holding back the transactions begun meanwhile by
+//! threads with none open. A thread can't resize the map while it has a transaction
+//! open itself, so a write which fills the map while its thread has a snapshot open
+//! fails rather than waiting for itself. Transactions which find the map was grown by
+//! another process adopt the new size and begin again.
+//!
+//! A database opened read-only, by a second process such as an indexer reading the
+//! ledger of a running node, is never written, nor are its counts or tables created.
+//! LMDB coordinates the processes through `lock.mdb`, which the reader must still be
+//! able to write: readers never wait for the writer or hold it up, but the pages a
+//! reader's snapshot sees aren't reused until it ends, so long snapshots grow the
+//! file.
+//!
+//! Each read is made in a snapshot, `LmdbSnapshot`, of its own, or of the caller's
+//! when the store is read through `Store::snapshot`. LMDB's read transactions are
+//! isolated from writes committed after they began, so the reads of one snapshot agree
+//! without holding up the writer. The environment is opened with `MDB_NOTLS`, so that
+//! a thread can read a snapshot while it has another one open.
+//!
+//! Unless every commit is synced, the database is opened with `MDB_NOSYNC`. With the
+//! batched policy, a thread flushes it at each interval until the store is dropped, and
+//! dropping the store flushes it whatever the policy.
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{self, ThreadId};
+
+use lmdb::{Cursor, Database, DatabaseFlags, Environment, EnvironmentFlags, RoTransaction, RwTransaction, Transaction,
+    WriteFlags};
+
+use nano_lib_rs::block::{Block, BlockHash, BlockKind};
+use nano_lib_rs::keys::PublicKey;
+use nano_lib_rs::network::{burn_account, NetworkParams};
+
+use crate::error::*;
+use super::export::previous;
+use super::{decode_block, decode_weight, encode_block, encode_weight, legacy, pending_key, unchecked_key,
+    AccountInfo, BlockCounts, BlockHeight, LmdbOptions, PendingInfo, Store, StoreStats, SyncPolicy, TableStats, WriteBatch};
+
+const MAX_DBS: u32 = 8;
+
+/// Concurrent read transactions allowed in a read-mostly database, such as those of RPC
+/// queries; LMDB's default is 126
+const READ_MOSTLY_MAX_READERS: u32 = 1024;
+
+/// Records written per transaction when compacting
+const COMPACT_TXN_RECORDS: u64 = 65_536;
+
+/// The key of the block counts in the `meta` database
+const BLOCK_COUNTS_KEY: &[u8] = b"block_counts";
+
+/// The key in the `meta` database of when the database was compacted into, in seconds
+/// since the Unix epoch
+const LAST_VACUUM_KEY: &[u8] = b"last_vacuum";
+
+/// The key of the schema version in the `meta` database, a big-endian `u64`
+const SCHEMA_VERSION_KEY: &[u8] = b"schema_version";
+
+/// The version of the layout this nano-rs writes
+const SCHEMA_VERSION: u64 = 4;
+
+/// A step upgrading a database to `version` from the version before it
+struct Migration {
+    version: u64,
+    description: &'static str,
+    migrate: fn(&LmdbStore, &mut RwTransaction) -> Result<()>,
+}
+
+/// The migrations from version 0, in order. Version 0 is the layout before the block
+/// counts were kept.
+const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, description: "count the blocks and cemented blocks", migrate: LmdbStore::count_blocks },
+    Migration { version: 2, description: "index the height of each block", migrate: LmdbStore::index_heights },
+    Migration {
+        version: 3,
+        description: "record the accounts of chains of legacy blocks",
+        migrate: LmdbStore::record_legacy_accounts,
+    },
+    Migration {
+        version: 4,
+        description: "leave the balances of burn accounts out of the weights",
+        migrate: LmdbStore::rebuild_weights,
+    },
+];
+
+#[derive(Debug)]
+pub struct LmdbStore {
+    env: Arc<Environment>,
+    path: PathBuf,
+    blocks: Database,
+    /// The account and height of each block, by hash
+    heights: Database,
+    accounts: Database,
+    weights: Database,
+    /// The hashes of pruned blocks, with empty values
+    pruned: Database,
+    /// Sends which haven't been received, by `pending_key`
+    pending: Database,
+    /// Blocks waiting for a block they depend on, by `unchecked_key`
+    unchecked: Database,
+    /// Records about the database as a whole, such as the block counts
+    meta: Database,
+    /// The transactions open, which the map can only be resized without
+    open: Mutex<OpenTxns>,
+    /// Notified when the last open transaction ends, and when a resize is done
+    idle: Condvar,
+    /// The size of the memory map in bytes
+    map_size: AtomicUsize,
+    read_only: bool,
+}
+
+/// The database as of a read transaction
+struct LmdbSnapshot<'a> {
+    store: &'a LmdbStore,
+    txn: Mutex<Txn<'a, RoTransaction<'a>>>,
+}
+
+// LMDB lets a read transaction be used from any thread, by one thread at a time, as the
+// environment is opened with `MDB_NOTLS`, and `txn` is only used while it is locked.
+// Snapshots are only lent out, never moved, so each is dropped on the thread which took
+// it, which `open` counts it against.
+unsafe impl<'a> Send for LmdbSnapshot<'a> {}
+unsafe impl<'a> Sync for LmdbSnapshot<'a> {}
+
+impl<'a> fmt::Debug for LmdbSnapshot<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LmdbSnapshot").finish()
+    }
+}
+
+/// The number of transactions each thread has open, and whether a resize is waiting for
+/// them to end
+#[derive(Debug, Default)]
+struct OpenTxns {
+    threads: HashMap<ThreadId, usize>,
+    resizing: bool,
+}
+
+/// A transaction counted in `open`, until it is dropped
+struct OpenTxn<'a> {
+    store: &'a LmdbStore,
+    thread: ThreadId,
+}
+
+impl<'a> Drop for OpenTxn<'a> {
+    fn drop(&mut self) {
+        let mut open = self.store.open.lock().unwrap();
+        let count = open.threads.get_mut(&self.thread).expect("an open transaction is counted");
+        *count -= 1;
+        if *count == 0 {
+            open.threads.remove(&self.thread);
+            if open.threads.is_empty() {
+                self.store.idle.notify_all();
+            }
+        }
+    }
+}
+
+/// A transaction, during which the memory map isn't resized
+struct Txn<'a, T> {
+    txn: T,
+    _open: OpenTxn<'a>,
+}
+
+impl<'a, T> Deref for Txn<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.txn
+    }
+}
+
+impl<'a, T> DerefMut for Txn<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.txn
+    }
+}
+
+impl<'a> Txn<'a, RwTransaction<'a>> {
+    fn commit(self) -> Result<()> {
+        self.txn.commit()?;
+        Ok(())
+    }
+}
+
+/// Flush `env` to disk every `interval`, until it is closed
+fn spawn_syncer(env: ::std::sync::Weak<Environment>, interval: ::std::time::Duration) -> Result<()> {
+    thread::Builder::new().name("lmdb-sync".into()).spawn(move || loop {
+        thread::sleep(interval);
+        let env = match env.upgrade() {
+            Some(env) => env,
+            None => return,
+        };
+        if let Err(e) = env.sync(true) {
+            warn!("Error syncing the LMDB store: {}", e);
+        }
+    })?;
+    Ok(())
+}
+
+/// The calls into LMDB which the `lmdb` crate doesn't wrap
+mod ffi {
+    use std::mem;
+    use std::os::raw::c_int;
+
+    use lmdb::{Database, Environment, Error, Transaction};
+    use lmdb_sys::{self, MDB_envinfo, MDB_stat};
+
+    fn check(code: c_int) -> Result<(), Error> {
+        if code == lmdb_sys::MDB_SUCCESS { Ok(()) } else { Err(Error::from_err_code(code)) }
+    }
+
+    /// Set the size of the memory map, or with a size of 0 adopt the size another
+    /// process grew it to. The process must have no transactions open.
+    pub fn set_map_size(env: &Environment, size: usize) -> Result<(), Error> {
+        check(unsafe { lmdb_sys::mdb_env_set_mapsize(env.env(), size) })
+    }
+
+    pub fn env_info(env: &Environment) -> Result<MDB_envinfo, Error> {
+        let mut info: MDB_envinfo = unsafe { mem::zeroed() };
+        check(unsafe { lmdb_sys::mdb_env_info(env.env(), &mut info) })?;
+        Ok(info)
+    }
+
+    pub fn env_stat(env: &Environment) -> Result<MDB_stat, Error> {
+        let mut stat: MDB_stat = unsafe { mem::zeroed() };
+        check(unsafe { lmdb_sys::mdb_env_stat(env.env(), &mut stat) })?;
+        Ok(stat)
+    }
+
+    pub fn db_stat<T: Transaction>(txn: &T, db: Database) -> Result<MDB_stat, Error> {
+        let mut stat: MDB_stat = unsafe { mem::zeroed() };
+        check(unsafe { lmdb_sys::mdb_stat(txn.txn(), db.dbi(), &mut stat) })?;
+        Ok(stat)
+    }
+}
+
+/// Whether `e` is LMDB running out of room in the memory map
+fn is_map_full(e: &Error) -> bool {
+    match *e.kind() {
+        ErrorKind::LmdbError(::lmdb::Error::MapFull) => true,
+        _ => false,
+    }
+}
+
+impl LmdbStore {
+    /// Open the database in the directory `path`, creating it if needed
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        LmdbStore::open_with(path, &LmdbOptions::default())
+    }
+
+    /// Open the database in `path` as set by `options`. A read-mostly database is tuned
+    /// for random reads from many readers: the OS doesn't read ahead of the pages LMDB
+    /// asks for, as queries rarely read neighbouring records.
+    pub fn open_with<P: AsRef<Path>>(path: P, options: &LmdbOptions) -> Result<Self> {
+        let path = path.as_ref();
+        let mut builder = Environment::new();
+        builder.set_max_dbs(MAX_DBS).set_map_size(options.map_size);
+        let mut flags = EnvironmentFlags::NO_TLS;
+        if options.read_mostly {
+            flags.insert(EnvironmentFlags::NO_READAHEAD);
+            builder.set_max_readers(READ_MOSTLY_MAX_READERS);
+        }
+        if options.read_only {
+            if !path.join("data.mdb").is_file() {
+                bail!("There is no LMDB store at {}", path.display());
+            }
+            flags.insert(EnvironmentFlags::READ_ONLY);
+        } else {
+            fs::create_dir_all(path)?;
+            if options.sync != SyncPolicy::Always {
+                flags.insert(EnvironmentFlags::NO_SYNC);
+            }
+        }
+        builder.set_flags(flags);
+        let env = Arc::new(builder.open(path)?);
+        if let (SyncPolicy::Batched(interval), false) = (options.sync, options.read_only) {
+            spawn_syncer(Arc::downgrade(&env), interval)?;
+        }
+        let open_db = |name: &'static str| -> Result<Database> {
+            if !options.read_only {
+                return Ok(env.create_db(Some(name), DatabaseFlags::empty())?);
+            }
+            env.open_db(Some(name)).map_err(|e| match e {
+                ::lmdb::Error::NotFound => Error::from(format!("The LMDB store at {} has no {} table; open it once \
+                    without --read-only to create it", path.display(), name)),
+                e => e.into(),
+            })
+        };
+        let store = LmdbStore {
+            blocks: open_db("blocks")?,
+            heights: open_db("heights")?,
+            accounts: open_db("accounts")?,
+            weights: open_db("weights")?,
+            pruned: open_db("pruned")?,
+            pending: open_db("pending")?,
+            unchecked: open_db("unchecked")?,
+            meta: open_db("meta")?,
+            env,
+            path: path.to_owned(),
+            open: Mutex::new(OpenTxns::default()),
+            idle: Condvar::new(),
+            map_size: AtomicUsize::new(options.map_size),
+            read_only: options.read_only,
+        };
+        if store.read_only {
+            let version = store.schema_version(&*store.read_txn()?)?;
+            store.check_version(version)?;
+            if version < SCHEMA_VERSION {
+                bail!("The LMDB store at {} was written by an older nano-rs; open it once without --read-only to \
+                    upgrade it", path.display());
+            }
+        } else {
+            store.migrate()?;
+        }
+        Ok(store)
+    }
+
+    /// Begin a transaction with `begin`, adopting the size of a map which another
+    /// process grew
+    fn begin<'a, T, F>(&'a self, begin: F) -> Result<Txn<'a, T>>
+        where F: Fn(&'a Environment) -> ::std::result::Result<T, ::lmdb::Error>
+    {
+        loop {
+            let open = self.count_txn();
+            match begin(&*self.env) {
+                Ok(txn) => return Ok(Txn { txn, _open: open }),
+                Err(::lmdb::Error::MapResized) => {
+                    drop(open);
+                    self.resize(|| {
+                        // A size of 0 adopts the size the map has grown to
+                        ffi::set_map_size(&self.env, 0)?;
+                        self.map_size.store(ffi::env_info(&self.env)?.me_mapsize, Ordering::SeqCst);
+                        Ok(())
+                    })?;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Count a transaction about to begin on this thread, first waiting out any resize
+    /// unless the thread has transactions open already, which the resize is waiting for
+    fn count_txn(&self) -> OpenTxn<'_> {
+        let thread = thread::current().id();
+        let mut open = self.open.lock().unwrap();
+        while open.resizing && !open.threads.contains_key(&thread) {
+            open = self.idle.wait(open).unwrap();
+        }
+        *open.threads.entry(thread).or_insert(0) += 1;
+        OpenTxn { store: self, thread }
+    }
+
+    /// Run `resize` once no transactions are open, failing if this thread has one open
+    fn resize<F: FnOnce() -> Result<()>>(&self, resize: F) -> Result<()> {
+        let mut open = self.open.lock().unwrap();
+        if open.threads.contains_key(&thread::current().id()) {
+            bail!("The LMDB map can't be resized while this thread has a transaction open");
+        }
+        while open.resizing {
+            open = self.idle.wait(open).unwrap();
+        }
+        open.resizing = true;
+        while !open.threads.is_empty() {
+            open = self.idle.wait(open).unwrap();
+        }
+        let result = resize();
+        open.resizing = false;
+        self.idle.notify_all();
+        result
+    }
+
+    fn read_txn(&self) -> Result<Txn<'_, RoTransaction<'_>>> {
+        self.begin(|env| env.begin_ro_txn())
+    }
+
+    /// A snapshot of the database as it is now
+    fn view(&self) -> Result<LmdbSnapshot<'_>> {
+        Ok(LmdbSnapshot { store: self, txn: Mutex::new(self.read_txn()?) })
+    }
+
+    fn write_txn(&self) -> Result<Txn<'_, RwTransaction<'_>>> {
+        self.begin(|env| env.begin_rw_txn())
+    }
+
+    /// Double the memory map, unless it has grown since it was `size` bytes
+    fn grow_map(&self, size: usize) -> Result<()> {
+        self.resize(|| {
+            if self.map_size.load(Ordering::SeqCst) != size {
+                return Ok(());
+            }
+            let grown = size.saturating_mul(2);
+            if grown == size {
+                bail!("The LMDB map is full at {} bytes, and can't grow any further", size);
+            }
+            ffi::set_map_size(&self.env, grown)?;
+            self.map_size.store(grown, Ordering::SeqCst);
+            info!("The LMDB map was full, grew it from {} to {} MiB", size >> 20, grown >> 20);
+            Ok(())
+        })
+    }
+
+    /// The block counts as of `txn`, or `None` if they were never stored
+    fn stored_counts<T: Transaction>(&self, txn: &T) -> Result<Option<BlockCounts>> {
+        match txn.get(self.meta, &BLOCK_COUNTS_KEY) {
+            Ok(encoded) => Ok(Some(BlockCounts::decode(encoded)?)),
+            Err(::lmdb::Error::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The version of the database's layout as of `txn`. Databases written before the
+    /// version was recorded are at version 1 if they have block counts.
+    fn schema_version<T: Transaction>(&self, txn: &T) -> Result<u64> {
+        match txn.get(self.meta, &SCHEMA_VERSION_KEY) {
+            Ok(encoded) if encoded.len() == 8 => {
+                let mut version = [0u8; 8];
+                version.copy_from_slice(encoded);
+                Ok(u64::from_be_bytes(version))
+            }
+            Ok(_) => bail!("The LMDB store at {} has a corrupt schema version", self.path.display()),
+            Err(::lmdb::Error::NotFound) => Ok(if self.stored_counts(txn)?.is_some() { 1 } else { 0 }),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Fail if the database is at a `version` newer than this nano-rs knows
+    fn check_version(&self, version: u64) -> Result<()> {
+        if version > SCHEMA_VERSION {
+            bail!("The LMDB store at {} is at schema version {}, written by a newer nano-rs; this one only \
+                reads up to version {}", self.path.display(), version, SCHEMA_VERSION);
+        }
+        Ok(())
+    }
+
+    /// Upgrade the database to `SCHEMA_VERSION`, backing it up first unless it is empty
+    fn migrate(&self) -> Result<()> {
+        let (version, empty) = {
+            let txn = self.read_txn()?;
+            (self.schema_version(&*txn)?, ffi::db_stat(&*txn, self.blocks)?.ms_entries == 0)
+        };
+        self.check_version(version)?;
+        if version == SCHEMA_VERSION {
+            return Ok(());
+        }
+        if !empty {
+            let backup = self.path.join(format!("pre-migration-v{}", version));
+            if backup.exists() {
+                bail!("{} already exists; move or remove the backup of an earlier upgrade first", backup.display());
+            }
+            info!("Backing up the LMDB store to {} before upgrading it", backup.display());
+            // Copied under another name first, so that an interrupted copy isn't taken
+            // for a backup
+            let partial = self.path.join(format!("pre-migration-v{}.partial", version));
+            if partial.exists() {
+                fs::remove_dir_all(&partial)?;
+            }
+            self.copy_into(&partial)?;
+            fs::rename(&partial, &backup)?;
+        }
+        for migration in MIGRATIONS.iter().filter(|migration| migration.version > version) {
+            info!("Upgrading the LMDB store to schema version {}: {}", migration.version, migration.description);
+            loop {
+                let size = self.map_size.load(Ordering::SeqCst);
+                match self.run_migration(migration) {
+                    Err(ref e) if is_map_full(e) => self.grow_map(size)?,
+                    result => break result?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn run_migration(&self, migration: &Migration) -> Result<()> {
+        let mut txn = self.write_txn()?;
+        (migration.migrate)(self, &mut txn)?;
+        txn.put(self.meta, &SCHEMA_VERSION_KEY, &migration.version.to_be_bytes(), WriteFlags::empty())?;
+        txn.commit()
+    }
+
+    /// Count the blocks and cemented blocks by a scan, for a database created before the
+    /// counts were kept
+    fn count_blocks(&self, txn: &mut RwTransaction) -> Result<()> {
+        let mut counts = BlockCounts::default();
+        {
+            let mut cursor = txn.open_ro_cursor(self.blocks)?;
+            for (_, value) in cursor.iter() {
+                if let Some(kind) = value.first().and_then(|&kind| BlockKind::from_value(kind)) {
+                    counts.add_block(kind);
+                }
+            }
+            let mut cursor = txn.open_ro_cursor(self.accounts)?;
+            for (_, value) in cursor.iter() {
+                counts.set_cemented(0, AccountInfo::decode(value)?.confirmation_height);
+            }
+        }
+        if counts.total() > 0 {
+            info!("Counted {} blocks in the LMDB store", counts.total());
+        }
+        txn.put(self.meta, &BLOCK_COUNTS_KEY, &counts.encode(), WriteFlags::empty())?;
+        Ok(())
+    }
+
+    /// Index the heights of the blocks of each account's chain, walking it back from
+    /// its latest block, for a database created before heights were indexed. A chain
+    /// which reaches a pruned block is left unindexed, as the height of the blocks
+    /// above it isn't known.
+    fn index_heights(&self, txn: &mut RwTransaction) -> Result<()> {
+        let mut heads = Vec::new();
+        {
+            let mut cursor = txn.open_ro_cursor(self.accounts)?;
+            for (key, value) in cursor.iter() {
+                heads.push((PublicKey::from_bytes(key)?, AccountInfo::decode(value)?.head));
+            }
+        }
+        let mut indexed = 0;
+        for (account, head) in heads {
+            let mut chain = Vec::new();
+            let mut current = Some(head);
+            while let Some(hash) = current {
+                match txn.get(self.blocks, hash.as_bytes()) {
+                    Ok(encoded) => current = previous(&decode_block(encoded)?),
+                    Err(::lmdb::Error::NotFound) => {
+                        chain.clear();
+                        break;
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+                chain.push(hash);
+            }
+            for (height, hash) in chain.iter().rev().enumerate() {
+                let height = BlockHeight { account: account.clone(), height: height as u64 + 1 };
+                txn.put(self.heights, hash.as_bytes(), &height.encode(), WriteFlags::empty())?;
+            }
+            indexed += chain.len();
+        }
+        if indexed > 0 {
+            info!("Indexed the heights of {} blocks in the LMDB store", indexed);
+        }
+        Ok(())
+    }
+
+    /// Record the accounts whose chains are only legacy blocks, with their weights and
+    /// the pending entries of their sends, for a database written before legacy blocks
+    /// were accounted for. The ledger is read as of the previous migration.
+    fn record_legacy_accounts(&self, txn: &mut RwTransaction) -> Result<()> {
+        let burn_accounts = self.burn_accounts(&*txn)?;
+        let (batch, unresolved) = legacy::record(&self.view()?, &burn_accounts)?;
+        self.put_batch(txn, &batch)?;
+        if !batch.accounts().is_empty() {
+            info!("Recorded {} accounts of legacy blocks in the LMDB store", batch.accounts().len());
+        }
+        if unresolved > 0 {
+            warn!("{} chains of legacy blocks reach pruned blocks, and were left without account records or \
+                weight", unresolved);
+        }
+        Ok(())
+    }
+
+    /// Compute the weights from the account records again, leaving out the balances of
+    /// burn accounts, which a database written before they were left out counts
+    fn rebuild_weights(&self, txn: &mut RwTransaction) -> Result<()> {
+        let burn_accounts = self.burn_accounts(&*txn)?;
+        let mut weights: HashMap<[u8; 32], u128> = HashMap::new();
+        {
+            let mut cursor = txn.open_ro_cursor(self.accounts)?;
+            for (key, value) in cursor.iter() {
+                let info = AccountInfo::decode(value)?;
+                if !burn_accounts.contains(&PublicKey::from_bytes(key)?) {
+                    let weight = weights.entry(*info.representative.as_bytes()).or_insert(0);
+                    *weight = weight.saturating_add(info.balance);
+                }
+            }
+        }
+        txn.clear_db(self.weights)?;
+        for (representative, weight) in weights {
+            txn.put(self.weights, &representative, &encode_weight(weight), WriteFlags::empty())?;
+        }
+        Ok(())
+    }
+
+    /// The burn accounts of the known network whose genesis account has a record, or
+    /// the burn account every network has. A store doesn't know its network, so it is
+    /// told by the genesis account, as `migrate` tells it.
+    fn burn_accounts<T: Transaction>(&self, txn: &T) -> Result<Vec<PublicKey>> {
+        for network in &[NetworkParams::live(), NetworkParams::beta(), NetworkParams::test(), NetworkParams::dev()] {
+            match txn.get(self.accounts, network.genesis_account.as_bytes()) {
+                Ok(_) => return Ok(network.burn_accounts.clone()),
+                Err(::lmdb::Error::NotFound) => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(vec![burn_account()])
+    }
+
+    /// Each database, by its name
+    fn databases(&self) -> [(&'static str, Database); 8] {
+        [("blocks", self.blocks), ("heights", self.heights), ("accounts", self.accounts), ("weights", self.weights), ("pruned", self.pruned),
+            ("pending", self.pending), ("unchecked", self.unchecked), ("meta", self.meta)]
+    }
+
+    /// Copy every record into a new database in the directory `destination`, as
+    /// `mdb_copy -c` does: records are appended in order, so that pages are packed full
+    /// and the pages freed by deletions aren't copied. The copy records when it was
+    /// made, for `stats`. Returns the records copied.
+    pub fn compact_into(&self, destination: &Path) -> Result<u64> {
+        let (env, copied) = self.copy_into(destination)?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs());
+        let meta = env.open_db(Some("meta"))?;
+        let mut txn = env.begin_rw_txn()?;
+        txn.put(meta, &LAST_VACUUM_KEY, &now.to_be_bytes(), WriteFlags::empty())?;
+        txn.commit()?;
+        env.sync(true)?;
+        Ok(copied)
+    }
+
+    /// Copy every table, packed in order, into a new database in `destination`,
+    /// returning it and the number of records copied
+    fn copy_into(&self, destination: &Path) -> Result<(Environment, u64)> {
+        fs::create_dir_all(destination)?;
+        let env = Environment::new()
+            .set_max_dbs(MAX_DBS)
+            .set_map_size(self.map_size.load(Ordering::SeqCst))
+            .open(destination)?;
+        let source = self.read_txn()?;
+        let mut copied = 0;
+        for &(name, db) in &self.databases() {
+            let copy = env.create_db(Some(name), DatabaseFlags::empty())?;
+            let mut txn = env.begin_rw_txn()?;
+            let mut cursor = source.open_ro_cursor(db)?;
+            for (key, value) in cursor.iter() {
+                txn.put(copy, &key, &value, WriteFlags::APPEND)?;
+                copied += 1;
+                if copied % COMPACT_TXN_RECORDS == 0 {
+                    txn.commit()?;
+                    txn = env.begin_rw_txn()?;
+                }
+            }
+            txn.commit()?;
+        }
+        env.sync(true)?;
+        Ok((env, copied))
+    }
+
+    /// The kind of the block `hash` as of `txn`, if it is stored
+    fn stored_kind(&self, txn: &RwTransaction, hash: &BlockHash) -> Result<Option<BlockKind>> {
+        match txn.get(self.blocks, hash.as_bytes()) {
+            Ok(encoded) => Ok(encoded.first().and_then(|&kind| BlockKind::from_value(kind))),
+            Err(::lmdb::Error::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Drop for LmdbStore {
+    fn drop(&mut self) {
+        if self.read_only {
+            return;
+        }
+        if let Err(e) = self.env.sync(true) {
+            warn!("Error syncing the LMDB store: {}", e);
+        }
+    }
+}
+
+impl Store for LmdbStore {
+    fn name(&self) -> &str {
+        "LMDB"
+    }
+
+    fn block(&self, hash: &BlockHash) -> Result<Option<Block>> {
+        self.view()?.block(hash)
+    }
+
+    fn block_exists(&self, hash: &BlockHash) -> Result<bool> {
+        self.view()?.block_exists(hash)
+    }
+
+    fn block_counts(&self) -> Result<BlockCounts> {
+        self.view()?.block_counts()
+    }
+
+    fn is_pruned(&self, hash: &BlockHash) -> Result<bool> {
+        self.view()?.is_pruned(hash)
+    }
+
+    fn pruned_count(&self) -> Result<u64> {
+        self.view()?.pruned_count()
+    }
+
+    fn height(&self, hash: &BlockHash) -> Result<Option<BlockHeight>> {
+        self.view()?.height(hash)
+    }
+
+    fn account(&self, account: &PublicKey) -> Result<Option<AccountInfo>> {
+        self.view()?.account(account)
+    }
+
+    fn account_count(&self) -> Result<u64> {
+        self.view()?.account_count()
+    }
+
+    fn pending(&self, account: &PublicKey) -> Result<Vec<(BlockHash, PendingInfo)>> {
+        self.view()?.pending(account)
+    }
+
+    fn unchecked(&self, dependency: &BlockHash) -> Result<Vec<(BlockHash, Block)>> {
+        self.view()?.unchecked(dependency)
+    }
+
+    fn unchecked_count(&self) -> Result<u64> {
+        self.view()?.unchecked_count()
+    }
+
+    fn weights(&self) -> Result<Vec<(PublicKey, u128)>> {
+        self.view()?.weights()
+    }
+
+    fn for_each_block(&self, f: &mut dyn FnMut(BlockHash, Block) -> Result<()>) -> Result<()> {
+        self.view()?.for_each_block(f)
+    }
+
+    fn for_each_account(&self, f: &mut dyn FnMut(PublicKey, AccountInfo) -> Result<()>) -> Result<()> {
+        self.view()?.for_each_account(f)
+    }
+
+    fn write(&self, batch: &WriteBatch) -> Result<()> {
+        if self.read_only {
+            bail!("The LMDB store was opened read-only");
+        }
+        loop {
+            let size = self.map_size.load(Ordering::SeqCst);
+            match self.try_write(batch) {
+                Err(ref e) if is_map_full(e) => self.grow_map(size)?,
+                result => return result,
+            }
+        }
+    }
+
+    fn stats(&self) -> Result<StoreStats> {
+        let (info, stat) = (ffi::env_info(&self.env)?, ffi::env_stat(&self.env)?);
+        let txn = self.read_txn()?;
+        let mut tables = Vec::new();
+        for &(name, db) in &self.databases() {
+            let stat = ffi::db_stat(&*txn, db)?;
+            tables.push(TableStats {
+                name,
+                entries: stat.ms_entries as u64,
+                pages: Some((stat.ms_branch_pages + stat.ms_leaf_pages + stat.ms_overflow_pages) as u64),
+                depth: Some(u64::from(stat.ms_depth)),
+            });
+        }
+        let last_vacuum = match txn.get(self.meta, &LAST_VACUUM_KEY) {
+            Ok(encoded) if encoded.len() == 8 => {
+                let mut secs = [0u8; 8];
+                secs.copy_from_slice(encoded);
+                Some(u64::from_be_bytes(secs))
+            }
+            Ok(_) | Err(::lmdb::Error::NotFound) => None,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(StoreStats {
+            size_on_disk: Some(fs::metadata(self.path.join("data.mdb"))?.len()),
+            map_size: Some(info.me_mapsize as u64),
+            page_size: Some(u64::from(stat.ms_psize)),
+            pages_used: Some(info.me_last_pgno as u64 + 1),
+            readers: Some(u64::from(info.me_numreaders)),
+            cached: None,
+            last_vacuum,
+            tables,
+        })
+    }
+
+    fn snapshot(&self, f: &mut dyn FnMut(&dyn Store) -> Result<()>) -> Result<()> {
+        f(&self.view()?)
+    }
+}
+
+impl<'a> Store for LmdbSnapshot<'a> {
+    fn name(&self) -> &str {
+        "LMDB"
+    }
+
+    fn block(&self, hash: &BlockHash) -> Result<Option<Block>> {
+        let txn = self.txn.lock().unwrap();
+        match txn.get(self.store.blocks, hash.as_bytes()) {
+            Ok(encoded) => Ok(Some(decode_block(encoded)?)),
+            Err(::lmdb::Error::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn block_exists(&self, hash: &BlockHash) -> Result<bool> {
+        let txn = self.txn.lock().unwrap();
+        match txn.get(self.store.blocks, hash.as_bytes()) {
+            Ok(_) => Ok(true),
+            Err(::lmdb::Error::NotFound) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn block_counts(&self) -> Result<BlockCounts> {
+        let txn = self.txn.lock().unwrap();
+        Ok(self.store.stored_counts(&**txn)?.unwrap_or_default())
+    }
+
+    fn is_pruned(&self, hash: &BlockHash) -> Result<bool> {
+        let txn = self.txn.lock().unwrap();
+        match txn.get(self.store.pruned, hash.as_bytes()) {
+            Ok(_) => Ok(true),
+            Err(::lmdb::Error::NotFound) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn pruned_count(&self) -> Result<u64> {
+        let txn = self.txn.lock().unwrap();
+        let mut cursor = txn.open_ro_cursor(self.store.pruned)?;
+        Ok(cursor.iter().count() as u64)
+    }
+
+    fn height(&self, hash: &BlockHash) -> Result<Option<BlockHeight>> {
+        let txn = self.txn.lock().unwrap();
+        match txn.get(self.store.heights, hash.as_bytes()) {
+            Ok(encoded) => Ok(Some(BlockHeight::decode(encoded)?)),
+            Err(::lmdb::Error::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn account(&self, account: &PublicKey) -> Result<Option<AccountInfo>> {
+        let txn = self.txn.lock().unwrap();
+        match txn.get(self.store.accounts, account.as_bytes()) {
+            Ok(encoded) => Ok(Some(AccountInfo::decode(encoded)?)),
+            Err(::lmdb::Error::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn account_count(&self) -> Result<u64> {
+        let txn = self.txn.lock().unwrap();
+        let mut cursor = txn.open_ro_cursor(self.store.accounts)?;
+        Ok(cursor.iter().count() as u64)
+    }
+
+    fn pending(&self, account: &PublicKey) -> Result<Vec<(BlockHash, PendingInfo)>> {
+        let txn = self.txn.lock().unwrap();
+        let mut cursor = txn.open_ro_cursor(self.store.pending)?;
+        let mut entries = Vec::new();
+        // iter_from unwraps its seek, which fails when no key sorts at or after the account
+        match cursor.get(Some(account.as_bytes()), None, ::lmdb_sys::MDB_SET_RANGE) {
+            Ok(_) => (),
+            Err(::lmdb::Error::NotFound) => return Ok(entries),
+            Err(e) => return Err(e.into()),
+        }
+        for (key, value) in cursor.iter_from(account.as_bytes()) {
+            if key.len() != 64 || &key[..32] != account.as_bytes() {
+                break;
+            }
+            entries.push((BlockHash::from_bytes(&key[32..])?, PendingInfo::decode(value)?));
+        }
+        Ok(entries)
+    }
+
+    fn unchecked(&self, dependency: &BlockHash) -> Result<Vec<(BlockHash, Block)>> {
+        let txn = self.txn.lock().unwrap();
+        let mut cursor = txn.open_ro_cursor(self.store.unchecked)?;
+        let mut entries = Vec::new();
+        match cursor.get(Some(dependency.as_bytes()), None, ::lmdb_sys::MDB_SET_RANGE) {
+            Ok(_) => (),
+            Err(::lmdb::Error::NotFound) => return Ok(entries),
+            Err(e) => return Err(e.into()),
+        }
+        for (key, value) in cursor.iter_from(dependency.as_bytes()) {
+            if key.len() != 64 || &key[..32] != dependency.as_bytes() {
+                break;
+            }
+            entries.push((BlockHash::from_bytes(&key[32..])?, decode_block(value)?));
+        }
+        Ok(entries)
+    }
+
+    fn unchecked_count(&self) -> Result<u64> {
+        let txn = self.txn.lock().unwrap();
+        Ok(ffi::db_stat(&**txn, self.store.unchecked)?.ms_entries as u64)
+    }
+
+    fn weights(&self) -> Result<Vec<(PublicKey, u128)>> {
+        let txn = self.txn.lock().unwrap();
+        let mut cursor = txn.open_ro_cursor(self.store.weights)?;
+        let mut weights = Vec::new();
+        for (key, value) in cursor.iter() {
+            weights.push((PublicKey::from_bytes(key)?, decode_weight(value)?));
+        }
+        Ok(weights)
+    }
+
+    fn for_each_block(&self, f: &mut dyn FnMut(BlockHash, Block) -> Result<()>) -> Result<()> {
+        let txn = self.txn.lock().unwrap();
+        let mut cursor = txn.open_ro_cursor(self.store.blocks)?;
+        for (key, value) in cursor.iter() {
+            f(BlockHash::from_bytes(key)?, decode_block(value)?)?;
+        }
+        Ok(())
+    }
+
+    fn for_each_account(&self, f: &mut dyn FnMut(PublicKey, AccountInfo) -> Result<()>) -> Result<()> {
+        let txn = self.txn.lock().unwrap();
+        let mut cursor = txn.open_ro_cursor(self.store.accounts)?;
+        for (key, value) in cursor.iter() {
+            f(PublicKey::from_bytes(key)?, AccountInfo::decode(value)?)?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, _batch: &WriteBatch) -> Result<()> {
+        bail!("A snapshot of the store can't be written")
+    }
+
+    fn snapshot(&self, f: &mut dyn FnMut(&dyn Store) -> Result<()>) -> Result<()> {
+        f(self)
+    }
+}
+
+impl LmdbStore {
+    /// Write `batch` in one transaction, which is aborted if it fails
+    fn try_write(&self, batch: &WriteBatch) -> Result<()> {
+        let mut txn = self.write_txn()?;
+        self.put_batch(&mut txn, batch)?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Make every change in `batch` in `txn`
+    fn put_batch(&self, txn: &mut RwTransaction, batch: &WriteBatch) -> Result<()> {
+        let mut counts = self.stored_counts(&*txn)?.unwrap_or_default();
+        for &(hash, ref block) in batch.blocks() {
+            if self.stored_kind(&txn, &hash)?.is_none() {
+                counts.add_block(block.kind);
+            }
+            txn.put(self.blocks, hash.as_bytes(), &encode_block(block), WriteFlags::empty())?;
+        }
+        for &(hash, ref height) in batch.heights() {
+            txn.put(self.heights, hash.as_bytes(), &height.encode(), WriteFlags::empty())?;
+        }
+        for &(ref account, ref info) in batch.accounts() {
+            let before = match txn.get(self.accounts, account.as_bytes()) {
+                Ok(encoded) => AccountInfo::decode(encoded)?.confirmation_height,
+                Err(::lmdb::Error::NotFound) => 0,
+                Err(e) => return Err(e.into()),
+            };
+            counts.set_cemented(before, info.confirmation_height);
+            txn.put(self.accounts, account.as_bytes(), &info.encode(), WriteFlags::empty())?;
+        }
+        for &(ref representative, weight) in batch.weights() {
+            txn.put(self.weights, representative.as_bytes(), &encode_weight(weight), WriteFlags::empty())?;
+        }
+        for hash in batch.pruned() {
+            if let Some(kind) = self.stored_kind(&txn, hash)? {
+                counts.remove_block(kind);
+            }
+            match txn.del(self.blocks, hash.as_bytes(), None) {
+                Ok(()) | Err(::lmdb::Error::NotFound) => (),
+                Err(e) => return Err(e.into()),
+            }
+            txn.put(self.pruned, hash.as_bytes(), b"", WriteFlags::empty())?;
+        }
+        for hash in batch.deleted_blocks() {
+            if let Some(kind) = self.stored_kind(&txn, hash)? {
+                counts.remove_block(kind);
+                txn.del(self.blocks, hash.as_bytes(), None)?;
+            }
+            match txn.del(self.heights, hash.as_bytes(), None) {
+                Ok(()) | Err(::lmdb::Error::NotFound) => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        for account in batch.deleted_accounts() {
+            let before = match txn.get(self.accounts, account.as_bytes()) {
+                Ok(encoded) => Some(AccountInfo::decode(encoded)?.confirmation_height),
+                Err(::lmdb::Error::NotFound) => None,
+                Err(e) => return Err(e.into()),
+            };
+            if let Some(before) = before {
+                counts.set_cemented(before, 0);
+                txn.del(self.accounts, account.as_bytes(), None)?;
+            }
+        }
+        for &(ref account, ref hash, ref info) in batch.pending() {
+            txn.put(self.pending, &pending_key(account, hash), &info.encode(), WriteFlags::empty())?;
+        }
+        for &(ref account, ref hash) in batch.received() {
+            match txn.del(self.pending, &pending_key(account, hash), None) {
+                Ok(()) | Err(::lmdb::Error::NotFound) => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        for &(ref dependency, ref hash, ref block) in batch.unchecked() {
+            txn.put(self.unchecked, &unchecked_key(dependency, hash), &encode_block(block), WriteFlags::empty())?;
+        }
+        for &(ref dependency, ref hash) in batch.checked() {
+            match txn.del(self.unchecked, &unchecked_key(dependency, hash), None) {
+                Ok(()) | Err(::lmdb::Error::NotFound) => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        txn.put(self.meta, &BLOCK_COUNTS_KEY, &counts.encode(), WriteFlags::empty())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nano_lib_rs::block::{BlockPayload, Link, Work};
+    use nano_lib_rs::keys::Signature;
+    use nano_lib_rs::network::GENESIS_AMOUNT;
+
+    #[test]
+    fn grows_a_full_map() {
+        let dir = ::std::env::temp_dir().join(format!("nano-rs-lmdb-test-{}", ::std::process::id()));
+        let options = LmdbOptions { map_size: 256 * 1024, ..LmdbOptions::default() };
+        let store = LmdbStore::open_with(&dir, &options).unwrap();
+        let account = PublicKey::from_bytes(&[1u8; 32]).unwrap();
+        let mut batch = WriteBatch::new();
+        for i in 0..4096u32 {
+            let mut link = [0u8; 32];
+            link[..4].copy_from_slice(&i.to_le_bytes());
+            let mut block = Block::new(BlockKind::State, Some(BlockPayload::State {
+                account: account.clone(),
+                previous: BlockHash::from_bytes(&[2u8; 32]).unwrap(),
+                representative: account.clone(),
+                balance: 1,
+                link: Link::Unknown(link),
+            }), None, None);
+            batch.put_block(block.hash(false).unwrap(), block);
+        }
+        // The map can't grow while the writing thread has a snapshot open, though the
+        // thread can still read in other transactions meanwhile
+        store.snapshot(&mut |view| {
+            assert!(store.write(&batch).is_err());
+            assert_eq!(store.block_count()?, 0);
+            assert_eq!(view.block_count()?, 0);
+            Ok(())
+        }).unwrap();
+        store.write(&batch).unwrap();
+        assert_eq!(store.block_count().unwrap(), 4096);
+        assert!(store.map_size.load(Ordering::SeqCst) > options.map_size);
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn opens_read_only() {
+        let dir = ::std::env::temp_dir().join(format!("nano-rs-lmdb-read-only-test-{}", ::std::process::id()));
+        let options = LmdbOptions { read_only: true, ..LmdbOptions::default() };
+        assert!(LmdbStore::open_with(&dir, &options).is_err());
+        let account = PublicKey::from_bytes(&[1u8; 32]).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put_account(account.clone(), AccountInfo {
+            head: BlockHash::from_bytes(&[2u8; 32]).unwrap(),
+            representative: account.clone(),
+            balance: 1,
+            confirmation_height: 0,
+        });
+        LmdbStore::open(&dir).unwrap().write(&batch).unwrap();
+
+        let store = LmdbStore::open_with(&dir, &options).unwrap();
+        assert_eq!(store.account(&account).unwrap().unwrap().balance, 1);
+        assert!(store.pending(&account).unwrap().is_empty());
+        assert!(store.write(&batch).is_err());
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn snapshots_ignore_later_writes() {
+        let dir = ::std::env::temp_dir().join(format!("nano-rs-lmdb-snapshot-test-{}", ::std::process::id()));
+        let store = Arc::new(LmdbStore::open(&dir).unwrap());
+        let account = PublicKey::from_bytes(&[1u8; 32]).unwrap();
+        let mut block = Block::new(BlockKind::State, Some(BlockPayload::State {
+            account: account.clone(),
+            previous: BlockHash::from_bytes(&[2u8; 32]).unwrap(),
+            representative: account.clone(),
+            balance: 1,
+            link: Link::Unknown([3u8; 32]),
+        }), Some(Signature::from_bytes(&[4u8; 64]).unwrap()),
+            Some(Work::from_hex("0000000000000000").unwrap()));
+        let hash = block.hash(false).unwrap();
+        let writer = store.clone();
+        store.snapshot(&mut |view| {
+            let mut batch = WriteBatch::new();
+            batch.put_block(hash, block.clone());
+            let writer = writer.clone();
+            thread::spawn(move || writer.write(&batch)).join().unwrap()?;
+            assert!(view.block(&hash)?.is_none());
+            assert_eq!(view.block_count()?, 0);
+            Ok(())
+        }).unwrap();
+        assert!(store.block(&hash).unwrap().is_some());
+        drop((store, writer));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn upgrades_old_layouts() {
+        let dir = ::std::env::temp_dir().join(format!("nano-rs-lmdb-migration-test-{}", ::std::process::id()));
+        let account = PublicKey::from_bytes(&[1u8; 32]).unwrap();
+        let mut block = Block::new(BlockKind::State, Some(BlockPayload::State {
+            account: account.clone(),
+            previous: BlockHash::from_bytes(&[2u8; 32]).unwrap(),
+            representative: account.clone(),
+            balance: 1,
+            link: Link::Unknown([3u8; 32]),
+        }), Some(Signature::from_bytes(&[4u8; 64]).unwrap()),
+            Some(Work::from_hex("0000000000000000").unwrap()));
+        let mut batch = WriteBatch::new();
+        batch.put_block(block.hash(false).unwrap(), block);
+        // A genesis block, whose account has no record until the upgrade gives it one
+        let genesis = PublicKey::from_bytes(&[4u8; 32]).unwrap();
+        let mut open = Block::new(BlockKind::Open, Some(BlockPayload::Open {
+            source: BlockHash::from_bytes(genesis.as_bytes()).unwrap(),
+            representative: genesis.clone(),
+            account: genesis.clone(),
+        }), Some(Signature::from_bytes(&[4u8; 64]).unwrap()),
+            Some(Work::from_hex("0000000000000000").unwrap()));
+        batch.put_block(open.hash(false).unwrap(), open);
+        // A burn account, whose balance stores of this age count in the weights
+        let representative = PublicKey::from_bytes(&[5u8; 32]).unwrap();
+        batch.put_account(burn_account(), AccountInfo {
+            head: BlockHash::from_bytes(&[6u8; 32]).unwrap(),
+            representative: representative.clone(),
+            balance: 7,
+            confirmation_height: 0,
+        });
+        batch.put_weight(representative, 7);
+        {
+            let store = LmdbStore::open(&dir).unwrap();
+            store.write(&batch).unwrap();
+            // Take the database back to version 0, from before the counts were kept
+            let mut txn = store.write_txn().unwrap();
+            txn.del(store.meta, &SCHEMA_VERSION_KEY, None).unwrap();
+            txn.del(store.meta, &BLOCK_COUNTS_KEY, None).unwrap();
+            txn.commit().unwrap();
+        }
+        let read_only = LmdbOptions { read_only: true, ..LmdbOptions::default() };
+        assert!(LmdbStore::open_with(&dir, &read_only).is_err());
+
+        let store = LmdbStore::open(&dir).unwrap();
+        assert_eq!(store.block_count().unwrap(), 2);
+        assert_eq!(store.account(&genesis).unwrap().unwrap().balance, GENESIS_AMOUNT);
+        assert_eq!(store.weights().unwrap(), vec![(genesis.clone(), GENESIS_AMOUNT)]);
+        assert_eq!(store.schema_version(&*store.read_txn().unwrap()).unwrap(), SCHEMA_VERSION);
+        assert!(dir.join("pre-migration-v0").join("data.mdb").is_file());
+        let mut txn = store.write_txn().unwrap();
+        txn.put(store.meta, &SCHEMA_VERSION_KEY, &(SCHEMA_VERSION + 1).to_be_bytes(), WriteFlags::empty()).unwrap();
+        txn.commit().unwrap();
+        drop(store);
+        assert!(LmdbStore::open(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+//! A store kept in memory, for tests and throwaway nodes. Snapshots don't copy the
+//! tables, but hold off writes until they have been read.
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::RwLock;
+
+use nano_lib_rs::block::{Block, BlockHash};
+use nano_lib_rs::keys::PublicKey;
+
+use crate::error::*;
+use super::{pending_key, unchecked_key, AccountInfo, BlockCounts, BlockHeight, PendingInfo, Store, WriteBatch};
+
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    blocks: RwLock<HashMap<BlockHash, Block>>,
+    heights: RwLock<HashMap<BlockHash, BlockHeight>>,
+    accounts: RwLock<HashMap<[u8; 32], AccountInfo>>,
+    weights: RwLock<HashMap<[u8; 32], (PublicKey, u128)>>,
+    pruned: RwLock<HashSet<BlockHash>>,
+    /// Each entry by its `pending_key`
+    pending: RwLock<BTreeMap<Vec<u8>, PendingInfo>>,
+    /// Each block by its `unchecked_key`
+    unchecked: RwLock<BTreeMap<Vec<u8>, Block>>,
+    counts: RwLock<BlockCounts>,
+    /// Held for writing while a batch is written, and for reading during snapshots
+    snapshots: RwLock<()>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+}
+
+impl Store for MemoryStore {
+    fn name(&self) -> &str {
+        "memory"
+    }
+
+    fn block(&self, hash: &BlockHash) -> Result<Option<Block>> {
+        Ok(self.blocks.read().unwrap().get(hash).cloned())
+    }
+
+    fn block_exists(&self, hash: &BlockHash) -> Result<bool> {
+        Ok(self.blocks.read().unwrap().contains_key(hash))
+    }
+
+    fn block_count(&self) -> Result<u64> {
+        Ok(self.blocks.read().unwrap().len() as u64)
+    }
+
+    fn block_counts(&self) -> Result<BlockCounts> {
+        Ok(*self.counts.read().unwrap())
+    }
+
+    fn is_pruned(&self, hash: &BlockHash) -> Result<bool> {
+        Ok(self.pruned.read().unwrap().contains(hash))
+    }
+
+    fn pruned_count(&self) -> Result<u64> {
+        Ok(self.pruned.read().unwrap().len() as u64)
+    }
+
+    fn height(&self, hash: &BlockHash) -> Result<Option<BlockHeight>> {
+        Ok(self.heights.read().unwrap().get(hash).cloned())
+    }
+
+    fn account(&self, account: &PublicKey) -> Result<Option<AccountInfo>> {
+        Ok(self.accounts.read().unwrap().get(account.as_bytes()).cloned())
+    }
+
+    fn account_count(&self) -> Result<u64> {
+        Ok(self.accounts.read().unwrap().len() as u64)
+    }
+
+    fn pending(&self, account: &PublicKey) -> Result<Vec<(BlockHash, PendingInfo)>> {
+        let pending = self.pending.read().unwrap();
+        let mut entries = Vec::new();
+        for (key, info) in pending.range(account.as_bytes().to_vec()..) {
+            if &key[..32] != account.as_bytes() {
+                break;
+            }
+            entries.push((BlockHash::from_bytes(&key[32..])?, info.clone()));
+        }
+        Ok(entries)
+    }
+
+    fn unchecked(&self, dependency: &BlockHash) -> Result<Vec<(BlockHash, Block)>> {
+        let unchecked = self.unchecked.read().unwrap();
+        let mut entries = Vec::new();
+        for (key, block) in unchecked.range(dependency.as_bytes().to_vec()..) {
+            if &key[..32] != dependency.as_bytes() {
+                break;
+            }
+            entries.push((BlockHash::from_bytes(&key[32..])?, block.clone()));
+        }
+        Ok(entries)
+    }
+
+    fn unchecked_count(&self) -> Result<u64> {
+        Ok(self.unchecked.read().unwrap().len() as u64)
+    }
+
+    fn weights(&self) -> Result<Vec<(PublicKey, u128)>> {
+        Ok(self.weights.read().unwrap().values().cloned().collect())
+    }
+
+    fn for_each_block(&self, f: &mut dyn FnMut(BlockHash, Block) -> Result<()>) -> Result<()> {
+        for (&hash, block) in self.blocks.read().unwrap().iter() {
+            f(hash, block.clone())?;
+        }
+        Ok(())
+    }
+
+    fn for_each_account(&self, f: &mut dyn FnMut(PublicKey, AccountInfo) -> Result<()>) -> Result<()> {
+        for (account, info) in self.accounts.read().unwrap().iter() {
+            f(PublicKey::from_bytes(account)?, info.clone())?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, batch: &WriteBatch) -> Result<()> {
+        let _snapshots = self.snapshots.write().unwrap();
+        let mut blocks = self.blocks.write().unwrap();
+        let mut accounts = self.accounts.write().unwrap();
+        let mut counts = self.counts.write().unwrap();
+        for &(hash, ref block) in batch.blocks() {
+            if blocks.insert(hash, block.clone()).is_none() {
+                counts.add_block(block.kind);
+            }
+        }
+        let mut heights = self.heights.write().unwrap();
+        for &(hash, ref height) in batch.heights() {
+            heights.insert(hash, height.clone());
+        }
+        for &(ref account, ref info) in batch.accounts() {
+            let before = accounts.insert(*account.as_bytes(), info.clone()).map_or(0, |info| info.confirmation_height);
+            counts.set_cemented(before, info.confirmation_height);
+        }
+        let mut weights = self.weights.write().unwrap();
+        for &(ref representative, weight) in batch.weights() {
+            weights.insert(*representative.as_bytes(), (representative.clone(), weight));
+        }
+        let mut pruned = self.pruned.write().unwrap();
+        for hash in batch.pruned() {
+            if let Some(block) = blocks.remove(hash) {
+                counts.remove_block(block.kind);
+            }
+            pruned.insert(*hash);
+        }
+        for hash in batch.deleted_blocks() {
+            if let Some(block) = blocks.remove(hash) {
+                counts.remove_block(block.kind);
+            }
+            heights.remove(hash);
+        }
+        for account in batch.deleted_accounts() {
+            if let Some(info) = accounts.remove(account.as_bytes()) {
+                counts.set_cemented(info.confirmation_height, 0);
+            }
+        }
+        let mut pending = self.pending.write().unwrap();
+        for &(ref account, ref hash, ref info) in batch.pending() {
+            pending.insert(pending_key(account, hash).to_vec(), info.clone());
+        }
+        for &(ref account, ref hash) in batch.received() {
+            pending.remove(&pending_key(account, hash)[..]);
+        }
+        let mut unchecked = self.unchecked.write().unwrap();
+        for &(ref dependency, ref hash, ref block) in batch.unchecked() {
+            unchecked.insert(unchecked_key(dependency, hash).to_vec(), block.clone());
+        }
+        for &(ref dependency, ref hash) in batch.checked() {
+            unchecked.remove(&unchecked_key(dependency, hash)[..]);
+        }
+        Ok(())
+    }
+
+    fn snapshot(&self, f: &mut dyn FnMut(&dyn Store) -> Result<()>) -> Result<()> {
+        let _snapshot = self.snapshots.read().unwrap();
+        f(self)
+    }
+}
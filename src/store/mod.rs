@@ -0,0 +1,779 @@
+//! Storage of the ledger's blocks, account records, representative weights and the
+//! sends which haven't been received yet.
+//!
+//! Backends implement `Store`, and every change is made through a `WriteBatch`
+//! applied in a single transaction, so that a batch is either written completely or
+//! not at all. Blocks are stored as their kind followed by their wire encoding.
+//! Pruned blocks are deleted, and their hashes kept so that they can be told apart
+//! from blocks which were never stored. Pending entries are keyed by the destination
+//! account followed by the hash of the send, so that the entries of an account are
+//! read together, in order of their hashes. Blocks which can't be applied until a
+//! block they depend on arrives are kept apart as unchecked, keyed by the hash of the
+//! missing block followed by their own, so that they survive a restart and are found
+//! together once it arrives. Backends keep `BlockCounts` up to date as batches are
+//! written, so that counting blocks doesn't mean scanning them.
+//! Unless disabled, the store is wrapped in a `CachedStore` which keeps recently used
+//! blocks and account records in memory. Queries which read the store several times,
+//! and need the reads to agree, read a snapshot, which batches written meanwhile
+//! don't change.
+pub mod cache;
+pub mod checksum;
+pub mod diff;
+pub mod export;
+pub mod fast_sync;
+pub mod heights;
+pub mod legacy;
+#[cfg(feature = "lmdb")]
+pub mod lmdb;
+pub mod memory;
+pub mod vacuum;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::{Buf, BufMut, Bytes};
+
+use nano_lib_rs::block::{Block, BlockHash, BlockKind};
+use nano_lib_rs::keys::PublicKey;
+
+use crate::error::*;
+
+/// A backend the ledger can be stored in
+pub trait Store: fmt::Debug + Send + Sync {
+    /// A short human readable name used in logs
+    fn name(&self) -> &str;
+
+    fn block(&self, hash: &BlockHash) -> Result<Option<Block>>;
+
+    fn block_exists(&self, hash: &BlockHash) -> Result<bool> {
+        Ok(self.block(hash)?.is_some())
+    }
+
+    fn block_count(&self) -> Result<u64> {
+        Ok(self.block_counts()?.total())
+    }
+
+    fn block_counts(&self) -> Result<BlockCounts>;
+
+    /// Whether the block `hash` was stored and then pruned
+    fn is_pruned(&self, hash: &BlockHash) -> Result<bool>;
+
+    /// The number of blocks which were stored and then pruned
+    fn pruned_count(&self) -> Result<u64>;
+
+    /// The account whose chain the block `hash` is in and its height there, if it is
+    /// indexed
+    fn height(&self, hash: &BlockHash) -> Result<Option<BlockHeight>>;
+
+    fn account(&self, account: &PublicKey) -> Result<Option<AccountInfo>>;
+
+    /// The number of account records
+    fn account_count(&self) -> Result<u64>;
+
+    /// The sends to `account` which it hasn't received yet, in order of their hashes
+    fn pending(&self, account: &PublicKey) -> Result<Vec<(BlockHash, PendingInfo)>>;
+
+    /// The blocks waiting for the block `dependency`, in order of their hashes
+    fn unchecked(&self, dependency: &BlockHash) -> Result<Vec<(BlockHash, Block)>>;
+
+    /// The number of blocks waiting for a dependency
+    fn unchecked_count(&self) -> Result<u64>;
+
+    /// The weight of every representative with a stored weight
+    fn weights(&self) -> Result<Vec<(PublicKey, u128)>>;
+
+    /// Call `f` with every stored block in no particular order, stopping at the first
+    /// error. `f` must not read from the store.
+    fn for_each_block(&self, f: &mut dyn FnMut(BlockHash, Block) -> Result<()>) -> Result<()>;
+
+    /// Call `f` with every account record in no particular order, stopping at the
+    /// first error. `f` must not read from the store.
+    fn for_each_account(&self, f: &mut dyn FnMut(PublicKey, AccountInfo) -> Result<()>) -> Result<()>;
+
+    /// Apply every change in `batch` in one transaction
+    fn write(&self, batch: &WriteBatch) -> Result<()>;
+
+    /// How the store is using its space. This counts the entries of the tables which
+    /// are counted cheaply; backends with finer figures give them instead.
+    fn stats(&self) -> Result<StoreStats> {
+        Ok(StoreStats {
+            tables: vec![
+                TableStats::counted("blocks", self.block_count()?),
+                TableStats::counted("accounts", self.account_count()?),
+                TableStats::counted("weights", self.weights()?.len() as u64),
+                TableStats::counted("pruned", self.pruned_count()?),
+                TableStats::counted("unchecked", self.unchecked_count()?),
+            ],
+            ..StoreStats::default()
+        })
+    }
+
+    /// Call `f` with a read-only view of the store as it is now, which batches written
+    /// while `f` runs don't change. `f` must only read from the view, not from the
+    /// store.
+    fn snapshot(&self, f: &mut dyn FnMut(&dyn Store) -> Result<()>) -> Result<()>;
+}
+
+/// Figures on the size of a store, for monitoring its growth. Figures a backend doesn't
+/// have are `None`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StoreStats {
+    /// Bytes the database file takes on disk
+    pub size_on_disk: Option<u64>,
+    pub map_size: Option<u64>,
+    pub page_size: Option<u64>,
+    /// Pages of the file in use, including those freed for reuse
+    pub pages_used: Option<u64>,
+    /// Read transactions open, in this process or others
+    pub readers: Option<u64>,
+    /// Blocks and account records held in memory by a `CachedStore`
+    pub cached: Option<(usize, usize)>,
+    /// When the database was last compacted by `database_vacuum`, in seconds since the
+    /// Unix epoch
+    pub last_vacuum: Option<u64>,
+    pub tables: Vec<TableStats>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TableStats {
+    pub name: &'static str,
+    pub entries: u64,
+    /// Branch, leaf and overflow pages of the table
+    pub pages: Option<u64>,
+    /// Levels of the table's B-tree
+    pub depth: Option<u64>,
+}
+
+impl TableStats {
+    /// A table of which only the entries are counted
+    pub fn counted(name: &'static str, entries: u64) -> Self {
+        TableStats { name, entries, pages: None, depth: None }
+    }
+}
+
+/// What `f` returns when called with a snapshot of `store`, as by `Store::snapshot`
+pub fn read_snapshot<T, F>(store: &dyn Store, f: F) -> Result<T>
+    where F: FnOnce(&dyn Store) -> Result<T>
+{
+    let (mut f, mut read) = (Some(f), None);
+    store.snapshot(&mut |view| {
+        if let Some(f) = f.take() {
+            read = Some(f(view)?);
+        }
+        Ok(())
+    })?;
+    read.ok_or_else(|| Error::from("The store did not read a snapshot"))
+}
+
+/// The latest state of an account's chain
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccountInfo {
+    /// The latest block of the chain
+    pub head: BlockHash,
+    pub representative: PublicKey,
+    pub balance: u128,
+    /// The number of blocks of the chain which have been confirmed and cemented
+    pub confirmation_height: u64,
+}
+
+const ACCOUNT_INFO_LEN: usize = 32 + 32 + 16 + 8;
+
+impl AccountInfo {
+    /// Encode this record for storage
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(ACCOUNT_INFO_LEN);
+        encoded.put_slice(self.head.as_bytes());
+        encoded.put_slice(self.representative.as_bytes());
+        encoded.put_u128(self.balance);
+        encoded.put_u64(self.confirmation_height);
+        encoded
+    }
+
+    /// Decode a record encoded with `encode`
+    pub fn decode(mut encoded: &[u8]) -> Result<Self> {
+        if encoded.len() != ACCOUNT_INFO_LEN {
+            bail!("Account record of {} bytes in store, expected {}", encoded.len(), ACCOUNT_INFO_LEN);
+        }
+        let head = BlockHash::from_bytes(&encoded[..32])?;
+        let representative = PublicKey::from_bytes(&encoded[32..64])?;
+        encoded.advance(64);
+        Ok(AccountInfo {
+            head,
+            representative,
+            balance: encoded.get_u128(),
+            confirmation_height: encoded.get_u64(),
+        })
+    }
+}
+
+/// The number of stored blocks of each kind, and of cemented blocks
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlockCounts {
+    pub send: u64,
+    pub receive: u64,
+    pub open: u64,
+    pub change: u64,
+    pub state: u64,
+    /// The sum of every account's confirmation height
+    pub cemented: u64,
+}
+
+const BLOCK_COUNTS_LEN: usize = 6 * 8;
+
+impl BlockCounts {
+    pub fn total(&self) -> u64 {
+        self.send + self.receive + self.open + self.change + self.state
+    }
+
+    fn count_mut(&mut self, kind: BlockKind) -> Option<&mut u64> {
+        match kind {
+            BlockKind::Send => Some(&mut self.send),
+            BlockKind::Receive => Some(&mut self.receive),
+            BlockKind::Open => Some(&mut self.open),
+            BlockKind::Change => Some(&mut self.change),
+            BlockKind::State => Some(&mut self.state),
+            _ => None,
+        }
+    }
+
+    /// Count a newly stored block of `kind`
+    pub fn add_block(&mut self, kind: BlockKind) {
+        if let Some(count) = self.count_mut(kind) {
+            *count += 1;
+        }
+    }
+
+    /// Stop counting a deleted block of `kind`
+    pub fn remove_block(&mut self, kind: BlockKind) {
+        if let Some(count) = self.count_mut(kind) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    /// Count an account's confirmation height changing from `before` to `after`
+    pub fn set_cemented(&mut self, before: u64, after: u64) {
+        self.cemented = self.cemented.saturating_sub(before).saturating_add(after);
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(BLOCK_COUNTS_LEN);
+        for &count in &[self.send, self.receive, self.open, self.change, self.state, self.cemented] {
+            encoded.put_u64(count);
+        }
+        encoded
+    }
+
+    /// Decode counts encoded with `encode`
+    pub fn decode(mut encoded: &[u8]) -> Result<Self> {
+        if encoded.len() != BLOCK_COUNTS_LEN {
+            bail!("Block counts of {} bytes in store, expected {}", encoded.len(), BLOCK_COUNTS_LEN);
+        }
+        Ok(BlockCounts {
+            send: encoded.get_u64(),
+            receive: encoded.get_u64(),
+            open: encoded.get_u64(),
+            change: encoded.get_u64(),
+            state: encoded.get_u64(),
+            cemented: encoded.get_u64(),
+        })
+    }
+}
+
+/// A send which its destination hasn't received yet
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingInfo {
+    /// The account which sent it
+    pub source: PublicKey,
+    pub amount: u128,
+}
+
+const PENDING_INFO_LEN: usize = 32 + 16;
+
+impl PendingInfo {
+    /// Encode this entry for storage
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(PENDING_INFO_LEN);
+        encoded.put_slice(self.source.as_bytes());
+        encoded.put_u128(self.amount);
+        encoded
+    }
+
+    /// Decode an entry encoded with `encode`
+    pub fn decode(mut encoded: &[u8]) -> Result<Self> {
+        if encoded.len() != PENDING_INFO_LEN {
+            bail!("Pending entry of {} bytes in store, expected {}", encoded.len(), PENDING_INFO_LEN);
+        }
+        let source = PublicKey::from_bytes(&encoded[..32])?;
+        encoded.advance(32);
+        Ok(PendingInfo { source, amount: encoded.get_u128() })
+    }
+}
+
+/// The key of the pending entry of the send `hash` to `account`
+pub fn pending_key(account: &PublicKey, hash: &BlockHash) -> [u8; 64] {
+    let mut key = [0u8; 64];
+    key[..32].copy_from_slice(account.as_bytes());
+    key[32..].copy_from_slice(hash.as_bytes());
+    key
+}
+
+/// The key of the unchecked block `hash`, which waits for the block `dependency`
+pub fn unchecked_key(dependency: &BlockHash, hash: &BlockHash) -> [u8; 64] {
+    let mut key = [0u8; 64];
+    key[..32].copy_from_slice(dependency.as_bytes());
+    key[32..].copy_from_slice(hash.as_bytes());
+    key
+}
+
+/// Encode a representative's weight for storage
+pub fn encode_weight(weight: u128) -> [u8; 16] {
+    weight.to_be_bytes()
+}
+
+/// Decode a weight encoded with `encode_weight`
+pub fn decode_weight(encoded: &[u8]) -> Result<u128> {
+    if encoded.len() != 16 {
+        bail!("Weight of {} bytes in store, expected 16", encoded.len());
+    }
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(encoded);
+    Ok(u128::from_be_bytes(bytes))
+}
+
+/// Where a block is in the ledger
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockHeight {
+    /// The account whose chain the block is in
+    pub account: PublicKey,
+    /// The block's height in the chain, the open block being at height 1
+    pub height: u64,
+}
+
+const BLOCK_HEIGHT_LEN: usize = 32 + 8;
+
+impl BlockHeight {
+    /// Encode this entry for storage
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(BLOCK_HEIGHT_LEN);
+        encoded.put_slice(self.account.as_bytes());
+        encoded.put_u64(self.height);
+        encoded
+    }
+
+    /// Decode an entry encoded with `encode`
+    pub fn decode(mut encoded: &[u8]) -> Result<Self> {
+        if encoded.len() != BLOCK_HEIGHT_LEN {
+            bail!("Block height of {} bytes in store, expected {}", encoded.len(), BLOCK_HEIGHT_LEN);
+        }
+        let account = PublicKey::from_bytes(&encoded[..32])?;
+        encoded.advance(32);
+        Ok(BlockHeight { account, height: encoded.get_u64() })
+    }
+}
+
+/// Changes to be applied to a store together
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    blocks: Vec<(BlockHash, Block)>,
+    heights: Vec<(BlockHash, BlockHeight)>,
+    accounts: Vec<(PublicKey, AccountInfo)>,
+    weights: HashMap<[u8; 32], (PublicKey, u128)>,
+    pruned: Vec<BlockHash>,
+    pending: Vec<(PublicKey, BlockHash, PendingInfo)>,
+    received: Vec<(PublicKey, BlockHash)>,
+    unchecked: Vec<(BlockHash, BlockHash, Block)>,
+    checked: Vec<(BlockHash, BlockHash)>,
+    deleted_blocks: Vec<BlockHash>,
+    deleted_accounts: Vec<PublicKey>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    pub fn put_block(&mut self, hash: BlockHash, block: Block) {
+        self.blocks.push((hash, block));
+    }
+
+    /// The block `hash` in this batch
+    pub fn block(&self, hash: &BlockHash) -> Option<&Block> {
+        self.blocks.iter().rev().find(|&&(ref key, _)| key == hash).map(|&(_, ref block)| block)
+    }
+
+    /// Index the account and height of the block `hash`
+    pub fn put_height(&mut self, hash: BlockHash, height: BlockHeight) {
+        self.heights.push((hash, height));
+    }
+
+    /// The account and height of the block `hash` indexed in this batch
+    pub fn height(&self, hash: &BlockHash) -> Option<&BlockHeight> {
+        self.heights.iter().rev().find(|&&(ref key, _)| key == hash).map(|&(_, ref height)| height)
+    }
+
+    /// Delete the stored block `hash`, remembering that it was pruned
+    pub fn prune_block(&mut self, hash: BlockHash) {
+        self.pruned.push(hash);
+    }
+
+    /// Delete the stored block `hash` outright, along with its height, as when it is
+    /// rolled back. Deletions are applied after the blocks of the same batch are put.
+    pub fn delete_block(&mut self, hash: BlockHash) {
+        self.deleted_blocks.push(hash);
+    }
+
+    /// Delete the record of `account`, after the records of the same batch are put
+    pub fn delete_account(&mut self, account: PublicKey) {
+        self.deleted_accounts.push(account);
+    }
+
+    /// Replace the record of `account`. Later records of the same account in a batch
+    /// replace earlier ones.
+    pub fn put_account(&mut self, account: PublicKey, info: AccountInfo) {
+        self.accounts.push((account, info));
+    }
+
+    /// The latest record of `account` in this batch
+    pub fn account(&self, account: &PublicKey) -> Option<&AccountInfo> {
+        self.accounts.iter().rev()
+            .find(|&&(ref key, _)| key.as_bytes() == account.as_bytes())
+            .map(|&(_, ref info)| info)
+    }
+
+    /// Add the pending entry of the send `hash` to `account`
+    pub fn put_pending(&mut self, account: PublicKey, hash: BlockHash, info: PendingInfo) {
+        self.pending.push((account, hash, info));
+    }
+
+    /// Delete the pending entry of the send `hash` to `account`, once it is received.
+    /// Entries are deleted after those of the same batch are added.
+    pub fn delete_pending(&mut self, account: PublicKey, hash: BlockHash) {
+        self.received.push((account, hash));
+    }
+
+    /// Keep the block `hash` as unchecked until the block `dependency` arrives
+    pub fn put_unchecked(&mut self, dependency: BlockHash, hash: BlockHash, block: Block) {
+        self.unchecked.push((dependency, hash, block));
+    }
+
+    /// Delete the unchecked block `hash` waiting for `dependency`, once it is applied.
+    /// Entries are deleted after those of the same batch are added.
+    pub fn delete_unchecked(&mut self, dependency: BlockHash, hash: BlockHash) {
+        self.checked.push((dependency, hash));
+    }
+
+    /// Set the weight of `representative`, replacing any weight set earlier in the batch
+    pub fn put_weight(&mut self, representative: PublicKey, weight: u128) {
+        self.weights.insert(*representative.as_bytes(), (representative, weight));
+    }
+
+    /// The weight of `representative` set in this batch
+    pub fn weight(&self, representative: &PublicKey) -> Option<u128> {
+        self.weights.get(representative.as_bytes()).map(|&(_, weight)| weight)
+    }
+
+    pub fn weights<'a>(&'a self) -> impl Iterator<Item=&'a (PublicKey, u128)> + 'a {
+        self.weights.values()
+    }
+
+    pub fn blocks(&self) -> &[(BlockHash, Block)] {
+        &self.blocks
+    }
+
+    pub fn heights(&self) -> &[(BlockHash, BlockHeight)] {
+        &self.heights
+    }
+
+    pub fn accounts(&self) -> &[(PublicKey, AccountInfo)] {
+        &self.accounts
+    }
+
+    pub fn pruned(&self) -> &[BlockHash] {
+        &self.pruned
+    }
+
+    pub fn pending(&self) -> &[(PublicKey, BlockHash, PendingInfo)] {
+        &self.pending
+    }
+
+    pub fn received(&self) -> &[(PublicKey, BlockHash)] {
+        &self.received
+    }
+
+    pub fn unchecked(&self) -> &[(BlockHash, BlockHash, Block)] {
+        &self.unchecked
+    }
+
+    pub fn checked(&self) -> &[(BlockHash, BlockHash)] {
+        &self.checked
+    }
+
+    pub fn deleted_blocks(&self) -> &[BlockHash] {
+        &self.deleted_blocks
+    }
+
+    pub fn deleted_accounts(&self) -> &[PublicKey] {
+        &self.deleted_accounts
+    }
+
+    /// The number of blocks in the batch
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty() && self.heights.is_empty() && self.accounts.is_empty() && self.weights.is_empty()
+            && self.pruned.is_empty() && self.pending.is_empty() && self.received.is_empty()
+            && self.unchecked.is_empty() && self.checked.is_empty() && self.deleted_blocks.is_empty()
+            && self.deleted_accounts.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.heights.clear();
+        self.accounts.clear();
+        self.weights.clear();
+        self.pruned.clear();
+        self.pending.clear();
+        self.received.clear();
+        self.unchecked.clear();
+        self.checked.clear();
+        self.deleted_blocks.clear();
+        self.deleted_accounts.clear();
+    }
+}
+
+/// Encode `block` for storage
+pub fn encode_block(block: &Block) -> Vec<u8> {
+    let mut encoded = vec![block.kind as u8];
+    encoded.extend_from_slice(&block.serialize_bytes());
+    encoded
+}
+
+/// Decode a block encoded with `encode_block`
+pub fn decode_block(encoded: &[u8]) -> Result<Block> {
+    let (&kind, data) = encoded.split_first().ok_or_else(|| Error::from("Empty block record"))?;
+    let kind = BlockKind::from_value(kind).ok_or_else(|| Error::from(format!("Unknown block kind {} in store", kind)))?;
+    Ok(Block::deserialize_bytes(Bytes::copy_from_slice(data), kind)?)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// An LMDB database on disk
+    Lmdb,
+    /// Kept in memory and lost when the node stops
+    Memory,
+}
+
+impl Backend {
+    pub fn from_name(name: &str) -> Option<Backend> {
+        match name {
+            "lmdb" => Some(Backend::Lmdb),
+            "memory" => Some(Backend::Memory),
+            _ => None,
+        }
+    }
+}
+
+/// How processed blocks are grouped into write transactions
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BatchConfig {
+    /// Blocks written in one transaction at most
+    pub max_blocks: usize,
+    /// The longest a block waits for its batch to fill before the batch is written
+    pub max_delay: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoreConfig {
+    pub backend: Backend,
+    /// Directory of an on-disk database
+    pub path: PathBuf,
+    pub batch: BatchConfig,
+    /// Recently used blocks kept in memory; 0 disables the cache
+    pub block_cache_size: usize,
+    /// Recently used account records kept in memory; 0 disables the cache
+    pub account_cache_size: usize,
+    pub lmdb: LmdbOptions,
+}
+
+/// How an LMDB database is opened
+#[derive(Clone, Debug, PartialEq)]
+pub struct LmdbOptions {
+    /// Tune the database for many concurrent readers and few writes
+    pub read_mostly: bool,
+    /// The size of the memory map in bytes when the database is opened. It is doubled
+    /// whenever a write finds it full.
+    pub map_size: usize,
+    pub sync: SyncPolicy,
+    /// Open an existing database without writing to it, as a second process reading
+    /// the ledger of a running node does
+    pub read_only: bool,
+}
+
+impl Default for LmdbOptions {
+    fn default() -> Self {
+        LmdbOptions {
+            read_mostly: false,
+            map_size: DEFAULT_MAP_SIZE,
+            sync: SyncPolicy::Always,
+            read_only: false,
+        }
+    }
+}
+
+/// When committed writes are flushed to disk. Whatever the policy, the node crashing
+/// loses nothing which was committed; only the OS crashing or the power failing can.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncPolicy {
+    /// Every commit is flushed before it returns
+    Always,
+    /// Commits are flushed together at this interval, so an OS crash can lose the
+    /// writes of the last interval, or corrupt the database
+    Batched(Duration),
+    /// Commits are left for the OS to flush, for throwaway nodes such as in tests
+    Never,
+}
+
+impl SyncPolicy {
+    /// The policy named `name`, `always`, `batched` or `none`, flushing every
+    /// `interval` if batched
+    pub fn from_name(name: &str, interval: Duration) -> Option<SyncPolicy> {
+        match name {
+            "always" => Some(SyncPolicy::Always),
+            "batched" => Some(SyncPolicy::Batched(interval)),
+            "none" => Some(SyncPolicy::Never),
+            _ => None,
+        }
+    }
+}
+
+/// The initial size of LMDB memory maps unless configured otherwise. LMDB only reserves
+/// address space for the map, so this can be far larger than the ledger.
+pub const DEFAULT_MAP_SIZE: usize = 128 * 1024 * 1024 * 1024;
+
+/// Open the store described by `config`
+pub fn open(config: &StoreConfig) -> Result<Arc<dyn Store>> {
+    let store: Arc<dyn Store> = match config.backend {
+        Backend::Lmdb => open_lmdb(config)?,
+        Backend::Memory => Arc::new(memory::MemoryStore::new()),
+    };
+    info!("Opened {} store with {} blocks", store.name(), store.block_count()?);
+    if config.block_cache_size == 0 && config.account_cache_size == 0 {
+        return Ok(store);
+    }
+    Ok(Arc::new(cache::CachedStore::new(store, config.block_cache_size, config.account_cache_size)))
+}
+
+#[cfg(feature = "lmdb")]
+fn open_lmdb(config: &StoreConfig) -> Result<Arc<dyn Store>> {
+    Ok(Arc::new(lmdb::LmdbStore::open_with(&config.path, &config.lmdb)?))
+}
+
+#[cfg(not(feature = "lmdb"))]
+fn open_lmdb(_config: &StoreConfig) -> Result<Arc<dyn Store>> {
+    bail!("The LMDB store was configured, but nano-rs was built without the `lmdb` feature")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nano_lib_rs::block::{BlockPayload, Link, Work};
+    use nano_lib_rs::keys::{PublicKey, Signature};
+
+    #[test]
+    fn writes_batches() {
+        let account = PublicKey::from_bytes(&[7u8; 32]).unwrap();
+        let mut block = Block::new(
+            BlockKind::State,
+            Some(BlockPayload::State {
+                account: account.clone(),
+                previous: BlockHash::from_bytes(&[1u8; 32]).unwrap(),
+                representative: account.clone(),
+                balance: 1_000_000,
+                link: Link::Unknown([2u8; 32]),
+            }),
+            Some(Signature::from_bytes(&[3u8; 64]).unwrap()),
+            Some(Work::from_bytes(&[4u8, 5, 6, 7, 8, 9, 10, 11]).unwrap()));
+        let hash = block.hash(false).unwrap();
+        assert_eq!(decode_block(&encode_block(&block)).unwrap().payload, block.payload);
+
+        let info = AccountInfo {
+            head: hash,
+            representative: account.clone(),
+            balance: 1_000_000,
+            confirmation_height: 3,
+        };
+        assert_eq!(AccountInfo::decode(&info.encode()).unwrap(), info);
+
+        let store = memory::MemoryStore::new();
+        let mut batch = WriteBatch::new();
+        batch.put_block(hash, block.clone());
+        batch.put_account(account.clone(), info.clone());
+        store.write(&batch).unwrap();
+        assert_eq!(store.block_count().unwrap(), 1);
+        let counts = store.block_counts().unwrap();
+        assert_eq!((counts.state, counts.total(), counts.cemented), (1, 1, 3));
+        assert_eq!(BlockCounts::decode(&counts.encode()).unwrap(), counts);
+        // Writing the same block again doesn't count it twice
+        store.write(&batch).unwrap();
+        assert_eq!(store.block_counts().unwrap(), counts);
+        assert!(store.block_exists(&hash).unwrap());
+        assert_eq!(store.block(&hash).unwrap().unwrap().payload, block.payload);
+        assert_eq!(store.account(&account).unwrap(), Some(info));
+        assert_eq!(store.account_count().unwrap(), 1);
+
+        batch.clear();
+        batch.put_weight(account.clone(), 5);
+        batch.put_weight(account.clone(), 7);
+        store.write(&batch).unwrap();
+        let weights = store.weights().unwrap();
+        assert_eq!(weights.len(), 1);
+        assert_eq!(weights[0].1, 7);
+        assert_eq!(decode_weight(&encode_weight(7)).unwrap(), 7);
+
+        let height = BlockHeight { account: account.clone(), height: 4 };
+        assert_eq!(BlockHeight::decode(&height.encode()).unwrap(), height);
+        batch.clear();
+        batch.put_height(hash, height.clone());
+        store.write(&batch).unwrap();
+        assert_eq!(store.height(&hash).unwrap(), Some(height.clone()));
+
+        // Pruned blocks keep their heights
+        batch.clear();
+        batch.prune_block(hash);
+        store.write(&batch).unwrap();
+        assert_eq!(store.height(&hash).unwrap(), Some(height));
+        assert!(!store.block_exists(&hash).unwrap());
+        assert!(store.is_pruned(&hash).unwrap());
+        assert_eq!(store.pruned_count().unwrap(), 1);
+        assert_eq!(store.block_counts().unwrap().total(), 0);
+
+        let (destination, other) = (PublicKey::from_bytes(&[8u8; 32]).unwrap(), PublicKey::from_bytes(&[9u8; 32]).unwrap());
+        let (first, second) = (BlockHash::from_bytes(&[11u8; 32]).unwrap(), BlockHash::from_bytes(&[10u8; 32]).unwrap());
+        let pending = PendingInfo { source: account.clone(), amount: 40 };
+        assert_eq!(PendingInfo::decode(&pending.encode()).unwrap(), pending);
+        batch.clear();
+        batch.put_pending(destination.clone(), first, pending.clone());
+        batch.put_pending(destination.clone(), second, pending.clone());
+        batch.put_pending(other.clone(), first, pending.clone());
+        batch.delete_pending(other.clone(), first);
+        store.write(&batch).unwrap();
+        assert_eq!(store.pending(&destination).unwrap(), vec![(second, pending.clone()), (first, pending)]);
+        assert!(store.pending(&other).unwrap().is_empty());
+
+        batch.clear();
+        batch.put_unchecked(first, hash, block.clone());
+        batch.put_unchecked(second, hash, block.clone());
+        batch.delete_unchecked(second, hash);
+        store.write(&batch).unwrap();
+        let unchecked = store.unchecked(&first).unwrap();
+        assert_eq!((unchecked.len(), unchecked[0].0), (1, hash));
+        assert_eq!(unchecked[0].1.payload, block.payload);
+        assert!(store.unchecked(&second).unwrap().is_empty());
+        assert_eq!(store.unchecked_count().unwrap(), 1);
+    }
+}
@@ -0,0 +1,123 @@
+//! Compacting the LMDB store, to reclaim the space freed by pruning and rollbacks.
+//!
+//! LMDB reuses the pages freed by deletions, but never shrinks its file, so a pruned
+//! ledger takes as much disk space as it did before it was pruned. `vacuum` copies the
+//! store into a new database whose records are packed in order, either in another
+//! directory or over the store itself, keeping the original file as a backup. The node
+//! must be stopped first.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::*;
+use super::{Backend, StoreConfig};
+
+/// The file LMDB keeps a database in, within the database's directory
+const DATA_FILE: &str = "data.mdb";
+
+/// The original file of a store compacted in place, within the store's directory
+const BACKUP_FILE: &str = "data.mdb.bak";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VacuumSummary {
+    pub records: u64,
+    /// Sizes in bytes of the database file before and after compacting
+    pub size_before: u64,
+    pub size_after: u64,
+    /// Where the original file was kept, if the store was compacted in place
+    pub backup: Option<PathBuf>,
+}
+
+/// Compact the LMDB store set up by `config` into the directory `destination`, or in
+/// place if it is `None`, moving the original file to `data.mdb.bak`
+#[cfg(feature = "lmdb")]
+pub fn vacuum(config: &StoreConfig, destination: Option<&Path>) -> Result<VacuumSummary> {
+    use super::lmdb::LmdbStore;
+
+    if config.backend != Backend::Lmdb {
+        bail!("Only the LMDB store can be vacuumed");
+    }
+    let data = config.path.join(DATA_FILE);
+    if !data.is_file() {
+        bail!("There is no LMDB store at {}", config.path.display());
+    }
+    let backup = config.path.join(BACKUP_FILE);
+    if destination.is_none() && backup.exists() {
+        bail!("{} already exists; move or remove the backup of the last vacuum first", backup.display());
+    }
+    let copy = destination.map(Path::to_owned).unwrap_or_else(|| config.path.join("vacuum"));
+    if copy.join(DATA_FILE).exists() {
+        bail!("{} already holds a database", copy.display());
+    }
+    let size_before = fs::metadata(&data)?.len();
+    let store = LmdbStore::open_with(&config.path, &config.lmdb)?;
+    let records = store.compact_into(&copy)?;
+    drop(store);
+    let size_after = fs::metadata(copy.join(DATA_FILE))?.len();
+    if destination.is_some() {
+        return Ok(VacuumSummary { records, size_before, size_after, backup: None });
+    }
+    fs::rename(&data, &backup)?;
+    fs::rename(copy.join(DATA_FILE), &data)?;
+    fs::remove_dir_all(&copy)?;
+    Ok(VacuumSummary { records, size_before, size_after, backup: Some(backup) })
+}
+
+#[cfg(not(feature = "lmdb"))]
+pub fn vacuum(_config: &StoreConfig, _destination: Option<&Path>) -> Result<VacuumSummary> {
+    bail!("Vacuuming needs nano-rs to be built with the `lmdb` feature")
+}
+
+#[cfg(all(test, feature = "lmdb"))]
+mod tests {
+    use super::*;
+    use nano_lib_rs::block::{Block, BlockHash, BlockKind, BlockPayload, Link, Work};
+    use nano_lib_rs::keys::{PublicKey, Signature};
+    use crate::config::Config;
+    use crate::store::{self, WriteBatch};
+
+    #[test]
+    fn vacuums_in_place() {
+        let dir = ::std::env::temp_dir().join(format!("nano-rs-vacuum-test-{}", ::std::process::id()));
+        let mut config = Config::default().store_config().unwrap();
+        config.path = dir.clone();
+        let account = PublicKey::from_bytes(&[1u8; 32]).unwrap();
+        let mut hashes = Vec::new();
+        {
+            let store = store::open(&config).unwrap();
+            let mut batch = WriteBatch::new();
+            for i in 0..4096u32 {
+                let mut link = [0u8; 32];
+                link[..4].copy_from_slice(&i.to_le_bytes());
+                let mut block = Block::new(BlockKind::State, Some(BlockPayload::State {
+                    account: account.clone(),
+                    previous: BlockHash::from_bytes(&[2u8; 32]).unwrap(),
+                    representative: account.clone(),
+                    balance: 1,
+                    link: Link::Unknown(link),
+                }), Some(Signature::from_bytes(&[4u8; 64]).unwrap()),
+                    Some(Work::from_hex("0000000000000000").unwrap()));
+                hashes.push(block.hash(false).unwrap());
+                batch.put_block(*hashes.last().unwrap(), block);
+            }
+            store.write(&batch).unwrap();
+            let mut batch = WriteBatch::new();
+            for hash in &hashes[1..] {
+                batch.delete_block(*hash);
+            }
+            store.write(&batch).unwrap();
+        }
+
+        let summary = vacuum(&config, None).unwrap();
+        assert!(summary.size_after < summary.size_before);
+        assert!(dir.join(BACKUP_FILE).is_file());
+        assert!(vacuum(&config, None).is_err());
+        let store = store::open(&config).unwrap();
+        assert_eq!(store.block_count().unwrap(), 1);
+        assert!(store.block(&hashes[0]).unwrap().is_some());
+        let stats = store.stats().unwrap();
+        assert!(stats.last_vacuum.is_some());
+        assert_eq!(stats.tables.iter().find(|table| table.name == "blocks").unwrap().entries, 1);
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
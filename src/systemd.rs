@@ -0,0 +1,52 @@
+//! Readiness and watchdog notifications for running under systemd with `Type=notify`.
+//!
+//! Messages are sent to the socket named by `NOTIFY_SOCKET`, so everything here is a
+//! no-op when the node isn't started by systemd.
+use std::env;
+use std::time::Duration;
+
+use crate::error::*;
+
+/// Send a notification such as `READY=1`, returning false if not running under systemd
+#[cfg(unix)]
+pub fn notify(state: &str) -> Result<bool> {
+    use std::os::unix::net::UnixDatagram;
+
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(false),
+    };
+    if path.to_string_lossy().starts_with('@') {
+        bail!("Abstract NOTIFY_SOCKET addresses are not supported");
+    }
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to(state.as_bytes(), path)?;
+    Ok(true)
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) -> Result<bool> {
+    Ok(false)
+}
+
+/// Log rather than fail on notification errors, which shouldn't stop the node
+pub fn notify_or_warn(state: &str) {
+    if let Err(e) = notify(state) {
+        warn!("Failed to notify systemd of {}: {}", state, e);
+    }
+}
+
+/// How often to ping the watchdog, if systemd enabled it for this process. This is
+/// half the configured timeout, as recommended by `sd_watchdog_enabled(3)`.
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if let Some(pid) = env::var("WATCHDOG_PID").ok() {
+        if pid.parse::<u32>().ok()? != ::std::process::id() {
+            return None;
+        }
+    }
+    if usec == 0 {
+        return None;
+    }
+    Some(Duration::from_millis(usec / 2000))
+}
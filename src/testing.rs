@@ -0,0 +1,426 @@
+//! Running clusters of dev network nodes in one process, for end-to-end tests.
+//!
+//! `Cluster::start` runs each node on a `MemoryNetwork` rather than a real socket,
+//! with an in-memory store in a temporary directory, peered with every other node.
+//! Nodes are given addresses in 10.0.0.0/8, as loopback addresses aren't accepted as
+//! peers. The genesis key of the dev network is well known, so the cluster can open
+//! the genesis account and distribute its funds with `fund`.
+//!
+//! Nodes don't flood blocks to their peers yet, so blocks are published straight to
+//! every node, and without elections a block counts as confirmed once every node has
+//! stored it. Tests can hold, drop and release frames between nodes through
+//! `Cluster::network`, or start the cluster with `start_with_faults` to have faults
+//! injected into everything the nodes send.
+//!
+//! Every cluster runs from one seed, which seeds each node's random choices, the work
+//! of the blocks the cluster publishes, and any faults, so that a run can be
+//! repeated. Timing still varies from run to run, as nodes run on a real clock.
+//!
+//! `scenario::Scenario` describes a test declaratively instead: the cluster's
+//! topology, partitions, the blocks submitted and the confirmations expected.
+#![cfg_attr(not(test), allow(dead_code))]
+pub mod scenario;
+
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use data_encoding::HEXUPPER;
+use futures::SinkExt;
+use futures::channel::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+
+use nano_lib_rs::block::{Block, BlockHash, BlockKind, BlockPayload, Link};
+use nano_lib_rs::keys::{self, PublicKey, SecretKey};
+use nano_lib_rs::message::{MessageBuilder, MessageKind, MessagePayload};
+use nano_lib_rs::network::NetworkParams;
+use nanopow_rs::WorkGenerator;
+
+use crate::config::{Config, ConfigReloader};
+use crate::daemon::Shutdown;
+use crate::error::*;
+use crate::logging::LogFilter;
+use crate::net::faults::{FaultConfig, FaultCounts, FaultyTransport};
+use crate::net::memory::{Delivery, MemoryChannel, MemoryNetwork};
+use crate::node::{self, state::State};
+use self::scenario::Topology;
+
+/// The secret key of the dev network's genesis account
+pub const DEV_GENESIS_KEY: &str = "34F0A37AAD20F4A260F0A5B3CB3D7FB50673212263E58A380BC10474BB039CE4";
+
+/// The raw amount the genesis account opens with
+pub const GENESIS_AMOUNT: u128 = ::std::u128::MAX;
+
+/// How often `await_confirmed` checks the nodes' stores
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Distinguishes the directories of clusters started by one process
+static CLUSTERS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn genesis_key() -> SecretKey {
+    SecretKey::from_bytes(&HEXUPPER.decode(DEV_GENESIS_KEY.as_bytes()).unwrap()).unwrap()
+}
+
+/// `block` with work meeting the dev network's threshold, signed with `key`. The work
+/// is searched for on one thread from `seed`, so the same block gets the same work.
+fn finish(mut block: Block, key: &SecretKey, seed: u64) -> Result<Block> {
+    let root = match block.payload {
+        Some(ref payload) => payload.work_source(),
+        None => bail!("Cannot finish a block without a payload"),
+    };
+    block.work = WorkGenerator::new()
+        .with_threads(1)
+        .with_seed(seed)
+        .with_threshold(NetworkParams::dev().work_threshold)
+        .generate(&root);
+    block.sign(key)?;
+    Ok(block)
+}
+
+/// An account opened by `Cluster::fund`
+pub struct Funded {
+    pub key: SecretKey,
+    pub account: PublicKey,
+    /// The account's open block, its only block
+    pub open: BlockHash,
+    pub balance: u128,
+}
+
+/// One node of a `Cluster`
+pub struct TestNode {
+    pub addr: SocketAddr,
+    pub state: Arc<State>,
+    /// The faults injected into what the node sent, if any
+    pub faults: Option<Arc<FaultCounts>>,
+    shutdown: Arc<Shutdown>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl TestNode {
+    /// Whether the node has stored `hash`
+    pub fn has_block(&self, hash: &BlockHash) -> bool {
+        self.state.store.block_exists(hash).unwrap_or(false)
+    }
+}
+
+/// Dev network nodes running on a `MemoryNetwork` in this process
+pub struct Cluster {
+    network: MemoryNetwork,
+    nodes: Vec<TestNode>,
+    /// Where blocks are published from
+    client: MemoryChannel,
+    dir: PathBuf,
+    seed: u64,
+    /// The genesis account's latest block and balance, once it's opened
+    genesis: Option<(BlockHash, u128)>,
+}
+
+impl Cluster {
+    /// Start `count` nodes, each peered with the others, from the seed 0. Must be
+    /// called within a Tokio runtime.
+    pub async fn start(count: usize) -> Result<Cluster> {
+        Cluster::start_seeded(count, 0).await
+    }
+
+    /// Start `count` nodes from `seed`. Each node is seeded by `seed` plus its index.
+    pub async fn start_seeded(count: usize, seed: u64) -> Result<Cluster> {
+        Cluster::start_with(count, seed, None, &Topology::Full).await
+    }
+
+    /// Start `count` nodes whose frames have faults injected as set by `faults`, from
+    /// `faults.seed`. Each node's faults are seeded by `faults.seed` plus its index.
+    pub async fn start_with_faults(count: usize, faults: FaultConfig) -> Result<Cluster> {
+        Cluster::start_with(count, faults.seed, Some(faults), &Topology::Full).await
+    }
+
+    /// Start `count` nodes, each peered with its neighbours in `topology`
+    async fn start_with(count: usize, seed: u64, faults: Option<FaultConfig>, topology: &Topology)
+        -> Result<Cluster>
+    {
+        let id = CLUSTERS.fetch_add(1, Ordering::SeqCst);
+        let dir = env::temp_dir().join(format!("nano-rs-cluster-{}-{}", ::std::process::id(), id));
+        fs::create_dir_all(&dir)?;
+        let port = NetworkParams::dev().default_port;
+        let addrs: Vec<SocketAddr> = (1..=count)
+            .map(|i| SocketAddr::from(([10, 0, (i / 256) as u8, (i % 256) as u8], port)))
+            .collect();
+        let network = MemoryNetwork::new();
+        let client = network.channel(SocketAddr::from(([10, 255, 255, 254], port)));
+        let mut nodes = Vec::with_capacity(count);
+        for (i, &addr) in addrs.iter().enumerate() {
+            let peers: Vec<String> = topology.peers(i, count).into_iter()
+                .map(|peer| format!("\"{}\"", addrs[peer]))
+                .collect();
+            let config = Config::from_toml(&format!(r#"
+                [node]
+                network = "dev"
+                preconfigured_peers = [{}]
+                block_processor_threads = 1
+                signature_checker_threads = 1
+                seed = {}
+                [store]
+                backend = "memory"
+                path = {:?}
+                [rpc]
+                enable = false
+            "#, peers.join(", "), seed.wrapping_add(i as u64), dir.join(format!("node{}", i)).to_string_lossy()))?;
+            let faults = faults.clone().map(|faults| FaultConfig { seed: faults.seed.wrapping_add(i as u64), ..faults });
+            nodes.push(start_node(&network, addr, config, faults).await?);
+        }
+        info!("Started a cluster of {} nodes", count);
+        Ok(Cluster { network, nodes, client, dir, seed, genesis: None })
+    }
+
+    pub fn network(&self) -> &MemoryNetwork {
+        &self.network
+    }
+
+    pub fn nodes(&self) -> &[TestNode] {
+        &self.nodes
+    }
+
+    /// The node at each of `indices`
+    fn select(&self, indices: &[usize]) -> Result<Vec<&TestNode>> {
+        indices.iter()
+            .map(|&i| self.nodes.get(i)
+                .ok_or_else(|| format!("There is no node {} in a cluster of {}", i, self.nodes.len()).into()))
+            .collect()
+    }
+
+    fn all(&self) -> Vec<usize> {
+        (0..self.nodes.len()).collect()
+    }
+
+    /// Publish `block` to every node, returning its hash
+    pub async fn publish(&mut self, block: Block) -> Result<BlockHash> {
+        let all = self.all();
+        self.publish_to(block, &all).await
+    }
+
+    /// Publish `block` to the nodes at `indices`, returning its hash
+    pub async fn publish_to(&mut self, mut block: Block, indices: &[usize]) -> Result<BlockHash> {
+        let hash = block.hash(false)?;
+        let msg = MessageBuilder::new(MessageKind::Publish)
+            .with_network(NetworkParams::dev().kind)
+            .with_block_kind(block.kind)
+            .with_payload(MessagePayload::Publish(block))
+            .build();
+        let addrs: Vec<SocketAddr> = self.select(indices)?.iter().map(|node| node.addr).collect();
+        for addr in addrs {
+            self.client.send((msg.clone(), addr)).await?;
+        }
+        Ok(hash)
+    }
+
+    /// Cut the nodes of each of `groups` off from the nodes of the others, dropping
+    /// the frames between them. Every node must be in exactly one group.
+    pub fn partition(&self, groups: &[Vec<usize>]) -> Result<()> {
+        let mut group_of = vec![None; self.nodes.len()];
+        for (group, indices) in groups.iter().enumerate() {
+            self.select(indices)?;
+            for &i in indices {
+                if group_of[i].replace(group).is_some() {
+                    bail!("Node {} is in more than one group of the partition", i);
+                }
+            }
+        }
+        if let Some(i) = group_of.iter().position(Option::is_none) {
+            bail!("Node {} is in no group of the partition", i);
+        }
+        for (a, from) in self.nodes.iter().enumerate() {
+            for (b, to) in self.nodes.iter().enumerate() {
+                let delivery = if group_of[a] == group_of[b] { Delivery::Immediate } else { Delivery::Drop };
+                self.network.set_link(from.addr, to.addr, delivery);
+            }
+        }
+        Ok(())
+    }
+
+    /// Deliver frames between every pair of nodes again, ending any partition
+    pub fn heal(&self) {
+        for from in &self.nodes {
+            for to in &self.nodes {
+                self.network.set_link(from.addr, to.addr, Delivery::Immediate);
+            }
+        }
+    }
+
+    /// Open the genesis account, if it isn't already, returning its open block
+    pub async fn open_genesis(&mut self) -> Result<BlockHash> {
+        if let Some((head, _)) = self.genesis {
+            return Ok(head);
+        }
+        let genesis = keys::public_key_from_secret(&genesis_key());
+        let open = finish(Block::new(BlockKind::Open, Some(BlockPayload::Open {
+            source: BlockHash::from_bytes(genesis.as_bytes())?,
+            representative: genesis.clone(),
+            account: genesis,
+        }), None, None), &genesis_key(), self.seed)?;
+        let hash = self.publish(open).await?;
+        self.genesis = Some((hash, GENESIS_AMOUNT));
+        Ok(hash)
+    }
+
+    /// Open `count` accounts, each sent `amount` raw from the genesis account, and wait
+    /// until every node has stored their blocks. The accounts' keys are derived from
+    /// a fixed seed, so each run funds the same accounts.
+    pub async fn fund(&mut self, count: u32, amount: u128) -> Result<Vec<Funded>> {
+        self.open_genesis().await?;
+        let genesis_key = genesis_key();
+        let genesis = keys::public_key_from_secret(&genesis_key);
+        let seed = [0x6e; keys::SEED_LENGTH];
+        let mut funded = Vec::with_capacity(count as usize);
+        let mut hashes = Vec::with_capacity(2 * count as usize);
+        for index in 0..count {
+            let (head, balance) = self.genesis.unwrap();
+            let balance = balance.checked_sub(amount)
+                .ok_or_else(|| Error::from("The genesis account doesn't hold enough to fund the accounts"))?;
+            let key = keys::deterministic_key(&seed, index);
+            let account = keys::public_key_from_secret(&key);
+            let send = finish(Block::new(BlockKind::State, Some(BlockPayload::State {
+                account: genesis.clone(),
+                previous: head,
+                representative: genesis.clone(),
+                balance,
+                link: Link::Destination(account.clone()),
+            }), None, None), &genesis_key, self.seed)?;
+            let send = self.publish(send).await?;
+            self.genesis = Some((send, balance));
+            let open = finish(Block::new(BlockKind::State, Some(BlockPayload::State {
+                account: account.clone(),
+                previous: BlockHash::from_bytes(&[0u8; 32])?,
+                representative: genesis.clone(),
+                balance: amount,
+                link: Link::Source(send),
+            }), None, None), &key, self.seed)?;
+            let open = self.publish(open).await?;
+            hashes.extend_from_slice(&[send, open]);
+            funded.push(Funded { key, account, open, balance: amount });
+        }
+        self.await_confirmed(&hashes, Duration::from_secs(30)).await?;
+        Ok(funded)
+    }
+
+    /// Wait until every node has stored every block of `hashes`, failing after
+    /// `timeout`
+    pub async fn await_confirmed(&self, hashes: &[BlockHash], timeout: Duration) -> Result<()> {
+        self.await_confirmed_on(&self.all(), hashes, timeout).await
+    }
+
+    /// Wait until the nodes at `indices` have stored every block of `hashes`, failing
+    /// after `timeout`
+    pub async fn await_confirmed_on(&self, indices: &[usize], hashes: &[BlockHash], timeout: Duration) -> Result<()> {
+        let nodes = self.select(indices)?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            let missing = nodes.iter()
+                .map(|node| hashes.iter().filter(|hash| !node.has_block(hash)).count())
+                .sum::<usize>();
+            if missing == 0 {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                bail!("{} of {} blocks were still unconfirmed across the cluster after {}s",
+                    missing, hashes.len() * nodes.len(), timeout.as_secs());
+            }
+            time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Wait until every node has `count` peers, failing after `timeout`
+    pub async fn await_peers(&self, count: usize, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        while self.nodes.iter().any(|node| node.state.peer_count() < count) {
+            if Instant::now() >= deadline {
+                bail!("Nodes did not reach {} peers within {}s", count, timeout.as_secs());
+            }
+            time::sleep(POLL_INTERVAL).await;
+        }
+        Ok(())
+    }
+
+    /// Stop every node, returning the first error any of them stopped with
+    pub async fn stop(self) -> Result<()> {
+        for node in &self.nodes {
+            node.shutdown.trigger();
+        }
+        let mut result = Ok(());
+        for node in self.nodes {
+            let stopped = node.task.await
+                .map_err(|_| Error::from("Node task exited unexpectedly"))
+                .and_then(|stopped| stopped);
+            if let Err(e) = stopped {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        let _ = fs::remove_dir_all(&self.dir);
+        result
+    }
+}
+
+/// Spawn a node at `addr` on `network`, returning once it's running
+async fn start_node(network: &MemoryNetwork, addr: SocketAddr, config: Config, faults: Option<FaultConfig>)
+    -> Result<TestNode>
+{
+    let node_config = config.node_config()?;
+    let reloader = ConfigReloader::new("nano-rs.toml", config, LogFilter::detached("info")?);
+    let shutdown = Arc::new(Shutdown::new());
+    let (started, state) = oneshot::channel();
+    let started = move |state| {
+        let _ = started.send(state);
+    };
+    let transport = network.transport(&[addr]);
+    let (task, faults) = match faults {
+        Some(faults) => {
+            let transport = FaultyTransport::new(transport, faults);
+            let counts = transport.counts();
+            (tokio::spawn(node::run_on(transport, node_config, reloader, shutdown.clone(), started)), Some(counts))
+        }
+        None => (tokio::spawn(node::run_on(transport, node_config, reloader, shutdown.clone(), started)), None),
+    };
+    match state.await {
+        Ok(state) => Ok(TestNode { addr, state, faults, shutdown, task }),
+        // The node failed to start, so its task has finished with the error
+        Err(_) => match task.await {
+            Ok(Err(e)) => Err(e),
+            _ => bail!("Node at {} exited before starting", addr),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn funds_accounts_across_the_cluster() {
+        let mut cluster = Cluster::start(3).await.unwrap();
+        cluster.await_peers(2, Duration::from_secs(10)).await.unwrap();
+        let funded = cluster.fund(2, 1_000_000).await.unwrap();
+        assert_eq!(funded.len(), 2);
+        assert!(cluster.nodes().iter().all(|node| node.has_block(&funded[1].open)));
+        cluster.stop().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn injects_faults_into_sent_frames() {
+        let faults = FaultConfig { seed: 7, drop: 0.2, duplicate: 0.2, reorder: 0.2, ..FaultConfig::default() };
+        let mut cluster = Cluster::start_with_faults(3, faults).await.unwrap();
+        cluster.await_peers(2, Duration::from_secs(10)).await.unwrap();
+        let funded = cluster.fund(1, 1_000_000).await.unwrap();
+        assert!(cluster.nodes().iter().all(|node| node.has_block(&funded[0].open)));
+        let (dropped, duplicated, reordered) = cluster.nodes().iter()
+            .map(|node| node.faults.as_ref().unwrap().get())
+            .fold((0, 0, 0), |total, counts| (total.0 + counts.0, total.1 + counts.1, total.2 + counts.2));
+        assert!(dropped > 0 && duplicated > 0 && reordered > 0);
+        assert!(cluster.network().counts().0 > 0);
+        cluster.stop().await.unwrap();
+    }
+}
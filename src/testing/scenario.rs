@@ -0,0 +1,422 @@
+//! Describing multi-node tests declaratively.
+//!
+//! A `Scenario` lists what happens to a cluster in order: how its nodes are peered,
+//! which accounts are funded, the partitions, the blocks submitted and to which
+//! nodes, and the confirmations expected with a timeout for each. `run` starts the
+//! cluster, plays the steps, and stops the cluster again whether or not they succeed;
+//! the first step to fail is named in the error.
+//!
+//! Accounts are those funded by `Cluster::fund`, referred to by their index, and
+//! blocks are referred to by the label they were submitted with. Nodes don't flood
+//! blocks yet, so a block only reaches the nodes it is submitted to, and a
+//! topology only sets each node's preconfigured peers, which keepalives extend.
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use nano_lib_rs::block::{Block, BlockHash, BlockKind, BlockPayload, Link};
+use nano_lib_rs::keys;
+
+use crate::error::*;
+use crate::net::faults::FaultConfig;
+use super::{finish, genesis_key, Cluster, Funded};
+
+/// How the nodes of a cluster are peered when they start
+#[derive(Clone, Debug, PartialEq)]
+pub enum Topology {
+    /// Every node with every other
+    Full,
+    /// Each node with the one before and after it
+    Line,
+    /// As `Line`, with the last node also peered with the first
+    Ring,
+    /// The first node with every other, and the others only with the first
+    Star,
+    /// The pairs of nodes listed, each peered with the other
+    Custom(Vec<(usize, usize)>),
+}
+
+impl Topology {
+    /// The peers of the node at `index` of `count`
+    pub fn peers(&self, index: usize, count: usize) -> Vec<usize> {
+        let linked = |a: usize, b: usize| match *self {
+            Topology::Full => true,
+            Topology::Line => a + 1 == b || b + 1 == a,
+            Topology::Ring => a + 1 == b || b + 1 == a || (a, b) == (0, count - 1) || (b, a) == (0, count - 1),
+            Topology::Star => a == 0 || b == 0,
+            Topology::Custom(ref pairs) => pairs.contains(&(a, b)) || pairs.contains(&(b, a)),
+        };
+        (0..count).filter(|&peer| peer != index && linked(index, peer)).collect()
+    }
+}
+
+/// The nodes a step applies to
+#[derive(Clone, Debug, PartialEq)]
+enum Nodes {
+    All,
+    Only(Vec<usize>),
+}
+
+impl fmt::Display for Nodes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Nodes::All => write!(f, "every node"),
+            Nodes::Only(ref nodes) => write!(f, "nodes {:?}", nodes),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Step {
+    AwaitPeers { count: usize, within: Duration },
+    Partition(Vec<Vec<usize>>),
+    Heal,
+    Send { label: String, from: usize, to: usize, amount: u128, nodes: Nodes },
+    Receive { label: String, account: usize, send: String, nodes: Nodes },
+    Resubmit { label: String, nodes: Nodes },
+    ExpectConfirmed { labels: Vec<String>, nodes: Nodes, within: Duration },
+    ExpectMissing { labels: Vec<String>, nodes: Nodes },
+    Wait(Duration),
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Step::AwaitPeers { count, within } => write!(f, "awaiting {} peers within {:?}", count, within),
+            Step::Partition(ref groups) => write!(f, "partitioning into {:?}", groups),
+            Step::Heal => write!(f, "healing the partition"),
+            Step::Send { ref label, from, to, amount, ref nodes } =>
+                write!(f, "sending `{}`, {} raw from account {} to {}, to {}", label, amount, from, to, nodes),
+            Step::Receive { ref label, account, ref send, ref nodes } =>
+                write!(f, "receiving `{}`, account {} receiving `{}`, to {}", label, account, send, nodes),
+            Step::Resubmit { ref label, ref nodes } => write!(f, "submitting `{}` again to {}", label, nodes),
+            Step::ExpectConfirmed { ref labels, ref nodes, within } =>
+                write!(f, "expecting {:?} confirmed on {} within {:?}", labels, nodes, within),
+            Step::ExpectMissing { ref labels, ref nodes } => write!(f, "expecting {:?} missing on {}", labels, nodes),
+            Step::Wait(duration) => write!(f, "waiting {:?}", duration),
+        }
+    }
+}
+
+/// A multi-node test, built up step by step and played by `run`
+#[derive(Clone, Debug)]
+pub struct Scenario {
+    nodes: usize,
+    seed: u64,
+    faults: Option<FaultConfig>,
+    topology: Topology,
+    accounts: u32,
+    funding: u128,
+    steps: Vec<Step>,
+}
+
+impl Scenario {
+    /// A scenario for a fully peered cluster of `nodes` nodes, from the seed 0
+    pub fn new(nodes: usize) -> Self {
+        Scenario {
+            nodes,
+            seed: 0,
+            faults: None,
+            topology: Topology::Full,
+            accounts: 0,
+            funding: 0,
+            steps: Vec::new(),
+        }
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Inject faults into the frames the nodes send, which are seeded by the
+    /// scenario's seed rather than `faults.seed`
+    pub fn faults(mut self, faults: FaultConfig) -> Self {
+        self.faults = Some(faults);
+        self
+    }
+
+    pub fn topology(mut self, topology: Topology) -> Self {
+        self.topology = topology;
+        self
+    }
+
+    /// Fund `count` accounts with `amount` raw each before the first step
+    pub fn fund(mut self, count: u32, amount: u128) -> Self {
+        self.accounts = count;
+        self.funding = amount;
+        self
+    }
+
+    fn step(mut self, step: Step) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    /// Wait until every node has `count` peers
+    pub fn await_peers(self, count: usize, within: Duration) -> Self {
+        self.step(Step::AwaitPeers { count, within })
+    }
+
+    /// Drop the frames between nodes of different `groups`, which together must hold
+    /// every node
+    pub fn partition(self, groups: &[&[usize]]) -> Self {
+        self.step(Step::Partition(groups.iter().map(|group| group.to_vec()).collect()))
+    }
+
+    /// Deliver frames between every pair of nodes again
+    pub fn heal(self) -> Self {
+        self.step(Step::Heal)
+    }
+
+    /// Submit to every node a send of `amount` raw from account `from` to account
+    /// `to`, labelled `label`
+    pub fn send(self, label: &str, from: usize, to: usize, amount: u128) -> Self {
+        self.step(Step::Send { label: label.to_owned(), from, to, amount, nodes: Nodes::All })
+    }
+
+    /// As `send`, submitted only to `nodes`
+    pub fn send_via(self, nodes: &[usize], label: &str, from: usize, to: usize, amount: u128) -> Self {
+        self.step(Step::Send { label: label.to_owned(), from, to, amount, nodes: Nodes::Only(nodes.to_vec()) })
+    }
+
+    /// Submit to every node account `account`'s receive of the send labelled `send`,
+    /// labelled `label`
+    pub fn receive(self, label: &str, account: usize, send: &str) -> Self {
+        self.step(Step::Receive { label: label.to_owned(), account, send: send.to_owned(), nodes: Nodes::All })
+    }
+
+    /// Submit the block labelled `label` again, to `nodes`
+    pub fn resubmit(self, label: &str, nodes: &[usize]) -> Self {
+        self.step(Step::Resubmit { label: label.to_owned(), nodes: Nodes::Only(nodes.to_vec()) })
+    }
+
+    /// Expect every node to have confirmed the blocks labelled `labels` within `within`
+    pub fn expect_confirmed(self, labels: &[&str], within: Duration) -> Self {
+        self.step(Step::ExpectConfirmed { labels: owned(labels), nodes: Nodes::All, within })
+    }
+
+    /// Expect `nodes` to have confirmed the blocks labelled `labels` within `within`
+    pub fn expect_confirmed_on(self, nodes: &[usize], labels: &[&str], within: Duration) -> Self {
+        self.step(Step::ExpectConfirmed { labels: owned(labels), nodes: Nodes::Only(nodes.to_vec()), within })
+    }
+
+    /// Expect `nodes` not to have stored any of the blocks labelled `labels`
+    pub fn expect_missing_on(self, nodes: &[usize], labels: &[&str]) -> Self {
+        self.step(Step::ExpectMissing { labels: owned(labels), nodes: Nodes::Only(nodes.to_vec()) })
+    }
+
+    pub fn wait(self, duration: Duration) -> Self {
+        self.step(Step::Wait(duration))
+    }
+
+    /// Start the cluster, play every step, and stop the cluster. Must be called within
+    /// a Tokio runtime.
+    pub async fn run(self) -> Result<()> {
+        let faults = self.faults.clone().map(|faults| FaultConfig { seed: self.seed, ..faults });
+        let mut cluster = Cluster::start_with(self.nodes, self.seed, faults, &self.topology).await?;
+        let played = self.play(&mut cluster).await;
+        let stopped = cluster.stop().await;
+        played.and(stopped)
+    }
+
+    async fn play(&self, cluster: &mut Cluster) -> Result<()> {
+        let accounts = if self.accounts > 0 {
+            cluster.fund(self.accounts, self.funding).await.chain_err(|| "Could not fund the scenario's accounts")?
+        } else {
+            Vec::new()
+        };
+        let mut play = Play {
+            heads: accounts.iter().map(|funded| (funded.open, funded.balance)).collect(),
+            accounts,
+            blocks: HashMap::new(),
+        };
+        for (i, step) in self.steps.iter().enumerate() {
+            play.step(cluster, step).await.chain_err(|| format!("Step {}, {}, failed", i + 1, step))?;
+        }
+        Ok(())
+    }
+}
+
+fn owned(labels: &[&str]) -> Vec<String> {
+    labels.iter().map(|&label| label.to_owned()).collect()
+}
+
+/// The accounts and blocks of a scenario being played
+struct Play {
+    accounts: Vec<Funded>,
+    /// The latest block and balance of each account
+    heads: Vec<(BlockHash, u128)>,
+    /// Each block submitted, by label, and the amount it sent
+    blocks: HashMap<String, (Block, u128)>,
+}
+
+impl Play {
+    /// The latest block and balance of account `index`
+    fn head(&self, index: usize) -> Result<(BlockHash, u128)> {
+        self.heads.get(index).cloned()
+            .ok_or_else(|| format!("There is no account {} among the {} funded", index, self.accounts.len()).into())
+    }
+
+    fn account(&self, index: usize) -> Result<&Funded> {
+        self.accounts.get(index)
+            .ok_or_else(|| format!("There is no account {} among the {} funded", index, self.accounts.len()).into())
+    }
+
+    fn block(&self, label: &str) -> Result<&(Block, u128)> {
+        self.blocks.get(label).ok_or_else(|| format!("No block was submitted as `{}`", label).into())
+    }
+
+    fn hashes(&self, labels: &[String]) -> Result<Vec<BlockHash>> {
+        labels.iter().map(|label| Ok(self.block(label)?.0.hash.expect("submitted blocks are hashed"))).collect()
+    }
+
+    /// Build, sign and submit account `index`'s next block, moving its balance to
+    /// `balance`, and remember it by `label` with the amount it sent
+    async fn submit(&mut self, cluster: &mut Cluster, label: &str, index: usize, balance: u128, link: Link,
+        nodes: &Nodes) -> Result<()>
+    {
+        if self.blocks.contains_key(label) {
+            bail!("A block was already submitted as `{}`", label);
+        }
+        let (head, previous_balance) = self.head(index)?;
+        let funded = self.account(index)?;
+        let representative = keys::public_key_from_secret(&genesis_key());
+        let mut block = finish(Block::new(BlockKind::State, Some(BlockPayload::State {
+            account: funded.account.clone(),
+            previous: head,
+            representative,
+            balance,
+            link,
+        }), None, None), &funded.key, cluster.seed)?;
+        let hash = block.hash(false)?;
+        publish(cluster, block.clone(), nodes).await?;
+        self.heads[index] = (hash, balance);
+        self.blocks.insert(label.to_owned(), (block, previous_balance.saturating_sub(balance)));
+        Ok(())
+    }
+
+    async fn step(&mut self, cluster: &mut Cluster, step: &Step) -> Result<()> {
+        match *step {
+            Step::AwaitPeers { count, within } => cluster.await_peers(count, within).await,
+            Step::Partition(ref groups) => cluster.partition(groups),
+            Step::Heal => {
+                cluster.heal();
+                Ok(())
+            }
+            Step::Send { ref label, from, to, amount, ref nodes } => {
+                let destination = self.account(to)?.account.clone();
+                let balance = self.head(from)?.1.checked_sub(amount)
+                    .ok_or_else(|| Error::from(format!("Account {} doesn't hold {} raw", from, amount)))?;
+                self.submit(cluster, label, from, balance, Link::Destination(destination), nodes).await
+            }
+            Step::Receive { ref label, account, ref send, ref nodes } => {
+                let (source, amount) = {
+                    let &(ref block, amount) = self.block(send)?;
+                    (block.hash.expect("submitted blocks are hashed"), amount)
+                };
+                let balance = self.head(account)?.1.saturating_add(amount);
+                self.submit(cluster, label, account, balance, Link::Source(source), nodes).await
+            }
+            Step::Resubmit { ref label, ref nodes } => {
+                let block = self.block(label)?.0.clone();
+                publish(cluster, block, nodes).await
+            }
+            Step::ExpectConfirmed { ref labels, ref nodes, within } => {
+                let hashes = self.hashes(labels)?;
+                match *nodes {
+                    Nodes::All => cluster.await_confirmed(&hashes, within).await,
+                    Nodes::Only(ref indices) => cluster.await_confirmed_on(indices, &hashes, within).await,
+                }
+            }
+            Step::ExpectMissing { ref labels, ref nodes } => {
+                let hashes = self.hashes(labels)?;
+                let indices = match *nodes {
+                    Nodes::All => cluster.all(),
+                    Nodes::Only(ref indices) => indices.clone(),
+                };
+                for (&i, node) in indices.iter().zip(cluster.select(&indices)?) {
+                    if let Some(hash) = hashes.iter().find(|hash| node.has_block(hash)) {
+                        bail!("Node {} has stored {}", i, String::from(*hash));
+                    }
+                }
+                Ok(())
+            }
+            Step::Wait(duration) => {
+                tokio::time::sleep(duration).await;
+                Ok(())
+            }
+        }
+    }
+}
+
+async fn publish(cluster: &mut Cluster, block: Block, nodes: &Nodes) -> Result<()> {
+    match *nodes {
+        Nodes::All => cluster.publish(block).await?,
+        Nodes::Only(ref indices) => cluster.publish_to(block, indices).await?,
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topologies() {
+        assert_eq!(Topology::Full.peers(1, 3), vec![0, 2]);
+        assert_eq!(Topology::Line.peers(0, 3), vec![1]);
+        assert_eq!(Topology::Ring.peers(0, 4), vec![1, 3]);
+        assert_eq!(Topology::Star.peers(2, 4), vec![0]);
+        assert_eq!(Topology::Custom(vec![(0, 2)]).peers(2, 3), vec![0]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn confirms_blocks_across_a_healed_partition() {
+        let second = Duration::from_secs(1);
+        Scenario::new(3)
+            .seed(3)
+            .topology(Topology::Line)
+            .fund(2, 1_000_000)
+            .await_peers(1, 10 * second)
+            .partition(&[&[0, 1], &[2]])
+            .send_via(&[0, 1], "pay", 0, 1, 1_000)
+            .expect_confirmed_on(&[0, 1], &["pay"], 10 * second)
+            .expect_missing_on(&[2], &["pay"])
+            .heal()
+            .resubmit("pay", &[2])
+            .receive("collect", 1, "pay")
+            .expect_confirmed(&["pay", "collect"], 10 * second)
+            .run()
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn confirms_blocks_sent_under_faults() {
+        let second = Duration::from_secs(1);
+        let faults = FaultConfig { drop: 0.2, duplicate: 0.2, reorder: 0.2, ..FaultConfig::default() };
+        Scenario::new(3)
+            .seed(5)
+            .faults(faults)
+            .fund(2, 1_000_000)
+            .await_peers(2, 10 * second)
+            .send("pay", 0, 1, 1_000)
+            .wait(Duration::from_millis(100))
+            .receive("collect", 1, "pay")
+            .expect_confirmed(&["pay", "collect"], 10 * second)
+            .run()
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn names_the_failing_step() {
+        let error = Scenario::new(1)
+            .expect_confirmed(&["never"], Duration::from_secs(1))
+            .run()
+            .await
+            .unwrap_err();
+        assert!(error.to_string().starts_with("Step 1, expecting [\"never\"] confirmed"));
+    }
+}
@@ -1,46 +1,112 @@
-use std::marker::PhantomData;
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
 
-use futures::{Async, Stream};
-use error::*;
+use chrono::{DateTime, Local};
+use data_encoding::HEXUPPER;
+use futures::channel::mpsc;
+use futures::{SinkExt, Stream, StreamExt};
+use nano_lib_rs::keys::{Address, PublicKey};
+use rand::{self, SeedableRng, XorShiftRng};
+use crate::error::*;
 
 #[macro_export]
 macro_rules! default_addr {
     () => { ::std::net::SocketAddrV6::new(::std::net::Ipv6Addr::from([0u8; 16]), 0, 0, 0) };
 }
 
-pub struct LogErrors<S: Stream, I> {
+/// Number of recent error messages kept by an `ErrorLog`
+const RECENT_ERRORS: usize = 32;
+
+/// Counts non-fatal errors by kind and keeps the most recent ones for diagnostics
+#[derive(Debug, Default)]
+pub struct ErrorLog {
+    counts: Mutex<HashMap<String, usize>>,
+    recent: Mutex<VecDeque<(DateTime<Local>, String)>>,
+}
+
+impl ErrorLog {
+    pub fn new() -> Self {
+        ErrorLog::default()
+    }
+
+    pub fn record(&self, error: &Error) {
+        *self.counts.lock().unwrap().entry(error.kind().description().to_owned()).or_insert(0) += 1;
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == RECENT_ERRORS {
+            recent.pop_front();
+        }
+        recent.push_back((Local::now(), format!("{}", error)));
+    }
+
+    /// The number of errors recorded of each kind
+    pub fn counts(&self) -> HashMap<String, usize> {
+        self.counts.lock().unwrap().clone()
+    }
+
+    /// The most recent errors, oldest first
+    pub fn recent(&self) -> Vec<(DateTime<Local>, String)> {
+        self.recent.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+pub struct LogErrors<S> {
     inner: S,
-    phantom_item: PhantomData<I>
+    errors: Arc<ErrorLog>,
 }
 
-impl<S, I> Stream for LogErrors<S, I> 
-    where S: Stream<Item = I, Error = Error>
+impl<S, I> Stream for LogErrors<S>
+    where S: Stream<Item = Result<I>> + Unpin
 {
-    type Item = S::Item;
-    type Error = Error;
+    type Item = Result<I>;
 
-    fn poll(&mut self) -> Result<Async<Option<S::Item>>> {
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
         loop {
-            match self.inner.poll() {
-                Ok(x) => return Ok(x),
-                Err(e) => {
+            match futures::ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Err(e)) => {
                     if let ErrorKind::FatalStreamError = *e.kind() {
-                        return Err(e);
+                        return Poll::Ready(Some(Err(e)));
                     } else {
                         error!("Non-fatal error in stream: {:?}", e);
+                        this.errors.record(&e);
                     }
                 },
+                item => return Poll::Ready(item),
             }
         }
     }
 }
 
-pub fn log_errors<S, I>(stream: S) -> LogErrors<S, I> 
-    where S: Stream<Item = I, Error = Error>
+/// Log and record non-fatal errors in `errors`, ending the stream only on fatal ones
+pub fn log_errors<S, I>(stream: S, errors: Arc<ErrorLog>) -> LogErrors<S>
+    where S: Stream<Item = Result<I>> + Unpin
 {
     LogErrors {
         inner: stream,
-        phantom_item: PhantomData,
+        errors,
+    }
+}
+
+/// Send every item of `stream` to `sender`, logging non-fatal errors. Stops at the end
+/// of the stream, on a fatal error, or once the receiver is dropped.
+pub async fn forward<S, I>(stream: S, mut sender: mpsc::Sender<I>, errors: Arc<ErrorLog>, task: &'static str)
+    where S: Stream<Item = Result<I>>
+{
+    let mut items = log_errors(Box::pin(stream), errors);
+    while let Some(item) = items.next().await {
+        match item {
+            Ok(item) => if let Err(e) = sender.send(item).await {
+                error!("Fatal error sending messages from {}: {}", task, e);
+                return;
+            },
+            Err(e) => {
+                error!("Fatal error in {}: {:?}", task, e);
+                return;
+            }
+        }
     }
 }
 
@@ -54,7 +120,7 @@ const IPV4_RESERVED_ADDRESSES: &[(u32, u32)] = &[
     (0xf0000000, 0xffffffff), // rfc 6890
 ];
 
-use std::net::{SocketAddr, SocketAddrV6};
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
 
 pub fn check_addr(addr: SocketAddrV6) -> bool {
     let ip = addr.ip().clone();
@@ -79,6 +145,11 @@ pub fn check_addr(addr: SocketAddrV6) -> bool {
     true
 }
 
+/// `duration` in whole milliseconds
+pub fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_nanos()) / 1_000_000
+}
+
 pub fn to_ipv6(addr: SocketAddr) -> SocketAddrV6 {
     match addr {
         SocketAddr::V4(addr) => SocketAddrV6::new(addr.ip().to_ipv6_mapped(), addr.port(), 0, 0),
@@ -86,3 +157,73 @@ pub fn to_ipv6(addr: SocketAddr) -> SocketAddrV6 {
     }
 }
 
+/// `addr` as an IPv4 address if it is IPv4-mapped, the inverse of `to_ipv6`
+pub fn from_ipv6(addr: SocketAddrV6) -> SocketAddr {
+    match addr.ip().to_ipv4() {
+        Some(ip) if addr.ip().segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => {
+            SocketAddr::V4(SocketAddrV4::new(ip, addr.port()))
+        }
+        _ => SocketAddr::V6(addr),
+    }
+}
+
+/// The `nano_` address of `key`
+pub fn account_to_address(key: &PublicKey) -> String {
+    Address::from(key.clone()).0
+}
+
+/// The public key encoded in a `nano_` or legacy `xrb_` address, checking its checksum
+pub fn address_to_account(address: &str) -> Result<PublicKey> {
+    Ok(Address(address.to_owned()).to_public_key()?)
+}
+
+/// Whether `address` is a well formed `nano_` or `xrb_` address with a correct checksum
+pub fn is_valid_address(address: &str) -> bool {
+    Address(address.to_owned()).is_valid()
+}
+
+/// A public key written as 64 hex digits, in either case
+pub fn parse_public_key(hex: &str) -> Result<PublicKey> {
+    let invalid = || Error::from(format!("`{}` is not a valid hex key", hex));
+    let bytes = HEXUPPER.decode(hex.to_uppercase().as_bytes()).map_err(|_| invalid())?;
+    PublicKey::from_bytes(&bytes).map_err(|_| invalid())
+}
+
+/// A generator for the random choices of the stream `stream`, seeded by `seed` so
+/// that runs with the same seed make the same choices, or from the OS if `None`
+pub fn seeded_rng(seed: Option<u64>, stream: u32) -> XorShiftRng {
+    match seed {
+        // XorShift can't be seeded with zeros, which the last word rules out
+        Some(seed) => XorShiftRng::from_seed([seed as u32, (seed >> 32) as u32, stream, 0x9e37_79b9]),
+        None => rand::weak_rng(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GENESIS_KEY: &str = "E89208DD038FBB269987689621D52292AE9C35941A7484756ECCED92A65093BA";
+    const GENESIS_ADDRESS: &str = "nano_3t6k35gi95xu6tergt6p69ck76ogmitsa8mnijtpxm9fkcm736xtoncuohr3";
+
+    #[test]
+    fn converts_keys_and_addresses() {
+        let key = parse_public_key(&GENESIS_KEY.to_lowercase()).unwrap();
+        assert_eq!(account_to_address(&key), GENESIS_ADDRESS);
+        let legacy = GENESIS_ADDRESS.replace("nano_", "xrb_");
+        assert_eq!(address_to_account(&legacy).unwrap().as_bytes(), key.as_bytes());
+        assert!(is_valid_address(&legacy));
+        assert!(!is_valid_address(&GENESIS_ADDRESS[..GENESIS_ADDRESS.len() - 1]));
+        assert!(parse_public_key(&GENESIS_KEY[2..]).is_err());
+        assert!(parse_public_key(GENESIS_ADDRESS).is_err());
+    }
+
+    #[test]
+    fn maps_ipv4_addresses() {
+        let v4: SocketAddr = "192.0.2.1:7075".parse().unwrap();
+        assert_eq!(from_ipv6(to_ipv6(v4)), v4);
+        let v6: SocketAddr = "[2001:db8::1]:7075".parse().unwrap();
+        assert_eq!(from_ipv6(to_ipv6(v6)), v6);
+    }
+}
+
@@ -0,0 +1,107 @@
+use std::collections::{HashMap, VecDeque};
+
+use nano_lib_rs::block::{InputHash, Work};
+
+/// Maximum number of roots kept in the work cache
+pub const WORK_CACHE_SIZE: usize = 4096;
+
+/// Previously generated work, keyed by root (the previous block hash, or the account's
+/// public key for open blocks). The oldest entries are evicted once full.
+#[derive(Debug)]
+pub struct WorkCache {
+    capacity: usize,
+    entries: HashMap<InputHash, Work>,
+    order: VecDeque<InputHash>,
+}
+
+impl WorkCache {
+    pub fn new(capacity: usize) -> Self {
+        WorkCache {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Returns cached work for `root`, if it still meets `threshold`
+    pub fn get(&self, root: &InputHash, threshold: u64) -> Option<Work> {
+        self.entries.get(root)
+            .cloned()
+            .filter(|work| nanopow_rs::check_work_threshold(root, work, threshold))
+    }
+
+    pub fn insert(&mut self, root: InputHash, work: Work) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.insert(root, work).is_none() {
+            self.order.push_back(root);
+        }
+        while self.entries.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+
+    /// Remove the entry for `root`, e.g. because a block using it was published and the
+    /// account frontier has moved on
+    pub fn invalidate(&mut self, root: &InputHash) -> bool {
+        if self.entries.remove(root).is_some() {
+            self.order.retain(|r| r != root);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Every entry, oldest first
+    pub fn entries(&self) -> Vec<(InputHash, Work)> {
+        self.order.iter().map(|root| (*root, self.entries[root])).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nanopow_rs::THRESHOLD_VALUE;
+
+    const ROOT: &str = "8D3E5F07BFF7B7484CDCB392F47009F62997253D28BD98B94BCED95F03C4DA09";
+    const WORK: &str = "4effb6b0cd5625e2";
+
+    #[test]
+    fn cache_returns_valid_work() {
+        let root = InputHash::from_hex(ROOT).unwrap();
+        let mut cache = WorkCache::new(4);
+        cache.insert(root, Work::from_hex(WORK).unwrap());
+        assert_eq!(cache.get(&root, THRESHOLD_VALUE), Some(Work::from_hex(WORK).unwrap()));
+        assert_eq!(cache.get(&root, u64::max_value()), None);
+        assert!(cache.invalidate(&root));
+        assert_eq!(cache.get(&root, THRESHOLD_VALUE), None);
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn cache_ignores_invalid_work() {
+        let root = InputHash::from_hex(ROOT).unwrap();
+        let mut cache = WorkCache::new(4);
+        cache.insert(root, Work::from_hex("4effc680cd5625e2").unwrap());
+        assert_eq!(cache.get(&root, THRESHOLD_VALUE), None);
+    }
+
+    #[test]
+    fn cache_evicts_oldest() {
+        let mut cache = WorkCache::new(2);
+        let roots: Vec<InputHash> = (0..3u8).map(|i| InputHash::new([i; 32])).collect();
+        for root in roots.iter() {
+            cache.insert(*root, Work::from_hex(WORK).unwrap());
+        }
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.entries.contains_key(&roots[0]));
+        assert!(cache.entries.contains_key(&roots[2]));
+    }
+}
@@ -0,0 +1,261 @@
+//! Proof of work generation for the node, shared by the wallet and RPC handlers
+pub mod cache;
+pub mod dpow;
+pub mod opencl;
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::fmt;
+
+use nano_lib_rs::block::{InputHash, Work};
+use nanopow_rs::{CancelHandle, WorkGenerator};
+
+use crate::error::*;
+
+use self::cache::{WorkCache, WORK_CACHE_SIZE};
+use self::dpow::{DpowConfig, DpowSource};
+use self::opencl::{OpenClConfig, OpenClSource};
+
+/// Something able to produce work for a root, such as a GPU or a remote service
+pub trait WorkSource: Send + Sync {
+    /// A short human readable name used in logs
+    fn name(&self) -> &str;
+
+    /// Generate work meeting `threshold` for `root`, returning `Ok(None)` if `cancel` was
+    /// triggered. Errors cause the pool to fall back to the next source.
+    fn generate(&self, root: &InputHash, threshold: u64, cancel: &CancelHandle) -> Result<Option<Work>>;
+}
+
+/// Generates work on the CPU with `nanopow_rs`
+pub struct CpuSource {
+    threads: Option<usize>,
+    seed: Option<u64>,
+}
+
+impl WorkSource for CpuSource {
+    fn name(&self) -> &str {
+        "CPU"
+    }
+
+    fn generate(&self, root: &InputHash, threshold: u64, cancel: &CancelHandle) -> Result<Option<Work>> {
+        let generator = match self.threads {
+            Some(threads) => WorkGenerator::new().with_threads(threads),
+            None => WorkGenerator::new(),
+        };
+        let generator = match self.seed {
+            Some(seed) => generator.with_seed(seed),
+            None => generator,
+        };
+        Ok(generator
+            .with_threshold(threshold)
+            .with_cancel_handle(cancel.clone())
+            .generate(root))
+    }
+}
+
+/// Default number of roots work may be pre-generated for at once
+pub const DEFAULT_PREGENERATION_LIMIT: usize = 2;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WorkConfig {
+    /// Threads used per CPU work generation request, or one per logical CPU if `None`
+    pub threads: Option<usize>,
+    /// Maximum number of roots work is pre-generated for concurrently; 0 disables it
+    pub pregeneration_limit: usize,
+    /// OpenCL device to generate work on
+    pub opencl: Option<OpenClConfig>,
+    /// Distributed PoW service to request work from
+    pub dpow: Option<DpowConfig>,
+    /// Seeds CPU work generation, so that tests find the same work on every run
+    pub seed: Option<u64>,
+}
+
+impl Default for WorkConfig {
+    fn default() -> Self {
+        WorkConfig {
+            threads: None,
+            pregeneration_limit: DEFAULT_PREGENERATION_LIMIT,
+            opencl: None,
+            dpow: None,
+            seed: None,
+        }
+    }
+}
+
+/// Generates work using the configured sources and keeps track of in-progress
+/// requests so they can be cancelled by root.
+///
+/// Sources are tried in order: a distributed PoW service, then an OpenCL device,
+/// always falling back to the CPU if the others fail.
+pub struct WorkPool {
+    sources: RwLock<Arc<Vec<Box<dyn WorkSource>>>>,
+    active: Mutex<HashMap<InputHash, CancelHandle>>,
+    cache: Mutex<WorkCache>,
+    pregeneration_limit: AtomicUsize,
+    pregenerating: AtomicUsize,
+}
+
+impl fmt::Debug for WorkPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WorkPool")
+            .field("sources", &self.source_names())
+            .field("active", &self.active)
+            .field("cache", &self.cache)
+            .field("pregenerating", &self.pregenerating)
+            .finish()
+    }
+}
+
+impl WorkPool {
+    /// Create a pool from `config`. Sources which fail to initialize are logged and skipped.
+    pub fn new(config: WorkConfig) -> Self {
+        WorkPool {
+            sources: RwLock::new(Arc::new(create_sources(&config))),
+            active: Mutex::new(HashMap::new()),
+            cache: Mutex::new(WorkCache::new(WORK_CACHE_SIZE)),
+            pregeneration_limit: AtomicUsize::new(config.pregeneration_limit),
+            pregenerating: AtomicUsize::new(0),
+        }
+    }
+
+    /// Replace the configured sources and limits. Requests already in progress finish
+    /// using the sources they started with.
+    pub fn reconfigure(&self, config: WorkConfig) {
+        let sources = create_sources(&config);
+        *self.sources.write().unwrap() = Arc::new(sources);
+        self.pregeneration_limit.store(config.pregeneration_limit, Ordering::SeqCst);
+    }
+
+    fn sources(&self) -> Arc<Vec<Box<dyn WorkSource>>> {
+        self.sources.read().unwrap().clone()
+    }
+
+    /// Generate work meeting `threshold` for `root`, blocking the current thread until a
+    /// valid nonce is found or the request is cancelled with `cancel`. Previously generated
+    /// work for the same root is returned immediately if it is still sufficient.
+    pub fn generate(&self, root: InputHash, threshold: u64) -> Option<Work> {
+        if let Some(work) = self.cache.lock().unwrap().get(&root, threshold) {
+            debug!("Using cached work for root {}", root);
+            return Some(work);
+        }
+        let handle = {
+            let mut active = self.active.lock().unwrap();
+            active.entry(root).or_insert_with(CancelHandle::new).clone()
+        };
+        debug!("Generating work for root {}", root);
+        let work = self.generate_from_sources(&root, threshold, &handle);
+        self.active.lock().unwrap().remove(&root);
+        match work {
+            Some(work) => self.cache.lock().unwrap().insert(root, work),
+            None => debug!("Work generation for root {} was cancelled", root),
+        }
+        work
+    }
+
+    fn generate_from_sources(&self, root: &InputHash, threshold: u64, handle: &CancelHandle) -> Option<Work> {
+        for source in self.sources().iter() {
+            match source.generate(root, threshold, handle) {
+                Ok(work) => return work,
+                Err(e) => warn!("{} work generation failed, trying next source: {}", source.name(), e),
+            }
+        }
+        None
+    }
+
+    /// Reserve a pre-generation slot for `root`. Returns false if work for it is already
+    /// cached or being generated, or if the concurrency limit has been reached; otherwise
+    /// the caller must call `end_pregeneration` once done.
+    pub fn begin_pregeneration(&self, root: &InputHash, threshold: u64) -> bool {
+        if self.cache.lock().unwrap().get(root, threshold).is_some()
+            || self.active.lock().unwrap().contains_key(root)
+        {
+            return false;
+        }
+        let mut current = self.pregenerating.load(Ordering::SeqCst);
+        loop {
+            if current >= self.pregeneration_limit.load(Ordering::SeqCst) {
+                return false;
+            }
+            match self.pregenerating.compare_exchange(current, current + 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return true,
+                Err(previous) => current = previous,
+            }
+        }
+    }
+
+    /// Release a slot reserved with `begin_pregeneration`
+    pub fn end_pregeneration(&self) {
+        self.pregenerating.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Cancel any in-progress generation for `root`. Returns whether one was running.
+    pub fn cancel(&self, root: &InputHash) -> bool {
+        let mut active = self.active.lock().unwrap();
+        if let Entry::Occupied(entry) = active.entry(*root) {
+            entry.get().cancel();
+            entry.remove();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Forget cached work for `root`. Should be called whenever an account's frontier
+    /// changes away from `root`.
+    pub fn invalidate(&self, root: &InputHash) -> bool {
+        self.cache.lock().unwrap().invalidate(root)
+    }
+
+    /// The number of roots with cached work
+    /// Every cached root and its work, oldest first, to be restored with `restore_cache`
+    pub fn cached_work(&self) -> Vec<(InputHash, Work)> {
+        self.cache.lock().unwrap().entries()
+    }
+
+    pub fn restore_cache(&self, entries: Vec<(InputHash, Work)>) {
+        let mut cache = self.cache.lock().unwrap();
+        for (root, work) in entries {
+            cache.insert(root, work);
+        }
+    }
+
+    pub fn cached_count(&self) -> usize {
+        self.cache.lock().unwrap().len()
+    }
+
+    /// The number of roots for which work is currently being generated
+    pub fn active_count(&self) -> usize {
+        self.active.lock().unwrap().len()
+    }
+
+    /// The number of pre-generation slots in use
+    pub fn pregenerating_count(&self) -> usize {
+        self.pregenerating.load(Ordering::SeqCst)
+    }
+
+    /// The names of the configured sources, in the order they are tried
+    pub fn source_names(&self) -> Vec<String> {
+        self.sources().iter().map(|s| s.name().to_owned()).collect()
+    }
+}
+
+/// Build the sources described by `config`, always ending with the CPU
+fn create_sources(config: &WorkConfig) -> Vec<Box<dyn WorkSource>> {
+    let mut sources: Vec<Box<dyn WorkSource>> = Vec::new();
+    if let Some(ref dpow) = config.dpow {
+        match DpowSource::new(dpow.clone()) {
+            Ok(source) => sources.push(Box::new(source)),
+            Err(e) => warn!("Failed to set up distributed PoW client: {}", e),
+        }
+    }
+    if let Some(opencl) = config.opencl {
+        match OpenClSource::new(opencl) {
+            Ok(source) => sources.push(Box::new(source)),
+            Err(e) => warn!("{}, using CPU work generation", e),
+        }
+    }
+    sources.push(Box::new(CpuSource { threads: config.threads, seed: config.seed }));
+    sources
+}
@@ -0,0 +1,87 @@
+#[cfg(feature = "opencl")]
+use std::sync::Mutex;
+
+use nano_lib_rs::block::{InputHash, Work};
+use nanopow_rs::CancelHandle;
+#[cfg(feature = "opencl")]
+use nanopow_rs::gpu::{GpuConfig, GpuWorkGenerator};
+
+use crate::error::*;
+use super::WorkSource;
+
+/// Selects an OpenCL device to generate work on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OpenClConfig {
+    pub platform: usize,
+    pub device: usize,
+    /// Number of nonces evaluated per kernel invocation
+    pub threads: usize,
+}
+
+impl Default for OpenClConfig {
+    fn default() -> Self {
+        OpenClConfig {
+            platform: 0,
+            device: 0,
+            threads: 1024 * 1024,
+        }
+    }
+}
+
+/// Generates work on an OpenCL device. Only available with the `opencl` feature.
+#[cfg(feature = "opencl")]
+pub struct OpenClSource {
+    gpu: Mutex<GpuWorkGenerator>,
+}
+
+#[cfg(feature = "opencl")]
+impl OpenClSource {
+    pub fn new(config: OpenClConfig) -> Result<Self> {
+        let gpu_config = GpuConfig {
+            platform: config.platform,
+            device: config.device,
+            threads: config.threads,
+        };
+        let gpu = GpuWorkGenerator::new(gpu_config)
+            .map_err(|e| Error::from(format!("Failed to initialize OpenCL: {}", e)))?;
+        info!("Generating work with OpenCL device {} on platform {}", config.device, config.platform);
+        Ok(OpenClSource {
+            gpu: Mutex::new(gpu),
+        })
+    }
+}
+
+#[cfg(feature = "opencl")]
+impl WorkSource for OpenClSource {
+    fn name(&self) -> &str {
+        "OpenCL"
+    }
+
+    fn generate(&self, root: &InputHash, threshold: u64, cancel: &CancelHandle) -> Result<Option<Work>> {
+        self.gpu.lock().unwrap()
+            .generate(root, threshold, cancel)
+            .map_err(|e| format!("{}", e).into())
+    }
+}
+
+/// Placeholder used when nano-rs is built without the `opencl` feature
+#[cfg(not(feature = "opencl"))]
+pub struct OpenClSource(());
+
+#[cfg(not(feature = "opencl"))]
+impl OpenClSource {
+    pub fn new(_config: OpenClConfig) -> Result<Self> {
+        bail!("OpenCL work generation was configured, but nano-rs was built without the `opencl` feature")
+    }
+}
+
+#[cfg(not(feature = "opencl"))]
+impl WorkSource for OpenClSource {
+    fn name(&self) -> &str {
+        "OpenCL"
+    }
+
+    fn generate(&self, _root: &InputHash, _threshold: u64, _cancel: &CancelHandle) -> Result<Option<Work>> {
+        bail!("nano-rs was built without the `opencl` feature")
+    }
+}